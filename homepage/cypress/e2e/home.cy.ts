@@ -163,7 +163,7 @@ describe('Homepage', () => {
 
       // Mock Status Polling (Processing then Completed)
       // The client polls every 3s if status is processing.
-      // When it receives non-JSON, it considers it done.
+      // Once completed, it fetches the audio from download_url.
       let requestCount = 0;
       cy.intercept('GET', '/api/tts/status/test-job-id', (req) => {
         requestCount++;
@@ -174,15 +174,20 @@ describe('Homepage', () => {
                 body: { status: 'processing' }
              });
         } else {
-             // Second request returns non-json (audio file) to signal completion
              req.reply({
                 statusCode: 200,
-                headers: { 'content-type': 'audio/mpeg' },
-                body: 'fake-audio-content'
+                headers: { 'content-type': 'application/json' },
+                body: { status: 'completed', download_url: '/download/test-job-id' }
              });
         }
       }).as('pollStatus');
 
+      cy.intercept('GET', '/api/tts/download/test-job-id', {
+        statusCode: 200,
+        headers: { 'content-type': 'audio/mpeg' },
+        body: 'fake-audio-content'
+      }).as('downloadAudio');
+
       // Fill form
       cy.get('#tts-file').selectFile('cypress/fixtures/sample.txt');
       cy.get('#tts-voice').select('af_heart');
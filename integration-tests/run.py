@@ -386,14 +386,22 @@ def test_text_to_speech(use_rancher):
         start_wait = time.time()
         completed = False
         while time.time() - start_wait < 60: # 60s timeout
-            status_res = run_command(["curl", "-s", "-I", f"http://localhost:{port}/status/{job_id}"], capture_output=True).stdout
-            
-            # Check headers for content-type
-            # processing returns JSON, completed returns audio/mpeg
-            if "audio/mpeg" in status_res:
-                log("Job completed and audio is ready.")
-                completed = True
-                break
+            status_res = run_command(["curl", "-s", f"http://localhost:{port}/status/{job_id}"], capture_output=True).stdout
+
+            # Status is JSON; once completed it points at the download endpoint
+            try:
+                status = json.loads(status_res)
+            except ValueError:
+                status = {}
+            if status.get("status") == "error":
+                log(f"Job failed: {status.get('message')}")
+                sys.exit(1)
+            if status.get("status") == "completed":
+                download_res = run_command(["curl", "-s", "-I", f"http://localhost:{port}{status['download_url']}"], capture_output=True).stdout
+                if "audio/mpeg" in download_res:
+                    log("Job completed and audio is ready.")
+                    completed = True
+                    break
                 
             time.sleep(2)
             
@@ -0,0 +1,377 @@
+//! Load generator for the streaming transcription endpoint.
+//!
+//! Opens N concurrent WebSocket sessions against `/transcribe`, streams recorded audio
+//! into each at real-time pace, commits, and reports the distribution of end-to-end
+//! latency (commit until the final transcript) and how sessions ended, including
+//! server `closing` reasons, to check concurrency limits, backpressure and backend
+//! scheduling under load.
+//!
+//! ```text
+//! cargo run --release --bin stt-loadgen -- \
+//!     --url ws://localhost:3000/transcribe --token "$TOKEN" \
+//!     --sessions 20 --ramp-secs 10 recording1.wav recording2.pcm
+//! ```
+//!
+//! Audio files must be 16kHz mono PCM16, either WAV or headerless `.pcm`. Sessions
+//! take files round-robin. The token can also be given in `STT_TOKEN`.
+
+use futures_util::{SinkExt, StreamExt};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+const BYTES_PER_SEC: usize = 16000 * 2;
+
+const USAGE: &str = "usage: stt-loadgen --url WS_URL [--token TOKEN] [--sessions N] \
+[--ramp-secs SECS] [--chunk-ms MS] [--timeout-secs SECS] FILE...";
+
+struct Config {
+    url: String,
+    token: Option<String>,
+    sessions: usize,
+    ramp: Duration,
+    chunk: Duration,
+    /// How long to wait for the final transcript after committing
+    timeout: Duration,
+    files: Vec<String>,
+}
+
+impl Config {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut config = Self {
+            url: String::new(),
+            token: std::env::var("STT_TOKEN").ok(),
+            sessions: 10,
+            ramp: Duration::ZERO,
+            chunk: Duration::from_millis(100),
+            timeout: Duration::from_secs(30),
+            files: Vec::new(),
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--url" => config.url = value()?,
+                "--token" => config.token = Some(value()?),
+                "--sessions" => config.sessions = parse_number(&value()?, "--sessions")?,
+                "--ramp-secs" => config.ramp = Duration::from_secs(parse_number(&value()?, "--ramp-secs")?),
+                "--chunk-ms" => config.chunk = Duration::from_millis(parse_number(&value()?, "--chunk-ms")?),
+                "--timeout-secs" => {
+                    config.timeout = Duration::from_secs(parse_number(&value()?, "--timeout-secs")?)
+                }
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                flag if flag.starts_with("--") => return Err(format!("Unknown option {}\n{}", flag, USAGE)),
+                _ => config.files.push(arg),
+            }
+        }
+
+        if config.url.is_empty() || config.files.is_empty() {
+            return Err(USAGE.to_string());
+        }
+        if config.sessions == 0 || config.chunk.is_zero() {
+            return Err("--sessions and --chunk-ms must be greater than zero".to_string());
+        }
+        Ok(config)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, flag: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value '{}' for {}", value, flag))
+}
+
+/// PCM16 samples of a WAV file's `data` chunk, or the whole file if it isn't a WAV
+fn pcm_from_file(bytes: Vec<u8>) -> Vec<u8> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return bytes;
+    }
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let size = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let body = offset + 8;
+        if &bytes[offset..offset + 4] == b"data" {
+            return bytes[body..(body + size).min(bytes.len())].to_vec();
+        }
+        offset = body + size + (size & 1);
+    }
+    Vec::new()
+}
+
+/// What happened in one session
+#[derive(Default)]
+struct SessionReport {
+    /// Connect until the server's `connected` message
+    connect: Option<Duration>,
+    /// Transcripts received while audio was still streaming
+    interim_transcripts: usize,
+    /// Commit until the final transcript
+    final_latency: Option<Duration>,
+    /// Worst delay of a chunk beyond its real-time schedule (send backpressure)
+    max_send_lag: Duration,
+    /// How the session ended if it didn't complete: `closing` reason, error or timeout
+    failure: Option<String>,
+}
+
+async fn run_session(config: Arc<Config>, audio: Arc<Vec<u8>>) -> SessionReport {
+    let mut report = SessionReport::default();
+    let url = match &config.token {
+        Some(token) => format!("{}?token={}", config.url, token),
+        None => config.url.clone(),
+    };
+
+    let started = Instant::now();
+    let (socket, _) = match tokio_tungstenite::connect_async(url.as_str()).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            report.failure = Some(format!("connect: {}", e));
+            return report;
+        }
+    };
+    let (mut sink, mut stream) = socket.split();
+
+    // Sent audio and received messages are handled concurrently, like a live client
+    let (events_tx, mut events_rx) = tokio::sync::mpsc::unbounded_channel();
+    let reader = tokio::spawn(async move {
+        while let Some(message) = stream.next().await {
+            let text = match message {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Close(frame)) => {
+                    let reason = frame.map(|f| f.reason.to_string()).unwrap_or_default();
+                    let _ = events_tx.send((Instant::now(), Err(format!("closed: {}", reason))));
+                    return;
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    let _ = events_tx.send((Instant::now(), Err(format!("receive: {}", e))));
+                    return;
+                }
+            };
+            let Ok(json) = serde_json::from_str::<serde_json::Value>(&text) else {
+                continue;
+            };
+            if events_tx.send((Instant::now(), Ok(json))).is_err() {
+                return;
+            }
+        }
+    });
+
+    let chunk_bytes = (BYTES_PER_SEC as u128 * config.chunk.as_millis() / 1000) as usize & !1;
+    let mut committed_at: Option<Instant> = None;
+    let mut chunks = audio.chunks(chunk_bytes.max(2));
+    let stream_start = Instant::now();
+    let mut next_chunk = 0u32;
+
+    loop {
+        let send_at = tokio::time::Instant::from_std(stream_start + config.chunk * next_chunk);
+        let commit_deadline = tokio::time::Instant::from_std(committed_at.unwrap_or(stream_start) + config.timeout);
+        tokio::select! {
+            event = events_rx.recv() => {
+                let Some((at, event)) = event else {
+                    report.failure.get_or_insert_with(|| "connection ended".to_string());
+                    break;
+                };
+                let json = match event {
+                    Ok(json) => json,
+                    Err(e) => {
+                        if report.final_latency.is_none() {
+                            report.failure.get_or_insert(e);
+                        }
+                        break;
+                    }
+                };
+                match json["type"].as_str() {
+                    Some("connected") => report.connect = Some(at - started),
+                    Some("transcript") if json["is_final"] == true => {
+                        if let Some(committed_at) = committed_at {
+                            report.final_latency = Some(at - committed_at);
+                            break;
+                        }
+                    }
+                    Some("transcript") => report.interim_transcripts += 1,
+                    Some("closing") => {
+                        report.failure = Some(format!("closing: {}", json["reason"].as_str().unwrap_or("unknown")));
+                    }
+                    Some("error") => {
+                        report.failure = Some(format!("error: {}", json["error"].as_str().unwrap_or("unknown")));
+                    }
+                    _ => {}
+                }
+            }
+            _ = tokio::time::sleep_until(send_at), if committed_at.is_none() => {
+                let lag = Instant::now().saturating_duration_since(send_at.into_std());
+                report.max_send_lag = report.max_send_lag.max(lag);
+                let result = match chunks.next() {
+                    Some(chunk) => sink.send(Message::Binary(chunk.to_vec())).await,
+                    None => {
+                        committed_at = Some(Instant::now());
+                        sink.send(Message::Text(r#"{"type":"commit"}"#.to_string())).await
+                    }
+                };
+                if let Err(e) = result {
+                    report.failure = Some(format!("send: {}", e));
+                    break;
+                }
+                next_chunk += 1;
+            }
+            _ = tokio::time::sleep_until(commit_deadline), if committed_at.is_some() => {
+                report.failure = Some("timeout waiting for final transcript".to_string());
+                break;
+            }
+        }
+    }
+
+    let _ = sink.send(Message::Close(None)).await;
+    reader.abort();
+    report
+}
+
+/// Nearest-rank percentile of sorted durations
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn print_distribution(label: &str, mut values: Vec<Duration>) {
+    if values.is_empty() {
+        println!("{:<22} no samples", label);
+        return;
+    }
+    values.sort();
+    println!(
+        "{:<22} n={:<5} p50={:>7.0}ms p90={:>7.0}ms p99={:>7.0}ms max={:>7.0}ms",
+        label,
+        values.len(),
+        percentile(&values, 50.0).as_secs_f64() * 1000.0,
+        percentile(&values, 90.0).as_secs_f64() * 1000.0,
+        percentile(&values, 99.0).as_secs_f64() * 1000.0,
+        values[values.len() - 1].as_secs_f64() * 1000.0,
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let config = match Config::parse(std::env::args().skip(1)) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut audio = Vec::new();
+    for file in &config.files {
+        match std::fs::read(file) {
+            Ok(bytes) => audio.push(Arc::new(pcm_from_file(bytes))),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", file, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    let longest = audio.iter().map(|a| a.len()).max().unwrap_or(0) as f64 / BYTES_PER_SEC as f64;
+    println!(
+        "Starting {} sessions over {:?} against {} ({} file(s), longest {:.1}s)",
+        config.sessions,
+        config.ramp,
+        config.url,
+        audio.len(),
+        longest
+    );
+
+    let started = Instant::now();
+    let mut handles = Vec::with_capacity(config.sessions);
+    for i in 0..config.sessions {
+        let delay = config.ramp.mul_f64(i as f64 / config.sessions as f64);
+        let config = Arc::clone(&config);
+        let audio = Arc::clone(&audio[i % audio.len()]);
+        handles.push(tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            run_session(config, audio).await
+        }));
+    }
+
+    let mut reports = Vec::with_capacity(handles.len());
+    for handle in handles {
+        reports.push(handle.await.unwrap_or_else(|e| SessionReport {
+            failure: Some(format!("panicked: {}", e)),
+            ..Default::default()
+        }));
+    }
+
+    let completed = reports.iter().filter(|r| r.final_latency.is_some()).count();
+    println!(
+        "\n{} of {} sessions completed in {:.1}s",
+        completed,
+        reports.len(),
+        started.elapsed().as_secs_f64()
+    );
+    print_distribution("connect", reports.iter().filter_map(|r| r.connect).collect());
+    println!(
+        "{:<22} {}",
+        "interim transcripts",
+        reports.iter().map(|r| r.interim_transcripts).sum::<usize>()
+    );
+    print_distribution("commit -> final", reports.iter().filter_map(|r| r.final_latency).collect());
+    print_distribution("send lag (max)", reports.iter().map(|r| r.max_send_lag).collect());
+
+    let mut failures: BTreeMap<&str, usize> = BTreeMap::new();
+    for failure in reports.iter().filter_map(|r| r.failure.as_deref()) {
+        *failures.entry(failure).or_default() += 1;
+    }
+    if !failures.is_empty() {
+        println!("\nFailures:");
+        for (failure, count) in &failures {
+            println!("{:>5}  {}", count, failure);
+        }
+    }
+
+    if completed < reports.len() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcm_from_file() {
+        let pcm = vec![1u8, 2, 3, 4];
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(4 + 8 + 16 + 8 + pcm.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&[0u8; 16]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(pcm.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&pcm);
+
+        assert_eq!(pcm_from_file(wav), pcm);
+        assert_eq!(pcm_from_file(pcm.clone()), pcm);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&values, 50.0), Duration::from_millis(5));
+        assert_eq!(percentile(&values, 90.0), Duration::from_millis(9));
+        assert_eq!(percentile(&values, 100.0), Duration::from_millis(10));
+        assert_eq!(percentile(&values, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_config_parse() {
+        let args = ["--url", "ws://localhost:3000/transcribe", "--sessions", "5", "a.wav"];
+        let config = Config::parse(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.sessions, 5);
+        assert_eq!(config.files, ["a.wav"]);
+
+        assert!(Config::parse(["a.wav"].iter().map(|s| s.to_string())).is_err());
+        assert!(Config::parse(["--url", "ws://x", "--sessions"].iter().map(|s| s.to_string())).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+//! Structured WebSocket close with reconnect guidance.
+//!
+//! When the server ends a session it sends a `closing` message followed by a
+//! close frame. Both carry a reason code and, where reconnecting makes sense,
+//! a suggested `retry_after_ms`, so clients back off consistently instead of
+//! reconnecting in a tight loop.
+
+use axum::extract::ws::{CloseFrame, Message};
+use serde::Serialize;
+
+/// Why the server is closing the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Server is shutting down or restarting
+    ServerShutdown,
+    /// Transcription backend is failing repeatedly
+    BackendUnavailable,
+    /// Token missing or invalid; reconnecting with the same token will not help
+    AuthFailed,
+}
+
+/// JSON payload carried in the close frame reason
+#[derive(Debug, Serialize)]
+struct ClosePayload {
+    reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+}
+
+impl CloseReason {
+    /// Reason code sent to clients
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ServerShutdown => "server_shutdown",
+            Self::BackendUnavailable => "backend_unavailable",
+            Self::AuthFailed => "auth_failed",
+        }
+    }
+
+    /// WebSocket close code (RFC 6455 / IANA registry)
+    pub fn code(self) -> u16 {
+        match self {
+            Self::ServerShutdown => 1012,     // Service Restart
+            Self::BackendUnavailable => 1013, // Try Again Later
+            Self::AuthFailed => 1008,         // Policy Violation
+        }
+    }
+
+    /// Suggested delay before reconnecting, or `None` if the client should not retry
+    pub fn retry_after_ms(self) -> Option<u64> {
+        match self {
+            Self::ServerShutdown => Some(2_000),
+            Self::BackendUnavailable => Some(10_000),
+            Self::AuthFailed => None,
+        }
+    }
+
+    /// Close frame whose reason is a compact JSON payload (fits the 123-byte limit)
+    pub fn close_message(self) -> Message {
+        let payload = ClosePayload {
+            reason: self.as_str(),
+            retry_after_ms: self.retry_after_ms(),
+        };
+        Message::Close(Some(CloseFrame {
+            code: self.code(),
+            reason: serde_json::to_string(&payload).unwrap().into(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_frame_payload() {
+        let Message::Close(Some(frame)) = CloseReason::BackendUnavailable.close_message() else {
+            panic!("expected close frame");
+        };
+        assert_eq!(frame.code, 1013);
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(payload["reason"], "backend_unavailable");
+        assert_eq!(payload["retry_after_ms"], 10_000);
+        assert!(frame.reason.len() <= 123);
+
+        let Message::Close(Some(frame)) = CloseReason::AuthFailed.close_message() else {
+            panic!("expected close frame");
+        };
+        let payload: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert!(payload.get("retry_after_ms").is_none());
+    }
+}
@@ -0,0 +1,188 @@
+//! Vocabulary biasing from Home Assistant entity names.
+//!
+//! When `HOME_ASSISTANT_URL` and `HOME_ASSISTANT_TOKEN` are set, the friendly names
+//! of controllable entities (lights, switches, fans, ...) are fetched periodically
+//! from the Home Assistant REST API and passed to Whisper as a prompt for live
+//! sessions, so device names like "hallway sconce" are recognized instead of being
+//! transcribed as similar-sounding common words.
+
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// Entity domains whose names people say in voice commands
+const DEFAULT_DOMAINS: &str =
+    "light,switch,fan,cover,climate,lock,media_player,scene,script,vacuum";
+
+/// Whisper only uses the last ~224 tokens of a prompt; stay comfortably inside that
+const MAX_PROMPT_CHARS: usize = 600;
+
+/// Prompt built from the current entity names, shared with live sessions
+pub type EntityPrompt = Arc<RwLock<Option<String>>>;
+
+#[derive(Debug, Clone)]
+pub struct HomeAssistantConfig {
+    pub url: String,
+    pub token: String,
+    pub domains: Vec<String>,
+    pub refresh_interval: Duration,
+}
+
+impl HomeAssistantConfig {
+    /// Read the integration's settings; `None` when it isn't configured
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("HOME_ASSISTANT_URL").ok()?;
+        let token = std::env::var("HOME_ASSISTANT_TOKEN").ok()?;
+        let domains = std::env::var("HOME_ASSISTANT_DOMAINS")
+            .unwrap_or_else(|_| DEFAULT_DOMAINS.to_string())
+            .split(',')
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty())
+            .collect();
+        let refresh_secs = std::env::var("HOME_ASSISTANT_REFRESH_SECS")
+            .unwrap_or_else(|_| "600".to_string())
+            .parse()
+            .expect("Invalid HOME_ASSISTANT_REFRESH_SECS");
+
+        Some(Self {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            domains,
+            refresh_interval: Duration::from_secs(refresh_secs),
+        })
+    }
+}
+
+/// One entry of `GET /api/states`
+#[derive(Debug, Deserialize)]
+struct EntityState {
+    entity_id: String,
+    #[serde(default)]
+    attributes: EntityAttributes,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct EntityAttributes {
+    friendly_name: Option<String>,
+}
+
+/// Keep the shared prompt up to date with the entity names in Home Assistant.
+/// A failed refresh keeps the previous names.
+pub fn spawn_refresh(config: HomeAssistantConfig, prompt: EntityPrompt) {
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut interval = tokio::time::interval(config.refresh_interval);
+        loop {
+            interval.tick().await;
+            match fetch_entity_names(&client, &config).await {
+                Ok(names) => {
+                    tracing::info!(entities = names.len(), "Refreshed Home Assistant entity names");
+                    *prompt.write().await = build_prompt(&names);
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to fetch Home Assistant entity names");
+                }
+            }
+        }
+    });
+}
+
+async fn fetch_entity_names(
+    client: &reqwest::Client,
+    config: &HomeAssistantConfig,
+) -> Result<Vec<String>, String> {
+    let response = client
+        .get(format!("{}/api/states", config.url))
+        .bearer_auth(&config.token)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .map_err(|e| format!("Request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err(format!("Home Assistant returned {}", response.status()));
+    }
+
+    let states: Vec<EntityState> = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse states: {}", e))?;
+    Ok(entity_names(&states, &config.domains))
+}
+
+/// Friendly names of entities in the given domains, de-duplicated and sorted
+fn entity_names(states: &[EntityState], domains: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut names: Vec<String> = states
+        .iter()
+        .filter(|s| {
+            s.entity_id
+                .split_once('.')
+                .is_some_and(|(domain, _)| domains.iter().any(|d| d == domain))
+        })
+        .filter_map(|s| s.attributes.friendly_name.as_deref())
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter(|name| seen.insert(name.to_lowercase()))
+        .map(str::to_string)
+        .collect();
+    names.sort_by_key(|name| name.to_lowercase());
+    names
+}
+
+/// Whisper prompt listing the entity names, truncated to fit the prompt window
+fn build_prompt(names: &[String]) -> Option<String> {
+    let mut prompt = String::new();
+    for name in names {
+        let separator = if prompt.is_empty() { "" } else { ", " };
+        if prompt.len() + separator.len() + name.len() > MAX_PROMPT_CHARS {
+            break;
+        }
+        prompt.push_str(separator);
+        prompt.push_str(name);
+    }
+    (!prompt.is_empty()).then(|| format!("{}.", prompt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(entity_id: &str, friendly_name: Option<&str>) -> EntityState {
+        EntityState {
+            entity_id: entity_id.to_string(),
+            attributes: EntityAttributes {
+                friendly_name: friendly_name.map(str::to_string),
+            },
+        }
+    }
+
+    #[test]
+    fn test_entity_names() {
+        let states = vec![
+            state("light.hallway_sconce", Some("Hallway Sconce")),
+            state("switch.kettle", Some("Kettle")),
+            state("sensor.outdoor_temperature", Some("Outdoor Temperature")),
+            state("light.hallway_sconce_2", Some("hallway sconce")),
+            state("fan.bedroom", None),
+        ];
+        let domains = vec!["light".to_string(), "switch".to_string(), "fan".to_string()];
+        assert_eq!(entity_names(&states, &domains), ["Hallway Sconce", "Kettle"]);
+    }
+
+    #[test]
+    fn test_build_prompt() {
+        assert_eq!(build_prompt(&[]), None);
+        assert_eq!(
+            build_prompt(&["Hallway Sconce".to_string(), "Kettle".to_string()]).as_deref(),
+            Some("Hallway Sconce, Kettle.")
+        );
+
+        let many: Vec<String> = (0..200).map(|i| format!("Lamp {}", i)).collect();
+        let prompt = build_prompt(&many).unwrap();
+        assert!(prompt.len() <= MAX_PROMPT_CHARS + 1);
+        assert!(prompt.starts_with("Lamp 0, Lamp 1,"));
+    }
+}
@@ -0,0 +1,360 @@
+//! Batch transcription jobs for long recordings.
+//!
+//! `POST /jobs` accepts one or more WAV parts (16kHz mono PCM16) that are
+//! concatenated in upload order, so recordings split across several files are
+//! transcribed as one. The audio is sent to Whisper in fixed-size chunks and
+//! `GET /jobs/:id` reports progress by audio duration processed, along with the
+//! transcript so far, while the job runs.
+
+use crate::auth::AuthenticatedUser;
+use crate::scheduler::{Scheduler, SegmentClass};
+use crate::state::AppState;
+use crate::whisper::WhisperClient;
+use axum::{
+    extract::{Extension, Multipart, Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+/// Sample rate of accepted audio
+const SAMPLE_RATE: u32 = 16000;
+/// Bytes per second of 16kHz mono PCM16
+const BYTES_PER_SEC: usize = SAMPLE_RATE as usize * 2;
+/// Audio sent to Whisper per request (Whisper's native window)
+const CHUNK_SECS: usize = 30;
+/// Finished jobs are dropped this long after they were created
+const JOB_RETENTION: Duration = Duration::from_secs(24 * 3600);
+
+/// In-memory job registry, keyed by job ID
+pub type JobStore = Arc<RwLock<HashMap<Uuid, TranscriptionJob>>>;
+
+pub enum JobState {
+    Processing,
+    Completed,
+    Error(String),
+}
+
+pub struct TranscriptionJob {
+    username: String,
+    created_at: Instant,
+    parts: usize,
+    total_secs: f64,
+    processed_secs: f64,
+    /// Transcript of each chunk processed so far
+    chunks: Vec<String>,
+    state: JobState,
+}
+
+impl TranscriptionJob {
+    fn transcript(&self) -> String {
+        self.chunks
+            .iter()
+            .map(|c| c.trim())
+            .filter(|c| !c.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn progress_percent(&self) -> f64 {
+        if self.total_secs <= 0.0 {
+            return 100.0;
+        }
+        (self.processed_secs / self.total_secs * 100.0).min(100.0)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum JobStatusResponse {
+    Processing {
+        progress_percent: f64,
+        processed_secs: f64,
+        total_secs: f64,
+        parts: usize,
+        partial_transcript: String,
+    },
+    Completed {
+        total_secs: f64,
+        parts: usize,
+        transcript: String,
+    },
+    Error {
+        message: String,
+        processed_secs: f64,
+        total_secs: f64,
+        partial_transcript: String,
+    },
+}
+
+impl From<&TranscriptionJob> for JobStatusResponse {
+    fn from(job: &TranscriptionJob) -> Self {
+        match &job.state {
+            JobState::Processing => Self::Processing {
+                progress_percent: job.progress_percent(),
+                processed_secs: job.processed_secs,
+                total_secs: job.total_secs,
+                parts: job.parts,
+                partial_transcript: job.transcript(),
+            },
+            JobState::Completed => Self::Completed {
+                total_secs: job.total_secs,
+                parts: job.parts,
+                transcript: job.transcript(),
+            },
+            JobState::Error(message) => Self::Error {
+                message: message.clone(),
+                processed_secs: job.processed_secs,
+                total_secs: job.total_secs,
+                partial_transcript: job.transcript(),
+            },
+        }
+    }
+}
+
+/// Create a transcription job from one or more `audio` WAV parts
+pub async fn create_job(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let mut pcm16 = Vec::new();
+    let mut parts = 0;
+    let mut language = "en".to_string();
+
+    while let Some(field) = multipart.next_field().await.map_err(|e| {
+        tracing::error!(error = %e, "Failed to parse multipart field");
+        (StatusCode::BAD_REQUEST, e.to_string())
+    })? {
+        let name = field.name().unwrap_or("").to_string();
+        if name == "audio" {
+            let filename = field.file_name().unwrap_or("").to_string();
+            let data = field.bytes().await.map_err(|e| {
+                tracing::error!(error = %e, "Failed to read audio part");
+                (StatusCode::BAD_REQUEST, e.to_string())
+            })?;
+            let samples = parse_wav_pcm16(&data).map_err(|e| {
+                tracing::warn!(part = parts, filename = %filename, error = %e, "Rejected audio part");
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Audio part {} ({}): {}", parts + 1, filename, e),
+                )
+            })?;
+            pcm16.extend_from_slice(samples);
+            parts += 1;
+        } else if name == "language" {
+            language = field.text().await.map_err(|e| {
+                tracing::error!(error = %e, "Failed to read language field");
+                (StatusCode::BAD_REQUEST, e.to_string())
+            })?;
+        }
+    }
+
+    if pcm16.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No audio provided".to_string()));
+    }
+
+    let job_id = Uuid::new_v4();
+    let total_secs = pcm16.len() as f64 / BYTES_PER_SEC as f64;
+    tracing::info!(
+        job_id = %job_id,
+        username = %user.username,
+        parts,
+        total_secs,
+        "Creating transcription job"
+    );
+
+    {
+        let mut jobs = state.jobs.write().await;
+        jobs.retain(|_, job| {
+            matches!(job.state, JobState::Processing) || job.created_at.elapsed() < JOB_RETENTION
+        });
+        jobs.insert(
+            job_id,
+            TranscriptionJob {
+                username: user.username.clone(),
+                created_at: Instant::now(),
+                parts,
+                total_secs,
+                processed_secs: 0.0,
+                chunks: Vec::new(),
+                state: JobState::Processing,
+            },
+        );
+    }
+
+    let whisper = WhisperClient::new(
+        reqwest::Client::new(),
+        state.whisper_url.clone(),
+        state.whisper_schema,
+    );
+    tokio::spawn(run_job(
+        state.jobs.clone(),
+        job_id,
+        pcm16,
+        language,
+        whisper,
+        state.scheduler.clone(),
+    ));
+
+    Ok(Json(serde_json::json!({ "id": job_id.to_string() })))
+}
+
+/// Report the status, progress and transcript so far of a job
+pub async fn job_status(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<JobStatusResponse>, (StatusCode, String)> {
+    let id = Uuid::parse_str(&id)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid job ID".to_string()))?;
+
+    let jobs = state.jobs.read().await;
+    match jobs.get(&id) {
+        Some(job) if job.username == user.username => Ok(Json(job.into())),
+        _ => Err((StatusCode::NOT_FOUND, "Job not found".to_string())),
+    }
+}
+
+/// Transcribe the audio chunk by chunk, publishing progress after each chunk
+async fn run_job(
+    jobs: JobStore,
+    job_id: Uuid,
+    pcm16: Vec<u8>,
+    language: String,
+    whisper: WhisperClient,
+    scheduler: Arc<Scheduler>,
+) {
+    let chunk_bytes = CHUNK_SECS * BYTES_PER_SEC;
+    let chunk_count = pcm16.len().div_ceil(chunk_bytes);
+
+    for (index, chunk) in pcm16.chunks(chunk_bytes).enumerate() {
+        tracing::debug!(job_id = %job_id, chunk = index + 1, chunks = chunk_count, "Transcribing chunk");
+        // Batch chunks yield the backend to live sessions when it is saturated
+        let queued_at = Instant::now();
+        let permit = scheduler.acquire(SegmentClass::Batch).await;
+        let queued = queued_at.elapsed();
+        let result = whisper.transcribe(chunk, &language, None).await;
+        drop(permit);
+        scheduler.record(SegmentClass::Batch, queued, queued_at.elapsed(), result.is_ok());
+
+        let mut jobs = jobs.write().await;
+        let Some(job) = jobs.get_mut(&job_id) else {
+            return;
+        };
+        match result {
+            Ok(transcription) => {
+                job.chunks.push(transcription.text);
+                job.processed_secs += chunk.len() as f64 / BYTES_PER_SEC as f64;
+            }
+            Err(e) => {
+                tracing::error!(job_id = %job_id, chunk = index + 1, error = %e, "Transcription job failed");
+                job.state = JobState::Error(e);
+                return;
+            }
+        }
+    }
+
+    if let Some(job) = jobs.write().await.get_mut(&job_id) {
+        job.processed_secs = job.total_secs;
+        job.state = JobState::Completed;
+    }
+    tracing::info!(job_id = %job_id, chunks = chunk_count, "Transcription job completed");
+}
+
+/// Validate a WAV file as 16kHz mono PCM16 and return its sample data
+fn parse_wav_pcm16(wav: &[u8]) -> Result<&[u8], String> {
+    if wav.len() < 12 || &wav[0..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return Err("not a WAV file".to_string());
+    }
+
+    let mut format_ok = false;
+    let mut offset = 12;
+    while offset + 8 <= wav.len() {
+        let id = &wav[offset..offset + 4];
+        let size = u32::from_le_bytes(wav[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let body_start = offset + 8;
+        let body_end = body_start.saturating_add(size).min(wav.len());
+        let body = &wav[body_start..body_end];
+
+        match id {
+            b"fmt " => {
+                if body.len() < 16 {
+                    return Err("truncated fmt chunk".to_string());
+                }
+                let audio_format = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]);
+                let sample_rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                let bits_per_sample = u16::from_le_bytes([body[14], body[15]]);
+                if audio_format != 1
+                    || channels != 1
+                    || sample_rate != SAMPLE_RATE
+                    || bits_per_sample != 16
+                {
+                    return Err(format!(
+                        "expected {}Hz mono 16-bit PCM, got format {} with {} channel(s) at {}Hz, {}-bit",
+                        SAMPLE_RATE, audio_format, channels, sample_rate, bits_per_sample
+                    ));
+                }
+                format_ok = true;
+            }
+            b"data" => {
+                if !format_ok {
+                    return Err("data chunk before fmt chunk".to_string());
+                }
+                // Drop a trailing odd byte so samples stay aligned across parts
+                return Ok(&body[..body.len() & !1]);
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        offset = body_start + size + (size & 1);
+    }
+
+    Err("missing data chunk".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::whisper::pcm16_to_wav;
+
+    #[test]
+    fn test_parse_wav_pcm16() {
+        let pcm = vec![1u8; 640];
+        let wav = pcm16_to_wav(&pcm, SAMPLE_RATE);
+        assert_eq!(parse_wav_pcm16(&wav).unwrap(), &pcm[..]);
+
+        let wrong_rate = pcm16_to_wav(&pcm, 44100);
+        assert!(parse_wav_pcm16(&wrong_rate).unwrap_err().contains("44100Hz"));
+        assert!(parse_wav_pcm16(b"not audio").is_err());
+    }
+
+    #[test]
+    fn test_job_status_progress() {
+        let mut job = TranscriptionJob {
+            username: "alice".to_string(),
+            created_at: Instant::now(),
+            parts: 2,
+            total_secs: 120.0,
+            processed_secs: 30.0,
+            chunks: vec![" Hello there.".to_string(), "".to_string()],
+            state: JobState::Processing,
+        };
+        let json = serde_json::to_value(JobStatusResponse::from(&job)).unwrap();
+        assert_eq!(json["status"], "processing");
+        assert_eq!(json["progress_percent"], 25.0);
+        assert_eq!(json["partial_transcript"], "Hello there.");
+
+        job.chunks.push("General Kenobi.".to_string());
+        job.state = JobState::Completed;
+        let json = serde_json::to_value(JobStatusResponse::from(&job)).unwrap();
+        assert_eq!(json["status"], "completed");
+        assert_eq!(json["transcript"], "Hello there. General Kenobi.");
+    }
+}
@@ -0,0 +1,95 @@
+//! Address the HTTP server listens on.
+//!
+//! `BIND_ADDR` (default `0.0.0.0`) and `PORT` (default `3000`) let the service run next to
+//! others on the host network, or on a port above 1024 without root, without rebuilding
+//! the image.
+//!
+//! Kept identical in `text-to-speech` and `speech-to-text`: each service is built as its own
+//! Docker context, so a shared path dependency wouldn't be visible to the image builds.
+//! Change both copies together.
+
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::TcpListener;
+
+const DEFAULT_PORT: u16 = 3000;
+
+/// Parse `BIND_ADDR`, an IPv4 or IPv6 address, and `PORT`
+pub fn parse_listen_addr(
+    bind_addr: Option<&str>,
+    port: Option<&str>,
+) -> Result<SocketAddr, String> {
+    let ip = match bind_addr.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid BIND_ADDR '{}', expected an IP address", v))?,
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let port = match port.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .parse::<u16>()
+            .ok()
+            .filter(|&p| p > 0)
+            .ok_or_else(|| format!("Invalid PORT '{}', expected 1 to 65535", v))?,
+        None => DEFAULT_PORT,
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Listen on `addr`, exiting with an explanation if that isn't possible
+pub async fn bind(addr: SocketAddr) -> TcpListener {
+    match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            let message = bind_error(addr, &e);
+            tracing::error!(%addr, error = %e, "{}", message);
+            panic!("{}", message);
+        }
+    }
+}
+
+fn bind_error(addr: SocketAddr, e: &std::io::Error) -> String {
+    let hint = match e.kind() {
+        ErrorKind::AddrInUse => "; another process is using the port, set PORT to a free one",
+        ErrorKind::PermissionDenied => {
+            "; ports below 1024 need root or CAP_NET_BIND_SERVICE, set PORT to a higher one"
+        }
+        ErrorKind::AddrNotAvailable => "; BIND_ADDR isn't an address of this host",
+        _ => "",
+    };
+    format!("Failed to listen on {}: {}{}", addr, e, hint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            parse_listen_addr(None, None),
+            Ok("0.0.0.0:3000".parse().unwrap())
+        );
+        assert_eq!(
+            parse_listen_addr(Some("127.0.0.1"), Some(" 8081 ")),
+            Ok("127.0.0.1:8081".parse().unwrap())
+        );
+        assert_eq!(
+            parse_listen_addr(Some("[::]"), None),
+            Ok("[::]:3000".parse().unwrap())
+        );
+        assert!(parse_listen_addr(Some("localhost"), None).is_err());
+        assert!(parse_listen_addr(None, Some("0")).is_err());
+        assert!(parse_listen_addr(None, Some("70000")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_error() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap();
+        let e = TcpListener::bind(addr).await.unwrap_err();
+        assert!(bind_error(addr, &e).contains("set PORT to a free one"));
+    }
+}
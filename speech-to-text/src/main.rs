@@ -1,15 +1,30 @@
 mod auth;
+mod close;
+mod home_assistant;
+mod jobs;
+mod listen;
+mod scheduler;
 mod state;
 mod transcribe;
 mod vad;
+mod whisper;
 
 use state::{AppState, JwksCache};
 
-use axum::{Router, routing::get};
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{get, post},
+};
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, watch};
 use tower_http::cors::{Any, CorsLayer};
 
+/// Upload limit for batch jobs; an hour of 16kHz mono PCM16 is ~115MB
+const MAX_JOB_UPLOAD_BYTES: usize = 512 * 1024 * 1024;
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
@@ -21,6 +36,22 @@ async fn main() {
         std::env::var("KEYCLOAK_AUDIENCE").unwrap_or_else(|_| "stt".to_string());
     let whisper_url = std::env::var("WHISPER_URL")
         .unwrap_or_else(|_| "http://localhost:8000".to_string());
+    let whisper_schema = std::env::var("WHISPER_SCHEMA")
+        .unwrap_or_else(|_| "custom".to_string())
+        .parse()
+        .expect("Invalid WHISPER_SCHEMA");
+    let whisper_max_concurrency = std::env::var("WHISPER_MAX_CONCURRENCY")
+        .unwrap_or_else(|_| "2".to_string())
+        .parse()
+        .expect("Invalid WHISPER_MAX_CONCURRENCY");
+    let listen_addr = listen::parse_listen_addr(
+        std::env::var("BIND_ADDR").ok().as_deref(),
+        std::env::var("PORT").ok().as_deref(),
+    )
+    .expect("Invalid BIND_ADDR or PORT");
+
+    // Signals open WebSocket sessions to close with reconnect guidance on shutdown
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
     let state = AppState {
         jwks_cache: Arc::new(RwLock::new(JwksCache::default())),
@@ -28,23 +59,77 @@ async fn main() {
         keycloak_realm,
         keycloak_audience,
         whisper_url,
+        whisper_schema,
+        shutdown: shutdown_rx,
+        jobs: Arc::new(RwLock::new(HashMap::new())),
+        scheduler: scheduler::Scheduler::new(whisper_max_concurrency),
+        entity_prompt: Arc::new(RwLock::new(None)),
     };
 
+    match home_assistant::HomeAssistantConfig::from_env() {
+        Some(config) => {
+            tracing::info!(url = %config.url, "Biasing live transcription with Home Assistant entity names");
+            home_assistant::spawn_refresh(config, Arc::clone(&state.entity_prompt));
+        }
+        None => tracing::info!("Home Assistant integration not configured"),
+    }
+
     // CORS configuration for WebSocket
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
 
+    // Batch jobs for long recordings, authenticated with a bearer token
+    let job_routes = Router::new()
+        .route("/jobs", post(jobs::create_job))
+        .route("/jobs/:id", get(jobs::job_status))
+        .layer(DefaultBodyLimit::max(MAX_JOB_UPLOAD_BYTES))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::auth_middleware,
+        ));
+
     let app = Router::new()
         .route("/transcribe", get(transcribe::ws_handler))
         .route("/health", get(health_check))
+        .route("/metrics", get(scheduler::metrics))
+        .merge(job_routes)
         .layer(cors)
         .with_state(state);
 
-    tracing::info!("Starting Speech-to-Text server on 0.0.0.0:3000");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    tracing::info!("Starting Speech-to-Text server on {}", listen_addr);
+    let listener = listen::bind(listen_addr).await;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            tracing::info!("Shutdown signal received, closing WebSocket sessions");
+            let _ = shutdown_tx.send(true);
+            // Upgraded WebSocket connections aren't tracked by graceful shutdown,
+            // so give sessions a moment to flush their close frames
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        })
+        .await
+        .unwrap();
+}
+
+/// Resolves on Ctrl+C or SIGTERM (sent by Kubernetes when stopping the pod)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
 async fn health_check() -> &'static str {
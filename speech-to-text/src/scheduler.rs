@@ -0,0 +1,341 @@
+//! Priority scheduling of requests to the Whisper backend.
+//!
+//! The backend (usually a single GPU) can only usefully run a few transcriptions
+//! at once. All sessions share one scheduler that caps concurrent backend requests;
+//! when it is saturated, waiting segments are granted a slot by class rather than
+//! arrival order, so a user waiting on a final transcript or a short command isn't
+//! stuck behind someone's long dictation chunks. Per-class counts and latencies are
+//! exported at `GET /metrics` in Prometheus text format.
+
+use crate::state::AppState;
+use axum::{extract::State, http::header, response::IntoResponse};
+use std::collections::BinaryHeap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Interim segments up to this long are treated as short commands
+const COMMAND_MAX_SECS: f32 = 3.0;
+
+/// Upper bounds (seconds) of the latency histogram buckets
+const LATENCY_BUCKETS: &[f64] = &[0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0];
+
+/// Priority class of a backend request, highest priority first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SegmentClass {
+    /// Last segment of a recording; the user is waiting on it
+    Final = 3,
+    /// Short interim segment, typically a spoken command
+    Command = 2,
+    /// Long interim segment of continuous dictation
+    Dictation = 1,
+    /// Chunk of a batch job for a long recording
+    Batch = 0,
+}
+
+impl SegmentClass {
+    pub const ALL: [SegmentClass; 4] = [Self::Final, Self::Command, Self::Dictation, Self::Batch];
+
+    /// Classify a live segment of 16kHz PCM16 audio
+    pub fn for_segment(len_bytes: usize, is_final: bool) -> Self {
+        let secs = len_bytes as f32 / (16000.0 * 2.0);
+        if is_final {
+            Self::Final
+        } else if secs <= COMMAND_MAX_SECS {
+            Self::Command
+        } else {
+            Self::Dictation
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Final => "final",
+            Self::Command => "command",
+            Self::Dictation => "dictation",
+            Self::Batch => "batch",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A request waiting for a backend slot
+struct Waiter {
+    class: SegmentClass,
+    /// Arrival order, so equal classes are served first come first served
+    seq: u64,
+    grant: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.class == other.class && self.seq == other.seq
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Max-heap: higher class first, then lower sequence number
+        self.class
+            .cmp(&other.class)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default, Clone)]
+struct ClassMetrics {
+    requests: u64,
+    failures: u64,
+    queue_seconds_sum: f64,
+    latency_seconds_sum: f64,
+    /// Cumulative counts per `LATENCY_BUCKETS` entry
+    latency_buckets: Vec<u64>,
+}
+
+struct Inner {
+    available: usize,
+    next_seq: u64,
+    waiting: BinaryHeap<Waiter>,
+    metrics: [ClassMetrics; 4],
+}
+
+/// Shared limiter for concurrent Whisper requests
+pub struct Scheduler {
+    max_concurrency: usize,
+    inner: Mutex<Inner>,
+}
+
+/// A held backend slot; released on drop
+pub struct Permit {
+    scheduler: Arc<Scheduler>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+/// A queued `acquire`. If the caller gives up after being granted a slot but
+/// before taking it, the slot is passed on instead of being lost.
+struct PendingGrant {
+    rx: oneshot::Receiver<()>,
+    scheduler: Arc<Scheduler>,
+}
+
+impl Drop for PendingGrant {
+    fn drop(&mut self) {
+        if self.rx.try_recv().is_ok() {
+            self.scheduler.release();
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new(max_concurrency: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_concurrency: max_concurrency.max(1),
+            inner: Mutex::new(Inner {
+                available: max_concurrency.max(1),
+                next_seq: 0,
+                waiting: BinaryHeap::new(),
+                metrics: std::array::from_fn(|_| ClassMetrics {
+                    latency_buckets: vec![0; LATENCY_BUCKETS.len()],
+                    ..Default::default()
+                }),
+            }),
+        })
+    }
+
+    /// Wait for a backend slot. Higher classes are granted first when saturated.
+    pub async fn acquire(self: &Arc<Self>, class: SegmentClass) -> Permit {
+        let rx = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.available > 0 && inner.waiting.is_empty() {
+                inner.available -= 1;
+                None
+            } else {
+                let (tx, rx) = oneshot::channel();
+                let seq = inner.next_seq;
+                inner.next_seq += 1;
+                inner.waiting.push(Waiter {
+                    class,
+                    seq,
+                    grant: tx,
+                });
+                Some(rx)
+            }
+        };
+
+        if let Some(rx) = rx {
+            let mut pending = PendingGrant {
+                rx,
+                scheduler: Arc::clone(self),
+            };
+            // The sender is only dropped after a send, so this can't fail
+            let _ = (&mut pending.rx).await;
+        }
+        Permit {
+            scheduler: Arc::clone(self),
+        }
+    }
+
+    /// Hand a freed slot to the highest-priority waiter that is still waiting
+    fn release(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        while let Some(waiter) = inner.waiting.pop() {
+            if waiter.grant.send(()).is_ok() {
+                return;
+            }
+            // Waiter gave up (session closed); try the next one
+        }
+        inner.available += 1;
+    }
+
+    /// Record a finished backend request
+    pub fn record(&self, class: SegmentClass, queued: Duration, latency: Duration, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let metrics = &mut inner.metrics[class.index()];
+        metrics.requests += 1;
+        if !success {
+            metrics.failures += 1;
+        }
+        metrics.queue_seconds_sum += queued.as_secs_f64();
+        let latency = latency.as_secs_f64();
+        metrics.latency_seconds_sum += latency;
+        for (count, bound) in metrics.latency_buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if latency <= *bound {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Metrics in Prometheus text exposition format
+    pub fn render_metrics(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP stt_backend_max_concurrency Maximum concurrent Whisper requests");
+        let _ = writeln!(out, "# TYPE stt_backend_max_concurrency gauge");
+        let _ = writeln!(out, "stt_backend_max_concurrency {}", self.max_concurrency);
+        let _ = writeln!(out, "# HELP stt_backend_in_flight Whisper requests currently running");
+        let _ = writeln!(out, "# TYPE stt_backend_in_flight gauge");
+        let _ = writeln!(out, "stt_backend_in_flight {}", self.max_concurrency - inner.available);
+
+        let mut waiting = [0usize; 4];
+        for waiter in &inner.waiting {
+            waiting[waiter.class.index()] += 1;
+        }
+        let _ = writeln!(out, "# HELP stt_segments_waiting Segments waiting for a backend slot");
+        let _ = writeln!(out, "# TYPE stt_segments_waiting gauge");
+        for class in SegmentClass::ALL {
+            let _ = writeln!(out, "stt_segments_waiting{{class=\"{}\"}} {}", class.as_str(), waiting[class.index()]);
+        }
+
+        let _ = writeln!(out, "# HELP stt_segment_failures_total Segments whose transcription failed");
+        let _ = writeln!(out, "# TYPE stt_segment_failures_total counter");
+        for class in SegmentClass::ALL {
+            let m = &inner.metrics[class.index()];
+            let _ = writeln!(out, "stt_segment_failures_total{{class=\"{}\"}} {}", class.as_str(), m.failures);
+        }
+
+        let _ = writeln!(out, "# HELP stt_segment_queue_seconds Time spent waiting for a backend slot");
+        let _ = writeln!(out, "# TYPE stt_segment_queue_seconds summary");
+        for class in SegmentClass::ALL {
+            let m = &inner.metrics[class.index()];
+            let _ = writeln!(out, "stt_segment_queue_seconds_sum{{class=\"{}\"}} {}", class.as_str(), m.queue_seconds_sum);
+            let _ = writeln!(out, "stt_segment_queue_seconds_count{{class=\"{}\"}} {}", class.as_str(), m.requests);
+        }
+
+        let _ = writeln!(out, "# HELP stt_segment_latency_seconds Time from segment ready to transcript, including queueing");
+        let _ = writeln!(out, "# TYPE stt_segment_latency_seconds histogram");
+        for class in SegmentClass::ALL {
+            let m = &inner.metrics[class.index()];
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&m.latency_buckets) {
+                let _ = writeln!(
+                    out,
+                    "stt_segment_latency_seconds_bucket{{class=\"{}\",le=\"{}\"}} {}",
+                    class.as_str(),
+                    bound,
+                    count
+                );
+            }
+            let _ = writeln!(out, "stt_segment_latency_seconds_bucket{{class=\"{}\",le=\"+Inf\"}} {}", class.as_str(), m.requests);
+            let _ = writeln!(out, "stt_segment_latency_seconds_sum{{class=\"{}\"}} {}", class.as_str(), m.latency_seconds_sum);
+            let _ = writeln!(out, "stt_segment_latency_seconds_count{{class=\"{}\"}} {}", class.as_str(), m.requests);
+        }
+
+        out
+    }
+}
+
+/// `GET /metrics`
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.scheduler.render_metrics(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_class() {
+        assert_eq!(SegmentClass::for_segment(32000 * 20, true), SegmentClass::Final);
+        assert_eq!(SegmentClass::for_segment(32000 * 2, false), SegmentClass::Command);
+        assert_eq!(SegmentClass::for_segment(32000 * 8, false), SegmentClass::Dictation);
+    }
+
+    #[tokio::test]
+    async fn test_saturated_scheduler_grants_by_priority() {
+        let scheduler = Scheduler::new(1);
+        let held = scheduler.acquire(SegmentClass::Dictation).await;
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for class in [SegmentClass::Batch, SegmentClass::Dictation, SegmentClass::Final, SegmentClass::Command] {
+            let scheduler = Arc::clone(&scheduler);
+            let order = Arc::clone(&order);
+            tasks.push(tokio::spawn(async move {
+                let _permit = scheduler.acquire(class).await;
+                order.lock().unwrap().push(class);
+            }));
+            // Let each task enqueue before the next
+            tokio::task::yield_now().await;
+        }
+
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            [SegmentClass::Final, SegmentClass::Command, SegmentClass::Dictation, SegmentClass::Batch]
+        );
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let scheduler = Scheduler::new(2);
+        scheduler.record(SegmentClass::Final, Duration::from_millis(100), Duration::from_millis(800), true);
+        let text = scheduler.render_metrics();
+        assert!(text.contains("stt_backend_max_concurrency 2"));
+        assert!(text.contains("stt_segment_latency_seconds_bucket{class=\"final\",le=\"0.5\"} 0"));
+        assert!(text.contains("stt_segment_latency_seconds_bucket{class=\"final\",le=\"1\"} 1"));
+        assert!(text.contains("stt_segment_latency_seconds_count{class=\"final\"} 1"));
+    }
+}
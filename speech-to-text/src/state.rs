@@ -1,7 +1,11 @@
+use crate::home_assistant::EntityPrompt;
+use crate::jobs::JobStore;
+use crate::scheduler::Scheduler;
+use crate::whisper::WhisperSchema;
 use jsonwebtoken::DecodingKey;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, watch};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -10,6 +14,15 @@ pub struct AppState {
     pub keycloak_realm: String,
     pub keycloak_audience: String,
     pub whisper_url: String,
+    pub whisper_schema: WhisperSchema,
+    /// Flips to `true` when the server starts shutting down
+    pub shutdown: watch::Receiver<bool>,
+    /// Batch transcription jobs for long recordings
+    pub jobs: JobStore,
+    /// Shared priority limiter for Whisper backend requests
+    pub scheduler: Arc<Scheduler>,
+    /// Home Assistant entity names used to bias live transcription
+    pub entity_prompt: EntityPrompt,
 }
 
 #[derive(Default)]
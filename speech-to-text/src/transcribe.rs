@@ -4,8 +4,12 @@
 //! using VAD-based segmentation and double buffering for continuous streaming.
 
 use crate::auth::{extract_token_from_query, validate_ws_token};
+use crate::close::CloseReason;
+use crate::home_assistant::EntityPrompt;
+use crate::scheduler::{Scheduler, SegmentClass};
 use crate::state::AppState;
-use crate::vad::{VadConfig, VadEvent, VadState};
+use crate::vad::{Calibration, VadConfig, VadEvent, VadState};
+use crate::whisper::WhisperClient;
 use axum::{
     extract::{
         State, WebSocketUpgrade,
@@ -14,34 +18,12 @@ use axum::{
     http::Uri,
     response::Response,
 };
-use futures_util::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
+use serde::Serialize;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::mpsc;
 
-/// Request to Whisper transcription API
-#[derive(Debug, Serialize)]
-struct WhisperRequest {
-    audio: String, // base64 encoded PCM16 audio
-    language: String,
-}
-
-/// Response from Whisper transcription API
-#[derive(Debug, Deserialize)]
-struct WhisperResponse {
-    text: String,
-    #[serde(default)]
-    segments: Vec<TranscriptSegment>,
-}
-
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct TranscriptSegment {
-    start: f32,
-    end: f32,
-    text: String,
-}
-
 /// Message sent to browser client
 #[derive(Debug, Serialize)]
 struct ClientMessage {
@@ -53,6 +35,14 @@ struct ClientMessage {
     error: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     is_final: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noise_floor: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    energy_threshold: Option<f32>,
 }
 
 impl ClientMessage {
@@ -62,6 +52,10 @@ impl ClientMessage {
             text: Some(text),
             error: None,
             is_final: Some(is_final),
+            reason: None,
+            retry_after_ms: None,
+            noise_floor: None,
+            energy_threshold: None,
         }
     }
 
@@ -71,6 +65,10 @@ impl ClientMessage {
             text: None,
             error: Some(msg),
             is_final: None,
+            reason: None,
+            retry_after_ms: None,
+            noise_floor: None,
+            energy_threshold: None,
         }
     }
 
@@ -80,16 +78,54 @@ impl ClientMessage {
             text: None,
             error: None,
             is_final: None,
+            reason: None,
+            retry_after_ms: None,
+            noise_floor: None,
+            energy_threshold: None,
+        }
+    }
+
+    fn closing(reason: CloseReason) -> Self {
+        Self {
+            msg_type: "closing".to_string(),
+            text: None,
+            error: None,
+            is_final: None,
+            reason: Some(reason.as_str().to_string()),
+            retry_after_ms: reason.retry_after_ms(),
+            noise_floor: None,
+            energy_threshold: None,
+        }
+    }
+
+    fn calibrated(calibration: Calibration) -> Self {
+        Self {
+            msg_type: "calibrated".to_string(),
+            text: None,
+            error: None,
+            is_final: None,
+            reason: None,
+            retry_after_ms: None,
+            noise_floor: Some(calibration.noise_floor),
+            energy_threshold: Some(calibration.energy_threshold),
         }
     }
 }
 
+/// Consecutive Whisper failures after which the session is closed with `BackendUnavailable`
+const MAX_CONSECUTIVE_BACKEND_FAILURES: u32 = 3;
+
+/// Upper bound on the requested noise calibration period
+const MAX_CALIBRATION_SECS: f32 = 10.0;
+
 /// Segment of audio to be transcribed
 struct AudioSegment {
     /// PCM16 audio data
     data: Vec<u8>,
     /// Whether this is the final segment (recording stopped)
     is_final: bool,
+    /// When the segment was cut, for latency metrics
+    queued_at: Instant,
 }
 
 /// WebSocket upgrade handler
@@ -100,12 +136,37 @@ pub async fn ws_handler(
 ) -> Response {
     // Extract token from query string
     let token = extract_token_from_query(uri.query());
+    let calibration_secs = calibration_secs_from_query(uri.query());
 
-    ws.on_upgrade(move |socket| handle_socket(socket, state, token))
+    ws.on_upgrade(move |socket| handle_socket(socket, state, token, calibration_secs))
+}
+
+/// Optional `calibrate_secs` query parameter: how much audio at the start of the
+/// session to treat as ambient noise for picking the VAD threshold
+fn calibration_secs_from_query(query: Option<&str>) -> f32 {
+    query
+        .and_then(|q| {
+            q.split('&').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key == "calibrate_secs" {
+                    value.parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+        })
+        .filter(|secs| secs.is_finite())
+        .unwrap_or(0.0)
+        .clamp(0.0, MAX_CALIBRATION_SECS)
 }
 
 /// Handle the WebSocket connection
-async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>) {
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    token: Option<String>,
+    calibration_secs: f32,
+) {
     // Validate authentication
     let token = match token {
         Some(t) => t,
@@ -113,8 +174,9 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
             let (mut sender, _) = socket.split();
             let msg = ClientMessage::error("Missing authentication token".to_string());
             let _ = sender
-                .send(Message::Text(serde_json::to_string(&msg).unwrap().into()))
+                .send(Message::Text(serde_json::to_string(&msg).unwrap()))
                 .await;
+            send_close(&mut sender, CloseReason::AuthFailed).await;
             return;
         }
     };
@@ -125,8 +187,9 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
             let (mut sender, _) = socket.split();
             let msg = ClientMessage::error(format!("Authentication failed: {}", e));
             let _ = sender
-                .send(Message::Text(serde_json::to_string(&msg).unwrap().into()))
+                .send(Message::Text(serde_json::to_string(&msg).unwrap()))
                 .await;
+            send_close(&mut sender, CloseReason::AuthFailed).await;
             return;
         }
     };
@@ -141,7 +204,7 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
         let mut sink = client_sink.lock().await;
         let connected_msg = ClientMessage::connected();
         if let Err(e) = sink
-            .send(Message::Text(serde_json::to_string(&connected_msg).unwrap().into()))
+            .send(Message::Text(serde_json::to_string(&connected_msg).unwrap()))
             .await
         {
             tracing::error!(error = %e, "Failed to send connected message to client");
@@ -154,16 +217,23 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
 
     // Spawn transcription background task
     let transcription_sink = Arc::clone(&client_sink);
-    let whisper_url = format!("{}/transcribe", state.whisper_url);
-    let http_client = reqwest::Client::new();
-    
-    let transcription_task = tokio::spawn(async move {
-        transcription_worker(segment_rx, transcription_sink, http_client, whisper_url).await;
+    let whisper = WhisperClient::new(
+        reqwest::Client::new(),
+        state.whisper_url.clone(),
+        state.whisper_schema,
+    );
+
+    let scheduler = Arc::clone(&state.scheduler);
+    let entity_prompt = Arc::clone(&state.entity_prompt);
+
+    let mut transcription_task = tokio::spawn(async move {
+        transcription_worker(segment_rx, transcription_sink, whisper, scheduler, entity_prompt).await;
     });
 
-    // VAD configuration
+    // VAD configuration, optionally calibrated against the first few seconds of audio
     let vad_config = VadConfig::default();
-    let mut vad = VadState::new(vad_config);
+    let calibration_samples = (calibration_secs * 16000.0) as usize;
+    let mut vad = VadState::with_calibration(vad_config, calibration_samples);
 
     // Double buffer: active buffer collects audio, ready buffer is sent for transcription
     let mut active_buffer: Vec<u8> = Vec::new();
@@ -172,8 +242,29 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
     const OVERLAP_SAMPLES: usize = 16000 / 2; // 0.5s at 16kHz
     const OVERLAP_BYTES: usize = OVERLAP_SAMPLES * 2; // PCM16 = 2 bytes per sample
 
+    let mut shutdown_rx = state.shutdown.clone();
+    let mut worker_finished = false;
+
     // Process messages from client
-    while let Some(msg) = client_stream.next().await {
+    loop {
+        let msg = tokio::select! {
+            msg = client_stream.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = shutdown_rx.changed() => {
+                tracing::info!(user = %user.username, "Server shutting down, closing WebSocket");
+                send_close(&mut *client_sink.lock().await, CloseReason::ServerShutdown).await;
+                transcription_task.abort();
+                break;
+            }
+            _ = &mut transcription_task => {
+                // Worker only exits early after closing the connection itself
+                worker_finished = true;
+                break;
+            }
+        };
+
         match msg {
             Ok(Message::Text(text)) => {
                 // Client sends JSON with audio data or control signals
@@ -192,6 +283,9 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
 
                             // Process through VAD
                             let event = vad.process(&samples);
+                            if event == VadEvent::CalibrationDone {
+                                send_calibrated(&client_sink, &vad).await;
+                            }
 
                             // Always add to active buffer while speaking or during grace period
                             if event == VadEvent::Speaking 
@@ -203,23 +297,24 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
 
                             // Check if we should send for transcription
                             match event {
-                                VadEvent::SpeechEnded | VadEvent::MaxDurationReached => {
-                                    if !active_buffer.is_empty() {
-                                        let segment = AudioSegment {
-                                            data: active_buffer.clone(),
-                                            is_final: false,
-                                        };
-
-                                        if let Err(e) = segment_tx.send(segment).await {
-                                            tracing::error!(error = %e, "Failed to send segment for transcription");
-                                        }
-
-                                        // Keep overlap for context
-                                        if active_buffer.len() > OVERLAP_BYTES {
-                                            active_buffer = active_buffer.split_off(active_buffer.len() - OVERLAP_BYTES);
-                                        } else {
-                                            active_buffer.clear();
-                                        }
+                                VadEvent::SpeechEnded | VadEvent::MaxDurationReached
+                                    if !active_buffer.is_empty() =>
+                                {
+                                    let segment = AudioSegment {
+                                        data: active_buffer.clone(),
+                                        is_final: false,
+                                        queued_at: Instant::now(),
+                                    };
+
+                                    if let Err(e) = segment_tx.send(segment).await {
+                                        tracing::error!(error = %e, "Failed to send segment for transcription");
+                                    }
+
+                                    // Keep overlap for context
+                                    if active_buffer.len() > OVERLAP_BYTES {
+                                        active_buffer = active_buffer.split_off(active_buffer.len() - OVERLAP_BYTES);
+                                    } else {
+                                        active_buffer.clear();
                                     }
                                 }
                                 _ => {}
@@ -232,6 +327,7 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
                             let segment = AudioSegment {
                                 data: std::mem::take(&mut active_buffer),
                                 is_final: true,
+                                queued_at: Instant::now(),
                             };
 
                             if let Err(e) = segment_tx.send(segment).await {
@@ -250,6 +346,9 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
                     .collect();
 
                 let event = vad.process(&samples);
+                if event == VadEvent::CalibrationDone {
+                    send_calibrated(&client_sink, &vad).await;
+                }
 
                 if event == VadEvent::Speaking 
                     || event == VadEvent::SpeechEnded 
@@ -258,22 +357,23 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
                     active_buffer.extend(data.to_vec());
                 }
 
-                if matches!(event, VadEvent::SpeechEnded | VadEvent::MaxDurationReached) {
-                    if !active_buffer.is_empty() {
-                        let segment = AudioSegment {
-                            data: active_buffer.clone(),
-                            is_final: false,
-                        };
-
-                        if let Err(e) = segment_tx.send(segment).await {
-                            tracing::error!(error = %e, "Failed to send segment");
-                        }
+                if matches!(event, VadEvent::SpeechEnded | VadEvent::MaxDurationReached)
+                    && !active_buffer.is_empty()
+                {
+                    let segment = AudioSegment {
+                        data: active_buffer.clone(),
+                        is_final: false,
+                        queued_at: Instant::now(),
+                    };
+
+                    if let Err(e) = segment_tx.send(segment).await {
+                        tracing::error!(error = %e, "Failed to send segment");
+                    }
 
-                        if active_buffer.len() > OVERLAP_BYTES {
-                            active_buffer = active_buffer.split_off(active_buffer.len() - OVERLAP_BYTES);
-                        } else {
-                            active_buffer.clear();
-                        }
+                    if active_buffer.len() > OVERLAP_BYTES {
+                        active_buffer = active_buffer.split_off(active_buffer.len() - OVERLAP_BYTES);
+                    } else {
+                        active_buffer.clear();
                     }
                 }
             }
@@ -291,7 +391,9 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
 
     // Clean up
     drop(segment_tx);
-    let _ = transcription_task.await;
+    if !worker_finished {
+        let _ = transcription_task.await;
+    }
 
     tracing::info!(user = %user.username, "WebSocket session ended");
 }
@@ -299,68 +401,117 @@ async fn handle_socket(socket: WebSocket, state: AppState, token: Option<String>
 /// Background worker that processes audio segments and sends transcriptions
 async fn transcription_worker(
     mut segment_rx: mpsc::Receiver<AudioSegment>,
-    client_sink: Arc<tokio::sync::Mutex<futures_util::stream::SplitSink<WebSocket, Message>>>,
-    http_client: reqwest::Client,
-    whisper_url: String,
+    client_sink: Arc<tokio::sync::Mutex<SplitSink<WebSocket, Message>>>,
+    whisper: WhisperClient,
+    scheduler: Arc<Scheduler>,
+    entity_prompt: EntityPrompt,
 ) {
-    while let Some(segment) = segment_rx.recv().await {
-        let audio_b64 = base64::Engine::encode(
-            &base64::engine::general_purpose::STANDARD,
-            &segment.data,
-        );
-
-        let request = WhisperRequest {
-            audio: audio_b64,
-            language: "en".to_string(),
-        };
+    let mut consecutive_failures = 0;
 
+    while let Some(segment) = segment_rx.recv().await {
+        let class = SegmentClass::for_segment(segment.data.len(), segment.is_final);
+        let permit = scheduler.acquire(class).await;
+        let queued = segment.queued_at.elapsed();
         tracing::info!(
             size_bytes = segment.data.len(),
             is_final = segment.is_final,
+            class = class.as_str(),
+            queued_ms = queued.as_millis() as u64,
             "Sending segment to Whisper"
         );
 
-        match http_client.post(&whisper_url).json(&request).send().await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    match response.json::<WhisperResponse>().await {
-                        Ok(whisper_response) => {
-                            let text = whisper_response.text.trim().to_string();
-                            if !text.is_empty() {
-                                let msg = ClientMessage::transcript(text.clone(), segment.is_final);
-                                let mut sink = client_sink.lock().await;
-                                if let Err(e) = sink
-                                    .send(Message::Text(serde_json::to_string(&msg).unwrap().into()))
-                                    .await
-                                {
-                                    tracing::error!(error = %e, "Failed to send transcript to client");
-                                    break;
-                                }
-                                tracing::info!(
-                                    text = %text,
-                                    is_final = segment.is_final,
-                                    "Transcript sent to client"
-                                );
-                            }
-                        }
-                        Err(e) => {
-                            tracing::error!(error = %e, "Failed to parse Whisper response");
-                        }
+        // Read per segment so a session picks up refreshed entity names
+        let prompt = entity_prompt.read().await.clone();
+        let result = whisper.transcribe(&segment.data, "en", prompt.as_deref()).await;
+        drop(permit);
+        scheduler.record(class, queued, segment.queued_at.elapsed(), result.is_ok());
+
+        match result {
+            Ok(transcription) => {
+                consecutive_failures = 0;
+                let text = transcription.text.trim().to_string();
+                if !text.is_empty() {
+                    let msg = ClientMessage::transcript(text.clone(), segment.is_final);
+                    let mut sink = client_sink.lock().await;
+                    if let Err(e) = sink
+                        .send(Message::Text(serde_json::to_string(&msg).unwrap()))
+                        .await
+                    {
+                        tracing::error!(error = %e, "Failed to send transcript to client");
+                        break;
                     }
-                } else {
-                    tracing::error!(status = %response.status(), "Whisper API error");
+                    tracing::info!(
+                        text = %text,
+                        segments = transcription.segments.len(),
+                        is_final = segment.is_final,
+                        "Transcript sent to client"
+                    );
                 }
             }
             Err(e) => {
-                tracing::error!(error = %e, "Failed to call Whisper API");
+                tracing::error!(error = %e, "Transcription failed");
+                consecutive_failures += 1;
                 let msg = ClientMessage::error(format!("Transcription failed: {}", e));
                 let mut sink = client_sink.lock().await;
                 let _ = sink
-                    .send(Message::Text(serde_json::to_string(&msg).unwrap().into()))
+                    .send(Message::Text(serde_json::to_string(&msg).unwrap()))
                     .await;
+
+                if consecutive_failures >= MAX_CONSECUTIVE_BACKEND_FAILURES {
+                    tracing::warn!(
+                        failures = consecutive_failures,
+                        "Whisper backend unavailable, closing WebSocket"
+                    );
+                    send_close(&mut sink, CloseReason::BackendUnavailable).await;
+                    return;
+                }
             }
         }
     }
 
     tracing::debug!("Transcription worker shutting down");
 }
+
+/// Report the noise calibration result to the client
+async fn send_calibrated(
+    client_sink: &tokio::sync::Mutex<SplitSink<WebSocket, Message>>,
+    vad: &VadState,
+) {
+    let Some(calibration) = vad.calibration() else {
+        return;
+    };
+    tracing::info!(
+        noise_floor = calibration.noise_floor,
+        energy_threshold = calibration.energy_threshold,
+        "VAD calibrated"
+    );
+    let msg = ClientMessage::calibrated(calibration);
+    let _ = client_sink
+        .lock()
+        .await
+        .send(Message::Text(serde_json::to_string(&msg).unwrap()))
+        .await;
+}
+
+/// Send a `closing` message followed by a close frame carrying reconnect guidance
+async fn send_close(sink: &mut SplitSink<WebSocket, Message>, reason: CloseReason) {
+    let msg = ClientMessage::closing(reason);
+    let _ = sink
+        .send(Message::Text(serde_json::to_string(&msg).unwrap()))
+        .await;
+    let _ = sink.send(reason.close_message()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration_secs_from_query() {
+        assert_eq!(calibration_secs_from_query(None), 0.0);
+        assert_eq!(calibration_secs_from_query(Some("token=abc")), 0.0);
+        assert_eq!(calibration_secs_from_query(Some("token=abc&calibrate_secs=1.5")), 1.5);
+        assert_eq!(calibration_secs_from_query(Some("calibrate_secs=600")), MAX_CALIBRATION_SECS);
+        assert_eq!(calibration_secs_from_query(Some("calibrate_secs=-1")), 0.0);
+    }
+}
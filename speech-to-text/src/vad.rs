@@ -44,6 +44,32 @@ pub enum VadEvent {
     SpeechEnded,
     /// Max duration reached, force segment end.
     MaxDurationReached,
+    /// Noise calibration just finished; see [`VadState::calibration`].
+    CalibrationDone,
+}
+
+/// Noise floor of 16kHz audio before any calibration has run
+const DEFAULT_NOISE_FLOOR: f32 = 0.005;
+
+/// Bounds on the calibrated energy threshold, so a silent or very noisy room
+/// can't produce a threshold that never or always triggers
+const MIN_CALIBRATED_THRESHOLD: f32 = 0.002;
+const MAX_CALIBRATED_THRESHOLD: f32 = 0.2;
+
+/// Result of measuring ambient noise at the start of a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    /// Mean RMS energy of the ambient audio
+    pub noise_floor: f32,
+    /// Speech threshold picked from the ambient level
+    pub energy_threshold: f32,
+}
+
+/// Ambient energy collected while calibrating
+#[derive(Debug)]
+struct CalibrationProgress {
+    remaining_samples: usize,
+    energies: Vec<f32>,
 }
 
 /// Voice Activity Detector state machine.
@@ -58,6 +84,10 @@ pub struct VadState {
     silence_start: Option<Instant>,
     /// Running average of energy for adaptive threshold.
     noise_floor: f32,
+    /// Set while the initial noise calibration is still collecting audio.
+    calibrating: Option<CalibrationProgress>,
+    /// Outcome of the noise calibration, once finished.
+    calibration: Option<Calibration>,
 }
 
 impl VadState {
@@ -68,13 +98,60 @@ impl VadState {
             is_speaking: false,
             speech_start: None,
             silence_start: None,
-            noise_floor: 0.005,
+            noise_floor: DEFAULT_NOISE_FLOOR,
+            calibrating: None,
+            calibration: None,
+        }
+    }
+
+    /// Create a VAD that treats the first `calibration_samples` samples as ambient
+    /// noise, and sets its noise floor and energy threshold from them.
+    pub fn with_calibration(config: VadConfig, calibration_samples: usize) -> Self {
+        let mut vad = Self::new(config);
+        if calibration_samples > 0 {
+            vad.calibrating = Some(CalibrationProgress {
+                remaining_samples: calibration_samples,
+                energies: Vec::new(),
+            });
         }
+        vad
     }
 
-    /// Create with default configuration.
-    pub fn with_defaults() -> Self {
-        Self::new(VadConfig::default())
+    /// Result of the noise calibration, once it has finished
+    pub fn calibration(&self) -> Option<Calibration> {
+        self.calibration
+    }
+
+    /// Feed a chunk into an in-progress calibration. Returns `true` once finished.
+    fn calibrate(&mut self, samples: &[i16]) -> bool {
+        let Some(progress) = self.calibrating.as_mut() else {
+            return false;
+        };
+        progress.energies.push(Self::calculate_energy(samples));
+        progress.remaining_samples = progress.remaining_samples.saturating_sub(samples.len());
+        if progress.remaining_samples > 0 {
+            return false;
+        }
+
+        let energies = std::mem::take(&mut progress.energies);
+        self.calibrating = None;
+
+        let mean = energies.iter().sum::<f32>() / energies.len() as f32;
+        let variance =
+            energies.iter().map(|e| (e - mean).powi(2)).sum::<f32>() / energies.len() as f32;
+        // Speech should clear both a multiple of the average noise and its spikes
+        let energy_threshold = (mean * 3.0)
+            .max(mean + 3.0 * variance.sqrt())
+            .clamp(MIN_CALIBRATED_THRESHOLD, MAX_CALIBRATED_THRESHOLD);
+
+        self.noise_floor = mean;
+        self.config.energy_threshold = energy_threshold;
+        self.calibration = Some(Calibration {
+            noise_floor: mean,
+            energy_threshold,
+        });
+        tracing::debug!(noise_floor = %mean, energy_threshold = %energy_threshold, "VAD: Calibration done");
+        true
     }
 
     /// Calculate RMS energy of PCM16 audio samples.
@@ -98,6 +175,15 @@ impl VadState {
     ///
     /// This should be called with each incoming audio chunk (typically 20-100ms of audio).
     pub fn process(&mut self, samples: &[i16]) -> VadEvent {
+        // Audio during calibration is treated as ambient noise, never speech
+        if self.calibrating.is_some() {
+            return if self.calibrate(samples) {
+                VadEvent::CalibrationDone
+            } else {
+                VadEvent::Silence
+            };
+        }
+
         let energy = Self::calculate_energy(samples);
         let now = Instant::now();
 
@@ -175,16 +261,6 @@ impl VadState {
         }
     }
 
-    /// Check if currently in a speech segment.
-    pub fn is_speaking(&self) -> bool {
-        self.is_speaking
-    }
-
-    /// Get the duration of the current speech segment, if any.
-    pub fn speech_duration(&self) -> Option<Duration> {
-        self.speech_start.map(|s| Instant::now().duration_since(s))
-    }
-
     /// Reset the VAD state.
     pub fn reset(&mut self) {
         self.is_speaking = false;
@@ -222,11 +298,30 @@ mod tests {
         // Initial silence
         let silence = vec![0i16; 100];
         assert_eq!(vad.process(&silence), VadEvent::Silence);
-        assert!(!vad.is_speaking());
+        assert!(!vad.is_speaking);
 
         // Speech starts
         let speech = vec![10000i16; 100];
         assert_eq!(vad.process(&speech), VadEvent::Speaking);
-        assert!(vad.is_speaking());
+        assert!(vad.is_speaking);
+    }
+
+    #[test]
+    fn test_calibration_sets_threshold_from_ambient_noise() {
+        let mut vad = VadState::with_calibration(VadConfig::default(), 1600);
+
+        // ~0.03 RMS background hum, louder than the default 0.01 threshold
+        let hum = vec![1000i16; 800];
+        assert_eq!(vad.process(&hum), VadEvent::Silence);
+        assert!(vad.calibration().is_none());
+        assert_eq!(vad.process(&hum), VadEvent::CalibrationDone);
+
+        let calibration = vad.calibration().unwrap();
+        assert!((calibration.noise_floor - 1000.0 / 32768.0).abs() < 1e-4);
+        assert!(calibration.energy_threshold > calibration.noise_floor);
+
+        // The hum no longer counts as speech, but louder audio does
+        assert_eq!(vad.process(&hum), VadEvent::Silence);
+        assert_eq!(vad.process(&vec![10000i16; 800]), VadEvent::Speaking);
     }
 }
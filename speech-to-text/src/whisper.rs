@@ -0,0 +1,277 @@
+//! Client for the Whisper transcription backend.
+//!
+//! Several server APIs are supported so the service can point at whichever
+//! backend is running. The API is selected with `WHISPER_SCHEMA`:
+//! - `custom` (default): the bundled `whisper_server.py`. JSON body with base64
+//!   PCM16 audio, `POST /transcribe`, response `{ text, segments }`.
+//! - `faster-whisper-server`: OpenAI-compatible `POST /v1/audio/transcriptions`,
+//!   multipart WAV upload, `verbose_json` response `{ text, segments, ... }`.
+//! - `whisper-cpp`: whisper.cpp server `POST /inference`, multipart WAV upload,
+//!   response `{ text }` or `{ error }`.
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Sample rate of the PCM16 audio sent by clients
+const SAMPLE_RATE: u32 = 16000;
+
+/// Which transcription server API the backend speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhisperSchema {
+    Custom,
+    FasterWhisperServer,
+    WhisperCpp,
+}
+
+impl FromStr for WhisperSchema {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "custom" => Ok(Self::Custom),
+            "faster-whisper-server" => Ok(Self::FasterWhisperServer),
+            "whisper-cpp" => Ok(Self::WhisperCpp),
+            other => Err(format!(
+                "Unknown Whisper schema '{}' (expected custom, faster-whisper-server or whisper-cpp)",
+                other
+            )),
+        }
+    }
+}
+
+/// Request to the custom Whisper transcription API
+#[derive(Debug, Serialize)]
+struct CustomRequest<'a> {
+    audio: String, // base64 encoded PCM16 audio
+    language: &'a str,
+    /// Vocabulary to bias decoding towards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_prompt: Option<&'a str>,
+}
+
+/// Response from the custom API and faster-whisper-server's `verbose_json` format
+#[derive(Debug, Deserialize)]
+struct SegmentedResponse {
+    text: String,
+    #[serde(default)]
+    segments: Vec<TranscriptSegment>,
+}
+
+/// Response from whisper.cpp server's `json` format
+#[derive(Debug, Deserialize)]
+struct WhisperCppResponse {
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+pub struct TranscriptSegment {
+    pub start: f32,
+    pub end: f32,
+    pub text: String,
+}
+
+/// Transcription result, normalized across backends
+#[derive(Debug)]
+pub struct Transcription {
+    pub text: String,
+    pub segments: Vec<TranscriptSegment>,
+}
+
+/// HTTP client for the configured Whisper backend
+pub struct WhisperClient {
+    http_client: reqwest::Client,
+    base_url: String,
+    schema: WhisperSchema,
+}
+
+impl WhisperClient {
+    pub fn new(http_client: reqwest::Client, base_url: String, schema: WhisperSchema) -> Self {
+        Self {
+            http_client,
+            base_url,
+            schema,
+        }
+    }
+
+    /// Transcribe 16kHz mono PCM16 audio. `prompt` is passed to Whisper as the
+    /// initial prompt, biasing it towards the words it contains.
+    pub async fn transcribe(
+        &self,
+        pcm16: &[u8],
+        language: &str,
+        prompt: Option<&str>,
+    ) -> Result<Transcription, String> {
+        let request = match self.schema {
+            WhisperSchema::Custom => {
+                let audio = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, pcm16);
+                self.http_client
+                    .post(format!("{}/transcribe", self.base_url))
+                    .json(&CustomRequest {
+                        audio,
+                        language,
+                        initial_prompt: prompt,
+                    })
+            }
+            WhisperSchema::FasterWhisperServer => self
+                .http_client
+                .post(format!("{}/v1/audio/transcriptions", self.base_url))
+                .multipart(
+                    with_prompt(wav_form(pcm16)?, prompt)
+                        .text("language", language.to_string())
+                        .text("response_format", "verbose_json"),
+                ),
+            WhisperSchema::WhisperCpp => self
+                .http_client
+                .post(format!("{}/inference", self.base_url))
+                .multipart(
+                    with_prompt(wav_form(pcm16)?, prompt)
+                        .text("language", language.to_string())
+                        .text("response_format", "json"),
+                ),
+        };
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Failed to call Whisper API: {}", e))?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read Whisper response: {}", e))?;
+
+        if !status.is_success() {
+            return Err(format!("Whisper API error ({}): {}", status, body));
+        }
+
+        parse_response(self.schema, &body)
+    }
+}
+
+/// Parse a backend response body into a normalized transcription
+fn parse_response(schema: WhisperSchema, body: &str) -> Result<Transcription, String> {
+    match schema {
+        WhisperSchema::Custom | WhisperSchema::FasterWhisperServer => {
+            let response: SegmentedResponse = serde_json::from_str(body)
+                .map_err(|e| format!("Failed to parse Whisper response: {}", e))?;
+            Ok(Transcription {
+                text: response.text,
+                segments: response.segments,
+            })
+        }
+        WhisperSchema::WhisperCpp => {
+            let response: WhisperCppResponse = serde_json::from_str(body)
+                .map_err(|e| format!("Failed to parse Whisper response: {}", e))?;
+            if let Some(error) = response.error {
+                return Err(format!("whisper.cpp error: {}", error));
+            }
+            Ok(Transcription {
+                text: response.text.unwrap_or_default(),
+                segments: Vec::new(),
+            })
+        }
+    }
+}
+
+/// Multipart form with the audio attached as a WAV file
+fn wav_form(pcm16: &[u8]) -> Result<reqwest::multipart::Form, String> {
+    let part = reqwest::multipart::Part::bytes(pcm16_to_wav(pcm16, SAMPLE_RATE))
+        .file_name("audio.wav")
+        .mime_str("audio/wav")
+        .map_err(|e| format!("Failed to build multipart body: {}", e))?;
+    Ok(reqwest::multipart::Form::new().part("file", part))
+}
+
+/// Add the `prompt` field both multipart APIs use for the initial prompt
+fn with_prompt(form: reqwest::multipart::Form, prompt: Option<&str>) -> reqwest::multipart::Form {
+    match prompt {
+        Some(prompt) => form.text("prompt", prompt.to_string()),
+        None => form,
+    }
+}
+
+/// Wrap mono PCM16 samples in a WAV container
+pub(crate) fn pcm16_to_wav(pcm16: &[u8], sample_rate: u32) -> Vec<u8> {
+    let data_size = pcm16.len() as u32;
+    let mut wav = Vec::with_capacity(44 + pcm16.len());
+    // RIFF header
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    // fmt subchunk
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes()); // subchunk size
+    wav.extend_from_slice(&1u16.to_le_bytes()); // audio format (PCM)
+    wav.extend_from_slice(&1u16.to_le_bytes()); // channels
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    // data subchunk
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    wav.extend_from_slice(pcm16);
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_from_str() {
+        assert_eq!("custom".parse(), Ok(WhisperSchema::Custom));
+        assert_eq!(
+            "faster-whisper-server".parse(),
+            Ok(WhisperSchema::FasterWhisperServer)
+        );
+        assert_eq!("whisper-cpp".parse(), Ok(WhisperSchema::WhisperCpp));
+        assert!("openai".parse::<WhisperSchema>().is_err());
+    }
+
+    #[test]
+    fn test_parse_faster_whisper_server_response() {
+        let body = r#"{
+            "task": "transcribe",
+            "language": "en",
+            "duration": 1.5,
+            "text": " Hello world.",
+            "segments": [
+                {"id": 0, "seek": 0, "start": 0.0, "end": 1.5, "text": " Hello world.",
+                 "tokens": [1, 2], "temperature": 0.0, "avg_logprob": -0.2,
+                 "compression_ratio": 1.0, "no_speech_prob": 0.01}
+            ]
+        }"#;
+        let result = parse_response(WhisperSchema::FasterWhisperServer, body).unwrap();
+        assert_eq!(result.text, " Hello world.");
+        assert_eq!(result.segments.len(), 1);
+        assert_eq!(result.segments[0].end, 1.5);
+    }
+
+    #[test]
+    fn test_parse_whisper_cpp_response() {
+        let result = parse_response(WhisperSchema::WhisperCpp, r#"{"text": " Hi there\n"}"#).unwrap();
+        assert_eq!(result.text, " Hi there\n");
+        assert!(result.segments.is_empty());
+
+        let err = parse_response(WhisperSchema::WhisperCpp, r#"{"error": "failed to read WAV file"}"#)
+            .unwrap_err();
+        assert!(err.contains("failed to read WAV file"));
+    }
+
+    #[test]
+    fn test_pcm16_to_wav_header() {
+        let pcm = vec![0u8; 320];
+        let wav = pcm16_to_wav(&pcm, 16000);
+        assert_eq!(wav.len(), 44 + 320);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[8..12], b"WAVE");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 16000);
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 320);
+    }
+}
@@ -35,6 +35,7 @@ class TranscribeRequest(BaseModel):
     """Request body for transcription."""
     audio: str  # Base64 encoded PCM16 audio at 16kHz
     language: str = "en"
+    initial_prompt: Optional[str] = None  # Vocabulary to bias decoding towards
 
 
 class TranscribeResponse(BaseModel):
@@ -93,6 +94,7 @@ async def transcribe(request: TranscribeRequest):
         segments, info = model.transcribe(
             audio_array,
             language=request.language,
+            initial_prompt=request.initial_prompt,
             beam_size=5,
             vad_filter=True,  # Filter out non-speech
         )
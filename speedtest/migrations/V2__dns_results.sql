@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS dns_results (
+    id SERIAL PRIMARY KEY,
+    timestamp TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    resolver_name TEXT NOT NULL,
+    resolver_addr TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    latency_ms REAL,
+    success BOOLEAN NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS dns_results_timestamp_idx ON dns_results (timestamp);
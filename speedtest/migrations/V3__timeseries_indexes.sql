@@ -0,0 +1,4 @@
+-- Range and per-target queries filter on these
+CREATE INDEX IF NOT EXISTS speedtest_results_timestamp_idx ON speedtest_results (timestamp);
+CREATE INDEX IF NOT EXISTS speedtest_results_server_timestamp_idx ON speedtest_results (server_name, timestamp);
+CREATE INDEX IF NOT EXISTS dns_results_resolver_timestamp_idx ON dns_results (resolver_name, timestamp);
@@ -3,22 +3,24 @@
 # Exit on error
 set -e
 
-# Check for speedtest CLI
-if ! command -v speedtest &> /dev/null; then
-    echo "Error: 'speedtest' CLI not found."
-    echo "Please install it using: brew tap teamookla/speedtest && brew install speedtest"
-    exit 1
-fi
-
-# Check if it's the Python version (wrong one)
-if speedtest --version | grep -q "speedtest-cli"; then
-    echo "Error: You have the Python 'speedtest-cli' installed."
-    echo "This app requires the official Ookla 'speedtest' CLI."
-    echo "Please run:"
-    echo "  brew uninstall speedtest-cli"
-    echo "  brew tap teamookla/speedtest"
-    echo "  brew install speedtest --force"
-    exit 1
+# Check for speedtest CLI, unless using synthetic results (SPEEDTEST_RUNNER=mock)
+if [ "${SPEEDTEST_RUNNER}" != "mock" ]; then
+    if ! command -v speedtest &> /dev/null; then
+        echo "Error: 'speedtest' CLI not found."
+        echo "Please install it using: brew tap teamookla/speedtest && brew install speedtest"
+        exit 1
+    fi
+
+    # Check if it's the Python version (wrong one)
+    if speedtest --version | grep -q "speedtest-cli"; then
+        echo "Error: You have the Python 'speedtest-cli' installed."
+        echo "This app requires the official Ookla 'speedtest' CLI."
+        echo "Please run:"
+        echo "  brew uninstall speedtest-cli"
+        echo "  brew tap teamookla/speedtest"
+        echo "  brew install speedtest --force"
+        exit 1
+    fi
 fi
 
 echo "1. Setting up port-forward to Postgres..."
@@ -1,15 +1,18 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
+    body::Bytes,
+    extract::{DefaultBodyLimit, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::Json,
-    routing::get,
+    routing::{get, post},
     Router,
 };
-use serde::Serialize;
-use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Duration, Utc};
 use std::sync::Arc;
 use std::collections::HashMap;
 use crate::db::Db;
+use crate::import::{dedup, parse_export, ImportError, RowError};
+use crate::series::{lttb, SeriesPoint};
 
 #[derive(Serialize, Clone)]
 pub struct SpeedtestResultResponse {
@@ -30,10 +33,98 @@ pub struct LocationSummary {
     pub avg_latency: f64,
 }
 
+#[derive(Serialize)]
+pub struct DnsResolverSummary {
+    pub resolver_name: String,
+    pub resolver_addr: String,
+    pub samples: i64,
+    pub success_rate: f64,
+    pub avg_latency_ms: Option<f64>,
+    pub p50_latency_ms: Option<f64>,
+    pub p95_latency_ms: Option<f64>,
+    pub max_latency_ms: Option<f64>,
+    pub last_checked: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct DnsSummaryQuery {
+    /// Look-back window in hours (default 24)
+    pub hours: Option<i32>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SeriesMetric {
+    Download,
+    Upload,
+    Latency,
+}
+
+impl SeriesMetric {
+    fn column(self) -> &'static str {
+        match self {
+            Self::Download => "download_bandwidth",
+            Self::Upload => "upload_bandwidth",
+            Self::Latency => "latency_ms",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SeriesQuery {
+    /// Server name to filter on; all targets when omitted
+    pub target: Option<String>,
+    pub metric: Option<SeriesMetric>,
+    /// Defaults to 30 days before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// Defaults to now
+    pub to: Option<DateTime<Utc>>,
+    /// Maximum number of points returned (default 500)
+    pub points: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SeriesResponse {
+    pub target: Option<String>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// Number of stored results in the range before downsampling
+    pub raw_points: usize,
+    pub points: Vec<SeriesPoint>,
+}
+
+#[derive(Serialize)]
+pub struct ImportResponse {
+    /// Results found in the upload
+    pub received: usize,
+    pub imported: u64,
+    /// Results skipped because one with the same timestamp and server already exists
+    pub duplicates: u64,
+}
+
+#[derive(Serialize)]
+pub struct ImportErrorResponse {
+    pub error: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rows: Vec<RowError>,
+}
+
+/// Largest accepted import upload
+const MAX_IMPORT_BYTES: usize = 32 * 1024 * 1024;
+
+/// Upper bound on `points`, so a single request can't ask for the whole table
+const MAX_SERIES_POINTS: usize = 5000;
+
 pub fn create_router(db: Arc<Db>) -> Router {
     Router::new()
         .route("/api/results", get(get_results))
         .route("/api/results/by-location", get(get_results_by_location))
+        .route("/api/results/series", get(get_results_series))
+        .route(
+            "/api/results/import",
+            post(import_results).layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES)),
+        )
+        .route("/api/dns/summary", get(get_dns_summary))
         .with_state(db)
 }
 
@@ -59,7 +150,7 @@ async fn get_results_by_location(
             for result in results {
                 grouped
                     .entry(result.server_name.clone())
-                    .or_insert_with(Vec::new)
+                    .or_default()
                     .push(result);
             }
             
@@ -67,7 +158,7 @@ async fn get_results_by_location(
             
             for (location, mut location_results) in grouped {
                 // Sort by timestamp descending (newest first)
-                location_results.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+                location_results.sort_by_key(|r| std::cmp::Reverse(r.timestamp));
                 
                 let count = location_results.len() as f64;
                 let avg_download = location_results.iter().map(|r| r.download_bandwidth as f64).sum::<f64>() / count;
@@ -91,3 +182,94 @@ async fn get_results_by_location(
         }
     }
 }
+
+async fn get_dns_summary(
+    State(db): State<Arc<Db>>,
+    Query(query): Query<DnsSummaryQuery>,
+) -> Result<Json<Vec<DnsResolverSummary>>, StatusCode> {
+    let hours = query.hours.unwrap_or(24);
+    if hours <= 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    match db.get_dns_summary(hours).await {
+        Ok(summaries) => Ok(Json(summaries)),
+        Err(e) => {
+            log::error!("Failed to fetch DNS summary: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_results_series(
+    State(db): State<Arc<Db>>,
+    Query(query): Query<SeriesQuery>,
+) -> Result<Json<SeriesResponse>, StatusCode> {
+    let metric = query.metric.unwrap_or(SeriesMetric::Download);
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - Duration::days(30));
+    // LTTB always keeps both endpoints, so fewer than 3 points isn't meaningful
+    let points = query.points.unwrap_or(500).clamp(3, MAX_SERIES_POINTS);
+    if from > to {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    match db.get_series(metric.column(), query.target.as_deref(), from, to).await {
+        Ok(raw) => Ok(Json(SeriesResponse {
+            target: query.target,
+            from,
+            to,
+            raw_points: raw.len(),
+            points: lttb(&raw, points),
+        })),
+        Err(e) => {
+            log::error!("Failed to fetch result series: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Backfill history from a CSV or JSON export (including the Ookla CLI's JSON and
+/// the Ookla app's CSV export). Results already stored for the same timestamp and
+/// server are skipped, so re-importing a file is harmless.
+async fn import_results(
+    State(db): State<Arc<Db>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<ImportResponse>, (StatusCode, Json<ImportErrorResponse>)> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok());
+
+    let results = parse_export(&body, content_type).map_err(|e| {
+        let (error, rows) = match e {
+            ImportError::Malformed(e) => (format!("{:#}", e), Vec::new()),
+            ImportError::InvalidRows(rows) => (format!("{} invalid row(s)", rows.len()), rows),
+        };
+        log::warn!("Rejected results import: {}", error);
+        (StatusCode::BAD_REQUEST, Json(ImportErrorResponse { error, rows }))
+    })?;
+
+    let received = results.len();
+    let unique = dedup(results);
+    match db.import_results(&unique).await {
+        Ok(imported) => {
+            log::info!("Imported {} of {} historical results", imported, received);
+            Ok(Json(ImportResponse {
+                received,
+                imported,
+                duplicates: received as u64 - imported,
+            }))
+        }
+        Err(e) => {
+            log::error!("Failed to import results: {}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ImportErrorResponse {
+                    error: "Failed to store results".to_string(),
+                    rows: Vec::new(),
+                }),
+            ))
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Command-line interface.
+//!
+//! With no subcommand (or `serve`) the binary runs the scheduler and HTTP API as
+//! before. `run` and `summarize` are one-off commands for interactive use and cron
+//! containers; they log to stderr and print their output to stdout.
+
+use crate::db::Db;
+use crate::speedtest::{SpeedtestRunner, TARGETS};
+use crate::webhook::Webhook;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use clap::{Parser, Subcommand};
+use log::{error, info};
+use serde::Serialize;
+
+#[derive(Parser)]
+#[command(about = "Scheduled internet speed and DNS monitoring")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the scheduler and HTTP API (default)
+    Serve,
+    /// Run speedtests once and store the results
+    Run {
+        /// Target to test, by name (e.g. "Hong Kong"); all targets when omitted
+        #[arg(long)]
+        target: Option<String>,
+        /// Print each result as a JSON line instead of a summary
+        #[arg(long)]
+        json: bool,
+        /// Don't store results in the database
+        #[arg(long)]
+        no_save: bool,
+    },
+    /// Print per-target averages of stored results
+    Summarize {
+        /// Look-back window, e.g. 30m, 12h, 7d, 2w
+        #[arg(long, default_value = "7d", value_parser = parse_since)]
+        since: Duration,
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Averages for one target over the summarized window
+#[derive(Serialize)]
+pub struct ServerSummary {
+    pub server_name: String,
+    pub samples: i64,
+    /// Bandwidths are in bytes/sec, as stored
+    pub avg_download_bandwidth: f64,
+    pub min_download_bandwidth: f64,
+    pub avg_upload_bandwidth: f64,
+    pub avg_latency_ms: f64,
+    pub max_latency_ms: f64,
+    pub last_run: DateTime<Utc>,
+}
+
+/// Parse a look-back window like `90m`, `12h` or `7d`
+fn parse_since(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.len() - value.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (amount, unit) = value.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}', expected e.g. 7d", value))?;
+    let duration = match unit {
+        "m" => Duration::minutes(amount),
+        "h" => Duration::hours(amount),
+        "d" => Duration::days(amount),
+        "w" => Duration::weeks(amount),
+        _ => return Err(format!("Invalid duration unit in '{}', expected m, h, d or w", value)),
+    };
+    if amount == 0 {
+        return Err("Duration must be greater than zero".to_string());
+    }
+    Ok(duration)
+}
+
+/// Bytes/sec as stored by the Ookla CLI to megabits/sec
+fn mbps(bandwidth: f64) -> f64 {
+    bandwidth / 125_000.0
+}
+
+/// `run`: test each selected target in turn, like a scheduled cycle
+pub async fn run(
+    runner: &dyn SpeedtestRunner,
+    target: Option<&str>,
+    json: bool,
+    no_save: bool,
+) -> Result<()> {
+    let targets: Vec<_> = match target {
+        Some(name) => {
+            let found = TARGETS
+                .iter()
+                .find(|(target, _)| target.eq_ignore_ascii_case(name))
+                .with_context(|| {
+                    let names: Vec<_> = TARGETS.iter().map(|(target, _)| *target).collect();
+                    format!("Unknown target '{}' (expected one of: {})", name, names.join(", "))
+                })?;
+            vec![*found]
+        }
+        None => TARGETS.to_vec(),
+    };
+
+    let db = if no_save { None } else { Some(Db::new().await?) };
+    let webhook = Webhook::new()?;
+
+    let mut failures = 0;
+    for (index, (name, server_id)) in targets.iter().enumerate() {
+        if index > 0 {
+            // Same spacing as the scheduled cycle, so runs don't compete for bandwidth
+            tokio::time::sleep(std::time::Duration::from_secs(120)).await;
+        }
+        info!("Running speedtest for {}", name);
+        let outcome = runner.run(*server_id);
+        webhook.notify(name, &outcome).await;
+
+        let result = match outcome {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to run speedtest for {}: {}", name, e);
+                failures += 1;
+                continue;
+            }
+        };
+
+        if json {
+            println!("{}", serde_json::to_string(&result)?);
+        } else {
+            println!(
+                "{}: {:.1} ms, {:.1} Mbps down, {:.1} Mbps up ({})",
+                name,
+                result.ping.latency,
+                mbps(result.download.bandwidth as f64),
+                mbps(result.upload.bandwidth as f64),
+                result.result.url
+            );
+        }
+
+        if let Some(db) = &db
+            && let Err(e) = db.insert_result(&result).await
+        {
+            error!("Failed to insert result for {}: {}", name, e);
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} of {} speedtest(s) failed", failures, targets.len());
+    }
+    Ok(())
+}
+
+/// `summarize`: per-target averages over the window
+pub async fn summarize(since: Duration, json: bool) -> Result<()> {
+    let db = Db::new().await?;
+    let summaries = db.get_summary(Utc::now() - since).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    if summaries.is_empty() {
+        println!("No results in this period");
+        return Ok(());
+    }
+
+    println!(
+        "{:<16} {:>7} {:>10} {:>10} {:>10} {:>9} {:>9}  LAST RUN",
+        "TARGET", "SAMPLES", "DOWN", "MIN DOWN", "UP", "LATENCY", "MAX LAT"
+    );
+    for s in &summaries {
+        println!(
+            "{:<16} {:>7} {:>10} {:>10} {:>10} {:>9} {:>9}  {}",
+            s.server_name,
+            s.samples,
+            format!("{:.1} Mbps", mbps(s.avg_download_bandwidth)),
+            format!("{:.1} Mbps", mbps(s.min_download_bandwidth)),
+            format!("{:.1} Mbps", mbps(s.avg_upload_bandwidth)),
+            format!("{:.1} ms", s.avg_latency_ms),
+            format!("{:.1} ms", s.max_latency_ms),
+            s.last_run.format("%Y-%m-%d %H:%M UTC")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since("7d"), Ok(Duration::days(7)));
+        assert_eq!(parse_since("12h"), Ok(Duration::hours(12)));
+        assert_eq!(parse_since("90m"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_since("2w"), Ok(Duration::weeks(2)));
+        assert!(parse_since("7").is_err());
+        assert!(parse_since("d").is_err());
+        assert!(parse_since("7y").is_err());
+        assert!(parse_since("0d").is_err());
+    }
+
+    #[test]
+    fn test_cli_parses_subcommands() {
+        let cli = Cli::try_parse_from(["speedtest"]).unwrap();
+        assert!(cli.command.is_none());
+
+        let cli = Cli::try_parse_from(["speedtest", "run", "--target", "London", "--json"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Run { target: Some(ref t), json: true, no_save: false }) if t == "London"
+        ));
+
+        let cli = Cli::try_parse_from(["speedtest", "summarize", "--since", "24h"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Summarize { since, json: false }) if since == Duration::hours(24)));
+    }
+}
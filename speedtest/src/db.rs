@@ -2,8 +2,12 @@ use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
 use tokio_postgres::NoTls;
 use std::env;
 use anyhow::Result;
+use crate::cli::ServerSummary;
+use crate::dns::DnsResult;
+use crate::import::ImportedResult;
+use crate::series::SeriesPoint;
 use crate::speedtest::SpeedtestResult;
-use log;
+use chrono::{DateTime, Utc};
 
 // Embed migrations
 mod embedded {
@@ -11,6 +15,70 @@ mod embedded {
     embed_migrations!("migrations");
 }
 
+/// Tables converted to TimescaleDB hypertables, partitioned on `timestamp`
+const HYPERTABLES: &[&str] = &["speedtest_results", "dns_results"];
+
+/// Time range covered by each hypertable chunk
+const HYPERTABLE_CHUNK_INTERVAL: &str = "30 days";
+
+/// Convert the results tables to TimescaleDB hypertables so range and aggregation
+/// queries only touch the chunks they need as years of samples accumulate.
+///
+/// Opt-in with `TIMESCALEDB_ENABLED=true`, since it needs the extension installed
+/// on the server. Runs after the embedded migrations (which stay plain SQL that works
+/// on any Postgres) and is idempotent, so it's safe on every startup; existing rows
+/// are migrated into chunks the first time.
+async fn setup_hypertables(client: &tokio_postgres::Client) -> Result<()> {
+    log::info!("Setting up TimescaleDB hypertables...");
+    client
+        .batch_execute("CREATE EXTENSION IF NOT EXISTS timescaledb")
+        .await?;
+
+    for table in HYPERTABLES {
+        // TimescaleDB requires the time column in every unique index of a hypertable,
+        // so the table is keyed by (id, timestamp) instead of id alone
+        let keyed_by_time: bool = client
+            .query_one(
+                r#"
+                SELECT EXISTS (
+                    SELECT 1 FROM pg_index i
+                    JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+                    WHERE i.indrelid = $1::TEXT::REGCLASS AND i.indisprimary AND a.attname = 'timestamp'
+                )
+                "#,
+                &[table],
+            )
+            .await?
+            .get(0);
+        if !keyed_by_time {
+            client
+                .batch_execute(&format!(
+                    "ALTER TABLE {table} DROP CONSTRAINT IF EXISTS {table}_pkey; \
+                     ALTER TABLE {table} ADD PRIMARY KEY (id, timestamp);"
+                ))
+                .await?;
+            log::info!("{} is keyed by (id, timestamp)", table);
+        }
+
+        client
+            .execute(
+                r#"
+                SELECT create_hypertable(
+                    $1::TEXT::REGCLASS, 'timestamp',
+                    chunk_time_interval => $2::TEXT::INTERVAL,
+                    if_not_exists => TRUE,
+                    migrate_data => TRUE
+                )
+                "#,
+                &[table, &HYPERTABLE_CHUNK_INTERVAL],
+            )
+            .await?;
+        log::info!("{} is a hypertable", table);
+    }
+
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct Db {
     pool: Pool,
@@ -33,6 +101,10 @@ impl Db {
         embedded::migrations::runner().run_async(&mut **client).await?;
         log::info!("Database migrations complete.");
 
+        if env::var("TIMESCALEDB_ENABLED").is_ok_and(|v| v == "true" || v == "1") {
+            setup_hypertables(&client).await?;
+        }
+
         Ok(Self { pool })
     }
 
@@ -63,6 +135,49 @@ impl Db {
         Ok(())
     }
 
+    /// Insert historical results in one transaction, skipping any whose timestamp and
+    /// server already exist. Returns the number inserted.
+    pub async fn import_results(&self, results: &[ImportedResult]) -> Result<u64> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        let stmt = tx.prepare(
+            r#"
+            INSERT INTO speedtest_results (
+                timestamp, server_id, server_name, server_country, latency_ms,
+                download_bandwidth, upload_bandwidth, download_bytes, upload_bytes, result_url
+            )
+            SELECT $1, $2, $3, $4, $5, $6, $7, $8, $9, $10
+            WHERE NOT EXISTS (
+                SELECT 1 FROM speedtest_results WHERE timestamp = $1 AND server_name = $3
+            )
+            "#,
+        )
+        .await?;
+
+        let mut inserted = 0;
+        for result in results {
+            inserted += tx.execute(
+                &stmt,
+                &[
+                    &result.timestamp,
+                    &result.server_id,
+                    &result.server_name,
+                    &result.server_country,
+                    &result.latency_ms,
+                    &result.download_bandwidth,
+                    &result.upload_bandwidth,
+                    &result.download_bytes,
+                    &result.upload_bytes,
+                    &result.result_url,
+                ]
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+
     pub async fn get_recent_results(&self) -> Result<Vec<crate::api::SpeedtestResultResponse>> {
         let client = self.pool.get().await?;
         let rows = client.query(
@@ -96,4 +211,142 @@ impl Db {
 
         Ok(results)
     }
+
+    /// Per-target bandwidth and latency aggregates for results since `since`
+    pub async fn get_summary(&self, since: DateTime<Utc>) -> Result<Vec<ServerSummary>> {
+        let client = self.pool.get().await?;
+        let rows = client.query(
+            r#"
+            SELECT
+                server_name,
+                COUNT(*) AS samples,
+                AVG(download_bandwidth)::FLOAT8 AS avg_download_bandwidth,
+                MIN(download_bandwidth)::FLOAT8 AS min_download_bandwidth,
+                AVG(upload_bandwidth)::FLOAT8 AS avg_upload_bandwidth,
+                AVG(latency_ms)::FLOAT8 AS avg_latency_ms,
+                MAX(latency_ms)::FLOAT8 AS max_latency_ms,
+                MAX(timestamp) AS last_run
+            FROM speedtest_results
+            WHERE timestamp >= $1 AND server_name IS NOT NULL
+            GROUP BY server_name
+            ORDER BY server_name
+            "#,
+            &[&since]
+        )
+        .await?;
+
+        let mut summaries = Vec::new();
+        for row in rows {
+            summaries.push(ServerSummary {
+                server_name: row.get("server_name"),
+                samples: row.get("samples"),
+                avg_download_bandwidth: row.get("avg_download_bandwidth"),
+                min_download_bandwidth: row.get("min_download_bandwidth"),
+                avg_upload_bandwidth: row.get("avg_upload_bandwidth"),
+                avg_latency_ms: row.get("avg_latency_ms"),
+                max_latency_ms: row.get("max_latency_ms"),
+                last_run: row.get("last_run"),
+            });
+        }
+
+        Ok(summaries)
+    }
+
+    pub async fn insert_dns_result(&self, result: &DnsResult) -> Result<()> {
+        let client = self.pool.get().await?;
+        client.execute(
+            r#"
+            INSERT INTO dns_results (
+                resolver_name, resolver_addr, domain, latency_ms, success, error
+            )
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            &[
+                &result.resolver_name,
+                &result.resolver_addr,
+                &result.domain,
+                &result.latency_ms,
+                &result.success,
+                &result.error,
+            ]
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Per-resolver latency and success rate over the last `hours` hours
+    pub async fn get_dns_summary(&self, hours: i32) -> Result<Vec<crate::api::DnsResolverSummary>> {
+        let client = self.pool.get().await?;
+        let rows = client.query(
+            r#"
+            SELECT
+                resolver_name,
+                resolver_addr,
+                COUNT(*) AS samples,
+                COUNT(*) FILTER (WHERE success)::FLOAT8 / COUNT(*) AS success_rate,
+                AVG(latency_ms)::FLOAT8 AS avg_latency_ms,
+                percentile_cont(0.5) WITHIN GROUP (ORDER BY latency_ms) AS p50_latency_ms,
+                percentile_cont(0.95) WITHIN GROUP (ORDER BY latency_ms) AS p95_latency_ms,
+                MAX(latency_ms)::FLOAT8 AS max_latency_ms,
+                MAX(timestamp) AS last_checked
+            FROM dns_results
+            WHERE timestamp > NOW() - make_interval(hours => $1)
+            GROUP BY resolver_name, resolver_addr
+            ORDER BY resolver_name
+            "#,
+            &[&hours]
+        )
+        .await?;
+
+        let mut summaries = Vec::new();
+        for row in rows {
+            summaries.push(crate::api::DnsResolverSummary {
+                resolver_name: row.get("resolver_name"),
+                resolver_addr: row.get("resolver_addr"),
+                samples: row.get("samples"),
+                success_rate: row.get("success_rate"),
+                avg_latency_ms: row.get("avg_latency_ms"),
+                p50_latency_ms: row.get("p50_latency_ms"),
+                p95_latency_ms: row.get("p95_latency_ms"),
+                max_latency_ms: row.get("max_latency_ms"),
+                last_checked: row.get("last_checked"),
+            });
+        }
+
+        Ok(summaries)
+    }
+
+    /// Raw time-ordered values of `column` for a target between `from` and `to`.
+    /// `column` must be a trusted column name, never user input.
+    pub async fn get_series(
+        &self,
+        column: &str,
+        target: Option<&str>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<SeriesPoint>> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            r#"
+            SELECT timestamp, {}::FLOAT8 AS value
+            FROM speedtest_results
+            WHERE ($1::TEXT IS NULL OR server_name = $1)
+                AND timestamp >= $2
+                AND timestamp <= $3
+                AND {} IS NOT NULL
+            ORDER BY timestamp ASC
+            "#,
+            column, column
+        );
+        let rows = client.query(&query, &[&target, &from, &to]).await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| SeriesPoint {
+                timestamp: row.get("timestamp"),
+                value: row.get("value"),
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,197 @@
+use crate::db::Db;
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// How long to wait for a resolver to answer before counting the query as failed
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsResult {
+    pub resolver_name: String,
+    pub resolver_addr: String,
+    pub domain: String,
+    pub latency_ms: Option<f32>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Resolvers to check, from `DNS_RESOLVERS` as `name=ip[:port]` pairs,
+/// e.g. `Router=192.168.1.1,ISP=203.0.113.53,Cloudflare=1.1.1.1`
+pub fn resolvers_from_env() -> Result<Vec<Resolver>> {
+    let spec = env::var("DNS_RESOLVERS")
+        .unwrap_or_else(|_| "Cloudflare=1.1.1.1,Google=8.8.8.8,Quad9=9.9.9.9".to_string());
+
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, addr) = entry
+                .split_once('=')
+                .with_context(|| format!("Invalid DNS_RESOLVERS entry '{}' (expected name=ip)", entry))?;
+            let addr = match addr.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(_) => SocketAddr::new(
+                    addr.parse::<IpAddr>()
+                        .with_context(|| format!("Invalid resolver address '{}'", addr))?,
+                    53,
+                ),
+            };
+            Ok(Resolver {
+                name: name.to_string(),
+                addr,
+            })
+        })
+        .collect()
+}
+
+/// Domains to resolve on each check, from `DNS_TEST_DOMAINS`
+pub fn domains_from_env() -> Vec<String> {
+    env::var("DNS_TEST_DOMAINS")
+        .unwrap_or_else(|_| "google.com,youtube.com,github.com".to_string())
+        .split(',')
+        .map(|d| d.trim().to_string())
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+/// Query every resolver for every domain and store the results
+pub async fn run_dns_checks(db: &Db, resolvers: &[Resolver], domains: &[String]) {
+    for resolver in resolvers {
+        for domain in domains {
+            let result = measure(resolver, domain).await;
+            if let Some(err) = &result.error {
+                error!("DNS lookup of {} via {} failed: {}", domain, resolver.name, err);
+            }
+            if let Err(e) = db.insert_dns_result(&result).await {
+                error!("Failed to insert DNS result for {}: {}", resolver.name, e);
+            }
+        }
+    }
+    info!("Finished DNS checks for {} resolvers", resolvers.len());
+}
+
+/// Time a single A-record lookup of `domain` against `resolver`
+pub async fn measure(resolver: &Resolver, domain: &str) -> DnsResult {
+    let start = Instant::now();
+    let outcome = tokio::time::timeout(QUERY_TIMEOUT, query(resolver.addr, domain))
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out after {:?}", QUERY_TIMEOUT)));
+    let elapsed_ms = start.elapsed().as_secs_f32() * 1000.0;
+
+    let (latency_ms, error) = match outcome {
+        Ok(()) => (Some(elapsed_ms), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    DnsResult {
+        resolver_name: resolver.name.clone(),
+        resolver_addr: resolver.addr.to_string(),
+        domain: domain.to_string(),
+        latency_ms,
+        success: error.is_none(),
+        error,
+    }
+}
+
+async fn query(addr: SocketAddr, domain: &str) -> Result<()> {
+    let bind_addr: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+
+    let id = query_id();
+    socket.send(&build_query(id, domain)?).await?;
+
+    let mut buf = [0u8; 512];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Ignore stray datagrams that don't answer our query
+        if len >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+            return check_response(&buf[..len]);
+        }
+    }
+}
+
+/// Pseudo-random query ID; only needs to distinguish our own in-flight query
+fn query_id() -> u16 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u16)
+        .unwrap_or(0)
+}
+
+/// Build a recursive DNS query for the A record of `domain`
+fn build_query(id: u16, domain: &str) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(18 + domain.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&0x0100u16.to_be_bytes()); // flags: recursion desired
+    packet.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    packet.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // ANCOUNT, NSCOUNT, ARCOUNT
+
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("Invalid domain name '{}'", domain);
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend_from_slice(&1u16.to_be_bytes()); // QTYPE A
+    packet.extend_from_slice(&1u16.to_be_bytes()); // QCLASS IN
+    Ok(packet)
+}
+
+fn check_response(packet: &[u8]) -> Result<()> {
+    if packet.len() < 12 {
+        anyhow::bail!("Truncated DNS response");
+    }
+    let flags = u16::from_be_bytes([packet[2], packet[3]]);
+    if flags & 0x8000 == 0 {
+        anyhow::bail!("DNS packet is not a response");
+    }
+    match flags & 0x000F {
+        0 => Ok(()),
+        2 => anyhow::bail!("SERVFAIL"),
+        3 => anyhow::bail!("NXDOMAIN"),
+        5 => anyhow::bail!("REFUSED"),
+        rcode => anyhow::bail!("DNS error rcode {}", rcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_query() {
+        let packet = build_query(0x1234, "example.com").unwrap();
+        assert_eq!(&packet[0..2], &[0x12, 0x34]);
+        assert_eq!(&packet[12..25], b"\x07example\x03com\x00");
+        assert_eq!(&packet[25..29], &[0, 1, 0, 1]);
+        assert!(build_query(1, "bad..name").is_err());
+    }
+
+    #[test]
+    fn test_check_response_rcode() {
+        let mut packet = [0u8; 12];
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        assert!(check_response(&packet).is_ok());
+        packet[3] = 0x83;
+        assert_eq!(check_response(&packet).unwrap_err().to_string(), "NXDOMAIN");
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Largest number of rows accepted in one import
+pub const MAX_IMPORT_ROWS: usize = 100_000;
+
+/// A historical result parsed from an export, ready to insert
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedResult {
+    pub timestamp: DateTime<Utc>,
+    pub server_id: Option<i32>,
+    pub server_name: String,
+    pub server_country: String,
+    pub latency_ms: f32,
+    /// Bytes per second, matching what the CLI reports
+    pub download_bandwidth: i32,
+    pub upload_bandwidth: i32,
+    pub download_bytes: Option<i32>,
+    pub upload_bytes: Option<i32>,
+    pub result_url: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RowError {
+    /// 1-based record number in the upload (excluding any CSV header)
+    pub row: usize,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// The upload couldn't be read as CSV or JSON at all
+    Malformed(anyhow::Error),
+    /// Some records are invalid; nothing is imported
+    InvalidRows(Vec<RowError>),
+}
+
+/// Fields of one record, keyed by normalized name (lowercase alphanumerics, with
+/// nested JSON objects flattened to `parent.child`)
+type Fields = HashMap<String, String>;
+
+/// Parse an export into results. JSON may be an array, a single object or
+/// newline-delimited objects; anything else is treated as CSV with a header row.
+///
+/// Fails with the per-row problems if any record is invalid, so an import is all
+/// or nothing.
+pub fn parse_export(
+    body: &[u8],
+    content_type: Option<&str>,
+) -> std::result::Result<Vec<ImportedResult>, ImportError> {
+    let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+    let is_json = match content_type {
+        Some(ct) if ct.contains("json") => true,
+        Some(ct) if ct.contains("csv") => false,
+        _ => matches!(
+            body.iter().find(|b| !b.is_ascii_whitespace()),
+            Some(b'[') | Some(b'{')
+        ),
+    };
+
+    let records = if is_json {
+        json_records(body)
+    } else {
+        csv_records(body)
+    }
+    .map_err(ImportError::Malformed)?;
+    if records.len() > MAX_IMPORT_ROWS {
+        return Err(ImportError::Malformed(anyhow::anyhow!(
+            "Too many rows ({}), the limit is {}",
+            records.len(),
+            MAX_IMPORT_ROWS
+        )));
+    }
+
+    let now = Utc::now();
+    let mut results = Vec::new();
+    let mut errors = Vec::new();
+    for (i, fields) in records.iter().enumerate() {
+        // Ookla's JSON lines output interleaves progress and log records
+        if fields.get("type").is_some_and(|t| t != "result") {
+            continue;
+        }
+        match parse_record(fields, now) {
+            Ok(result) => results.push(result),
+            Err(e) => errors.push(RowError {
+                row: i + 1,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(results)
+    } else {
+        Err(ImportError::InvalidRows(errors))
+    }
+}
+
+/// Drop results that share a timestamp and server with an earlier one in the same upload
+pub fn dedup(results: Vec<ImportedResult>) -> Vec<ImportedResult> {
+    let mut seen = HashSet::new();
+    results
+        .into_iter()
+        .filter(|r| seen.insert((r.timestamp, r.server_name.clone())))
+        .collect()
+}
+
+fn normalize_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '.')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+fn json_records(body: &[u8]) -> Result<Vec<Fields>> {
+    let values = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(items)) => items,
+        Ok(value) => vec![value],
+        // Newline-delimited JSON, e.g. `speedtest -f jsonl`
+        Err(_) => body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_slice(line).with_context(|| format!("Invalid JSON on line {}", i + 1))
+            })
+            .collect::<Result<_>>()?,
+    };
+
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let Value::Object(_) = value else {
+                bail!("Record {} is not a JSON object", i + 1);
+            };
+            let mut fields = Fields::new();
+            flatten_json("", value, &mut fields);
+            Ok(fields)
+        })
+        .collect()
+}
+
+fn flatten_json(prefix: &str, value: &Value, fields: &mut Fields) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                let key = normalize_key(key);
+                let path = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                flatten_json(&path, child, fields);
+            }
+        }
+        Value::Null => {}
+        Value::String(s) => {
+            fields.insert(prefix.to_string(), s.clone());
+        }
+        other => {
+            fields.insert(prefix.to_string(), other.to_string());
+        }
+    }
+}
+
+fn csv_records(body: &[u8]) -> Result<Vec<Fields>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(body);
+    let headers: Vec<String> = reader
+        .headers()
+        .context("Failed to read CSV header")?
+        .iter()
+        .map(normalize_key)
+        .collect();
+
+    reader
+        .records()
+        .enumerate()
+        .map(|(i, record)| {
+            let record = record.with_context(|| format!("Invalid CSV on row {}", i + 1))?;
+            Ok(headers
+                .iter()
+                .zip(record.iter())
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(key, value)| (key.clone(), value.to_string()))
+                .collect())
+        })
+        .collect()
+}
+
+/// First present field among `keys`
+fn field<'a>(fields: &'a Fields, keys: &[&str]) -> Option<&'a str> {
+    keys.iter().find_map(|k| fields.get(*k)).map(|s| s.as_str())
+}
+
+fn number(fields: &Fields, keys: &[&str], name: &str) -> Result<Option<f64>> {
+    let Some(value) = field(fields, keys) else {
+        return Ok(None);
+    };
+    let n: f64 = value
+        .parse()
+        .with_context(|| format!("Invalid {} '{}'", name, value))?;
+    if !n.is_finite() || n < 0.0 {
+        bail!("Invalid {} '{}'", name, value);
+    }
+    Ok(Some(n))
+}
+
+fn to_i32(value: f64, name: &str) -> Result<i32> {
+    let rounded = value.round();
+    if rounded > i32::MAX as f64 {
+        bail!("{} {} is out of range", name, value);
+    }
+    Ok(rounded as i32)
+}
+
+/// Bandwidth in bytes/s, from either the CLI's bytes/s fields or the Ookla app's Kbps columns
+fn bandwidth(fields: &Fields, direction: &str) -> Result<i32> {
+    let bytes_keys = [format!("{}bandwidth", direction), format!("{}.bandwidth", direction)];
+    let bytes_keys: Vec<&str> = bytes_keys.iter().map(String::as_str).collect();
+    if let Some(bytes_per_sec) = number(fields, &bytes_keys, &format!("{} bandwidth", direction))? {
+        return to_i32(bytes_per_sec, direction);
+    }
+    match number(fields, &[direction], direction)? {
+        Some(kbps) => to_i32(kbps * 1000.0 / 8.0, direction),
+        None => bail!("Missing {} bandwidth", direction),
+    }
+}
+
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(ts) = DateTime::parse_from_rfc3339(value) {
+        return Ok(ts.with_timezone(&Utc));
+    }
+    // Ookla app exports use local-looking timestamps without an offset; treat them as UTC
+    const FORMATS: &[&str] = &[
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%m/%d/%Y %H:%M:%S",
+        "%m/%d/%Y %H:%M",
+    ];
+    FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(value, f).ok())
+        .map(|naive| naive.and_utc())
+        .with_context(|| format!("Invalid timestamp '{}'", value))
+}
+
+fn parse_record(fields: &Fields, now: DateTime<Utc>) -> Result<ImportedResult> {
+    let timestamp = field(fields, &["timestamp", "date", "time", "datetime"])
+        .context("Missing timestamp")
+        .and_then(parse_timestamp)?;
+    // Allow for clock skew between the exporting device and this server
+    if timestamp > now + Duration::minutes(5) {
+        bail!("Timestamp {} is in the future", timestamp);
+    }
+
+    let server_name = field(fields, &["servername", "server.name"])
+        .context("Missing server name")?
+        .to_string();
+    let server_id = number(fields, &["serverid", "server.id"], "server id")?
+        .map(|id| to_i32(id, "server id"))
+        .transpose()?;
+    let server_country = field(fields, &["servercountry", "server.country", "country"])
+        .unwrap_or_default()
+        .to_string();
+    let latency_ms = number(
+        fields,
+        &["latencyms", "ping.latency", "latency", "ping", "idlelatency"],
+        "latency",
+    )?
+    .context("Missing latency")? as f32;
+
+    let download_bytes = number(fields, &["downloadbytes", "download.bytes"], "download bytes")?
+        .map(|b| to_i32(b, "download bytes"))
+        .transpose()?;
+    let upload_bytes = number(fields, &["uploadbytes", "upload.bytes"], "upload bytes")?
+        .map(|b| to_i32(b, "upload bytes"))
+        .transpose()?;
+
+    Ok(ImportedResult {
+        timestamp,
+        server_id,
+        server_name,
+        server_country,
+        latency_ms,
+        download_bandwidth: bandwidth(fields, "download")?,
+        upload_bandwidth: bandwidth(fields, "upload")?,
+        download_bytes,
+        upload_bytes,
+        result_url: field(fields, &["resulturl", "result.url", "shareurl"]).map(str::to_string),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str, content_type: Option<&str>) -> Vec<ImportedResult> {
+        parse_export(body.as_bytes(), content_type).unwrap()
+    }
+
+    #[test]
+    fn test_parse_ookla_cli_json() {
+        let body = r#"{"type":"result","timestamp":"2023-05-01T10:00:00Z",
+            "ping":{"jitter":0.5,"latency":12.5},
+            "download":{"bandwidth":11875000,"bytes":150000000,"elapsed":10000},
+            "upload":{"bandwidth":2500000,"bytes":30000000,"elapsed":10000},
+            "server":{"id":18229,"name":"Starry","location":"Los Angeles","country":"United States"},
+            "result":{"id":"abc","url":"https://www.speedtest.net/result/c/abc"}}"#;
+        let results = parse(body, Some("application/json"));
+        assert_eq!(results.len(), 1);
+        let r = &results[0];
+        assert_eq!(r.server_id, Some(18229));
+        assert_eq!(r.server_name, "Starry");
+        assert_eq!(r.latency_ms, 12.5);
+        assert_eq!(r.download_bandwidth, 11_875_000);
+        assert_eq!(r.upload_bytes, Some(30_000_000));
+        assert_eq!(r.result_url.as_deref(), Some("https://www.speedtest.net/result/c/abc"));
+    }
+
+    #[test]
+    fn test_parse_ookla_app_csv() {
+        let body = "\u{feff}Date,ConnType,Lat,Lon,Download,Upload,Latency,ServerName,InternalIp,ExternalIp\n\
+                    2019-03-21 18:54,Wifi,-33.8,151.2,95000,20000,14,\"Aussie Broadband, Sydney\",10.0.0.2,1.2.3.4\n";
+        let results = parse(body, None);
+        assert_eq!(results.len(), 1);
+        let r = &results[0];
+        assert_eq!(r.timestamp.to_rfc3339(), "2019-03-21T18:54:00+00:00");
+        assert_eq!(r.server_name, "Aussie Broadband, Sydney");
+        // Kbps to bytes/s
+        assert_eq!(r.download_bandwidth, 11_875_000);
+        assert_eq!(r.upload_bandwidth, 2_500_000);
+    }
+
+    #[test]
+    fn test_parse_reports_invalid_rows() {
+        let body = "timestamp,server_name,latency_ms,download_bandwidth,upload_bandwidth\n\
+                    2023-05-01T10:00:00Z,Local,10,1000,1000\n\
+                    not-a-date,Local,10,1000,1000\n\
+                    2023-05-01T11:00:00Z,,10,1000,1000\n\
+                    2099-01-01T00:00:00Z,Local,10,1000,1000\n\
+                    2023-05-01T12:00:00Z,Local,-1,1000,1000\n";
+        let Err(ImportError::InvalidRows(errors)) = parse_export(body.as_bytes(), Some("text/csv")) else {
+            panic!("expected invalid rows");
+        };
+        let rows: Vec<usize> = errors.iter().map(|e| e.row).collect();
+        assert_eq!(rows, [2, 3, 4, 5]);
+        assert!(errors[0].message.contains("Invalid timestamp"));
+        assert!(errors[1].message.contains("Missing server name"));
+        assert!(errors[2].message.contains("future"));
+    }
+
+    #[test]
+    fn test_jsonl_skips_non_result_records_and_dedups() {
+        let line = r#"{"type":"result","timestamp":"2023-05-01T10:00:00Z","ping":{"latency":10},"download":{"bandwidth":1000},"upload":{"bandwidth":500},"server":{"name":"Local"}}"#;
+        let body = format!("{{\"type\":\"log\",\"message\":\"hi\"}}\n{}\n{}\n", line, line);
+        let results = parse(&body, None);
+        assert_eq!(results.len(), 2);
+        assert_eq!(dedup(results).len(), 1);
+    }
+}
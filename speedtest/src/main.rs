@@ -1,10 +1,18 @@
 mod api;
+mod cli;
 mod db;
+mod dns;
+mod import;
+mod series;
 mod speedtest;
+mod webhook;
 
+use crate::cli::{Cli, Command};
 use crate::db::Db;
-use crate::speedtest::run_speedtest;
+use crate::speedtest::{runner_from_env, TARGETS};
+use crate::webhook::Webhook;
 use anyhow::Result;
+use clap::Parser;
 use dotenvy::dotenv;
 use log::{error, info};
 use tokio_cron_scheduler::{Job, JobScheduler};
@@ -14,35 +22,43 @@ async fn main() -> Result<()> {
     dotenv().ok();
     env_logger::init();
 
+    match Cli::parse().command.unwrap_or(Command::Serve) {
+        Command::Serve => serve().await,
+        Command::Run { target, json, no_save } => {
+            cli::run(runner_from_env()?.as_ref(), target.as_deref(), json, no_save).await
+        }
+        Command::Summarize { since, json } => cli::summarize(since, json).await,
+    }
+}
+
+/// Long-running mode: scheduled speedtests and DNS checks plus the HTTP API
+async fn serve() -> Result<()> {
     info!("Starting Speedtest App");
 
     let db = Db::new().await?;
     let sched = JobScheduler::new().await?;
-
-    // Define target servers
-    // Local (None), Los Angeles, Hong Kong, New York, London
-    // Note: These IDs might need to be updated.
-    let targets = vec![
-        ("Local", None),
-        ("Los Angeles", Some(18229)), // Starry
-        ("Hong Kong", Some(13538)),   // CSL
-        ("Atlanta", Some(10152)),     // Comcast - major peering hub, replaced slow NY server
-        ("London", Some(30690)),      // Community Fibre
-    ];
+    let webhook = Webhook::new()?;
+    let runner = runner_from_env()?;
 
     // Clone for the closure
     let db_clone = db.clone();
-    let targets_clone = targets.clone();
+    let targets_clone = TARGETS.to_vec();
+    let webhook_clone = webhook.clone();
+    let runner_clone = runner.clone();
 
     // Run every hour
     let job = Job::new_async("0 0 * * * *", move |_uuid, _l| {
         let db = db_clone.clone();
         let targets = targets_clone.clone();
+        let webhook = webhook_clone.clone();
+        let runner = runner_clone.clone();
         Box::pin(async move {
             info!("Starting scheduled speedtest cycle");
             for (name, server_id) in targets {
                 info!("Running speedtest for {}", name);
-                match run_speedtest(server_id) {
+                let outcome = runner.run(server_id);
+                webhook.notify(name, &outcome).await;
+                match outcome {
                     Ok(result) => {
                         info!("Speedtest for {} successful: {} ms latency, {} Mbps down", name, result.ping.latency, result.download.bandwidth / 125000); // bandwidth is in bytes/sec usually? No, speedtest-cli json is usually bits/s or bytes/s. Let's check. 
                         // speedtest-cli json: bandwidth is bytes/sec. 
@@ -65,6 +81,21 @@ async fn main() -> Result<()> {
 
     sched.add(job).await?;
 
+    // DNS resolution checks every 5 minutes, so slow DNS can be told apart from slow bandwidth
+    let dns_resolvers = dns::resolvers_from_env()?;
+    let dns_domains = dns::domains_from_env();
+    let db_clone = db.clone();
+    let dns_job = Job::new_async("0 */5 * * * *", move |_uuid, _l| {
+        let db = db_clone.clone();
+        let resolvers = dns_resolvers.clone();
+        let domains = dns_domains.clone();
+        Box::pin(async move {
+            dns::run_dns_checks(&db, &resolvers, &domains).await;
+        })
+    })?;
+
+    sched.add(dns_job).await?;
+
     info!("Scheduler started");
     sched.start().await?;
 
@@ -84,7 +115,9 @@ async fn main() -> Result<()> {
         for (name, server_id) in targets {
              info!("Running initial speedtest for {}", name);
              // We reuse the logic, but just for local to test quickly
-             match run_speedtest(server_id) {
+             let outcome = runner.run(server_id);
+             webhook.notify(name, &outcome).await;
+             match outcome {
                  Ok(result) => {
                      info!("Initial speedtest for {} successful", name);
                      if let Err(e) = db.insert_result(&result).await {
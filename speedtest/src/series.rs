@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct SeriesPoint {
+    pub timestamp: DateTime<Utc>,
+    pub value: f64,
+}
+
+/// Downsample a time-ordered series to at most `threshold` points using
+/// Largest-Triangle-Three-Buckets, which keeps the visual shape (peaks and dips)
+/// far better than plain bucket averages.
+pub fn lttb(points: &[SeriesPoint], threshold: usize) -> Vec<SeriesPoint> {
+    if threshold >= points.len() || threshold < 3 {
+        return points.to_vec();
+    }
+
+    let x = |p: &SeriesPoint| p.timestamp.timestamp_millis() as f64;
+
+    let mut sampled = Vec::with_capacity(threshold);
+    sampled.push(points[0]);
+
+    // First and last points are always kept; the rest are split into equal buckets
+    let bucket_size = (points.len() - 2) as f64 / (threshold - 2) as f64;
+    let mut a = 0;
+
+    for i in 0..threshold - 2 {
+        let bucket_start = (i as f64 * bucket_size) as usize + 1;
+        let bucket_end = ((i + 1) as f64 * bucket_size) as usize + 1;
+
+        // Average of the next bucket is the third triangle vertex
+        let next_start = bucket_end;
+        let next_end = (((i + 2) as f64 * bucket_size) as usize + 1).min(points.len());
+        let next = &points[next_start..next_end.max(next_start + 1)];
+        let avg_x = next.iter().map(x).sum::<f64>() / next.len() as f64;
+        let avg_y = next.iter().map(|p| p.value).sum::<f64>() / next.len() as f64;
+
+        let (ax, ay) = (x(&points[a]), points[a].value);
+        let mut max_area = -1.0;
+        let mut chosen = bucket_start;
+        for (j, p) in points[bucket_start..bucket_end].iter().enumerate() {
+            let area = ((ax - avg_x) * (p.value - ay) - (ax - x(p)) * (avg_y - ay)).abs();
+            if area > max_area {
+                max_area = area;
+                chosen = bucket_start + j;
+            }
+        }
+
+        sampled.push(points[chosen]);
+        a = chosen;
+    }
+
+    sampled.push(points[points.len() - 1]);
+    sampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn series(values: &[f64]) -> Vec<SeriesPoint> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| SeriesPoint {
+                timestamp: Utc.timestamp_opt(i as i64 * 3600, 0).unwrap(),
+                value,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lttb_passthrough_when_small() {
+        let points = series(&[1.0, 2.0, 3.0]);
+        assert_eq!(lttb(&points, 500), points);
+    }
+
+    #[test]
+    fn test_lttb_keeps_endpoints_and_spikes() {
+        let mut values = vec![100.0; 1000];
+        values[500] = 5.0; // outage dip
+        let points = series(&values);
+
+        let sampled = lttb(&points, 50);
+        assert_eq!(sampled.len(), 50);
+        assert_eq!(sampled.first(), points.first());
+        assert_eq!(sampled.last(), points.last());
+        assert!(sampled.iter().any(|p| p.value == 5.0));
+        assert!(sampled.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+    }
+}
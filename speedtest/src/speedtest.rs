@@ -1,8 +1,20 @@
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::process::Command;
+use std::sync::Arc;
 use anyhow::{Result, Context};
 use log::{info, error};
 
+/// Servers tested each cycle: local (no server pinned), Los Angeles, Hong Kong,
+/// Atlanta and London. Note: these IDs might need to be updated.
+pub const TARGETS: &[(&str, Option<i32>)] = &[
+    ("Local", None),
+    ("Los Angeles", Some(18229)), // Starry
+    ("Hong Kong", Some(13538)),   // CSL
+    ("Atlanta", Some(10152)),     // Comcast - major peering hub, replaced slow NY server
+    ("London", Some(30690)),      // Community Fibre
+];
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SpeedtestResult {
     #[serde(rename = "server")]
@@ -57,7 +69,142 @@ pub struct ResultUrl {
     pub url: String,
 }
 
-pub fn run_speedtest(server_id: Option<i32>) -> Result<SpeedtestResult> {
+/// Runs a single speedtest against a server (the nearest one when `None`).
+///
+/// Selected by `SPEEDTEST_RUNNER`: `cli` (default) runs the Ookla CLI, `mock` returns
+/// synthetic results so the scheduler and API can be tested and demoed without it.
+pub trait SpeedtestRunner: Send + Sync {
+    fn run(&self, server_id: Option<i32>) -> Result<SpeedtestResult>;
+}
+
+pub fn runner_from_env() -> Result<Arc<dyn SpeedtestRunner>> {
+    match env::var("SPEEDTEST_RUNNER").as_deref() {
+        Err(_) | Ok("cli") => Ok(Arc::new(CliRunner)),
+        Ok("mock") => {
+            let runner = MockRunner::from_env()?;
+            info!("Using mock speedtest runner: {:?}", runner);
+            Ok(Arc::new(runner))
+        }
+        Ok(other) => anyhow::bail!("Unknown SPEEDTEST_RUNNER '{}', expected cli or mock", other),
+    }
+}
+
+/// The Ookla `speedtest` CLI
+pub struct CliRunner;
+
+impl SpeedtestRunner for CliRunner {
+    fn run(&self, server_id: Option<i32>) -> Result<SpeedtestResult> {
+        run_speedtest(server_id)
+    }
+}
+
+/// Synthetic results: the same configured numbers on every run, so output is predictable
+#[derive(Debug, Clone)]
+pub struct MockRunner {
+    pub download_mbps: f64,
+    pub upload_mbps: f64,
+    pub latency_ms: f32,
+    /// Fail every run instead, e.g. to exercise failure webhooks
+    pub fail: bool,
+}
+
+impl Default for MockRunner {
+    fn default() -> Self {
+        Self {
+            download_mbps: 500.0,
+            upload_mbps: 50.0,
+            latency_ms: 10.0,
+            fail: false,
+        }
+    }
+}
+
+impl MockRunner {
+    /// Defaults overridden by `SPEEDTEST_MOCK_DOWNLOAD_MBPS`, `SPEEDTEST_MOCK_UPLOAD_MBPS`,
+    /// `SPEEDTEST_MOCK_LATENCY_MS` and `SPEEDTEST_MOCK_FAIL`
+    pub fn from_env() -> Result<Self> {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> Result<T> {
+            match env::var(name) {
+                Ok(value) => value
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid {}: '{}'", name, value)),
+                Err(_) => Ok(default),
+            }
+        }
+
+        let defaults = Self::default();
+        Ok(Self {
+            download_mbps: var("SPEEDTEST_MOCK_DOWNLOAD_MBPS", defaults.download_mbps)?,
+            upload_mbps: var("SPEEDTEST_MOCK_UPLOAD_MBPS", defaults.upload_mbps)?,
+            latency_ms: var("SPEEDTEST_MOCK_LATENCY_MS", defaults.latency_ms)?,
+            fail: var("SPEEDTEST_MOCK_FAIL", defaults.fail)?,
+        })
+    }
+}
+
+impl SpeedtestRunner for MockRunner {
+    fn run(&self, server_id: Option<i32>) -> Result<SpeedtestResult> {
+        if self.fail {
+            anyhow::bail!("Speedtest failed: mock runner configured to fail");
+        }
+
+        // Megabits/sec to bytes/sec as the CLI reports it; bytes as if each test took 10 s
+        let download_bandwidth = (self.download_mbps * 125_000.0) as i32;
+        let upload_bandwidth = (self.upload_mbps * 125_000.0) as i32;
+        let id = server_id.unwrap_or(0);
+        let mut result = SpeedtestResult {
+            server_info: ServerInfo {
+                id,
+                name: format!("Mock Server {}", id),
+                location: "Mock".to_string(),
+                country: "Mockland".to_string(),
+            },
+            ping: PingInfo {
+                latency: self.latency_ms,
+            },
+            download: BandwidthInfo {
+                bandwidth: download_bandwidth,
+                bytes: download_bandwidth.saturating_mul(10),
+            },
+            upload: BandwidthInfo {
+                bandwidth: upload_bandwidth,
+                bytes: upload_bandwidth.saturating_mul(10),
+            },
+            result: ResultUrl {
+                url: format!("https://www.speedtest.net/result/c/mock-{}", id),
+            },
+            server_id: None,
+            server_name: None,
+            server_country: None,
+            latency_ms: None,
+            download_bandwidth: None,
+            upload_bandwidth: None,
+            download_bytes: None,
+            upload_bytes: None,
+            result_url: None,
+        };
+        result.populate_flattened();
+        Ok(result)
+    }
+}
+
+impl SpeedtestResult {
+    /// Copy the nested CLI fields into the flattened ones used for inserts
+    fn populate_flattened(&mut self) {
+        self.server_id = Some(self.server_info.id);
+        self.server_name = Some(self.server_info.name.clone());
+        self.server_country = Some(self.server_info.country.clone());
+        self.latency_ms = Some(self.ping.latency);
+        self.download_bandwidth = Some(self.download.bandwidth);
+        self.upload_bandwidth = Some(self.upload.bandwidth);
+        self.download_bytes = Some(self.download.bytes);
+        self.upload_bytes = Some(self.upload.bytes);
+        self.result_url = Some(self.result.url.clone());
+    }
+}
+
+fn run_speedtest(server_id: Option<i32>) -> Result<SpeedtestResult> {
     let mut cmd = Command::new("speedtest");
     cmd.arg("--accept-license").arg("--accept-gdpr").arg("-f").arg("json");
 
@@ -77,16 +224,35 @@ pub fn run_speedtest(server_id: Option<i32>) -> Result<SpeedtestResult> {
     let stdout = String::from_utf8_lossy(&output.stdout);
     let mut result: SpeedtestResult = serde_json::from_str(&stdout).context("Failed to parse speedtest JSON output")?;
 
-    // Populate flattened fields
-    result.server_id = Some(result.server_info.id);
-    result.server_name = Some(result.server_info.name.clone());
-    result.server_country = Some(result.server_info.country.clone());
-    result.latency_ms = Some(result.ping.latency);
-    result.download_bandwidth = Some(result.download.bandwidth);
-    result.upload_bandwidth = Some(result.upload.bandwidth);
-    result.download_bytes = Some(result.download.bytes);
-    result.upload_bytes = Some(result.upload.bytes);
-    result.result_url = Some(result.result.url.clone());
+    result.populate_flattened();
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_runner() {
+        let runner = MockRunner {
+            download_mbps: 100.0,
+            upload_mbps: 20.0,
+            latency_ms: 15.5,
+            fail: false,
+        };
+        let result = runner.run(Some(18229)).unwrap();
+        assert_eq!(result.server_id, Some(18229));
+        assert_eq!(result.download_bandwidth, Some(12_500_000));
+        assert_eq!(result.upload_bandwidth, Some(2_500_000));
+        assert_eq!(result.latency_ms, Some(15.5));
+        assert_eq!(result.server_name.as_deref(), Some("Mock Server 18229"));
+
+        // Deterministic across runs
+        assert_eq!(runner.run(Some(18229)).unwrap().download.bytes, result.download.bytes);
+        assert_eq!(runner.run(None).unwrap().server_id, Some(0));
+
+        let failing = MockRunner { fail: true, ..MockRunner::default() };
+        assert!(failing.run(None).is_err());
+    }
+}
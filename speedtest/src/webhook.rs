@@ -0,0 +1,114 @@
+use crate::speedtest::SpeedtestResult;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::env;
+use std::time::Duration;
+
+/// Body POSTed to the webhook after every run
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum WebhookPayload<'a> {
+    Success {
+        target: &'a str,
+        timestamp: DateTime<Utc>,
+        result: &'a SpeedtestResult,
+    },
+    Failure {
+        target: &'a str,
+        timestamp: DateTime<Utc>,
+        error: String,
+    },
+}
+
+/// Posts run results to an optional per-target webhook.
+///
+/// The URL for a target comes from `WEBHOOK_URL_<TARGET>` (target name upper-cased,
+/// non-alphanumerics replaced by `_`, e.g. `WEBHOOK_URL_LOS_ANGELES`), falling back
+/// to `WEBHOOK_URL`. Targets with neither set are skipped.
+#[derive(Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new() -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self { client })
+    }
+
+    fn url_for(target: &str) -> Option<String> {
+        env::var(format!("WEBHOOK_URL_{}", env_key(target)))
+            .or_else(|_| env::var("WEBHOOK_URL"))
+            .ok()
+            .filter(|url| !url.is_empty())
+    }
+
+    /// Send the outcome of a run. Failures to deliver are logged, never propagated.
+    pub async fn notify(&self, target: &str, outcome: &Result<SpeedtestResult>) {
+        let Some(url) = Self::url_for(target) else {
+            return;
+        };
+
+        let timestamp = Utc::now();
+        let payload = match outcome {
+            Ok(result) => WebhookPayload::Success {
+                target,
+                timestamp,
+                result,
+            },
+            Err(e) => WebhookPayload::Failure {
+                target,
+                timestamp,
+                error: format!("{:#}", e),
+            },
+        };
+
+        match self.client.post(&url).json(&payload).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                info!("Webhook for {} delivered", target);
+            }
+            Ok(resp) => {
+                error!("Webhook for {} returned {}", target, resp.status());
+            }
+            Err(e) => {
+                error!("Failed to deliver webhook for {}: {}", target, e);
+            }
+        }
+    }
+}
+
+/// Convert a target name like "Los Angeles" to an env var suffix like "LOS_ANGELES"
+fn env_key(target: &str) -> String {
+    target
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_key() {
+        assert_eq!(env_key("Los Angeles"), "LOS_ANGELES");
+        assert_eq!(env_key("Local"), "LOCAL");
+    }
+
+    #[test]
+    fn test_failure_payload() {
+        let payload = WebhookPayload::Failure {
+            target: "London",
+            timestamp: Utc::now(),
+            error: "Speedtest failed: timeout".to_string(),
+        };
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["status"], "failure");
+        assert_eq!(json["target"], "London");
+        assert_eq!(json["error"], "Speedtest failed: timeout");
+    }
+}
@@ -0,0 +1,2 @@
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS title TEXT;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS tag TEXT;
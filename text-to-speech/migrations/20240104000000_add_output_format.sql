@@ -0,0 +1,2 @@
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS format TEXT NOT NULL DEFAULT 'mp3';
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS content_type TEXT NOT NULL DEFAULT 'audio/mpeg';
@@ -0,0 +1,2 @@
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS bitrate_kbps INTEGER;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS sample_rate INTEGER;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS audio_usage (
+    username TEXT NOT NULL,
+    -- First day of the accounting month (UTC)
+    month DATE NOT NULL,
+    audio_seconds DOUBLE PRECISION NOT NULL DEFAULT 0,
+    PRIMARY KEY (username, month)
+);
@@ -0,0 +1,3 @@
+-- Hash of everything that determines a job's output, so identical requests reuse it
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS content_hash TEXT;
+CREATE INDEX IF NOT EXISTS idx_jobs_username_content_hash ON jobs (username, content_hash);
@@ -0,0 +1,2 @@
+-- Per-job retention override in days; NULL uses CLEANUP_RETENTION_DAYS
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS ttl_days INTEGER;
@@ -0,0 +1,2 @@
+-- Input text of each job, so jobs interrupted by a restart can be synthesized again
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS input_text TEXT;
@@ -0,0 +1,7 @@
+-- Transient failures are retried with backoff. max_retries is fixed when the job is created;
+-- next_attempt_at keeps a requeued job from being claimed until its backoff has passed.
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS retry_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS max_retries INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS next_attempt_at TIMESTAMPTZ;
+-- Retries synthesize the text again, but it only counts against the monthly cap once
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS usage_recorded BOOLEAN NOT NULL DEFAULT FALSE;
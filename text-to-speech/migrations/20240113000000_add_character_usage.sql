@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS character_usage (
+    username TEXT NOT NULL,
+    -- Accounting day (UTC)
+    day DATE NOT NULL,
+    characters BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (username, day)
+);
@@ -0,0 +1,11 @@
+-- Static API keys for clients that can't obtain a Keycloak token, sent as X-Api-Key.
+-- Only the hex SHA-256 of each key is stored.
+CREATE TABLE IF NOT EXISTS api_keys (
+    key_hash TEXT PRIMARY KEY,
+    -- Requests made with the key are attributed to this user
+    username TEXT NOT NULL,
+    description TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    -- When the request was received
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- NULL for downloads through a signed link
+    username TEXT,
+    method TEXT NOT NULL,
+    -- Route template, e.g. /status/:id
+    route TEXT NOT NULL,
+    job_id UUID,
+    -- HTTP status of the response
+    status INTEGER NOT NULL,
+    duration_ms DOUBLE PRECISION NOT NULL,
+    client_ip TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_created_at ON audit_log (created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_username_created_at ON audit_log (username, created_at);
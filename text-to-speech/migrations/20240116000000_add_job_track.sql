@@ -0,0 +1,2 @@
+-- Track number tag, e.g. "3" or "3/12", for jobs that are part of a series
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS track TEXT;
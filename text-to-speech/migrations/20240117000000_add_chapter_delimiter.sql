@@ -0,0 +1,2 @@
+-- Lines starting with this mark chapters in m4b output, instead of detected headings
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS chapter_delimiter TEXT;
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS lexicon_entries (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    -- Word or phrase as written, matched case-insensitively on word boundaries
+    grapheme TEXT NOT NULL,
+    -- Exactly one of: IPA phonemes, or a respelling to read instead
+    phonemes TEXT,
+    replacement TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CHECK ((phonemes IS NULL) <> (replacement IS NULL))
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_lexicon_entries_username_grapheme ON lexicon_entries (username, LOWER(grapheme));
@@ -0,0 +1,3 @@
+-- espeak-ng language the text is phonemized with, e.g. es or fr-fr; NULL for jobs from
+-- before it could be chosen, which used the voice's language
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS lang TEXT;
@@ -0,0 +1,2 @@
+-- Pitch shift in semitones, applied while encoding; NULL for none
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS pitch REAL;
@@ -0,0 +1,4 @@
+-- Labels to find jobs by; unlike tag, not written to the output file
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS idx_jobs_tags ON jobs USING GIN (tags);
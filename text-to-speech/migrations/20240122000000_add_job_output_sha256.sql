@@ -0,0 +1,2 @@
+-- SHA-256 (hex) of the encoded output, served as the download's ETag
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS output_sha256 TEXT;
@@ -0,0 +1,2 @@
+-- Target integrated loudness in LUFS, applied while encoding; NULL for none
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS loudness_lufs REAL;
@@ -0,0 +1,13 @@
+-- Formats requested besides the job's own, e.g. {opus,vtt}
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS renditions TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE TABLE IF NOT EXISTS job_artifacts (
+    job_id UUID NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+    -- One of the job's renditions
+    format TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    file_size_bytes BIGINT,
+    sha256 TEXT,
+    PRIMARY KEY (job_id, format)
+);
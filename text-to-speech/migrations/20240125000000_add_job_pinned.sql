@@ -0,0 +1,2 @@
+-- Pinned jobs are never deleted by cleanup or evicted for the storage quota
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS pinned BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+-- X-Request-Id of the request that created the job, to correlate its logs
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS request_id TEXT;
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS custom_voices (
+    username TEXT NOT NULL,
+    -- Name the voice is selected by, unique per user
+    name TEXT NOT NULL,
+    -- espeak-ng language text is phonemized with unless a request sets lang
+    lang TEXT NOT NULL,
+    -- The (510, 1, 256) style matrix as little-endian f32
+    embedding BYTEA NOT NULL,
+    -- SHA-256 of the embedding, so jobs with a replaced voice aren't reused
+    sha256 TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (username, name)
+);
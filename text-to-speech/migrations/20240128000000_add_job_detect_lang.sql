@@ -0,0 +1,3 @@
+-- Whether each sentence is phonemized in the language it's detected as, for jobs whose
+-- request didn't set lang. Older jobs used the one language throughout.
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS detect_lang BOOLEAN NOT NULL DEFAULT FALSE;
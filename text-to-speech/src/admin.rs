@@ -0,0 +1,214 @@
+//! Operator endpoints under `/admin`, for users listed in `ADMIN_USERS`.
+
+use crate::auth::AuthenticatedUser;
+use crate::cleanup::{self, CleanupReport};
+use crate::phonemizer::CacheStats;
+use crate::state::AppState;
+use axum::{
+    extract::{Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Parse `ADMIN_USERS`: comma-separated usernames
+pub fn parse_admin_users(value: &str) -> HashSet<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|user| !user.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Rejects users who aren't admins with `403`. Runs after `auth_middleware`.
+pub async fn require_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    let is_admin = request
+        .extensions()
+        .get::<AuthenticatedUser>()
+        .is_some_and(|user| state.admin_users.contains(&user.username));
+    if !is_admin {
+        return Err((StatusCode::FORBIDDEN, "Admin access required".to_string()));
+    }
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize)]
+pub struct CleanupParams {
+    /// Overrides `CLEANUP_ORPHANS_DRY_RUN` for this run
+    orphans_dry_run: Option<bool>,
+}
+
+/// `POST /admin/cleanup`: delete expired jobs now instead of waiting for the cleanup loop
+pub async fn trigger_cleanup(
+    State(state): State<AppState>,
+    Query(params): Query<CleanupParams>,
+) -> Result<Json<CleanupReport>, (StatusCode, String)> {
+    let report = cleanup::run_cleanup(
+        &state.pool,
+        &state.storage_path,
+        state.cleanup.retention_days,
+        params
+            .orphans_dry_run
+            .unwrap_or(state.cleanup.orphans_dry_run),
+    )
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Manual cleanup failed");
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    tracing::info!(
+        jobs_removed = report.jobs_removed,
+        bytes_removed = report.bytes_removed,
+        orphans_found = report.orphans_found,
+        "Manual cleanup finished"
+    );
+    Ok(Json(report))
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct StorageUsage {
+    /// Files under `storage_path`, including any no longer referenced by a job
+    pub files: u64,
+    pub bytes: u64,
+    /// Rows in the jobs table
+    pub jobs: i64,
+}
+
+/// `GET /admin/storage`: current disk usage of the storage directory
+pub async fn storage_usage(
+    State(state): State<AppState>,
+) -> Result<Json<StorageUsage>, (StatusCode, String)> {
+    let storage_path = state.storage_path.clone();
+    let mut usage = tokio::task::spawn_blocking(move || disk_usage(Path::new(&storage_path)))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to measure storage usage");
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+
+    usage.jobs = sqlx::query_scalar("SELECT COUNT(*) FROM jobs")
+        .fetch_one(&state.pool)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(usage))
+}
+
+/// `GET /admin/phonemizer`: hit rate of the phoneme cache since startup
+pub async fn phonemizer_stats(State(state): State<AppState>) -> Json<CacheStats> {
+    Json(state.phonemizer.cache_stats())
+}
+
+/// Count and total size of the files under `dir`, recursively
+fn disk_usage(dir: &Path) -> std::io::Result<StorageUsage> {
+    let mut usage = StorageUsage::default();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                usage.files += 1;
+                usage.bytes += entry.metadata()?.len();
+            }
+        }
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_parse_admin_users() {
+        assert!(parse_admin_users("").is_empty());
+        assert_eq!(
+            parse_admin_users(" alice, ,bob "),
+            HashSet::from(["alice".to_string(), "bob".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.mp3"), [0u8; 10]).unwrap();
+        std::fs::create_dir_all(dir.path().join("alice/2025")).unwrap();
+        std::fs::write(dir.path().join("alice/2025/b.mp3"), [0u8; 5]).unwrap();
+        assert_eq!(
+            disk_usage(dir.path()).unwrap(),
+            StorageUsage {
+                files: 2,
+                bytes: 15,
+                jobs: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_cleanup() {
+        let app = TestApp::spawn().await;
+
+        let expired = app.insert_completed_job("alice", &[0u8; 100]).await;
+        app.insert_completed_job("alice", &[0u8; 40]).await;
+        sqlx::query("UPDATE jobs SET last_accessed_at = NOW() - INTERVAL '30 days' WHERE id = $1")
+            .bind(expired)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+
+        // Regular users are refused
+        let resp = app
+            .client
+            .post(app.url("/admin/cleanup"))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let storage = |app: &TestApp| {
+            app.client
+                .get(app.url("/admin/storage"))
+                .bearer_auth(app.token(TestApp::ADMIN))
+                .send()
+        };
+        let body: serde_json::Value = storage(&app).await.unwrap().json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "files": 2, "bytes": 140, "jobs": 2 })
+        );
+
+        let resp = app
+            .client
+            .post(app.url("/admin/cleanup"))
+            .bearer_auth(app.token(TestApp::ADMIN))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["jobs_removed"], 1);
+        assert_eq!(body["bytes_removed"], 100);
+        assert_eq!(body["orphans_found"], 0);
+
+        let body: serde_json::Value = storage(&app).await.unwrap().json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "files": 1, "bytes": 40, "jobs": 1 })
+        );
+
+        app.teardown().await;
+    }
+}
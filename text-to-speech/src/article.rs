@@ -0,0 +1,418 @@
+//! Fetching web pages for `POST /generate/url` and extracting their main article.
+//!
+//! Extraction is readability-style: an `<article>` or `<main>` element when the page has
+//! one, otherwise the element holding the most paragraph text. Navigation, headers,
+//! footers, forms, scripts and blocks whose class or id looks like comments, sharing
+//! buttons or ads are left out.
+//!
+//! Unless `URL_FETCH_ALLOW_PRIVATE` is set, pages are only fetched from public addresses,
+//! so the endpoint can't be used to reach services inside the cluster.
+
+use crate::html_text::{collapse_whitespace, text_of};
+use axum::http::StatusCode;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Largest page that is downloaded
+const MAX_PAGE_BYTES: usize = 10 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_REDIRECTS: usize = 5;
+/// Paragraphs shorter than this (captions, bylines, buttons) don't count towards a
+/// candidate's score
+const MIN_PARAGRAPH_CHARS: usize = 25;
+
+/// Elements that are never part of the article
+const SKIPPED_ELEMENTS: &[&str] = &[
+    "head", "script", "style", "noscript", "nav", "header", "footer", "aside", "form", "button",
+    "iframe", "svg", "figure", "template",
+];
+
+/// Class or id fragments of boilerplate blocks
+const BOILERPLATE_HINTS: &[&str] = &[
+    "comment",
+    "share",
+    "social",
+    "related",
+    "promo",
+    "advert",
+    "newsletter",
+    "subscribe",
+    "cookie",
+    "sidebar",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct Article {
+    pub title: Option<String>,
+    pub text: String,
+}
+
+/// The main article of an HTML page, or `None` if there's no text to read
+pub fn extract_article(html: &str) -> Option<Article> {
+    let document = Html::parse_document(html);
+    let select = |selector: &str| {
+        let selector = Selector::parse(selector).expect("valid selector");
+        document.select(&selector).collect::<Vec<_>>()
+    };
+
+    let title = select("meta[property='og:title']")
+        .first()
+        .and_then(|meta| meta.value().attr("content"))
+        .map(collapse_whitespace)
+        .or_else(|| {
+            select("title")
+                .first()
+                .map(|title| collapse_whitespace(&title.text().collect::<String>()))
+        })
+        .filter(|title| !title.is_empty());
+
+    let candidate = select("article")
+        .into_iter()
+        .max_by_key(|article| paragraph_chars(*article))
+        .or_else(|| select("main, [role=main]").into_iter().next())
+        .or_else(|| best_paragraph_container(&select("p")))
+        .or_else(|| select("body").into_iter().next())?;
+
+    let text = text_of(candidate, &is_boilerplate);
+    if text.is_empty() {
+        return None;
+    }
+    Some(Article { title, text })
+}
+
+fn is_boilerplate(element: ElementRef) -> bool {
+    let value = element.value();
+    if SKIPPED_ELEMENTS.contains(&value.name()) {
+        return true;
+    }
+    let names = format!(
+        "{} {}",
+        value.attr("class").unwrap_or(""),
+        value.attr("id").unwrap_or("")
+    )
+    .to_ascii_lowercase();
+    BOILERPLATE_HINTS.iter().any(|hint| names.contains(hint))
+}
+
+/// Characters of paragraph text directly or indirectly under `element`
+fn paragraph_chars(element: ElementRef) -> usize {
+    let selector = Selector::parse("p").expect("valid selector");
+    element
+        .select(&selector)
+        .map(|p| p.text().map(str::len).sum::<usize>())
+        .filter(|&len| len >= MIN_PARAGRAPH_CHARS)
+        .sum()
+}
+
+/// The element whose child paragraphs hold the most text
+fn best_paragraph_container<'a>(paragraphs: &[ElementRef<'a>]) -> Option<ElementRef<'a>> {
+    let mut scores: HashMap<ego_tree::NodeId, (ElementRef<'a>, usize)> = HashMap::new();
+    for paragraph in paragraphs {
+        let len: usize = paragraph.text().map(str::len).sum();
+        if len < MIN_PARAGRAPH_CHARS
+            || paragraph
+                .ancestors()
+                .any(|a| ElementRef::wrap(a).is_some_and(is_boilerplate))
+        {
+            continue;
+        }
+        if let Some(parent) = paragraph.parent().and_then(ElementRef::wrap) {
+            scores.entry(parent.id()).or_insert((parent, 0)).1 += len;
+        }
+    }
+    scores
+        .into_values()
+        .max_by_key(|(_, score)| *score)
+        .map(|(element, _)| element)
+}
+
+/// Whether `ip` is reachable on the public internet, as opposed to loopback, private,
+/// link-local or otherwise reserved ranges
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Resolves host names to their public addresses only
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(
+                    format!("{} does not resolve to a public address", name.as_str()).into(),
+                );
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Whether `url`'s host is an IP address that isn't public. Host names are checked
+/// when they are resolved.
+fn is_private_literal(url: &reqwest::Url) -> bool {
+    url.host_str()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+        .and_then(|host| host.parse::<IpAddr>().ok())
+        .is_some_and(|ip| !is_public_ip(ip))
+}
+
+/// A fetched page's body and content type
+pub struct Page {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/// Download `url`, following redirects. Errors are the status to respond with and why.
+pub async fn fetch(url: &str, allow_private: bool) -> Result<Page, (StatusCode, String)> {
+    let url = reqwest::Url::parse(url)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid URL: {}", e)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Only http and https URLs can be fetched".to_string(),
+        ));
+    }
+    let forbidden = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "URLs must point to a public address".to_string(),
+        )
+    };
+    if !allow_private && is_private_literal(&url) {
+        return Err(forbidden());
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .user_agent(concat!("text-to-speech/", env!("CARGO_PKG_VERSION")));
+    builder = if allow_private {
+        builder.redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS))
+    } else {
+        builder
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("Too many redirects")
+                } else if is_private_literal(attempt.url()) {
+                    attempt.error("Redirected to a private address")
+                } else {
+                    attempt.follow()
+                }
+            }))
+    };
+    let client = builder
+        .build()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut response = client.get(url.clone()).send().await.map_err(|e| {
+        let e = error_chain(&e);
+        tracing::warn!(url = %url, error = %e, "Failed to fetch URL");
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to fetch {}: {}", url, e),
+        )
+    })?;
+    if !response.status().is_success() {
+        return Err((
+            StatusCode::BAD_GATEWAY,
+            format!("Fetching {} returned HTTP {}", url, response.status()),
+        ));
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to read {}: {}", url, e),
+        )
+    })? {
+        if body.len() + chunk.len() > MAX_PAGE_BYTES {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Page is larger than {} bytes", MAX_PAGE_BYTES),
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Page { content_type, body })
+}
+
+/// An error and its causes, which reqwest leaves out of its own message
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    const PAGE: &str = r#"<html><head>
+<title>Site name | Fallback title</title>
+<meta property="og:title" content="Why  Speech Matters">
+<script>var tracking = 1;</script>
+</head><body>
+<header><nav><a href="/">Home</a> <a href="/about">About</a></nav></header>
+<div class="layout">
+  <div class="content">
+    <h1>Why Speech Matters</h1>
+    <p>Listening to long articles is easier than reading them on a small screen.</p>
+    <div class="share-buttons"><p>Share this article with your friends and family today!</p></div>
+    <p>Synthesized speech has become good enough to enjoy for hours at a time.</p>
+  </div>
+  <div class="sidebar"><p>Popular posts that you might also want to read next week.</p></div>
+</div>
+<div id="comments"><p>Great article, thanks for writing it up for all of us!</p></div>
+<footer><p>Copyright 2024 Example Media Group, all rights reserved.</p></footer>
+</body></html>"#;
+
+    #[test]
+    fn test_extract_article() {
+        let article = extract_article(PAGE).unwrap();
+        assert_eq!(article.title.as_deref(), Some("Why Speech Matters"));
+        assert_eq!(
+            article.text,
+            "Why Speech Matters\n\
+             Listening to long articles is easier than reading them on a small screen.\n\
+             Synthesized speech has become good enough to enjoy for hours at a time."
+        );
+
+        // An <article> element is taken as is
+        let html = "<html><head><title>T</title></head><body><p>Unrelated intro text outside of the article body.</p><article><p>The story.</p><aside>Ad</aside></article></body></html>";
+        let article = extract_article(html).unwrap();
+        assert_eq!(article.title.as_deref(), Some("T"));
+        assert_eq!(article.text, "The story.");
+
+        assert_eq!(
+            extract_article("<html><body><nav>Menu</nav></body></html>"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_private_addresses() {
+        let err = fetch("http://127.0.0.1:9/", false).await.err().unwrap();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = fetch("http://localhost:9/", false).await.err().unwrap();
+        assert_eq!(err.0, StatusCode::BAD_GATEWAY);
+        assert!(err.1.contains("public address"), "{}", err.1);
+        let err = fetch("file:///etc/passwd", false).await.err().unwrap();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_url() {
+        let app = TestApp::spawn_without_workers().await;
+        let site = axum::Router::new()
+            .route(
+                "/article",
+                axum::routing::get(|| async { axum::response::Html(PAGE) }),
+            )
+            .route(
+                "/empty",
+                axum::routing::get(|| async { axum::response::Html("<html><body></body></html>") }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let site_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, site).await.unwrap() });
+
+        let generate = |path: &str| {
+            app.client
+                .post(app.url("/generate/url"))
+                .bearer_auth(app.token("alice"))
+                .json(&serde_json::json!({ "url": format!("http://{}{}", site_addr, path), "voice": "af_heart" }))
+                .send()
+        };
+
+        let resp = generate("/article").await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let row: (Option<String>, String) =
+            sqlx::query_as("SELECT title, input_text FROM jobs WHERE id = $1")
+                .bind(uuid::Uuid::parse_str(body["id"].as_str().unwrap()).unwrap())
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(row.0.as_deref(), Some("Why Speech Matters"));
+        assert!(
+            row.1.starts_with("Why Speech Matters\nListening"),
+            "{}",
+            row.1
+        );
+
+        let resp = generate("/empty").await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let resp = generate("/missing").await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_GATEWAY);
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,332 @@
+//! Audit log of API activity.
+//!
+//! Generate, status and download requests that get past authentication are recorded in
+//! `audit_log` with the user, route, job, response status, duration and client IP, so an
+//! admin can see who generated or fetched what through `GET /admin/audit`. Rows older
+//! than `AUDIT_RETENTION_DAYS` are removed by the cleanup loop.
+
+use crate::auth::AuthenticatedUser;
+use crate::state::AppState;
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Query, RawPathParams, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Json, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Row};
+use std::net::SocketAddr;
+use std::time::Instant;
+use uuid::Uuid;
+
+const DEFAULT_RETENTION_DAYS: i32 = 90;
+const DEFAULT_QUERY_LIMIT: i64 = 100;
+const MAX_QUERY_LIMIT: i64 = 1000;
+
+/// Attributes a response to a job that isn't in the request path, e.g. one just created
+#[derive(Debug, Clone, Copy)]
+pub struct AuditJobId(pub Uuid);
+
+/// Parse `AUDIT_RETENTION_DAYS`: days audit rows are kept (default 90)
+pub fn parse_retention_days(value: Option<&str>) -> Result<i32, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .parse::<i32>()
+            .ok()
+            .filter(|&days| days > 0)
+            .ok_or_else(|| format!("Invalid AUDIT_RETENTION_DAYS '{}'", v)),
+        None => Ok(DEFAULT_RETENTION_DAYS),
+    }
+}
+
+/// Records the request once the handler has responded. Layer it on individual routes,
+/// inside `auth_middleware`, so the user is known.
+pub async fn record(
+    State(state): State<AppState>,
+    matched_path: MatchedPath,
+    path_params: RawPathParams,
+    request: Request,
+    next: Next,
+) -> Response {
+    let received_at = Utc::now();
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let username = request
+        .extensions()
+        .get::<AuthenticatedUser>()
+        .map(|user| user.username.clone());
+    let client_ip = client_ip(
+        request.headers(),
+        request.extensions().get::<ConnectInfo<SocketAddr>>(),
+    );
+    let path_job_id = path_params
+        .iter()
+        .find(|(name, _)| *name == "id")
+        .and_then(|(_, value)| Uuid::parse_str(value).ok());
+
+    let response = next.run(request).await;
+
+    let job_id = response
+        .extensions()
+        .get::<AuditJobId>()
+        .map(|AuditJobId(id)| *id)
+        .or(path_job_id);
+    let status = response.status().as_u16() as i32;
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    // Don't hold up the response on the write
+    tokio::spawn(async move {
+        let result = sqlx::query(
+            "INSERT INTO audit_log (created_at, username, method, route, job_id, status, duration_ms, client_ip) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(received_at)
+        .bind(username)
+        .bind(method)
+        .bind(matched_path.as_str())
+        .bind(job_id)
+        .bind(status)
+        .bind(duration_ms)
+        .bind(client_ip)
+        .execute(&state.pool)
+        .await;
+        if let Err(e) = result {
+            tracing::error!(error = %e, "Failed to write audit log entry");
+        }
+    });
+    response
+}
+
+/// The first address in `X-Forwarded-For` (set by the ingress), else the peer address
+fn client_ip(headers: &HeaderMap, peer: Option<&ConnectInfo<SocketAddr>>) -> Option<String> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.split(',').next())
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(str::to_string)
+        .or_else(|| peer.map(|ConnectInfo(addr)| addr.ip().to_string()))
+}
+
+/// Delete audit rows older than `retention_days`, returning how many were removed
+pub async fn prune(pool: &Pool<Postgres>, retention_days: i32) -> Result<u64, sqlx::Error> {
+    let result =
+        sqlx::query("DELETE FROM audit_log WHERE created_at < NOW() - make_interval(days => $1)")
+            .bind(retention_days)
+            .execute(pool)
+            .await?;
+    Ok(result.rows_affected())
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    username: Option<String>,
+    job_id: Option<String>,
+    /// Route template, e.g. `/status/:id`
+    route: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// Newest entries first; default 100, at most 1000
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    id: i64,
+    created_at: DateTime<Utc>,
+    /// `null` for downloads through a signed link
+    username: Option<String>,
+    method: String,
+    route: String,
+    job_id: Option<String>,
+    status: i32,
+    duration_ms: f64,
+    client_ip: Option<String>,
+}
+
+/// `GET /admin/audit`: recorded requests, newest first, optionally filtered
+pub async fn query_audit_log(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, (StatusCode, String)> {
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    if !(1..=MAX_QUERY_LIMIT).contains(&limit) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("limit must be between 1 and {}", MAX_QUERY_LIMIT),
+        ));
+    }
+
+    let job_id = query
+        .job_id
+        .as_deref()
+        .map(Uuid::parse_str)
+        .transpose()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid job_id".to_string()))?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT id, created_at, username, method, route, job_id, status, duration_ms, client_ip
+        FROM audit_log
+        WHERE ($1::TEXT IS NULL OR username = $1)
+          AND ($2::UUID IS NULL OR job_id = $2)
+          AND ($3::TEXT IS NULL OR route = $3)
+          AND ($4::TIMESTAMPTZ IS NULL OR created_at >= $4)
+          AND ($5::TIMESTAMPTZ IS NULL OR created_at < $5)
+        ORDER BY created_at DESC, id DESC
+        LIMIT $6
+        "#,
+    )
+    .bind(&query.username)
+    .bind(job_id)
+    .bind(&query.route)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Failed to query audit log");
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+
+    let entries = rows
+        .iter()
+        .map(|row| AuditEntry {
+            id: row.get("id"),
+            created_at: row.get("created_at"),
+            username: row.get("username"),
+            method: row.get("method"),
+            route: row.get("route"),
+            job_id: row
+                .get::<Option<Uuid>, _>("job_id")
+                .map(|id| id.to_string()),
+            status: row.get("status"),
+            duration_ms: row.get("duration_ms"),
+            client_ip: row.get("client_ip"),
+        })
+        .collect();
+    Ok(Json(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_parse_retention_days() {
+        assert_eq!(parse_retention_days(None), Ok(DEFAULT_RETENTION_DAYS));
+        assert_eq!(parse_retention_days(Some(" 30 ")), Ok(30));
+        assert!(parse_retention_days(Some("0")).is_err());
+        assert!(parse_retention_days(Some("forever")).is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let peer = ConnectInfo(SocketAddr::from(([10, 0, 0, 7], 51234)));
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            client_ip(&headers, Some(&peer)).as_deref(),
+            Some("10.0.0.7")
+        );
+        headers.insert("x-forwarded-for", "203.0.113.9, 10.0.0.1".parse().unwrap());
+        assert_eq!(
+            client_ip(&headers, Some(&peer)).as_deref(),
+            Some("203.0.113.9")
+        );
+        assert_eq!(client_ip(&HeaderMap::new(), None), None);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let app = TestApp::spawn_without_workers().await;
+
+        let resp = app
+            .client
+            .post(app.url("/generate-json"))
+            .bearer_auth(app.token("alice"))
+            .header("x-forwarded-for", "203.0.113.9")
+            .json(&serde_json::json!({ "text": "Hello." }))
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let job_id = body["id"].as_str().unwrap().to_string();
+        let resp = app
+            .client
+            .get(app.url(&format!("/status/{}", job_id)))
+            .bearer_auth(app.token("bob"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        // Not an audited route
+        app.client
+            .get(app.url("/jobs"))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap();
+
+        // Entries are written in the background
+        let audit = |query: &str| {
+            app.client
+                .get(app.url(&format!("/admin/audit{}", query)))
+                .bearer_auth(app.token(TestApp::ADMIN))
+                .send()
+        };
+        let deadline = Instant::now() + std::time::Duration::from_secs(5);
+        let entries = loop {
+            let entries: Vec<serde_json::Value> = audit("").await.unwrap().json().await.unwrap();
+            if entries.len() >= 2 || Instant::now() > deadline {
+                break entries;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        };
+        assert_eq!(entries.len(), 2);
+
+        // Newest first
+        assert_eq!(entries[0]["username"], "bob");
+        assert_eq!(entries[0]["method"], "GET");
+        assert_eq!(entries[0]["route"], "/status/:id");
+        assert_eq!(entries[0]["job_id"], job_id);
+        assert_eq!(entries[0]["status"], 404);
+        assert_eq!(entries[0]["client_ip"], "127.0.0.1");
+
+        assert_eq!(entries[1]["username"], "alice");
+        assert_eq!(entries[1]["route"], "/generate-json");
+        assert_eq!(entries[1]["job_id"], job_id);
+        assert_eq!(entries[1]["status"], 200);
+        assert_eq!(entries[1]["client_ip"], "203.0.113.9");
+
+        let entries: Vec<serde_json::Value> = audit("?username=alice")
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        let resp = audit("?limit=0").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = app
+            .client
+            .get(app.url("/admin/audit"))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        assert_eq!(prune(&app.pool, 1).await.unwrap(), 0);
+        sqlx::query(
+            "UPDATE audit_log SET created_at = NOW() - INTERVAL '2 days' WHERE username = 'bob'",
+        )
+        .execute(&app.pool)
+        .await
+        .unwrap();
+        assert_eq!(prune(&app.pool, 1).await.unwrap(), 1);
+
+        app.teardown().await;
+    }
+}
@@ -1,3 +1,4 @@
+use crate::error::ApiError;
 use crate::state::AppState;
 use axum::{
     extract::{Request, State},
@@ -7,8 +8,12 @@ use axum::{
 };
 use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
+/// Header carrying a static API key, for clients that can't obtain a Keycloak token
+pub const API_KEY_HEADER: &str = "x-api-key";
+
 /// Represents an authenticated user extracted from the JWT token.
 /// This is added to request extensions by the auth middleware.
 #[derive(Debug, Clone)]
@@ -16,6 +21,58 @@ pub struct AuthenticatedUser {
     pub username: String,
 }
 
+/// Static API keys from `API_KEYS`, each attributed to a username. Kept as hashes, like
+/// the keys in the `api_keys` table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApiKeys {
+    users_by_hash: HashMap<String, String>,
+}
+
+impl ApiKeys {
+    /// Parse `API_KEYS`: comma-separated `username=key` pairs
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut users_by_hash = HashMap::new();
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (username, key) = entry
+                .split_once('=')
+                .map(|(username, key)| (username.trim(), key.trim()))
+                .filter(|(username, key)| !username.is_empty() && !key.is_empty())
+                .ok_or("Invalid API_KEYS entry, expected username=key")?;
+            users_by_hash.insert(hash_api_key(key), username.to_string());
+        }
+        Ok(Self { users_by_hash })
+    }
+}
+
+/// Hex SHA-256 of an API key, as stored in the `api_keys` table
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// The user an API key belongs to, from `API_KEYS` or the `api_keys` table. Only an unknown
+/// or revoked key is a `401`; failing to look it up is a `500`.
+async fn validate_api_key(state: &AppState, key: &str) -> Result<String, ApiError> {
+    let key_hash = hash_api_key(key);
+    if let Some(username) = state.api_keys.users_by_hash.get(&key_hash) {
+        return Ok(username.clone());
+    }
+
+    sqlx::query_scalar(
+        "UPDATE api_keys SET last_used_at = NOW() WHERE key_hash = $1 AND revoked_at IS NULL RETURNING username",
+    )
+    .bind(&key_hash)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(ApiError::database)?
+    .ok_or_else(|| {
+        ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_api_key",
+            "Authentication failed: Unknown or revoked API key",
+        )
+    })
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 struct KeycloakClaims {
@@ -144,15 +201,34 @@ pub async fn auth_middleware(
     State(state): State<AppState>,
     mut request: Request,
     next: Next,
-) -> Result<Response, (StatusCode, String)> {
+) -> Result<Response, ApiError> {
     // Skip auth in test mode - use a default test user
-    if std::env::var("TTS_TEST_MODE").is_ok() {
+    if state.auth_disabled {
+        crate::logging::record_user("test_user");
         request.extensions_mut().insert(AuthenticatedUser {
             username: "test_user".to_string(),
         });
         return Ok(next.run(request).await);
     }
 
+    if let Some(key) = request.headers().get(API_KEY_HEADER) {
+        let key = key.to_str().unwrap_or_default();
+        return match validate_api_key(&state, key).await {
+            Ok(username) => {
+                crate::logging::record_user(&username);
+                tracing::info!(user = %username, "Authenticated user with API key");
+                request
+                    .extensions_mut()
+                    .insert(AuthenticatedUser { username });
+                Ok(next.run(request).await)
+            }
+            Err(e) => {
+                tracing::warn!(error = %e.detail, "API key authentication failed");
+                Err(e)
+            }
+        };
+    }
+
     let auth_header = request
         .headers()
         .get(header::AUTHORIZATION)
@@ -161,9 +237,10 @@ pub async fn auth_middleware(
     let token = match auth_header {
         Some(h) if h.starts_with("Bearer ") => &h[7..],
         _ => {
-            return Err((
+            return Err(ApiError::new(
                 StatusCode::UNAUTHORIZED,
-                "Missing or invalid Authorization header".to_string(),
+                "missing_credentials",
+                "Missing or invalid Authorization header",
             ));
         }
     };
@@ -173,7 +250,8 @@ pub async fn auth_middleware(
             let username = claims
                 .preferred_username
                 .unwrap_or_else(|| claims.sub.clone());
-            tracing::info!("Authenticated user: {}", username);
+            crate::logging::record_user(&username);
+            tracing::info!(user = %username, "Authenticated user");
 
             // Store authenticated user in request extensions
             request
@@ -183,11 +261,143 @@ pub async fn auth_middleware(
             Ok(next.run(request).await)
         }
         Err(e) => {
-            tracing::warn!("Authentication failed: {}", e);
-            Err((
+            tracing::warn!(error = %e, "Authentication failed");
+            Err(ApiError::new(
                 StatusCode::UNAUTHORIZED,
+                "invalid_token",
                 format!("Authentication failed: {}", e),
             ))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{TestApp, sign_token};
+
+    #[test]
+    fn test_api_keys_parse() {
+        let keys = ApiKeys::parse(" cron=abc123, kindle=k=v ").unwrap();
+        assert_eq!(
+            keys.users_by_hash.get(&hash_api_key("abc123")).unwrap(),
+            "cron"
+        );
+        // Keys may contain '='
+        assert_eq!(
+            keys.users_by_hash.get(&hash_api_key("k=v")).unwrap(),
+            "kindle"
+        );
+        assert_eq!(ApiKeys::parse("").unwrap(), ApiKeys::default());
+        assert!(ApiKeys::parse("cron").is_err());
+        assert!(ApiKeys::parse("=abc123").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_auth() {
+        let app = TestApp::spawn().await;
+        sqlx::query("INSERT INTO api_keys (key_hash, username, revoked_at) VALUES ($1, 'reader', NULL), ($2, 'reader', NOW())")
+            .bind(hash_api_key("db-key"))
+            .bind(hash_api_key("revoked-key"))
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        let jobs = |key: &str| {
+            app.client
+                .get(app.url("/jobs"))
+                .header(API_KEY_HEADER, key)
+                .send()
+        };
+
+        // Requests are attributed to the key's user, whether it comes from API_KEYS or the table
+        for (key, username) in [
+            (TestApp::API_KEY, TestApp::API_KEY_USER),
+            ("db-key", "reader"),
+        ] {
+            let id = app.insert_completed_job(username, b"audio").await;
+            let resp = jobs(key).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body.as_array().unwrap().len(), 1);
+            assert_eq!(body[0]["id"], id.to_string());
+        }
+        let last_used: Option<chrono::DateTime<chrono::Utc>> = sqlx::query_scalar(
+            "SELECT last_used_at FROM api_keys WHERE username = 'reader' AND revoked_at IS NULL",
+        )
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        assert!(last_used.is_some());
+
+        assert_eq!(
+            jobs("revoked-key").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            jobs("wrong").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // A failed lookup isn't the key's fault, and its cause isn't shown to the caller
+        sqlx::query("ALTER TABLE api_keys RENAME TO api_keys_moved")
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        let resp = jobs("db-key").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "internal_error");
+        assert_eq!(problem["detail"], "Database error");
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_auth_middleware() {
+        let app = TestApp::spawn().await;
+
+        let resp = app.client.get(app.url("/jobs")).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = app
+            .client
+            .get(app.url("/jobs"))
+            .bearer_auth("not-a-jwt")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Expired beyond the default leeway
+        let expired = sign_token("alice", "tts", -600);
+        let resp = app
+            .client
+            .get(app.url("/jobs"))
+            .bearer_auth(expired)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let wrong_audience = sign_token("alice", "stt", 3600);
+        let resp = app
+            .client
+            .get(app.url("/jobs"))
+            .bearer_auth(wrong_audience)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = app
+            .client
+            .get(app.url("/jobs"))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        app.teardown().await;
+    }
+}
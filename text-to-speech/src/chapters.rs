@@ -0,0 +1,173 @@
+//! Chapters for audiobook (`m4b`) output.
+//!
+//! The input text is split at chapter headings: Markdown headings (`# Title`) and lines
+//! starting with "Chapter", or, when a job has a `chapter_delimiter`, lines starting with
+//! that instead. Each chapter is synthesized separately, and its start and end times are
+//! written to an ffmetadata file that ffmpeg muxes into the output as chapter marks.
+
+/// Longest line that is still treated as a "Chapter ..." heading rather than prose
+const MAX_HEADING_CHARS: usize = 80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    /// Text to synthesize, starting with the heading so it is read out
+    pub text: String,
+}
+
+/// Split `text` into chapters at headings, or at lines starting with `delimiter`.
+/// Text before the first heading becomes a chapter of its own; untitled chapters are
+/// numbered. Chapters with nothing to read are dropped.
+pub fn split_chapters(text: &str, delimiter: Option<&str>) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    let mut title: Option<String> = None;
+    let mut body = String::new();
+
+    for line in text.lines() {
+        match heading(line, delimiter) {
+            Some(next_title) => {
+                push_chapter(&mut chapters, title.take(), std::mem::take(&mut body));
+                title = Some(next_title);
+            }
+            None => {
+                body.push_str(line);
+                body.push('\n');
+            }
+        }
+    }
+    push_chapter(&mut chapters, title, body);
+    chapters
+}
+
+fn push_chapter(chapters: &mut Vec<Chapter>, title: Option<String>, body: String) {
+    let title = title.filter(|t| !t.is_empty());
+    if title.is_none() && body.trim().is_empty() {
+        return;
+    }
+    let mut text = String::new();
+    if let Some(title) = &title {
+        text.push_str(&spoken_heading(title));
+        text.push('\n');
+    }
+    text.push_str(body.trim());
+    let title = title.unwrap_or_else(|| format!("Chapter {}", chapters.len() + 1));
+    chapters.push(Chapter {
+        title,
+        text: text.trim().to_string(),
+    });
+}
+
+/// A heading as it is read out: as a sentence of its own
+pub fn spoken_heading(title: &str) -> String {
+    if title.ends_with(['.', '!', '?', ':']) {
+        title.to_string()
+    } else {
+        format!("{}.", title)
+    }
+}
+
+/// The chapter title if `line` is a heading
+fn heading(line: &str, delimiter: Option<&str>) -> Option<String> {
+    let line = line.trim();
+    if let Some(delimiter) = delimiter {
+        return line
+            .strip_prefix(delimiter)
+            .map(|title| title.trim().to_string());
+    }
+
+    let hashes = line.chars().take_while(|&c| c == '#').count();
+    if (1..=3).contains(&hashes) && line[hashes..].starts_with(' ') {
+        return Some(line[hashes..].trim().to_string());
+    }
+    let is_chapter_line = line
+        .get(..8)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("chapter "))
+        && line.chars().count() <= MAX_HEADING_CHARS;
+    is_chapter_line.then(|| line.to_string())
+}
+
+/// An ffmetadata file marking each chapter, given the chapters' titles and durations
+pub fn ffmetadata(chapters: &[(&str, f64)]) -> String {
+    let mut metadata = String::from(";FFMETADATA1\n");
+    let mut start_ms = 0u64;
+    for (title, duration_secs) in chapters {
+        let end_ms = start_ms + (duration_secs * 1000.0).round() as u64;
+        metadata.push_str(&format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            start_ms,
+            end_ms,
+            escape(title)
+        ));
+        start_ms = end_ms;
+    }
+    metadata
+}
+
+/// Escape ffmetadata's special characters with a backslash
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_chapters() {
+        let text = "A Tale\nBy Someone.\n\n# The Beginning\nIt was a dark night.\n\nCHAPTER TWO\nThe end.\n";
+        let chapters = split_chapters(text, None);
+        assert_eq!(
+            chapters,
+            [
+                Chapter {
+                    title: "Chapter 1".to_string(),
+                    text: "A Tale\nBy Someone.".to_string(),
+                },
+                Chapter {
+                    title: "The Beginning".to_string(),
+                    text: "The Beginning.\nIt was a dark night.".to_string(),
+                },
+                Chapter {
+                    title: "CHAPTER TWO".to_string(),
+                    text: "CHAPTER TWO.\nThe end.".to_string(),
+                },
+            ]
+        );
+
+        // Prose that merely starts with "Chapter" or "#" isn't a heading
+        let long_line = format!("Chapter and verse {}", "word ".repeat(20));
+        assert_eq!(split_chapters(&long_line, None).len(), 1);
+        assert_eq!(split_chapters("#hashtag\n#### Deep heading", None).len(), 1);
+
+        assert!(split_chapters("  \n\n", None).is_empty());
+    }
+
+    #[test]
+    fn test_split_chapters_with_delimiter() {
+        let text = "# Not a heading\n===\nFirst.\n=== Second part\nSecond.\n";
+        let chapters = split_chapters(text, Some("==="));
+        let titles: Vec<&str> = chapters.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(titles, ["Chapter 1", "Chapter 2", "Second part"]);
+        assert_eq!(chapters[0].text, "# Not a heading");
+        assert_eq!(chapters[1].text, "First.");
+        assert_eq!(chapters[2].text, "Second part.\nSecond.");
+    }
+
+    #[test]
+    fn test_ffmetadata() {
+        let metadata = ffmetadata(&[("Intro", 1.5), ("Part 1; a=b", 2.0)]);
+        assert_eq!(
+            metadata,
+            ";FFMETADATA1\n\
+             [CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=1500\ntitle=Intro\n\
+             [CHAPTER]\nTIMEBASE=1/1000\nSTART=1500\nEND=3500\ntitle=Part 1\\; a\\=b\n"
+        );
+    }
+}
@@ -1,26 +1,426 @@
+use crate::format::OutputFormat;
+use crate::renditions::Rendition;
+use serde::Serialize;
 use sqlx::{Pool, Postgres, Row};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-pub async fn run_cleanup(pool: &Pool<Postgres>, storage_path: &str) -> anyhow::Result<()> {
+/// Name prefix of per-job scratch directories created under the scratch root
+pub const SCRATCH_DIR_PREFIX: &str = "tts-job-";
+
+/// Upper bound for a job's `ttl_days`
+pub const MAX_TTL_DAYS: i32 = 3650;
+
+/// Audio files younger than this are never treated as orphans: a job writes its output
+/// before recording the path in the database
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(3600);
+
+/// How long jobs are kept after they were last accessed, and how often to check
+#[derive(Debug, Clone, PartialEq)]
+pub struct CleanupConfig {
+    /// Default for jobs without their own `ttl_days`
+    pub retention_days: i32,
+    pub interval: Duration,
+    /// Only report audio files no job references, instead of deleting them
+    pub orphans_dry_run: bool,
+}
+
+impl CleanupConfig {
+    /// Parse `CLEANUP_RETENTION_DAYS` (default 7), `CLEANUP_INTERVAL_SECS` (default 3600)
+    /// and `CLEANUP_ORPHANS_DRY_RUN` (`true`/`1`, default off)
+    pub fn parse(
+        retention_days: Option<&str>,
+        interval_secs: Option<&str>,
+        orphans_dry_run: Option<&str>,
+    ) -> Result<Self, String> {
+        let retention_days = match retention_days.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(days) => parse_ttl_days(days)
+                .map_err(|_| format!("Invalid CLEANUP_RETENTION_DAYS '{}'", days))?,
+            None => 7,
+        };
+        let interval_secs = match interval_secs.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(secs) => secs
+                .parse::<u64>()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .ok_or_else(|| format!("Invalid CLEANUP_INTERVAL_SECS '{}'", secs))?,
+            None => 3600,
+        };
+        let orphans_dry_run = match orphans_dry_run.map(str::trim).filter(|v| !v.is_empty()) {
+            Some("1") | Some("true") => true,
+            Some("0") | Some("false") | None => false,
+            Some(other) => return Err(format!("Invalid CLEANUP_ORPHANS_DRY_RUN '{}'", other)),
+        };
+        Ok(Self {
+            retention_days,
+            interval: Duration::from_secs(interval_secs),
+            orphans_dry_run,
+        })
+    }
+}
+
+/// Parse a retention period in whole days, between 1 and `MAX_TTL_DAYS`
+pub fn parse_ttl_days(value: &str) -> Result<i32, String> {
+    match value.trim().parse::<i32>() {
+        Ok(days) if (1..=MAX_TTL_DAYS).contains(&days) => Ok(days),
+        _ => Err(format!(
+            "Invalid ttl_days '{}', expected a whole number of days from 1 to {}",
+            value, MAX_TTL_DAYS
+        )),
+    }
+}
+
+/// What a cleanup run removed
+#[derive(Debug, Default, Serialize)]
+pub struct CleanupReport {
+    pub jobs_removed: u64,
+    /// Size of all deleted files, from expired jobs and orphans
+    pub bytes_removed: u64,
+    /// Audio files under the storage path that no job references
+    pub orphans_found: u64,
+    pub orphan_bytes: u64,
+    /// Zero in dry-run mode
+    pub orphans_removed: u64,
+}
+
+/// Delete unpinned jobs not accessed within their `ttl_days`, or `retention_days` for jobs
+/// without one, along with their files and renditions. Then sweep audio files that no job
+/// references, e.g. left by a crash between encoding and the database update; with
+/// `orphans_dry_run` they are only counted.
+pub async fn run_cleanup(
+    pool: &Pool<Postgres>,
+    storage_path: &str,
+    retention_days: i32,
+    orphans_dry_run: bool,
+) -> anyhow::Result<CleanupReport> {
     tracing::info!("Running cleanup task...");
-    // Find jobs not accessed in the last 7 days
-    let rows = sqlx::query(
-        "DELETE FROM jobs WHERE last_accessed_at < NOW() - INTERVAL '7 days' RETURNING file_path",
+    let (jobs_removed, bytes_removed) = delete_jobs(
+        pool,
+        storage_path,
+        "DELETE FROM jobs WHERE NOT pinned AND last_accessed_at < NOW() - make_interval(days => COALESCE(ttl_days, $1)) RETURNING id, file_path",
+        retention_days,
     )
-    .fetch_all(pool)
     .await?;
+    let mut report = CleanupReport {
+        jobs_removed,
+        bytes_removed,
+        ..Default::default()
+    };
+
+    sweep_orphans(pool, storage_path, orphans_dry_run, &mut report).await?;
+    Ok(report)
+}
+
+/// Run `delete`, a `DELETE FROM jobs ... RETURNING id, file_path` statement taking `bind`
+/// as `$1`, and remove the deleted jobs' files and those of their renditions. Returns the
+/// number of jobs deleted and the bytes of files removed.
+pub async fn delete_jobs<T>(
+    pool: &Pool<Postgres>,
+    storage_path: &str,
+    delete: &str,
+    bind: T,
+) -> Result<(u64, u64), sqlx::Error>
+where
+    T: 'static + Send + for<'q> sqlx::Encode<'q, Postgres> + sqlx::Type<Postgres>,
+{
+    // Artifacts go with their job, so their paths are read in the same statement
+    let sql = format!(
+        "WITH deleted AS ({}) SELECT id, file_path, ARRAY(SELECT a.file_path FROM job_artifacts a WHERE a.job_id = deleted.id) AS artifact_paths FROM deleted",
+        delete
+    );
+    let rows = sqlx::query(&sql).bind(bind).fetch_all(pool).await?;
 
-    for row in rows {
+    let mut bytes_removed = 0;
+    for row in &rows {
+        let job_id: uuid::Uuid = row.get("id");
         let file_path: Option<String> = row.get("file_path");
-        if let Some(path) = file_path {
+        let artifact_paths: Vec<String> = row.get("artifact_paths");
+        for path in file_path.into_iter().chain(artifact_paths) {
             // Check if path is within storage_path to avoid any issues, though it should be.
             if path.starts_with(storage_path) {
+                let size = tokio::fs::metadata(&path)
+                    .await
+                    .map(|m| m.len())
+                    .unwrap_or(0);
                 if let Err(e) = tokio::fs::remove_file(&path).await {
-                    tracing::error!(path = %path, error = %e, "Failed to delete file during cleanup");
+                    tracing::error!(job_id = %job_id, path = %path, error = %e, "Failed to delete file during cleanup");
                 } else {
-                    tracing::info!(path = %path, "Deleted old file during cleanup");
+                    tracing::info!(job_id = %job_id, path = %path, "Deleted old file during cleanup");
+                    bytes_removed += size;
+                    // Nested storage layouts leave per-user/per-month directories behind
+                    crate::storage::remove_empty_parents(
+                        std::path::Path::new(&path),
+                        std::path::Path::new(storage_path),
+                    )
+                    .await;
                 }
             }
         }
     }
+    Ok((rows.len() as u64, bytes_removed))
+}
+
+async fn sweep_orphans(
+    pool: &Pool<Postgres>,
+    storage_path: &str,
+    dry_run: bool,
+    report: &mut CleanupReport,
+) -> anyhow::Result<()> {
+    // Listed before the referenced paths are loaded, so a job completing in between
+    // can't make its new file look unreferenced
+    let root = PathBuf::from(storage_path);
+    let files = tokio::task::spawn_blocking(move || audio_files(&root)).await??;
+
+    let referenced: HashSet<PathBuf> =
+        sqlx::query_scalar::<_, String>(
+            "SELECT file_path FROM jobs WHERE file_path IS NOT NULL UNION ALL SELECT file_path FROM job_artifacts",
+        )
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+
+    let cutoff = SystemTime::now() - ORPHAN_MIN_AGE;
+    for (path, size, modified) in files {
+        if referenced.contains(&path) || modified > cutoff {
+            continue;
+        }
+        report.orphans_found += 1;
+        report.orphan_bytes += size;
+        if dry_run {
+            tracing::info!(path = %path.display(), size, "Found orphaned file (dry run)");
+            continue;
+        }
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {
+                tracing::info!(path = %path.display(), size, "Deleted orphaned file");
+                report.orphans_removed += 1;
+                report.bytes_removed += size;
+                crate::storage::remove_empty_parents(&path, Path::new(storage_path)).await;
+            }
+            Err(e) => {
+                tracing::error!(path = %path.display(), error = %e, "Failed to delete orphaned file")
+            }
+        }
+    }
+
+    if report.orphans_found > 0 {
+        tracing::warn!(
+            orphans_found = report.orphans_found,
+            orphan_bytes = report.orphan_bytes,
+            orphans_removed = report.orphans_removed,
+            dry_run,
+            "Swept orphaned files"
+        );
+    }
     Ok(())
 }
+
+/// Files under `dir` (recursively) with an output format's or a rendition's extension,
+/// with their size and modification time
+fn audio_files(dir: &Path) -> std::io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                // Intermediate files of running jobs, if the scratch root is inside storage
+                if !entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(SCRATCH_DIR_PREFIX)
+                {
+                    pending.push(entry.path());
+                }
+                continue;
+            }
+            let path = entry.path();
+            let is_audio = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    OutputFormat::ALL.iter().any(|f| f.extension() == ext)
+                        || ext == Rendition::Vtt.extension()
+                });
+            if file_type.is_file() && is_audio {
+                let metadata = entry.metadata()?;
+                files.push((path, metadata.len(), metadata.modified()?));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Remove scratch directories left behind by a previous process that crashed or was
+/// killed mid-job. Only called at startup, before any job can be running.
+pub fn remove_stale_scratch_dirs(scratch_path: &str) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(scratch_path)? {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(SCRATCH_DIR_PREFIX)
+        {
+            continue;
+        }
+        let path = entry.path();
+        match std::fs::remove_dir_all(&path) {
+            Ok(()) => tracing::info!(path = %path.display(), "Removed stale scratch directory"),
+            Err(e) => {
+                tracing::error!(path = %path.display(), error = %e, "Failed to remove stale scratch directory")
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_cleanup_config_parse() {
+        assert_eq!(
+            CleanupConfig::parse(None, None, None),
+            Ok(CleanupConfig {
+                retention_days: 7,
+                interval: Duration::from_secs(3600),
+                orphans_dry_run: false,
+            })
+        );
+        assert_eq!(
+            CleanupConfig::parse(Some("30"), Some(" 600 "), Some("true")),
+            Ok(CleanupConfig {
+                retention_days: 30,
+                interval: Duration::from_secs(600),
+                orphans_dry_run: true,
+            })
+        );
+        assert!(CleanupConfig::parse(Some("0"), None, None).is_err());
+        assert!(CleanupConfig::parse(None, Some("0"), None).is_err());
+        assert!(CleanupConfig::parse(Some("week"), None, None).is_err());
+        assert!(CleanupConfig::parse(None, None, Some("yes")).is_err());
+    }
+
+    #[test]
+    fn test_parse_ttl_days() {
+        assert_eq!(parse_ttl_days("1"), Ok(1));
+        assert_eq!(parse_ttl_days(" 90 "), Ok(90));
+        assert!(parse_ttl_days("0").is_err());
+        assert!(parse_ttl_days("-1").is_err());
+        assert!(parse_ttl_days("1.5").is_err());
+        assert!(parse_ttl_days(&(MAX_TTL_DAYS + 1).to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_cleanup_respects_ttl_days() {
+        let app = TestApp::spawn().await;
+
+        // All last accessed 3 days ago; only the one with a 1-day TTL has expired
+        let short = app.insert_completed_job("alice", b"audio").await;
+        let default = app.insert_completed_job("alice", b"audio").await;
+        let long = app.insert_completed_job("alice", b"audio").await;
+        for (id, ttl_days) in [(short, Some(1)), (default, None), (long, Some(30))] {
+            sqlx::query(
+                "UPDATE jobs SET ttl_days = $1, last_accessed_at = NOW() - INTERVAL '3 days' WHERE id = $2",
+            )
+            .bind(ttl_days)
+            .bind(id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        }
+
+        run_cleanup(&app.pool, app.storage_path(), 7, false)
+            .await
+            .unwrap();
+        let remaining: Vec<Uuid> = sqlx::query("SELECT id FROM jobs ORDER BY created_at")
+            .fetch_all(&app.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("id"))
+            .collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(!remaining.contains(&short));
+        assert!(
+            !std::path::Path::new(app.storage_path())
+                .join(format!("{}.mp3", short))
+                .exists()
+        );
+
+        // A shorter default retention expires jobs without their own TTL
+        run_cleanup(&app.pool, app.storage_path(), 2, false)
+            .await
+            .unwrap();
+        let remaining: Vec<Uuid> = sqlx::query("SELECT id FROM jobs")
+            .fetch_all(&app.pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("id"))
+            .collect();
+        assert_eq!(remaining, vec![long]);
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_sweep_orphans() {
+        let app = TestApp::spawn().await;
+
+        let referenced = app.insert_completed_job("alice", b"audio").await;
+        let storage = std::path::Path::new(app.storage_path());
+        let old = SystemTime::now() - Duration::from_secs(2 * 3600);
+        let write = |relative: &str, age: SystemTime| {
+            let path = storage.join(relative);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, [0u8; 10]).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(age)
+                .unwrap();
+            path
+        };
+        let orphan = write("alice/2025/03/orphan.flac", old);
+        // Too new: may belong to a job that hasn't recorded its path yet
+        let fresh = write("fresh.mp3", SystemTime::now());
+        let scratch = write(&format!("{}abc/chunk_0.wav", SCRATCH_DIR_PREFIX), old);
+        let other = write("notes.txt", old);
+        std::fs::File::options()
+            .write(true)
+            .open(storage.join(format!("{}.mp3", referenced)))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let report = run_cleanup(&app.pool, app.storage_path(), 7, true)
+            .await
+            .unwrap();
+        assert_eq!(report.orphans_found, 1);
+        assert_eq!(report.orphan_bytes, 10);
+        assert_eq!(report.orphans_removed, 0);
+        assert!(orphan.exists());
+
+        let report = run_cleanup(&app.pool, app.storage_path(), 7, false)
+            .await
+            .unwrap();
+        assert_eq!(report.orphans_removed, 1);
+        assert_eq!(report.bytes_removed, 10);
+        assert!(!orphan.exists());
+        // Its now-empty directories go with it
+        assert!(!storage.join("alice").exists());
+        assert!(fresh.exists() && scratch.exists() && other.exists());
+        assert!(storage.join(format!("{}.mp3", referenced)).exists());
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,610 @@
+//! Service configuration.
+//!
+//! Every setting can be given in a TOML file, `TTS_CONFIG_FILE` (default `/app/config.toml`
+//! if it exists), and overridden by its environment variable, e.g. `[cleanup] retention_days`
+//! by `CLEANUP_RETENTION_DAYS`, so a deployment can keep most settings in one file and
+//! secrets in the environment. All of them are checked here at startup; an invalid one
+//! stops the server with an error naming it and, if it came from the file, where.
+
+use crate::{
+    admin, audit, auth, cleanup, cors, db, engine, handlers, inference, join, listen, logging,
+    loudness, model_download, phonemizer, queue, quota, recovery, silence, storage, tls,
+    upload_limit, usage, ws_handler,
+};
+use figment::error::Actual;
+use figment::providers::{Format, Toml};
+use figment::value::{Dict, Map, Value};
+use figment::{Figment, Metadata, Profile, Provider, Source};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_CONFIG_FILE: &str = "/app/config.toml";
+
+/// Each setting's environment variable and its `section.key` in the config file
+const SETTINGS: &[(&str, &str)] = &[
+    ("DATABASE_URL", "database.url"),
+    ("DB_MAX_CONNECTIONS", "database.max_connections"),
+    ("DB_ACQUIRE_TIMEOUT_SECS", "database.acquire_timeout_secs"),
+    ("DB_CONNECT_ATTEMPTS", "database.connect_attempts"),
+    ("BIND_ADDR", "server.bind_addr"),
+    ("PORT", "server.port"),
+    ("CORS_ALLOWED_ORIGINS", "server.cors_allowed_origins"),
+    ("SWAGGER_UI", "server.swagger_ui"),
+    ("TTS_TEST_MODE", "server.test_mode"),
+    ("LOG_FORMAT", "server.log_format"),
+    ("TLS_CERT_PATH", "server.tls_cert_path"),
+    ("TLS_KEY_PATH", "server.tls_key_path"),
+    ("WS_PING_INTERVAL_SECS", "server.ws_ping_interval_secs"),
+    ("WS_IDLE_TIMEOUT_SECS", "server.ws_idle_timeout_secs"),
+    ("STORAGE_PATH", "storage.path"),
+    ("STORAGE_LAYOUT", "storage.layout"),
+    ("SCRATCH_PATH", "storage.scratch_path"),
+    ("TTS_STORAGE_QUOTA_BYTES", "storage.quota_bytes"),
+    ("TTS_STORAGE_QUOTA_POLICY", "storage.quota_policy"),
+    ("KEYCLOAK_URL", "keycloak.url"),
+    ("KEYCLOAK_REALM", "keycloak.realm"),
+    ("KEYCLOAK_AUDIENCE", "keycloak.audience"),
+    ("API_KEYS", "auth.api_keys"),
+    ("ADMIN_USERS", "auth.admin_users"),
+    ("DOWNLOAD_URL_SECRET", "auth.download_url_secret"),
+    ("MONTHLY_AUDIO_MINUTES", "limits.monthly_audio_minutes"),
+    (
+        "MONTHLY_AUDIO_MINUTES_OVERRIDES",
+        "limits.monthly_audio_minutes_overrides",
+    ),
+    ("DAILY_CHARACTER_QUOTA", "limits.daily_character_quota"),
+    (
+        "DAILY_CHARACTER_QUOTA_OVERRIDES",
+        "limits.daily_character_quota_overrides",
+    ),
+    ("TTS_MAX_INPUT_CHARS", "limits.max_input_chars"),
+    ("TTS_MAX_UPLOAD_BYTES", "limits.max_upload_bytes"),
+    ("URL_FETCH_ALLOW_PRIVATE", "limits.url_fetch_allow_private"),
+    ("CLEANUP_RETENTION_DAYS", "cleanup.retention_days"),
+    ("CLEANUP_INTERVAL_SECS", "cleanup.interval_secs"),
+    ("CLEANUP_ORPHANS_DRY_RUN", "cleanup.orphans_dry_run"),
+    ("AUDIT_RETENTION_DAYS", "cleanup.audit_retention_days"),
+    ("TTS_MAX_CONCURRENT_JOBS", "jobs.max_concurrent"),
+    ("TTS_JOB_MAX_RETRIES", "jobs.max_retries"),
+    ("TTS_JOB_RETRY_BASE_SECS", "jobs.retry_base_secs"),
+    ("STUCK_JOB_THRESHOLD_SECS", "jobs.stuck_threshold_secs"),
+    ("STUCK_JOB_ACTION", "jobs.stuck_action"),
+    ("TTS_BATCH_ENGINE", "synthesis.batch_engine"),
+    ("TTS_LIVE_ENGINE", "synthesis.live_engine"),
+    ("TTS_HTTP_ENGINE_URL", "synthesis.http_engine_url"),
+    ("TTS_HTTP_ENGINE_MODEL", "synthesis.http_engine_model"),
+    ("TTS_HTTP_ENGINE_API_KEY", "synthesis.http_engine_api_key"),
+    ("TTS_LOUDNESS_LUFS", "synthesis.loudness_lufs"),
+    ("TTS_TRIM_SILENCE_DB", "synthesis.trim_silence_db"),
+    ("TTS_SENTENCE_GAP_MS", "synthesis.sentence_gap_ms"),
+    ("TTS_CROSSFADE_MS", "synthesis.crossfade_ms"),
+    ("KOKORO_MODEL_PATH", "model.path"),
+    ("KOKORO_VOICES_PATH", "model.voices_path"),
+    ("KOKORO_FAST_MODEL_PATH", "model.fast_path"),
+    ("KOKORO_MODEL_URL", "model.url"),
+    ("KOKORO_MODEL_SHA256", "model.sha256"),
+    ("KOKORO_VOICES_URL", "model.voices_url"),
+    ("KOKORO_VOICES_SHA256", "model.voices_sha256"),
+    ("KOKORO_QUALITY", "model.quality"),
+    ("KOKORO_EXECUTION_PROVIDER", "model.execution_provider"),
+    ("KOKORO_GPU_DEVICE", "model.gpu_device"),
+    ("KOKORO_SESSIONS", "model.sessions"),
+    ("KOKORO_VOICE_CACHE", "model.voice_cache"),
+    ("KOKORO_WARMUP", "model.warmup"),
+    ("G2P_MODELS", "model.g2p_models"),
+    ("ESPEAK_BACKEND", "model.espeak_backend"),
+    ("PHONEME_CACHE_SIZE", "model.phoneme_cache_size"),
+];
+
+/// All settings of the service, validated
+pub struct Settings {
+    pub database_url: String,
+    pub pool: db::PoolConfig,
+    pub listen_addr: SocketAddr,
+    pub cors_origins: Option<cors::AllowedOrigins>,
+    pub swagger_ui: bool,
+    /// Skips auth and synthesizes silence, for CI without Keycloak or model files
+    pub test_mode: bool,
+    pub log_format: logging::LogFormat,
+    pub tls: Option<tls::TlsConfig>,
+    pub ws_keepalive: ws_handler::Keepalive,
+    pub storage_path: String,
+    pub storage_layout: storage::StorageLayout,
+    pub scratch_path: String,
+    pub storage_quota: Option<quota::StorageQuota>,
+    pub keycloak_url: String,
+    pub keycloak_realm: String,
+    pub keycloak_audience: String,
+    pub api_keys: auth::ApiKeys,
+    pub admin_users: HashSet<String>,
+    pub download_url_secret: Option<String>,
+    pub usage_caps: usage::UsageCaps,
+    pub character_quotas: usage::CharacterQuotas,
+    pub max_input_chars: usize,
+    pub max_upload_bytes: usize,
+    pub url_fetch_allow_private: bool,
+    pub cleanup: cleanup::CleanupConfig,
+    pub audit_retention_days: i32,
+    pub max_concurrent_jobs: usize,
+    pub retry_policy: queue::RetryPolicy,
+    pub recovery: recovery::RecoveryConfig,
+    pub batch_engine: engine::EngineKind,
+    pub live_engine: engine::EngineKind,
+    pub http_engine: Option<engine::HttpEngine>,
+    pub default_loudness: Option<f32>,
+    pub silence_trim: Option<silence::SilenceTrim>,
+    pub sentence_gap_ms: u32,
+    pub crossfade_ms: u32,
+    pub kokoro_model_path: String,
+    pub kokoro_voices_path: String,
+    /// Quantized model for the `fast` quality
+    pub kokoro_fast_model_path: Option<String>,
+    pub model_runtime: inference::RuntimeConfig,
+    pub model_warmup: inference::Warmup,
+    /// Model files to fetch at startup if they're missing
+    pub model_downloads: Vec<model_download::Download>,
+    pub g2p_models: String,
+    pub espeak_backend: phonemizer::EspeakBackend,
+    pub phoneme_cache_size: usize,
+}
+
+impl Settings {
+    /// Load the config file named by `TTS_CONFIG_FILE`, under the environment
+    pub fn load() -> Result<Self, String> {
+        let file = match std::env::var("TTS_CONFIG_FILE") {
+            Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+            _ => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.is_file()),
+        };
+        let config = Config::load(file.as_deref(), std::env::vars())?;
+        Self::from_config(&config)
+    }
+
+    fn from_config(config: &Config) -> Result<Self, String> {
+        let get = |name: &str| config.get(name);
+        let flag = |name: &str| matches!(get(name).as_deref(), Some("1") | Some("true"));
+        let at = |e: String| config.locate(e);
+
+        let database_url = get("DATABASE_URL").ok_or_else(|| {
+            "DATABASE_URL must be set (or database.url in the config file)".to_string()
+        })?;
+        let pool = db::PoolConfig::parse(
+            get("DB_MAX_CONNECTIONS").as_deref(),
+            get("DB_ACQUIRE_TIMEOUT_SECS").as_deref(),
+            get("DB_CONNECT_ATTEMPTS").as_deref(),
+        )
+        .map_err(at)?;
+        let listen_addr =
+            listen::parse_listen_addr(get("BIND_ADDR").as_deref(), get("PORT").as_deref())
+                .map_err(at)?;
+        let cors_origins =
+            cors::parse_allowed_origins(get("CORS_ALLOWED_ORIGINS").as_deref()).map_err(at)?;
+        let log_format = logging::parse_log_format(get("LOG_FORMAT").as_deref()).map_err(at)?;
+        let tls = tls::TlsConfig::parse(
+            get("TLS_CERT_PATH").as_deref(),
+            get("TLS_KEY_PATH").as_deref(),
+        )
+        .map_err(at)?;
+        let ws_keepalive = ws_handler::Keepalive::parse(
+            get("WS_PING_INTERVAL_SECS").as_deref(),
+            get("WS_IDLE_TIMEOUT_SECS").as_deref(),
+        )
+        .map_err(at)?;
+        let model_runtime = inference::RuntimeConfig::parse(
+            get("KOKORO_EXECUTION_PROVIDER").as_deref(),
+            get("KOKORO_GPU_DEVICE").as_deref(),
+            get("KOKORO_SESSIONS").as_deref(),
+            get("KOKORO_QUALITY").as_deref(),
+            get("KOKORO_VOICE_CACHE").as_deref(),
+        )
+        .map_err(at)?;
+        let model_warmup = inference::Warmup::parse(get("KOKORO_WARMUP").as_deref()).map_err(at)?;
+        let espeak_backend =
+            phonemizer::EspeakBackend::parse(get("ESPEAK_BACKEND").as_deref()).map_err(at)?;
+        let phoneme_cache_size =
+            phonemizer::parse_cache_size(get("PHONEME_CACHE_SIZE").as_deref()).map_err(at)?;
+        let kokoro_model_path =
+            get("KOKORO_MODEL_PATH").unwrap_or_else(|| "/app/kokoro-v1.0.onnx".to_string());
+        let kokoro_voices_path =
+            get("KOKORO_VOICES_PATH").unwrap_or_else(|| "/app/voices-v1.0.bin".to_string());
+        let model_downloads = [
+            ("KOKORO_MODEL", &kokoro_model_path),
+            ("KOKORO_VOICES", &kokoro_voices_path),
+        ]
+        .into_iter()
+        .filter_map(|(name, path)| {
+            model_download::Download::parse(
+                name,
+                path,
+                get(&format!("{}_URL", name)).as_deref(),
+                get(&format!("{}_SHA256", name)).as_deref(),
+            )
+            .map_err(at)
+            .transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+        let test_mode =
+            get("TTS_TEST_MODE").is_some_and(|v| !matches!(v.as_str(), "" | "0" | "false"));
+
+        let storage_layout = storage::StorageLayout::parse(
+            get("STORAGE_LAYOUT")
+                .as_deref()
+                .unwrap_or(storage::DEFAULT_LAYOUT),
+        )
+        .map_err(at)?;
+        let storage_quota = quota::StorageQuota::parse(
+            get("TTS_STORAGE_QUOTA_BYTES").as_deref(),
+            get("TTS_STORAGE_QUOTA_POLICY").as_deref(),
+        )
+        .map_err(at)?;
+
+        let api_keys = auth::ApiKeys::parse(&get("API_KEYS").unwrap_or_default()).map_err(at)?;
+        let usage_caps = usage::UsageCaps::parse(
+            get("MONTHLY_AUDIO_MINUTES").as_deref(),
+            &get("MONTHLY_AUDIO_MINUTES_OVERRIDES").unwrap_or_default(),
+        )
+        .map_err(at)?;
+        let character_quotas = usage::CharacterQuotas::parse(
+            get("DAILY_CHARACTER_QUOTA").as_deref(),
+            &get("DAILY_CHARACTER_QUOTA_OVERRIDES").unwrap_or_default(),
+        )
+        .map_err(at)?;
+        let max_input_chars =
+            handlers::parse_max_input_chars(get("TTS_MAX_INPUT_CHARS").as_deref()).map_err(at)?;
+        let max_upload_bytes =
+            upload_limit::parse_max_upload_bytes(get("TTS_MAX_UPLOAD_BYTES").as_deref())
+                .map_err(at)?;
+
+        let cleanup = cleanup::CleanupConfig::parse(
+            get("CLEANUP_RETENTION_DAYS").as_deref(),
+            get("CLEANUP_INTERVAL_SECS").as_deref(),
+            get("CLEANUP_ORPHANS_DRY_RUN").as_deref(),
+        )
+        .map_err(at)?;
+        let audit_retention_days =
+            audit::parse_retention_days(get("AUDIT_RETENTION_DAYS").as_deref()).map_err(at)?;
+
+        let max_concurrent_jobs =
+            queue::parse_max_concurrent(get("TTS_MAX_CONCURRENT_JOBS").as_deref()).map_err(at)?;
+        let retry_policy = queue::RetryPolicy::parse(
+            get("TTS_JOB_MAX_RETRIES").as_deref(),
+            get("TTS_JOB_RETRY_BASE_SECS").as_deref(),
+        )
+        .map_err(at)?;
+        let recovery = recovery::RecoveryConfig::parse(
+            get("STUCK_JOB_THRESHOLD_SECS").as_deref(),
+            get("STUCK_JOB_ACTION").as_deref(),
+        )
+        .map_err(at)?;
+
+        let batch_engine = engine::EngineKind::parse(
+            get("TTS_BATCH_ENGINE").as_deref(),
+            "TTS_BATCH_ENGINE",
+            engine::EngineKind::Cli,
+        )
+        .map_err(at)?;
+        let live_engine = engine::EngineKind::parse(
+            get("TTS_LIVE_ENGINE").as_deref(),
+            "TTS_LIVE_ENGINE",
+            engine::EngineKind::Onnx,
+        )
+        .map_err(at)?;
+        let http_engine = engine::HttpEngine::parse(
+            get("TTS_HTTP_ENGINE_URL").as_deref(),
+            get("TTS_HTTP_ENGINE_MODEL").as_deref(),
+            get("TTS_HTTP_ENGINE_API_KEY").as_deref(),
+        )
+        .map_err(at)?;
+        if http_engine.is_none() && [batch_engine, live_engine].contains(&engine::EngineKind::Http)
+        {
+            return Err(at(
+                "TTS_HTTP_ENGINE_URL is required with the http engine".to_string()
+            ));
+        }
+        let default_loudness =
+            loudness::parse_default_loudness(get("TTS_LOUDNESS_LUFS").as_deref()).map_err(at)?;
+        let silence_trim =
+            silence::parse_trim_silence_db(get("TTS_TRIM_SILENCE_DB").as_deref()).map_err(at)?;
+        let sentence_gap_ms =
+            join::parse_sentence_gap_ms(get("TTS_SENTENCE_GAP_MS").as_deref()).map_err(at)?;
+        let crossfade_ms =
+            join::parse_crossfade_ms(get("TTS_CROSSFADE_MS").as_deref()).map_err(at)?;
+
+        Ok(Self {
+            database_url,
+            pool,
+            listen_addr,
+            cors_origins,
+            swagger_ui: flag("SWAGGER_UI"),
+            test_mode,
+            log_format,
+            tls,
+            ws_keepalive,
+            storage_path: get("STORAGE_PATH").unwrap_or_else(|| "/app/storage".to_string()),
+            storage_layout,
+            scratch_path: get("SCRATCH_PATH")
+                .unwrap_or_else(|| std::env::temp_dir().to_string_lossy().into_owned()),
+            storage_quota,
+            keycloak_url: get("KEYCLOAK_URL")
+                .unwrap_or_else(|| "http://keycloak.keycloak.svc.cluster.local".to_string()),
+            keycloak_realm: get("KEYCLOAK_REALM").unwrap_or_else(|| "homekube".to_string()),
+            keycloak_audience: get("KEYCLOAK_AUDIENCE").unwrap_or_else(|| "tts".to_string()),
+            api_keys,
+            admin_users: admin::parse_admin_users(&get("ADMIN_USERS").unwrap_or_default()),
+            download_url_secret: get("DOWNLOAD_URL_SECRET").filter(|secret| !secret.is_empty()),
+            usage_caps,
+            character_quotas,
+            max_input_chars,
+            max_upload_bytes,
+            url_fetch_allow_private: flag("URL_FETCH_ALLOW_PRIVATE"),
+            cleanup,
+            audit_retention_days,
+            max_concurrent_jobs,
+            retry_policy,
+            recovery,
+            batch_engine,
+            live_engine,
+            http_engine,
+            default_loudness,
+            silence_trim,
+            sentence_gap_ms,
+            crossfade_ms,
+            kokoro_model_path,
+            kokoro_voices_path,
+            kokoro_fast_model_path: get("KOKORO_FAST_MODEL_PATH").filter(|path| !path.is_empty()),
+            model_runtime,
+            model_warmup,
+            model_downloads,
+            g2p_models: get("G2P_MODELS").unwrap_or_default(),
+            espeak_backend,
+            phoneme_cache_size,
+        })
+    }
+}
+
+/// The config file with the environment layered over it
+struct Config {
+    figment: Figment,
+}
+
+impl Config {
+    fn load(
+        file: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, String> {
+        let mut figment = Figment::new();
+        if let Some(path) = file {
+            if !path.is_file() {
+                return Err(format!("Config file {} not found", path.display()));
+            }
+            let toml = Toml::file_exact(path);
+            let data = toml.data().map_err(|e| e.to_string())?;
+            let mut unknown = Vec::new();
+            if let Some(dict) = data.get(&Profile::Default) {
+                unknown_keys(dict, "", &mut unknown);
+            }
+            if !unknown.is_empty() {
+                return Err(format!(
+                    "Unknown settings in {}: {}",
+                    path.display(),
+                    unknown.join(", ")
+                ));
+            }
+            figment = figment.merge(toml);
+        }
+        let env = env
+            .into_iter()
+            .filter(|(name, _)| file_key(name).is_some())
+            .collect();
+        Ok(Self {
+            figment: figment.merge(EnvVars(env)),
+        })
+    }
+
+    /// The setting whose environment variable is `name`, from the environment if it is set
+    /// there and otherwise from the file
+    fn get(&self, name: &str) -> Option<String> {
+        let value = self.figment.find_value(file_key(name)?).ok()?;
+        setting_string(&value)
+    }
+
+    /// Say where in the config file the settings named in `error` were set, if they were
+    fn locate(&self, error: String) -> String {
+        let mut located = error.clone();
+        for (name, key) in SETTINGS {
+            if !mentions(&error, name) {
+                continue;
+            }
+            if let Some(Metadata {
+                source: Some(Source::File(path)),
+                ..
+            }) = self.figment.find_metadata(key)
+            {
+                located.push_str(&format!(" (`{}` in {})", key, path.display()));
+            }
+        }
+        located
+    }
+}
+
+fn file_key(name: &str) -> Option<&'static str> {
+    SETTINGS
+        .iter()
+        .find(|(env, _)| *env == name)
+        .map(|(_, key)| *key)
+}
+
+/// Whether `text` contains the variable `name` as a whole word, e.g. not as the tail of
+/// `TTS_STORAGE_PATH`
+fn mentions(text: &str, name: &str) -> bool {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(name)
+        .any(|(i, _)| !text[..i].ends_with(is_word) && !text[i + name.len()..].starts_with(is_word))
+}
+
+/// Keys in the config file that aren't settings, most likely typos
+fn unknown_keys(dict: &Dict, prefix: &str, unknown: &mut Vec<String>) {
+    for (key, value) in dict {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        if SETTINGS.iter().any(|(_, setting)| *setting == key) {
+            continue;
+        }
+        match value {
+            Value::Dict(_, dict) => unknown_keys(dict, &key, unknown),
+            _ => unknown.push(key),
+        }
+    }
+}
+
+/// A value from the file as its environment variable would spell it. Lists, e.g. of
+/// admin users, are comma-separated, and tables, e.g. of per-user overrides, become
+/// `key=value` pairs.
+fn setting_string(value: &Value) -> Option<String> {
+    let list = |items: Vec<String>| Some(items.join(","));
+    match value {
+        Value::String(_, s) => Some(s.clone()),
+        Value::Char(_, c) => Some(c.to_string()),
+        Value::Bool(_, b) => Some(b.to_string()),
+        Value::Num(_, n) => match n.to_actual() {
+            Actual::Unsigned(n) => Some(n.to_string()),
+            Actual::Signed(n) => Some(n.to_string()),
+            Actual::Float(n) => Some(n.to_string()),
+            _ => None,
+        },
+        Value::Empty(..) => None,
+        Value::Array(_, items) => list(items.iter().filter_map(setting_string).collect()),
+        Value::Dict(_, dict) => list(
+            dict.iter()
+                .filter_map(|(key, value)| Some(format!("{}={}", key, setting_string(value)?)))
+                .collect(),
+        ),
+    }
+}
+
+/// The environment variables of settings, taken as the strings they are rather than
+/// parsed the way figment's `Env` provider would
+struct EnvVars(Vec<(String, String)>);
+
+impl Provider for EnvVars {
+    fn metadata(&self) -> Metadata {
+        Metadata::named("environment variable")
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, figment::Error> {
+        let mut sections = Dict::new();
+        for (name, value) in &self.0 {
+            let Some((section, key)) = file_key(name).and_then(|key| key.split_once('.')) else {
+                continue;
+            };
+            let section = sections
+                .entry(section.to_string())
+                .or_insert_with(|| Dict::new().into());
+            if let Value::Dict(_, section) = section {
+                section.insert(key.to_string(), value.clone().into());
+            }
+        }
+        Ok(Profile::Default.collect(sections))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn config_file(toml: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(toml.as_bytes()).unwrap();
+        file
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_config_file_under_env() {
+        let file = config_file(
+            r#"
+            [database]
+            url = "postgres://file/tts"
+
+            [server]
+            port = 8080
+            swagger_ui = true
+
+            [auth]
+            admin_users = ["alice", "bob"]
+
+            [limits.monthly_audio_minutes_overrides]
+            alice = 600
+            bob = "unlimited"
+
+            [synthesis]
+            loudness_lufs = -16.5
+            "#,
+        );
+        let config = Config::load(
+            Some(file.path()),
+            env(&[("PORT", "9090"), ("HOME", "/root")]),
+        )
+        .unwrap();
+        assert_eq!(
+            config.get("DATABASE_URL").as_deref(),
+            Some("postgres://file/tts")
+        );
+        assert_eq!(config.get("PORT").as_deref(), Some("9090"));
+        assert_eq!(config.get("SWAGGER_UI").as_deref(), Some("true"));
+        assert_eq!(config.get("ADMIN_USERS").as_deref(), Some("alice,bob"));
+        assert_eq!(
+            config.get("MONTHLY_AUDIO_MINUTES_OVERRIDES").as_deref(),
+            Some("alice=600,bob=unlimited")
+        );
+        assert_eq!(config.get("TTS_LOUDNESS_LUFS").as_deref(), Some("-16.5"));
+        assert_eq!(config.get("STORAGE_PATH"), None);
+
+        let settings = Settings::from_config(&config).unwrap();
+        assert_eq!(settings.listen_addr, "0.0.0.0:9090".parse().unwrap());
+        assert!(settings.swagger_ui);
+        assert!(settings.admin_users.contains("bob"));
+        assert_eq!(settings.default_loudness, Some(-16.5));
+        assert_eq!(settings.kokoro_model_path, "/app/kokoro-v1.0.onnx");
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let file = config_file("[cleanup]\nretention_days = 0\nretention_dayz = 7\n");
+        let err = Config::load(Some(file.path()), env(&[])).err().unwrap();
+        assert!(err.contains("cleanup.retention_dayz"), "{}", err);
+        assert!(Config::load(Some(Path::new("/nonexistent.toml")), env(&[])).is_err());
+
+        // An invalid value from the file says where it was set
+        let file = config_file("[cleanup]\nretention_days = 0\n");
+        let config =
+            Config::load(Some(file.path()), env(&[("DATABASE_URL", "postgres://")])).unwrap();
+        let err = Settings::from_config(&config).err().unwrap();
+        assert!(err.contains("CLEANUP_RETENTION_DAYS"), "{}", err);
+        assert!(err.contains("`cleanup.retention_days` in"), "{}", err);
+
+        let config = Config::load(None, env(&[])).unwrap();
+        assert!(
+            Settings::from_config(&config)
+                .err()
+                .unwrap()
+                .contains("DATABASE_URL")
+        );
+    }
+
+    #[test]
+    fn test_mentions() {
+        assert!(mentions("Invalid PORT '0'", "PORT"));
+        assert!(!mentions("Invalid TTS_STORAGE_PATH", "STORAGE_PATH"));
+        assert!(!mentions("Invalid PORTS", "PORT"));
+    }
+}
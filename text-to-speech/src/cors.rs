@@ -0,0 +1,191 @@
+//! Cross-origin access for browser front-ends.
+//!
+//! With `CORS_ALLOWED_ORIGINS` set, every route answers CORS preflight requests and adds
+//! `Access-Control-Allow-*` headers for those origins, so a web app can call `/generate`
+//! (multipart with a bearer token, which always needs a preflight) and download the result.
+//! Browsers don't apply CORS to WebSockets, so the WebSocket routes instead refuse
+//! upgrades whose `Origin` isn't allowed. Without it, no CORS headers are sent.
+
+use crate::state::AppState;
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue, Method, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// How long browsers may cache a preflight response
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Origins allowed to call the API from a browser
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedOrigins {
+    /// `*`: any origin
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+/// Parse `CORS_ALLOWED_ORIGINS`: comma-separated origins such as `https://app.example.com`,
+/// or `*` for any. Unset means no cross-origin access.
+pub fn parse_allowed_origins(value: Option<&str>) -> Result<Option<AllowedOrigins>, String> {
+    let Some(value) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    if value == "*" {
+        return Ok(Some(AllowedOrigins::Any));
+    }
+    let mut origins = Vec::new();
+    for origin in value.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+        // Browsers send the origin without a path, so a trailing slash would never match
+        let origin = origin.trim_end_matches('/');
+        let valid = origin.split_once("://").is_some_and(|(scheme, host)| {
+            matches!(scheme, "http" | "https") && !host.is_empty() && !host.contains('/')
+        });
+        let value = HeaderValue::from_str(origin)
+            .ok()
+            .filter(|_| valid)
+            .ok_or_else(|| format!("Invalid origin '{}' in CORS_ALLOWED_ORIGINS", origin))?;
+        origins.push(value);
+    }
+    Ok(Some(AllowedOrigins::List(origins)))
+}
+
+impl AllowedOrigins {
+    pub fn allows(&self, origin: &HeaderValue) -> bool {
+        match self {
+            Self::Any => true,
+            Self::List(origins) => origins.contains(origin),
+        }
+    }
+
+    /// Answers preflight requests and adds CORS headers to responses for these origins
+    pub fn layer(&self) -> CorsLayer {
+        let allow_origin = match self {
+            Self::Any => AllowOrigin::any(),
+            Self::List(origins) => AllowOrigin::list(origins.clone()),
+        };
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::RANGE,
+                header::IF_NONE_MATCH,
+                header::IF_MODIFIED_SINCE,
+                HeaderName::from_static("x-api-key"),
+                crate::request_id::REQUEST_ID_HEADER,
+            ])
+            // So a web app can name and seek downloads and read their validators
+            .expose_headers([
+                header::CONTENT_DISPOSITION,
+                header::CONTENT_LENGTH,
+                header::CONTENT_RANGE,
+                header::ACCEPT_RANGES,
+                header::ETAG,
+                crate::request_id::REQUEST_ID_HEADER,
+                header::LAST_MODIFIED,
+            ])
+            .max_age(PREFLIGHT_MAX_AGE)
+    }
+}
+
+/// Refuses WebSocket upgrades from browser pages on origins that aren't allowed. Clients
+/// that send no `Origin`, i.e. anything but a browser, are let through.
+pub async fn check_ws_origin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let (Some(allowed), Some(origin)) =
+        (&state.cors_origins, request.headers().get(header::ORIGIN))
+        && !allowed.allows(origin)
+    {
+        tracing::warn!(origin = ?origin, path = %request.uri().path(), "WebSocket from a disallowed origin");
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_parse_allowed_origins() {
+        assert_eq!(parse_allowed_origins(None), Ok(None));
+        assert_eq!(
+            parse_allowed_origins(Some(" * ")),
+            Ok(Some(AllowedOrigins::Any))
+        );
+        let origins =
+            parse_allowed_origins(Some("https://app.example.com/, http://localhost:5173"))
+                .unwrap()
+                .unwrap();
+        assert!(origins.allows(&HeaderValue::from_static("https://app.example.com")));
+        assert!(origins.allows(&HeaderValue::from_static("http://localhost:5173")));
+        assert!(!origins.allows(&HeaderValue::from_static("https://evil.example")));
+        assert!(parse_allowed_origins(Some("app.example.com")).is_err());
+        assert!(parse_allowed_origins(Some("https://app.example.com/path")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let app = TestApp::spawn().await;
+
+        // A multipart upload with a token is preflighted, and answered without auth
+        let preflight = |origin: &'static str| {
+            app.client
+                .request(Method::OPTIONS, app.url("/generate"))
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(
+                    header::ACCESS_CONTROL_REQUEST_HEADERS,
+                    "authorization,content-type",
+                )
+                .send()
+        };
+        let resp = preflight(TestApp::CORS_ORIGIN).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            TestApp::CORS_ORIGIN
+        );
+        let resp = preflight("https://evil.example").await.unwrap();
+        assert!(
+            resp.headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .is_none()
+        );
+
+        let resp = app
+            .client
+            .get(app.url("/healthz"))
+            .header(header::ORIGIN, TestApp::CORS_ORIGIN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            TestApp::CORS_ORIGIN
+        );
+
+        let ws = |origin: &'static str| {
+            app.client
+                .get(app.url("/ws/live"))
+                .header(header::ORIGIN, origin)
+                .send()
+        };
+        assert_eq!(
+            ws("https://evil.example").await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_ne!(
+            ws(TestApp::CORS_ORIGIN).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,433 @@
+//! Per-user custom voices, managed with `/voices/custom`.
+//!
+//! A custom voice is a style embedding the user uploads as a .npy file of the same
+//! (510, 1, 256) shape as the voices Kokoro ships with, e.g. one blended from several
+//! voices or trained separately. Once uploaded it is selected by name like any other
+//! voice, in jobs, `/stream` and `/ws/live`, by that user only. Only the in-process model
+//! can speak with an embedding, so custom voices always use it, whatever
+//! `TTS_BATCH_ENGINE` and `TTS_LIVE_ENGINE` say.
+
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::{
+    body::Bytes,
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres, Row};
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Most custom voices a user can have; each takes about 500 KB
+const MAX_VOICES: i64 = 20;
+const MAX_NAME_CHARS: usize = 64;
+
+/// A user's custom voice, ready for synthesis
+#[derive(Debug, Clone)]
+pub struct CustomVoice {
+    pub name: String,
+    /// Language text is phonemized with unless a request sets `lang`
+    pub lang: String,
+    /// SHA-256 of the embedding
+    pub sha256: String,
+    /// The flat (510 x 256) style matrix
+    pub embedding: Arc<Vec<f32>>,
+}
+
+impl CustomVoice {
+    /// The user's custom voice called `name`, if they have one
+    pub async fn load(
+        pool: &Pool<Postgres>,
+        username: &str,
+        name: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT name, lang, embedding, sha256 FROM custom_voices WHERE username = $1 AND name = $2",
+        )
+        .bind(username)
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+        Ok(row.map(|row| {
+            let bytes: Vec<u8> = row.get("embedding");
+            Self {
+                name: row.get("name"),
+                lang: row.get("lang"),
+                sha256: row.get("sha256"),
+                embedding: Arc::new(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect(),
+                ),
+            }
+        }))
+    }
+}
+
+/// A custom voice as returned by the API
+#[derive(Serialize, ToSchema)]
+pub struct CustomVoiceInfo {
+    name: String,
+    /// Default `lang` for the voice
+    lang: String,
+    /// SHA-256 of the uploaded embedding
+    sha256: String,
+    created_at: DateTime<Utc>,
+}
+
+impl CustomVoiceInfo {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Self {
+        Self {
+            name: row.get("name"),
+            lang: row.get("lang"),
+            sha256: row.get("sha256"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// Query of `PUT /voices/custom/:name`
+#[derive(Deserialize, utoipa::IntoParams)]
+pub struct UploadQuery {
+    /// Language to phonemize text as with this voice, e.g. `fr-fr`; defaults to `en-us`
+    lang: Option<String>,
+}
+
+/// Check a custom voice name: lowercase letters, digits, `_` and `-`
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || name.len() > MAX_NAME_CHARS
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-')
+    {
+        return Err(format!(
+            "Voice name must be 1 to {} lowercase letters, digits, '_' or '-'",
+            MAX_NAME_CHARS
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `name` is a built-in voice's: one the model has loaded or, until it has, one in
+/// the voices file it loads them from
+async fn is_built_in(state: &AppState, name: &str) -> Result<bool, ApiError> {
+    if let Some(model) = state.kokoro_model.read().await.model() {
+        return Ok(model.has_voice(name));
+    }
+    let path = std::path::PathBuf::from(&state.model_files.voices_path);
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || crate::inference::voices_file_has(&path, &name))
+        .await
+        .map_err(|e| ApiError::internal(format!("Voice lookup failed: {}", e)))
+}
+
+/// `GET /voices/custom`: the user's custom voices, by name
+#[utoipa::path(
+    get,
+    path = "/voices/custom",
+    tag = "voices",
+    responses(
+        (status = 200, body = Vec<CustomVoiceInfo>),
+        (status = 500, description = "Database error", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn list_custom_voices(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<CustomVoiceInfo>>, ApiError> {
+    let rows = sqlx::query(
+        "SELECT name, lang, sha256, created_at FROM custom_voices WHERE username = $1 ORDER BY name",
+    )
+    .bind(&user.username)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(ApiError::database)?;
+    Ok(Json(rows.iter().map(CustomVoiceInfo::from_row).collect()))
+}
+
+/// `PUT /voices/custom/:name`: upload a voice embedding as a .npy body, replacing the
+/// user's voice of that name if they have one
+#[utoipa::path(
+    put,
+    path = "/voices/custom/{name}",
+    tag = "voices",
+    params(("name" = String, Path, description = "Voice name"), UploadQuery),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "A .npy file of little-endian float32 with shape (510, 1, 256)"),
+    responses(
+        (status = 201, description = "Created", body = CustomVoiceInfo),
+        (status = 200, description = "Replaced", body = CustomVoiceInfo),
+        (status = 400, description = "Invalid name, lang or embedding, a built-in voice's name, or the user has too many voices", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn upload_custom_voice(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<UploadQuery>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<CustomVoiceInfo>), ApiError> {
+    validate_name(&name)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_voice_name", e))?;
+    if is_built_in(&state, &name).await? {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "built_in_voice_name",
+            format!("'{}' is the name of a built-in voice", name),
+        ));
+    }
+    let lang = crate::voices::resolve_lang(query.lang.as_deref(), "").map_err(ApiError::invalid)?;
+    let embedding = crate::inference::parse_voice_npy(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_embedding", e))?;
+    let bytes: Vec<u8> = embedding.iter().flat_map(|f| f.to_le_bytes()).collect();
+    let sha256 = hex::encode(Sha256::digest(&bytes));
+
+    // A lock on the user's voices, held until the transaction ends, makes concurrent uploads
+    // take turns, so together they can't go over the limit; locking their rows wouldn't
+    // stop two first uploads. Nothing is written when the user's other voices are at the
+    // limit, and xmax is only set on a row the upsert updated.
+    let mut tx = state.pool.begin().await.map_err(ApiError::database)?;
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('custom_voices:' || $1))")
+        .bind(&user.username)
+        .execute(&mut *tx)
+        .await
+        .map_err(ApiError::database)?;
+    let row = sqlx::query(
+        "INSERT INTO custom_voices (username, name, lang, embedding, sha256) SELECT $1, $2, $3, $4, $5 WHERE (SELECT COUNT(*) FROM custom_voices WHERE username = $1 AND name <> $2) < $6 ON CONFLICT (username, name) DO UPDATE SET lang = $3, embedding = $4, sha256 = $5, created_at = NOW() RETURNING name, lang, sha256, created_at, (xmax = 0) AS inserted",
+    )
+    .bind(&user.username)
+    .bind(&name)
+    .bind(lang)
+    .bind(&bytes)
+    .bind(&sha256)
+    .bind(MAX_VOICES)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(ApiError::database)?
+    .ok_or_else(|| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "too_many_voices",
+            format!("A user can have at most {} custom voices", MAX_VOICES),
+        )
+    })?;
+    tx.commit().await.map_err(ApiError::database)?;
+    tracing::info!(user = %user.username, voice = %name, lang, "Uploaded custom voice");
+    let status = if row.get("inserted") {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(CustomVoiceInfo::from_row(&row))))
+}
+
+/// `DELETE /voices/custom/:name`: remove a custom voice
+#[utoipa::path(
+    delete,
+    path = "/voices/custom/{name}",
+    tag = "voices",
+    params(("name" = String, Path, description = "Voice name")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "No such voice for this user", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn delete_custom_voice(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let result = sqlx::query("DELETE FROM custom_voices WHERE username = $1 AND name = $2")
+        .bind(&user.username)
+        .bind(&name)
+        .execute(&state.pool)
+        .await
+        .map_err(ApiError::database)?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "voice_not_found",
+            "Custom voice not found",
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    /// A .npy file of `values` f32s declared as `shape`
+    fn npy(descr: &str, shape: &str, values: usize) -> Vec<u8> {
+        let header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            descr, shape
+        );
+        let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+        npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        npy.extend_from_slice(header.as_bytes());
+        for i in 0..values {
+            npy.extend_from_slice(&(i as f32 / values as f32).to_le_bytes());
+        }
+        npy
+    }
+
+    const VALUES: usize = 510 * 256;
+
+    #[test]
+    fn test_parse_voice_npy() {
+        use crate::inference::parse_voice_npy;
+        let embedding = parse_voice_npy(&npy("<f4", "(510, 1, 256)", VALUES)).unwrap();
+        assert_eq!(embedding.len(), VALUES);
+        assert!(parse_voice_npy(&npy("<f4", "(510, 256)", VALUES)).is_err());
+        assert!(parse_voice_npy(&npy("<f4", "(3, 1, 256)", 3 * 256)).is_err());
+        assert!(parse_voice_npy(&npy("<f8", "(510, 1, 256)", VALUES)).is_err());
+        assert!(parse_voice_npy(&npy("<f4", "(510, 1, 256)", VALUES - 1)).is_err());
+        assert!(parse_voice_npy(b"not a numpy file").is_err());
+
+        assert!(validate_name("my-voice_2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("My Voice").is_err());
+        assert!(validate_name("../af_heart").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_custom_voice_crud() {
+        let app = TestApp::spawn_without_workers().await;
+        let upload = |username: &str, path: &str, body: Vec<u8>| {
+            app.client
+                .put(app.url(path))
+                .bearer_auth(app.token(username))
+                .body(body)
+                .send()
+        };
+
+        let resp = upload(
+            "alice",
+            "/voices/custom/narrator?lang=fr",
+            npy("<f4", "(510, 1, 256)", VALUES),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let voice: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(voice["lang"], "fr-fr");
+
+        let stored = CustomVoice::load(&app.pool, "alice", "narrator")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.embedding.len(), VALUES);
+        assert_eq!(stored.sha256, voice["sha256"]);
+        assert!(
+            CustomVoice::load(&app.pool, "bob", "narrator")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let resp = upload(
+            "alice",
+            "/voices/custom/narrator",
+            npy("<f4", "(510, 1, 256)", VALUES),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = upload(
+            "alice",
+            "/voices/custom/broken",
+            npy("<f4", "(511, 1, 256)", 511 * 256),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "invalid_embedding");
+
+        let list = |username: &str| {
+            app.client
+                .get(app.url("/voices/custom"))
+                .bearer_auth(app.token(username))
+                .send()
+        };
+        let voices: serde_json::Value = list("alice").await.unwrap().json().await.unwrap();
+        assert_eq!(voices.as_array().unwrap().len(), 1);
+        assert_eq!(voices[0]["lang"], "en-us");
+        let voices: serde_json::Value = list("bob").await.unwrap().json().await.unwrap();
+        assert_eq!(voices, serde_json::json!([]));
+
+        let delete = |username: &str| {
+            app.client
+                .delete(app.url("/voices/custom/narrator"))
+                .bearer_auth(app.token(username))
+                .send()
+        };
+        let resp = delete("bob").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "voice_not_found");
+        assert_eq!(
+            delete("alice").await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_custom_voice_limit() {
+        let app = TestApp::spawn_without_workers().await;
+        let upload = |name: String| {
+            app.client
+                .put(app.url(&format!("/voices/custom/{}", name)))
+                .bearer_auth(app.token("alice"))
+                .body(npy("<f4", "(510, 1, 256)", VALUES))
+                .send()
+        };
+
+        // Uploaded all at once, no more than the limit get in
+        let mut uploads = tokio::task::JoinSet::new();
+        for i in 0..MAX_VOICES + 5 {
+            uploads.spawn(upload(format!("voice-{}", i)));
+        }
+        let mut statuses = Vec::new();
+        while let Some(resp) = uploads.join_next().await {
+            statuses.push(resp.unwrap().unwrap().status());
+        }
+        let created = statuses
+            .iter()
+            .filter(|s| **s == StatusCode::CREATED)
+            .count();
+        assert_eq!(created as i64, MAX_VOICES, "{:?}", statuses);
+
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM custom_voices WHERE username = 'alice'")
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(count, MAX_VOICES);
+
+        // A voice that is there can still be replaced
+        let name = sqlx::query_scalar::<_, String>(
+            "SELECT name FROM custom_voices WHERE username = 'alice' LIMIT 1",
+        )
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        assert_eq!(upload(name).await.unwrap().status(), StatusCode::OK);
+        let resp = upload("one-more".to_string()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "too_many_voices");
+
+        app.teardown().await;
+    }
+}
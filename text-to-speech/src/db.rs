@@ -0,0 +1,147 @@
+//! Database connection pool.
+//!
+//! On a cluster cold start Postgres is often not up yet when the service starts, so
+//! connecting is retried with exponential backoff (1 s doubling up to 30 s) for up to
+//! `DB_CONNECT_ATTEMPTS` attempts before giving up, rather than exiting at once and
+//! crash-looping. Only errors that can go away on their own are retried; a wrong
+//! password or database name fails straight away.
+
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+
+/// Longest wait between connection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    /// How long a query waits for a free connection before failing
+    pub acquire_timeout: Duration,
+    pub connect_attempts: u32,
+}
+
+impl PoolConfig {
+    /// Parse `DB_MAX_CONNECTIONS` (default 5), `DB_ACQUIRE_TIMEOUT_SECS` (default 30) and
+    /// `DB_CONNECT_ATTEMPTS` (default 10)
+    pub fn parse(
+        max_connections: Option<&str>,
+        acquire_timeout_secs: Option<&str>,
+        connect_attempts: Option<&str>,
+    ) -> Result<Self, String> {
+        let positive = |value: Option<&str>, name: &str, default: u32| match value
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+        {
+            Some(v) => v
+                .parse::<u32>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("Invalid {} '{}'", name, v)),
+            None => Ok(default),
+        };
+        Ok(Self {
+            max_connections: positive(max_connections, "DB_MAX_CONNECTIONS", 5)?,
+            acquire_timeout: Duration::from_secs(
+                positive(acquire_timeout_secs, "DB_ACQUIRE_TIMEOUT_SECS", 30)?.into(),
+            ),
+            connect_attempts: positive(connect_attempts, "DB_CONNECT_ATTEMPTS", 10)?,
+        })
+    }
+}
+
+/// Connect to `url`, retrying while the database isn't reachable or is still starting up.
+/// Each attempt itself keeps trying for up to the acquire timeout.
+pub async fn connect(url: &str, config: &PoolConfig) -> Result<Pool<Postgres>, sqlx::Error> {
+    let mut attempt = 1;
+    loop {
+        let result = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .acquire_timeout(config.acquire_timeout)
+            .connect(url)
+            .await;
+        match result {
+            Ok(pool) => {
+                tracing::info!(
+                    attempt,
+                    max_connections = config.max_connections,
+                    "Connected to Postgres"
+                );
+                return Ok(pool);
+            }
+            Err(e) if attempt < config.connect_attempts && is_transient(&e) => {
+                let delay = backoff(attempt);
+                tracing::warn!(
+                    attempt,
+                    attempts = config.connect_attempts,
+                    retry_in_secs = delay.as_secs(),
+                    error = %e,
+                    "Postgres not available yet"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Errors seen while Postgres is unreachable or starting up
+fn is_transient(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::Tls(_) => true,
+        // cannot_connect_now: the database system is starting up
+        sqlx::Error::Database(e) => e.code().as_deref() == Some("57P03"),
+        _ => false,
+    }
+}
+
+/// Delay after the `attempt`th failed attempt
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << (attempt - 1).min(5)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_config_parse() {
+        assert_eq!(
+            PoolConfig::parse(None, None, None),
+            Ok(PoolConfig {
+                max_connections: 5,
+                acquire_timeout: Duration::from_secs(30),
+                connect_attempts: 10,
+            })
+        );
+        assert_eq!(
+            PoolConfig::parse(Some("20"), Some(" 5 "), Some("1"))
+                .unwrap()
+                .acquire_timeout,
+            Duration::from_secs(5)
+        );
+        assert!(PoolConfig::parse(Some("0"), None, None).is_err());
+        assert!(PoolConfig::parse(None, Some("soon"), None).is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(3), Duration::from_secs(4));
+        assert_eq!(backoff(6), MAX_BACKOFF);
+        assert_eq!(backoff(40), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_connect_gives_up() {
+        // Nothing listens on port 1, so both attempts are refused
+        let config = PoolConfig::parse(None, Some("1"), Some("2")).unwrap();
+        let started = std::time::Instant::now();
+        let e = connect("postgres://user@127.0.0.1:1/postgres", &config)
+            .await
+            .unwrap_err();
+        assert!(is_transient(&e), "{}", e);
+        assert!(started.elapsed() >= backoff(1));
+    }
+}
@@ -0,0 +1,685 @@
+//! Encoding a job's audio in process, without ffmpeg.
+//!
+//! Each chunk is joined (see `join`) into a [`PcmSink`] as soon as it is synthesized. WAV
+//! output is written as 16-bit PCM, MP3 is encoded with LAME and Opus with libopus into an
+//! Ogg container, so none of them needs an intermediate file or an ffmpeg run. All are
+//! resampled in process if the job asks for another sample rate (see `resample`). Pitch
+//! shifts, loudness normalization and the formats without an encoder here still go
+//! through ffmpeg.
+
+use crate::format::{EncodingOptions, OutputFormat};
+use crate::resample::ResamplingSink;
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Opus bitrate when the job doesn't set one, in kbps
+const DEFAULT_OPUS_KBPS: u32 = 64;
+/// MP3 bitrate when the job doesn't set one, in kbps
+const DEFAULT_MP3_KBPS: u32 = 192;
+/// Ogg stream serial number; each file holds a single stream
+const OGG_SERIAL: u32 = 1;
+
+/// Receives a job's joined audio as it is produced
+pub trait PcmSink {
+    /// Called once, before any samples, with the layout of the synthesized audio
+    fn start(&mut self, channels: usize, sample_rate: u32) -> Result<(), String>;
+    /// Interleaved samples, nominally between -1.0 and 1.0
+    fn write(&mut self, samples: &[f32]) -> Result<(), String>;
+    /// Called once after the last samples
+    fn finish(&mut self) -> Result<(), String>;
+}
+
+/// Whether `encoding` can be written in process from audio at `sample_rate`. Only Ogg
+/// Vorbis, FLAC and M4B can't; with those, and for pitch shifts and loudness
+/// normalization, the joined audio goes through ffmpeg instead.
+pub fn supports(encoding: &EncodingOptions, sample_rate: u32) -> bool {
+    let output_rate = encoding.sample_rate.unwrap_or(sample_rate);
+    match encoding.format {
+        OutputFormat::Wav => true,
+        OutputFormat::Mp3 => MP3_SAMPLE_RATES.contains(&output_rate),
+        OutputFormat::Opus => SampleRate::try_from(output_rate as i32).is_ok(),
+        _ => false,
+    }
+}
+
+/// Sample rates MPEG-1, 2 and 2.5 layer III can hold
+const MP3_SAMPLE_RATES: &[u32] = &[8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+/// A sink writing `encoding` to `path`, with `tags` (title, artist, album, track and
+/// comment) as the format's metadata. Only for encodings [`supports`] accepts.
+pub fn create_sink(
+    encoding: &EncodingOptions,
+    path: &Path,
+    tags: &[(&str, &str)],
+) -> Result<Box<dyn PcmSink>, String> {
+    let sink = create_encoder(encoding, path, tags)?;
+    Ok(match encoding.sample_rate {
+        Some(rate) => Box::new(ResamplingSink::new(sink, rate)),
+        None => sink,
+    })
+}
+
+fn create_encoder(
+    encoding: &EncodingOptions,
+    path: &Path,
+    tags: &[(&str, &str)],
+) -> Result<Box<dyn PcmSink>, String> {
+    match encoding.format {
+        OutputFormat::Wav => {
+            let info: Vec<([u8; 4], String)> = tags
+                .iter()
+                .filter_map(|(key, value)| riff_info_id(key).map(|id| (id, value.to_string())))
+                .collect();
+            Ok(Box::new(WavWriter::create(path, SampleType::Int16, info)?))
+        }
+        OutputFormat::Opus => {
+            let comments = tags
+                .iter()
+                .map(|(key, value)| (vorbis_comment_key(key), value.to_string()))
+                .collect();
+            let kbps = encoding.bitrate_kbps.unwrap_or(DEFAULT_OPUS_KBPS);
+            Ok(Box::new(OggOpusWriter::create(path, kbps, comments)?))
+        }
+        OutputFormat::Mp3 => {
+            let kbps = encoding.bitrate_kbps.unwrap_or(DEFAULT_MP3_KBPS);
+            Ok(Box::new(Mp3Writer::create(path, kbps, id3v2_tag(tags))?))
+        }
+        format => Err(format!("{} can't be encoded in process", format.as_str())),
+    }
+}
+
+/// RIFF INFO chunk ID for a tag, as ffmpeg names them
+fn riff_info_id(key: &str) -> Option<[u8; 4]> {
+    match key {
+        "title" => Some(*b"INAM"),
+        "artist" => Some(*b"IART"),
+        "album" => Some(*b"IPRD"),
+        "track" => Some(*b"IPRT"),
+        "comment" => Some(*b"ICMT"),
+        _ => None,
+    }
+}
+
+/// ID3v2 text frame for a tag
+fn id3_frame_id(key: &str) -> Option<&'static [u8; 4]> {
+    match key {
+        "title" => Some(b"TIT2"),
+        "artist" => Some(b"TPE1"),
+        "album" => Some(b"TALB"),
+        "track" => Some(b"TRCK"),
+        "comment" => Some(b"COMM"),
+        _ => None,
+    }
+}
+
+/// An ID3v2.3 tag holding `tags`, which is what ffmpeg writes with `-id3v2_version 3`.
+/// Text is ISO-8859-1 if it is ASCII, otherwise UTF-16.
+fn id3v2_tag(tags: &[(&str, &str)]) -> Vec<u8> {
+    let mut frames = Vec::new();
+    for (key, value) in tags {
+        let Some(id) = id3_frame_id(key) else {
+            continue;
+        };
+        let (encoding, text, terminator): (u8, Vec<u8>, &[u8]) = if value.is_ascii() {
+            (0, value.as_bytes().to_vec(), &[0])
+        } else {
+            let mut text = vec![0xff, 0xfe];
+            text.extend(value.encode_utf16().flat_map(u16::to_le_bytes));
+            (1, text, &[0, 0])
+        };
+        let mut body = vec![encoding];
+        if id == b"COMM" {
+            // Language, then an empty description in the same encoding
+            body.extend_from_slice(b"eng");
+            if encoding == 1 {
+                body.extend_from_slice(&[0xff, 0xfe]);
+            }
+            body.extend_from_slice(terminator);
+        }
+        body.extend(text);
+        frames.extend_from_slice(id);
+        frames.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&[0, 0]);
+        frames.extend(body);
+    }
+    if frames.is_empty() {
+        return frames;
+    }
+    // The tag's size is stored 7 bits to a byte
+    let size = frames.len() as u32;
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+    tag.extend(frames);
+    tag
+}
+
+/// Vorbis comment field name for a tag
+fn vorbis_comment_key(key: &str) -> String {
+    match key {
+        "track" => "TRACKNUMBER".to_string(),
+        key => key.to_ascii_uppercase(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleType {
+    Int16,
+    Float32,
+}
+
+/// Writes a WAV file, filling in its sizes once all samples are written
+pub struct WavWriter {
+    writer: BufWriter<std::fs::File>,
+    sample_type: SampleType,
+    /// Encoded `LIST` chunk with the RIFF INFO tags, empty without tags
+    list: Vec<u8>,
+    fmt: Vec<u8>,
+    data_len: u64,
+    buffer: Vec<u8>,
+}
+
+impl WavWriter {
+    pub fn create(
+        path: &Path,
+        sample_type: SampleType,
+        info: Vec<([u8; 4], String)>,
+    ) -> Result<Self, String> {
+        let file = std::fs::File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let mut list = Vec::new();
+        if !info.is_empty() {
+            let mut body = b"INFO".to_vec();
+            for (id, value) in info {
+                // NUL-terminated and padded to an even length
+                let len = value.len() + 1;
+                body.extend_from_slice(&id);
+                body.extend_from_slice(&(len as u32).to_le_bytes());
+                body.extend_from_slice(value.as_bytes());
+                body.extend(std::iter::repeat_n(0, 1 + (len & 1)));
+            }
+            list.extend_from_slice(b"LIST");
+            list.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            list.extend_from_slice(&body);
+        }
+        Ok(Self {
+            writer: BufWriter::new(file),
+            sample_type,
+            list,
+            fmt: Vec::new(),
+            data_len: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        write_wav_header(&mut self.writer, &self.fmt, &self.list, self.data_len)
+    }
+}
+
+impl PcmSink for WavWriter {
+    fn start(&mut self, channels: usize, sample_rate: u32) -> Result<(), String> {
+        let (tag, bytes_per_sample) = match self.sample_type {
+            SampleType::Int16 => (1u16, 2u16),
+            SampleType::Float32 => (3, 4),
+        };
+        let block_align = channels as u16 * bytes_per_sample;
+        self.fmt = [
+            &tag.to_le_bytes()[..],
+            &(channels as u16).to_le_bytes(),
+            &sample_rate.to_le_bytes(),
+            &(sample_rate * block_align as u32).to_le_bytes(),
+            &block_align.to_le_bytes(),
+            &(bytes_per_sample * 8).to_le_bytes(),
+        ]
+        .concat();
+        self.write_header()
+            .map_err(|e| format!("Failed to write WAV: {}", e))
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.buffer.clear();
+        for sample in samples {
+            match self.sample_type {
+                SampleType::Int16 => self.buffer.extend_from_slice(
+                    &((sample * 32768.0).round().clamp(-32768.0, 32767.0) as i16).to_le_bytes(),
+                ),
+                SampleType::Float32 => self.buffer.extend_from_slice(&sample.to_le_bytes()),
+            }
+        }
+        self.data_len += self.buffer.len() as u64;
+        self.writer
+            .write_all(&self.buffer)
+            .map_err(|e| format!("Failed to write WAV: {}", e))
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        // The sizes weren't known when the header was written
+        self.writer
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.write_header())
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("Failed to write WAV: {}", e))
+    }
+}
+
+/// Write a WAV header: the `fmt ` chunk, then `list` (a whole `LIST` chunk, or empty), then
+/// the start of a `data` chunk of `data_len` bytes
+pub fn write_wav_header(
+    writer: &mut impl Write,
+    fmt: &[u8],
+    list: &[u8],
+    data_len: u64,
+) -> std::io::Result<()> {
+    // Sizes that don't fit are left at the maximum; readers then go by the file length
+    let riff_len = (20 + fmt.len() as u64 + list.len() as u64 + data_len).min(u32::MAX as u64);
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(riff_len as u32).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&(fmt.len() as u32).to_le_bytes())?;
+    writer.write_all(fmt)?;
+    writer.write_all(list)?;
+    writer.write_all(b"data")?;
+    writer.write_all(&(data_len.min(u32::MAX as u64) as u32).to_le_bytes())
+}
+
+/// Encodes Opus into an Ogg file, as laid out in RFC 7845
+pub struct OggOpusWriter {
+    packets: ogg::PacketWriter<BufWriter<std::fs::File>>,
+    bitrate_kbps: u32,
+    comments: Vec<(String, String)>,
+    encoder: Option<Encoder>,
+    channels: usize,
+    /// Samples per channel in each 20 ms frame
+    frame_len: usize,
+    /// Opus timestamps are in 48 kHz samples whatever the input rate
+    granule_scale: u64,
+    /// Samples per channel the encoder's delay adds to the start, at 48 kHz
+    pre_skip: u64,
+    /// Samples waiting for a full frame
+    pending: Vec<f32>,
+    /// Samples per channel received, and encoded including padding
+    received: u64,
+    encoded: u64,
+    packet: Vec<u8>,
+}
+
+impl OggOpusWriter {
+    pub fn create(
+        path: &Path,
+        bitrate_kbps: u32,
+        comments: Vec<(String, String)>,
+    ) -> Result<Self, String> {
+        let file = std::fs::File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        Ok(Self {
+            packets: ogg::PacketWriter::new(BufWriter::new(file)),
+            bitrate_kbps,
+            comments,
+            encoder: None,
+            channels: 0,
+            frame_len: 0,
+            granule_scale: 0,
+            pre_skip: 0,
+            pending: Vec::new(),
+            received: 0,
+            encoded: 0,
+            // Large enough for any Opus packet
+            packet: vec![0; 4000],
+        })
+    }
+
+    /// Encode whole frames from `pending`, leaving any remainder. With `end_granule`, the
+    /// last frame ends the stream at that granule position.
+    fn encode_frames(&mut self, end_granule: Option<u64>) -> Result<(), String> {
+        let encoder = self.encoder.as_ref().ok_or("Opus encoder not started")?;
+        let frame_samples = self.frame_len * self.channels;
+        let frames = self.pending.len() / frame_samples;
+        for (index, frame) in self.pending[..frames * frame_samples]
+            .chunks_exact(frame_samples)
+            .enumerate()
+        {
+            let len = encoder
+                .encode_float(frame, &mut self.packet)
+                .map_err(|e| format!("Opus encoding failed: {}", e))?;
+            self.encoded += self.frame_len as u64;
+            let (end, granule) = match end_granule {
+                Some(granule) if index + 1 == frames => {
+                    (ogg::PacketWriteEndInfo::EndStream, granule)
+                }
+                _ => (
+                    ogg::PacketWriteEndInfo::NormalPacket,
+                    self.encoded * self.granule_scale,
+                ),
+            };
+            self.packets
+                .write_packet(
+                    self.packet[..len].to_vec().into_boxed_slice(),
+                    OGG_SERIAL,
+                    end,
+                    granule,
+                )
+                .map_err(|e| format!("Failed to write Opus: {}", e))?;
+        }
+        self.pending.drain(..frames * frame_samples);
+        Ok(())
+    }
+}
+
+impl PcmSink for OggOpusWriter {
+    fn start(&mut self, channels: usize, sample_rate: u32) -> Result<(), String> {
+        let rate = SampleRate::try_from(sample_rate as i32)
+            .map_err(|_| format!("Opus can't encode audio at {} Hz", sample_rate))?;
+        let opus_channels = match channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            _ => return Err(format!("Opus can't encode {} channels", channels)),
+        };
+        let mut encoder = Encoder::new(rate, opus_channels, Application::Audio)
+            .map_err(|e| format!("Failed to create Opus encoder: {}", e))?;
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(self.bitrate_kbps as i32 * 1000))
+            .map_err(|e| format!("Failed to set Opus bitrate: {}", e))?;
+        let lookahead = encoder
+            .lookahead()
+            .map_err(|e| format!("Failed to get Opus lookahead: {}", e))?;
+
+        self.channels = channels;
+        self.frame_len = sample_rate as usize / 50;
+        self.granule_scale = 48000 / sample_rate as u64;
+        self.pre_skip = lookahead as u64 * self.granule_scale;
+        self.encoder = Some(encoder);
+
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(channels as u8);
+        head.extend_from_slice(&(self.pre_skip as u16).to_le_bytes());
+        head.extend_from_slice(&sample_rate.to_le_bytes());
+        // No output gain, single stream
+        head.extend_from_slice(&[0, 0, 0]);
+
+        let vendor = concat!("text-to-speech ", env!("CARGO_PKG_VERSION"));
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for (key, value) in &self.comments {
+            let comment = format!("{}={}", key, value);
+            tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            tags.extend_from_slice(comment.as_bytes());
+        }
+
+        // The headers each get a page of their own
+        for header in [head, tags] {
+            self.packets
+                .write_packet(
+                    header.into_boxed_slice(),
+                    OGG_SERIAL,
+                    ogg::PacketWriteEndInfo::EndPage,
+                    0,
+                )
+                .map_err(|e| format!("Failed to write Opus: {}", e))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.pending.extend_from_slice(samples);
+        self.received += (samples.len() / self.channels.max(1)) as u64;
+        self.encode_frames(None)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let lookahead =
+            self.encoder
+                .as_ref()
+                .ok_or("Opus encoder not started")?
+                .lookahead()
+                .map_err(|e| format!("Failed to get Opus lookahead: {}", e))? as usize;
+        // Pad with silence to a whole frame past the encoder's delay, so the last samples
+        // make it out. The final granule position marks where the audio ends, so players
+        // trim the padding.
+        let frame_samples = self.frame_len * self.channels;
+        let padded = (self.pending.len() + lookahead * self.channels + 1).div_ceil(frame_samples)
+            * frame_samples;
+        self.pending.resize(padded, 0.0);
+        self.encode_frames(Some(self.pre_skip + self.received * self.granule_scale))?;
+        self.packets
+            .inner_mut()
+            .flush()
+            .map_err(|e| format!("Failed to write Opus: {}", e))
+    }
+}
+
+/// Encodes constant-bitrate MP3 with LAME, after an ID3v2 tag
+pub struct Mp3Writer {
+    writer: BufWriter<std::fs::File>,
+    bitrate_kbps: u32,
+    encoder: Option<mp3lame_encoder::Encoder>,
+    channels: usize,
+    buffer: Vec<u8>,
+}
+
+impl Mp3Writer {
+    pub fn create(path: &Path, bitrate_kbps: u32, id3_tag: Vec<u8>) -> Result<Self, String> {
+        let file = std::fs::File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+        writer
+            .write_all(&id3_tag)
+            .map_err(|e| format!("Failed to write MP3: {}", e))?;
+        Ok(Self {
+            writer,
+            bitrate_kbps,
+            encoder: None,
+            channels: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// The LAME bitrate closest to `kbps`. LAME lowers it further if the sample rate
+    /// can't carry it, e.g. to 160 kbps at 24 kHz.
+    fn lame_bitrate(kbps: u32) -> mp3lame_encoder::Bitrate {
+        use mp3lame_encoder::Bitrate::*;
+        [
+            Kbps8, Kbps16, Kbps24, Kbps32, Kbps40, Kbps48, Kbps64, Kbps80, Kbps96, Kbps112,
+            Kbps128, Kbps160, Kbps192, Kbps224, Kbps256, Kbps320,
+        ]
+        .into_iter()
+        .min_by_key(|bitrate| (*bitrate as u32).abs_diff(kbps))
+        .unwrap_or(Kbps192)
+    }
+
+    fn write_buffer(&mut self) -> Result<(), String> {
+        self.writer
+            .write_all(&self.buffer)
+            .map_err(|e| format!("Failed to write MP3: {}", e))?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl PcmSink for Mp3Writer {
+    fn start(&mut self, channels: usize, sample_rate: u32) -> Result<(), String> {
+        let mode = match channels {
+            1 => mp3lame_encoder::Mode::Mono,
+            2 => mp3lame_encoder::Mode::JointStereo,
+            _ => return Err(format!("MP3 can't encode {} channels", channels)),
+        };
+        let failed =
+            |e: mp3lame_encoder::BuildError| format!("Failed to create MP3 encoder: {}", e);
+        let mut builder = mp3lame_encoder::Builder::new().ok_or("Failed to create MP3 encoder")?;
+        builder.set_num_channels(channels as u8).map_err(failed)?;
+        builder.set_sample_rate(sample_rate).map_err(failed)?;
+        // Keep the rate; LAME would otherwise pick one to suit the bitrate
+        builder
+            .set_output_sample_rate(std::num::NonZeroU32::new(sample_rate))
+            .map_err(failed)?;
+        builder
+            .set_brate(Self::lame_bitrate(self.bitrate_kbps))
+            .map_err(failed)?;
+        builder.set_mode(mode).map_err(failed)?;
+        // The Xing header can only be written by seeking back once the file is done
+        builder.set_to_write_vbr_tag(false).map_err(failed)?;
+        self.encoder = Some(builder.build().map_err(failed)?);
+        self.channels = channels;
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        let encoder = self.encoder.as_mut().ok_or("MP3 encoder not started")?;
+        // LAME's worst case for the encoded size
+        self.buffer.reserve(samples.len() * 5 / 4 + 7200);
+        let result = if self.channels == 1 {
+            encoder.encode_to_vec(mp3lame_encoder::MonoPcm(samples), &mut self.buffer)
+        } else {
+            encoder.encode_to_vec(mp3lame_encoder::InterleavedPcm(samples), &mut self.buffer)
+        };
+        result.map_err(|e| format!("MP3 encoding failed: {}", e))?;
+        self.write_buffer()
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let encoder = self.encoder.as_mut().ok_or("MP3 encoder not started")?;
+        self.buffer.reserve(7200);
+        encoder
+            .flush_to_vec::<mp3lame_encoder::FlushNoGap>(&mut self.buffer)
+            .map_err(|e| format!("MP3 encoding failed: {}", e))?;
+        self.write_buffer()?;
+        self.writer
+            .flush()
+            .map_err(|e| format!("Failed to write MP3: {}", e))
+    }
+}
+
+/// Passes the same audio to several sinks, e.g. a job's output and its renditions
+pub struct TeeSink(pub Vec<Box<dyn PcmSink>>);
+
+impl PcmSink for TeeSink {
+    fn start(&mut self, channels: usize, sample_rate: u32) -> Result<(), String> {
+        self.0
+            .iter_mut()
+            .try_for_each(|sink| sink.start(channels, sample_rate))
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.0.iter_mut().try_for_each(|sink| sink.write(samples))
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.0.iter_mut().try_for_each(|sink| sink.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supports() {
+        let opus = EncodingOptions::new(OutputFormat::Opus, None, None).unwrap();
+        assert!(supports(&opus, 24000));
+        assert!(!supports(&opus, 22050));
+        // Resampled in process to a rate Opus takes
+        let opus_16k = EncodingOptions::new(OutputFormat::Opus, None, Some("16000")).unwrap();
+        assert!(supports(&opus_16k, 22050));
+        let wav = EncodingOptions::new(OutputFormat::Wav, None, None).unwrap();
+        assert!(supports(&wav, 22050));
+        assert!(supports(&EncodingOptions::default(), 24000));
+        let flac = EncodingOptions::new(OutputFormat::Flac, None, None).unwrap();
+        assert!(!supports(&flac, 24000));
+    }
+
+    #[test]
+    fn test_mp3_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.mp3");
+        let mp3 = EncodingOptions::new(OutputFormat::Mp3, Some("64k"), None).unwrap();
+        let tags = [
+            ("title", "Intro"),
+            ("artist", "af_heart"),
+            ("album", "Café"),
+        ];
+        let mut sink = create_sink(&mp3, &path, &tags).unwrap();
+        sink.start(1, 24000).unwrap();
+        // A second of a 440 Hz tone, written unevenly
+        let tone: Vec<f32> = (0..24000)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / 24000.0).sin() * 0.5)
+            .collect();
+        for part in tone.chunks(1000) {
+            sink.write(part).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.starts_with(b"ID3\x03\x00\x00"));
+        let size = bytes[6..10]
+            .iter()
+            .fold(0usize, |size, byte| size << 7 | *byte as usize);
+        let tag = &bytes[10..10 + size];
+        assert!(tag.starts_with(b"TIT2\0\0\0\x06\0\0\0Intro"));
+        let album = b"TALB\0\0\0\x0b\0\0\x01\xff\xfeC\0a\0f\0\xe9\0";
+        assert!(tag.windows(album.len()).any(|window| window == album));
+        // MPEG-2 layer III frames follow the tag, about 64 kbps worth of them
+        let audio = &bytes[10 + size..];
+        assert_eq!(&audio[..2], &[0xff, 0xf3]);
+        assert!((7000..9000).contains(&audio.len()), "{}", audio.len());
+    }
+
+    #[test]
+    fn test_wav_writer_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        let wav = EncodingOptions::new(OutputFormat::Wav, None, None).unwrap();
+        let mut sink = create_sink(&wav, &path, &[("title", "Intro"), ("track", "3")]).unwrap();
+        sink.start(1, 1000).unwrap();
+        sink.write(&[0.5, -1.0, 2.0]).unwrap();
+        sink.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let (audio, sample_rate) = crate::join::decode_wav(&bytes).unwrap();
+        assert_eq!((audio.len(), sample_rate), (3, 1000));
+        let list = b"LIST\x1c\0\0\0INFOINAM\x06\0\0\0Intro\0IPRT\x02\0\0\x003\0";
+        assert!(bytes.windows(list.len()).any(|window| window == list));
+        assert!(bytes.ends_with(&[0x00, 0x40, 0x00, 0x80, 0xff, 0x7f]));
+    }
+
+    #[test]
+    fn test_ogg_opus_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.opus");
+        let opus = EncodingOptions::new(OutputFormat::Opus, Some("32k"), None).unwrap();
+        let mut sink = create_sink(&opus, &path, &[("title", "Intro"), ("track", "3")]).unwrap();
+        sink.start(1, 24000).unwrap();
+        // Half a second of a 440 Hz tone, written unevenly
+        let tone: Vec<f32> = (0..12000)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / 24000.0).sin() * 0.5)
+            .collect();
+        for part in tone.chunks(1000) {
+            sink.write(part).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let mut reader = ogg::PacketReader::new(std::fs::File::open(&path).unwrap());
+        let head = reader.read_packet_expected().unwrap();
+        assert!(head.data.starts_with(b"OpusHead\x01\x01"));
+        assert_eq!(&head.data[12..16], &24000u32.to_le_bytes());
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as u64;
+        let tags = reader.read_packet_expected().unwrap();
+        assert!(tags.data.starts_with(b"OpusTags"));
+        assert!(tags.data.ends_with(b"TRACKNUMBER=3"));
+
+        let mut audio_packets = 0;
+        let mut last = None;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            audio_packets += 1;
+            last = Some(packet);
+        }
+        let last = last.unwrap();
+        assert!(last.last_in_stream());
+        // The end is trimmed to exactly the samples written, at 48 kHz
+        assert_eq!(last.absgp_page(), pre_skip + 24000);
+        assert!(audio_packets as u64 * 960 >= pre_skip + 24000);
+    }
+}
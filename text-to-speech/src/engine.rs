@@ -0,0 +1,474 @@
+//! Engines that turn text into audio.
+//!
+//! Live synthesis (`/ws/live`, `/stream`) and batch jobs both go through a
+//! [`SynthesisEngine`], picked by `TTS_LIVE_ENGINE` and `TTS_BATCH_ENGINE`:
+//!
+//! - `onnx`: the in-process Kokoro model, see [`crate::model_loader`]
+//! - `cli`: the kokoro-tts CLI, run once per chunk of a job, or per sentence live
+//! - `http`: a remote speech API at `TTS_HTTP_ENGINE_URL` that takes an OpenAI-style
+//!   `{ "model", "input", "voice", "speed", "response_format": "wav" }` body and returns a
+//!   WAV, e.g. a Piper or Kokoro server
+//!
+//! A user's [custom voice](crate::custom_voices) is always spoken by the in-process model.
+//!
+//! Whatever the engine, its audio is resampled to [`SAMPLE_RATE`], so timing, joining and
+//! encoding work the same for all of them.
+
+use crate::custom_voices::CustomVoice;
+use crate::error::ApiError;
+use crate::handlers::JobError;
+use crate::inference::{Cancel, KokoroModel, Quality, SAMPLE_RATE, Synthesis};
+use crate::state::AppState;
+use axum::http::StatusCode;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Which engine synthesizes (`TTS_LIVE_ENGINE`, `TTS_BATCH_ENGINE`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineKind {
+    /// The in-process Kokoro model
+    Onnx,
+    /// The kokoro-tts CLI
+    Cli,
+    /// The remote API at `TTS_HTTP_ENGINE_URL`
+    Http,
+}
+
+impl EngineKind {
+    /// Parse the setting `name`: `onnx`, `cli` or `http`, `default` if unset
+    pub fn parse(value: Option<&str>, name: &str, default: Self) -> Result<Self, String> {
+        match value.map(str::trim).filter(|v| !v.is_empty()) {
+            None => Ok(default),
+            Some("onnx") => Ok(Self::Onnx),
+            Some("cli") => Ok(Self::Cli),
+            Some("http") => Ok(Self::Http),
+            Some(v) => Err(format!(
+                "Invalid {} '{}': expected onnx, cli or http",
+                name, v
+            )),
+        }
+    }
+}
+
+/// A sentence, or a batch chunk of several, to synthesize
+pub struct Utterance<'a> {
+    pub text: &'a str,
+    /// Phonemes of `text`, for engines that [need them](SynthesisEngine::needs_phonemes)
+    pub phonemes: &'a str,
+    pub voice: &'a str,
+    pub lang: &'a str,
+    pub speed: f32,
+    /// Only used by the in-process model
+    pub quality: Option<Quality>,
+    /// Stops the synthesis early, where the engine supports it
+    pub cancel: Option<&'a Cancel>,
+}
+
+/// Something that turns text into audio. Calls block, so they belong on blocking threads.
+pub trait SynthesisEngine: Send + Sync {
+    /// Name in logs, as in `TTS_LIVE_ENGINE`
+    fn name(&self) -> &'static str;
+
+    /// Whether the engine speaks with `voice`; engines that can't tell say yes
+    fn has_voice(&self, _voice: &str) -> bool {
+        true
+    }
+
+    /// Whether [`Self::synthesize`] reads the phonemes rather than the text
+    fn needs_phonemes(&self) -> bool {
+        false
+    }
+
+    /// Synthesize one sentence at [`SAMPLE_RATE`]
+    fn synthesize(&self, utterance: &Utterance) -> Result<Synthesis, JobError>;
+
+    /// Whether a job's chunk goes to [`Self::synthesize`] whole, for engines that pay a
+    /// startup cost for each call, rather than a sentence at a time
+    fn synthesizes_chunks(&self) -> bool {
+        false
+    }
+}
+
+/// The in-process Kokoro model
+pub struct OnnxEngine(pub Arc<KokoroModel>);
+
+impl SynthesisEngine for OnnxEngine {
+    fn name(&self) -> &'static str {
+        "onnx"
+    }
+
+    fn has_voice(&self, voice: &str) -> bool {
+        self.0.has_voice(voice)
+    }
+
+    fn needs_phonemes(&self) -> bool {
+        true
+    }
+
+    fn synthesize(&self, utterance: &Utterance) -> Result<Synthesis, JobError> {
+        self.0
+            .synthesize_with(
+                utterance.phonemes,
+                utterance.voice,
+                utterance.speed,
+                utterance.quality,
+                utterance.cancel,
+            )
+            .map_err(|e| JobError::Permanent(format!("Synthesis failed: {}", e)))
+    }
+}
+
+/// The in-process Kokoro model speaking with a user's custom voice
+pub struct CustomVoiceEngine {
+    pub model: Arc<KokoroModel>,
+    pub voice: CustomVoice,
+}
+
+impl SynthesisEngine for CustomVoiceEngine {
+    fn name(&self) -> &'static str {
+        "onnx"
+    }
+
+    fn has_voice(&self, voice: &str) -> bool {
+        voice == self.voice.name
+    }
+
+    fn needs_phonemes(&self) -> bool {
+        true
+    }
+
+    fn synthesize(&self, utterance: &Utterance) -> Result<Synthesis, JobError> {
+        self.model
+            .synthesize_embedding(
+                utterance.phonemes,
+                &self.voice.embedding,
+                utterance.speed,
+                utterance.quality,
+                utterance.cancel,
+            )
+            .map_err(|e| JobError::Permanent(format!("Synthesis failed: {}", e)))
+    }
+}
+
+/// The kokoro-tts CLI. It loads the model on every run, so it is slow for single sentences.
+pub struct CliEngine {
+    /// Where the CLI's text and audio files are written, and removed once read
+    pub scratch_path: String,
+    /// Job the runs are for, in logs
+    pub job_id: Option<uuid::Uuid>,
+}
+
+impl SynthesisEngine for CliEngine {
+    fn name(&self) -> &'static str {
+        "cli"
+    }
+
+    fn synthesize(&self, utterance: &Utterance) -> Result<Synthesis, JobError> {
+        let owner = self.job_id.map_or("live".to_string(), |id| id.to_string());
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("{}{}-", crate::cleanup::SCRATCH_DIR_PREFIX, owner))
+            .tempdir_in(&self.scratch_path)
+            .map_err(|e| {
+                JobError::Retryable(format!("Failed to create scratch directory: {}", e))
+            })?;
+        // The CLI only reads and writes files
+        let text_path = dir.path().join("text.txt");
+        let wav_path = dir.path().join("audio.wav");
+        std::fs::write(&text_path, utterance.text)
+            .map_err(|e| JobError::Retryable(format!("Failed to write text file: {}", e)))?;
+        crate::handlers::run_kokoro_tts(
+            self.job_id,
+            text_path.to_str().ok_or("Invalid path")?,
+            wav_path.to_str().ok_or("Invalid path")?,
+            utterance.voice,
+            utterance.lang,
+            &utterance.speed.to_string(),
+        )?;
+        let bytes = std::fs::read(&wav_path)
+            .map_err(|e| JobError::Retryable(format!("Failed to read synthesized WAV: {}", e)))?;
+        decode(&bytes)
+    }
+
+    fn synthesizes_chunks(&self) -> bool {
+        true
+    }
+}
+
+/// A remote speech API
+#[derive(Debug)]
+pub struct HttpEngine {
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    client: reqwest::Client,
+}
+
+impl HttpEngine {
+    /// Parse `TTS_HTTP_ENGINE_URL`, `TTS_HTTP_ENGINE_MODEL` (default `tts-1`) and
+    /// `TTS_HTTP_ENGINE_API_KEY`; `None` without a URL
+    pub fn parse(
+        url: Option<&str>,
+        model: Option<&str>,
+        api_key: Option<&str>,
+    ) -> Result<Option<Self>, String> {
+        let Some(url) = url.map(str::trim).filter(|url| !url.is_empty()) else {
+            return Ok(None);
+        };
+        reqwest::Url::parse(url)
+            .ok()
+            .filter(|parsed| matches!(parsed.scheme(), "http" | "https"))
+            .ok_or_else(|| format!("Invalid TTS_HTTP_ENGINE_URL '{}'", url))?;
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(300))
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+        Ok(Some(Self {
+            url: url.to_string(),
+            model: model
+                .map(str::trim)
+                .filter(|model| !model.is_empty())
+                .unwrap_or("tts-1")
+                .to_string(),
+            api_key: api_key
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string),
+            client,
+        }))
+    }
+
+    async fn request(&self, utterance: &Utterance<'_>) -> Result<Vec<u8>, JobError> {
+        let mut request = self.client.post(&self.url).json(&serde_json::json!({
+            "model": self.model,
+            "input": utterance.text,
+            "voice": utterance.voice,
+            "speed": utterance.speed,
+            "response_format": "wav",
+        }));
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        // The server being down or overloaded may pass; a rejected request won't
+        let failed = |e: reqwest::Error| {
+            let message = format!("Speech API request failed: {}", e);
+            match e.status() {
+                Some(status) if status.is_client_error() => JobError::Permanent(message),
+                _ => JobError::Retryable(message),
+            }
+        };
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(failed)?;
+        Ok(response.bytes().await.map_err(failed)?.to_vec())
+    }
+}
+
+impl SynthesisEngine for HttpEngine {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn synthesize(&self, utterance: &Utterance) -> Result<Synthesis, JobError> {
+        let bytes = tokio::runtime::Handle::current().block_on(self.request(utterance))?;
+        decode(&bytes)
+    }
+}
+
+/// A WAV from an engine as a [`Synthesis`] at [`SAMPLE_RATE`]
+fn decode(bytes: &[u8]) -> Result<Synthesis, JobError> {
+    let (mut audio, sample_rate) = crate::join::decode_wav(bytes)
+        .map_err(|e| JobError::Permanent(format!("Engine returned unreadable audio: {}", e)))?;
+    if sample_rate != SAMPLE_RATE {
+        let mut resampler = crate::resample::Resampler::new(1, sample_rate, SAMPLE_RATE)
+            .map_err(JobError::Permanent)?;
+        audio = resampler.process(&audio).map_err(JobError::Permanent)?;
+        audio.extend(resampler.flush().map_err(JobError::Permanent)?);
+    }
+    Ok(Synthesis {
+        audio,
+        phoneme_spans: None,
+    })
+}
+
+/// The engine for live synthesis, with `custom_voice` if the request picked one. The
+/// in-process model is the one pinned to `model_version`, if given.
+pub async fn live_engine(
+    state: &AppState,
+    model_version: Option<&str>,
+    custom_voice: Option<CustomVoice>,
+) -> Result<Arc<dyn SynthesisEngine>, ApiError> {
+    if let Some(voice) = custom_voice {
+        let model = crate::model_loader::model_for(state, model_version).await?;
+        return Ok(Arc::new(CustomVoiceEngine { model, voice }));
+    }
+    Ok(match state.live_engine {
+        EngineKind::Onnx => Arc::new(OnnxEngine(
+            crate::model_loader::model_for(state, model_version).await?,
+        )),
+        EngineKind::Cli => Arc::new(CliEngine {
+            scratch_path: state.scratch_path.clone(),
+            job_id: None,
+        }),
+        EngineKind::Http => http_engine(state)?,
+    })
+}
+
+/// The engine for a batch job speaking `voice`, or `custom_voice` if it is one. Jobs for
+/// the in-process model go to the CLI until the model is ready, or if it lacks the voice;
+/// jobs with a custom voice wait for the model.
+pub async fn batch_engine(
+    state: &AppState,
+    job_id: uuid::Uuid,
+    voice: &str,
+    custom_voice: Option<CustomVoice>,
+) -> Result<Arc<dyn SynthesisEngine>, JobError> {
+    if let Some(voice) = custom_voice {
+        let model =
+            state.kokoro_model.read().await.model().ok_or_else(|| {
+                JobError::Retryable("Model not loaded for custom voice".to_string())
+            })?;
+        return Ok(Arc::new(CustomVoiceEngine { model, voice }));
+    }
+    let cli = || -> Arc<dyn SynthesisEngine> {
+        Arc::new(CliEngine {
+            scratch_path: state.scratch_path.clone(),
+            job_id: Some(job_id),
+        })
+    };
+    Ok(match state.batch_engine {
+        EngineKind::Onnx => match state.kokoro_model.read().await.model() {
+            Some(model) if model.has_voice(voice) => Arc::new(OnnxEngine(model)),
+            Some(_) => {
+                tracing::warn!(job_id = %job_id, voice = %voice, "Model has no such voice, using kokoro-tts");
+                cli()
+            }
+            None => {
+                tracing::warn!(job_id = %job_id, "Model not loaded, using kokoro-tts");
+                cli()
+            }
+        },
+        EngineKind::Cli => cli(),
+        EngineKind::Http => http_engine(state).map_err(|e| JobError::Permanent(e.detail))?,
+    })
+}
+
+fn http_engine(state: &AppState) -> Result<Arc<dyn SynthesisEngine>, ApiError> {
+    state
+        .http_engine
+        .clone()
+        .map(|engine| engine as Arc<dyn SynthesisEngine>)
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "engine_unavailable",
+                "TTS_HTTP_ENGINE_URL is not set",
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_engine() {
+        let parse = |value| EngineKind::parse(value, "TTS_BATCH_ENGINE", EngineKind::Cli);
+        assert_eq!(parse(None), Ok(EngineKind::Cli));
+        assert_eq!(parse(Some(" onnx ")), Ok(EngineKind::Onnx));
+        assert_eq!(parse(Some("http")), Ok(EngineKind::Http));
+        assert!(parse(Some("piper")).is_err());
+
+        assert!(
+            HttpEngine::parse(None, Some("piper"), None)
+                .unwrap()
+                .is_none()
+        );
+        assert!(HttpEngine::parse(Some("localhost:5000"), None, None).is_err());
+        let engine = HttpEngine::parse(Some("http://piper:5000/v1/audio/speech"), None, Some(""))
+            .unwrap()
+            .unwrap();
+        assert_eq!(engine.model, "tts-1");
+        assert_eq!(engine.api_key, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_http_engine() {
+        // A server answering with a second of 16 kHz silence for the voice it knows
+        let api = axum::Router::new().route(
+            "/v1/audio/speech",
+            axum::routing::post(
+                |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    if body["voice"] != "en_US-amy" || body["response_format"] != "wav" {
+                        return Err(StatusCode::BAD_REQUEST);
+                    }
+                    let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0".to_vec();
+                    wav.extend_from_slice(&16000u32.to_le_bytes());
+                    wav.extend_from_slice(&32000u32.to_le_bytes());
+                    wav.extend_from_slice(&[2, 0, 16, 0]);
+                    wav.extend_from_slice(b"data");
+                    wav.extend_from_slice(&32000u32.to_le_bytes());
+                    wav.extend(std::iter::repeat_n(0u8, 32000));
+                    Ok(wav)
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, api).await.unwrap() });
+
+        let engine = HttpEngine::parse(
+            Some(&format!("http://{}/v1/audio/speech", addr)),
+            Some("piper"),
+            None,
+        )
+        .unwrap()
+        .unwrap();
+        let utterance = |voice| Utterance {
+            text: "Hello.",
+            phonemes: "",
+            voice,
+            lang: "en-us",
+            speed: 1.0,
+            quality: None,
+            cancel: None,
+        };
+        let synthesis = tokio::task::spawn_blocking(move || {
+            (
+                engine.synthesize(&utterance("en_US-amy")),
+                engine.synthesize(&utterance("af_heart")),
+            )
+        })
+        .await
+        .unwrap();
+
+        // Resampled to the model's rate
+        let audio = synthesis.0.unwrap().audio;
+        assert!(
+            audio.len().abs_diff(SAMPLE_RATE as usize) < 100,
+            "{}",
+            audio.len()
+        );
+        assert!(matches!(synthesis.1, Err(JobError::Permanent(_))));
+    }
+
+    #[tokio::test]
+    async fn test_live_engine_errors() {
+        let mut app = crate::test_support::TestApp::spawn_without_workers().await;
+        // The test app's model never loads
+        let Err(error) = live_engine(&app.state, None, None).await else {
+            panic!("live engine without a model");
+        };
+        assert_eq!(error.status, StatusCode::SERVICE_UNAVAILABLE);
+
+        app.state.live_engine = EngineKind::Http;
+        let Err(error) = live_engine(&app.state, None, None).await else {
+            panic!("HTTP engine without a URL");
+        };
+        assert_eq!(error.code, "engine_unavailable");
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,488 @@
+//! Text extraction from EPUB uploads (`POST /generate/epub`).
+//!
+//! The spine lists the book's documents in reading order. Each becomes a chapter, titled
+//! with its first heading, except front matter (cover, title page, copyright, table of
+//! contents) and documents marked non-linear. Footnotes, endnotes and the markers that
+//! reference them are dropped, as are scripts, styles and images.
+
+use crate::chapters::{self, Chapter};
+use crate::html_text::{collapse_whitespace, has_type};
+use scraper::{Html, Selector};
+use std::collections::HashMap;
+use std::io::Read;
+
+/// Largest size a single document in the archive may decompress to
+const MAX_ENTRY_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Marks each chapter's title line in the text given to a job, as its `chapter_delimiter`
+pub const CHAPTER_DELIMITER: &str = "# ";
+
+/// Spine documents whose file name starts with one of these (ignoring `-` and `_`) are
+/// front matter
+const FRONT_MATTER_NAMES: &[&str] = &[
+    "cover",
+    "titlepage",
+    "halftitle",
+    "copyright",
+    "toc",
+    "contents",
+    "colophon",
+];
+
+/// EPUB 2 guide and EPUB 3 `epub:type` values marking front matter
+const FRONT_MATTER_TYPES: &[&str] = &[
+    "cover",
+    "title-page",
+    "titlepage",
+    "halftitlepage",
+    "copyright-page",
+    "toc",
+    "colophon",
+];
+
+/// `epub:type` and ARIA `role` values of notes and note references
+const NOTE_TYPES: &[&str] = &[
+    "footnote",
+    "footnotes",
+    "endnote",
+    "endnotes",
+    "rearnote",
+    "rearnotes",
+    "noteref",
+    "doc-footnote",
+    "doc-endnote",
+    "doc-endnotes",
+    "doc-noteref",
+];
+
+/// Elements whose text isn't read
+const SKIPPED_ELEMENTS: &[&str] = &[
+    "head", "script", "style", "aside", "nav", "img", "svg", "math", "figure",
+];
+
+#[derive(Debug, PartialEq)]
+pub struct Book {
+    /// `dc:title` from the package metadata
+    pub title: Option<String>,
+    pub chapters: Vec<Chapter>,
+}
+
+impl Book {
+    /// The book as one text, with chapter titles on lines starting with `delimiter`, or
+    /// as plain lines to be read out when there is none
+    pub fn to_text(&self, delimiter: Option<&str>) -> String {
+        let mut text = String::new();
+        for chapter in &self.chapters {
+            match delimiter {
+                Some(delimiter) => text.push_str(&format!("{}{}\n", delimiter, chapter.title)),
+                None => text.push_str(&format!("{}\n", chapters::spoken_heading(&chapter.title))),
+            }
+            text.push_str(&chapter.text);
+            text.push_str("\n\n");
+        }
+        text.trim_end().to_string()
+    }
+}
+
+/// Extract the chapters of an EPUB file
+pub fn extract(bytes: &[u8]) -> Result<Book, String> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))
+        .map_err(|e| format!("Not a valid EPUB file: {}", e))?;
+
+    let container = read_entry(&mut archive, "META-INF/container.xml")?;
+    let container = roxmltree::Document::parse(&container)
+        .map_err(|e| format!("Invalid META-INF/container.xml: {}", e))?;
+    let package_path = container
+        .descendants()
+        .find(|n| n.has_tag_name("rootfile"))
+        .and_then(|n| n.attribute("full-path"))
+        .ok_or("META-INF/container.xml has no rootfile")?
+        .to_string();
+
+    let package = read_entry(&mut archive, &package_path)?;
+    let package = roxmltree::Document::parse(&package)
+        .map_err(|e| format!("Invalid package document {}: {}", package_path, e))?;
+    let base_dir = package_path
+        .rsplit_once('/')
+        .map_or("", |(dir, _)| dir)
+        .to_string();
+
+    let book_title = package
+        .descendants()
+        .find(|n| {
+            n.has_tag_name("title")
+                && n.tag_name()
+                    .namespace()
+                    .is_some_and(|ns| ns.contains("purl.org/dc"))
+        })
+        .and_then(|n| n.text())
+        .map(collapse_whitespace)
+        .filter(|t| !t.is_empty());
+
+    // id -> (href, properties)
+    let manifest: HashMap<&str, (&str, &str)> = package
+        .descendants()
+        .filter(|n| n.has_tag_name("item"))
+        .filter_map(|n| {
+            Some((
+                n.attribute("id")?,
+                (
+                    n.attribute("href")?,
+                    n.attribute("properties").unwrap_or(""),
+                ),
+            ))
+        })
+        .collect();
+    let front_matter_hrefs: Vec<&str> = package
+        .descendants()
+        .filter(|n| n.has_tag_name("reference"))
+        .filter(|n| {
+            n.attribute("type")
+                .is_some_and(|t| FRONT_MATTER_TYPES.contains(&t))
+        })
+        .filter_map(|n| n.attribute("href"))
+        .map(|href| href.split('#').next().unwrap_or(href))
+        .collect();
+
+    let mut book_chapters = Vec::new();
+    for itemref in package.descendants().filter(|n| n.has_tag_name("itemref")) {
+        if itemref.attribute("linear") == Some("no") {
+            continue;
+        }
+        let Some(&(href, properties)) = itemref.attribute("idref").and_then(|id| manifest.get(id))
+        else {
+            continue;
+        };
+        if properties.split_whitespace().any(|p| p == "nav")
+            || front_matter_hrefs.contains(&href)
+            || is_front_matter_name(href)
+        {
+            tracing::debug!(href, "Skipping EPUB front matter");
+            continue;
+        }
+
+        let document = read_entry(&mut archive, &resolve_href(&base_dir, href))?;
+        if let Some(mut chapter) = extract_chapter(&document) {
+            if chapter.title.is_empty() {
+                chapter.title = format!("Chapter {}", book_chapters.len() + 1);
+            }
+            book_chapters.push(chapter);
+        }
+    }
+
+    if book_chapters.is_empty() {
+        return Err("No readable text found in the EPUB file".to_string());
+    }
+    Ok(Book {
+        title: book_title,
+        chapters: book_chapters,
+    })
+}
+
+fn read_entry(
+    archive: &mut zip::ZipArchive<std::io::Cursor<&[u8]>>,
+    name: &str,
+) -> Result<String, String> {
+    let entry = archive
+        .by_name(name)
+        .map_err(|e| format!("Missing {} in EPUB: {}", name, e))?;
+    let mut content = String::new();
+    entry
+        .take(MAX_ENTRY_BYTES)
+        .read_to_string(&mut content)
+        .map_err(|e| format!("Failed to read {} from EPUB: {}", name, e))?;
+    Ok(content)
+}
+
+/// Path within the archive of `href`, relative to the package document's directory
+fn resolve_href(base_dir: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or(href);
+    let mut parts: Vec<String> = base_dir
+        .split('/')
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(percent_decode(part)),
+        }
+    }
+    parts.join("/")
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = value
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn is_front_matter_name(href: &str) -> bool {
+    let name = href.rsplit('/').next().unwrap_or(href).to_ascii_lowercase();
+    let name: String = name.chars().filter(|c| !matches!(c, '-' | '_')).collect();
+    FRONT_MATTER_NAMES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// A document's chapter: its first heading as the title (empty if it has none) and the
+/// rest of its text. `None` for front matter and documents without text.
+fn extract_chapter(xhtml: &str) -> Option<Chapter> {
+    let document = Html::parse_document(xhtml);
+    let body_selector = Selector::parse("body").expect("valid selector");
+    let body = document.select(&body_selector).next()?;
+
+    let first_section = body.child_elements().next();
+    if [Some(body), first_section]
+        .into_iter()
+        .flatten()
+        .any(|el| has_type(el, FRONT_MATTER_TYPES))
+    {
+        return None;
+    }
+
+    let heading_selector = Selector::parse("h1, h2, h3").expect("valid selector");
+    let heading = body
+        .select(&heading_selector)
+        .find(|h| !collapse_whitespace(&h.text().collect::<String>()).is_empty());
+    let title = heading
+        .map(|h| collapse_whitespace(&h.text().collect::<String>()))
+        .unwrap_or_default();
+
+    // Leave out the heading used as the title, notes and non-text elements
+    let heading_id = heading.map(|h| h.id());
+    let text = crate::html_text::text_of(body, &|element| {
+        let name = element.value().name();
+        // Note markers are usually superscript links, e.g. <sup><a href="#fn1">1</a></sup>
+        let is_note_marker =
+            name == "sup" && element.child_elements().any(|c| c.value().name() == "a");
+        Some(element.id()) == heading_id
+            || SKIPPED_ELEMENTS.contains(&name)
+            || is_note_marker
+            || has_type(element, NOTE_TYPES)
+    });
+    if text.is_empty() {
+        return None;
+    }
+    Some(Chapter { title, text })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+    use sqlx::Row;
+    use std::io::Write;
+
+    /// A small EPUB 3 with a cover, a table of contents, two chapters and an appendix
+    /// marked non-linear
+    fn sample_epub() -> Vec<u8> {
+        let files = [
+            (
+                "META-INF/container.xml",
+                r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#,
+            ),
+            (
+                "OEBPS/content.opf",
+                r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>The  Sample Book</dc:title></metadata>
+  <manifest>
+    <item id="cover" href="Text/cover.xhtml" media-type="application/xhtml+xml"/>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="c1" href="Text/chapter%201.xhtml" media-type="application/xhtml+xml"/>
+    <item id="c2" href="Text/ch2.xhtml" media-type="application/xhtml+xml"/>
+    <item id="notes" href="Text/notes.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="cover"/><itemref idref="nav"/><itemref idref="c1"/><itemref idref="c2"/>
+    <itemref idref="notes" linear="no"/>
+  </spine>
+</package>"#,
+            ),
+            (
+                "OEBPS/Text/cover.xhtml",
+                "<html><body><img src='cover.jpg'/></body></html>",
+            ),
+            (
+                "OEBPS/nav.xhtml",
+                "<html><body><nav><ol><li>Chapter 1</li></ol></nav></body></html>",
+            ),
+            (
+                "OEBPS/Text/chapter 1.xhtml",
+                r#"<html xmlns:epub="http://www.idpf.org/2007/ops"><head><title>ch1</title><style>p { color: red }</style></head>
+<body><h1>The  Beginning</h1>
+<p>It was a dark&nbsp;night.<sup><a href="notes.xhtml#n1">1</a></sup> The wind
+   howled.</p>
+<aside epub:type="footnote"><p>A footnote.</p></aside>
+<p>Morning came.</p></body></html>"#,
+            ),
+            (
+                "OEBPS/Text/ch2.xhtml",
+                "<html><body><p>No heading here.</p><p>The end.</p></body></html>",
+            ),
+            (
+                "OEBPS/Text/notes.xhtml",
+                "<html><body><p>1. A note.</p></body></html>",
+            ),
+        ];
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer
+                .start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_extract() {
+        let book = extract(&sample_epub()).unwrap();
+        assert_eq!(book.title.as_deref(), Some("The Sample Book"));
+        assert_eq!(
+            book.chapters,
+            [
+                Chapter {
+                    title: "The Beginning".to_string(),
+                    text: "It was a dark night. The wind howled.\nMorning came.".to_string(),
+                },
+                Chapter {
+                    title: "Chapter 2".to_string(),
+                    text: "No heading here.\nThe end.".to_string(),
+                },
+            ]
+        );
+
+        assert_eq!(
+            book.to_text(Some(CHAPTER_DELIMITER)),
+            "# The Beginning\nIt was a dark night. The wind howled.\nMorning came.\n\n# Chapter 2\nNo heading here.\nThe end."
+        );
+        assert!(book.to_text(None).starts_with("The Beginning.\nIt was"));
+        // The delimited text splits back into the same chapters
+        let chapters = chapters::split_chapters(
+            &book.to_text(Some(CHAPTER_DELIMITER)),
+            Some(CHAPTER_DELIMITER),
+        );
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[0].title, "The Beginning");
+    }
+
+    #[test]
+    fn test_extract_invalid() {
+        assert!(
+            extract(b"not a zip")
+                .unwrap_err()
+                .starts_with("Not a valid EPUB file")
+        );
+
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        writer
+            .start_file("mimetype", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(b"application/epub+zip").unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        assert!(
+            extract(&bytes)
+                .unwrap_err()
+                .contains("META-INF/container.xml")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate_epub() {
+        let app = TestApp::spawn_without_workers().await;
+        let upload = |epub: Vec<u8>, format: Option<&str>| {
+            let mut form = reqwest::multipart::Form::new().part(
+                "epub_file",
+                reqwest::multipart::Part::bytes(epub).file_name("book.epub"),
+            );
+            if let Some(format) = format {
+                form = form.text("format", format.to_string());
+            }
+            app.client
+                .post(app.url("/generate/epub"))
+                .bearer_auth(app.token("alice"))
+                .multipart(form)
+                .send()
+        };
+
+        let resp = upload(b"not an epub".to_vec(), None).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let resp = upload(sample_epub(), None).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let row = sqlx::query(
+            "SELECT format, chapter_delimiter, title, tag, input_text FROM jobs WHERE id = $1",
+        )
+        .bind(uuid::Uuid::parse_str(body["id"].as_str().unwrap()).unwrap())
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        assert_eq!(row.get::<String, _>("format"), "m4b");
+        assert_eq!(
+            row.get::<Option<String>, _>("chapter_delimiter").as_deref(),
+            Some(CHAPTER_DELIMITER)
+        );
+        assert_eq!(
+            row.get::<Option<String>, _>("title").as_deref(),
+            Some("The Sample Book")
+        );
+        assert_eq!(
+            row.get::<Option<String>, _>("tag").as_deref(),
+            Some("The Sample Book")
+        );
+        assert!(
+            row.get::<String, _>("input_text")
+                .starts_with("# The Beginning\nIt was")
+        );
+
+        // Other formats read the chapter titles out instead
+        let resp = upload(sample_epub(), Some("mp3")).await.unwrap();
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let input_text: String = sqlx::query_scalar("SELECT input_text FROM jobs WHERE id = $1")
+            .bind(uuid::Uuid::parse_str(body["id"].as_str().unwrap()).unwrap())
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        assert!(input_text.starts_with("The Beginning.\nIt was"));
+
+        app.teardown().await;
+    }
+
+    #[test]
+    fn test_resolve_href() {
+        assert_eq!(
+            resolve_href("OEBPS", "Text/ch%201.xhtml#top"),
+            "OEBPS/Text/ch 1.xhtml"
+        );
+        assert_eq!(
+            resolve_href("OEBPS/Text", "../ch2.xhtml"),
+            "OEBPS/ch2.xhtml"
+        );
+        assert_eq!(resolve_href("", "ch3.xhtml"), "ch3.xhtml");
+    }
+}
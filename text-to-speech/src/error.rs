@@ -0,0 +1,186 @@
+//! API errors.
+//!
+//! Errors are returned as RFC 7807 `application/problem+json` documents with a
+//! machine-readable `code` alongside the human-readable `detail`, so clients can tell
+//! errors apart without matching on the message, and the ID of the request, to quote
+//! when reporting a problem:
+//!
+//! ```json
+//! {
+//!   "type": "urn:tts:error:input_too_long",
+//!   "title": "Payload Too Large",
+//!   "status": 413,
+//!   "detail": "Input text is 1001 characters, over the limit of 1000 characters",
+//!   "code": "input_too_long",
+//!   "request_id": "3f2c9a1e-..."
+//! }
+//! ```
+
+use crate::request_id::RequestId;
+use axum::Json;
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+tokio::task_local! {
+    /// ID of the request being handled, set by [`crate::request_id::set_request_id`]
+    pub static CURRENT_REQUEST_ID: RequestId;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// Stable identifier of the kind of error, e.g. `input_too_long`
+    pub code: &'static str,
+    pub detail: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            detail: detail.into(),
+        }
+    }
+
+    /// 400 for a missing or invalid option
+    pub fn invalid(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", detail)
+    }
+
+    pub fn internal(detail: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", detail)
+    }
+
+    /// 500 for a failed query, which is logged rather than shown to the client
+    pub fn database(error: sqlx::Error) -> Self {
+        tracing::error!(error = %error, "Database error");
+        Self::internal("Database error")
+    }
+
+    pub fn job_not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, "job_not_found", "Job not found")
+    }
+
+    pub fn invalid_id() -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_id", "Invalid UUID")
+    }
+}
+
+/// Errors from helpers that report a status and message, coded by the status alone
+impl From<(StatusCode, String)> for ApiError {
+    fn from((status, detail): (StatusCode, String)) -> Self {
+        let code = match status {
+            StatusCode::BAD_REQUEST => "invalid_request",
+            StatusCode::UNAUTHORIZED => "unauthorized",
+            StatusCode::FORBIDDEN => "forbidden",
+            StatusCode::NOT_FOUND => "not_found",
+            StatusCode::CONFLICT => "conflict",
+            StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+            StatusCode::UNPROCESSABLE_ENTITY => "unprocessable",
+            StatusCode::BAD_GATEWAY => "upstream_error",
+            StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+            StatusCode::INSUFFICIENT_STORAGE => "storage_full",
+            _ if status.is_server_error() => "internal_error",
+            _ => "error",
+        };
+        Self::new(status, code, detail)
+    }
+}
+
+/// An RFC 7807 problem details document
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    /// `urn:tts:error:` followed by `code`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The HTTP status text
+    pub title: String,
+    pub status: u16,
+    /// What went wrong, for people
+    pub detail: String,
+    /// What went wrong, for programs, e.g. `input_too_long` or `job_not_found`
+    pub code: String,
+    /// The request's `X-Request-Id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl From<&ApiError> for Problem {
+    fn from(error: &ApiError) -> Self {
+        Self {
+            kind: format!("urn:tts:error:{}", error.code),
+            title: error
+                .status
+                .canonical_reason()
+                .unwrap_or("Error")
+                .to_string(),
+            status: error.status.as_u16(),
+            detail: error.detail.clone(),
+            code: error.code.to_string(),
+            request_id: CURRENT_REQUEST_ID.try_with(|id| id.0.clone()).ok(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(Problem::from(&self))).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(PROBLEM_CONTENT_TYPE),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(error: ApiError) -> (Response, serde_json::Value) {
+        let response = error.into_response();
+        let (parts, body) = response.into_parts();
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (
+            Response::from_parts(parts, axum::body::Body::empty()),
+            serde_json::from_slice(&bytes).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_problem_response() {
+        let (response, problem) = CURRENT_REQUEST_ID
+            .scope(
+                RequestId("req-1".to_string()),
+                body(ApiError::job_not_found()),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            PROBLEM_CONTENT_TYPE
+        );
+        assert_eq!(
+            problem,
+            serde_json::json!({
+                "type": "urn:tts:error:job_not_found",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "Job not found",
+                "code": "job_not_found",
+                "request_id": "req-1",
+            })
+        );
+
+        // Outside a request there is no ID to report
+        let error = ApiError::from((StatusCode::INSUFFICIENT_STORAGE, "Full".to_string()));
+        let (_, problem) = body(error).await;
+        assert_eq!(problem["code"], "storage_full");
+        assert!(problem.get("request_id").is_none());
+    }
+}
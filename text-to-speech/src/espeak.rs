@@ -0,0 +1,95 @@
+//! espeak-ng called in-process through its C library, built with `--features espeak-ng`.
+//!
+//! Running the `espeak-ng` binary costs a process spawn and a voice load per sentence,
+//! which dominates the latency of short live sentences. The library is loaded once and
+//! phonemizes clause by clause with `espeak_TextToPhonemes`, which is what the binary's
+//! `--ipa` does. Its state is global and not thread-safe, so calls take turns.
+
+use std::ffi::{CStr, CString, c_char, c_int, c_void};
+use std::sync::{Mutex, OnceLock};
+
+const AUDIO_OUTPUT_SYNCHRONOUS: c_int = 2;
+/// Report initialization errors instead of exiting the process
+const INITIALIZE_DONT_EXIT: c_int = 0x8000;
+const CHARS_UTF8: c_int = 1;
+const PHONEMES_IPA: c_int = 0x02;
+const EE_OK: c_int = 0;
+
+#[link(name = "espeak-ng")]
+unsafe extern "C" {
+    fn espeak_Initialize(
+        output: c_int,
+        buflength: c_int,
+        path: *const c_char,
+        options: c_int,
+    ) -> c_int;
+    fn espeak_SetVoiceByName(name: *const c_char) -> c_int;
+    fn espeak_TextToPhonemes(
+        textptr: *mut *const c_void,
+        textmode: c_int,
+        phonememode: c_int,
+    ) -> *const c_char;
+}
+
+/// The voice last set, once the library is initialized
+static ESPEAK: OnceLock<Result<Mutex<Option<String>>, String>> = OnceLock::new();
+
+fn library() -> Result<&'static Mutex<Option<String>>, String> {
+    ESPEAK
+        .get_or_init(|| {
+            // SAFETY: no path means the default data directory; nothing else is passed
+            let sample_rate = unsafe {
+                espeak_Initialize(
+                    AUDIO_OUTPUT_SYNCHRONOUS,
+                    0,
+                    std::ptr::null(),
+                    INITIALIZE_DONT_EXIT,
+                )
+            };
+            if sample_rate < 0 {
+                return Err(format!("espeak_Initialize failed ({})", sample_rate));
+            }
+            Ok(Mutex::new(None))
+        })
+        .as_ref()
+        .map_err(Clone::clone)
+}
+
+/// Load the library and its data, so the first sentence doesn't pay for it
+pub fn init() -> Result<(), String> {
+    library().map(|_| ())
+}
+
+/// Convert text to IPA phonemes, as `espeak-ng --ipa -q -v <lang>` would
+pub fn phonemize(text: &str, lang: &str) -> Result<String, String> {
+    let text = CString::new(text).map_err(|_| "Text contains a NUL byte".to_string())?;
+    let mut voice = library()?
+        .lock()
+        .map_err(|e| format!("Failed to lock espeak-ng: {}", e))?;
+    if voice.as_deref() != Some(lang) {
+        let name = CString::new(lang).map_err(|_| format!("Invalid lang '{}'", lang))?;
+        // SAFETY: the library is initialized and the lock held
+        if unsafe { espeak_SetVoiceByName(name.as_ptr()) } != EE_OK {
+            *voice = None;
+            return Err(format!("espeak-ng has no voice '{}'", lang));
+        }
+        *voice = Some(lang.to_string());
+    }
+
+    // Each call phonemizes one clause and moves the pointer past it, to null at the end
+    let mut clauses = Vec::new();
+    let mut position = text.as_ptr() as *const c_void;
+    while !position.is_null() {
+        // SAFETY: `position` points into `text`, which outlives the loop; the returned
+        // buffer is the library's and is copied out before the next call
+        let phonemes = unsafe {
+            let phonemes = espeak_TextToPhonemes(&mut position, CHARS_UTF8, PHONEMES_IPA);
+            if phonemes.is_null() {
+                break;
+            }
+            CStr::from_ptr(phonemes).to_string_lossy().into_owned()
+        };
+        clauses.push(phonemes);
+    }
+    Ok(clauses.join(" "))
+}
@@ -0,0 +1,266 @@
+//! Output audio formats for batch jobs.
+//!
+//! Kokoro produces WAVs that are encoded into the format requested with the job's
+//! `format` field, optionally with a `bitrate` and `sample_rate`: by ffmpeg, or in
+//! process for the formats `encode` supports. Other sample rates are converted in process
+//! (see `resample`). The format is
+//! stored with the job so status and download responses report the right content
+//! type and file extension. `m4b` audiobooks also get chapter marks (see `chapters`).
+
+use serde::Serialize;
+
+/// Bitrates accepted for lossy formats, in kbps
+const BITRATE_RANGE_KBPS: std::ops::RangeInclusive<u32> = 32..=320;
+
+/// Sample rates ffmpeg's MP3, Vorbis, FLAC and WAV encoders all accept
+const COMMON_SAMPLE_RATES: &[u32] = &[8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000];
+/// Opus only encodes at these rates
+const OPUS_SAMPLE_RATES: &[u32] = &[8000, 12000, 16000, 24000, 48000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Mp3,
+    /// Opus in an Ogg container
+    Opus,
+    /// Vorbis in an Ogg container
+    Ogg,
+    Flac,
+    /// Uncompressed 16-bit PCM
+    Wav,
+    /// AAC audiobook in an MP4 container, with chapter marks
+    M4b,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 6] = [
+        Self::Mp3,
+        Self::Opus,
+        Self::Ogg,
+        Self::Flac,
+        Self::Wav,
+        Self::M4b,
+    ];
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim().to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|f| f.as_str() == value)
+            .ok_or_else(|| {
+                format!(
+                    "Unsupported format '{}' (expected one of {})",
+                    value,
+                    Self::ALL.map(|f| f.as_str()).join(", ")
+                )
+            })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+            Self::Ogg => "ogg",
+            Self::Flac => "flac",
+            Self::Wav => "wav",
+            Self::M4b => "m4b",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.as_str()
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Mp3 => "audio/mpeg",
+            Self::Opus => "audio/ogg; codecs=opus",
+            Self::Ogg => "audio/ogg",
+            Self::Flac => "audio/flac",
+            Self::Wav => "audio/wav",
+            Self::M4b => "audio/mp4",
+        }
+    }
+
+    /// ffmpeg muxer for writing the format to a pipe. MP4 needs to seek back to write
+    /// its index, so `m4b` can't be streamed.
+    pub fn stream_muxer(self) -> Option<&'static str> {
+        match self {
+            Self::Mp3 => Some("mp3"),
+            Self::Opus | Self::Ogg => Some("ogg"),
+            Self::Flac => Some("flac"),
+            Self::Wav => Some("wav"),
+            Self::M4b => None,
+        }
+    }
+
+    fn codec(self) -> &'static str {
+        match self {
+            Self::Mp3 => "libmp3lame",
+            Self::Opus => "libopus",
+            Self::Ogg => "libvorbis",
+            Self::Flac => "flac",
+            Self::Wav => "pcm_s16le",
+            Self::M4b => "aac",
+        }
+    }
+
+    fn is_lossless(self) -> bool {
+        matches!(self, Self::Flac | Self::Wav)
+    }
+
+    fn sample_rates(self) -> &'static [u32] {
+        match self {
+            Self::Opus => OPUS_SAMPLE_RATES,
+            _ => COMMON_SAMPLE_RATES,
+        }
+    }
+}
+
+/// How a job's audio is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EncodingOptions {
+    pub format: OutputFormat,
+    /// Target bitrate for lossy formats; the format's default when unset
+    pub bitrate_kbps: Option<u32>,
+    /// Output sample rate; the synthesized audio's rate when unset
+    pub sample_rate: Option<u32>,
+}
+
+impl EncodingOptions {
+    /// Build options from the raw form fields, rejecting combinations the encoder can't produce
+    pub fn new(
+        format: OutputFormat,
+        bitrate: Option<&str>,
+        sample_rate: Option<&str>,
+    ) -> Result<Self, String> {
+        let bitrate_kbps = bitrate.map(parse_bitrate).transpose()?;
+        if bitrate_kbps.is_some() && format.is_lossless() {
+            return Err(format!(
+                "Bitrate can't be set for lossless format '{}'",
+                format.as_str()
+            ));
+        }
+
+        let sample_rate = sample_rate
+            .map(|value| {
+                value
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid sample rate '{}'", value))
+            })
+            .transpose()?;
+        if let Some(rate) = sample_rate
+            && !format.sample_rates().contains(&rate)
+        {
+            return Err(format!(
+                "Unsupported sample rate {} for {} (expected one of {})",
+                rate,
+                format.as_str(),
+                format
+                    .sample_rates()
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        Ok(Self {
+            format,
+            bitrate_kbps,
+            sample_rate,
+        })
+    }
+
+    /// ffmpeg codec, bitrate and sample rate arguments
+    pub fn ffmpeg_args(&self) -> Vec<String> {
+        let mut args = vec!["-c:a".to_string(), self.format.codec().to_string()];
+        match (self.format, self.bitrate_kbps) {
+            (_, Some(kbps)) => args.extend(["-b:a".to_string(), format!("{}k", kbps)]),
+            (OutputFormat::Mp3, None) => args.extend(["-b:a".to_string(), "192k".to_string()]),
+            (OutputFormat::Opus | OutputFormat::M4b, None) => {
+                args.extend(["-b:a".to_string(), "64k".to_string()])
+            }
+            (OutputFormat::Ogg, None) => args.extend(["-q:a".to_string(), "5".to_string()]),
+            _ => {}
+        }
+        if let Some(rate) = self.sample_rate {
+            args.extend(["-ar".to_string(), rate.to_string()]);
+        }
+        args
+    }
+}
+
+/// Parse a bitrate such as "64k" or "128" into kbps
+fn parse_bitrate(value: &str) -> Result<u32, String> {
+    let trimmed = value.trim().to_ascii_lowercase();
+    let kbps = trimmed
+        .strip_suffix('k')
+        .unwrap_or(&trimmed)
+        .parse::<u32>()
+        .map_err(|_| format!("Invalid bitrate '{}'", value))?;
+    if !BITRATE_RANGE_KBPS.contains(&kbps) {
+        return Err(format!(
+            "Bitrate must be between {}k and {}k",
+            BITRATE_RANGE_KBPS.start(),
+            BITRATE_RANGE_KBPS.end()
+        ));
+    }
+    Ok(kbps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(OutputFormat::parse("mp3"), Ok(OutputFormat::Mp3));
+        assert_eq!(OutputFormat::parse(" FLAC "), Ok(OutputFormat::Flac));
+        assert_eq!(OutputFormat::parse("opus").unwrap().extension(), "opus");
+        assert_eq!(
+            OutputFormat::parse("m4b").unwrap().content_type(),
+            "audio/mp4"
+        );
+        assert!(
+            OutputFormat::parse("aac")
+                .unwrap_err()
+                .contains("mp3, opus, ogg, flac, wav, m4b")
+        );
+    }
+
+    #[test]
+    fn test_encoding_options() {
+        let defaults = EncodingOptions::default();
+        assert_eq!(
+            defaults.ffmpeg_args(),
+            ["-c:a", "libmp3lame", "-b:a", "192k"]
+        );
+
+        let speech = EncodingOptions::new(OutputFormat::Mp3, Some("64k"), Some("22050")).unwrap();
+        assert_eq!(
+            speech.ffmpeg_args(),
+            ["-c:a", "libmp3lame", "-b:a", "64k", "-ar", "22050"]
+        );
+        assert_eq!(
+            EncodingOptions::new(OutputFormat::Ogg, Some("96"), None)
+                .unwrap()
+                .ffmpeg_args(),
+            ["-c:a", "libvorbis", "-b:a", "96k"]
+        );
+        assert_eq!(
+            EncodingOptions::new(OutputFormat::M4b, None, None)
+                .unwrap()
+                .ffmpeg_args(),
+            ["-c:a", "aac", "-b:a", "64k"]
+        );
+
+        assert!(EncodingOptions::new(OutputFormat::Mp3, Some("8k"), None).is_err());
+        assert!(EncodingOptions::new(OutputFormat::Mp3, Some("fast"), None).is_err());
+        assert!(EncodingOptions::new(OutputFormat::Flac, Some("128k"), None).is_err());
+        assert!(EncodingOptions::new(OutputFormat::Opus, None, Some("44100")).is_err());
+        assert!(EncodingOptions::new(OutputFormat::Opus, None, Some("48000")).is_ok());
+    }
+}
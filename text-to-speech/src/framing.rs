@@ -0,0 +1,120 @@
+//! Headers of the binary audio frames of the WebSocket streams.
+//!
+//! By default a frame starts with the index of its sentence, a u32 LE, then, unless the
+//! codec is `pcm_f32`, a codec byte, as frames always have. A `synthesize` message can ask
+//! for `"framing": "v1"` instead, where every frame starts with a header describing it, so
+//! a client can tell a stream it doesn't understand from audio. All fields are LE:
+//!
+//! | Bytes | Field |
+//! |-------|-------|
+//! | 0–3   | Magic, `KTTS` |
+//! | 4     | Version, 1 |
+//! | 5     | Codec: 0 `pcm_f32`, 1 `pcm16`, 2 `opus` |
+//! | 6–7   | Header length in bytes, 24; later versions may add fields, to be skipped |
+//! | 8–11  | Sample rate in Hz |
+//! | 12–15 | Sentence index |
+//! | 16–19 | Sequence number of the frame in its batch, from 0 |
+//! | 20–23 | Timestamp: milliseconds of the batch's audio before the frame |
+
+use serde::Deserialize;
+
+pub const MAGIC: &[u8; 4] = b"KTTS";
+pub const VERSION: u8 = 1;
+const HEADER_LEN: u16 = 24;
+
+/// Which header frames start with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// Sentence index and codec byte only
+    #[default]
+    Legacy,
+    V1,
+}
+
+/// Writes the header of each frame of a batch, keeping count of its frames and audio
+pub struct Framer {
+    framing: Framing,
+    codec: u8,
+    sample_rate: u32,
+    sequence: u32,
+    samples: u64,
+}
+
+impl Framer {
+    /// `codec` is the codec's number in the table above
+    pub fn new(framing: Framing, codec: u8, sample_rate: u32) -> Self {
+        Self {
+            framing,
+            codec,
+            sample_rate,
+            sequence: 0,
+            samples: 0,
+        }
+    }
+
+    /// The header of the next frame, of sentence `sentence_index` and holding `samples`
+    /// samples, for the payload to be appended to
+    pub fn start_frame(&mut self, sentence_index: u32, samples: usize) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN as usize);
+        match self.framing {
+            Framing::Legacy => {
+                frame.extend_from_slice(&sentence_index.to_le_bytes());
+                if self.codec != 0 {
+                    frame.push(self.codec);
+                }
+            }
+            Framing::V1 => {
+                let timestamp_ms = self.samples * 1000 / self.sample_rate as u64;
+                frame.extend_from_slice(MAGIC);
+                frame.push(VERSION);
+                frame.push(self.codec);
+                frame.extend_from_slice(&HEADER_LEN.to_le_bytes());
+                frame.extend_from_slice(&self.sample_rate.to_le_bytes());
+                frame.extend_from_slice(&sentence_index.to_le_bytes());
+                frame.extend_from_slice(&self.sequence.to_le_bytes());
+                frame.extend_from_slice(&(timestamp_ms as u32).to_le_bytes());
+            }
+        }
+        self.sequence += 1;
+        self.samples += samples as u64;
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1_header() {
+        let mut framer = Framer::new(Framing::V1, 2, 24000);
+        let first = framer.start_frame(3, 480);
+        let second = framer.start_frame(3, 480);
+        assert_eq!(first.len(), HEADER_LEN as usize);
+        assert_eq!(&first[..4], b"KTTS");
+        assert_eq!(first[4..6], [1, 2]);
+        assert_eq!(u16::from_le_bytes(first[6..8].try_into().unwrap()), 24);
+        assert_eq!(u32::from_le_bytes(first[8..12].try_into().unwrap()), 24000);
+        assert_eq!(u32::from_le_bytes(first[12..16].try_into().unwrap()), 3);
+        assert_eq!(first[16..24], [0; 8]);
+        // The next frame comes 20 ms later
+        assert_eq!(u32::from_le_bytes(second[16..20].try_into().unwrap()), 1);
+        assert_eq!(u32::from_le_bytes(second[20..24].try_into().unwrap()), 20);
+    }
+
+    #[test]
+    fn test_legacy_header() {
+        assert_eq!(
+            Framer::new(Framing::Legacy, 0, 24000).start_frame(7, 10),
+            7u32.to_le_bytes()
+        );
+        assert_eq!(
+            Framer::new(Framing::Legacy, 1, 24000).start_frame(7, 10),
+            [7, 0, 0, 0, 1]
+        );
+        let framing = |name: &str| serde_json::from_value::<Framing>(serde_json::json!(name));
+        assert_eq!(framing("v1").unwrap(), Framing::V1);
+        assert!(framing("v2").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! ONNX-based grapheme-to-phoneme (G2P) model.
+//!
+//! An in-process alternative to espeak-ng. The model is a character-level
+//! transformer (DeepPhonemizer-style "forward" model): grapheme IDs go in and
+//! per-position phoneme logits come out. Decoding takes the argmax at each
+//! position, collapses repeats and drops padding.
+
+use ndarray::Array2;
+use ort::session::Session;
+use ort::value::Value;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Punctuation passed through unchanged around words (all present in the Kokoro vocab)
+const PASSTHROUGH_PUNCTUATION: &[char] = &[';', ':', ',', '.', '!', '?', '"', '(', ')'];
+
+/// Symbol tables shipped alongside the model as `vocab.json`.
+/// Index 0 of each table is the padding symbol.
+#[derive(Debug, Deserialize)]
+struct G2pVocab {
+    graphemes: Vec<String>,
+    phonemes: Vec<String>,
+}
+
+/// G2P ONNX model
+pub struct G2pModel {
+    session: Mutex<Session>,
+    graphemes: HashMap<char, i64>,
+    phonemes: Vec<String>,
+}
+
+impl G2pModel {
+    /// Load a G2P model from a directory containing `model.onnx` and `vocab.json`
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        let dir = dir.as_ref();
+
+        let vocab_json = std::fs::read_to_string(dir.join("vocab.json"))
+            .map_err(|e| format!("Failed to read G2P vocab: {}", e))?;
+        let vocab: G2pVocab = serde_json::from_str(&vocab_json)
+            .map_err(|e| format!("Failed to parse G2P vocab: {}", e))?;
+
+        let graphemes = vocab
+            .graphemes
+            .iter()
+            .enumerate()
+            .skip(1) // padding
+            .filter_map(|(id, g)| {
+                let mut chars = g.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some((c, id as i64)),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let session = Session::builder()
+            .map_err(|e| format!("Failed to create ORT session builder: {}", e))?
+            .with_intra_threads(1)
+            .map_err(|e| format!("Failed to set thread count: {}", e))?
+            .commit_from_file(dir.join("model.onnx"))
+            .map_err(|e| format!("Failed to load G2P model: {}", e))?;
+
+        Ok(Self {
+            session: Mutex::new(session),
+            graphemes,
+            phonemes: vocab.phonemes,
+        })
+    }
+
+    /// Convert a line of text to phonemes, word by word, keeping punctuation in place
+    pub fn phonemize(&self, text: &str) -> Result<String, String> {
+        let mut out = Vec::new();
+        for token in text.split_whitespace() {
+            let (prefix, word, suffix) = split_punctuation(token);
+            let phonemes = if word.is_empty() {
+                String::new()
+            } else {
+                self.phonemize_word(word)?
+            };
+            out.push(format!("{}{}{}", prefix, phonemes, suffix));
+        }
+        Ok(out.join(" "))
+    }
+
+    fn phonemize_word(&self, word: &str) -> Result<String, String> {
+        let ids: Vec<i64> = word
+            .to_lowercase()
+            .chars()
+            .filter_map(|c| self.graphemes.get(&c).copied())
+            .collect();
+        if ids.is_empty() {
+            return Ok(String::new());
+        }
+
+        let input = Array2::from_shape_vec((1, ids.len()), ids)
+            .map_err(|e| format!("Failed to create G2P input array: {}", e))?;
+        let input_value = Value::from_array(input)
+            .map_err(|e| format!("Failed to create G2P input tensor: {}", e))?;
+
+        let mut session = self
+            .session
+            .lock()
+            .map_err(|e| format!("Failed to lock G2P session: {}", e))?;
+        let outputs = session
+            .run(ort::inputs![input_value])
+            .map_err(|e| format!("G2P inference failed: {}", e))?;
+
+        // Output shape: [batch=1, positions, phoneme vocab size]
+        let (shape, logits) = outputs[0]
+            .try_extract_tensor::<f32>()
+            .map_err(|e| format!("Failed to extract G2P logits: {}", e))?;
+        let num_classes = *shape.last().ok_or("G2P output has no dimensions")? as usize;
+
+        Ok(decode_logits(logits, num_classes, &self.phonemes))
+    }
+}
+
+/// Greedy decode: argmax per position, collapse repeats, drop padding (index 0)
+fn decode_logits(logits: &[f32], num_classes: usize, phonemes: &[String]) -> String {
+    if num_classes == 0 {
+        return String::new();
+    }
+
+    let mut out = String::new();
+    let mut prev = 0;
+    for position in logits.chunks_exact(num_classes) {
+        let best = position
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        if best != 0
+            && best != prev
+            && let Some(p) = phonemes.get(best)
+        {
+            out.push_str(p);
+        }
+        prev = best;
+    }
+    out
+}
+
+/// Split leading and trailing punctuation off a whitespace-delimited token
+fn split_punctuation(token: &str) -> (&str, &str, &str) {
+    let word_start = token
+        .find(|c: char| !PASSTHROUGH_PUNCTUATION.contains(&c))
+        .unwrap_or(token.len());
+    let word_end = token
+        .rfind(|c: char| !PASSTHROUGH_PUNCTUATION.contains(&c))
+        .map(|i| i + token[i..].chars().next().map_or(1, char::len_utf8))
+        .unwrap_or(word_start);
+    (
+        &token[..word_start],
+        &token[word_start..word_end],
+        &token[word_end..],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_punctuation() {
+        assert_eq!(split_punctuation("Hello,"), ("", "Hello", ","));
+        assert_eq!(split_punctuation("(world)."), ("(", "world", ")."));
+        assert_eq!(split_punctuation("café"), ("", "café", ""));
+        assert_eq!(split_punctuation("?!"), ("?!", "", ""));
+    }
+
+    #[test]
+    fn test_decode_logits() {
+        let phonemes: Vec<String> = ["_", "h", "ə", "l"].iter().map(|s| s.to_string()).collect();
+        // Positions: h, h, pad, ə, l
+        let logits = [
+            0.0, 1.0, 0.0, 0.0, //
+            0.0, 1.0, 0.0, 0.0, //
+            1.0, 0.0, 0.0, 0.0, //
+            0.0, 0.0, 1.0, 0.0, //
+            0.0, 0.0, 0.0, 1.0, //
+        ];
+        assert_eq!(decode_logits(&logits, 4, &phonemes), "həl");
+    }
+}
@@ -1,487 +1,2689 @@
 use crate::auth::AuthenticatedUser;
-use crate::state::AppState;
+use crate::error::ApiError;
+use crate::format::{EncodingOptions, OutputFormat};
+use crate::renditions::Rendition;
+use crate::request_id::RequestId;
+use crate::state::{ActiveJob, AppState};
 use axum::{
     body::Body,
-    extract::{Extension, Multipart, Path, State},
-    http::{StatusCode, header},
+    extract::{Extension, Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Json, Response},
 };
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres, Row};
-use std::io::Write;
 use std::process::{Command, Stdio};
-use tempfile::Builder;
+use tempfile::{Builder, TempDir};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 #[serde(tag = "status", rename_all = "lowercase")]
-#[allow(dead_code)]
 enum JobStatusResponse {
-    Processing,
-    Completed,
-    Error { message: String },
+    /// Waiting for a free worker, or for its next attempt after a transient failure
+    Queued {
+        /// 1 for the next job to be processed
+        position: i64,
+        #[serde(skip_serializing_if = "is_zero")]
+        retry_count: i32,
+        /// Why the previous attempt failed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        last_error: Option<String>,
+    },
+    Processing {
+        /// Percentage of the text synthesized so far; absent until the text has been split
+        #[serde(skip_serializing_if = "Option::is_none")]
+        progress: Option<f64>,
+        chunks_done: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        chunks_total: Option<i32>,
+    },
+    Completed {
+        /// Always 100
+        progress: f64,
+        download_url: String,
+        format: String,
+        content_type: String,
+        /// Length of the encoded audio
+        #[serde(skip_serializing_if = "Option::is_none")]
+        duration_seconds: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        file_size_bytes: Option<i64>,
+        /// When cleanup will delete the job unless it is downloaded again before then;
+        /// absent for pinned jobs
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<DateTime<Utc>>,
+    },
+    Error {
+        message: String,
+    },
 }
 
+fn is_zero(n: &i32) -> bool {
+    *n == 0
+}
+
+/// A generate request, from either the multipart or the JSON endpoint
+struct GenerateRequest {
+    text_bytes: axum::body::Bytes,
+    /// Declared content type of an uploaded file
+    content_type: Option<String>,
+    speed: String,
+    pitch: Option<String>,
+    loudness: Option<String>,
+    voice: String,
+    lang: Option<String>,
+    input_filename: Option<String>,
+    title: Option<String>,
+    tag: Option<String>,
+    track: Option<String>,
+    chapter_delimiter: Option<String>,
+    format: Option<String>,
+    bitrate: Option<String>,
+    sample_rate: Option<String>,
+    ttl_days: Option<String>,
+    /// Labels to find the job by; comma-separated values are split
+    tags: Vec<String>,
+    /// Formats to produce besides `format`; comma-separated values are split
+    renditions: Vec<String>,
+}
+
+/// Body of `POST /generate-json`
+#[derive(Deserialize, ToSchema)]
+pub struct GenerateJsonRequest {
+    text: String,
+    #[serde(flatten)]
+    options: GenerateOptions,
+}
+
+/// Body of `POST /generate/url`
+#[derive(Deserialize, ToSchema)]
+pub struct GenerateUrlRequest {
+    /// `http` or `https` page to read; HTML pages are reduced to their main article
+    url: String,
+    #[serde(flatten)]
+    options: GenerateOptions,
+}
+
+/// Job options of the JSON generate endpoints
+#[derive(Deserialize, ToSchema)]
+pub struct GenerateOptions {
+    /// Defaults to `af_heart`; see `GET /voices`
+    voice: Option<String>,
+    /// Language to phonemize the text as, e.g. `es` or `fr-fr`. Defaults to the voice's;
+    /// `auto` phonemizes sentences detected as another language as that one.
+    lang: Option<String>,
+    /// Speaking rate, `0.25` to `4.0`, defaults to `1.0`
+    speed: Option<f32>,
+    /// Pitch shift in semitones, `-12` to `12`
+    pitch: Option<f32>,
+    /// Target loudness in LUFS, `-70` to `-5`, e.g. `-16`; `0` for none. Defaults to
+    /// `TTS_LOUDNESS_LUFS`
+    loudness: Option<f32>,
+    /// `mp3` (default), `opus`, `ogg`, `flac`, `wav` or `m4b`
+    format: Option<String>,
+    /// For lossy formats, `32k` to `320k`
+    bitrate: Option<String>,
+    /// Output sample rate in Hz, defaults to 24000
+    sample_rate: Option<u32>,
+    /// For `m4b`: lines starting with this begin a chapter, instead of headings
+    chapter_delimiter: Option<String>,
+    /// Track title tag
+    title: Option<String>,
+    /// Album tag
+    tag: Option<String>,
+    /// Track number tag, e.g. `3` or `3/12`
+    track: Option<String>,
+    /// Delete the job this many days after it was last downloaded (1 to 3650)
+    ttl_days: Option<u32>,
+    /// Labels to find the job by with `GET /jobs?tag=`; not written to the file
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Other formats to produce as well, e.g. `["opus", "vtt"]`; `vtt` is a WebVTT
+    /// transcript. Listed by `GET /jobs/:id/artifacts`
+    #[serde(default)]
+    renditions: Vec<String>,
+}
+
+/// Response of `POST /generate` and `POST /generate-json`
+#[derive(Serialize, ToSchema)]
+pub struct JobCreated {
+    id: String,
+    /// Set when an identical completed job was returned instead of creating a new one
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    reused: bool,
+    /// Problems with the input that didn't stop the job, e.g. PDF pages without text
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+impl IntoResponse for JobCreated {
+    fn into_response(self) -> Response {
+        let job_id = Uuid::parse_str(&self.id).ok();
+        let mut response = Json(self).into_response();
+        if let Some(id) = job_id {
+            response
+                .extensions_mut()
+                .insert(crate::audit::AuditJobId(id));
+        }
+        response
+    }
+}
+
+/// Queue a job to synthesize an uploaded text file
+#[utoipa::path(
+    post,
+    path = "/generate",
+    tag = "jobs",
+    request_body(content = crate::openapi::GenerateForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, body = JobCreated),
+        (status = 400, description = "Missing `text_file` or an invalid option", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 413, description = "Text longer than `TTS_MAX_INPUT_CHARS` characters, or a JSON `UploadTooLarge` for a body over `TTS_MAX_UPLOAD_BYTES`", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 422, description = "The text is empty", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Over the monthly audio limit or daily character quota", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 507, description = "The storage quota is reached", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "The model is still loading", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
 pub async fn generate_speech(
     Extension(user): Extension<AuthenticatedUser>,
+    Extension(request_id): Extension<RequestId>,
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<JobCreated, ApiError> {
+    tracing::info!(user = %user.username, "Received generate_speech request");
+    let mut request = read_generate_form(&mut multipart, "text_file").await?;
+    if !crate::pdf::is_pdf(&request.text_bytes, request.content_type.as_deref()) {
+        return create_job(user, request_id, state, request).await;
+    }
+
+    let pdf = extract_pdf(std::mem::take(&mut request.text_bytes)).await?;
+    request.text_bytes = pdf.text.into();
+
+    let mut created = create_job(user, request_id, state, request).await?;
+    if !pdf.empty_pages.is_empty() {
+        created
+            .warnings
+            .push(pdf_empty_pages_warning(&pdf.empty_pages));
+    }
+    Ok(created)
+}
+
+async fn extract_pdf(bytes: axum::body::Bytes) -> Result<crate::pdf::ExtractedPdf, ApiError> {
+    // pdf-extract panics on some malformed files; that surfaces as a join error here
+    let pdf = tokio::task::spawn_blocking(move || crate::pdf::extract(&bytes))
+        .await
+        .map_err(|_| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "unreadable_input",
+                "Failed to read PDF",
+            )
+        })?
+        .map_err(|e| {
+            tracing::warn!(error = %e, "Failed to extract PDF text");
+            ApiError::new(StatusCode::BAD_REQUEST, "unreadable_input", e)
+        })?;
+    tracing::info!(text_len = pdf.text.len(), empty_pages = ?pdf.empty_pages, "Extracted PDF text");
+    Ok(pdf)
+}
+
+fn pdf_empty_pages_warning(empty_pages: &[usize]) -> String {
+    format!(
+        "No text found on PDF pages {}; scanned pages need OCR",
+        empty_pages
+            .iter()
+            .map(|page| page.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Queue an audiobook job for an uploaded EPUB, with a chapter per document in the book
+#[utoipa::path(
+    post,
+    path = "/generate/epub",
+    tag = "jobs",
+    request_body(content = crate::openapi::GenerateEpubForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, body = JobCreated),
+        (status = 400, description = "Missing or unreadable `epub_file`, or an invalid option", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 413, description = "Text longer than `TTS_MAX_INPUT_CHARS` characters, or a JSON `UploadTooLarge` for a body over `TTS_MAX_UPLOAD_BYTES`", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 422, description = "The text is empty", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Over the monthly audio limit or daily character quota", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 507, description = "The storage quota is reached", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "The model is still loading", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn generate_epub(
+    Extension(user): Extension<AuthenticatedUser>,
+    Extension(request_id): Extension<RequestId>,
     State(state): State<AppState>,
     mut multipart: Multipart,
-) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    tracing::info!(username = %user.username, "Received generate_speech request");
+) -> Result<JobCreated, ApiError> {
+    tracing::info!(user = %user.username, "Received generate_epub request");
+    let mut request = read_generate_form(&mut multipart, "epub_file").await?;
+    if request.chapter_delimiter.is_some() {
+        return Err(ApiError::invalid(
+            "chapter_delimiter can't be set for EPUB uploads; chapters come from the book",
+        ));
+    }
+
+    let epub_bytes = std::mem::take(&mut request.text_bytes);
+    let book = tokio::task::spawn_blocking(move || crate::epub::extract(&epub_bytes))
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .map_err(|e| {
+            tracing::warn!(error = %e, "Failed to extract EPUB text");
+            ApiError::new(StatusCode::BAD_REQUEST, "unreadable_input", e)
+        })?;
+    tracing::info!(chapters = book.chapters.len(), title = ?book.title, "Extracted EPUB chapters");
+
+    // Chapters are marked in the text for m4b, the default here; other formats read
+    // the chapter titles out but have no chapter marks
+    let format = request
+        .format
+        .get_or_insert_with(|| OutputFormat::M4b.as_str().to_string());
+    let delimiter = (OutputFormat::parse(format) == Ok(OutputFormat::M4b))
+        .then_some(crate::epub::CHAPTER_DELIMITER);
+    request.text_bytes = book.to_text(delimiter).into();
+    request.chapter_delimiter = delimiter.map(str::to_string);
+    if request.title.is_none() {
+        request.title = book.title.clone();
+    }
+    if request.tag.is_none() {
+        request.tag = book.title;
+    }
+    create_job(user, request_id, state, request).await
+}
 
+/// Read the fields of a `/generate` form, with the input file in `file_field`
+async fn read_generate_form(
+    multipart: &mut Multipart,
+    file_field: &str,
+) -> Result<GenerateRequest, ApiError> {
     let mut text_content = None;
+    let mut content_type = None;
     let mut speed = "1.0".to_string();
+    let mut pitch = None;
+    let mut loudness = None;
     let mut voice = "af_heart".to_string();
+    let mut lang = None;
     let mut input_filename = None;
+    let mut title = None;
+    let mut tag = None;
+    let mut track = None;
+    let mut chapter_delimiter = None;
+    let mut format = None;
+    let mut bitrate = None;
+    let mut sample_rate = None;
+    let mut ttl_days = None;
+    let mut tags = Vec::new();
+    let mut renditions = Vec::new();
 
     while let Some(field) = multipart.next_field().await.map_err(|e| {
         tracing::error!(error = %e, "Failed to parse multipart field");
-        (StatusCode::BAD_REQUEST, e.to_string())
+        ApiError::invalid(e.to_string())
     })? {
         let name = field.name().unwrap_or("").to_string();
         tracing::debug!(field_name = %name, "Processing multipart field");
 
-        if name == "text_file" {
+        if name == file_field {
             input_filename = field.file_name().map(|s| s.to_string());
+            content_type = field.content_type().map(|s| s.to_string());
             let data = field.bytes().await.map_err(|e| {
-                tracing::error!(error = %e, "Failed to read text_file bytes");
-                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                tracing::error!(error = %e, field_name = %name, "Failed to read file bytes");
+                ApiError::internal(e.to_string())
             })?;
-            tracing::info!(size_bytes = data.len(), "Received text_file");
+            tracing::info!(field_name = %name, size_bytes = data.len(), "Received file");
             text_content = Some(data);
         } else if name == "speed" {
             let txt = field.text().await.map_err(|e| {
                 tracing::error!(error = %e, "Failed to read speed field");
-                (StatusCode::BAD_REQUEST, e.to_string())
+                ApiError::invalid(e.to_string())
             })?;
             speed = txt;
+        } else if name == "tags" {
+            let txt = field.text().await.map_err(|e| {
+                tracing::error!(error = %e, "Failed to read tags field");
+                ApiError::invalid(e.to_string())
+            })?;
+            tags.push(txt);
+        } else if name == "renditions" {
+            let txt = field.text().await.map_err(|e| {
+                tracing::error!(error = %e, "Failed to read renditions field");
+                ApiError::invalid(e.to_string())
+            })?;
+            renditions.push(txt);
         } else if name == "voice" {
             let txt = field.text().await.map_err(|e| {
                 tracing::error!(error = %e, "Failed to read voice field");
-                (StatusCode::BAD_REQUEST, e.to_string())
+                ApiError::invalid(e.to_string())
             })?;
             voice = txt;
+        } else if matches!(
+            name.as_str(),
+            "lang"
+                | "pitch"
+                | "loudness"
+                | "format"
+                | "bitrate"
+                | "sample_rate"
+                | "title"
+                | "tag"
+                | "track"
+                | "chapter_delimiter"
+                | "ttl_days"
+        ) {
+            let txt = field.text().await.map_err(|e| {
+                tracing::error!(error = %e, field_name = %name, "Failed to read field");
+                ApiError::invalid(e.to_string())
+            })?;
+            let txt = txt.trim().to_string();
+            if !txt.is_empty() {
+                let slot = match name.as_str() {
+                    "lang" => &mut lang,
+                    "pitch" => &mut pitch,
+                    "loudness" => &mut loudness,
+                    "format" => &mut format,
+                    "bitrate" => &mut bitrate,
+                    "sample_rate" => &mut sample_rate,
+                    "title" => &mut title,
+                    "track" => &mut track,
+                    "chapter_delimiter" => &mut chapter_delimiter,
+                    "ttl_days" => &mut ttl_days,
+                    _ => &mut tag,
+                };
+                *slot = Some(txt);
+            }
         }
     }
 
     let text_bytes = text_content.ok_or_else(|| {
-        tracing::error!("Missing text_file field in request");
-        (
+        tracing::error!(field_name = %file_field, "Missing file field in request");
+        ApiError::new(
             StatusCode::BAD_REQUEST,
-            "Missing text_file field".to_string(),
+            "missing_input",
+            format!("Missing {} field", file_field),
         )
     })?;
 
-    if speed.parse::<f32>().is_err() {
-        tracing::error!(speed = %speed, "Invalid speed parameter");
-        return Err((
+    Ok(GenerateRequest {
+        text_bytes,
+        content_type,
+        speed,
+        pitch,
+        loudness,
+        voice,
+        lang,
+        input_filename,
+        title,
+        tag,
+        track,
+        chapter_delimiter,
+        format,
+        bitrate,
+        sample_rate,
+        ttl_days,
+        tags,
+        renditions,
+    })
+}
+
+/// Same as `/generate`, for clients that would rather send the text as JSON than build a multipart form
+#[utoipa::path(
+    post,
+    path = "/generate-json",
+    tag = "jobs",
+    request_body = GenerateJsonRequest,
+    responses(
+        (status = 200, body = JobCreated),
+        (status = 400, description = "An invalid option", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 413, description = "Text longer than `TTS_MAX_INPUT_CHARS` characters, or a JSON `UploadTooLarge` for a body over `TTS_MAX_UPLOAD_BYTES`", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 422, description = "The text is empty", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Over the monthly audio limit or daily character quota", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 507, description = "The storage quota is reached", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "The model is still loading", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn generate_speech_json(
+    Extension(user): Extension<AuthenticatedUser>,
+    Extension(request_id): Extension<RequestId>,
+    State(state): State<AppState>,
+    Json(body): Json<GenerateJsonRequest>,
+) -> Result<JobCreated, ApiError> {
+    tracing::info!(user = %user.username, text_len = body.text.len(), "Received generate_speech_json request");
+
+    create_job(
+        user,
+        request_id,
+        state,
+        body.options.into_request(body.text, None),
+    )
+    .await
+}
+
+impl GenerateOptions {
+    fn into_request(self, text: String, input_filename: Option<String>) -> GenerateRequest {
+        let non_empty = |value: Option<String>| {
+            value
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        GenerateRequest {
+            text_bytes: text.into(),
+            content_type: None,
+            speed: self.speed.unwrap_or(1.0).to_string(),
+            pitch: self.pitch.map(|pitch| pitch.to_string()),
+            loudness: self.loudness.map(|lufs| lufs.to_string()),
+            voice: self.voice.unwrap_or_else(|| "af_heart".to_string()),
+            lang: non_empty(self.lang),
+            input_filename,
+            title: non_empty(self.title),
+            tag: non_empty(self.tag),
+            track: non_empty(self.track),
+            chapter_delimiter: non_empty(self.chapter_delimiter),
+            format: non_empty(self.format),
+            bitrate: non_empty(self.bitrate),
+            sample_rate: self.sample_rate.map(|rate| rate.to_string()),
+            ttl_days: self.ttl_days.map(|days| days.to_string()),
+            tags: self.tags,
+            renditions: self.renditions,
+        }
+    }
+}
+
+/// Fetch a web page and queue a job to synthesize its main article
+#[utoipa::path(
+    post,
+    path = "/generate/url",
+    tag = "jobs",
+    request_body = GenerateUrlRequest,
+    responses(
+        (status = 200, body = JobCreated),
+        (status = 400, description = "Invalid or private URL, a page without readable text, or an invalid option", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 413, description = "Text longer than `TTS_MAX_INPUT_CHARS` characters, or a JSON `UploadTooLarge` for a body over `TTS_MAX_UPLOAD_BYTES`", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 422, description = "The text is empty", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Over the monthly audio limit or daily character quota", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 507, description = "The storage quota is reached", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 502, description = "The page couldn't be fetched", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "The model is still loading", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn generate_from_url(
+    Extension(user): Extension<AuthenticatedUser>,
+    Extension(request_id): Extension<RequestId>,
+    State(state): State<AppState>,
+    Json(body): Json<GenerateUrlRequest>,
+) -> Result<JobCreated, ApiError> {
+    tracing::info!(user = %user.username, url = %body.url, "Received generate_from_url request");
+
+    let page = crate::article::fetch(&body.url, state.url_fetch_allow_private).await?;
+    let mime = page
+        .content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let mut warnings = Vec::new();
+    let (text, page_title) = if crate::pdf::is_pdf(&page.body, Some(&mime)) {
+        let extracted = extract_pdf(page.body.into()).await?;
+        if !extracted.empty_pages.is_empty() {
+            warnings.push(pdf_empty_pages_warning(&extracted.empty_pages));
+        }
+        (extracted.text, None)
+    } else if mime == "text/plain" {
+        (String::from_utf8_lossy(&page.body).into_owned(), None)
+    } else if mime.is_empty() || mime == "text/html" || mime == "application/xhtml+xml" {
+        let html = String::from_utf8_lossy(&page.body);
+        let article = crate::article::extract_article(&html).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "no_readable_text",
+                "No readable text found on the page",
+            )
+        })?;
+        (article.text, article.title)
+    } else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "unsupported_content_type",
+            format!("Unsupported content type {}", mime),
+        ));
+    };
+    if text.trim().is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "no_readable_text",
+            "No readable text found on the page",
+        ));
+    }
+
+    let mut request = body.options.into_request(text, None);
+    if request.title.is_none() {
+        request.title = page_title;
+    }
+    let mut created = create_job(user, request_id, state, request).await?;
+    created.warnings.extend(warnings);
+    Ok(created)
+}
+
+/// Longest input text accepted by default, in characters: a long novel
+const DEFAULT_MAX_INPUT_CHARS: usize = 1_000_000;
+
+/// Parse `TTS_MAX_INPUT_CHARS`
+pub fn parse_max_input_chars(value: Option<&str>) -> Result<usize, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid TTS_MAX_INPUT_CHARS '{}'", v)),
+        None => Ok(DEFAULT_MAX_INPUT_CHARS),
+    }
+}
+
+/// Validate a generate request, record the job and start processing it in the background
+async fn create_job(
+    user: AuthenticatedUser,
+    request_id: RequestId,
+    state: AppState,
+    request: GenerateRequest,
+) -> Result<JobCreated, ApiError> {
+    let GenerateRequest {
+        text_bytes,
+        content_type: _,
+        speed,
+        pitch,
+        loudness,
+        voice,
+        lang,
+        input_filename,
+        title,
+        tag,
+        track,
+        chapter_delimiter,
+        format,
+        bitrate,
+        sample_rate,
+        ttl_days,
+        tags,
+        renditions,
+    } = request;
+
+    // Checked first, so the limit is reported whatever else is wrong with the request
+    {
+        let text = String::from_utf8_lossy(&text_bytes);
+        if text.trim().is_empty() {
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "empty_input",
+                "Input text is empty",
+            ));
+        }
+        let chars = text.chars().count();
+        if chars > state.max_input_chars {
+            tracing::warn!(chars, limit = state.max_input_chars, "Input text too long");
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "input_too_long",
+                format!(
+                    "Input text is {} characters, over the limit of {} characters",
+                    chars, state.max_input_chars
+                ),
+            ));
+        }
+    }
+
+    if let Err(e) = crate::prosody::parse_speed(&speed) {
+        tracing::warn!(speed = %speed, "Invalid speed parameter");
+        return Err(ApiError::invalid(e));
+    }
+    let pitch = pitch
+        .as_deref()
+        .map(crate::prosody::parse_pitch)
+        .transpose()
+        .map_err(ApiError::invalid)?
+        .flatten();
+    let loudness = match loudness {
+        Some(loudness) => crate::loudness::parse_loudness(&loudness).map_err(ApiError::invalid)?,
+        None => state.default_loudness,
+    };
+
+    let format = match format {
+        Some(format) => OutputFormat::parse(&format).map_err(|e| {
+            tracing::warn!(format = %format, "Unsupported output format requested");
+            ApiError::invalid(e)
+        })?,
+        None => OutputFormat::default(),
+    };
+    let encoding = EncodingOptions::new(format, bitrate.as_deref(), sample_rate.as_deref())
+        .map_err(|e| {
+            tracing::warn!(error = %e, "Invalid encoding options");
+            ApiError::invalid(e)
+        })?;
+    let ttl_days = ttl_days
+        .as_deref()
+        .map(crate::cleanup::parse_ttl_days)
+        .transpose()
+        .map_err(ApiError::invalid)?;
+    let tags = parse_tags(&tags).map_err(ApiError::invalid)?;
+    let renditions =
+        crate::renditions::parse_renditions(&renditions, &encoding).map_err(ApiError::invalid)?;
+    if chapter_delimiter.is_some() && !has_m4b(format, &renditions) {
+        return Err(ApiError::invalid(
+            "chapter_delimiter only applies to m4b output",
+        ));
+    }
+    let track = track
+        .as_deref()
+        .map(parse_track)
+        .transpose()
+        .map_err(ApiError::invalid)?;
+
+    let custom_voice = crate::custom_voices::CustomVoice::load(&state.pool, &user.username, &voice)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to load custom voice");
+            ApiError::internal(e.to_string())
+        })?;
+    // With `auto`, each sentence is phonemized in the language it's written in
+    let detect_lang = crate::voices::is_auto(lang.as_deref());
+    // A custom voice speaks its own language unless the request says otherwise
+    let lang = lang
+        .filter(|_| !detect_lang)
+        .or_else(|| custom_voice.as_ref().map(|custom| custom.lang.clone()));
+    let lang = crate::voices::resolve_lang(lang.as_deref(), &voice).map_err(|e| {
+        tracing::warn!(error = %e, "Invalid lang");
+        ApiError::invalid(e)
+    })?;
+
+    // Pronunciations from the user's lexicon change the audio, so the entries that apply
+    // are part of what makes two jobs identical
+    let lexicon = crate::lexicon::Lexicon::load(&state.pool, &user.username)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to load lexicon");
+            ApiError::internal(e.to_string())
+        })?;
+    let lexicon_fingerprint = lexicon.fingerprint(&crate::normalize::normalize_lang(
+        &String::from_utf8_lossy(&text_bytes),
+        lang,
+    ));
+    // Jobs from before lang could be set used the voice's language
+    let lang_override = (lang != crate::voices::default_lang(&voice)).then_some(lang);
+    let pitch_setting = pitch.map(|pitch| pitch.to_string());
+    let loudness_setting = loudness.map(|lufs| lufs.to_string());
+    let renditions: Vec<&str> = renditions.iter().map(|r| r.as_str()).collect();
+    let renditions_setting = (!renditions.is_empty()).then(|| renditions.join(","));
+
+    // An identical earlier request already produced this audio; hand back that job
+    // instead of synthesizing it again (and without counting it against the cap)
+    let hash = content_hash(
+        &text_bytes,
+        &voice,
+        &speed,
+        &encoding,
+        &HashInputs {
+            title: title.as_deref(),
+            tag: tag.as_deref(),
+            input_filename: input_filename.as_deref(),
+            track: track.as_deref(),
+            chapter_delimiter: chapter_delimiter.as_deref(),
+            lexicon: lexicon_fingerprint.as_deref(),
+            lang: lang_override,
+            pitch: pitch_setting.as_deref(),
+            loudness: loudness_setting.as_deref(),
+            renditions: renditions_setting.as_deref(),
+            custom_voice: custom_voice.as_ref().map(|custom| custom.sha256.as_str()),
+            detect_lang,
+        },
+    );
+    if let Some(existing) =
+        find_reusable_job(&state.pool, &user.username, &hash, ttl_days, &tags).await
+    {
+        tracing::info!(job_id = %existing, user = %user.username, "Reusing completed job with identical content");
+        return Ok(JobCreated {
+            id: existing.to_string(),
+            reused: true,
+            warnings: Vec::new(),
+        });
+    }
+
+    crate::usage::check_cap(&state, &user.username).await?;
+    crate::quota::check_storage(&state).await?;
+
+    // Reject unknown voices up front rather than failing inside synthesis. A remote engine
+    // has voices of its own, and custom voices aren't in the model's.
+    if custom_voice.is_none()
+        && state.batch_engine != crate::engine::EngineKind::Http
+        && let Some(model) = state.kokoro_model.read().await.model()
+        && !model.has_voice(&voice)
+    {
+        tracing::warn!(voice = %voice, "Unknown voice requested");
+        return Err(ApiError::new(
             StatusCode::BAD_REQUEST,
-            "Invalid speed parameter".to_string(),
+            "unknown_voice",
+            format!(
+                "Unknown voice '{}'. See GET /voices for available voices.",
+                voice
+            ),
         ));
     }
 
-    tracing::info!(speed = %speed, voice = %voice, text_size_bytes = text_bytes.len(), "Processing TTS request");
+    tracing::info!(speed = %speed, voice = %voice, format = format.as_str(), text_size_bytes = text_bytes.len(), "Processing TTS request");
 
     let job_id = Uuid::new_v4();
+    // Postgres text can't hold NUL bytes
+    let text = String::from_utf8_lossy(&text_bytes).replace('\0', "");
+
+    // The characters are only charged if the job is created
+    let mut tx = state.pool.begin().await.map_err(ApiError::database)?;
+    crate::usage::charge_characters_in(
+        &state,
+        &mut tx,
+        &user.username,
+        text.chars().count() as i64,
+    )
+    .await?;
 
     // Insert into DB with user info
-    tracing::info!(job_id = %job_id, username = %user.username, "Creating job in database");
+    tracing::info!(job_id = %job_id, user = %user.username, "Creating job in database");
     sqlx::query(
-        "INSERT INTO jobs (id, status, username, voice, speed, input_filename) VALUES ($1, 'processing', $2, $3, $4, $5)"
+        "INSERT INTO jobs (id, status, username, voice, speed, input_filename, title, tag, track, chapter_delimiter, format, content_type, bitrate_kbps, sample_rate, content_hash, ttl_days, input_text, max_retries, lang, pitch, tags, loudness_lufs, renditions, request_id, detect_lang) VALUES ($1, 'queued', $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20, $21, $22, $23, $24)"
     )
         .bind(job_id)
         .bind(&user.username)
         .bind(&voice)
         .bind(&speed)
         .bind(&input_filename)
-        .execute(&state.pool)
+        .bind(&title)
+        .bind(&tag)
+        .bind(&track)
+        .bind(&chapter_delimiter)
+        .bind(format.as_str())
+        .bind(format.content_type())
+        .bind(encoding.bitrate_kbps.map(|v| v as i32))
+        .bind(encoding.sample_rate.map(|v| v as i32))
+        .bind(&hash)
+        .bind(ttl_days)
+        .bind(&text)
+        .bind(state.retry_policy.max_retries as i32)
+        .bind(lang)
+        .bind(pitch)
+        .bind(&tags)
+        .bind(loudness)
+        .bind(&renditions)
+        .bind(&request_id.0)
+        .bind(detect_lang)
+        .execute(&mut *tx)
         .await
         .map_err(|e| {
             tracing::error!(job_id = %job_id, error = %e, "Failed to insert job into database");
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            ApiError::internal(e.to_string())
         })?;
+    tx.commit().await.map_err(ApiError::database)?;
+
+    // Picked up by the worker pool once a worker is free
+    state.job_queue.notify();
 
+    Ok(JobCreated {
+        id: job_id.to_string(),
+        reused: false,
+        warnings: Vec::new(),
+    })
+}
+
+/// Everything needed to synthesize and encode a job in the background
+pub struct JobSpec {
+    id: Uuid,
+    username: String,
+    text: String,
+    speed: String,
+    voice: String,
+    /// espeak-ng language of the text
+    lang: String,
+    /// Whether sentences detected as another language are phonemized as that one
+    detect_lang: bool,
+    /// Pitch shift in semitones
+    pitch: Option<f32>,
+    /// Target loudness in LUFS
+    loudness: Option<f32>,
+    tags: Id3Tags,
+    /// Outputs besides the main one
+    renditions: Vec<Rendition>,
+    encoding: EncodingOptions,
+    chapter_delimiter: Option<String>,
+    output_path: std::path::PathBuf,
+    /// ID of the request that created the job
+    request_id: Option<String>,
+}
+
+impl JobSpec {
+    /// Span for the job's logs, naming the job, its user and the request that created it
+    pub fn span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "job",
+            job_id = %self.id,
+            user = %self.username,
+            request_id = self.request_id.as_deref(),
+        )
+    }
+}
+
+/// Tags for a job's output file
+fn id3_tags(
+    job_id: Uuid,
+    title: Option<String>,
+    input_filename: Option<&str>,
+    voice: &str,
+    tag: Option<String>,
+    track: Option<String>,
+) -> Id3Tags {
+    Id3Tags {
+        title: title
+            .or_else(|| input_filename.map(filename_stem))
+            .unwrap_or_else(|| job_id.to_string()),
+        artist: voice.to_string(),
+        album: tag,
+        track,
+        comment: job_id.to_string(),
+    }
+}
+
+/// Rebuild a recorded job's spec from the database, e.g. to synthesize it again.
+/// Fails for jobs created before input text was stored.
+pub async fn load_job_spec(state: &AppState, id: Uuid) -> Result<JobSpec, String> {
+    let row = sqlx::query(
+        "SELECT username, voice, lang, speed, pitch, loudness_lufs, input_filename, title, tag, track, chapter_delimiter, format, bitrate_kbps, sample_rate, renditions, input_text, created_at, request_id, detect_lang FROM jobs WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| format!("Failed to load job: {}", e))?
+    .ok_or("Job not found")?;
+
+    let text: String = row
+        .get::<Option<String>, _>("input_text")
+        .ok_or("Input text was not stored for this job")?;
+    let username: String = row.get::<Option<String>, _>("username").unwrap_or_default();
+    let voice: String = row
+        .get::<Option<String>, _>("voice")
+        .unwrap_or_else(|| "af_heart".to_string());
+    let lang: String = row
+        .get::<Option<String>, _>("lang")
+        .unwrap_or_else(|| crate::voices::default_lang(&voice).to_string());
+    let format = OutputFormat::parse(row.get("format"))?;
+    let encoding = EncodingOptions {
+        format,
+        bitrate_kbps: row.get::<Option<i32>, _>("bitrate_kbps").map(|v| v as u32),
+        sample_rate: row.get::<Option<i32>, _>("sample_rate").map(|v| v as u32),
+    };
+    let renditions = row
+        .get::<Vec<String>, _>("renditions")
+        .iter()
+        .map(|rendition| Rendition::parse(rendition))
+        .collect::<Result<_, _>>()?;
+    let input_filename: Option<String> = row.get("input_filename");
+    let created_at: DateTime<Utc> = row.get("created_at");
+
+    Ok(JobSpec {
+        id,
+        tags: id3_tags(
+            id,
+            row.get("title"),
+            input_filename.as_deref(),
+            &voice,
+            row.get("tag"),
+            row.get("track"),
+        ),
+        renditions,
+        output_path: state.storage_layout.path_for(
+            &state.storage_path,
+            &username,
+            id,
+            created_at,
+            format.extension(),
+        ),
+        username,
+        text,
+        speed: row
+            .get::<Option<String>, _>("speed")
+            .unwrap_or_else(|| "1.0".to_string()),
+        pitch: row.get("pitch"),
+        loudness: row.get("loudness_lufs"),
+        voice,
+        lang,
+        detect_lang: row.get("detect_lang"),
+        encoding,
+        chapter_delimiter: row.get("chapter_delimiter"),
+        request_id: row.get("request_id"),
+    })
+}
+
+/// Synthesize and encode a job on a blocking thread, recording the outcome in its row.
+/// Returns once the job has finished.
+pub async fn run_job(state: &AppState, spec: JobSpec) {
+    let JobSpec {
+        id: job_id,
+        username,
+        text,
+        speed,
+        pitch,
+        loudness,
+        voice,
+        lang,
+        detect_lang,
+        tags,
+        renditions,
+        encoding,
+        chapter_delimiter,
+        output_path,
+        request_id: _,
+    } = spec;
+
+    let lexicon = crate::lexicon::Lexicon::load(&state.pool, &username)
+        .await
+        .map_err(|e| JobError::Retryable(format!("Failed to load lexicon: {}", e)));
+    let engine = match crate::custom_voices::CustomVoice::load(&state.pool, &username, &voice).await
+    {
+        Ok(custom_voice) => crate::engine::batch_engine(state, job_id, &voice, custom_voice).await,
+        Err(e) => Err(JobError::Retryable(format!(
+            "Failed to load custom voice: {}",
+            e
+        ))),
+    };
+    let (lexicon, engine) = match (lexicon, engine) {
+        (Ok(lexicon), Ok(engine)) => (lexicon, engine),
+        (Err(error), _) | (_, Err(error)) => {
+            tracing::error!(job_id = %job_id, error = ?error, "TTS processing failed");
+            if let Err(e) =
+                crate::queue::retry_or_fail(&state.pool, &state.retry_policy, job_id, &error).await
+            {
+                tracing::error!(job_id = %job_id, error = %e, "Failed to record job failure");
+            }
+            return;
+        }
+    };
+
+    let (published, _) = tokio::sync::broadcast::channel(crate::state::JOB_STREAM_CAPACITY);
+    state.active_jobs.write().await.insert(
+        job_id,
+        ActiveJob {
+            username: username.clone(),
+            chunks: published.clone(),
+        },
+    );
+
+    let phonemizer = std::sync::Arc::clone(&state.phonemizer);
     let pool = state.pool.clone();
-    let storage_path = state.storage_path.clone();
+    let scratch_path = state.scratch_path.clone();
+    let mock_synthesis = state.mock_synthesis;
+    let crossfade_ms = state.crossfade_ms;
+    let silence_trim = state.silence_trim;
+    let sentence_gap_ms = state.sentence_gap_ms;
 
-    tokio::task::spawn_blocking(move || {
+    let outcome = tokio::task::spawn_blocking(move || {
         let rt = tokio::runtime::Handle::current();
         tracing::info!(job_id = %job_id, "Starting TTS processing in background");
-        if let Err(e) = process_tts(
-            pool.clone(),
+        process_tts(
+            pool,
             job_id,
-            text_bytes,
+            &username,
+            &text,
             speed,
+            pitch,
+            loudness,
             voice,
-            storage_path,
+            lang,
+            detect_lang,
+            tags,
+            &renditions,
+            encoding,
+            chapter_delimiter,
+            &lexicon,
+            output_path,
+            scratch_path,
+            crossfade_ms,
+            sentence_gap_ms,
+            silence_trim,
+            mock_synthesis,
+            engine,
+            phonemizer,
+            &published,
             &rt,
-        ) {
-            tracing::error!(job_id = %job_id, error = %e, "TTS processing failed");
-            rt.block_on(async {
-                let _ = sqlx::query(
-                    "UPDATE jobs SET status = 'error', error_message = $1 WHERE id = $2",
-                )
-                .bind(&e)
-                .bind(job_id)
-                .execute(&pool)
-                .await;
-            });
-        } else {
+        )
+    })
+    .await;
+    state.active_jobs.write().await.remove(&job_id);
+
+    let error = match outcome {
+        Ok(Ok(())) => {
             tracing::info!(job_id = %job_id, "TTS processing completed successfully");
+            return;
         }
-    });
+        Ok(Err(e)) => e,
+        // A panic mid-job would otherwise leave it processing forever
+        Err(e) => {
+            tracing::error!(job_id = %job_id, error = %e, "TTS processing panicked");
+            JobError::Permanent("Processing failed unexpectedly".to_string())
+        }
+    };
+    tracing::error!(job_id = %job_id, error = ?error, "TTS processing failed");
+    if let Err(e) =
+        crate::queue::retry_or_fail(&state.pool, &state.retry_policy, job_id, &error).await
+    {
+        tracing::error!(job_id = %job_id, error = %e, "Failed to record job failure");
+    }
+}
 
-    Ok(Json(serde_json::json!({ "id": job_id.to_string() })))
+/// What besides the text, voice, speed and encoding determines a job's output file, for
+/// [`content_hash`]
+#[derive(Debug, Default)]
+struct HashInputs<'a> {
+    title: Option<&'a str>,
+    /// The album tag
+    tag: Option<&'a str>,
+    input_filename: Option<&'a str>,
+    track: Option<&'a str>,
+    chapter_delimiter: Option<&'a str>,
+    /// Fingerprint of the lexicon entries that apply to the text
+    lexicon: Option<&'a str>,
+    /// The language, if it isn't the voice's own
+    lang: Option<&'a str>,
+    pitch: Option<&'a str>,
+    loudness: Option<&'a str>,
+    renditions: Option<&'a str>,
+    /// Checksum of the custom voice's embedding
+    custom_voice: Option<&'a str>,
+    /// Whether each sentence's language is detected
+    detect_lang: bool,
 }
 
-fn process_tts(
-    pool: Pool<Postgres>,
-    job_id: Uuid,
-    text_bytes: axum::body::Bytes,
-    speed: String,
-    voice: String,
-    storage_path: String,
-    rt: &tokio::runtime::Handle,
-) -> Result<(), String> {
-    tracing::info!(job_id = %job_id, "Starting TTS processing");
-
-    // 1. Write text content to a temp file
-    tracing::debug!(job_id = %job_id, "Creating temporary text file");
-    let mut text_file = Builder::new()
-        .suffix(".txt")
-        .tempfile()
-        .map_err(|e| format!("Failed to create temp text file: {}", e))?;
-    text_file
-        .write_all(&text_bytes)
-        .map_err(|e| format!("Failed to write text file: {}", e))?;
-    // Flush to ensure all data is written to disk before external process reads it
-    text_file
-        .flush()
-        .map_err(|e| format!("Failed to flush text file: {}", e))?;
-    let text_path = text_file.path().to_str().ok_or("Invalid path")?.to_string();
-    tracing::debug!(job_id = %job_id, text_path = %text_path, "Text file created");
-
-    let output_dir = std::path::Path::new(&storage_path);
-    // wav intermediate in temp, mp3 result in persistent storage
-    let wav_path = format!("/tmp/{}.wav", job_id);
-    let mp3_filename = format!("{}.mp3", job_id);
-    let mp3_path = output_dir.join(&mp3_filename);
-
-    // Check if we're in test mode (skip actual TTS, generate dummy audio)
-    let test_mode = std::env::var("TTS_TEST_MODE").is_ok();
-
-    if test_mode {
-        // Generate a minimal valid WAV file for testing
-        // WAV header (44 bytes) + 1 second of silence at 22050 Hz, 16-bit mono
-        let sample_rate: u32 = 22050;
-        let bits_per_sample: u16 = 16;
-        let num_channels: u16 = 1;
-        let duration_secs: u32 = 1;
-        let data_size =
-            sample_rate * duration_secs * (bits_per_sample as u32 / 8) * num_channels as u32;
-        let file_size = 36 + data_size;
-
-        let mut wav_data = Vec::with_capacity(44 + data_size as usize);
-        // RIFF header
-        wav_data.extend_from_slice(b"RIFF");
-        wav_data.extend_from_slice(&file_size.to_le_bytes());
-        wav_data.extend_from_slice(b"WAVE");
-        // fmt subchunk
-        wav_data.extend_from_slice(b"fmt ");
-        wav_data.extend_from_slice(&16u32.to_le_bytes()); // subchunk size
-        wav_data.extend_from_slice(&1u16.to_le_bytes()); // audio format (PCM)
-        wav_data.extend_from_slice(&num_channels.to_le_bytes());
-        wav_data.extend_from_slice(&sample_rate.to_le_bytes());
-        wav_data.extend_from_slice(
-            &(sample_rate * num_channels as u32 * bits_per_sample as u32 / 8).to_le_bytes(),
-        ); // byte rate
-        wav_data.extend_from_slice(&(num_channels * bits_per_sample / 8).to_le_bytes()); // block align
-        wav_data.extend_from_slice(&bits_per_sample.to_le_bytes());
-        // data subchunk
-        wav_data.extend_from_slice(b"data");
-        wav_data.extend_from_slice(&data_size.to_le_bytes());
-        // Silent audio data (zeros)
-        wav_data.extend(std::iter::repeat(0u8).take(data_size as usize));
-
-        std::fs::write(&wav_path, wav_data)
-            .map_err(|e| format!("Failed to write test WAV file: {}", e))?;
-
-        tracing::info!(job_id = %job_id, wav_path = %wav_path, "Test mode: Generated dummy WAV file");
-    } else {
-        // Production mode: run actual kokoro-tts
-        tracing::info!(
-            job_id = %job_id,
-            text_path = %text_path,
-            wav_path = %wav_path,
-            voice = %voice,
-            speed = %speed,
-            "Executing kokoro-tts"
-        );
-        let mut child = Command::new("kokoro-tts")
-            .current_dir("/app") // Model files are in /app
-            .arg(&text_path)
-            .arg(&wav_path)
-            .arg("--voice")
-            .arg(&voice)
-            .arg("--speed")
-            .arg(&speed)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(|e| format!("Failed to spawn kokoro-tts: {}", e))?;
-
-        // Stream stdout and stderr in separate threads so we get real-time logs
-        // even if the process is OOM-killed
-        let stdout = child.stdout.take();
-        let stderr = child.stderr.take();
-        let job_id_str = job_id.to_string();
-
-        let stdout_job_id = job_id_str.clone();
-        let stdout_handle = std::thread::spawn(move || {
-            use std::io::BufRead;
-            let mut collected = String::new();
-            if let Some(out) = stdout {
-                for line in std::io::BufReader::new(out).lines() {
-                    match line {
-                        Ok(l) => {
-                            tracing::info!(job_id = %stdout_job_id, line = %l, "kokoro-tts stdout");
-                            collected.push_str(&l);
-                            collected.push('\n');
-                        }
-                        Err(e) => {
-                            tracing::warn!(job_id = %stdout_job_id, error = %e, "Error reading kokoro-tts stdout");
-                            break;
-                        }
-                    }
-                }
-            }
-            collected
-        });
+/// SHA-256 (hex) over everything that determines a job's output file. Fields are
+/// length-prefixed so adjacent values can't run together.
+fn content_hash(
+    text: &[u8],
+    voice: &str,
+    speed: &str,
+    encoding: &EncodingOptions,
+    inputs: &HashInputs,
+) -> String {
+    use sha2::{Digest, Sha256};
 
-        let stderr_job_id = job_id_str.clone();
-        let stderr_handle = std::thread::spawn(move || {
-            use std::io::BufRead;
-            let mut collected = String::new();
-            if let Some(err) = stderr {
-                for line in std::io::BufReader::new(err).lines() {
-                    match line {
-                        Ok(l) => {
-                            tracing::warn!(job_id = %stderr_job_id, line = %l, "kokoro-tts stderr");
-                            collected.push_str(&l);
-                            collected.push('\n');
-                        }
-                        Err(e) => {
-                            tracing::warn!(job_id = %stderr_job_id, error = %e, "Error reading kokoro-tts stderr");
-                            break;
-                        }
-                    }
-                }
-            }
-            collected
-        });
+    // "1", "1.0" and "1.00" all synthesize the same audio
+    let speed = speed
+        .parse::<f32>()
+        .map_or_else(|_| speed.to_string(), |s| s.to_string());
+    let bitrate = encoding.bitrate_kbps.map(|v| v.to_string());
+    let sample_rate = encoding.sample_rate.map(|v| v.to_string());
 
-        let status = child
-            .wait()
-            .map_err(|e| format!("Failed to wait on kokoro-tts: {}", e))?;
-        let stdout_output = stdout_handle.join().unwrap_or_default();
-        let stderr_output = stderr_handle.join().unwrap_or_default();
+    let mut hasher = Sha256::new();
+    let fields: [Option<&[u8]>; 9] = [
+        Some(text),
+        Some(voice.as_bytes()),
+        Some(speed.as_bytes()),
+        Some(encoding.format.as_str().as_bytes()),
+        bitrate.as_deref().map(str::as_bytes),
+        sample_rate.as_deref().map(str::as_bytes),
+        inputs.title.map(str::as_bytes),
+        inputs.tag.map(str::as_bytes),
+        inputs.input_filename.map(str::as_bytes),
+    ];
+    for field in fields {
+        match field {
+            Some(bytes) => {
+                hasher.update([1]);
+                hasher.update((bytes.len() as u64).to_le_bytes());
+                hasher.update(bytes);
+            }
+            None => hasher.update([0]),
+        }
+    }
+    // Fields added later are only hashed when set, each with its own marker, so jobs
+    // from before they existed still match. New fields go at the end.
+    for (index, field) in [
+        inputs.track,
+        inputs.chapter_delimiter,
+        inputs.lexicon,
+        inputs.lang,
+        inputs.pitch,
+        inputs.loudness,
+        inputs.renditions,
+        inputs.custom_voice,
+        inputs.detect_lang.then_some("detect"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        if let Some(value) = field {
+            hasher.update([index as u8 + 1]);
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        }
+    }
+    hex::encode(hasher.finalize())
+}
 
-        let exit_code = status.code();
-        tracing::info!(job_id = %job_id, exit_code = ?exit_code, "kokoro-tts process exited");
+/// Most labels a job can have
+const MAX_TAGS: usize = 20;
+/// Longest label, in characters
+const MAX_TAG_CHARS: usize = 64;
 
-        if !status.success() {
-            tracing::error!(
-                job_id = %job_id,
-                exit_code = ?exit_code,
-                "kokoro-tts failed"
-            );
-            return Err(format!(
-                "kokoro-tts failed (exit code {:?}): stdout={}, stderr={}",
-                exit_code, stdout_output, stderr_output
-            ));
+/// Split comma-separated labels, dropping blanks and repeats
+fn parse_tags(values: &[String]) -> Result<Vec<String>, String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in values
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+    {
+        if tag.is_empty() || tags.iter().any(|t| t == tag) {
+            continue;
         }
-        tracing::info!(job_id = %job_id, "kokoro-tts completed successfully");
-
-        // Verify the WAV file was actually created
-        if !std::path::Path::new(&wav_path).exists() {
-            tracing::error!(job_id = %job_id, wav_path = %wav_path, "kokoro-tts did not produce output file");
-            return Err(format!(
-                "kokoro-tts did not produce output file. stdout: {}",
-                stdout_output
-            ));
+        if tag.chars().count() > MAX_TAG_CHARS {
+            return Err(format!("Tags can be at most {} characters", MAX_TAG_CHARS));
         }
+        tags.push(tag.to_string());
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(format!("A job can have at most {} tags", MAX_TAGS));
     }
+    Ok(tags)
+}
 
-    let mp3_path_str = mp3_path.to_str().ok_or("Invalid MP3 path")?;
-    tracing::info!(job_id = %job_id, wav_path = %wav_path, mp3_path = %mp3_path_str, "Executing ffmpeg to convert WAV to MP3");
-    let ffmpeg_output = Command::new("ffmpeg")
-        .arg("-i")
-        .arg(&wav_path)
-        .arg("-b:a")
-        .arg("192k")
-        .arg("-y")
-        .arg(mp3_path_str)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .map_err(|e| format!("Failed to execute ffmpeg: {}", e))?;
+/// A completed job of `username`'s with this content hash whose file is still on disk.
+/// A `ttl_days` given with the new request replaces the reused job's, and its `tags` are
+/// added to the reused job's.
+async fn find_reusable_job(
+    pool: &Pool<Postgres>,
+    username: &str,
+    hash: &str,
+    ttl_days: Option<i32>,
+    tags: &[String],
+) -> Option<Uuid> {
+    let row = sqlx::query(
+        "SELECT id, file_path FROM jobs WHERE username = $1 AND content_hash = $2 AND status = 'completed' AND file_path IS NOT NULL ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(username)
+    .bind(hash)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| tracing::warn!(error = %e, "Failed to look up job by content hash"))
+    .ok()??;
 
-    if !ffmpeg_output.status.success() {
-        let stderr = String::from_utf8_lossy(&ffmpeg_output.stderr);
-        let stdout = String::from_utf8_lossy(&ffmpeg_output.stdout);
-        let exit_code = ffmpeg_output.status.code();
-        tracing::error!(
-            job_id = %job_id,
-            exit_code = ?exit_code,
-            stdout = %stdout,
-            stderr = %stderr,
-            "ffmpeg failed"
-        );
-        return Err(format!(
-            "ffmpeg failed (exit code {:?}): {}",
-            exit_code, stderr
-        ));
+    let id: Uuid = row.get("id");
+    let file_path: String = row.get("file_path");
+    if !tokio::fs::try_exists(&file_path).await.unwrap_or(false) {
+        tracing::info!(job_id = %id, file_path = %file_path, "Matching job's file is gone, synthesizing again");
+        return None;
     }
-    tracing::info!(
-        job_id = %job_id,
-        stderr = %String::from_utf8_lossy(&ffmpeg_output.stderr),
-        "ffmpeg conversion completed successfully"
-    );
 
-    // Cleanup wav file
-    let _ = std::fs::remove_file(wav_path);
+    // Counts as an access, so cleanup keeps the reused file around
+    let _ = sqlx::query(
+        "UPDATE jobs SET last_accessed_at = NOW(), ttl_days = COALESCE($2, ttl_days), tags = tags || ARRAY(SELECT UNNEST($3::TEXT[]) EXCEPT SELECT UNNEST(tags)) WHERE id = $1",
+    )
+    .bind(id)
+    .bind(ttl_days)
+    .bind(tags)
+    .execute(pool)
+    .await;
+    Some(id)
+}
 
-    // Get duration using ffprobe
-    let duration_secs: Option<f64> = Command::new("ffprobe")
-        .arg("-v")
-        .arg("error")
-        .arg("-show_entries")
-        .arg("format=duration")
-        .arg("-of")
-        .arg("default=noprint_wrappers=1:nokey=1")
-        .arg(mp3_path_str)
-        .output()
-        .ok()
-        .and_then(|out| {
-            String::from_utf8_lossy(&out.stdout)
-                .trim()
-                .parse::<f64>()
-                .ok()
-        });
-    tracing::info!(job_id = %job_id, duration_secs = ?duration_secs, "Got audio duration");
+/// Tags written into the output file so downloads are identifiable in music players.
+/// MP3s get ID3 tags; Ogg and FLAC files get the same fields as Vorbis comments.
+struct Id3Tags {
+    title: String,
+    /// Voice used for synthesis
+    artist: String,
+    /// Optional user-supplied tag, e.g. a book or series name
+    album: Option<String>,
+    /// Position within the album, e.g. "3" or "3/12"
+    track: Option<String>,
+    /// Job ID, so a file can be traced back to its job
+    comment: String,
+}
 
-    // Get output file size
-    let output_file_size: Option<i64> = std::fs::metadata(mp3_path_str)
-        .ok()
-        .map(|m| m.len() as i64);
-    tracing::info!(job_id = %job_id, output_file_size = ?output_file_size, "Got output file size");
+impl Id3Tags {
+    /// The tags that are set, by ffmpeg's name for them
+    fn fields(&self) -> Vec<(&'static str, &str)> {
+        let mut fields = vec![
+            ("title", self.title.as_str()),
+            ("artist", self.artist.as_str()),
+            ("comment", self.comment.as_str()),
+        ];
+        if let Some(album) = &self.album {
+            fields.push(("album", album));
+        }
+        if let Some(track) = &self.track {
+            fields.push(("track", track));
+        }
+        fields
+    }
 
-    // Update DB
-    rt.block_on(async {
+    /// ffmpeg `-metadata` arguments for these tags
+    fn ffmpeg_args(&self, format: OutputFormat) -> Vec<String> {
+        let mut args = Vec::new();
+        for (key, value) in self.fields() {
+            args.push("-metadata".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+        if format == OutputFormat::Mp3 {
+            // ID3v2.3 is the most widely supported by players
+            args.push("-id3v2_version".to_string());
+            args.push("3".to_string());
+        }
+        args
+    }
+}
+
+/// Validate a track number: `N` or `N/TOTAL`, with `1 <= N <= TOTAL`
+fn parse_track(value: &str) -> Result<String, String> {
+    let invalid = || {
+        format!(
+            "Invalid track '{}': expected a number like 3 or 3/12",
+            value
+        )
+    };
+    let (number, total) = match value.split_once('/') {
+        Some((number, total)) => (number, Some(total)),
+        None => (value, None),
+    };
+    let number: u32 = number.trim().parse().map_err(|_| invalid())?;
+    let total: Option<u32> = total
+        .map(|total| total.trim().parse())
+        .transpose()
+        .map_err(|_| invalid())?;
+    if number == 0 || total.is_some_and(|total| total < number) {
+        return Err(invalid());
+    }
+    Ok(match total {
+        Some(total) => format!("{}/{}", number, total),
+        None => number.to_string(),
+    })
+}
+
+/// Strip directory and extension from an uploaded filename, e.g. "notes/ch1.txt" -> "ch1"
+fn filename_stem(filename: &str) -> String {
+    std::path::Path::new(filename)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| filename.to_string())
+}
+
+/// SHA-256 (hex) of a file's contents
+fn file_sha256(path: &str) -> std::io::Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Create a per-job scratch directory under `scratch_path`
+fn create_scratch_dir(scratch_path: &str, job_id: Uuid) -> Result<TempDir, String> {
+    Builder::new()
+        .prefix(&format!(
+            "{}{}-",
+            crate::cleanup::SCRATCH_DIR_PREFIX,
+            job_id
+        ))
+        .tempdir_in(scratch_path)
+        .map_err(|e| format!("Failed to create scratch directory: {}", e))
+}
+
+/// Percentage of a job's chunks that have been synthesized, to one decimal place
+fn progress_percent(chunks_done: i32, chunks_total: Option<i32>) -> Option<f64> {
+    let total = chunks_total.filter(|total| *total > 0)?;
+    Some((chunks_done.min(total) as f64 * 1000.0 / total as f64).round() / 10.0)
+}
+
+/// Longest chunk of text handed to kokoro-tts in one run. Each run reloads the model,
+/// so chunks are kept large; the job's progress advances once per chunk.
+const MAX_CHUNK_CHARS: usize = 4000;
+
+/// Group sentences into chunks of at most `max_chars` characters. Sentences longer than
+/// `max_chars`, such as text without punctuation, are broken up with [`split_long_sentence`].
+fn chunk_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let sentences = crate::phonemizer::split_sentences(text)
+        .into_iter()
+        .flat_map(|sentence| split_long_sentence(&sentence, max_chars));
+    for sentence in sentences {
+        if !current.is_empty() && current.chars().count() + 1 + sentence.chars().count() > max_chars
+        {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(&sentence);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Split a sentence into pieces of at most `max_chars` characters, each ending at the
+/// last clause break (`,`, `;`, `:` or a dash) that fits, or failing that the last space
+fn split_long_sentence(sentence: &str, max_chars: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut rest = sentence.trim();
+    while rest.chars().count() > max_chars {
+        // Byte offset just past the longest prefix that fits
+        let limit = rest
+            .char_indices()
+            .nth(max_chars)
+            .map_or(rest.len(), |(offset, _)| offset);
+        let window = &rest[..limit];
+        let end = window
+            .rfind([',', ';', ':', '—', '–'])
+            .map(|offset| offset + window[offset..].chars().next().map_or(1, char::len_utf8))
+            .or_else(|| window.rfind(char::is_whitespace))
+            .filter(|&end| end > 0)
+            .unwrap_or(limit);
+        pieces.push(rest[..end].trim().to_string());
+        rest = rest[end..].trim_start();
+    }
+    if !rest.is_empty() {
+        pieces.push(rest.to_string());
+    }
+    pieces
+}
+
+/// Record how many chunks of a job have been synthesized
+fn set_progress(
+    pool: &Pool<Postgres>,
+    job_id: Uuid,
+    chunks_done: usize,
+    chunks_total: usize,
+    rt: &tokio::runtime::Handle,
+) {
+    let result = rt.block_on(
+        sqlx::query("UPDATE jobs SET chunks_done = $1, chunks_total = $2 WHERE id = $3")
+            .bind(chunks_done as i32)
+            .bind(chunks_total as i32)
+            .bind(job_id)
+            .execute(pool),
+    );
+    if let Err(e) = result {
+        tracing::warn!(job_id = %job_id, error = %e, "Failed to record job progress");
+    }
+}
+
+/// Why processing a job failed
+#[derive(Debug, PartialEq)]
+pub enum JobError {
+    /// Caused by the environment rather than the job, e.g. running out of memory or disk
+    /// space; the job is queued again until its retries run out
+    Retryable(String),
+    /// Retrying would fail the same way
+    Permanent(String),
+}
+
+impl JobError {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Retryable(message) | Self::Permanent(message) => message,
+        }
+    }
+}
+
+impl From<&str> for JobError {
+    fn from(message: &str) -> Self {
+        Self::Permanent(message.to_string())
+    }
+}
+
+/// Whether a failed kokoro-tts or ffmpeg run is worth retrying: it was killed by a signal
+/// (usually the OOM killer) or ran out of memory or disk space
+fn is_transient_failure(exit_code: Option<i32>, stderr: &str) -> bool {
+    const TRANSIENT_ERRORS: &[&str] = &[
+        "No space left on device",
+        "Cannot allocate memory",
+        "MemoryError",
+        "Resource temporarily unavailable",
+    ];
+    exit_code.is_none() || TRANSIENT_ERRORS.iter().any(|e| stderr.contains(e))
+}
+
+/// A missing or non-executable binary won't fix itself; anything else (e.g. no memory
+/// to fork) might
+fn spawn_error(program: &str, e: std::io::Error) -> JobError {
+    let message = format!("Failed to run {}: {}", program, e);
+    match e.kind() {
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied => {
+            JobError::Permanent(message)
+        }
+        _ => JobError::Retryable(message),
+    }
+}
+
+/// Synthesize one chunk with `engine`, a sentence at a time since the model takes a limited
+/// number of phonemes. With `detect_lang`, sentences detected as
+/// another language than `lang` are phonemized as that one. Each sentence is trimmed with
+/// `silence_trim`, if given, and joined to the next with `joiner`.
+#[allow(clippy::too_many_arguments)]
+fn synthesize_chunk(
+    engine: &dyn crate::engine::SynthesisEngine,
+    phonemizer: &crate::phonemizer::Phonemizer,
+    chunk: &str,
+    voice: &str,
+    lang: &str,
+    detect_lang: bool,
+    speed: f32,
+    lexicon: &crate::lexicon::Lexicon,
+    silence_trim: Option<crate::silence::SilenceTrim>,
+    mut joiner: crate::join::SentenceJoiner,
+) -> Result<Vec<f32>, JobError> {
+    let mut chunk_audio = Vec::new();
+    let sentences = crate::phonemizer::split_sentences(chunk);
+    for (index, sentence) in sentences.iter().enumerate() {
+        let lang = if detect_lang {
+            crate::voices::sentence_lang(sentence, lang)
+        } else {
+            lang
+        };
+        let (text, phonemes) = if engine.needs_phonemes() {
+            let phonemes = phonemizer
+                .phonemize_with_lexicon(sentence, lang, lexicon)
+                .map_err(|e| JobError::Permanent(format!("Phonemization failed: {}", e)))?;
+            (sentence.clone(), phonemes)
+        } else {
+            (lexicon.respell(sentence), String::new())
+        };
+        if text.trim().is_empty() || (engine.needs_phonemes() && phonemes.is_empty()) {
+            continue;
+        }
+        let mut audio = engine
+            .synthesize(&crate::engine::Utterance {
+                text: &text,
+                phonemes: &phonemes,
+                voice,
+                lang,
+                speed,
+                quality: None,
+                cancel: None,
+            })?
+            .audio;
+        if let Some(trim) = silence_trim {
+            trim.apply(&mut audio, 1, crate::inference::SAMPLE_RATE);
+        }
+        let (audio, _) = joiner.push(audio, 0, index + 1 == sentences.len());
+        chunk_audio.extend(audio);
+    }
+    chunk_audio.extend(joiner.finish());
+    Ok(chunk_audio)
+}
+
+/// Synthesize one text file to WAV with the kokoro-tts CLI, for `job_id` if it's a job's
+pub fn run_kokoro_tts(
+    job_id: Option<Uuid>,
+    text_path: &str,
+    wav_path: &str,
+    voice: &str,
+    lang: &str,
+    speed: &str,
+) -> Result<(), JobError> {
+    let job_id = job_id.map(|id| id.to_string());
+    tracing::info!(
+        job_id = job_id.as_deref(),
+        text_path = %text_path,
+        wav_path = %wav_path,
+        voice = %voice,
+        lang = %lang,
+        speed = %speed,
+        "Executing kokoro-tts"
+    );
+    let mut child = Command::new("kokoro-tts")
+        .current_dir("/app") // Model files are in /app
+        .arg(text_path)
+        .arg(wav_path)
+        .arg("--voice")
+        .arg(voice)
+        .arg("--lang")
+        .arg(lang)
+        .arg("--speed")
+        .arg(speed)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_error("kokoro-tts", e))?;
+
+    // Stream stdout and stderr in separate threads so we get real-time logs
+    // even if the process is OOM-killed
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let stdout_job_id = job_id.clone();
+    let stdout_handle = std::thread::spawn(move || {
+        use std::io::BufRead;
+        let mut collected = String::new();
+        if let Some(out) = stdout {
+            for line in std::io::BufReader::new(out).lines() {
+                match line {
+                    Ok(l) => {
+                        tracing::info!(job_id = stdout_job_id.as_deref(), line = %l, "kokoro-tts stdout");
+                        collected.push_str(&l);
+                        collected.push('\n');
+                    }
+                    Err(e) => {
+                        tracing::warn!(job_id = stdout_job_id.as_deref(), error = %e, "Error reading kokoro-tts stdout");
+                        break;
+                    }
+                }
+            }
+        }
+        collected
+    });
+
+    let stderr_job_id = job_id.clone();
+    let stderr_handle = std::thread::spawn(move || {
+        use std::io::BufRead;
+        let mut collected = String::new();
+        if let Some(err) = stderr {
+            for line in std::io::BufReader::new(err).lines() {
+                match line {
+                    Ok(l) => {
+                        tracing::warn!(job_id = stderr_job_id.as_deref(), line = %l, "kokoro-tts stderr");
+                        collected.push_str(&l);
+                        collected.push('\n');
+                    }
+                    Err(e) => {
+                        tracing::warn!(job_id = stderr_job_id.as_deref(), error = %e, "Error reading kokoro-tts stderr");
+                        break;
+                    }
+                }
+            }
+        }
+        collected
+    });
+
+    let status = child
+        .wait()
+        .map_err(|e| JobError::Retryable(format!("Failed to wait on kokoro-tts: {}", e)))?;
+    let stdout_output = stdout_handle.join().unwrap_or_default();
+    let stderr_output = stderr_handle.join().unwrap_or_default();
+
+    let exit_code = status.code();
+    tracing::info!(job_id = job_id.as_deref(), exit_code = ?exit_code, "kokoro-tts process exited");
+
+    if !status.success() {
+        tracing::error!(
+            job_id = job_id.as_deref(),
+            exit_code = ?exit_code,
+            "kokoro-tts failed"
+        );
+        let message = format!(
+            "kokoro-tts failed (exit code {:?}): stdout={}, stderr={}",
+            exit_code, stdout_output, stderr_output
+        );
+        return Err(if is_transient_failure(exit_code, &stderr_output) {
+            JobError::Retryable(message)
+        } else {
+            JobError::Permanent(message)
+        });
+    }
+    tracing::info!(
+        job_id = job_id.as_deref(),
+        "kokoro-tts completed successfully"
+    );
+
+    // Verify the WAV file was actually created
+    if !std::path::Path::new(wav_path).exists() {
+        tracing::error!(job_id = job_id.as_deref(), wav_path = %wav_path, "kokoro-tts did not produce output file");
+        return Err(JobError::Permanent(format!(
+            "kokoro-tts did not produce output file. stdout: {}",
+            stdout_output
+        )));
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_tts(
+    pool: Pool<Postgres>,
+    job_id: Uuid,
+    username: &str,
+    text: &str,
+    speed: String,
+    pitch: Option<f32>,
+    loudness: Option<f32>,
+    voice: String,
+    lang: String,
+    detect_lang: bool,
+    tags: Id3Tags,
+    renditions: &[Rendition],
+    encoding: EncodingOptions,
+    chapter_delimiter: Option<String>,
+    lexicon: &crate::lexicon::Lexicon,
+    output_path: std::path::PathBuf,
+    scratch_path: String,
+    crossfade_ms: u32,
+    sentence_gap_ms: u32,
+    silence_trim: Option<crate::silence::SilenceTrim>,
+    mock_synthesis: bool,
+    engine: std::sync::Arc<dyn crate::engine::SynthesisEngine>,
+    phonemizer: std::sync::Arc<crate::phonemizer::Phonemizer>,
+    published: &tokio::sync::broadcast::Sender<crate::state::JobChunk>,
+    rt: &tokio::runtime::Handle,
+) -> Result<(), JobError> {
+    tracing::info!(job_id = %job_id, engine = engine.name(), "Starting TTS processing");
+    let speed: f32 = speed
+        .parse()
+        .map_err(|_| JobError::Permanent(format!("Invalid speed '{}'", speed)))?;
+
+    // Intermediate files live in a per-job scratch directory that is removed when
+    // this function returns, whether or not processing succeeded
+    let scratch_dir = create_scratch_dir(&scratch_path, job_id).map_err(JobError::Retryable)?;
+    tracing::debug!(job_id = %job_id, scratch_dir = %scratch_dir.path().display(), "Created scratch directory");
+
+    // 1. Split the text into chunks that are synthesized one at a time, so progress
+    // can be reported while long texts are processing. Audiobooks are split into
+    // chapters first so that no chunk spans two chapters. Numbers and abbreviations are
+    // spelled out before splitting, so their periods don't end sentences.
+    let chapters = if has_m4b(encoding.format, renditions) {
+        crate::chapters::split_chapters(text, chapter_delimiter.as_deref())
+    } else {
+        vec![crate::chapters::Chapter {
+            title: String::new(),
+            text: text.to_string(),
+        }]
+    };
+    // Each chunk with the index of its chapter and the silence after it, in milliseconds.
+    // Pauses before a chapter's first text are dropped.
+    let mut chunks: Vec<(usize, String, u32)> = Vec::new();
+    for (index, chapter) in chapters.iter().enumerate() {
+        for piece in crate::pauses::split_pauses(&chapter.text) {
+            match piece {
+                crate::pauses::Piece::Text(text) => chunks.extend(
+                    chunk_text(
+                        &crate::normalize::normalize_lang(&text, &lang),
+                        MAX_CHUNK_CHARS,
+                    )
+                    .into_iter()
+                    .map(|chunk| (index, chunk, 0)),
+                ),
+                crate::pauses::Piece::Pause(ms) => {
+                    if let Some(last) = chunks.last_mut().filter(|(chapter, ..)| *chapter == index)
+                    {
+                        last.2 += ms;
+                    }
+                }
+            }
+        }
+    }
+    if chunks.is_empty() {
+        return Err(JobError::Permanent("Input text is empty".to_string()));
+    }
+    let chunks_total = chunks.len();
+    // Chunks hold whole sentences, so the gap between sentences also goes between chunks
+    // that have no pause between them
+    if sentence_gap_ms > 0 {
+        for (_, _, pause_ms) in &mut chunks[..chunks_total - 1] {
+            if *pause_ms == 0 {
+                *pause_ms = sentence_gap_ms;
+            }
+        }
+    }
+    tracing::info!(job_id = %job_id, chunks_total, "Split text into chunks");
+    set_progress(&pool, job_id, 0, chunks_total, rt);
+
+    // Encoded result in persistent storage
+    if let Some(output_dir) = output_path.parent() {
+        std::fs::create_dir_all(output_dir).map_err(|e| {
+            JobError::Retryable(format!("Failed to create output directory: {}", e))
+        })?;
+    }
+
+    // Each chunk is joined onto the last and encoded as soon as it is synthesized. WAV, MP3
+    // and Opus go straight into their files, for the output and each audio rendition,
+    // unless ffmpeg has to shift the pitch or normalize the loudness. Whatever ffmpeg
+    // encodes is joined into one WAV at the output rate for it first.
+    let sample_rate = crate::inference::SAMPLE_RATE;
+    let output_rate = encoding.sample_rate.unwrap_or(sample_rate);
+    let filters = audio_filters(pitch, loudness, output_rate);
+    let in_process = |encoding: &EncodingOptions| {
+        filters.is_empty() && crate::encode::supports(encoding, sample_rate)
+    };
+    let output_path_str = output_path.to_str().ok_or("Invalid output path")?;
+    let encode_in_process = in_process(&encoding);
+    let mut sinks: Vec<Box<dyn crate::encode::PcmSink>> = Vec::new();
+    if encode_in_process {
+        sinks.push(
+            crate::encode::create_sink(&encoding, &output_path, &tags.fields())
+                .map_err(JobError::Retryable)?,
+        );
+    }
+    let mut ffmpeg_renditions = Vec::new();
+    for &rendition in renditions {
+        let Rendition::Audio(format) = rendition else {
+            continue;
+        };
+        let rendition_encoding = crate::renditions::rendition_encoding(format, &encoding)
+            .map_err(JobError::Permanent)?;
+        let path = output_path.with_extension(rendition.extension());
+        if in_process(&rendition_encoding) {
+            sinks.push(
+                crate::encode::create_sink(&rendition_encoding, &path, &tags.fields())
+                    .map_err(JobError::Retryable)?,
+            );
+        } else {
+            ffmpeg_renditions.push((rendition_encoding, path));
+        }
+    }
+    let joined_path = scratch_dir.path().join("joined.wav");
+    if !encode_in_process || !ffmpeg_renditions.is_empty() {
+        let joined = crate::encode::WavWriter::create(
+            &joined_path,
+            crate::encode::SampleType::Float32,
+            Vec::new(),
+        )
+        .map_err(JobError::Retryable)?;
+        sinks.push(Box::new(crate::resample::ResamplingSink::new(
+            Box::new(joined),
+            output_rate,
+        )));
+    }
+    let mut joiner = crate::join::ChunkJoiner::new(
+        Box::new(crate::encode::TeeSink(sinks)),
+        sample_rate,
+        crossfade_ms,
+        silence_trim,
+    )
+    .map_err(JobError::Retryable)?;
+
+    let mut synthesized_secs = 0.0;
+    for (index, (chapter, chunk, pause_ms)) in chunks.iter().enumerate() {
+        let audio = if mock_synthesis {
+            tracing::info!(job_id = %job_id, chunk = index, "Test mode: Generated silent audio");
+            vec![0.0; sample_rate as usize]
+        } else if engine.synthesizes_chunks() {
+            // Engines that only take text get just the respellings of the lexicon
+            let respelled = lexicon.respell(chunk);
+            engine
+                .synthesize(&crate::engine::Utterance {
+                    text: &respelled,
+                    phonemes: "",
+                    voice: &voice,
+                    lang: &lang,
+                    speed,
+                    quality: None,
+                    cancel: None,
+                })?
+                .audio
+        } else {
+            let sentence_joiner =
+                crate::join::SentenceJoiner::new(sample_rate, crossfade_ms, sentence_gap_ms);
+            synthesize_chunk(
+                engine.as_ref(),
+                &phonemizer,
+                chunk,
+                &voice,
+                &lang,
+                detect_lang,
+                speed,
+                lexicon,
+                silence_trim,
+                sentence_joiner,
+            )?
+        };
+        synthesized_secs += audio.len() as f64 / sample_rate as f64;
+
+        // Anyone following the job over `/jobs/:id/stream` hears the chunk as it is done
+        if published.receiver_count() > 0 {
+            let _ = published.send(crate::state::JobChunk {
+                index: index as u32,
+                text: chunk.clone(),
+                audio: audio.as_slice().into(),
+                pause_ms: *pause_ms,
+            });
+        }
+
+        // Chunks are crossfaded unless a pause or the start of a chapter comes between them
+        let next_chapter = chunks.get(index + 1).map(|(next, ..)| *next);
+        let seam = if crossfade_ms > 0 && *pause_ms == 0 && next_chapter == Some(*chapter) {
+            crate::join::Seam::Crossfade
+        } else {
+            crate::join::Seam::Silence(*pause_ms)
+        };
+        joiner
+            .push(audio, seam)
+            .map_err(|e| JobError::Permanent(format!("Failed to encode audio: {}", e)))?;
+        set_progress(&pool, job_id, index + 1, chunks_total, rt);
+    }
+
+    // Synthesis is done, so count it against the user's monthly audio whether or not encoding
+    // succeeds. A retry synthesizes the text again, but it is only counted once.
+    rt.block_on(async {
+        let first_time = sqlx::query(
+            "UPDATE jobs SET usage_recorded = TRUE WHERE id = $1 AND NOT usage_recorded",
+        )
+        .bind(job_id)
+        .execute(&pool)
+        .await
+        .map_or(true, |result| result.rows_affected() > 0);
+        if first_time {
+            tracing::debug!(job_id = %job_id, audio_secs = synthesized_secs, "Recording synthesized audio");
+            crate::usage::record(&pool, username, synthesized_secs).await;
+        }
+    });
+
+    // Seconds of audio each chunk occupies in the output, with the pause after it
+    let chunk_secs = joiner
+        .finish()
+        .map_err(|e| JobError::Permanent(format!("Failed to encode audio: {}", e)))?;
+    tracing::info!(
+        job_id = %job_id,
+        output_path = %output_path_str,
+        format = encoding.format.as_str(),
+        bitrate_kbps = ?encoding.bitrate_kbps,
+        crossfade_ms,
+        trim_silence = silence_trim.is_some(),
+        in_process = encode_in_process,
+        "Joined and encoded chunks"
+    );
+
+    if !encode_in_process {
+        encode_with_ffmpeg(
+            job_id,
+            &joined_path,
+            &chapters,
+            &chunks,
+            &chunk_secs,
+            &filters,
+            &encoding,
+            &tags,
+            output_path_str,
+            scratch_dir.path(),
+        )?;
+    }
+    for (rendition_encoding, path) in &ffmpeg_renditions {
+        encode_with_ffmpeg(
+            job_id,
+            &joined_path,
+            &chapters,
+            &chunks,
+            &chunk_secs,
+            &filters,
+            rendition_encoding,
+            &tags,
+            path.to_str().ok_or("Invalid output path")?,
+            scratch_dir.path(),
+        )?;
+    }
+
+    // Each rendition goes next to the main output, with its own extension
+    let mut artifacts = Vec::with_capacity(renditions.len());
+    for &rendition in renditions {
+        let path = output_path.with_extension(rendition.extension());
+        if rendition == Rendition::Vtt {
+            std::fs::write(&path, crate::renditions::webvtt(&chunks, &chunk_secs))
+                .map_err(|e| JobError::Retryable(format!("Failed to write transcript: {}", e)))?;
+        }
+        let size = std::fs::metadata(&path).ok().map(|m| m.len() as i64);
+        let sha256 = file_sha256(path.to_str().ok_or("Invalid output path")?)
+            .map_err(|e| tracing::warn!(job_id = %job_id, error = %e, "Failed to hash rendition"))
+            .ok();
+        tracing::info!(job_id = %job_id, rendition = rendition.as_str(), size = ?size, "Wrote rendition");
+        artifacts.push((rendition, path, size, sha256));
+    }
+
+    // Intermediate files are no longer needed
+    drop(scratch_dir);
+
+    // The synthesized audio's length, which encoding doesn't change, unless ffprobe can
+    // read the encoded file
+    let probed_secs = if encode_in_process {
+        None
+    } else {
+        probe_duration(output_path_str)
+    };
+    let duration_secs = probed_secs.unwrap_or_else(|| chunk_secs.iter().sum());
+    tracing::info!(job_id = %job_id, duration_secs, "Got audio duration");
+
+    // Get output file size
+    let output_file_size: Option<i64> = std::fs::metadata(output_path_str)
+        .ok()
+        .map(|m| m.len() as i64);
+    tracing::info!(job_id = %job_id, output_file_size = ?output_file_size, "Got output file size");
+
+    let output_sha256 = file_sha256(output_path_str)
+        .map_err(|e| tracing::warn!(job_id = %job_id, error = %e, "Failed to hash output file"))
+        .ok();
+
+    // Update DB
+    rt.block_on(async {
         let _ = sqlx::query(
-            "UPDATE jobs SET status = 'completed', file_path = $1, duration_secs = $2, output_file_size = $3 WHERE id = $4",
+            "UPDATE jobs SET status = 'completed', file_path = $1, duration_secs = $2, output_file_size = $3, output_sha256 = $4, error_message = NULL WHERE id = $5",
         )
-            .bind(mp3_path_str)
+            .bind(output_path_str)
             .bind(duration_secs)
             .bind(output_file_size)
+            .bind(output_sha256)
             .bind(job_id)
             .execute(&pool)
             .await;
+        for (rendition, path, size, sha256) in &artifacts {
+            let _ = sqlx::query(
+                "INSERT INTO job_artifacts (job_id, format, file_path, content_type, file_size_bytes, sha256) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (job_id, format) DO UPDATE SET file_path = EXCLUDED.file_path, file_size_bytes = EXCLUDED.file_size_bytes, sha256 = EXCLUDED.sha256",
+            )
+                .bind(job_id)
+                .bind(rendition.as_str())
+                .bind(path.to_str())
+                .bind(rendition.content_type())
+                .bind(size)
+                .bind(sha256)
+                .execute(&pool)
+                .await;
+        }
     });
 
-    Ok(())
+    Ok(())
+}
+
+/// Whether a job writes an `m4b` audiobook, for which the text is split into chapters
+fn has_m4b(format: OutputFormat, renditions: &[Rendition]) -> bool {
+    format == OutputFormat::M4b || renditions.contains(&Rendition::Audio(OutputFormat::M4b))
+}
+
+/// ffmpeg audio filters for a job's audio at `sample_rate`: the pitch shift, then loudness
+/// normalization, so that it measures the shifted audio
+fn audio_filters(pitch: Option<f32>, loudness: Option<f32>, sample_rate: u32) -> Vec<String> {
+    let mut filters = Vec::new();
+    if let Some(pitch) = pitch {
+        filters.push(crate::prosody::pitch_filter(pitch, sample_rate));
+    }
+    if let Some(lufs) = loudness {
+        filters.push(crate::loudness::loudnorm_filter(lufs, sample_rate));
+    }
+    filters
+}
+
+/// Encode the joined WAV at `input` to `output_path` with ffmpeg. `m4b` audiobooks get
+/// chapter marks placed from `chunk_secs`; `filters` are ffmpeg audio filters applied in
+/// order.
+#[allow(clippy::too_many_arguments)]
+fn encode_with_ffmpeg(
+    job_id: Uuid,
+    input: &std::path::Path,
+    chapters: &[crate::chapters::Chapter],
+    chunks: &[(usize, String, u32)],
+    chunk_secs: &[f64],
+    filters: &[String],
+    encoding: &EncodingOptions,
+    tags: &Id3Tags,
+    output_path: &str,
+    scratch_dir: &std::path::Path,
+) -> Result<(), JobError> {
+    // Chapter marks are placed from the length of each chapter's audio
+    let chapter_metadata_path = if encoding.format == OutputFormat::M4b {
+        let mut chapter_secs = vec![0.0; chapters.len()];
+        for ((chapter, ..), secs) in chunks.iter().zip(chunk_secs) {
+            chapter_secs[*chapter] += *secs;
+        }
+        let marks: Vec<(&str, f64)> = chapters
+            .iter()
+            .zip(chapter_secs)
+            .map(|(chapter, secs)| (chapter.title.as_str(), secs))
+            .collect();
+        let path = scratch_dir.join("chapters.txt");
+        std::fs::write(&path, crate::chapters::ffmetadata(&marks))
+            .map_err(|e| JobError::Retryable(format!("Failed to write chapter metadata: {}", e)))?;
+        tracing::info!(job_id = %job_id, chapters = marks.len(), "Wrote chapter marks");
+        Some(path)
+    } else {
+        None
+    };
+
+    tracing::info!(
+        job_id = %job_id,
+        output_path = %output_path,
+        format = encoding.format.as_str(),
+        bitrate_kbps = ?encoding.bitrate_kbps,
+        sample_rate = ?encoding.sample_rate,
+        "Executing ffmpeg to encode WAV"
+    );
+    let mut ffmpeg = Command::new("ffmpeg");
+    ffmpeg.arg("-i").arg(input);
+    if let Some(path) = &chapter_metadata_path {
+        ffmpeg
+            .arg("-i")
+            .arg(path)
+            .args(["-map", "0:a", "-map_chapters", "1"]);
+    }
+    if !filters.is_empty() {
+        ffmpeg.arg("-af").arg(filters.join(","));
+    }
+    let ffmpeg_output = ffmpeg
+        .args(encoding.ffmpeg_args())
+        .args(tags.ffmpeg_args(encoding.format))
+        .arg("-y")
+        .arg(output_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| spawn_error("ffmpeg", e))?;
+
+    if !ffmpeg_output.status.success() {
+        let stderr = String::from_utf8_lossy(&ffmpeg_output.stderr);
+        let stdout = String::from_utf8_lossy(&ffmpeg_output.stdout);
+        let exit_code = ffmpeg_output.status.code();
+        tracing::error!(
+            job_id = %job_id,
+            exit_code = ?exit_code,
+            stdout = %stdout,
+            stderr = %stderr,
+            "ffmpeg failed"
+        );
+        let message = format!("ffmpeg failed (exit code {:?}): {}", exit_code, stderr);
+        return Err(if is_transient_failure(exit_code, &stderr) {
+            JobError::Retryable(message)
+        } else {
+            JobError::Permanent(message)
+        });
+    }
+    tracing::info!(
+        job_id = %job_id,
+        stderr = %String::from_utf8_lossy(&ffmpeg_output.stderr),
+        "ffmpeg conversion completed successfully"
+    );
+    Ok(())
+}
+
+/// Duration of an encoded file according to ffprobe
+fn probe_duration(path: &str) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .arg("-v")
+        .arg("error")
+        .arg("-show_entries")
+        .arg("format=duration")
+        .arg("-of")
+        .arg("default=noprint_wrappers=1:nokey=1")
+        .arg(path)
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse::<f64>()
+        .ok()
+}
+
+/// Status of a job
+#[utoipa::path(
+    get,
+    path = "/status/{id}",
+    tag = "jobs",
+    params(("id" = Uuid, Path, description = "Job ID")),
+    responses(
+        (status = 200, body = JobStatusResponse),
+        (status = 400, description = "Invalid job ID", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such job for this user", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn check_status(
+    Extension(user): Extension<AuthenticatedUser>,
+    Path(id_str): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    tracing::debug!(job_id = %id_str, user = %user.username, "Checking job status");
+    let id = match Uuid::parse_str(&id_str) {
+        Ok(u) => u,
+        Err(_) => {
+            tracing::warn!(job_id = %id_str, "Invalid UUID in status check");
+            return ApiError::invalid_id().into_response();
+        }
+    };
+
+    let row = match sqlx::query(
+        "SELECT status, error_message, duration_secs, output_file_size, format, content_type, chunks_done, chunks_total, retry_count, CASE WHEN pinned THEN NULL ELSE last_accessed_at + make_interval(days => COALESCE(ttl_days, $3)) END AS expires_at FROM jobs WHERE id = $1 AND username = $2",
+    )
+    .bind(id)
+    .bind(&user.username)
+    .bind(state.cleanup.retention_days)
+    .fetch_optional(&state.pool)
+    .await
+    {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            tracing::debug!(job_id = %id, user = %user.username, "Job not found");
+            return ApiError::job_not_found().into_response();
+        }
+        Err(e) => {
+            tracing::error!(job_id = %id, error = %e, "Database error while fetching job");
+            return ApiError::internal(e.to_string()).into_response();
+        }
+    };
+
+    let status: String = row.get("status");
+    tracing::debug!(job_id = %id, status = %status, "Job status retrieved");
+
+    match status.as_str() {
+        "queued" => match crate::queue::queue_position(&state.pool, id).await {
+            Ok(position) => Json(JobStatusResponse::Queued {
+                position,
+                retry_count: row.get("retry_count"),
+                last_error: row.get("error_message"),
+            })
+            .into_response(),
+            Err(e) => {
+                tracing::error!(job_id = %id, error = %e, "Failed to get queue position");
+                ApiError::internal(e.to_string()).into_response()
+            }
+        },
+        "processing" => {
+            let chunks_done: i32 = row.get("chunks_done");
+            let chunks_total: Option<i32> = row.get("chunks_total");
+            Json(JobStatusResponse::Processing {
+                progress: progress_percent(chunks_done, chunks_total),
+                chunks_done,
+                chunks_total,
+            })
+            .into_response()
+        }
+        "error" => {
+            let msg: String = row.get("error_message");
+            Json(JobStatusResponse::Error { message: msg }).into_response()
+        }
+        "completed" => Json(JobStatusResponse::Completed {
+            progress: 100.0,
+            download_url: format!("/download/{}", id),
+            format: row.get("format"),
+            content_type: row.get("content_type"),
+            duration_seconds: row.get::<Option<f32>, _>("duration_secs").map(|v| v as f64),
+            file_size_bytes: row.get("output_file_size"),
+            expires_at: row.get("expires_at"),
+        })
+        .into_response(),
+        _ => {
+            tracing::error!(job_id = %id, status = %status, "Unknown job status in database");
+            ApiError::internal("Unknown status").into_response()
+        }
+    }
+}
+
+/// The text a job was submitted with, e.g. to resubmit a failed job
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}/input",
+    tag = "jobs",
+    params(("id" = Uuid, Path, description = "Job ID")),
+    responses(
+        (status = 200, body = String, content_type = "text/plain"),
+        (status = 400, description = "Invalid job ID", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such job, or its text wasn't stored", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn job_input(
+    Extension(user): Extension<AuthenticatedUser>,
+    Path(id_str): Path<String>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(id) = Uuid::parse_str(&id_str) else {
+        return ApiError::invalid_id().into_response();
+    };
+
+    let input = sqlx::query_scalar::<_, Option<String>>(
+        "SELECT input_text FROM jobs WHERE id = $1 AND username = $2",
+    )
+    .bind(id)
+    .bind(&user.username)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match input {
+        Ok(Some(Some(text))) => {
+            ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response()
+        }
+        Ok(Some(None)) => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "input_not_stored",
+            "Input text was not stored for this job",
+        )
+        .into_response(),
+        Ok(None) => ApiError::job_not_found().into_response(),
+        Err(e) => {
+            tracing::error!(job_id = %id, error = %e, "Database error while fetching job input");
+            ApiError::internal(e.to_string()).into_response()
+        }
+    }
+}
+
+/// Pin a job, so cleanup never deletes it however long it goes without being downloaded
+#[utoipa::path(
+    post,
+    path = "/jobs/{id}/pin",
+    tag = "jobs",
+    params(("id" = Uuid, Path, description = "Job ID")),
+    responses(
+        (status = 204, description = "The job is pinned"),
+        (status = 400, description = "Invalid job ID", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such job for this user", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn pin_job(
+    Extension(user): Extension<AuthenticatedUser>,
+    Path(id_str): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ApiError> {
+    set_pinned(&state, &user, &id_str, true).await
+}
+
+/// Unpin a job, so cleanup deletes it again once it expires
+#[utoipa::path(
+    post,
+    path = "/jobs/{id}/unpin",
+    tag = "jobs",
+    params(("id" = Uuid, Path, description = "Job ID")),
+    responses(
+        (status = 204, description = "The job is no longer pinned"),
+        (status = 400, description = "Invalid job ID", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such job for this user", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn unpin_job(
+    Extension(user): Extension<AuthenticatedUser>,
+    Path(id_str): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ApiError> {
+    set_pinned(&state, &user, &id_str, false).await
+}
+
+async fn set_pinned(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    id_str: &str,
+    pinned: bool,
+) -> Result<StatusCode, ApiError> {
+    let id = Uuid::parse_str(id_str).map_err(|_| ApiError::invalid_id())?;
+    let result = sqlx::query("UPDATE jobs SET pinned = $1 WHERE id = $2 AND username = $3")
+        .bind(pinned)
+        .bind(id)
+        .bind(&user.username)
+        .execute(&state.pool)
+        .await
+        .map_err(|e| {
+            tracing::error!(job_id = %id, error = %e, "Failed to update job pin");
+            ApiError::internal(e.to_string())
+        })?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::job_not_found());
+    }
+    tracing::info!(job_id = %id, user = %user.username, pinned, "Updated job pin");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A file a completed job produced
+#[derive(Serialize, ToSchema)]
+pub struct Artifact {
+    /// The job's `format`, or one of its `renditions`
+    format: String,
+    content_type: String,
+    download_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_size_bytes: Option<i64>,
+}
+
+/// The files of a completed job: its main output first, then its renditions
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}/artifacts",
+    tag = "jobs",
+    params(("id" = Uuid, Path, description = "Job ID")),
+    responses(
+        (status = 200, body = Vec<Artifact>),
+        (status = 400, description = "Invalid job ID", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such job for this user", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The job hasn't completed", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn list_artifacts(
+    Extension(user): Extension<AuthenticatedUser>,
+    Path(id_str): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Artifact>>, ApiError> {
+    let id = Uuid::parse_str(&id_str).map_err(|_| ApiError::invalid_id())?;
+    let db_error = |e: sqlx::Error| {
+        tracing::error!(job_id = %id, error = %e, "Database error while listing artifacts");
+        ApiError::internal(e.to_string())
+    };
+
+    let row = sqlx::query(
+        "SELECT status, format, content_type, output_file_size FROM jobs WHERE id = $1 AND username = $2",
+    )
+    .bind(id)
+    .bind(&user.username)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?
+    .ok_or_else(ApiError::job_not_found)?;
+    if row.get::<String, _>("status") != "completed" {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "job_not_completed",
+            "Job is not completed",
+        ));
+    }
+
+    let mut artifacts = vec![Artifact {
+        format: row.get("format"),
+        content_type: row.get("content_type"),
+        download_url: format!("/download/{}", id),
+        file_size_bytes: row.get("output_file_size"),
+    }];
+    let rows = sqlx::query(
+        "SELECT format, content_type, file_size_bytes FROM job_artifacts WHERE job_id = $1 ORDER BY format",
+    )
+    .bind(id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+    artifacts.extend(rows.into_iter().map(|row| {
+        let format: String = row.get("format");
+        Artifact {
+            download_url: format!("/download/{}?format={}", id, format),
+            format,
+            content_type: row.get("content_type"),
+            file_size_bytes: row.get("file_size_bytes"),
+        }
+    }));
+    Ok(Json(artifacts))
 }
 
-pub async fn check_status(
-    Extension(user): Extension<AuthenticatedUser>,
+/// Query parameters of `GET /download/:id` besides a signed link's
+#[derive(Debug, Default, Deserialize)]
+pub struct DownloadQuery {
+    /// One of the job's renditions, instead of its main output
+    format: Option<String>,
+}
+
+/// Download the audio of a completed job. Supports single byte-range requests so
+/// audio players can seek within long files. Signed links (`expires` and `sig`, from
+/// `POST /jobs/:id/signed-url`) work without authentication.
+#[utoipa::path(
+    get,
+    path = "/download/{id}",
+    tag = "jobs",
+    params(
+        ("id" = Uuid, Path, description = "Job ID"),
+        ("expires" = Option<i64>, Query, description = "Expiry of a signed link, Unix seconds"),
+        ("sig" = Option<String>, Query, description = "Signature of a signed link"),
+        ("format" = Option<String>, Query, description = "One of the job's renditions, from `GET /jobs/{id}/artifacts`"),
+    ),
+    responses(
+        (status = 200, description = "The audio, with the job's `content_type`", content_type = "application/octet-stream"),
+        (status = 206, description = "The requested byte range"),
+        (status = 304, description = "The copy named by `If-None-Match` or `If-Modified-Since` is current"),
+        (status = 400, description = "Invalid job ID", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Invalid or expired signed link", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such job for this user, or no such rendition", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The job hasn't completed", body = crate::error::Problem, content_type = "application/problem+json"),
+        (status = 416, description = "Unsatisfiable range"),
+    )
+)]
+pub async fn download(
+    user: Option<Extension<AuthenticatedUser>>,
     Path(id_str): Path<String>,
+    Query(link): Query<crate::signed_url::SignedLink>,
+    Query(query): Query<DownloadQuery>,
     State(state): State<AppState>,
+    headers: HeaderMap,
 ) -> Response {
-    tracing::debug!(job_id = %id_str, username = %user.username, "Checking job status");
     let id = match Uuid::parse_str(&id_str) {
         Ok(u) => u,
         Err(_) => {
-            tracing::warn!(job_id = %id_str, "Invalid UUID in status check");
-            return (StatusCode::BAD_REQUEST, "Invalid UUID").into_response();
+            tracing::warn!(job_id = %id_str, "Invalid UUID in download");
+            return ApiError::invalid_id().into_response();
         }
     };
 
+    // Without a user, the request got past auth with a signature, which grants this job
+    // regardless of owner
+    let username = user.map(|Extension(user)| user.username);
+    if username.is_none() && !link.grants(&state, id) {
+        tracing::warn!(job_id = %id, "Invalid or expired signed download link");
+        return ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_signed_link",
+            "Invalid or expired signed link",
+        )
+        .into_response();
+    }
+
     let row = match sqlx::query(
-        "SELECT status, error_message, file_path FROM jobs WHERE id = $1 AND username = $2",
+        "SELECT status, file_path, format, content_type, output_sha256 FROM jobs WHERE id = $1 AND ($2::TEXT IS NULL OR username = $2)",
     )
     .bind(id)
-    .bind(&user.username)
+    .bind(&username)
     .fetch_optional(&state.pool)
     .await
     {
         Ok(Some(r)) => r,
         Ok(None) => {
-            tracing::debug!(job_id = %id, username = %user.username, "Job not found");
-            return (StatusCode::NOT_FOUND, "Job not found").into_response();
+            tracing::debug!(job_id = %id, user = ?username, "Job not found");
+            return ApiError::job_not_found().into_response();
         }
         Err(e) => {
             tracing::error!(job_id = %id, error = %e, "Database error while fetching job");
-            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+            return ApiError::internal(e.to_string()).into_response();
         }
     };
 
     let status: String = row.get("status");
-    tracing::debug!(job_id = %id, status = %status, "Job status retrieved");
+    let path: Option<String> = row.get("file_path");
+    let mut content_type: String = row.get("content_type");
+    let format: String = row.get("format");
+    let mut extension = OutputFormat::parse(&format).unwrap_or_default().extension();
+    let mut sha256: Option<String> = row.get("output_sha256");
+    let mut path = match (status.as_str(), path) {
+        ("completed", Some(path)) => path,
+        _ => {
+            return ApiError::new(
+                StatusCode::CONFLICT,
+                "job_not_completed",
+                "Job is not completed",
+            )
+            .into_response();
+        }
+    };
 
-    match status.as_str() {
-        "processing" => Json(JobStatusResponse::Processing).into_response(),
-        "error" => {
-            let msg: String = row.get("error_message");
-            Json(JobStatusResponse::Error { message: msg }).into_response()
+    if let Some(rendition) = query.format.filter(|requested| *requested != format) {
+        let artifact = sqlx::query(
+            "SELECT file_path, content_type, sha256 FROM job_artifacts WHERE job_id = $1 AND format = $2",
+        )
+        .bind(id)
+        .bind(&rendition)
+        .fetch_optional(&state.pool)
+        .await;
+        match artifact {
+            Ok(Some(artifact)) => {
+                path = artifact.get("file_path");
+                content_type = artifact.get("content_type");
+                sha256 = artifact.get("sha256");
+                extension = Rendition::parse(&rendition).map_or("bin", Rendition::extension);
+            }
+            Ok(None) => {
+                return ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "rendition_not_found",
+                    "Job has no such rendition",
+                )
+                .into_response();
+            }
+            Err(e) => {
+                tracing::error!(job_id = %id, error = %e, "Database error while fetching artifact");
+                return ApiError::internal(e.to_string()).into_response();
+            }
         }
-        "completed" => {
-            let path: String = row.get("file_path");
+    }
 
-            // Check if file exists
-            match tokio::fs::File::open(&path).await {
-                Ok(file) => {
-                    // Update last_accessed_at
-                    let _ = sqlx::query("UPDATE jobs SET last_accessed_at = NOW() WHERE id = $1")
-                        .bind(id)
-                        .execute(&state.pool)
-                        .await;
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            // Should theoretically not happen if storage is persistent and logic correct
+            tracing::error!(job_id = %id, path = %path, error = %e, "File missing from storage");
+            return ApiError::internal("File missing from storage").into_response();
+        }
+    };
+    let metadata = match file.metadata().await {
+        Ok(m) => m,
+        Err(e) => {
+            tracing::error!(job_id = %id, path = %path, error = %e, "Failed to stat output file");
+            return ApiError::internal(e.to_string()).into_response();
+        }
+    };
 
-                    let stream = tokio_util::io::ReaderStream::new(file);
-                    let body = Body::from_stream(stream);
+    // Update last_accessed_at
+    let _ = sqlx::query("UPDATE jobs SET last_accessed_at = NOW() WHERE id = $1")
+        .bind(id)
+        .execute(&state.pool)
+        .await;
 
-                    (
-                        [
-                            (header::CONTENT_TYPE, "audio/mpeg"),
-                            (
-                                header::CONTENT_DISPOSITION,
-                                &format!("attachment; filename=\"{}.mp3\"", id),
-                            ),
-                        ],
-                        body,
-                    )
-                        .into_response()
-                }
-                Err(e) => {
-                    // Should theoretically not happen if storage is persistent and logic correct
-                    tracing::error!(job_id = %id, path = %path, error = %e, "File missing from storage");
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "File missing from storage",
-                    )
-                        .into_response()
-                }
+    // A job's output never changes once completed, so its hash identifies it. Jobs from
+    // before hashes were recorded fall back to ID and size.
+    let size = metadata.len();
+    let etag = match sha256 {
+        Some(hash) => format!("\"{}\"", hash),
+        None => format!("\"{}-{}\"", id, size),
+    };
+    let last_modified: Option<DateTime<Utc>> = metadata.modified().ok().map(DateTime::from);
+
+    // Clients may keep a copy, but check it is current with a conditional request
+    let mut cache_headers = HeaderMap::new();
+    cache_headers.insert(header::CACHE_CONTROL, "private, no-cache".parse().unwrap());
+    if let Ok(value) = etag.parse() {
+        cache_headers.insert(header::ETAG, value);
+    }
+    if let Some(value) = last_modified.and_then(|time| http_date(time).parse().ok()) {
+        cache_headers.insert(header::LAST_MODIFIED, value);
+    }
+    if not_modified(&headers, &etag, last_modified) {
+        tracing::debug!(job_id = %id, "Client's copy is current");
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    let disposition = format!("attachment; filename=\"{}.{}\"", id, extension);
+
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| parse_range(h, size))
+        .unwrap_or(Ok(None));
+
+    match range {
+        Ok(Some((start, end))) => {
+            if let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await {
+                tracing::error!(job_id = %id, error = %e, "Failed to seek output file");
+                return ApiError::internal(e.to_string()).into_response();
             }
+            let len = end - start + 1;
+            tracing::debug!(job_id = %id, start, end, "Serving byte range");
+            let body = Body::from_stream(tokio_util::io::ReaderStream::new(file.take(len)));
+            (
+                StatusCode::PARTIAL_CONTENT,
+                cache_headers,
+                [
+                    (header::CONTENT_TYPE, content_type),
+                    (header::CONTENT_LENGTH, len.to_string()),
+                    (
+                        header::CONTENT_RANGE,
+                        format!("bytes {}-{}/{}", start, end, size),
+                    ),
+                    (header::ACCEPT_RANGES, "bytes".to_string()),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                body,
+            )
+                .into_response()
         }
-        _ => {
-            tracing::error!(job_id = %id, status = %status, "Unknown job status in database");
-            (StatusCode::INTERNAL_SERVER_ERROR, "Unknown status").into_response()
+        Ok(None) => {
+            let body = Body::from_stream(tokio_util::io::ReaderStream::new(file));
+            (
+                cache_headers,
+                [
+                    (header::CONTENT_TYPE, content_type),
+                    (header::CONTENT_LENGTH, size.to_string()),
+                    (header::ACCEPT_RANGES, "bytes".to_string()),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                body,
+            )
+                .into_response()
         }
+        Err(()) => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+        )
+            .into_response(),
+    }
+}
+
+/// Whether the client's copy is current: it sent a matching `If-None-Match`, or, without
+/// one, an `If-Modified-Since` no earlier than `last_modified`
+fn not_modified(headers: &HeaderMap, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        });
+    }
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| DateTime::parse_from_rfc2822(h).ok());
+    match (since, last_modified) {
+        (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+/// Format a time as an HTTP date, e.g. "Tue, 07 May 2024 09:30:00 GMT"
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Parse a `Range` header against a file of `size` bytes into an inclusive byte range.
+///
+/// Returns `Ok(None)` for headers we don't handle (non-byte units, multiple ranges),
+/// which are answered with the full file, and `Err(())` if the range is unsatisfiable.
+fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let (start, end) = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        // bytes=start-end
+        (Some(start), Some(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        // bytes=start-
+        (Some(start), None) if end.is_empty() => (start, size.saturating_sub(1)),
+        // bytes=-suffix_length
+        (None, Some(suffix)) if start.is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+
+    if size == 0 || start >= size {
+        return Err(());
     }
+    Ok(Some((start, end)))
 }
 
 /// Response structure for a single job in the list
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct JobListItem {
     pub id: String,
     pub status: String,
@@ -494,54 +2696,1106 @@ pub struct JobListItem {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub input_filename: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Exempt from cleanup; see `POST /jobs/:id/pin`
+    pub pinned: bool,
+    pub format: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate_kbps: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub duration_secs: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub output_file_size: Option<i64>,
+    /// Percentage synthesized, for jobs still processing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<f64>,
     pub created_at: DateTime<Utc>,
 }
 
-/// List all jobs for the authenticated user
+/// Jobs listed when no `limit` is given
+const DEFAULT_JOBS_LIMIT: i64 = 50;
+/// Most jobs listed at once
+const MAX_JOBS_LIMIT: i64 = 500;
+
+/// Filters for `GET /jobs`; all are optional and combine with AND
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct JobsQuery {
+    /// Part of the title or uploaded filename, ignoring case
+    title: Option<String>,
+    /// A label from `tags`, or the album `tag`, ignoring case
+    tag: Option<String>,
+    /// `queued`, `processing`, `completed` or `error`
+    status: Option<String>,
+    /// Jobs created at or after this time (RFC 3339)
+    since: Option<DateTime<Utc>>,
+    /// Jobs created before this time (RFC 3339)
+    until: Option<DateTime<Utc>>,
+    /// Newest jobs first; default 50, at most 500
+    limit: Option<i64>,
+}
+
+/// Escape `LIKE` wildcards so `value` only matches itself
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// List the authenticated user's jobs, newest first, optionally filtered
+#[utoipa::path(
+    get,
+    path = "/jobs",
+    tag = "jobs",
+    params(JobsQuery),
+    responses(
+        (status = 200, body = Vec<JobListItem>),
+        (status = 400, description = "An invalid filter", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
 pub async fn list_jobs(
     Extension(user): Extension<AuthenticatedUser>,
     State(state): State<AppState>,
-) -> Result<Json<Vec<JobListItem>>, (StatusCode, String)> {
-    tracing::info!(username = %user.username, "Listing jobs for user");
+    Query(query): Query<JobsQuery>,
+) -> Result<Json<Vec<JobListItem>>, ApiError> {
+    tracing::info!(user = %user.username, query = ?query, "Listing jobs for user");
+
+    let limit = query.limit.unwrap_or(DEFAULT_JOBS_LIMIT);
+    if !(1..=MAX_JOBS_LIMIT).contains(&limit) {
+        return Err(ApiError::invalid(format!(
+            "limit must be between 1 and {}",
+            MAX_JOBS_LIMIT
+        )));
+    }
+    let non_empty = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    };
+    let title_pattern = non_empty(&query.title).map(|title| format!("%{}%", escape_like(&title)));
 
     let rows = sqlx::query(
         r#"
-        SELECT id, status, error_message, voice, speed, input_filename, duration_secs, output_file_size, created_at
+        SELECT id, status, error_message, voice, speed, input_filename, title, tag, track, tags, pinned, format, bitrate_kbps, sample_rate, duration_secs, output_file_size, chunks_done, chunks_total, created_at
         FROM jobs
         WHERE username = $1
+          AND ($2::TEXT IS NULL OR title ILIKE $2 OR input_filename ILIKE $2)
+          AND ($3::TEXT IS NULL OR LOWER(tag) = LOWER($3) OR EXISTS (SELECT 1 FROM UNNEST(tags) AS label WHERE LOWER(label) = LOWER($3)))
+          AND ($4::TEXT IS NULL OR status = $4)
+          AND ($5::TIMESTAMPTZ IS NULL OR created_at >= $5)
+          AND ($6::TIMESTAMPTZ IS NULL OR created_at < $6)
         ORDER BY created_at DESC
-        LIMIT 50
+        LIMIT $7
         "#,
     )
     .bind(&user.username)
+    .bind(title_pattern)
+    .bind(non_empty(&query.tag))
+    .bind(non_empty(&query.status))
+    .bind(query.since)
+    .bind(query.until)
+    .bind(limit)
     .fetch_all(&state.pool)
     .await
     .map_err(|e| {
         tracing::error!(error = %e, "Failed to fetch jobs from database");
-        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        ApiError::internal(e.to_string())
     })?;
 
     let jobs: Vec<JobListItem> = rows
         .iter()
         .map(|row| {
             let id: Uuid = row.get("id");
+            let status: String = row.get("status");
+            let progress = if status == "processing" {
+                progress_percent(row.get("chunks_done"), row.get("chunks_total"))
+            } else {
+                None
+            };
             JobListItem {
                 id: id.to_string(),
-                status: row.get("status"),
+                status,
                 error_message: row.get("error_message"),
                 voice: row.get("voice"),
                 speed: row.get("speed"),
                 input_filename: row.get("input_filename"),
+                title: row.get("title"),
+                tag: row.get("tag"),
+                track: row.get("track"),
+                tags: row.get("tags"),
+                pinned: row.get("pinned"),
+                format: row.get("format"),
+                bitrate_kbps: row.get("bitrate_kbps"),
+                sample_rate: row.get("sample_rate"),
                 duration_secs: row.get::<Option<f32>, _>("duration_secs").map(|v| v as f64),
                 output_file_size: row.get("output_file_size"),
+                progress,
                 created_at: row.get("created_at"),
             }
         })
         .collect();
 
-    tracing::info!(username = %user.username, count = jobs.len(), "Jobs retrieved");
+    tracing::info!(user = %user.username, count = jobs.len(), "Jobs retrieved");
     Ok(Json(jobs))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_id3_tag_args() {
+        let tags = Id3Tags {
+            title: "Chapter 1".to_string(),
+            artist: "af_heart".to_string(),
+            album: Some("My Book".to_string()),
+            track: Some("3/12".to_string()),
+            comment: "job-123".to_string(),
+        };
+        let args = tags.ffmpeg_args(OutputFormat::Mp3);
+        assert_eq!(
+            args,
+            [
+                "-metadata",
+                "title=Chapter 1",
+                "-metadata",
+                "artist=af_heart",
+                "-metadata",
+                "comment=job-123",
+                "-metadata",
+                "album=My Book",
+                "-metadata",
+                "track=3/12",
+                "-id3v2_version",
+                "3",
+            ]
+        );
+
+        let tags = Id3Tags {
+            album: None,
+            track: None,
+            ..tags
+        };
+        let args = tags.ffmpeg_args(OutputFormat::Mp3);
+        assert!(
+            !args
+                .iter()
+                .any(|a| a.starts_with("album=") || a.starts_with("track="))
+        );
+
+        // The ID3 version option only applies to the MP3 muxer
+        let args = tags.ffmpeg_args(OutputFormat::Flac);
+        assert!(args.contains(&"title=Chapter 1".to_string()));
+        assert!(!args.contains(&"-id3v2_version".to_string()));
+    }
+
+    #[test]
+    fn test_parse_track() {
+        assert_eq!(parse_track("3"), Ok("3".to_string()));
+        assert_eq!(parse_track("3 / 12"), Ok("3/12".to_string()));
+        assert_eq!(parse_track("12/12"), Ok("12/12".to_string()));
+        for invalid in ["0", "13/12", "three", "3/", "-1", "3/12/1"] {
+            assert!(parse_track(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_scratch_dir_removed_on_drop() {
+        let root = tempfile::tempdir().unwrap();
+        let job_id = Uuid::new_v4();
+        let scratch = create_scratch_dir(root.path().to_str().unwrap(), job_id).unwrap();
+        let name = scratch
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        assert!(name.starts_with(&format!("tts-job-{}-", job_id)));
+
+        std::fs::write(scratch.path().join("output.wav"), b"RIFF").unwrap();
+        let path = scratch.path().to_path_buf();
+        drop(scratch);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=9-5", 1000), Ok(None));
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let modified: DateTime<Utc> = "2024-05-07T09:30:00Z".parse().unwrap();
+        assert_eq!(http_date(modified), "Tue, 07 May 2024 09:30:00 GMT");
+        let check = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            not_modified(&headers, "\"abc\"", Some(modified))
+        };
+
+        assert!(check(header::IF_NONE_MATCH, "\"abc\""));
+        assert!(check(header::IF_NONE_MATCH, "\"old\", W/\"abc\""));
+        assert!(check(header::IF_NONE_MATCH, "*"));
+        assert!(!check(header::IF_NONE_MATCH, "\"old\""));
+        assert!(check(
+            header::IF_MODIFIED_SINCE,
+            "Tue, 07 May 2024 09:30:00 GMT"
+        ));
+        assert!(!check(
+            header::IF_MODIFIED_SINCE,
+            "Tue, 07 May 2024 09:29:59 GMT"
+        ));
+        assert!(!check(header::IF_MODIFIED_SINCE, "yesterday"));
+        assert!(!not_modified(&HeaderMap::new(), "\"abc\"", Some(modified)));
+    }
+
+    #[test]
+    fn test_filename_stem() {
+        assert_eq!(filename_stem("chapter1.txt"), "chapter1");
+        assert_eq!(filename_stem("books/ch 2.md"), "ch 2");
+        assert_eq!(filename_stem("notes"), "notes");
+    }
+
+    #[test]
+    fn test_chunk_text() {
+        let text = "One two. Three four! Five six? Seven";
+        assert_eq!(
+            chunk_text(text, 20),
+            vec!["One two. Three four!", "Five six? Seven"]
+        );
+        assert_eq!(chunk_text(text, 1000), vec![text]);
+        // Overlong sentences are broken at clause breaks, then spaces, then anywhere
+        assert_eq!(
+            chunk_text("First clause, second clause. Hi.", 16),
+            vec!["First clause,", "second clause.", "Hi."]
+        );
+        assert_eq!(
+            chunk_text("no punctuation here at all", 12),
+            vec!["no", "punctuation", "here at all"]
+        );
+        assert_eq!(chunk_text("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert!(chunk_text("  ", 100).is_empty());
+    }
+
+    #[test]
+    fn test_progress_percent() {
+        assert_eq!(progress_percent(0, None), None);
+        assert_eq!(progress_percent(0, Some(3)), Some(0.0));
+        assert_eq!(progress_percent(1, Some(3)), Some(33.3));
+        assert_eq!(progress_percent(3, Some(3)), Some(100.0));
+    }
+
+    #[test]
+    fn test_is_transient_failure() {
+        // Killed by a signal, e.g. the OOM killer
+        assert!(is_transient_failure(None, ""));
+        assert!(is_transient_failure(
+            Some(1),
+            "out.mp3: No space left on device\n"
+        ));
+        assert!(!is_transient_failure(
+            Some(1),
+            "Unknown encoder 'libmp3lame'\n"
+        ));
+    }
+
+    #[test]
+    fn test_audio_filters() {
+        assert!(audio_filters(None, None, 24000).is_empty());
+        assert_eq!(
+            audio_filters(Some(12.0), Some(-16.0), 24000).join(","),
+            "asetrate=48000,aresample=24000,atempo=0.500000,loudnorm=I=-16:TP=-1.5:LRA=11,aresample=24000"
+        );
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let mp3 = EncodingOptions::new(OutputFormat::default(), None, None).unwrap();
+        let hash = |text: &[u8], voice, speed, title| {
+            content_hash(
+                text,
+                voice,
+                speed,
+                &mp3,
+                &HashInputs {
+                    title,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let base = hash(b"Hello", "af_heart", "1.0", None);
+        assert_eq!(base.len(), 64);
+        assert_eq!(base, hash(b"Hello", "af_heart", "1", None));
+        assert_ne!(base, hash(b"Hello!", "af_heart", "1.0", None));
+        assert_ne!(base, hash(b"Hello", "am_adam", "1.0", None));
+        assert_ne!(base, hash(b"Hello", "af_heart", "1.5", None));
+        assert_ne!(base, hash(b"Hello", "af_heart", "1.0", Some("")));
+        // Field boundaries are unambiguous
+        assert_ne!(hash(b"ab", "c", "1", None), hash(b"a", "bc", "1", None));
+
+        let flac = EncodingOptions::new(OutputFormat::parse("flac").unwrap(), None, None).unwrap();
+        let with = |inputs: HashInputs| content_hash(b"Hello", "af_heart", "1.0", &mp3, &inputs);
+        assert_eq!(base, with(HashInputs::default()));
+        assert_ne!(
+            base,
+            content_hash(b"Hello", "af_heart", "1.0", &flac, &HashInputs::default())
+        );
+
+        // Each later field is told apart from the others when set to the same value
+        let one = Some("1");
+        let hashes = [
+            with(HashInputs {
+                track: one,
+                ..Default::default()
+            }),
+            with(HashInputs {
+                chapter_delimiter: one,
+                ..Default::default()
+            }),
+            with(HashInputs {
+                lexicon: one,
+                ..Default::default()
+            }),
+            with(HashInputs {
+                lang: one,
+                ..Default::default()
+            }),
+            with(HashInputs {
+                pitch: one,
+                ..Default::default()
+            }),
+            with(HashInputs {
+                loudness: one,
+                ..Default::default()
+            }),
+            with(HashInputs {
+                renditions: one,
+                ..Default::default()
+            }),
+            with(HashInputs {
+                custom_voice: one,
+                ..Default::default()
+            }),
+            with(HashInputs {
+                detect_lang: true,
+                ..Default::default()
+            }),
+        ];
+        for (index, hash) in hashes.iter().enumerate() {
+            assert_ne!(base, *hash);
+            assert!(!hashes[index + 1..].contains(hash));
+        }
+    }
+
+    async fn job_status(
+        app: &TestApp,
+        id: &str,
+        username: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let resp = app
+            .client
+            .get(app.url(&format!("/status/{}", id)))
+            .bearer_auth(app.token(username))
+            .send()
+            .await
+            .unwrap();
+        let status = resp.status();
+        (status, resp.json().await.unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_generate_creates_job_for_user() {
+        let app = TestApp::spawn().await;
+
+        let resp = app
+            .client
+            .post(app.url("/generate-json"))
+            .bearer_auth(app.token("alice"))
+            .json(&serde_json::json!({ "text": "First sentence. Second sentence." }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let id = body["id"].as_str().unwrap().to_string();
+
+        // Other users can't see the job
+        let (status, _) = job_status(&app, &id, "bob").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // The input is kept, so the job can be resubmitted or requeued
+        let input = |username: &str| {
+            app.client
+                .get(app.url(&format!("/jobs/{}/input", id)))
+                .bearer_auth(app.token(username))
+                .send()
+        };
+        let resp = input("alice").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.text().await.unwrap(),
+            "First sentence. Second sentence."
+        );
+        assert_eq!(input("bob").await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        // Mocked synthesis finishes every chunk; whether encoding then succeeds
+        // depends on ffmpeg being installed, so only the chunk progress is checked
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(20);
+        loop {
+            let (status, body) = job_status(&app, &id, "alice").await;
+            assert_eq!(status, StatusCode::OK);
+            if body["status"] != "queued" && body["status"] != "processing" {
+                break;
+            }
+            assert!(std::time::Instant::now() < deadline, "Job did not finish");
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let row = sqlx::query("SELECT chunks_done, chunks_total FROM jobs WHERE id = $1")
+            .bind(Uuid::parse_str(&id).unwrap())
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<i32, _>("chunks_done"), 1);
+        assert_eq!(row.get::<Option<i32>, _>("chunks_total"), Some(1));
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_input_limits() {
+        let app = TestApp::spawn_without_workers().await;
+        let generate = |text: String| {
+            app.client
+                .post(app.url("/generate-json"))
+                .bearer_auth(app.token("alice"))
+                .json(&serde_json::json!({ "text": text }))
+                .send()
+        };
+
+        let resp = generate(" \n ".to_string()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // The limit is in characters, not bytes
+        let resp = generate("é".repeat(TestApp::MAX_INPUT_CHARS))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = generate("é".repeat(TestApp::MAX_INPUT_CHARS + 1))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            crate::error::PROBLEM_CONTENT_TYPE
+        );
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "input_too_long");
+        assert_eq!(
+            problem["detail"],
+            "Input text is 1001 characters, over the limit of 1000 characters"
+        );
+
+        // Uploads are checked too
+        let form = reqwest::multipart::Form::new().part(
+            "text_file",
+            reqwest::multipart::Part::bytes(Vec::new()).file_name("empty.txt"),
+        );
+        let resp = app
+            .client
+            .post(app.url("/generate"))
+            .bearer_auth(app.token("alice"))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        app.teardown().await;
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        let values = [
+            "news, Tech ,".to_string(),
+            "news".to_string(),
+            "Weekly".to_string(),
+        ];
+        assert_eq!(parse_tags(&values).unwrap(), ["news", "Tech", "Weekly"]);
+        assert!(parse_tags(&["x".repeat(MAX_TAG_CHARS + 1)]).is_err());
+        let many: Vec<String> = (0..=MAX_TAGS).map(|i| i.to_string()).collect();
+        assert!(parse_tags(&many).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_jobs_filters() {
+        let app = TestApp::spawn_without_workers().await;
+        for body in [
+            serde_json::json!({ "text": "One.", "title": "Morning news", "tags": ["news", "daily"] }),
+            serde_json::json!({ "text": "Two.", "title": "100% fiction", "tag": "Stories" }),
+            serde_json::json!({ "text": "Three.", "title": "Evening NEWS", "tags": ["news"] }),
+        ] {
+            let resp = app
+                .client
+                .post(app.url("/generate-json"))
+                .bearer_auth(app.token("alice"))
+                .json(&body)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let titles = |query: &'static str| {
+            let request = app
+                .client
+                .get(app.url(&format!("/jobs?{}", query)))
+                .bearer_auth(app.token("alice"))
+                .send();
+            async move {
+                let jobs: Vec<serde_json::Value> = request.await.unwrap().json().await.unwrap();
+                jobs.iter()
+                    .map(|job| job["title"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(titles("title=news").await, ["Evening NEWS", "Morning news"]);
+        assert_eq!(titles("title=100%25").await, ["100% fiction"]);
+        assert!(titles("title=%25news").await.is_empty());
+        assert_eq!(titles("tag=DAILY").await, ["Morning news"]);
+        // The album tag matches too
+        assert_eq!(titles("tag=stories").await, ["100% fiction"]);
+        assert_eq!(titles("tag=news&limit=1").await, ["Evening NEWS"]);
+        assert!(titles("status=completed").await.is_empty());
+        assert_eq!(titles("since=2000-01-01T00:00:00Z").await.len(), 3);
+        assert!(titles("until=2000-01-01T00:00:00Z").await.is_empty());
+
+        let resp = app
+            .client
+            .get(app.url("/jobs?limit=0"))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_speed_and_pitch() {
+        let app = TestApp::spawn_without_workers().await;
+        let generate = |body: serde_json::Value| {
+            app.client
+                .post(app.url("/generate-json"))
+                .bearer_auth(app.token("alice"))
+                .json(&body)
+                .send()
+        };
+
+        for body in [
+            serde_json::json!({ "text": "Hi.", "speed": 1000 }),
+            serde_json::json!({ "text": "Hi.", "speed": 0 }),
+            serde_json::json!({ "text": "Hi.", "pitch": 24 }),
+            serde_json::json!({ "text": "Hi.", "loudness": 3 }),
+        ] {
+            assert_eq!(
+                generate(body).await.unwrap().status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+
+        let resp = generate(
+            serde_json::json!({ "text": "Hi.", "speed": 1.5, "pitch": -2.5, "loudness": -16 }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let (pitch, loudness): (Option<f32>, Option<f32>) =
+            sqlx::query_as("SELECT pitch, loudness_lufs FROM jobs WHERE id = $1")
+                .bind(Uuid::parse_str(body["id"].as_str().unwrap()).unwrap())
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(pitch, Some(-2.5));
+        assert_eq!(loudness, Some(-16.0));
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_lang() {
+        let app = TestApp::spawn_without_workers().await;
+        let generate = |body: serde_json::Value| {
+            app.client
+                .post(app.url("/generate-json"))
+                .bearer_auth(app.token("alice"))
+                .json(&body)
+                .send()
+        };
+
+        let resp =
+            generate(serde_json::json!({ "text": "Hola.", "voice": "ef_dora", "lang": "klingon" }))
+                .await
+                .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp =
+            generate(serde_json::json!({ "text": "Hola.", "voice": "af_heart", "lang": "ES" }))
+                .await
+                .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let (lang, detect_lang): (Option<String>, bool) =
+            sqlx::query_as("SELECT lang, detect_lang FROM jobs WHERE id = $1")
+                .bind(Uuid::parse_str(body["id"].as_str().unwrap()).unwrap())
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(lang.as_deref(), Some("es"));
+        assert!(!detect_lang);
+
+        // With `auto`, sentences in other languages are detected
+        let resp =
+            generate(serde_json::json!({ "text": "Hola.", "voice": "af_heart", "lang": "auto" }))
+                .await
+                .unwrap();
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let (lang, detect_lang): (Option<String>, bool) =
+            sqlx::query_as("SELECT lang, detect_lang FROM jobs WHERE id = $1")
+                .bind(Uuid::parse_str(body["id"].as_str().unwrap()).unwrap())
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(lang.as_deref(), Some("en-us"));
+        assert!(detect_lang);
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_audiobook_chapters() {
+        let app = TestApp::spawn().await;
+        let generate = |body: serde_json::Value| {
+            app.client
+                .post(app.url("/generate-json"))
+                .bearer_auth(app.token("alice"))
+                .json(&body)
+                .send()
+        };
+
+        let resp = generate(serde_json::json!({
+            "text": "Hello.", "format": "mp3", "chapter_delimiter": "==="
+        }))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Short chapters still get a chunk each, so every chapter can be marked
+        let resp = generate(serde_json::json!({
+            "text": "# One\nFirst.\n# Two\nSecond.\n# Three\nThird.", "format": "m4b"
+        }))
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let id = Uuid::parse_str(body["id"].as_str().unwrap()).unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(20);
+        loop {
+            let (_, body) = job_status(&app, &id.to_string(), "alice").await;
+            if body["status"] != "queued" && body["status"] != "processing" {
+                break;
+            }
+            assert!(std::time::Instant::now() < deadline, "Job did not finish");
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        let chunks_total: Option<i32> =
+            sqlx::query_scalar("SELECT chunks_total FROM jobs WHERE id = $1")
+                .bind(id)
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(chunks_total, Some(3));
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_wav_in_process() {
+        let app = TestApp::spawn().await;
+
+        // WAV is written and resampled without ffmpeg, so the job completes even where it
+        // isn't installed
+        let resp = app
+            .client
+            .post(app.url("/generate-json"))
+            .bearer_auth(app.token("alice"))
+            .json(&serde_json::json!({ "text": "Hello there.", "format": "wav", "sample_rate": 16000, "title": "Intro" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let id = body["id"].as_str().unwrap().to_string();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(20);
+        let status = loop {
+            let (_, body) = job_status(&app, &id, "alice").await;
+            if body["status"] != "queued" && body["status"] != "processing" {
+                break body;
+            }
+            assert!(std::time::Instant::now() < deadline, "Job did not finish");
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        };
+        assert_eq!(status["status"], "completed", "{}", status);
+        // One chunk of the test mode's second of silence
+        assert_eq!(status["duration_seconds"], 1.0);
+
+        let resp = app
+            .client
+            .get(app.url(&format!("/download/{}", id)))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "audio/wav");
+        let wav = resp.bytes().await.unwrap();
+        assert_eq!(status["file_size_bytes"], wav.len());
+        let (audio, sample_rate) = crate::join::decode_wav(&wav).unwrap();
+        assert_eq!((audio.len(), sample_rate), (16000, 16000));
+        assert!(
+            wav.windows(13)
+                .any(|window| window == b"INAM\x06\0\0\0Intro")
+        );
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_renditions() {
+        let app = TestApp::spawn().await;
+
+        // At an Opus rate, so all three are written without ffmpeg
+        let resp = app
+            .client
+            .post(app.url("/generate-json"))
+            .bearer_auth(app.token("alice"))
+            .json(&serde_json::json!({ "text": "Hello there.", "format": "wav", "sample_rate": 24000, "renditions": ["opus,vtt", "wav"] }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let id = body["id"].as_str().unwrap().to_string();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(20);
+        loop {
+            let (_, body) = job_status(&app, &id, "alice").await;
+            if body["status"] != "queued" && body["status"] != "processing" {
+                assert_eq!(body["status"], "completed", "{}", body);
+                break;
+            }
+            assert!(std::time::Instant::now() < deadline, "Job did not finish");
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        let artifacts: serde_json::Value = app
+            .client
+            .get(app.url(&format!("/jobs/{}/artifacts", id)))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let formats: Vec<&str> = artifacts
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|artifact| artifact["format"].as_str().unwrap())
+            .collect();
+        assert_eq!(formats, ["wav", "opus", "vtt"]);
+        assert_eq!(
+            artifacts[2]["download_url"],
+            format!("/download/{}?format=vtt", id)
+        );
+
+        let download = |format: &'static str| {
+            app.client
+                .get(app.url(&format!("/download/{}?format={}", id, format)))
+                .bearer_auth(app.token("alice"))
+                .send()
+        };
+        let resp = download("opus").await.unwrap();
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "audio/ogg; codecs=opus"
+        );
+        assert!(resp.bytes().await.unwrap().starts_with(b"OggS"));
+        let resp = download("vtt").await.unwrap();
+        assert_eq!(
+            resp.text().await.unwrap(),
+            "WEBVTT\n\n00:00:00.000 --> 00:00:01.000\nHello there.\n"
+        );
+        assert_eq!(
+            download("flac").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // Renditions must be encodable at the job's sample rate
+        let resp = app
+            .client
+            .post(app.url("/generate-json"))
+            .bearer_auth(app.token("alice"))
+            .json(
+                &serde_json::json!({ "text": "Hi.", "sample_rate": 22050, "renditions": ["opus"] }),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_generate_reuses_identical_job() {
+        let app = TestApp::spawn().await;
+
+        let id = app.insert_completed_job("alice", b"audio").await;
+        let mp3 = EncodingOptions::new(OutputFormat::default(), None, None).unwrap();
+        sqlx::query("UPDATE jobs SET content_hash = $1 WHERE id = $2")
+            .bind(content_hash(
+                b"Hello there.",
+                "af_heart",
+                "1",
+                &mp3,
+                &HashInputs::default(),
+            ))
+            .bind(id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+
+        let generate = |username: &'static str| {
+            app.client
+                .post(app.url("/generate-json"))
+                .bearer_auth(app.token(username))
+                .json(&serde_json::json!({ "text": "Hello there." }))
+                .send()
+        };
+
+        let body: serde_json::Value = generate("alice").await.unwrap().json().await.unwrap();
+        assert_eq!(body["id"], id.to_string());
+        assert_eq!(body["reused"], true);
+
+        // Other users' jobs are never handed out
+        let body: serde_json::Value = generate("bob").await.unwrap().json().await.unwrap();
+        assert_ne!(body["id"], id.to_string());
+        assert!(body.get("reused").is_none());
+
+        // Once the file is gone the text is synthesized again
+        let file_path: String = sqlx::query("SELECT file_path FROM jobs WHERE id = $1")
+            .bind(id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap()
+            .get("file_path");
+        std::fs::remove_file(file_path).unwrap();
+        let body: serde_json::Value = generate("alice").await.unwrap().json().await.unwrap();
+        assert_ne!(body["id"], id.to_string());
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_status_transitions() {
+        // Without workers, so the job isn't claimed while queued
+        let app = TestApp::spawn_without_workers().await;
+
+        let id = app.insert_completed_job("alice", b"audio").await;
+        let set = |status: &'static str, error: Option<&'static str>| {
+            sqlx::query(
+                "UPDATE jobs SET status = $1, error_message = $2, chunks_done = 1, chunks_total = 4 WHERE id = $3",
+            )
+            .bind(status)
+            .bind(error)
+            .bind(id)
+            .execute(&app.pool)
+        };
+
+        set("queued", None).await.unwrap();
+        let (_, body) = job_status(&app, &id.to_string(), "alice").await;
+        assert_eq!(
+            body,
+            serde_json::json!({ "status": "queued", "position": 1 })
+        );
+
+        set("processing", None).await.unwrap();
+        let (_, body) = job_status(&app, &id.to_string(), "alice").await;
+        assert_eq!(body["status"], "processing");
+        assert_eq!(body["progress"], 25.0);
+        assert_eq!(body["chunks_total"], 4);
+
+        set("error", Some("kokoro-tts failed")).await.unwrap();
+        let (_, body) = job_status(&app, &id.to_string(), "alice").await;
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["message"], "kokoro-tts failed");
+
+        set("completed", None).await.unwrap();
+        let (_, body) = job_status(&app, &id.to_string(), "alice").await;
+        assert_eq!(body["status"], "completed");
+        assert_eq!(body["progress"], 100.0);
+        assert_eq!(body["download_url"], format!("/download/{}", id));
+        assert_eq!(body["file_size_bytes"], 5);
+        let expires_at: DateTime<Utc> = body["expires_at"].as_str().unwrap().parse().unwrap();
+        let retention = chrono::Duration::days(app.state.cleanup.retention_days as i64);
+        assert!((expires_at - Utc::now() - retention).num_seconds().abs() < 60);
+
+        let (status, _) = job_status(&app, "not-a-uuid", "alice").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_pin_job() {
+        let app = TestApp::spawn().await;
+
+        let id = app.insert_completed_job("alice", b"audio").await;
+        sqlx::query("UPDATE jobs SET last_accessed_at = NOW() - INTERVAL '30 days' WHERE id = $1")
+            .bind(id)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        let post = |action: &'static str, username: &'static str| {
+            app.client
+                .post(app.url(&format!("/jobs/{}/{}", id, action)))
+                .bearer_auth(app.token(username))
+                .send()
+        };
+        assert_eq!(
+            post("pin", "bob").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            post("pin", "alice").await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+
+        // Pinned jobs never expire
+        let (_, body) = job_status(&app, &id.to_string(), "alice").await;
+        assert!(body.get("expires_at").is_none(), "{}", body);
+        let report = crate::cleanup::run_cleanup(&app.pool, app.storage_path(), 7, false)
+            .await
+            .unwrap();
+        assert_eq!(report.jobs_removed, 0);
+
+        assert_eq!(
+            post("unpin", "alice").await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+        let report = crate::cleanup::run_cleanup(&app.pool, app.storage_path(), 7, false)
+            .await
+            .unwrap();
+        assert_eq!(report.jobs_removed, 1);
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_ranges() {
+        let app = TestApp::spawn().await;
+
+        let audio: Vec<u8> = (0..100).collect();
+        let id = app.insert_completed_job("alice", &audio).await;
+        let download = |range: Option<&'static str>| {
+            let mut request = app
+                .client
+                .get(app.url(&format!("/download/{}", id)))
+                .bearer_auth(app.token("alice"));
+            if let Some(range) = range {
+                request = request.header(header::RANGE, range);
+            }
+            request.send()
+        };
+
+        let resp = download(None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(resp.bytes().await.unwrap().as_ref(), audio.as_slice());
+
+        let resp = download(Some("bytes=10-19")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 10-19/100");
+        assert_eq!(resp.bytes().await.unwrap().as_ref(), &audio[10..20]);
+
+        let resp = download(Some("bytes=-5")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.bytes().await.unwrap().as_ref(), &audio[95..]);
+
+        let resp = download(Some("bytes=100-")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */100");
+
+        // A client holding the current copy gets a 304 without the body
+        let resp = download(None).await.unwrap();
+        let etag = resp.headers()[header::ETAG].clone();
+        let last_modified = resp.headers()[header::LAST_MODIFIED].clone();
+        let conditional = |name, value| {
+            app.client
+                .get(app.url(&format!("/download/{}", id)))
+                .bearer_auth(app.token("alice"))
+                .header(name, value)
+                .send()
+        };
+        let resp = conditional(header::IF_NONE_MATCH, etag.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[header::ETAG], etag);
+        assert!(resp.bytes().await.unwrap().is_empty());
+        let resp = conditional(header::IF_MODIFIED_SINCE, last_modified)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        let stale = header::HeaderValue::from_static("\"stale\"");
+        let resp = conditional(header::IF_NONE_MATCH, stale).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Other users' jobs are not found
+        let resp = app
+            .client
+            .get(app.url(&format!("/download/{}", id)))
+            .bearer_auth(app.token("bob"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        app.teardown().await;
+    }
+}
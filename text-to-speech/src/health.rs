@@ -0,0 +1,185 @@
+//! Liveness and readiness probes.
+//!
+//! `/healthz` only shows the process is serving requests. `/readyz` checks that the
+//! service can actually do work: the database answers, `storage_path` is writable, and
+//! the Kokoro model has loaded and warmed up (not required in test mode, which runs
+//! without it).
+
+use crate::inference::LoadStage;
+use crate::model_loader;
+use crate::state::{AppState, ModelState};
+use axum::{
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Each check fails rather than hanging the probe past this
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Response body for `GET /readyz`: `"ok"` or why each check failed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadinessResponse {
+    ready: bool,
+    database: String,
+    storage: String,
+    model: String,
+    /// How long the model took to warm up, once it has
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model_warmup_ms: Option<u64>,
+}
+
+/// Liveness probe: always OK while the server is responding
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    security(()),
+    responses((status = 200, body = String, content_type = "text/plain"))
+)]
+pub async fn healthz() -> &'static str {
+    "OK"
+}
+
+/// Readiness probe: `200` when every check passes, otherwise `503`
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    security(()),
+    responses(
+        (status = 200, body = ReadinessResponse),
+        (status = 503, description = "A check failed", body = ReadinessResponse),
+    )
+)]
+pub async fn readyz(State(state): State<AppState>) -> Response {
+    let (database, storage) = tokio::join!(check_database(&state), check_storage(&state));
+    let (model, model_loading) = check_model(&state).await;
+    let model_warmup_ms = state
+        .kokoro_model
+        .read()
+        .await
+        .model()
+        .and_then(|model| model_loader::warmup_ms(&model));
+
+    let results = [&database, &storage, &model];
+    let ready = results.iter().all(|result| result.is_ok());
+    let body = ReadinessResponse {
+        ready,
+        database: describe(database),
+        storage: describe(storage),
+        model: describe(model),
+        model_warmup_ms,
+    };
+    if ready {
+        return Json(body).into_response();
+    }
+
+    tracing::warn!(database = %body.database, storage = %body.storage, model = %body.model, "Not ready");
+    let mut response = (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response();
+    if model_loading {
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            model_loader::RETRY_AFTER_SECS.parse().unwrap(),
+        );
+    }
+    response
+}
+
+fn describe(result: Result<(), String>) -> String {
+    result.err().unwrap_or_else(|| "ok".to_string())
+}
+
+async fn check_database(state: &AppState) -> Result<(), String> {
+    let query = sqlx::query("SELECT 1").execute(&state.pool);
+    match tokio::time::timeout(CHECK_TIMEOUT, query).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("Database query failed: {}", e)),
+        Err(_) => Err("Database did not respond in time".to_string()),
+    }
+}
+
+/// Create and remove a file in `storage_path`
+async fn check_storage(state: &AppState) -> Result<(), String> {
+    let storage_path = state.storage_path.clone();
+    let write = tokio::task::spawn_blocking(move || {
+        tempfile::Builder::new()
+            .prefix(".readyz-")
+            .tempfile_in(&storage_path)
+            .map(drop)
+    });
+    match tokio::time::timeout(CHECK_TIMEOUT, write).await {
+        Ok(Ok(Ok(()))) => Ok(()),
+        Ok(Ok(Err(e))) => Err(format!("Storage is not writable: {}", e)),
+        Ok(Err(e)) => Err(format!("Storage check failed: {}", e)),
+        Err(_) => Err("Storage did not respond in time".to_string()),
+    }
+}
+
+/// Whether the model is usable, and whether it is still loading
+async fn check_model(state: &AppState) -> (Result<(), String>, bool) {
+    match &*state.kokoro_model.read().await {
+        ModelState::Ready(_) => (Ok(()), false),
+        ModelState::Loading { progress, .. } => {
+            let message = match progress.as_ref().map(|progress| progress.stage) {
+                Some(LoadStage::Download) => "Model files are downloading",
+                Some(LoadStage::Warmup) => "Model is warming up",
+                _ => "Model is still loading",
+            };
+            (Err(message.to_string()), true)
+        }
+        // Test mode synthesizes silence and has no model files
+        ModelState::Failed(_) if state.mock_synthesis => (Ok(()), false),
+        ModelState::Failed(message) => (Err(format!("Model failed to load: {}", message)), false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::TestApp;
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn test_probes() {
+        let app = TestApp::spawn_without_workers().await;
+
+        let resp = app.client.get(app.url("/healthz")).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The model isn't loaded in tests, but isn't needed in test mode either
+        let resp = app.client.get(app.url("/readyz")).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "ready": true, "database": "ok", "storage": "ok", "model": "ok" })
+        );
+        // The probe file doesn't linger
+        let leftover = std::fs::read_dir(app.storage_path()).unwrap().any(|entry| {
+            entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with(".readyz-")
+        });
+        assert!(!leftover);
+
+        std::fs::remove_dir_all(app.storage_path()).unwrap();
+        let resp = app.client.get(app.url("/readyz")).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["database"], "ok");
+        assert!(
+            body["storage"]
+                .as_str()
+                .unwrap()
+                .starts_with("Storage is not writable")
+        );
+
+        app.teardown().await;
+    }
+}
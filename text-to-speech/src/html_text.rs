@@ -0,0 +1,80 @@
+//! Readable text from HTML, shared by EPUB and web page ingestion.
+
+use scraper::{ElementRef, Node};
+
+/// Elements that start a new line
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "br",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "tr",
+    "blockquote",
+    "pre",
+    "hr",
+    "dt",
+    "dd",
+];
+
+/// The text under `element`, one line per block, leaving out elements `skip` returns
+/// true for (and everything inside them)
+pub fn text_of(element: ElementRef, skip: &dyn Fn(ElementRef) -> bool) -> String {
+    let mut raw = String::new();
+    collect_text(*element, skip, &mut raw);
+    raw.lines()
+        .map(collapse_whitespace)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn collect_text(
+    node: ego_tree::NodeRef<Node>,
+    skip: &dyn Fn(ElementRef) -> bool,
+    out: &mut String,
+) {
+    match node.value() {
+        // Line breaks in the source are just whitespace; blocks start new lines
+        Node::Text(text) => out.push_str(&text.replace(['\n', '\r'], " ")),
+        Node::Element(element) => {
+            let element_ref = ElementRef::wrap(node).expect("element node");
+            if skip(element_ref) {
+                return;
+            }
+            let is_block = BLOCK_ELEMENTS.contains(&element.name());
+            if is_block {
+                out.push('\n');
+            }
+            for child in node.children() {
+                collect_text(child, skip, out);
+            }
+            if is_block {
+                out.push('\n');
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Whether `element` has one of `types` in its `epub:type` or ARIA `role`
+pub fn has_type(element: ElementRef, types: &[&str]) -> bool {
+    let value = element.value();
+    [value.attr("epub:type"), value.attr("role")]
+        .into_iter()
+        .flatten()
+        .flat_map(str::split_whitespace)
+        .any(|t| types.contains(&t))
+}
@@ -2,30 +2,64 @@
 //!
 //! This module loads the Kokoro ONNX model and voice embeddings,
 //! then provides synthesis functionality returning PCM f32 audio at 24kHz.
+//!
+//! The model runs on the CPU unless `KOKORO_EXECUTION_PROVIDER` asks for `cuda` or `rocm`,
+//! which needs a build with the matching cargo feature and an ONNX Runtime with that
+//! provider. If the provider can't be set up, the model falls back to the CPU.
+//!
+//! An ONNX session runs one inference at a time, so the model keeps a pool of
+//! `KOKORO_SESSIONS` sessions, each a full copy of the model, and a synthesis waits for a
+//! free one. More sessions let WebSocket clients and batch jobs synthesize in parallel.
+//!
+//! A quantized (int8 or fp16) export of the model can be loaded alongside the full one
+//! from `KOKORO_FAST_MODEL_PATH`, for nodes too slow to stream the full model in real
+//! time. Requests pick it with the `fast` quality; `KOKORO_QUALITY` sets the default.
+//! Once loaded, each model synthesizes a test sentence and logs how fast it ran.
+//!
+//! ONNX Runtime sets up much of a session on its first run, and voices are parsed on first
+//! use, so a cold model answers its first requests slowly. Before the model is marked
+//! ready, the voices in `KOKORO_WARMUP` each synthesize a short phrase on every session.
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use ndarray::{Array1, Array2};
-use ort::session::Session;
+use ort::ep::{CUDA, ExecutionProvider, ROCm};
+use ort::session::{RunOptions, Session};
 use ort::value::Value;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Cursor, Read};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
 
 /// Sample rate for Kokoro output audio
 pub const SAMPLE_RATE: u32 = 24000;
 
+/// "Hello, this is a test of the speech model." for the startup benchmark and warmup
+const BENCHMARK_PHONEMES: &str = "həlˈoʊ, ðɪs ɪz ɐ tˈɛst ʌv ðə spˈiːtʃ mˈɑːdəl.";
+
+/// Names exports of the model give their output of each token's predicted duration
+const DURATION_OUTPUTS: &[&str] = &["durations", "duration", "pred_dur"];
+
 /// Phoneme to token ID mapping from the Kokoro model's config.json.
 /// IDs are non-contiguous and must match exactly what the model expects.
 fn build_vocab() -> HashMap<char, i64> {
     let entries: &[(char, i64)] = &[
         // Punctuation
-        (';', 1), (':', 2), (',', 3), ('.', 4), ('!', 5), ('?', 6),
-        ('\u{2014}', 9), // — em dash
+        (';', 1),
+        (':', 2),
+        (',', 3),
+        ('.', 4),
+        ('!', 5),
+        ('?', 6),
+        ('\u{2014}', 9),  // — em dash
         ('\u{2026}', 10), // … ellipsis
         ('"', 11),
-        ('(', 12), (')', 13),
+        ('(', 12),
+        (')', 13),
         ('\u{201C}', 14), // " left double quote
         ('\u{201D}', 15), // " right double quote
         (' ', 16),
@@ -38,14 +72,40 @@ fn build_vocab() -> HashMap<char, i64> {
         ('\u{1D5D}', 22), // ᵝ
         ('\u{AB67}', 23), // ꭧ
         // Uppercase letters (sparse)
-        ('A', 24), ('I', 25), ('O', 31), ('Q', 33), ('S', 35), ('T', 36),
-        ('W', 39), ('Y', 41),
+        ('A', 24),
+        ('I', 25),
+        ('O', 31),
+        ('Q', 33),
+        ('S', 35),
+        ('T', 36),
+        ('W', 39),
+        ('Y', 41),
         ('\u{1D4A}', 42), // ᵊ
         // Lowercase letters
-        ('a', 43), ('b', 44), ('c', 45), ('d', 46), ('e', 47), ('f', 48),
-        ('h', 50), ('i', 51), ('j', 52), ('k', 53), ('l', 54), ('m', 55),
-        ('n', 56), ('o', 57), ('p', 58), ('q', 59), ('r', 60), ('s', 61),
-        ('t', 62), ('u', 63), ('v', 64), ('w', 65), ('x', 66), ('y', 67),
+        ('a', 43),
+        ('b', 44),
+        ('c', 45),
+        ('d', 46),
+        ('e', 47),
+        ('f', 48),
+        ('h', 50),
+        ('i', 51),
+        ('j', 52),
+        ('k', 53),
+        ('l', 54),
+        ('m', 55),
+        ('n', 56),
+        ('o', 57),
+        ('p', 58),
+        ('q', 59),
+        ('r', 60),
+        ('s', 61),
+        ('t', 62),
+        ('u', 63),
+        ('v', 64),
+        ('w', 65),
+        ('x', 66),
+        ('y', 67),
         ('z', 68),
         // IPA vowels and consonants
         ('\u{0251}', 69),  // ɑ
@@ -121,76 +181,200 @@ fn build_vocab() -> HashMap<char, i64> {
 
 /// Maximum number of style token positions in voice embeddings
 const MAX_STYLE_TOKENS: usize = 510;
+/// Parsed voices kept in memory unless `KOKORO_VOICE_CACHE` says otherwise
+const DEFAULT_VOICE_CACHE: usize = 8;
 /// Embedding dimension per style vector
 const EMBEDDING_DIM: usize = 256;
 
-/// Voice embedding data structure
-/// Loaded from voices-v1.0.bin (NPZ format — ZIP archive of NumPy arrays)
-/// Each voice has shape (510, 1, 256) — 510 style vectors indexed by token count
+/// Voice embeddings in voices-v1.0.bin (NPZ format — ZIP archive of NumPy arrays).
+/// Each voice has shape (510, 1, 256) — 510 style vectors indexed by token count.
+///
+/// Only the archive's directory is read at load; a voice is parsed on first use and kept
+/// in a small LRU cache, so startup is quick and memory holds just the voices in use.
 struct VoiceEmbeddings {
-    /// Map from voice name to style matrix
-    /// Outer Vec has 510 entries (one per token position), each is 256 floats
-    embeddings: HashMap<String, Vec<Vec<f32>>>,
+    path: PathBuf,
+    /// Name in the archive of each voice's entry. Entries are read by name, so they are
+    /// found even if the file is replaced with one in another order.
+    entries: HashMap<String, String>,
+    cache: Mutex<VoiceCache>,
+}
+
+/// Parsed voices, least recently used first
+struct VoiceCache {
+    capacity: usize,
+    voices: Vec<(String, Arc<Vec<f32>>)>,
+}
+
+impl VoiceCache {
+    fn get(&mut self, voice: &str) -> Option<Arc<Vec<f32>>> {
+        let position = self.voices.iter().position(|(name, _)| name == voice)?;
+        let entry = self.voices.remove(position);
+        let matrix = entry.1.clone();
+        self.voices.push(entry);
+        Some(matrix)
+    }
+
+    fn insert(&mut self, voice: &str, matrix: Arc<Vec<f32>>) {
+        // Another thread may have parsed the voice at the same time
+        self.voices.retain(|(name, _)| name != voice);
+        if self.voices.len() >= self.capacity {
+            self.voices.remove(0);
+        }
+        self.voices.push((voice.to_string(), matrix));
+    }
 }
 
 impl VoiceEmbeddings {
+    #[cfg(test)]
     fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
-        let file =
-            File::open(path).map_err(|e| format!("Failed to open voices file: {}", e))?;
-
-        let mut archive =
-            zip::ZipArchive::new(file).map_err(|e| format!("Failed to read voices ZIP: {}", e))?;
-
-        let mut embeddings = HashMap::new();
-
-        for i in 0..archive.len() {
-            let mut entry = archive
-                .by_index(i)
-                .map_err(|e| format!("Failed to read ZIP entry {}: {}", i, e))?;
-
-            let name = entry.name().to_string();
-            // Strip .npy extension to get voice name
-            let voice_name = name.strip_suffix(".npy").unwrap_or(&name).to_string();
-
-            // Read the full NPY file into memory
-            let mut npy_data = Vec::new();
-            entry
-                .read_to_end(&mut npy_data)
-                .map_err(|e| format!("Failed to read NPY data for {}: {}", voice_name, e))?;
-
-            // Parse NPY format
-            let floats = parse_npy_f32(&npy_data)
-                .map_err(|e| format!("Failed to parse NPY for {}: {}", voice_name, e))?;
-
-            // Reshape from flat (510 * 1 * 256) into Vec<Vec<f32>> of 510 x 256
-            let num_positions = floats.len() / EMBEDDING_DIM;
-            let mut style_matrix = Vec::with_capacity(num_positions);
-            for pos in 0..num_positions {
-                let start = pos * EMBEDDING_DIM;
-                let end = start + EMBEDDING_DIM;
-                style_matrix.push(floats[start..end].to_vec());
-            }
+        Self::load_with_progress(path, DEFAULT_VOICE_CACHE, |_, _| {})
+    }
+
+    /// List the voices in the archive, calling `on_voice(loaded, total)` once they are
+    fn load_with_progress<P: AsRef<Path>>(
+        path: P,
+        cache_capacity: usize,
+        mut on_voice: impl FnMut(usize, usize),
+    ) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let archive = Self::open(&path)?;
+        let entries: HashMap<String, String> = archive
+            .file_names()
+            .map(|name| {
+                // Strip .npy extension to get voice name
+                let voice_name = name.strip_suffix(".npy").unwrap_or(name).to_string();
+                (voice_name, name.to_string())
+            })
+            .collect();
+        on_voice(entries.len(), entries.len());
+
+        tracing::info!("Found {} voice embeddings in NPZ", entries.len());
+        Ok(Self {
+            path,
+            entries,
+            cache: Mutex::new(VoiceCache {
+                capacity: cache_capacity.max(1),
+                voices: Vec::new(),
+            }),
+        })
+    }
+
+    fn open(path: &Path) -> Result<zip::ZipArchive<File>, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open voices file: {}", e))?;
+        zip::ZipArchive::new(file).map_err(|e| format!("Failed to read voices ZIP: {}", e))
+    }
+
+    fn contains(&self, voice: &str) -> bool {
+        self.entries.contains_key(voice)
+    }
 
-            embeddings.insert(voice_name, style_matrix);
+    fn cache(&self) -> Result<std::sync::MutexGuard<'_, VoiceCache>, String> {
+        self.cache
+            .lock()
+            .map_err(|e| format!("Failed to lock voice cache: {}", e))
+    }
+
+    /// The flat (positions x 256) style matrix of a voice, parsed if it isn't cached. The
+    /// cache is only locked to look the voice up and to add it, so other voices aren't held
+    /// up while one is parsed.
+    fn voice(&self, voice: &str) -> Result<Arc<Vec<f32>>, String> {
+        let name = self
+            .entries
+            .get(voice)
+            .ok_or_else(|| format!("Unknown voice: {}", voice))?;
+        if let Some(matrix) = self.cache()?.get(voice) {
+            return Ok(matrix);
         }
 
-        tracing::info!("Loaded {} voice embeddings from NPZ", embeddings.len());
-        Ok(Self { embeddings })
+        let mut archive = Self::open(&self.path)?;
+        let mut entry = archive
+            .by_name(name)
+            .map_err(|e| format!("Failed to read ZIP entry {}: {}", name, e))?;
+        // Read the full NPY file into memory
+        let mut npy_data = Vec::new();
+        entry
+            .read_to_end(&mut npy_data)
+            .map_err(|e| format!("Failed to read NPY data for {}: {}", voice, e))?;
+        let floats = parse_npy_f32(&npy_data)
+            .map_err(|e| format!("Failed to parse NPY for {}: {}", voice, e))?;
+        if floats.len() < EMBEDDING_DIM {
+            return Err(format!("Voice {} has no style vectors", voice));
+        }
+        tracing::debug!(voice, "Parsed voice embedding");
+        let matrix = Arc::new(floats);
+        self.cache()?.insert(voice, matrix.clone());
+        Ok(matrix)
+    }
+
+    /// Get the style embedding for a voice, indexed by token count
+    #[cfg(test)]
+    fn get(&self, voice: &str, token_len: usize) -> Result<Vec<f32>, String> {
+        Ok(style_vector(&self.voice(voice)?, token_len).to_vec())
     }
+}
+
+/// Whether the voices file at `path` has `voice`, for when no model has it loaded. A file
+/// that can't be read has no voices.
+pub fn voices_file_has(path: &Path, voice: &str) -> bool {
+    VoiceEmbeddings::open(path).is_ok_and(|archive| {
+        archive
+            .file_names()
+            .any(|name| name.strip_suffix(".npy").unwrap_or(name) == voice)
+    })
+}
+
+/// The style vector of a (positions x 256) voice matrix for an input of `token_len`
+/// tokens, matching the Python behavior: `voice[len(tokens)]`
+fn style_vector(matrix: &[f32], token_len: usize) -> &[f32] {
+    let positions = matrix.len() / EMBEDDING_DIM;
+    let start = token_len.min(positions - 1) * EMBEDDING_DIM;
+    &matrix[start..start + EMBEDDING_DIM]
+}
 
-    /// Get the style embedding for a voice, indexed by token count.
-    /// The style vector is selected based on the number of tokens in the input,
-    /// matching the Python behavior: `voice[len(tokens)]`
-    fn get(&self, voice: &str, token_len: usize) -> Option<Vec<f32>> {
-        let matrix = self.embeddings.get(voice)?;
-        let idx = token_len.min(matrix.len() - 1);
-        Some(matrix[idx].clone())
+/// Parse an uploaded voice embedding: a .npy file of little-endian f32 with the same
+/// (510, 1, 256) shape as the voices in the voices file
+pub fn parse_voice_npy(data: &[u8]) -> Result<Vec<f32>, String> {
+    let (header, _) = npy_header(data)?;
+    let field = |name: &str| {
+        header
+            .split_once(&format!("'{}':", name))
+            .map(|(_, rest)| rest.trim_start())
+    };
+    if !field("descr").is_some_and(|descr| descr.starts_with("'<f4'")) {
+        return Err("Voice embedding must be little-endian float32 ('<f4')".to_string());
+    }
+    if field("fortran_order").is_some_and(|order| order.starts_with("True")) {
+        return Err("Voice embedding must be in C order".to_string());
+    }
+    let shape = field("shape")
+        .and_then(|rest| rest.strip_prefix('('))
+        .and_then(|rest| rest.split_once(')'))
+        .map(|(dims, _)| dims.replace(' ', ""))
+        .ok_or("NPY header has no shape")?;
+    let expected = format!("{},1,{}", MAX_STYLE_TOKENS, EMBEDDING_DIM);
+    if shape.trim_end_matches(',') != expected {
+        return Err(format!(
+            "Voice embedding has shape ({}), expected ({}, 1, {})",
+            shape, MAX_STYLE_TOKENS, EMBEDDING_DIM
+        ));
+    }
+    let floats = parse_npy_f32(data)?;
+    if floats.len() != MAX_STYLE_TOKENS * EMBEDDING_DIM {
+        return Err(format!(
+            "Voice embedding has {} values, expected {}",
+            floats.len(),
+            MAX_STYLE_TOKENS * EMBEDDING_DIM
+        ));
+    }
+    if !floats.iter().all(|f| f.is_finite()) {
+        return Err("Voice embedding contains NaN or infinite values".to_string());
     }
+    Ok(floats)
 }
 
-/// Parse a NumPy .npy file and extract f32 data.
+/// The header string of a NumPy .npy file and where its data starts.
 /// NPY format: magic (\x93NUMPY) + version (2 bytes) + header_len + header string + raw data
-fn parse_npy_f32(data: &[u8]) -> Result<Vec<f32>, String> {
+fn npy_header(data: &[u8]) -> Result<(&str, usize), String> {
     if data.len() < 10 {
         return Err("NPY file too short".to_string());
     }
@@ -227,6 +411,15 @@ fn parse_npy_f32(data: &[u8]) -> Result<Vec<f32>, String> {
         ));
     }
 
+    let header = std::str::from_utf8(&data[header_offset..data_start])
+        .map_err(|_| "NPY header is not valid text".to_string())?;
+    Ok((header, data_start))
+}
+
+/// Parse a NumPy .npy file and extract f32 data
+fn parse_npy_f32(data: &[u8]) -> Result<Vec<f32>, String> {
+    let (_, data_start) = npy_header(data)?;
+
     // Read raw f32 little-endian data
     let raw = &data[data_start..];
     let num_floats = raw.len() / 4;
@@ -242,36 +435,534 @@ fn parse_npy_f32(data: &[u8]) -> Result<Vec<f32>, String> {
     Ok(floats)
 }
 
+/// Stage of a model load in progress
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadStage {
+    /// Downloading missing model files
+    Download,
+    /// Building the ONNX Runtime session from the model file
+    Session,
+    /// Listing the voices in the NPZ archive
+    Voices,
+    /// Synthesizing with the warmup voices, counted in `voices_loaded`
+    Warmup,
+}
+
+/// Where the model runs
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Accelerator {
+    #[default]
+    Cpu,
+    Cuda {
+        device_id: i32,
+    },
+    Rocm {
+        device_id: i32,
+    },
+}
+
+impl Accelerator {
+    /// Parse `KOKORO_EXECUTION_PROVIDER` (`cpu`, the default, `cuda` or `rocm`) and
+    /// `KOKORO_GPU_DEVICE` (default 0)
+    pub fn parse(provider: Option<&str>, device_id: Option<&str>) -> Result<Self, String> {
+        let device_id = match device_id.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(v) => v
+                .parse::<i32>()
+                .ok()
+                .filter(|&id| id >= 0)
+                .ok_or_else(|| format!("Invalid KOKORO_GPU_DEVICE '{}'", v))?,
+            None => 0,
+        };
+        match provider.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("cpu") => Ok(Self::Cpu),
+            Some("cuda") => Ok(Self::Cuda { device_id }),
+            Some("rocm") => Ok(Self::Rocm { device_id }),
+            Some(other) => Err(format!(
+                "Invalid KOKORO_EXECUTION_PROVIDER '{}', expected cpu, cuda or rocm",
+                other
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Cpu => "cpu",
+            Self::Cuda { .. } => "cuda",
+            Self::Rocm { .. } => "rocm",
+        }
+    }
+}
+
+/// Which of the loaded models synthesizes a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    /// The full model
+    #[default]
+    High,
+    /// The quantized model, or the full one if there is none
+    Fast,
+}
+
+impl Quality {
+    fn name(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Fast => "fast",
+        }
+    }
+}
+
+/// How the model's ONNX sessions are set up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuntimeConfig {
+    pub accelerator: Accelerator,
+    /// Sessions in the pool, the number of syntheses that can run at once
+    pub sessions: usize,
+    /// Used by requests that don't ask for a quality
+    pub default_quality: Quality,
+    /// Parsed voices kept in memory
+    pub voice_cache: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            accelerator: Accelerator::Cpu,
+            sessions: 1,
+            default_quality: Quality::High,
+            voice_cache: DEFAULT_VOICE_CACHE,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// Parse `KOKORO_EXECUTION_PROVIDER`, `KOKORO_GPU_DEVICE`, `KOKORO_SESSIONS` (default 1),
+    /// `KOKORO_QUALITY` (`high`, the default, or `fast`) and `KOKORO_VOICE_CACHE` (default 8)
+    pub fn parse(
+        provider: Option<&str>,
+        device_id: Option<&str>,
+        sessions: Option<&str>,
+        quality: Option<&str>,
+        voice_cache: Option<&str>,
+    ) -> Result<Self, String> {
+        let positive = |value: Option<&str>, name: &str, default: usize| match value
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+        {
+            Some(v) => v
+                .parse::<usize>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("Invalid {} '{}'", name, v)),
+            None => Ok(default),
+        };
+        let sessions = positive(sessions, "KOKORO_SESSIONS", 1)?;
+        let voice_cache = positive(voice_cache, "KOKORO_VOICE_CACHE", DEFAULT_VOICE_CACHE)?;
+        let default_quality = match quality.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("high") => Quality::High,
+            Some("fast") => Quality::Fast,
+            Some(other) => {
+                return Err(format!(
+                    "Invalid KOKORO_QUALITY '{}', expected high or fast",
+                    other
+                ));
+            }
+        };
+        Ok(Self {
+            accelerator: Accelerator::parse(provider, device_id)?,
+            sessions,
+            default_quality,
+            voice_cache,
+        })
+    }
+}
+
+/// Which voices warm the model up after it loads
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warmup {
+    None,
+    All,
+    Voices(Vec<String>),
+}
+
+impl Default for Warmup {
+    fn default() -> Self {
+        Self::Voices(vec!["af_heart".to_string()])
+    }
+}
+
+impl Warmup {
+    /// Parse `KOKORO_WARMUP`: `none`, `all`, or a comma-separated list of voices
+    /// (default `af_heart`)
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        let value = value.map(str::trim).unwrap_or_default();
+        match value.to_ascii_lowercase().as_str() {
+            "" => Ok(Self::default()),
+            "none" | "off" => Ok(Self::None),
+            "all" => Ok(Self::All),
+            _ => {
+                let voices: Vec<String> = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|voice| !voice.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if voices.is_empty() {
+                    return Err(format!("Invalid KOKORO_WARMUP '{}'", value));
+                }
+                Ok(Self::Voices(voices))
+            }
+        }
+    }
+}
+
+/// Items handed out to one user at a time, e.g. ONNX sessions
+struct Pool<T> {
+    idle: Mutex<Vec<T>>,
+    returned: Condvar,
+    /// Items in the pool, taken or not
+    size: usize,
+}
+
+impl<T> Pool<T> {
+    fn new(items: Vec<T>) -> Self {
+        Self {
+            size: items.len(),
+            idle: Mutex::new(items),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Take an item, waiting for one to be returned if all are in use
+    fn take(&self) -> Result<Pooled<'_, T>, String> {
+        let mut idle = self
+            .idle
+            .lock()
+            .map_err(|e| format!("Failed to lock session pool: {}", e))?;
+        loop {
+            if let Some(item) = idle.pop() {
+                return Ok(Pooled {
+                    pool: self,
+                    item: Some(item),
+                });
+            }
+            idle = self
+                .returned
+                .wait(idle)
+                .map_err(|e| format!("Failed to lock session pool: {}", e))?;
+        }
+    }
+}
+
+/// An item taken from a [`Pool`], returned to it when dropped
+struct Pooled<'a, T> {
+    pool: &'a Pool<T>,
+    item: Option<T>,
+}
+
+impl<T> Deref for Pooled<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("item is only taken on drop")
+    }
+}
+
+impl<T> DerefMut for Pooled<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().expect("item is only taken on drop")
+    }
+}
+
+impl<T> Drop for Pooled<'_, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            // A poisoned lock still holds a usable list
+            let mut idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+            idle.push(item);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+/// The pool of `runtime.sessions` sessions of the model at `model_path`. The first session
+/// settles which provider the others use.
+fn create_sessions(model_path: &Path, runtime: RuntimeConfig) -> Result<Pool<Session>, String> {
+    let (first, accelerator) = create_session(model_path, runtime.accelerator)?;
+    let mut sessions = vec![first];
+    for _ in 1..runtime.sessions {
+        sessions.push(try_create_session(model_path, accelerator)?);
+    }
+    tracing::info!(sessions = sessions.len(), "Created ONNX sessions");
+    Ok(Pool::new(sessions))
+}
+
+/// Create the ONNX session on `accelerator`, or on the CPU if that doesn't work out,
+/// returning where it ended up
+fn create_session(
+    model_path: &Path,
+    accelerator: Accelerator,
+) -> Result<(Session, Accelerator), String> {
+    if accelerator != Accelerator::Cpu {
+        match try_create_session(model_path, accelerator) {
+            Ok(session) => {
+                tracing::info!(
+                    provider = accelerator.name(),
+                    ?accelerator,
+                    "Selected ONNX execution provider"
+                );
+                return Ok((session, accelerator));
+            }
+            Err(e) => tracing::warn!(
+                provider = accelerator.name(),
+                error = %e,
+                "Execution provider unavailable, falling back to CPU"
+            ),
+        }
+    }
+    let session = try_create_session(model_path, Accelerator::Cpu)?;
+    tracing::info!(provider = "cpu", "Selected ONNX execution provider");
+    Ok((session, Accelerator::Cpu))
+}
+
+fn try_create_session(model_path: &Path, accelerator: Accelerator) -> Result<Session, String> {
+    let mut builder = Session::builder()
+        .map_err(|e| format!("Failed to create ORT session builder: {}", e))?
+        .with_intra_threads(4)
+        .map_err(|e| format!("Failed to set thread count: {}", e))?;
+    // Registered by hand rather than with `with_execution_providers`, which only logs
+    // failures, so that a missing provider is noticed and reported
+    let registered = match accelerator {
+        Accelerator::Cpu => Ok(()),
+        Accelerator::Cuda { device_id } => CUDA::default()
+            .with_device_id(device_id)
+            .register(&mut builder),
+        Accelerator::Rocm { device_id } => ROCm::default()
+            .with_device_id(device_id)
+            .register(&mut builder),
+    };
+    registered.map_err(|e| format!("Failed to register execution provider: {}", e))?;
+    builder
+        .commit_from_file(model_path)
+        .map_err(|e| format!("Failed to load ONNX model: {}", e))
+}
+
+/// Snapshot of model load progress, reported while `KokoroModel::load_with_progress` runs
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadProgress {
+    pub stage: LoadStage,
+    pub voices_loaded: usize,
+    pub voices_total: usize,
+}
+
 /// Kokoro TTS model
 pub struct KokoroModel {
-    session: Mutex<Session>,
+    /// Name of the model file without its extension, e.g. `kokoro-v1.0`
+    version: String,
+    sessions: Pool<Session>,
+    /// Sessions of the quantized model, if one is configured
+    fast_sessions: Option<Pool<Session>>,
+    default_quality: Quality,
     voices: VoiceEmbeddings,
     vocab: HashMap<char, i64>,
+    /// How long the last warmup took
+    warmup_time: OnceLock<Duration>,
 }
 
 impl KokoroModel {
     /// Load the Kokoro ONNX model and voice embeddings
+    #[cfg(test)]
     pub fn load<P: AsRef<Path>>(model_path: P, voices_path: P) -> Result<Arc<Self>, String> {
-        tracing::info!("Loading Kokoro ONNX model...");
+        Self::load_with_progress(
+            model_path,
+            voices_path,
+            None,
+            RuntimeConfig::default(),
+            |_| {},
+        )
+    }
 
-        let session = Session::builder()
-            .map_err(|e| format!("Failed to create ORT session builder: {}", e))?
-            .with_intra_threads(4)
-            .map_err(|e| format!("Failed to set thread count: {}", e))?
-            .commit_from_file(model_path)
-            .map_err(|e| format!("Failed to load ONNX model: {}", e))?;
+    /// Load the Kokoro ONNX model, the quantized one at `fast_model_path` if given, and
+    /// the voice embeddings, reporting progress as it goes
+    pub fn load_with_progress<P: AsRef<Path>>(
+        model_path: P,
+        voices_path: P,
+        fast_model_path: Option<P>,
+        runtime: RuntimeConfig,
+        mut on_progress: impl FnMut(LoadProgress),
+    ) -> Result<Arc<Self>, String> {
+        tracing::info!("Loading Kokoro ONNX model...");
+        on_progress(LoadProgress {
+            stage: LoadStage::Session,
+            voices_loaded: 0,
+            voices_total: 0,
+        });
+
+        let sessions = create_sessions(model_path.as_ref(), runtime)?;
+        let fast_sessions = match fast_model_path {
+            Some(path) => {
+                tracing::info!(path = %path.as_ref().display(), "Loading quantized Kokoro model");
+                Some(create_sessions(path.as_ref(), runtime)?)
+            }
+            None => None,
+        };
+        if runtime.default_quality == Quality::Fast && fast_sessions.is_none() {
+            tracing::warn!("KOKORO_QUALITY is fast but no KOKORO_FAST_MODEL_PATH is set");
+        }
 
-        let voices = VoiceEmbeddings::load(voices_path)?;
+        let voices = VoiceEmbeddings::load_with_progress(
+            voices_path,
+            runtime.voice_cache,
+            |loaded, total| {
+                on_progress(LoadProgress {
+                    stage: LoadStage::Voices,
+                    voices_loaded: loaded,
+                    voices_total: total,
+                })
+            },
+        )?;
         let vocab = build_vocab();
 
-        tracing::info!("Kokoro model loaded successfully");
+        let version = model_path
+            .as_ref()
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        tracing::info!(version, "Kokoro model loaded successfully");
         Ok(Arc::new(Self {
-            session: Mutex::new(session),
+            version,
+            sessions,
+            fast_sessions,
+            default_quality: runtime.default_quality,
             voices,
             vocab,
+            warmup_time: OnceLock::new(),
         }))
     }
 
+    fn sessions(&self, quality: Quality) -> &Pool<Session> {
+        match (quality, &self.fast_sessions) {
+            (Quality::Fast, Some(fast)) => fast,
+            _ => &self.sessions,
+        }
+    }
+
+    /// Time a test sentence with each loaded model and log how much faster than real time
+    /// it ran, to tell whether a node can stream live audio
+    pub fn benchmark(&self) {
+        let Some(voice) = self.voice_names().into_iter().next() else {
+            return;
+        };
+        let qualities: &[Quality] = match self.fast_sessions {
+            Some(_) => &[Quality::High, Quality::Fast],
+            None => &[Quality::High],
+        };
+        for &quality in qualities {
+            let started = std::time::Instant::now();
+            match self.synthesize_with(BENCHMARK_PHONEMES, &voice, 1.0, Some(quality), None) {
+                Ok(synthesis) => {
+                    let elapsed = started.elapsed().as_secs_f64();
+                    let audio_secs = synthesis.audio.len() as f64 / SAMPLE_RATE as f64;
+                    tracing::info!(
+                        quality = quality.name(),
+                        elapsed_ms = (elapsed * 1000.0).round(),
+                        audio_secs,
+                        realtime_factor = audio_secs / elapsed.max(f64::EPSILON),
+                        "Benchmarked Kokoro model"
+                    );
+                }
+                Err(e) => tracing::warn!(quality = quality.name(), error = %e, "Benchmark failed"),
+            }
+        }
+    }
+
+    /// Synthesize a short phrase with each voice of `warmup` on every session, so that the
+    /// first requests don't pay for ONNX Runtime's lazy setup or for parsing their voice.
+    /// Reports `(voices warmed, voices to warm)` as it goes. A failed synthesis is logged
+    /// but doesn't fail the warmup.
+    pub fn warmup(&self, warmup: &Warmup, mut on_progress: impl FnMut(usize, usize)) {
+        let voices: Vec<String> = match warmup {
+            Warmup::None => return,
+            Warmup::All => self.voice_names(),
+            Warmup::Voices(names) => names
+                .iter()
+                .filter(|name| {
+                    let known = self.has_voice(name);
+                    if !known {
+                        tracing::warn!(voice = %name, "Skipping warmup with unknown voice");
+                    }
+                    known
+                })
+                .cloned()
+                .collect(),
+        };
+        let pools: Vec<(Quality, &Pool<Session>)> =
+            std::iter::once((Quality::High, &self.sessions))
+                .chain(self.fast_sessions.iter().map(|pool| (Quality::Fast, pool)))
+                .collect();
+
+        let started = Instant::now();
+        for (warmed, voice) in voices.iter().enumerate() {
+            on_progress(warmed, voices.len());
+            for &(quality, pool) in &pools {
+                // As many syntheses at once as there are sessions, so each session runs one
+                std::thread::scope(|scope| {
+                    for _ in 0..pool.size {
+                        scope.spawn(|| {
+                            let result = self.synthesize_with(
+                                BENCHMARK_PHONEMES,
+                                voice,
+                                1.0,
+                                Some(quality),
+                                None,
+                            );
+                            if let Err(e) = result {
+                                tracing::warn!(
+                                    voice,
+                                    quality = quality.name(),
+                                    error = %e,
+                                    "Warmup synthesis failed"
+                                );
+                            }
+                        });
+                    }
+                });
+            }
+        }
+        on_progress(voices.len(), voices.len());
+
+        let elapsed = started.elapsed();
+        tracing::info!(
+            voices = voices.len(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Warmed up Kokoro model"
+        );
+        let _ = self.warmup_time.set(elapsed);
+    }
+
+    /// How long the warmup took, if the model was warmed up
+    pub fn warmup_time(&self) -> Option<Duration> {
+        self.warmup_time.get().copied()
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Names of the loaded voices, sorted
+    pub fn voice_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.voices.entries.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Whether a voice with this name was loaded
+    pub fn has_voice(&self, voice: &str) -> bool {
+        self.voices.contains(voice)
+    }
+
     /// Convert text phonemes to token IDs
     fn phonemes_to_tokens(&self, phonemes: &str) -> Vec<i64> {
         phonemes_to_tokens(&self.vocab, phonemes)
@@ -280,27 +971,95 @@ impl KokoroModel {
     /// Synthesize audio from phonemes
     ///
     /// Returns PCM f32 samples at 24kHz sample rate
+    #[cfg(test)]
     pub fn synthesize(&self, phonemes: &str, voice: &str, speed: f32) -> Result<Vec<f32>, String> {
+        self.synthesize_with(phonemes, voice, speed, None, None)
+            .map(|synthesis| synthesis.audio)
+    }
+
+    /// [`Self::synthesize`], with when each phoneme is spoken if the model outputs its
+    /// durations, at `quality` or the default, stopping early with an error once `cancel`
+    /// is cancelled. Phonemes too long for the model in one go, e.g. of a run-on sentence,
+    /// are split at phrase or word boundaries and synthesized piece by piece.
+    pub fn synthesize_with(
+        &self,
+        phonemes: &str,
+        voice: &str,
+        speed: f32,
+        quality: Option<Quality>,
+        cancel: Option<&Cancel>,
+    ) -> Result<Synthesis, String> {
+        let matrix = self.voices.voice(voice)?;
+        self.synthesize_embedding(phonemes, &matrix, speed, quality, cancel)
+    }
+
+    /// [`Self::synthesize_with`] a voice given as its (510 x 256) style matrix rather
+    /// than by name, e.g. a user's [custom voice](crate::custom_voices)
+    pub fn synthesize_embedding(
+        &self,
+        phonemes: &str,
+        embedding: &[f32],
+        speed: f32,
+        quality: Option<Quality>,
+        cancel: Option<&Cancel>,
+    ) -> Result<Synthesis, String> {
+        let pieces = split_phonemes(&self.vocab, phonemes, MAX_STYLE_TOKENS - 2);
+        if pieces.len() == 1 {
+            return self.synthesize_piece(phonemes, embedding, speed, quality, cancel);
+        }
+        tracing::debug!(pieces = pieces.len(), "Splitting over-long phonemes");
+        let mut synthesis = Synthesis {
+            audio: Vec::new(),
+            phoneme_spans: Some(Vec::with_capacity(phonemes.chars().count())),
+        };
+        for piece in pieces {
+            let part = self.synthesize_piece(piece, embedding, speed, quality, cancel)?;
+            let offset = synthesis.audio.len();
+            // A piece too short to synthesize has no spans either, but takes no time
+            let part_spans = match part.phoneme_spans {
+                Some(spans) => Some(spans),
+                None if part.audio.is_empty() => Some(vec![(0, 0); piece.chars().count()]),
+                None => None,
+            };
+            synthesis.phoneme_spans = match (synthesis.phoneme_spans, part_spans) {
+                (Some(mut spans), Some(part_spans)) => {
+                    spans.extend(
+                        part_spans
+                            .into_iter()
+                            .map(|(start, end)| (start + offset, end + offset)),
+                    );
+                    Some(spans)
+                }
+                _ => None,
+            };
+            synthesis.audio.extend(part.audio);
+        }
+        Ok(synthesis)
+    }
+
+    /// Synthesize phonemes that fit in the model's input
+    fn synthesize_piece(
+        &self,
+        phonemes: &str,
+        embedding: &[f32],
+        speed: f32,
+        quality: Option<Quality>,
+        cancel: Option<&Cancel>,
+    ) -> Result<Synthesis, String> {
         // Convert phonemes to tokens first (needed for style lookup)
         let tokens = self.phonemes_to_tokens(phonemes);
         let seq_len = tokens.len();
 
         if seq_len < 3 {
-            return Ok(Vec::new()); // Too short to synthesize
-        }
-
-        if seq_len > MAX_STYLE_TOKENS {
-            return Err(format!(
-                "Token sequence too long ({} > {}). Split into shorter sentences.",
-                seq_len, MAX_STYLE_TOKENS
-            ));
+            // Too short to synthesize
+            return Ok(Synthesis {
+                audio: Vec::new(),
+                phoneme_spans: None,
+            });
         }
 
         // Get voice embedding indexed by token count
-        let voice_embedding = self
-            .voices
-            .get(voice, seq_len)
-            .ok_or_else(|| format!("Unknown voice: {}", voice))?;
+        let voice_embedding = style_vector(embedding, seq_len).to_vec();
 
         // Prepare input tensors
         // tokens: [batch=1, seq_len]
@@ -323,13 +1082,16 @@ impl KokoroModel {
             .map_err(|e| format!("Failed to create speed tensor: {}", e))?;
 
         let mut session = self
-            .session
-            .lock()
-            .map_err(|e| format!("Failed to lock session: {}", e))?;
-
-        let outputs = session
-            .run(ort::inputs!["tokens" => tokens_value, "style" => style_value, "speed" => speed_value])
-            .map_err(|e| format!("ONNX inference failed: {}", e))?;
+            .sessions(quality.unwrap_or(self.default_quality))
+            .take()?;
+
+        let inputs =
+            ort::inputs!["tokens" => tokens_value, "style" => style_value, "speed" => speed_value];
+        let outputs = match cancel {
+            Some(cancel) => session.run_with_options(inputs, &cancel.0),
+            None => session.run(inputs),
+        }
+        .map_err(|e| format!("ONNX inference failed: {}", e))?;
 
         // Extract audio output
         // Output shape: [batch=1, samples]
@@ -341,14 +1103,320 @@ impl KokoroModel {
 
         let audio: Vec<f32> = audio_array.iter().copied().collect();
 
-        Ok(audio)
+        // Token durations, in frames as integers or floats depending on the export
+        let durations = DURATION_OUTPUTS
+            .iter()
+            .find_map(|name| outputs.get(name))
+            .and_then(|output| match output.try_extract_array::<i64>() {
+                Ok(array) => Some(array.iter().map(|&d| d as f64).collect::<Vec<_>>()),
+                Err(_) => output
+                    .try_extract_array::<f32>()
+                    .ok()
+                    .map(|array| array.iter().map(|&d| d as f64).collect()),
+            });
+        let phoneme_spans = durations
+            .and_then(|durations| phoneme_spans(&self.vocab, phonemes, &durations, audio.len()));
+
+        Ok(Synthesis {
+            audio,
+            phoneme_spans,
+        })
+    }
+}
+
+/// Audio synthesized from phonemes
+pub struct Synthesis {
+    /// PCM f32 samples at 24kHz
+    pub audio: Vec<f32>,
+    /// Start and end sample of each character of the phonemes, from the durations the
+    /// model predicted; `None` if it doesn't output them
+    pub phoneme_spans: Option<Vec<(usize, usize)>>,
+}
+
+/// Spread `total_samples` over the characters of `phonemes` by the predicted duration of
+/// each of their tokens, after the start token's. Characters without a token take no time.
+/// Punctuation ending a phrase, after which a long sentence is best split
+const PHRASE_ENDS: &[char] = &[',', ';', ':', '.', '!', '?', '\u{2014}', '\u{2026}'];
+
+/// Split `phonemes` into pieces of at most `max_tokens` tokens each, after a phrase in
+/// the second half of a piece if there is one, else between words, else anywhere
+fn split_phonemes<'a>(
+    vocab: &HashMap<char, i64>,
+    phonemes: &'a str,
+    max_tokens: usize,
+) -> Vec<&'a str> {
+    let mut pieces = Vec::new();
+    let mut rest = phonemes;
+    loop {
+        let mut tokens = 0;
+        let mut cut = None;
+        let mut phrase_end = None;
+        let mut word_end = None;
+        for (i, c) in rest.char_indices() {
+            if !vocab.contains_key(&c) {
+                continue;
+            }
+            if tokens == max_tokens {
+                cut = Some(i);
+                break;
+            }
+            tokens += 1;
+            if c == ' ' && i > 0 {
+                // The space stays with the words before it
+                word_end = Some(i + 1);
+                if rest[..i].ends_with(PHRASE_ENDS) && tokens >= max_tokens / 2 {
+                    phrase_end = Some(i + 1);
+                }
+            }
+        }
+        let Some(cut) = cut else {
+            pieces.push(rest);
+            return pieces;
+        };
+        let end = phrase_end.or(word_end).unwrap_or(cut);
+        pieces.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+}
+
+fn phoneme_spans(
+    vocab: &HashMap<char, i64>,
+    phonemes: &str,
+    durations: &[f64],
+    total_samples: usize,
+) -> Option<Vec<(usize, usize)>> {
+    let tokens = phonemes.chars().filter(|c| vocab.contains_key(c)).count() + 2;
+    let total: f64 = durations.iter().sum();
+    if durations.len() != tokens || total <= 0.0 {
+        return None;
+    }
+    let scale = total_samples as f64 / total;
+    let mut durations = durations.iter();
+    let mut at = durations.next()? * scale;
+    let spans = phonemes
+        .chars()
+        .map(|c| {
+            let start = at;
+            if vocab.contains_key(&c) {
+                at += durations.next().copied().unwrap_or(0.0) * scale;
+            }
+            (start.round() as usize, at.round() as usize)
+        })
+        .collect();
+    Some(spans)
+}
+
+/// Aborts a [`KokoroModel::synthesize_with`] call from another thread, e.g. when the
+/// client it is for goes away. Each call needs its own: once cancelled, it stays so.
+#[derive(Clone)]
+pub struct Cancel(Arc<RunOptions>);
+
+impl Cancel {
+    pub fn new() -> Result<Self, String> {
+        RunOptions::new()
+            .map(|options| Self(Arc::new(options)))
+            .map_err(|e| format!("Failed to create run options: {}", e))
+    }
+
+    /// Stop the inference at its next chance; a run not yet started stops straight away
+    pub fn cancel(&self) {
+        if let Err(e) = self.0.terminate() {
+            tracing::warn!(error = %e, "Failed to cancel inference");
+        }
+    }
+}
+
+/// Convert text phonemes to token IDs
+fn phonemes_to_tokens(vocab: &HashMap<char, i64>, phonemes: &str) -> Vec<i64> {
+    let mut tokens = Vec::with_capacity(phonemes.len() + 2);
+
+    // Start token
+    tokens.push(0); // $ = start of sequence
+
+    for c in phonemes.chars() {
+        if let Some(&id) = vocab.get(&c) {
+            tokens.push(id);
+        }
+        // Skip unknown characters
     }
+
+    // End token
+    tokens.push(0); // $ = end of sequence
+
+    tokens
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_accelerator_parse() {
+        assert_eq!(Accelerator::parse(None, None), Ok(Accelerator::Cpu));
+        assert_eq!(
+            Accelerator::parse(Some(" CUDA "), None),
+            Ok(Accelerator::Cuda { device_id: 0 })
+        );
+        assert_eq!(
+            Accelerator::parse(Some("rocm"), Some("1")),
+            Ok(Accelerator::Rocm { device_id: 1 })
+        );
+        assert!(Accelerator::parse(Some("tpu"), None).is_err());
+        assert!(Accelerator::parse(Some("cuda"), Some("-1")).is_err());
+
+        assert_eq!(
+            RuntimeConfig::parse(None, None, None, None, None),
+            Ok(RuntimeConfig::default())
+        );
+        assert_eq!(
+            RuntimeConfig::parse(Some("cuda"), None, Some(" 3 "), None, None)
+                .unwrap()
+                .sessions,
+            3
+        );
+        assert_eq!(
+            RuntimeConfig::parse(None, None, None, Some("Fast"), None)
+                .unwrap()
+                .default_quality,
+            Quality::Fast
+        );
+        assert!(RuntimeConfig::parse(None, None, Some("0"), None, None).is_err());
+        assert!(RuntimeConfig::parse(None, None, None, Some("low"), None).is_err());
+        assert!(RuntimeConfig::parse(None, None, None, None, Some("none")).is_err());
+    }
+
+    #[test]
+    fn test_warmup_parse() {
+        assert_eq!(Warmup::parse(None), Ok(Warmup::default()));
+        assert_eq!(Warmup::parse(Some("None")), Ok(Warmup::None));
+        assert_eq!(Warmup::parse(Some("all")), Ok(Warmup::All));
+        assert_eq!(
+            Warmup::parse(Some(" af_heart, bm_george,")),
+            Ok(Warmup::Voices(vec![
+                "af_heart".to_string(),
+                "bm_george".to_string()
+            ]))
+        );
+        assert!(Warmup::parse(Some(" , ")).is_err());
+    }
+
+    #[test]
+    fn test_pool() {
+        let pool = Arc::new(Pool::new(vec![1, 2]));
+        let first = pool.take().unwrap();
+        let second = pool.take().unwrap();
+        assert_ne!(*first, *second);
+
+        // With both taken, the next user waits until one is returned
+        let waiting = std::thread::spawn({
+            let pool = pool.clone();
+            move || *pool.take().unwrap()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        let returned = *second;
+        drop(second);
+        assert_eq!(waiting.join().unwrap(), returned);
+        drop(first);
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+    }
+
+    /// An NPZ archive of voices whose style vectors are all `value`
+    fn write_voices(path: &Path, voices: &[(&str, f32)]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, value) in voices {
+            zip.start_file(
+                format!("{}.npy", name),
+                zip::write::SimpleFileOptions::default(),
+            )
+            .unwrap();
+            let header = b"{'descr': '<f4', 'fortran_order': False, 'shape': (3, 1, 256), }";
+            let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+            npy.extend_from_slice(&(header.len() as u16).to_le_bytes());
+            npy.extend_from_slice(header);
+            for _ in 0..3 * EMBEDDING_DIM {
+                npy.extend_from_slice(&value.to_le_bytes());
+            }
+            std::io::Write::write_all(&mut zip, &npy).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_lazy_voices() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("voices.bin");
+        write_voices(&path, &[("af_a", 1.0), ("af_b", 2.0), ("af_c", 3.0)]);
+        let voices = VoiceEmbeddings::load_with_progress(&path, 2, |_, _| {}).unwrap();
+        assert!(voices.contains("af_b"));
+        assert!(voices.cache.lock().unwrap().voices.is_empty());
+
+        assert_eq!(voices.get("af_a", 0).unwrap(), vec![1.0; EMBEDDING_DIM]);
+        assert_eq!(voices.get("af_b", 999).unwrap(), vec![2.0; EMBEDDING_DIM]);
+        // Using af_a again makes af_b the one to go when af_c comes in
+        voices.get("af_a", 1).unwrap();
+        voices.get("af_c", 1).unwrap();
+        let cached: Vec<_> = voices
+            .cache
+            .lock()
+            .unwrap()
+            .voices
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        assert_eq!(cached, ["af_a", "af_c"]);
+        assert!(voices.get("af_x", 1).is_err());
+
+        // A voices file replaced with its entries in another order still gives each voice
+        write_voices(&path, &[("af_c", 3.0), ("af_b", 2.0), ("af_a", 1.0)]);
+        assert_eq!(voices.get("af_b", 1).unwrap(), vec![2.0; EMBEDDING_DIM]);
+        // A voice parsed twice at once is cached once
+        voices
+            .cache()
+            .unwrap()
+            .insert("af_b", Arc::new(vec![2.0; EMBEDDING_DIM]));
+        let cached: Vec<_> = voices
+            .cache()
+            .unwrap()
+            .voices
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        assert_eq!(cached, ["af_c", "af_b"]);
+    }
+
+    #[test]
+    fn test_split_phonemes() {
+        let vocab = build_vocab();
+        // Short enough: left alone
+        assert_eq!(split_phonemes(&vocab, "hɛlˈoʊ", 510), vec!["hɛlˈoʊ"]);
+
+        // After a phrase if it comes late enough, between words otherwise
+        let phonemes = "wˈʌn, tˈuː θɹˈiː fˈɔːɹ, fˈaɪv sˈɪks";
+        let pieces = split_phonemes(&vocab, phonemes, 24);
+        assert_eq!(pieces, vec!["wˈʌn, tˈuː θɹˈiː fˈɔːɹ, ", "fˈaɪv sˈɪks"]);
+        let pieces = split_phonemes(&vocab, phonemes, 12);
+        assert_eq!(
+            pieces,
+            vec!["wˈʌn, ", "tˈuː θɹˈiː ", "fˈɔːɹ, ", "fˈaɪv sˈɪks"]
+        );
+        assert_eq!(pieces.concat(), phonemes);
+
+        // A word longer than a piece is cut anywhere
+        assert_eq!(split_phonemes(&vocab, "abcdef", 4), vec!["abcd", "ef"]);
+    }
+
+    #[test]
+    fn test_phoneme_spans() {
+        let vocab = build_vocab();
+        // "hi" and a space: start token, h, i, space, end token; `~` isn't in the vocab
+        let spans = phoneme_spans(&vocab, "hi~ ", &[2.0, 1.0, 3.0, 1.0, 1.0], 800).unwrap();
+        assert_eq!(spans, vec![(200, 300), (300, 600), (600, 600), (600, 700)]);
+        // Durations that don't match the tokens are ignored
+        assert!(phoneme_spans(&vocab, "hi", &[1.0, 1.0], 800).is_none());
+        assert!(phoneme_spans(&vocab, "hi", &[0.0; 4], 800).is_none());
+    }
+
     #[test]
     fn test_vocab_build() {
         let vocab = build_vocab();
@@ -401,70 +1469,76 @@ mod tests {
         // Test 1: Voice embeddings load correctly from NPZ
         println!("Loading voice embeddings...");
         let voices = VoiceEmbeddings::load(&voices_path).expect("Failed to load voices");
-        println!("Loaded {} voices", voices.embeddings.len());
+        println!("Loaded {} voices", voices.entries.len());
 
-        assert_eq!(voices.embeddings.len(), 50, "Expected 50 voices");
+        assert_eq!(voices.entries.len(), 50, "Expected 50 voices");
 
         // Test 2: All expected voices are present
-        let expected_voices = ["af_heart", "af_bella", "af_nicole", "af_sky",
-                               "bm_daniel", "bm_george", "bm_lewis"];
+        let expected_voices = [
+            "af_heart",
+            "af_bella",
+            "af_nicole",
+            "af_sky",
+            "bm_daniel",
+            "bm_george",
+            "bm_lewis",
+        ];
         for name in &expected_voices {
-            assert!(
-                voices.embeddings.contains_key(*name),
-                "Missing voice: {}",
-                name
-            );
+            assert!(voices.contains(name), "Missing voice: {}", name);
         }
 
         // Test 3: Each voice has correct dimensions (510 positions x 256 floats)
-        for (name, matrix) in &voices.embeddings {
+        for name in voices.entries.keys() {
+            let matrix = voices.voice(name).unwrap();
             assert_eq!(
                 matrix.len(),
-                MAX_STYLE_TOKENS,
-                "Voice {} has {} positions, expected {}",
+                MAX_STYLE_TOKENS * EMBEDDING_DIM,
+                "Voice {} has {} floats, expected {}",
                 name,
                 matrix.len(),
-                MAX_STYLE_TOKENS
+                MAX_STYLE_TOKENS * EMBEDDING_DIM
             );
-            for (i, vec) in matrix.iter().enumerate() {
-                assert_eq!(
-                    vec.len(),
-                    EMBEDDING_DIM,
-                    "Voice {} position {} has {} dims, expected {}",
-                    name,
-                    i,
-                    vec.len(),
-                    EMBEDDING_DIM
-                );
-            }
         }
 
         // Test 4: Style lookup by token length works
-        let style = voices.get("af_heart", 10).expect("Failed to get af_heart style");
+        let style = voices
+            .get("af_heart", 10)
+            .expect("Failed to get af_heart style");
         assert_eq!(style.len(), EMBEDDING_DIM);
 
         // Verify different token lengths give different style vectors
         let style_5 = voices.get("af_heart", 5).unwrap();
         let style_50 = voices.get("af_heart", 50).unwrap();
-        assert_ne!(style_5, style_50, "Different token lengths should give different styles");
+        assert_ne!(
+            style_5, style_50,
+            "Different token lengths should give different styles"
+        );
 
         // Test 5: Clamping works for out-of-range token lengths
         let style_max = voices.get("af_heart", 999).unwrap();
         let style_509 = voices.get("af_heart", 509).unwrap();
-        assert_eq!(style_max, style_509, "Out-of-range should clamp to last position");
+        assert_eq!(
+            style_max, style_509,
+            "Out-of-range should clamp to last position"
+        );
 
         println!("All voice loading tests passed!");
 
         // Test 6: Full synthesis (only if ONNX model is available)
         if !model_path.exists() {
-            println!("Skipping synthesis test (kokoro-v1.0.onnx not found at {:?})", model_path);
-            println!("To run full test, download: wget -q -O /tmp/test-kokoro-v1.0.onnx https://github.com/nazdridoy/kokoro-tts/releases/download/v1.0.0/kokoro-v1.0.onnx");
+            println!(
+                "Skipping synthesis test (kokoro-v1.0.onnx not found at {:?})",
+                model_path
+            );
+            println!(
+                "To run full test, download: wget -q -O /tmp/test-kokoro-v1.0.onnx https://github.com/nazdridoy/kokoro-tts/releases/download/v1.0.0/kokoro-v1.0.onnx"
+            );
             return;
         }
 
         println!("Loading ONNX model...");
-        let model = KokoroModel::load(&model_path, &voices_path)
-            .expect("Failed to load Kokoro model");
+        let model =
+            KokoroModel::load(&model_path, &voices_path).expect("Failed to load Kokoro model");
 
         // Simple phoneme test (IPA for "hello")
         let phonemes = "hɛˈloʊ";
@@ -483,8 +1557,16 @@ mod tests {
 
         // Verify audio is valid (not all zeros, in reasonable range)
         let max_val = audio.iter().fold(0.0f32, |a, &b| a.max(b.abs()));
-        assert!(max_val > 0.001, "Audio appears to be silence (max={:.6})", max_val);
-        assert!(max_val < 10.0, "Audio values out of range (max={:.6})", max_val);
+        assert!(
+            max_val > 0.001,
+            "Audio appears to be silence (max={:.6})",
+            max_val
+        );
+        assert!(
+            max_val < 10.0,
+            "Audio values out of range (max={:.6})",
+            max_val
+        );
 
         // Write audio to WAV file for manual listening verification
         let wav_path = "/tmp/test-kokoro-output.wav";
@@ -510,50 +1592,40 @@ mod tests {
 
         // RIFF header
         file.write_all(b"RIFF").map_err(|e| e.to_string())?;
-        file.write_all(&file_size.to_le_bytes()).map_err(|e| e.to_string())?;
+        file.write_all(&file_size.to_le_bytes())
+            .map_err(|e| e.to_string())?;
         file.write_all(b"WAVE").map_err(|e| e.to_string())?;
 
         // fmt chunk
         file.write_all(b"fmt ").map_err(|e| e.to_string())?;
-        file.write_all(&16u32.to_le_bytes()).map_err(|e| e.to_string())?;  // chunk size
-        file.write_all(&1u16.to_le_bytes()).map_err(|e| e.to_string())?;   // PCM format
-        file.write_all(&num_channels.to_le_bytes()).map_err(|e| e.to_string())?;
-        file.write_all(&sample_rate.to_le_bytes()).map_err(|e| e.to_string())?;
-        file.write_all(&byte_rate.to_le_bytes()).map_err(|e| e.to_string())?;
-        file.write_all(&block_align.to_le_bytes()).map_err(|e| e.to_string())?;
-        file.write_all(&bits_per_sample.to_le_bytes()).map_err(|e| e.to_string())?;
+        file.write_all(&16u32.to_le_bytes())
+            .map_err(|e| e.to_string())?; // chunk size
+        file.write_all(&1u16.to_le_bytes())
+            .map_err(|e| e.to_string())?; // PCM format
+        file.write_all(&num_channels.to_le_bytes())
+            .map_err(|e| e.to_string())?;
+        file.write_all(&sample_rate.to_le_bytes())
+            .map_err(|e| e.to_string())?;
+        file.write_all(&byte_rate.to_le_bytes())
+            .map_err(|e| e.to_string())?;
+        file.write_all(&block_align.to_le_bytes())
+            .map_err(|e| e.to_string())?;
+        file.write_all(&bits_per_sample.to_le_bytes())
+            .map_err(|e| e.to_string())?;
 
         // data chunk
         file.write_all(b"data").map_err(|e| e.to_string())?;
-        file.write_all(&data_size.to_le_bytes()).map_err(|e| e.to_string())?;
+        file.write_all(&data_size.to_le_bytes())
+            .map_err(|e| e.to_string())?;
 
         // Convert f32 samples to i16 and write
         for &sample in samples {
-            let clamped = sample.max(-1.0).min(1.0);
+            let clamped = sample.clamp(-1.0, 1.0);
             let int_sample = (clamped * 32767.0) as i16;
-            file.write_all(&int_sample.to_le_bytes()).map_err(|e| e.to_string())?;
+            file.write_all(&int_sample.to_le_bytes())
+                .map_err(|e| e.to_string())?;
         }
 
         Ok(())
     }
 }
-
-/// Convert text phonemes to token IDs
-fn phonemes_to_tokens(vocab: &HashMap<char, i64>, phonemes: &str) -> Vec<i64> {
-    let mut tokens = Vec::with_capacity(phonemes.len() + 2);
-
-    // Start token
-    tokens.push(0); // $ = start of sequence
-
-    for c in phonemes.chars() {
-        if let Some(&id) = vocab.get(&c) {
-            tokens.push(id);
-        }
-        // Skip unknown characters
-    }
-
-    // End token
-    tokens.push(0); // $ = end of sequence
-
-    tokens
-}
@@ -0,0 +1,437 @@
+//! Joining a job's chunks into one, and sentences synthesized one at a time.
+//!
+//! Each chunk or sentence is synthesized separately, so the audio on either side of a seam
+//! doesn't line up and a plain join can click. With `TTS_SENTENCE_GAP_MS` set, that much
+//! silence goes between them. Otherwise, with `TTS_CROSSFADE_MS` set, ones that follow each
+//! other directly overlap by that much, the end of one fading out as the start of the next
+//! fades in. With `TTS_TRIM_SILENCE_DB` set, each chunk's leading and trailing silence is
+//! trimmed first.
+//!
+//! A job's chunks go through a [`ChunkJoiner`] as they are synthesized, into a [`PcmSink`]:
+//! the output encoders themselves for formats encoded in process, otherwise a WAV for
+//! ffmpeg. Streamed sentences go through a [`SentenceJoiner`].
+
+use crate::encode::{PcmSink, SampleType};
+use crate::silence::SilenceTrim;
+
+/// Longest crossfade accepted for `TTS_CROSSFADE_MS`
+const MAX_CROSSFADE_MS: u32 = 1000;
+/// Longest gap accepted for `TTS_SENTENCE_GAP_MS`
+const MAX_SENTENCE_GAP_MS: u32 = 2000;
+
+/// Parse `TTS_CROSSFADE_MS` (default 0, no crossfade)
+pub fn parse_crossfade_ms(value: Option<&str>) -> Result<u32, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .parse::<u32>()
+            .ok()
+            .filter(|&ms| ms <= MAX_CROSSFADE_MS)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid TTS_CROSSFADE_MS '{}', expected 0 to {}",
+                    v, MAX_CROSSFADE_MS
+                )
+            }),
+        None => Ok(0),
+    }
+}
+
+/// Parse `TTS_SENTENCE_GAP_MS` (default 0, no gap)
+pub fn parse_sentence_gap_ms(value: Option<&str>) -> Result<u32, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .parse::<u32>()
+            .ok()
+            .filter(|&ms| ms <= MAX_SENTENCE_GAP_MS)
+            .ok_or_else(|| {
+                format!(
+                    "Invalid TTS_SENTENCE_GAP_MS '{}', expected 0 to {}",
+                    v, MAX_SENTENCE_GAP_MS
+                )
+            }),
+        None => Ok(0),
+    }
+}
+
+fn frames(sample_rate: u32, duration_ms: u32) -> usize {
+    (sample_rate as u64 * duration_ms as u64 / 1000) as usize
+}
+
+/// Fade the end of `tail` into the start of `samples`, both interleaved, over as many
+/// frames as both have. Returns how many frames of `tail` that is; the ones before them
+/// are left to be played as they are.
+fn fade_into(tail: &[f32], samples: &mut [f32], channels: usize) -> usize {
+    let overlap = (tail.len() / channels).min(samples.len() / channels);
+    let unfaded = tail.len() - overlap * channels;
+    for frame in 0..overlap {
+        let fade_in = (frame + 1) as f32 / (overlap + 1) as f32;
+        for channel in 0..channels {
+            let sample = &mut samples[frame * channels + channel];
+            *sample =
+                tail[unfaded + frame * channels + channel] * (1.0 - fade_in) + *sample * fade_in;
+        }
+    }
+    overlap
+}
+
+/// Joins mono sentences at the model's sample rate as they are synthesized, for audio
+/// that is played or encoded while later sentences are still being synthesized
+pub struct SentenceJoiner {
+    sample_rate: u32,
+    crossfade_ms: u32,
+    gap_ms: u32,
+    /// End of the previous sentence, held back to be faded into the start of the next
+    tail: Vec<f32>,
+}
+
+impl SentenceJoiner {
+    pub fn new(sample_rate: u32, crossfade_ms: u32, gap_ms: u32) -> Self {
+        Self {
+            sample_rate,
+            crossfade_ms,
+            gap_ms,
+            tail: Vec::new(),
+        }
+    }
+
+    /// Add the next sentence, followed by `pause_ms` of silence, or by the gap if there is
+    /// no pause and it isn't the `last`. Returns the audio that is ready, and where in it
+    /// the sentence starts. Unless it is the last, the end of a sentence that the next
+    /// directly follows may be held back to crossfade the two.
+    pub fn push(&mut self, mut audio: Vec<f32>, pause_ms: u32, last: bool) -> (Vec<f32>, usize) {
+        let overlap = fade_into(&self.tail, &mut audio, 1);
+        let mut ready = std::mem::take(&mut self.tail);
+        ready.truncate(ready.len() - overlap);
+        let start = ready.len();
+
+        let silence_ms = if pause_ms > 0 || last {
+            pause_ms
+        } else {
+            self.gap_ms
+        };
+        let held = if silence_ms == 0 && !last {
+            frames(self.sample_rate, self.crossfade_ms).min(audio.len() / 2)
+        } else {
+            0
+        };
+        self.tail = audio.split_off(audio.len() - held);
+        ready.extend(audio);
+        ready.extend(std::iter::repeat_n(
+            0.0,
+            frames(self.sample_rate, silence_ms),
+        ));
+        (ready, start)
+    }
+
+    /// Audio still held back, e.g. when the sentences after it turned out to be silent
+    pub fn finish(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.tail)
+    }
+}
+
+/// What comes between a chunk and the next one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Seam {
+    /// Overlap the two by the crossfade
+    Crossfade,
+    /// Silence, in milliseconds; 0 joins them end to end
+    Silence(u32),
+}
+
+/// Sample layout from a WAV's `fmt ` chunk
+struct Format {
+    sample_type: SampleType,
+    channels: usize,
+    sample_rate: u32,
+}
+
+impl Format {
+    fn parse(fmt: &[u8]) -> Result<Self, String> {
+        if fmt.len() < 16 {
+            return Err("WAV format chunk is too short".to_string());
+        }
+        let mut tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+        // WAVE_FORMAT_EXTENSIBLE keeps the real format at the start of its subformat GUID
+        if tag == 0xFFFE && fmt.len() >= 26 {
+            tag = u16::from_le_bytes([fmt[24], fmt[25]]);
+        }
+        let channels = u16::from_le_bytes([fmt[2], fmt[3]]) as usize;
+        let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+        let bits_per_sample = u16::from_le_bytes([fmt[14], fmt[15]]);
+        let sample_type = match (tag, bits_per_sample) {
+            (1, 16) => SampleType::Int16,
+            (3, 32) => SampleType::Float32,
+            _ => {
+                return Err(format!(
+                    "Unsupported WAV sample format {} with {} bits per sample",
+                    tag, bits_per_sample
+                ));
+            }
+        };
+        if channels == 0 {
+            return Err("WAV has no channels".to_string());
+        }
+        Ok(Self {
+            sample_type,
+            channels,
+            sample_rate,
+        })
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        match self.sample_type {
+            SampleType::Int16 => 2,
+            SampleType::Float32 => 4,
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Vec<f32> {
+        match self.sample_type {
+            SampleType::Int16 => data
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+                .collect(),
+            SampleType::Float32 => data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        }
+    }
+}
+
+/// The `fmt ` and `data` chunks of a WAV file
+fn split_wav(bytes: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err("Not a WAV file".to_string());
+    }
+    let mut fmt = None;
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let size = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let body = offset + 8;
+        match &bytes[offset..offset + 4] {
+            b"fmt " => fmt = bytes.get(body..body + size),
+            b"data" => {
+                // Writers that stream sometimes leave the size unset; take the rest of the file
+                let data = &bytes[body..(body.saturating_add(size)).min(bytes.len())];
+                return fmt
+                    .map(|fmt| (fmt, data))
+                    .ok_or_else(|| "WAV has no format chunk".to_string());
+            }
+            _ => {}
+        }
+        offset = body.saturating_add(size + (size & 1));
+    }
+    Err("WAV has no data chunk".to_string())
+}
+
+/// Decode a 16-bit PCM or 32-bit float WAV to mono samples, averaging its channels, and
+/// its sample rate
+pub fn decode_wav(bytes: &[u8]) -> Result<(Vec<f32>, u32), String> {
+    let (fmt, data) = split_wav(bytes)?;
+    let format = Format::parse(fmt)?;
+    let frame_len = format.channels * format.bytes_per_sample();
+    let samples = format.decode(&data[..data.len() - data.len() % frame_len]);
+    let mono = samples
+        .chunks_exact(format.channels)
+        .map(|frame| frame.iter().sum::<f32>() / format.channels as f32)
+        .collect();
+    Ok((mono, format.sample_rate))
+}
+
+/// Joins a job's mono chunks at `sample_rate` into a sink as each is synthesized, putting
+/// each chunk's seam between it and the next. Chunks are trimmed first, if a trim is
+/// given. A crossfade is at most half as long as either chunk it joins.
+pub struct ChunkJoiner {
+    sink: Box<dyn PcmSink>,
+    sample_rate: u32,
+    crossfade_ms: u32,
+    trim: Option<SilenceTrim>,
+    /// End of the previous chunk, held back to be faded into the start of the next
+    tail: Vec<f32>,
+    /// Silence after the previous chunk, written once another chunk follows it
+    silence_frames: usize,
+    /// Frames of the output each chunk takes up so far
+    frames_per_part: Vec<usize>,
+}
+
+impl ChunkJoiner {
+    pub fn new(
+        mut sink: Box<dyn PcmSink>,
+        sample_rate: u32,
+        crossfade_ms: u32,
+        trim: Option<SilenceTrim>,
+    ) -> Result<Self, String> {
+        sink.start(1, sample_rate)?;
+        Ok(Self {
+            sink,
+            sample_rate,
+            crossfade_ms,
+            trim,
+            tail: Vec::new(),
+            silence_frames: 0,
+            frames_per_part: Vec::new(),
+        })
+    }
+
+    /// Add the next chunk, followed by `seam` unless it turns out to be the last
+    pub fn push(&mut self, mut samples: Vec<f32>, seam: Seam) -> Result<(), String> {
+        if let Some(trim) = self.trim {
+            trim.apply(&mut samples, 1, self.sample_rate);
+        }
+        let len = samples.len();
+        if let Some(previous) = self.frames_per_part.last_mut() {
+            *previous += self.silence_frames;
+        }
+        self.sink.write(&vec![0.0; self.silence_frames])?;
+        self.silence_frames = 0;
+
+        // Fade the held-back tail into this chunk's start
+        let overlap = fade_into(&self.tail, &mut samples, 1);
+        let unfaded = self.tail.len() - overlap;
+        if let Some(previous) = self.frames_per_part.last_mut() {
+            *previous -= overlap;
+        }
+        self.frames_per_part.push(len);
+
+        let held = match seam {
+            Seam::Crossfade => frames(self.sample_rate, self.crossfade_ms).min(len / 2),
+            Seam::Silence(_) => 0,
+        };
+        self.sink.write(&self.tail[..unfaded])?;
+        self.sink.write(&samples[..len - held])?;
+        self.tail = samples.split_off(len - held);
+        if let Seam::Silence(ms) = seam {
+            self.silence_frames = frames(self.sample_rate, ms);
+        }
+        Ok(())
+    }
+
+    /// Write what is held back and finish the sink. Returns how many seconds of the
+    /// output each chunk takes up, including the silence after it and excluding the
+    /// overlap with the next chunk.
+    pub fn finish(mut self) -> Result<Vec<f64>, String> {
+        if self.frames_per_part.is_empty() {
+            return Err("No chunks to join".to_string());
+        }
+        self.sink.write(&self.tail)?;
+        self.sink.finish()?;
+        Ok(self
+            .frames_per_part
+            .into_iter()
+            .map(|frames| frames as f64 / self.sample_rate as f64)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::WavWriter;
+
+    /// A 16-bit mono WAV at 1 kHz holding `samples`
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let mut bytes = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0".to_vec();
+        bytes.extend_from_slice(&1000u32.to_le_bytes());
+        bytes.extend_from_slice(&2000u32.to_le_bytes());
+        bytes.extend_from_slice(&[2, 0, 16, 0]);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(samples.len() as u32 * 2).to_le_bytes());
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn samples(bytes: &[u8]) -> Vec<i16> {
+        let (_, data) = split_wav(bytes).unwrap();
+        data.chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_wav() {
+        let (audio, sample_rate) = decode_wav(&wav(&[0, 16384, -32768])).unwrap();
+        assert_eq!(sample_rate, 1000);
+        assert_eq!(audio, vec![0.0, 0.5, -1.0]);
+        assert!(decode_wav(b"not a wav").is_err());
+    }
+
+    #[test]
+    fn test_parse_crossfade_ms() {
+        assert_eq!(parse_crossfade_ms(None), Ok(0));
+        assert_eq!(parse_crossfade_ms(Some(" 40 ")), Ok(40));
+        assert!(parse_crossfade_ms(Some("5000")).is_err());
+        assert!(parse_crossfade_ms(Some("-1")).is_err());
+    }
+
+    #[test]
+    fn test_parse_sentence_gap_ms() {
+        assert_eq!(parse_sentence_gap_ms(None), Ok(0));
+        assert_eq!(parse_sentence_gap_ms(Some("150")), Ok(150));
+        assert!(parse_sentence_gap_ms(Some("3000")).is_err());
+    }
+
+    #[test]
+    fn test_sentence_joiner() {
+        // At 1 kHz, 2 ms of each sentence overlap the next
+        let mut joiner = SentenceJoiner::new(1000, 2, 0);
+        assert_eq!(joiner.push(vec![1.0; 6], 0, false), (vec![1.0; 4], 0));
+        let (ready, start) = joiner.push(vec![4.0; 6], 1, false);
+        assert_eq!(ready, [2.0, 3.0, 4.0, 4.0, 4.0, 4.0, 0.0]);
+        assert_eq!(start, 0);
+        assert_eq!(joiner.push(vec![2.0; 2], 0, true), (vec![2.0; 2], 0));
+        assert!(joiner.finish().is_empty());
+
+        // A gap replaces the crossfade, but not after the last sentence
+        let mut joiner = SentenceJoiner::new(1000, 2, 3);
+        assert_eq!(
+            joiner.push(vec![1.0; 2], 0, false).0,
+            [1.0, 1.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(joiner.push(vec![1.0; 2], 0, true).0, [1.0, 1.0]);
+
+        // A held-back tail longer than the next sentence is partly played as it is
+        let mut joiner = SentenceJoiner::new(1000, 3, 0);
+        joiner.push(vec![1.0; 6], 0, false);
+        assert_eq!(joiner.push(vec![1.0], 0, true), (vec![1.0; 3], 2));
+    }
+
+    #[test]
+    fn test_chunk_joiner() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("joined.wav");
+        let writer =
+            || Box::new(WavWriter::create(&output, SampleType::Int16, Vec::new()).unwrap());
+        let (a, b, c) = (
+            vec![1000.0 / 32768.0; 10],
+            vec![3000.0 / 32768.0; 10],
+            vec![500.0 / 32768.0; 4],
+        );
+
+        // 3 ms of a and b overlap, then 2 ms of silence before c. The pause after the last
+        // chunk is dropped.
+        let mut joiner = ChunkJoiner::new(writer(), 1000, 3, None).unwrap();
+        joiner.push(a.clone(), Seam::Crossfade).unwrap();
+        joiner.push(b, Seam::Silence(2)).unwrap();
+        joiner.push(c.clone(), Seam::Silence(5)).unwrap();
+        assert_eq!(joiner.finish().unwrap(), [0.007, 0.012, 0.004]);
+        let joined = std::fs::read(&output).unwrap();
+        let mut expected = vec![1000; 7];
+        expected.extend([1500, 2000, 2500]);
+        expected.extend([3000; 7]);
+        expected.extend([0; 2]);
+        expected.extend([500; 4]);
+        assert_eq!(samples(&joined), expected);
+
+        // The crossfade is limited to half of the shorter chunk
+        let mut joiner = ChunkJoiner::new(writer(), 1000, 100, None).unwrap();
+        joiner.push(c, Seam::Crossfade).unwrap();
+        joiner.push(a, Seam::Silence(0)).unwrap();
+        assert_eq!(joiner.finish().unwrap(), [0.002, 0.010]);
+
+        let joiner = ChunkJoiner::new(writer(), 1000, 3, None).unwrap();
+        assert!(joiner.finish().is_err());
+    }
+}
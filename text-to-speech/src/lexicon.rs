@@ -0,0 +1,565 @@
+//! Per-user pronunciation lexicon, managed with `/lexicon`.
+//!
+//! Each entry maps a word or phrase (the grapheme) to either IPA phonemes or a respelling
+//! that is read instead, e.g. "Nguyen" → `/wɪn/` or "SQL" → "sequel". Entries are matched
+//! case-insensitively on word boundaries, longest first, after text normalization, and
+//! applied to every job and live synthesis request of the user. The kokoro-tts CLI only takes text, so batch jobs it
+//! synthesizes get respellings but not phoneme entries.
+
+use crate::auth::AuthenticatedUser;
+use crate::state::AppState;
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Row};
+use std::ops::Range;
+use utoipa::ToSchema;
+
+/// Most entries a user can have
+const MAX_ENTRIES: i64 = 1000;
+const MAX_GRAPHEME_CHARS: usize = 100;
+const MAX_PRONUNCIATION_CHARS: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pronunciation {
+    /// IPA, passed to the model as is
+    Phonemes(String),
+    /// Text read in place of the grapheme
+    Replacement(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    grapheme: String,
+    pronunciation: Pronunciation,
+}
+
+/// A piece of text after lexicon lookup
+#[derive(Debug, PartialEq)]
+pub enum Segment {
+    /// Text to phonemize as usual, with respellings applied
+    Text(String),
+    /// Phonemes of a lexicon entry
+    Phonemes(String),
+}
+
+/// A user's lexicon entries, ready for matching
+#[derive(Debug, Clone, Default)]
+pub struct Lexicon {
+    /// Longest grapheme first, so phrases win over the words in them
+    entries: Vec<Entry>,
+}
+
+impl Lexicon {
+    fn new(mut entries: Vec<Entry>) -> Self {
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.grapheme.chars().count()));
+        Self { entries }
+    }
+
+    pub async fn load(pool: &Pool<Postgres>, username: &str) -> Result<Self, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT grapheme, phonemes, replacement FROM lexicon_entries WHERE username = $1",
+        )
+        .bind(username)
+        .fetch_all(pool)
+        .await?;
+        let entries = rows
+            .into_iter()
+            .map(|row| {
+                let pronunciation = match row.get::<Option<String>, _>("phonemes") {
+                    Some(phonemes) => Pronunciation::Phonemes(phonemes),
+                    None => Pronunciation::Replacement(
+                        row.get::<Option<String>, _>("replacement")
+                            .unwrap_or_default(),
+                    ),
+                };
+                Entry {
+                    grapheme: row.get("grapheme"),
+                    pronunciation,
+                }
+            })
+            .collect();
+        Ok(Self::new(entries))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Non-overlapping matches of entries in `text`, in order
+    fn matches(&self, text: &str) -> Vec<(Range<usize>, &Entry)> {
+        let mut matches = Vec::new();
+        if self.entries.is_empty() {
+            return matches;
+        }
+        let mut previous: Option<char> = None;
+        let mut skip_until = 0;
+        for (start, c) in text.char_indices() {
+            let at_word_start = !previous.is_some_and(char::is_alphanumeric);
+            previous = Some(c);
+            if start < skip_until || !at_word_start {
+                continue;
+            }
+            let found = self.entries.iter().find_map(|entry| {
+                let end = match_len(&text[start..], &entry.grapheme)? + start;
+                let at_word_end = !text[end..]
+                    .chars()
+                    .next()
+                    .is_some_and(char::is_alphanumeric);
+                at_word_end.then_some((start..end, entry))
+            });
+            if let Some((range, entry)) = found {
+                skip_until = range.end;
+                matches.push((range, entry));
+            }
+        }
+        matches
+    }
+
+    /// `text` split around entries with phonemes, with respellings substituted
+    pub fn segments(&self, text: &str) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut current = String::new();
+        let mut position = 0;
+        for (range, entry) in self.matches(text) {
+            current.push_str(&text[position..range.start]);
+            match &entry.pronunciation {
+                Pronunciation::Replacement(replacement) => current.push_str(replacement),
+                Pronunciation::Phonemes(phonemes) => {
+                    if !current.trim().is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut current)));
+                    }
+                    current.clear();
+                    segments.push(Segment::Phonemes(phonemes.clone()));
+                }
+            }
+            position = range.end;
+        }
+        current.push_str(&text[position..]);
+        if !current.trim().is_empty() {
+            segments.push(Segment::Text(current));
+        }
+        segments
+    }
+
+    /// `text` with respellings substituted, for synthesizers that only take text
+    pub fn respell(&self, text: &str) -> String {
+        let mut respelled = String::with_capacity(text.len());
+        let mut position = 0;
+        for (range, entry) in self.matches(text) {
+            if let Pronunciation::Replacement(replacement) = &entry.pronunciation {
+                respelled.push_str(&text[position..range.start]);
+                respelled.push_str(replacement);
+                position = range.end;
+            }
+        }
+        respelled.push_str(&text[position..]);
+        respelled
+    }
+
+    /// The entries that apply to `text`, in a stable form, so jobs whose text is affected
+    /// by a lexicon change aren't mistaken for earlier identical jobs. `None` if no entry
+    /// applies.
+    pub fn fingerprint(&self, text: &str) -> Option<String> {
+        let mut applied: Vec<String> = self
+            .matches(text)
+            .into_iter()
+            .map(|(_, entry)| match &entry.pronunciation {
+                Pronunciation::Phonemes(p) => {
+                    format!("{}\u{1f}/{}", entry.grapheme.to_lowercase(), p)
+                }
+                Pronunciation::Replacement(r) => {
+                    format!("{}\u{1f}{}", entry.grapheme.to_lowercase(), r)
+                }
+            })
+            .collect();
+        applied.sort();
+        applied.dedup();
+        (!applied.is_empty()).then(|| applied.join("\u{1e}"))
+    }
+}
+
+/// Length in bytes of the prefix of `text` equal to `grapheme`, ignoring case
+fn match_len(text: &str, grapheme: &str) -> Option<usize> {
+    let mut chars = text.char_indices();
+    for expected in grapheme.chars() {
+        let (_, c) = chars.next()?;
+        if c != expected && !c.to_lowercase().eq(expected.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(chars.next().map_or(text.len(), |(index, _)| index))
+}
+
+/// A lexicon entry as returned by the API
+#[derive(Serialize, ToSchema)]
+pub struct LexiconEntry {
+    id: i64,
+    grapheme: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phonemes: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replacement: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl LexiconEntry {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Self {
+        Self {
+            id: row.get("id"),
+            grapheme: row.get("grapheme"),
+            phonemes: row.get("phonemes"),
+            replacement: row.get("replacement"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+}
+
+/// Body of `POST /lexicon` and `PUT /lexicon/:id`
+#[derive(Deserialize, ToSchema)]
+pub struct LexiconEntryRequest {
+    /// Word or phrase as written, e.g. `Nguyen`
+    grapheme: String,
+    /// IPA phonemes to pronounce it with, e.g. `wɪn`
+    phonemes: Option<String>,
+    /// Text to read instead, e.g. `win`
+    replacement: Option<String>,
+}
+
+impl LexiconEntryRequest {
+    /// Trimmed grapheme, phonemes and replacement, exactly one of the latter two set
+    fn validate(self) -> Result<(String, Option<String>, Option<String>), String> {
+        let grapheme = self.grapheme.trim().to_string();
+        if !grapheme.chars().any(char::is_alphanumeric) {
+            return Err("grapheme must contain a letter or digit".to_string());
+        }
+        if grapheme.chars().count() > MAX_GRAPHEME_CHARS || grapheme.contains('\n') {
+            return Err(format!(
+                "grapheme must be a single line of at most {} characters",
+                MAX_GRAPHEME_CHARS
+            ));
+        }
+        let non_empty = |value: Option<String>| {
+            value
+                .map(|v| v.trim().trim_matches('/').trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let phonemes = non_empty(self.phonemes);
+        let replacement = self
+            .replacement
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        if phonemes.is_some() == replacement.is_some() {
+            return Err("Give exactly one of phonemes and replacement".to_string());
+        }
+        let pronunciation = phonemes.as_deref().or(replacement.as_deref()).unwrap_or("");
+        if pronunciation.chars().count() > MAX_PRONUNCIATION_CHARS || pronunciation.contains('\n') {
+            return Err(format!(
+                "phonemes and replacement must be a single line of at most {} characters",
+                MAX_PRONUNCIATION_CHARS
+            ));
+        }
+        Ok((grapheme, phonemes, replacement))
+    }
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    if e.as_database_error()
+        .is_some_and(|e| e.is_unique_violation())
+    {
+        return (
+            StatusCode::CONFLICT,
+            "An entry for this grapheme already exists".to_string(),
+        );
+    }
+    tracing::error!(error = %e, "Database error in lexicon");
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+/// `GET /lexicon`: the user's entries, alphabetically
+#[utoipa::path(
+    get,
+    path = "/lexicon",
+    tag = "lexicon",
+    responses((status = 200, body = Vec<LexiconEntry>))
+)]
+pub async fn list_entries(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<LexiconEntry>>, (StatusCode, String)> {
+    let rows = sqlx::query(
+        "SELECT id, grapheme, phonemes, replacement, created_at, updated_at FROM lexicon_entries WHERE username = $1 ORDER BY LOWER(grapheme)",
+    )
+    .bind(&user.username)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok(Json(rows.iter().map(LexiconEntry::from_row).collect()))
+}
+
+/// `POST /lexicon`: add an entry
+#[utoipa::path(
+    post,
+    path = "/lexicon",
+    tag = "lexicon",
+    request_body = LexiconEntryRequest,
+    responses(
+        (status = 201, body = LexiconEntry),
+        (status = 400, description = "Invalid entry, or the user has too many", body = String, content_type = "text/plain"),
+        (status = 409, description = "The user already has an entry for this grapheme", body = String, content_type = "text/plain"),
+    )
+)]
+pub async fn create_entry(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+    Json(body): Json<LexiconEntryRequest>,
+) -> Result<(StatusCode, Json<LexiconEntry>), (StatusCode, String)> {
+    let (grapheme, phonemes, replacement) =
+        body.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM lexicon_entries WHERE username = $1")
+        .bind(&user.username)
+        .fetch_one(&state.pool)
+        .await
+        .map_err(db_error)?;
+    if count >= MAX_ENTRIES {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("A lexicon can have at most {} entries", MAX_ENTRIES),
+        ));
+    }
+
+    let row = sqlx::query(
+        "INSERT INTO lexicon_entries (username, grapheme, phonemes, replacement) VALUES ($1, $2, $3, $4) RETURNING id, grapheme, phonemes, replacement, created_at, updated_at",
+    )
+    .bind(&user.username)
+    .bind(&grapheme)
+    .bind(&phonemes)
+    .bind(&replacement)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(db_error)?;
+    tracing::info!(user = %user.username, grapheme = %grapheme, "Added lexicon entry");
+    Ok((StatusCode::CREATED, Json(LexiconEntry::from_row(&row))))
+}
+
+/// `PUT /lexicon/:id`: replace an entry
+#[utoipa::path(
+    put,
+    path = "/lexicon/{id}",
+    tag = "lexicon",
+    params(("id" = i64, Path, description = "Entry ID")),
+    request_body = LexiconEntryRequest,
+    responses(
+        (status = 200, body = LexiconEntry),
+        (status = 400, description = "Invalid entry", body = String, content_type = "text/plain"),
+        (status = 404, description = "No such entry for this user", body = String, content_type = "text/plain"),
+        (status = 409, description = "The user already has another entry for this grapheme", body = String, content_type = "text/plain"),
+    )
+)]
+pub async fn update_entry(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(body): Json<LexiconEntryRequest>,
+) -> Result<Json<LexiconEntry>, (StatusCode, String)> {
+    let (grapheme, phonemes, replacement) =
+        body.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let row = sqlx::query(
+        "UPDATE lexicon_entries SET grapheme = $3, phonemes = $4, replacement = $5, updated_at = NOW() WHERE id = $1 AND username = $2 RETURNING id, grapheme, phonemes, replacement, created_at, updated_at",
+    )
+    .bind(id)
+    .bind(&user.username)
+    .bind(&grapheme)
+    .bind(&phonemes)
+    .bind(&replacement)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?
+    .ok_or((StatusCode::NOT_FOUND, "Lexicon entry not found".to_string()))?;
+    Ok(Json(LexiconEntry::from_row(&row)))
+}
+
+/// `DELETE /lexicon/:id`: remove an entry
+#[utoipa::path(
+    delete,
+    path = "/lexicon/{id}",
+    tag = "lexicon",
+    params(("id" = i64, Path, description = "Entry ID")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "No such entry for this user", body = String, content_type = "text/plain"),
+    )
+)]
+pub async fn delete_entry(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = sqlx::query("DELETE FROM lexicon_entries WHERE id = $1 AND username = $2")
+        .bind(id)
+        .bind(&user.username)
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Lexicon entry not found".to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    fn lexicon() -> Lexicon {
+        let entry = |grapheme: &str, pronunciation: Pronunciation| Entry {
+            grapheme: grapheme.to_string(),
+            pronunciation,
+        };
+        Lexicon::new(vec![
+            entry("Nguyen", Pronunciation::Phonemes("wˈɪn".to_string())),
+            entry("SQL", Pronunciation::Replacement("sequel".to_string())),
+            entry(
+                "SQL Server",
+                Pronunciation::Replacement("sequel server".to_string()),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_segments() {
+        let lexicon = lexicon();
+        assert_eq!(
+            lexicon.segments("Ask nguyen's team about SQL Server, not SQLite."),
+            [
+                Segment::Text("Ask ".to_string()),
+                Segment::Phonemes("wˈɪn".to_string()),
+                Segment::Text("'s team about sequel server, not SQLite.".to_string()),
+            ]
+        );
+        assert_eq!(
+            lexicon.segments("Nguyen"),
+            [Segment::Phonemes("wˈɪn".to_string())]
+        );
+        assert_eq!(lexicon.respell("Nguyen uses sql."), "Nguyen uses sequel.");
+        assert_eq!(Lexicon::default().respell("SQL"), "SQL");
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let lexicon = lexicon();
+        assert_eq!(lexicon.fingerprint("Nothing to see."), None);
+        assert_eq!(
+            lexicon.fingerprint("SQL and sql"),
+            lexicon.fingerprint("sql")
+        );
+        assert_ne!(lexicon.fingerprint("SQL"), lexicon.fingerprint("Nguyen"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let request = |grapheme: &str, phonemes: Option<&str>, replacement: Option<&str>| {
+            LexiconEntryRequest {
+                grapheme: grapheme.to_string(),
+                phonemes: phonemes.map(str::to_string),
+                replacement: replacement.map(str::to_string),
+            }
+            .validate()
+        };
+        assert_eq!(
+            request(" Nguyen ", Some("/wɪn/"), None).unwrap(),
+            ("Nguyen".to_string(), Some("wɪn".to_string()), None)
+        );
+        assert!(request("Nguyen", Some("wɪn"), Some("win")).is_err());
+        assert!(request("Nguyen", None, Some("  ")).is_err());
+        assert!(request("...", None, Some("dots")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lexicon_crud() {
+        let app = TestApp::spawn_without_workers().await;
+        let create = |username: &str, body: serde_json::Value| {
+            app.client
+                .post(app.url("/lexicon"))
+                .bearer_auth(app.token(username))
+                .json(&body)
+                .send()
+        };
+
+        let resp = create(
+            "alice",
+            serde_json::json!({ "grapheme": "Nguyen", "phonemes": "wɪn" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let entry: serde_json::Value = resp.json().await.unwrap();
+        let id = entry["id"].as_i64().unwrap();
+        assert_eq!(entry["phonemes"], "wɪn");
+
+        let resp = create(
+            "alice",
+            serde_json::json!({ "grapheme": "NGUYEN", "replacement": "win" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let resp = create(
+            "bob",
+            serde_json::json!({ "grapheme": "Nguyen", "replacement": "new yen" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = app
+            .client
+            .put(app.url(&format!("/lexicon/{}", id)))
+            .bearer_auth(app.token("alice"))
+            .json(&serde_json::json!({ "grapheme": "Nguyen", "replacement": "win" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let entries: serde_json::Value = app
+            .client
+            .get(app.url("/lexicon"))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        assert_eq!(entries[0]["replacement"], "win");
+        assert!(entries[0].get("phonemes").is_none());
+
+        let lexicon = Lexicon::load(&app.pool, "alice").await.unwrap();
+        assert_eq!(lexicon.respell("Hi Nguyen."), "Hi win.");
+
+        let delete = |username: &str| {
+            app.client
+                .delete(app.url(&format!("/lexicon/{}", id)))
+                .bearer_auth(app.token(username))
+                .send()
+        };
+        assert_eq!(delete("bob").await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            delete("alice").await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+        assert!(Lexicon::load(&app.pool, "alice").await.unwrap().is_empty());
+
+        app.teardown().await;
+    }
+}
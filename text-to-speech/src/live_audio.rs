@@ -0,0 +1,238 @@
+//! Binary audio frames of the WebSocket streams.
+//!
+//! Raw float samples are about 768 kbps at 24 kHz, a lot for clients on cellular, so a
+//! `synthesize` message can ask for a smaller `codec`: `pcm16` halves that and `opus`, at
+//! 32 kbps, cuts it about 24 times.
+//!
+//! Every frame starts with a header, see `framing`. `pcm_f32` frames, the default, follow
+//! it with the samples as f32 LE, `pcm16` ones with the samples as i16 LE, and `opus` ones
+//! with a single Opus packet of 20 ms of mono audio at the stream's sample rate.
+
+use crate::framing::{Framer, Framing};
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use serde::Deserialize;
+
+/// Opus bitrate of the streams, plenty for speech
+const OPUS_KBPS: i32 = 32;
+
+/// How streamed audio is sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    #[default]
+    PcmF32,
+    Pcm16,
+    Opus,
+}
+
+impl Codec {
+    /// Its number in frame headers
+    fn id(self) -> u8 {
+        match self {
+            Codec::PcmF32 => 0,
+            Codec::Pcm16 => 1,
+            Codec::Opus => 2,
+        }
+    }
+}
+
+/// Turns the samples of a stream into frames in the client's codec
+pub struct FrameEncoder {
+    codec: Codec,
+    framer: Framer,
+    opus: Option<OpusPackets>,
+}
+
+struct OpusPackets {
+    encoder: Encoder,
+    /// Samples in each 20 ms packet
+    frame_len: usize,
+    /// Samples waiting for a full packet
+    pending: Vec<f32>,
+    packet: Vec<u8>,
+}
+
+impl FrameEncoder {
+    pub fn new(codec: Codec, framing: Framing, sample_rate: u32) -> Result<Self, String> {
+        let opus = match codec {
+            Codec::Opus => {
+                let rate = SampleRate::try_from(sample_rate as i32)
+                    .map_err(|_| format!("Opus can't encode audio at {} Hz", sample_rate))?;
+                let mut encoder = Encoder::new(rate, Channels::Mono, Application::Voip)
+                    .map_err(|e| format!("Failed to create Opus encoder: {}", e))?;
+                encoder
+                    .set_bitrate(Bitrate::BitsPerSecond(OPUS_KBPS * 1000))
+                    .map_err(|e| format!("Failed to set Opus bitrate: {}", e))?;
+                Some(OpusPackets {
+                    encoder,
+                    frame_len: sample_rate as usize / 50,
+                    pending: Vec::new(),
+                    // Large enough for any Opus packet
+                    packet: vec![0; 4000],
+                })
+            }
+            Codec::PcmF32 | Codec::Pcm16 => None,
+        };
+        Ok(Self {
+            codec,
+            framer: Framer::new(framing, codec.id(), sample_rate),
+            opus,
+        })
+    }
+
+    /// Frames for `samples` of sentence `sentence_index`. Opus packets are only sent
+    /// whole, so samples short of one wait for the next call, and a packet holding the
+    /// end of one sentence and the start of the next carries the later index.
+    pub fn encode(&mut self, sentence_index: u32, samples: &[f32]) -> Result<Vec<Vec<u8>>, String> {
+        if let Some(opus) = &mut self.opus {
+            opus.pending.extend_from_slice(samples);
+            return opus.packets(&mut self.framer, sentence_index);
+        }
+        if samples.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut frame = self.framer.start_frame(sentence_index, samples.len());
+        match self.codec {
+            Codec::Pcm16 => {
+                frame.reserve(samples.len() * 2);
+                for sample in samples {
+                    let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                    frame.extend_from_slice(&sample.to_le_bytes());
+                }
+            }
+            _ => {
+                frame.reserve(samples.len() * 4);
+                for sample in samples {
+                    frame.extend_from_slice(&sample.to_le_bytes());
+                }
+            }
+        }
+        Ok(vec![frame])
+    }
+
+    /// Frames for the samples still held back, padded with silence so that they make it
+    /// through the encoder's delay. Called at the end of each batch of sentences.
+    pub fn finish(&mut self, sentence_index: u32) -> Result<Vec<Vec<u8>>, String> {
+        let Some(opus) = &mut self.opus else {
+            return Ok(Vec::new());
+        };
+        if opus.pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        let lookahead =
+            opus.encoder
+                .lookahead()
+                .map_err(|e| format!("Failed to get Opus lookahead: {}", e))? as usize;
+        let padded = (opus.pending.len() + lookahead).div_ceil(opus.frame_len) * opus.frame_len;
+        opus.pending.resize(padded, 0.0);
+        opus.packets(&mut self.framer, sentence_index)
+    }
+}
+
+impl OpusPackets {
+    /// A frame per whole packet in `pending`, leaving any remainder
+    fn packets(
+        &mut self,
+        framer: &mut Framer,
+        sentence_index: u32,
+    ) -> Result<Vec<Vec<u8>>, String> {
+        let frames = self.pending.len() / self.frame_len;
+        let mut packets = Vec::with_capacity(frames);
+        for samples in self.pending[..frames * self.frame_len].chunks_exact(self.frame_len) {
+            let len = self
+                .encoder
+                .encode_float(samples, &mut self.packet)
+                .map_err(|e| format!("Opus encoding failed: {}", e))?;
+            let mut frame = framer.start_frame(sentence_index, self.frame_len);
+            frame.extend_from_slice(&self.packet[..len]);
+            packets.push(frame);
+        }
+        self.pending.drain(..frames * self.frame_len);
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audiopus::coder::Decoder;
+
+    fn tone(len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / 24000.0).sin() * 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn test_codec_names() {
+        let codec = |name: &str| serde_json::from_value::<Codec>(serde_json::json!(name));
+        assert_eq!(codec("pcm_f32").unwrap(), Codec::PcmF32);
+        assert_eq!(codec("pcm16").unwrap(), Codec::Pcm16);
+        assert_eq!(codec("opus").unwrap(), Codec::Opus);
+        assert!(codec("mp3").is_err());
+    }
+
+    #[test]
+    fn test_pcm_frames() {
+        // The default stays as it was: no codec byte
+        let mut f32_frames = FrameEncoder::new(Codec::PcmF32, Framing::Legacy, 24000).unwrap();
+        let frames = f32_frames.encode(3, &[0.5, -1.0]).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(&frames[0][..4], &3u32.to_le_bytes());
+        assert_eq!(&frames[0][4..8], &0.5f32.to_le_bytes());
+        assert_eq!(frames[0].len(), 12);
+
+        let mut pcm16 = FrameEncoder::new(Codec::Pcm16, Framing::Legacy, 24000).unwrap();
+        let frames = pcm16.encode(3, &[0.5, -2.0]).unwrap();
+        assert_eq!(frames[0][4], 1);
+        assert_eq!(&frames[0][5..7], &16383i16.to_le_bytes());
+        assert_eq!(&frames[0][7..9], &(-i16::MAX).to_le_bytes());
+        assert!(pcm16.finish(3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_opus_frames() {
+        let mut encoder = FrameEncoder::new(Codec::Opus, Framing::Legacy, 24000).unwrap();
+        let audio = tone(2400 * 3 + 100);
+
+        // 100 ms chunks make 5 packets each; what doesn't fill a packet waits
+        let mut frames = encoder.encode(0, &audio[..2400]).unwrap();
+        assert_eq!(frames.len(), 5);
+        frames.extend(encoder.encode(1, &audio[2400..2500]).unwrap());
+        assert_eq!(frames.len(), 5);
+        frames.extend(encoder.encode(1, &audio[2500..]).unwrap());
+        frames.extend(encoder.finish(1).unwrap());
+        assert!(encoder.finish(1).unwrap().is_empty());
+
+        let mut decoder = Decoder::new(SampleRate::Hz24000, Channels::Mono).unwrap();
+        let mut decoded = 0;
+        let mut bytes = 0;
+        for frame in &frames {
+            assert_eq!(frame[4], 2);
+            bytes += frame.len();
+            let mut output = vec![0f32; 480];
+            let packet = (&frame[5..]).try_into().unwrap();
+            let output = (&mut output[..]).try_into().unwrap();
+            decoded += decoder.decode_float(Some(packet), output, false).unwrap();
+        }
+        assert_eq!(u32::from_le_bytes(frames[0][..4].try_into().unwrap()), 0);
+        assert_eq!(
+            u32::from_le_bytes(frames.last().unwrap()[..4].try_into().unwrap()),
+            1
+        );
+        // Everything comes out, in whole packets, far smaller than the float samples
+        assert!(decoded >= audio.len() && decoded % 480 == 0);
+        assert!(bytes * 10 < audio.len() * 4);
+
+        // With v1 headers, each packet is stamped with its place in the stream
+        let mut encoder = FrameEncoder::new(Codec::Opus, Framing::V1, 24000).unwrap();
+        let frames = encoder.encode(0, &audio[..2400]).unwrap();
+        assert_eq!(frames[4][5], 2);
+        assert_eq!(u32::from_le_bytes(frames[4][16..20].try_into().unwrap()), 4);
+        assert_eq!(
+            u32::from_le_bytes(frames[4][20..24].try_into().unwrap()),
+            80
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Log output.
+//!
+//! `LOG_FORMAT=json` writes one JSON object per line, for Loki or Elasticsearch to index
+//! instead of parsing text. Every HTTP request runs in a `request` span with its `method`,
+//! `route`, `request_id` and, once authenticated, `user`, and every batch job in a `job`
+//! span with its `job_id`, `user` and the `request_id` that created it, so the logs of a
+//! request or job all carry those fields.
+
+use crate::request_id::RequestId;
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::Instrument;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Parse `LOG_FORMAT`: `text` (default) or `json`
+pub fn parse_log_format(value: Option<&str>) -> Result<LogFormat, String> {
+    match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("") | Some("text") => Ok(LogFormat::Text),
+        Some("json") => Ok(LogFormat::Json),
+        Some(other) => Err(format!(
+            "Invalid LOG_FORMAT '{}', expected text or json",
+            other
+        )),
+    }
+}
+
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::init(),
+        // Fields of the current span, e.g. the request's route and user, go in `span`
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
+
+/// Runs the request in a `request` span; the auth middleware adds the user to it
+pub async fn request_span(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone());
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        route = %route,
+        request_id,
+        user = tracing::field::Empty,
+    );
+    next.run(request).instrument(span).await
+}
+
+/// Name the authenticated user on the current request's span
+pub fn record_user(username: &str) {
+    tracing::Span::current().record("user", username);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!(parse_log_format(None), Ok(LogFormat::Text));
+        assert_eq!(parse_log_format(Some(" JSON ")), Ok(LogFormat::Json));
+        assert!(parse_log_format(Some("logfmt")).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! EBU R128 loudness normalization of batch job output.
+//!
+//! Jobs can have their audio brought to a target integrated loudness, in LUFS, so that
+//! every voice plays back at the same volume, as does other podcast content mastered to
+//! the same target. It is applied by ffmpeg's `loudnorm` filter while encoding.
+
+use std::ops::RangeInclusive;
+
+/// Loudness targets accepted, in LUFS, as `loudnorm` takes them
+const LUFS_RANGE: RangeInclusive<f32> = -70.0..=-5.0;
+/// Highest true peak after normalization, in dBTP, leaving headroom for lossy encoding
+const TRUE_PEAK_DBTP: f32 = -1.5;
+/// Loudness range allowed, in LU; speech rarely needs more
+const LOUDNESS_RANGE_LU: f32 = 11.0;
+
+/// Parse a loudness target in LUFS, e.g. "-16". `0` turns normalization off (`None`).
+pub fn parse_loudness(value: &str) -> Result<Option<f32>, String> {
+    let lufs = value
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| format!("Invalid loudness '{}'", value))?;
+    if lufs == 0.0 {
+        return Ok(None);
+    }
+    if !LUFS_RANGE.contains(&lufs) {
+        return Err(format!(
+            "Loudness must be between {} and {} LUFS, or 0 for none",
+            LUFS_RANGE.start(),
+            LUFS_RANGE.end()
+        ));
+    }
+    Ok(Some(lufs))
+}
+
+/// Parse `TTS_LOUDNESS_LUFS`, the target of jobs that don't set one; unset is none
+pub fn parse_default_loudness(value: Option<&str>) -> Result<Option<f32>, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => parse_loudness(v).map_err(|e| format!("Invalid TTS_LOUDNESS_LUFS: {}", e)),
+        None => Ok(None),
+    }
+}
+
+/// ffmpeg audio filter normalizing audio at `sample_rate` to `lufs`. `loudnorm` works
+/// at 192 kHz, so its output is resampled back.
+pub fn loudnorm_filter(lufs: f32, sample_rate: u32) -> String {
+    format!(
+        "loudnorm=I={}:TP={}:LRA={},aresample={}",
+        lufs, TRUE_PEAK_DBTP, LOUDNESS_RANGE_LU, sample_rate
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_loudness() {
+        assert_eq!(parse_loudness(" -16 "), Ok(Some(-16.0)));
+        assert_eq!(parse_loudness("0"), Ok(None));
+        assert!(parse_loudness("-80").is_err());
+        assert!(parse_loudness("-1").is_err());
+        assert!(parse_loudness("loud").is_err());
+        assert_eq!(parse_default_loudness(None), Ok(None));
+        assert_eq!(parse_default_loudness(Some("-23")), Ok(Some(-23.0)));
+        assert!(parse_default_loudness(Some("5")).is_err());
+    }
+
+    #[test]
+    fn test_loudnorm_filter() {
+        assert_eq!(
+            loudnorm_filter(-16.0, 24000),
+            "loudnorm=I=-16:TP=-1.5:LRA=11,aresample=24000"
+        );
+    }
+}
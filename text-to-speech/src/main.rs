@@ -1,52 +1,123 @@
+mod admin;
+mod article;
+mod audit;
 mod auth;
+mod chapters;
 mod cleanup;
+mod config;
+mod cors;
+mod custom_voices;
+mod db;
+mod encode;
+mod engine;
+mod epub;
+mod error;
+#[cfg(feature = "espeak-ng")]
+mod espeak;
+mod format;
+mod framing;
+mod g2p;
 mod handlers;
+mod health;
+mod html_text;
 mod inference;
+mod join;
+mod lexicon;
+mod listen;
+mod live_audio;
+mod logging;
+mod loudness;
+mod model_download;
+mod model_loader;
+mod normalize;
+mod openapi;
+mod pauses;
+mod pdf;
 mod phonemizer;
+mod prosody;
+mod queue;
+mod quota;
+mod recovery;
+mod renditions;
+mod request_id;
+mod resample;
+mod signed_url;
+mod silence;
 mod state;
+mod storage;
+mod stream;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod upload_limit;
+mod usage;
+mod voices;
 mod ws_handler;
 
-use state::{AppState, JwksCache};
+use state::{AppState, JwksCache, ModelState};
 
 use axum::{
-    Router, middleware,
-    routing::{get, post},
+    Router,
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post, put},
 };
-use sqlx::postgres::PgPoolOptions;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::Instrument;
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    let settings = config::Settings::load().expect("Invalid configuration");
+    logging::init(settings.log_format);
+    // Loaded before waiting for the database, so a bad certificate is reported at once
+    let tls_acceptor = settings
+        .tls
+        .as_ref()
+        .map(|tls| tls::acceptor(tls).expect("Invalid TLS_CERT_PATH or TLS_KEY_PATH"));
 
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let storage_path = std::env::var("STORAGE_PATH").unwrap_or_else(|_| "/app/storage".to_string());
-    let keycloak_url = std::env::var("KEYCLOAK_URL")
-        .unwrap_or_else(|_| "http://keycloak.keycloak.svc.cluster.local".to_string());
-    let keycloak_realm = std::env::var("KEYCLOAK_REALM").unwrap_or_else(|_| "homekube".to_string());
-    let keycloak_audience =
-        std::env::var("KEYCLOAK_AUDIENCE").unwrap_or_else(|_| "tts".to_string());
+    let storage_path = settings.storage_path;
+    let scratch_path = settings.scratch_path;
+    let cleanup_config = settings.cleanup;
+    let audit_retention_days = settings.audit_retention_days;
+    let url_signer = settings
+        .download_url_secret
+        .map(|secret| Arc::new(signed_url::UrlSigner::new(&secret)));
 
     // Ensure storage directory exists
     tokio::fs::create_dir_all(&storage_path).await.unwrap();
 
-    // Load Kokoro ONNX model for live TTS (optional - may not exist in test mode)
-    let kokoro_model =
-        match inference::KokoroModel::load("/app/kokoro-v1.0.onnx", "/app/voices-v1.0.bin") {
-            Ok(model) => {
-                tracing::info!("Kokoro TTS model loaded successfully");
-                Some(model)
-            }
-            Err(e) => {
-                tracing::warn!("Failed to load Kokoro model (live TTS disabled): {}", e);
-                None
-            }
-        };
+    // Ensure scratch directory exists and clear anything a crashed run left behind
+    tokio::fs::create_dir_all(&scratch_path).await.unwrap();
+    if let Err(e) = cleanup::remove_stale_scratch_dirs(&scratch_path) {
+        tracing::error!(scratch_path = %scratch_path, error = %e, "Failed to sweep scratch directory");
+    }
+
+    // Load Kokoro ONNX model for live TTS in the background (optional - may not exist in test mode).
+    // Requests that need the model get 503 until loading finishes.
+    let kokoro_model = Arc::new(RwLock::new(ModelState::loading()));
+    let model_files = model_loader::ModelFiles {
+        model_path: settings.kokoro_model_path,
+        voices_path: settings.kokoro_voices_path,
+        fast_model_path: settings.kokoro_fast_model_path,
+        runtime: settings.model_runtime,
+        warmup: settings.model_warmup,
+    };
+    model_loader::spawn_load(
+        kokoro_model.clone(),
+        model_files.clone(),
+        settings.model_downloads,
+    );
 
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
+    // Optional per-language G2P models; everything else is phonemized with espeak-ng
+    let phonemizer = Arc::new(phonemizer::Phonemizer::load(
+        &settings.g2p_models,
+        settings.espeak_backend,
+        settings.phoneme_cache_size,
+    ));
+
+    let pool = db::connect(&settings.database_url, &settings.pool)
         .await
         .expect("Failed to connect to Postgres");
 
@@ -59,46 +130,248 @@ async fn main() {
     let state = AppState {
         pool: pool.clone(),
         storage_path,
+        storage_layout: settings.storage_layout,
+        scratch_path,
         jwks_cache: Arc::new(RwLock::new(JwksCache::default())),
-        keycloak_url,
-        keycloak_realm,
-        keycloak_audience,
+        keycloak_url: settings.keycloak_url,
+        keycloak_realm: settings.keycloak_realm,
+        keycloak_audience: settings.keycloak_audience,
+        api_keys: Arc::new(settings.api_keys),
+        url_signer,
         kokoro_model,
+        phonemizer,
+        job_queue: Arc::new(queue::JobQueue::default()),
+        retry_policy: settings.retry_policy,
+        active_jobs: Arc::new(RwLock::new(HashMap::new())),
+        usage_caps: Arc::new(settings.usage_caps),
+        character_quotas: Arc::new(settings.character_quotas),
+        cleanup: cleanup_config.clone(),
+        storage_quota: settings.storage_quota,
+        admin_users: Arc::new(settings.admin_users),
+        cors_origins: settings.cors_origins,
+        swagger_ui: settings.swagger_ui,
+        url_fetch_allow_private: settings.url_fetch_allow_private,
+        max_input_chars: settings.max_input_chars,
+        max_upload_bytes: settings.max_upload_bytes,
+        crossfade_ms: settings.crossfade_ms,
+        sentence_gap_ms: settings.sentence_gap_ms,
+        silence_trim: settings.silence_trim,
+        batch_engine: settings.batch_engine,
+        live_engine: settings.live_engine,
+        http_engine: settings.http_engine.map(Arc::new),
+        default_loudness: settings.default_loudness,
+        ws_keepalive: settings.ws_keepalive,
+        model_files: Arc::new(model_files),
+        model_versions: Arc::default(),
+        suspended_sessions: Arc::default(),
+        auth_disabled: settings.test_mode,
+        mock_synthesis: settings.test_mode,
     };
 
+    // Jobs left processing by a previous run have nothing working on them
+    if let Err(e) = recovery::recover_stuck_jobs(&state, &settings.recovery).await {
+        tracing::error!(error = %e, "Failed to recover interrupted jobs");
+    }
+    queue::spawn_workers(state.clone(), settings.max_concurrent_jobs);
+
     // Spawn cleanup task
     let cleanup_pool = pool.clone();
     let cleanup_storage = state.storage_path.clone();
-    tokio::spawn(async move {
-        // Run every hour
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(3600));
+    tracing::info!(
+        retention_days = cleanup_config.retention_days,
+        interval_secs = cleanup_config.interval.as_secs(),
+        orphans_dry_run = cleanup_config.orphans_dry_run,
+        "Cleanup configured"
+    );
+    let cleanup_task = async move {
+        let mut interval = tokio::time::interval(cleanup_config.interval);
         loop {
             interval.tick().await;
-            if let Err(e) = cleanup::run_cleanup(&cleanup_pool, &cleanup_storage).await {
-                tracing::error!(error = %e, "Cleanup task failed");
+            match cleanup::run_cleanup(
+                &cleanup_pool,
+                &cleanup_storage,
+                cleanup_config.retention_days,
+                cleanup_config.orphans_dry_run,
+            )
+            .await
+            {
+                Ok(report) => tracing::info!(
+                    jobs_removed = report.jobs_removed,
+                    bytes_removed = report.bytes_removed,
+                    orphans_found = report.orphans_found,
+                    "Cleanup finished"
+                ),
+                Err(e) => tracing::error!(error = %e, "Cleanup task failed"),
+            }
+            match audit::prune(&cleanup_pool, audit_retention_days).await {
+                Ok(removed) => tracing::info!(removed, "Pruned audit log"),
+                Err(e) => tracing::error!(error = %e, "Failed to prune audit log"),
             }
         }
-    });
+    };
+    tokio::spawn(cleanup_task.instrument(tracing::info_span!("cleanup")));
+
+    let app = app(state);
+
+    let listener = listen::bind(settings.listen_addr).await;
+    if let Some(acceptor) = tls_acceptor {
+        tracing::info!("Starting TTS server on {} with TLS", settings.listen_addr);
+        tls::serve(listener, acceptor, app).await;
+        return;
+    }
+    tracing::info!("Starting TTS server on {}", settings.listen_addr);
+    // Peer addresses are recorded in the audit log
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+/// All routes of the service
+fn app(state: AppState) -> Router {
+    // Rejects requests with 503 while the model is still loading
+    let model_gate =
+        middleware::from_fn_with_state(state.clone(), model_loader::require_model_ready);
+    // Records the request in the audit log; goes inside the auth layer
+    let audited = middleware::from_fn_with_state(state.clone(), audit::record);
+
+    // Operator routes, for users in ADMIN_USERS
+    let admin_routes = Router::new()
+        .route("/admin/cleanup", post(admin::trigger_cleanup))
+        .route("/admin/storage", get(admin::storage_usage))
+        .route("/admin/phonemizer", get(admin::phonemizer_stats))
+        .route("/admin/audit", get(audit::query_audit_log))
+        .route("/admin/model", get(model_loader::model_versions))
+        .route("/admin/model/reload", post(model_loader::reload_model))
+        .route(
+            "/admin/model/versions/:version",
+            delete(model_loader::remove_model_version),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            admin::require_admin,
+        ));
 
     // Routes requiring auth middleware
     let authed_routes = Router::new()
-        .route("/generate", post(handlers::generate_speech))
-        .route("/status/:id", get(handlers::check_status))
+        .route(
+            "/generate",
+            post(handlers::generate_speech)
+                .layer(model_gate.clone())
+                .layer(audited.clone()),
+        )
+        .route(
+            "/generate/epub",
+            post(handlers::generate_epub)
+                .layer(model_gate.clone())
+                .layer(audited.clone()),
+        )
+        .route(
+            "/generate/url",
+            post(handlers::generate_from_url)
+                .layer(model_gate.clone())
+                .layer(audited.clone()),
+        )
+        .route(
+            "/generate-json",
+            post(handlers::generate_speech_json)
+                .layer(model_gate.clone())
+                .layer(audited.clone()),
+        )
+        .route(
+            "/stream",
+            post(stream::stream_speech)
+                .layer(model_gate.clone())
+                .layer(audited.clone()),
+        )
+        .route(
+            "/status/:id",
+            get(handlers::check_status).layer(audited.clone()),
+        )
+        .route("/jobs/:id/input", get(handlers::job_input))
+        .route("/jobs/:id/artifacts", get(handlers::list_artifacts))
+        .route("/jobs/:id/signed-url", post(signed_url::create_signed_url))
+        .route("/jobs/:id/pin", post(handlers::pin_job))
+        .route("/jobs/:id/unpin", post(handlers::unpin_job))
         .route("/jobs", get(handlers::list_jobs))
+        .route("/me/usage", get(usage::get_usage))
+        .route("/usage", get(usage::get_character_usage))
+        .route(
+            "/lexicon",
+            get(lexicon::list_entries).post(lexicon::create_entry),
+        )
+        .route(
+            "/lexicon/:id",
+            put(lexicon::update_entry).delete(lexicon::delete_entry),
+        )
+        .route(
+            "/voices",
+            get(voices::list_voices).layer(model_gate.clone()),
+        )
+        .route("/voices/custom", get(custom_voices::list_custom_voices))
+        .route(
+            "/voices/custom/:name",
+            put(custom_voices::upload_custom_voice).delete(custom_voices::delete_custom_voice),
+        )
+        .merge(admin_routes)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            upload_limit::limit_body,
+        ))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth::auth_middleware,
         ));
 
+    // Signed links are checked by the handler instead of auth_middleware
+    let download_routes = Router::new().route(
+        "/download/:id",
+        get(handlers::download)
+            .layer(audited)
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                signed_url::auth_unless_signed,
+            )),
+    );
+
     // WebSocket route - auth is handled via first message, not middleware
-    let ws_routes = Router::new().route("/ws/live", get(ws_handler::ws_live_handler));
+    let ws_routes = Router::new()
+        .route("/ws/live", get(ws_handler::ws_live_handler))
+        .route("/jobs/:id/stream", get(ws_handler::job_stream_handler))
+        .layer(model_gate)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            cors::check_ws_origin,
+        ));
 
+    // Unauthenticated probe, status and API description routes
+    let mut public_routes = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/model/status", get(model_loader::model_status));
+    public_routes = if state.swagger_ui {
+        // Also serves /openapi.json
+        public_routes.merge(openapi::swagger_ui())
+    } else {
+        public_routes.route("/openapi.json", get(openapi::openapi_json))
+    };
+
+    let max_upload_bytes = state.max_upload_bytes;
+    let cors = state.cors_origins.as_ref().map(cors::AllowedOrigins::layer);
     let app = Router::new()
         .merge(authed_routes)
+        .merge(download_routes)
         .merge(ws_routes)
+        .merge(public_routes)
+        .layer(DefaultBodyLimit::max(max_upload_bytes))
+        .layer(middleware::from_fn(logging::request_span))
+        .layer(middleware::from_fn(request_id::set_request_id))
         .with_state(state);
-
-    tracing::info!("Starting TTS server on 0.0.0.0:3000");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // Outermost, so preflight requests are answered before they reach auth
+    match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    }
 }
@@ -0,0 +1,253 @@
+//! Download of missing model files at startup.
+//!
+//! The model and voices are about 300 MB, too much to bake into every image. When
+//! `KOKORO_MODEL_URL` or `KOKORO_VOICES_URL` is set and the file isn't at its configured
+//! path yet, it is downloaded there before the model loads. The download goes to a
+//! `.part` file next to the target and is only renamed into place once its SHA-256 matches
+//! the configured checksum, so a crash or a corrupt transfer never leaves a bad file
+//! behind. Failed attempts are retried with exponential backoff; until the files are
+//! present the model counts as loading, so `/readyz` fails.
+
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// Attempts per file before giving up and failing the model load
+const ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for each one after
+pub const RETRY_BASE: Duration = Duration::from_secs(2);
+
+/// A model file to fetch from `url` if it's missing from `path`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Download {
+    pub url: String,
+    /// Expected SHA-256 of the file, lowercase hex
+    pub sha256: String,
+    pub path: String,
+}
+
+impl Download {
+    /// Parse the URL and checksum settings of the file at `path`, e.g. `KOKORO_MODEL_URL`
+    /// and `KOKORO_MODEL_SHA256` for `name` `KOKORO_MODEL`. `None` if no URL is set.
+    pub fn parse(
+        name: &str,
+        path: &str,
+        url: Option<&str>,
+        sha256: Option<&str>,
+    ) -> Result<Option<Self>, String> {
+        let Some(url) = url.map(str::trim).filter(|url| !url.is_empty()) else {
+            return Ok(None);
+        };
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!(
+                "Invalid {}_URL '{}', expected an http(s) URL",
+                name, url
+            ));
+        }
+        let sha256 = sha256
+            .map(str::trim)
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if sha256.is_empty() {
+            return Err(format!("{}_SHA256 is required with {}_URL", name, name));
+        }
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("Invalid {}_SHA256 '{}'", name, sha256));
+        }
+        Ok(Some(Self {
+            url: url.to_string(),
+            sha256,
+            path: path.to_string(),
+        }))
+    }
+
+    /// Whether the file still has to be downloaded
+    pub fn is_missing(&self) -> bool {
+        !Path::new(&self.path).is_file()
+    }
+
+    fn part_path(&self) -> PathBuf {
+        let mut part = PathBuf::from(&self.path).into_os_string();
+        part.push(".part");
+        part.into()
+    }
+
+    /// Download the file once, replacing nothing unless its checksum matches
+    async fn fetch(&self, client: &reqwest::Client) -> Result<u64, String> {
+        let part_path = self.part_path();
+        if let Some(dir) = Path::new(&self.path).parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+
+        let mut response = client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to download {}: {}", self.url, e))?;
+        let mut file = tokio::fs::File::create(&part_path)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", part_path.display(), e))?;
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| format!("Failed to download {}: {}", self.url, e))?
+        {
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| format!("Failed to write {}: {}", part_path.display(), e))?;
+        }
+        file.sync_all()
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", part_path.display(), e))?;
+
+        let sha256 = hex::encode(hasher.finalize());
+        if sha256 != self.sha256 {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            return Err(format!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                self.url, self.sha256, sha256
+            ));
+        }
+        tokio::fs::rename(&part_path, &self.path)
+            .await
+            .map_err(|e| format!("Failed to move download to {}: {}", self.path, e))?;
+        Ok(size)
+    }
+}
+
+/// Download each file that isn't at its path yet, retrying failures `ATTEMPTS` times with
+/// backoff starting at `retry_base`
+pub async fn download_missing(downloads: &[Download], retry_base: Duration) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+    for download in downloads {
+        if !download.is_missing() {
+            continue;
+        }
+        tracing::info!(url = %download.url, path = %download.path, "Downloading model file");
+        let mut attempt = 1;
+        loop {
+            match download.fetch(&client).await {
+                Ok(size) => {
+                    tracing::info!(path = %download.path, size, "Downloaded model file");
+                    break;
+                }
+                Err(e) if attempt < ATTEMPTS => {
+                    let delay = retry_base * 2u32.pow(attempt - 1);
+                    tracing::warn!(
+                        error = %e,
+                        attempt,
+                        retry_in_secs = delay.as_secs_f64(),
+                        "Model download failed, retrying"
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &[u8] = b"not really a model";
+    /// SHA-256 of `CONTENT`
+    fn content_sha256() -> String {
+        hex::encode(Sha256::digest(CONTENT))
+    }
+
+    #[test]
+    fn test_parse() {
+        let sha256 = content_sha256();
+        assert_eq!(
+            Download::parse("KOKORO_MODEL", "/app/m.onnx", None, Some(&sha256)),
+            Ok(None)
+        );
+        let download = Download::parse(
+            "KOKORO_MODEL",
+            "/app/m.onnx",
+            Some("https://example.com/m.onnx"),
+            Some(&sha256.to_ascii_uppercase()),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(download.sha256, sha256);
+
+        let url = Some("https://example.com/m.onnx");
+        assert!(Download::parse("KOKORO_MODEL", "/app/m.onnx", url, None).is_err());
+        assert!(Download::parse("KOKORO_MODEL", "/app/m.onnx", url, Some("abc")).is_err());
+        assert!(
+            Download::parse(
+                "KOKORO_MODEL",
+                "/app/m.onnx",
+                Some("ftp://x/m"),
+                Some(&sha256)
+            )
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_missing() {
+        let site = axum::Router::new()
+            .route("/model.onnx", axum::routing::get(|| async { CONTENT }))
+            .route(
+                "/other.onnx",
+                axum::routing::get(|| async { "something else" }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, site).await.unwrap() });
+
+        let dir = tempfile::tempdir().unwrap();
+        let download = |name: &str| Download {
+            url: format!("http://{}/{}", addr, name),
+            sha256: content_sha256(),
+            path: dir
+                .path()
+                .join("models")
+                .join(name)
+                .to_string_lossy()
+                .into_owned(),
+        };
+
+        let model = download("model.onnx");
+        download_missing(std::slice::from_ref(&model), Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&model.path).unwrap(), CONTENT);
+        assert!(!model.part_path().exists());
+
+        // A file that's already there isn't fetched again
+        std::fs::write(&model.path, b"local").unwrap();
+        download_missing(std::slice::from_ref(&model), Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&model.path).unwrap(), b"local");
+
+        // Neither a wrong checksum nor a missing file leaves anything behind
+        for name in ["other.onnx", "missing.onnx"] {
+            let download = download(name);
+            let err = download_missing(std::slice::from_ref(&download), Duration::ZERO)
+                .await
+                .unwrap_err();
+            assert!(err.contains(&download.url), "{}", err);
+            assert!(!Path::new(&download.path).exists());
+            assert!(!download.part_path().exists());
+        }
+    }
+}
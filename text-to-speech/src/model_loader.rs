@@ -0,0 +1,463 @@
+//! Background loading of the Kokoro ONNX model.
+//!
+//! Loading the model and voice embeddings takes several seconds, so it runs on a
+//! blocking thread after the server starts, once any missing model files have been
+//! downloaded. Until it finishes, `/readyz` fails and routes that need the model respond
+//! with 503 and a `Retry-After` header.
+//!
+//! `POST /admin/model/reload` loads a new model while the current one keeps serving, then
+//! swaps it in. Synthesis already running finishes on the model it started with. The
+//! replaced model can be kept loaded under its version, the model file's name, so that
+//! clients pinned to it with `model_version` keep working during an upgrade.
+//!
+//! Both kinds of load warm the model up before it serves requests; `/readyz` and
+//! `/model/status` report how long that took.
+
+use crate::error::ApiError;
+use crate::inference::{KokoroModel, LoadProgress, LoadStage, RuntimeConfig, Warmup};
+use crate::model_download::{self, Download};
+use crate::state::{AppState, ModelState};
+use axum::{
+    extract::{Path, Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Seconds clients should wait before retrying while the model is loading
+pub const RETRY_AFTER_SECS: &str = "5";
+
+/// Response body for `GET /model/status`
+#[derive(Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum ModelStatusResponse {
+    Loading {
+        elapsed_secs: f64,
+        #[serde(flatten)]
+        progress: Option<LoadProgress>,
+    },
+    Ready {
+        version: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        warmup_ms: Option<u64>,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+/// The files the model is loaded from, and how it runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelFiles {
+    pub model_path: String,
+    pub voices_path: String,
+    /// Quantized model for the `fast` quality
+    pub fast_model_path: Option<String>,
+    pub runtime: RuntimeConfig,
+    pub warmup: Warmup,
+}
+
+impl ModelFiles {
+    /// Load the model and warm it up
+    fn load(&self, mut on_progress: impl FnMut(LoadProgress)) -> Result<Arc<KokoroModel>, String> {
+        for path in std::iter::once(&self.model_path).chain(&self.fast_model_path) {
+            if !std::path::Path::new(path).is_file() {
+                return Err(format!("Model file {} not found", path));
+            }
+        }
+        let model = KokoroModel::load_with_progress(
+            &self.model_path,
+            &self.voices_path,
+            self.fast_model_path.as_ref(),
+            self.runtime,
+            &mut on_progress,
+        )?;
+        model.warmup(&self.warmup, |warmed, total| {
+            on_progress(LoadProgress {
+                stage: LoadStage::Warmup,
+                voices_loaded: warmed,
+                voices_total: total,
+            })
+        });
+        Ok(model)
+    }
+}
+
+/// Models replaced by a reload but kept loaded for requests pinned to their version
+#[derive(Default)]
+pub struct ModelVersions {
+    previous: std::sync::RwLock<HashMap<String, Arc<KokoroModel>>>,
+    /// Held while a reload runs, so that two don't load at once
+    reloading: tokio::sync::Mutex<()>,
+}
+
+impl ModelVersions {
+    fn get(&self, version: &str) -> Option<Arc<KokoroModel>> {
+        self.previous.read().unwrap().get(version).cloned()
+    }
+
+    fn keep(&self, model: Arc<KokoroModel>) {
+        let version = model.version().to_string();
+        self.previous.write().unwrap().insert(version, model);
+    }
+
+    fn remove(&self, version: &str) -> Option<Arc<KokoroModel>> {
+        self.previous.write().unwrap().remove(version)
+    }
+
+    /// Versions kept, sorted
+    fn versions(&self) -> Vec<String> {
+        let mut versions: Vec<_> = self.previous.read().unwrap().keys().cloned().collect();
+        versions.sort();
+        versions
+    }
+}
+
+/// The current model, or the one of `version` if a request is pinned to one
+pub async fn model_for(
+    state: &AppState,
+    version: Option<&str>,
+) -> Result<Arc<KokoroModel>, ApiError> {
+    let current = state.kokoro_model.read().await.model().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "model_not_loaded",
+            "TTS model not loaded",
+        )
+    })?;
+    match version {
+        Some(version) if version != current.version() => {
+            state.model_versions.get(version).ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "unknown_model_version",
+                    format!("Unknown model version '{}'", version),
+                )
+            })
+        }
+        _ => Ok(current),
+    }
+}
+
+/// Download whichever of `downloads` are missing, then load the model on a blocking
+/// thread, recording progress in `model_state`
+pub fn spawn_load(
+    model_state: Arc<RwLock<ModelState>>,
+    files: ModelFiles,
+    downloads: Vec<Download>,
+) {
+    tokio::spawn(async move {
+        if downloads.iter().any(Download::is_missing)
+            && let ModelState::Loading { progress, .. } = &mut *model_state.write().await
+        {
+            *progress = Some(LoadProgress {
+                stage: LoadStage::Download,
+                voices_loaded: 0,
+                voices_total: 0,
+            });
+        }
+        if let Err(e) =
+            model_download::download_missing(&downloads, model_download::RETRY_BASE).await
+        {
+            tracing::error!(error = %e, "Failed to download model files (live TTS disabled)");
+            *model_state.write().await = ModelState::Failed(e);
+            return;
+        }
+        tokio::task::spawn_blocking(move || load(&model_state, &files));
+    });
+}
+
+fn load(model_state: &RwLock<ModelState>, files: &ModelFiles) {
+    let result = files.load(|update| {
+        if let ModelState::Loading { progress, .. } = &mut *model_state.blocking_write() {
+            *progress = Some(update);
+        }
+    });
+
+    match result {
+        Ok(model) => {
+            tracing::info!("Kokoro TTS model loaded successfully");
+            *model_state.blocking_write() = ModelState::Ready(model.clone());
+            model.benchmark();
+        }
+        Err(e) => {
+            tracing::warn!("Failed to load Kokoro model (live TTS disabled): {}", e);
+            *model_state.blocking_write() = ModelState::Failed(e);
+        }
+    }
+}
+
+fn model_loading_response() -> Response {
+    let mut response = ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "model_loading",
+        "TTS model is still loading",
+    )
+    .into_response();
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from_static(RETRY_AFTER_SECS),
+    );
+    response
+}
+
+/// How long `model` took to warm up, in milliseconds
+pub fn warmup_ms(model: &KokoroModel) -> Option<u64> {
+    model.warmup_time().map(|time| time.as_millis() as u64)
+}
+
+/// Reject requests with 503 while the model is still loading
+pub async fn require_model_ready(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.kokoro_model.read().await.is_loading() {
+        tracing::debug!(path = %request.uri().path(), "Rejecting request while model is loading");
+        return model_loading_response();
+    }
+    next.run(request).await
+}
+
+/// Report model load progress
+pub async fn model_status(State(state): State<AppState>) -> Json<ModelStatusResponse> {
+    let response = match &*state.kokoro_model.read().await {
+        ModelState::Loading {
+            started_at,
+            progress,
+        } => ModelStatusResponse::Loading {
+            elapsed_secs: started_at.elapsed().as_secs_f64(),
+            progress: progress.clone(),
+        },
+        ModelState::Ready(model) => ModelStatusResponse::Ready {
+            version: model.version().to_string(),
+            warmup_ms: warmup_ms(model),
+        },
+        ModelState::Failed(message) => ModelStatusResponse::Failed {
+            message: message.clone(),
+        },
+    };
+    Json(response)
+}
+
+/// Body of `POST /admin/model/reload`; paths default to the configured ones
+#[derive(Deserialize)]
+pub struct ReloadRequest {
+    model_path: Option<String>,
+    voices_path: Option<String>,
+    fast_model_path: Option<String>,
+    /// Keep the replaced model loaded for requests pinned to its version
+    #[serde(default)]
+    keep_previous: bool,
+}
+
+/// Response of the `/admin/model` endpoints
+#[derive(Debug, Serialize)]
+pub struct ModelVersionsResponse {
+    /// Version of the current model; `None` if none is loaded
+    current: Option<String>,
+    /// Versions kept loaded for pinned requests
+    previous: Vec<String>,
+}
+
+async fn versions_response(state: &AppState) -> ModelVersionsResponse {
+    ModelVersionsResponse {
+        current: state
+            .kokoro_model
+            .read()
+            .await
+            .model()
+            .map(|model| model.version().to_string()),
+        previous: state.model_versions.versions(),
+    }
+}
+
+/// `GET /admin/model`: the loaded model versions
+pub async fn model_versions(State(state): State<AppState>) -> Json<ModelVersionsResponse> {
+    Json(versions_response(&state).await)
+}
+
+/// `POST /admin/model/reload`: load a model and make it the current one. The current model
+/// serves requests until the new one has loaded; if it fails to load, nothing changes.
+pub async fn reload_model(
+    State(state): State<AppState>,
+    Json(body): Json<ReloadRequest>,
+) -> Result<Json<ModelVersionsResponse>, ApiError> {
+    let _reloading = state.model_versions.reloading.try_lock().map_err(|_| {
+        ApiError::new(
+            StatusCode::CONFLICT,
+            "reload_in_progress",
+            "A model reload is already running",
+        )
+    })?;
+    if state.kokoro_model.read().await.is_loading() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "model_loading",
+            "The model is still loading",
+        ));
+    }
+    let files = ModelFiles {
+        model_path: body
+            .model_path
+            .unwrap_or_else(|| state.model_files.model_path.clone()),
+        voices_path: body
+            .voices_path
+            .unwrap_or_else(|| state.model_files.voices_path.clone()),
+        fast_model_path: body
+            .fast_model_path
+            .or_else(|| state.model_files.fast_model_path.clone()),
+        runtime: state.model_files.runtime,
+        warmup: state.model_files.warmup.clone(),
+    };
+    tracing::info!(model_path = %files.model_path, "Reloading Kokoro model");
+    let model = tokio::task::spawn_blocking(move || files.load(|_| {}))
+        .await
+        .map_err(|e| ApiError::internal(format!("Model reload failed: {}", e)))?
+        .map_err(|e| {
+            tracing::error!(error = %e, "Model reload failed, keeping the current model");
+            ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "model_load_failed", e)
+        })?;
+
+    let replaced = std::mem::replace(
+        &mut *state.kokoro_model.write().await,
+        ModelState::Ready(model.clone()),
+    );
+    // The new model now serves its own version
+    state.model_versions.remove(model.version());
+    if let ModelState::Ready(previous) = replaced
+        && body.keep_previous
+        && previous.version() != model.version()
+    {
+        state.model_versions.keep(previous);
+    }
+    tracing::info!(version = model.version(), "Kokoro model reloaded");
+    tokio::task::spawn_blocking({
+        let model = model.clone();
+        move || model.benchmark()
+    });
+    Ok(Json(versions_response(&state).await))
+}
+
+/// `DELETE /admin/model/versions/:version`: unload a kept model. Requests still using it
+/// finish first.
+pub async fn remove_model_version(
+    State(state): State<AppState>,
+    Path(version): Path<String>,
+) -> Result<Json<ModelVersionsResponse>, ApiError> {
+    state.model_versions.remove(&version).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_version_not_found",
+            format!("Model version '{}' is not kept", version),
+        )
+    })?;
+    tracing::info!(version, "Unloaded Kokoro model version");
+    Ok(Json(versions_response(&state).await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_loading_status_flattens_progress() {
+        let status = ModelStatusResponse::Loading {
+            elapsed_secs: 1.5,
+            progress: Some(LoadProgress {
+                stage: LoadStage::Voices,
+                voices_loaded: 10,
+                voices_total: 50,
+            }),
+        };
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["state"], "loading");
+        assert_eq!(json["stage"], "voices");
+        assert_eq!(json["voices_loaded"], 10);
+        assert_eq!(json["voices_total"], 50);
+
+        let json = serde_json::to_value(ModelStatusResponse::Failed {
+            message: "missing".to_string(),
+        })
+        .unwrap();
+        assert_eq!(json["state"], "failed");
+        assert_eq!(json["message"], "missing");
+    }
+
+    #[tokio::test]
+    async fn test_reload_model() {
+        let app = TestApp::spawn_without_workers().await;
+        let admin = |method: reqwest::Method, path: &str| {
+            app.client
+                .request(method, app.url(path))
+                .bearer_auth(app.token(TestApp::ADMIN))
+        };
+
+        // A model that can't be loaded leaves things as they were
+        let resp = admin(reqwest::Method::POST, "/admin/model/reload")
+            .json(&serde_json::json!({ "model_path": "/nonexistent/kokoro-v2.onnx" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "model_load_failed");
+        assert!(app.state.kokoro_model.read().await.model().is_none());
+
+        let body: serde_json::Value = admin(reqwest::Method::GET, "/admin/model")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body, serde_json::json!({ "current": null, "previous": [] }));
+
+        let resp = admin(reqwest::Method::DELETE, "/admin/model/versions/kokoro-v1.0")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "model_version_not_found");
+
+        // Pinning needs a loaded model
+        let Err(error) = model_for(&app.state, Some("kokoro-v1.0")).await else {
+            panic!("No model is loaded");
+        };
+        assert_eq!(error.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.code, "model_not_loaded");
+
+        app.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn test_model_gate() {
+        let app = TestApp::spawn_without_workers().await;
+        *app.state.kokoro_model.write().await = ModelState::loading();
+
+        let resp = app
+            .client
+            .post(app.url("/generate-json"))
+            .bearer_auth(app.token("alice"))
+            .json(&serde_json::json!({ "text": "Hello." }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], RETRY_AFTER_SECS);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            crate::error::PROBLEM_CONTENT_TYPE
+        );
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "model_loading");
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,468 @@
+//! Text normalization: expands numbers, currency, dates, times and abbreviations into
+//! the words a reader would say, before the text is split into sentences and phonemized.
+//!
+//! espeak-ng reads "$5.99" as "dollar five point nine nine" and splits sentences at the
+//! period in "Dr.", which is jarring in long-form listening. Rules are applied in order,
+//! most specific first, so that e.g. a date isn't read as three separate numbers.
+
+use regex::{Captures, Regex};
+use std::sync::LazyLock;
+
+const ONES: [&str; 20] = [
+    "zero",
+    "one",
+    "two",
+    "three",
+    "four",
+    "five",
+    "six",
+    "seven",
+    "eight",
+    "nine",
+    "ten",
+    "eleven",
+    "twelve",
+    "thirteen",
+    "fourteen",
+    "fifteen",
+    "sixteen",
+    "seventeen",
+    "eighteen",
+    "nineteen",
+];
+const TENS: [&str; 10] = [
+    "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+];
+const SCALES: [(u64, &str); 4] = [
+    (1_000_000_000_000, "trillion"),
+    (1_000_000_000, "billion"),
+    (1_000_000, "million"),
+    (1_000, "thousand"),
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Longer digit strings are read one digit at a time
+const MAX_CARDINAL_DIGITS: usize = 15;
+
+static ABBREVIATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b(?:Dr|Mr|Mrs|Ms|Prof|St|Mt|Jr|Sr|No|vs|etc|approx|e\.g|i\.e)\.").unwrap()
+});
+static ISO_DATE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(\d{4})-(\d{2})-(\d{2})\b").unwrap());
+static TIME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d{1,2}):(\d{2})\b").unwrap());
+static CURRENCY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"([$€£])(\d{1,3}(?:,\d{3})+|\d+)(?:\.(\d+))?(?:\s+(thousand|million|billion|trillion)\b)?",
+    )
+    .unwrap()
+});
+static PERCENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(\d{1,3}(?:,\d{3})+|\d+)(?:\.(\d+))?\s?%").unwrap());
+static ORDINAL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(\d+)(?:st|nd|rd|th)\b").unwrap());
+static FRACTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(\d{1,3})/(\d{1,3})\b").unwrap());
+static NUMBER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"-?(?:\d{1,3}(?:,\d{3})+|\d+)(?:\.\d+)?").unwrap());
+
+/// `normalize` for text in `lang`. The rules are English, so text in other languages is
+/// left to espeak-ng.
+pub fn normalize_lang(text: &str, lang: &str) -> String {
+    if lang.starts_with("en") {
+        normalize(text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// `text` with numbers, currency, dates, times and common abbreviations spelled out
+pub fn normalize(text: &str) -> String {
+    let text = ABBREVIATION.replace_all(text, |caps: &Captures| expand_abbreviation(text, caps));
+    let text = ISO_DATE.replace_all(&text, |caps: &Captures| {
+        let year: u64 = caps[1].parse().unwrap_or(0);
+        let month: usize = caps[2].parse().unwrap_or(0);
+        let day: u64 = caps[3].parse().unwrap_or(0);
+        if (1..=12).contains(&month) && (1..=31).contains(&day) {
+            format!(
+                "{} {}, {}",
+                MONTHS[month - 1],
+                ordinal(day),
+                year_words(year)
+            )
+        } else {
+            caps[0].to_string()
+        }
+    });
+    let text = TIME.replace_all(&text, |caps: &Captures| {
+        let hour: u64 = caps[1].parse().unwrap_or(99);
+        let minute: u64 = caps[2].parse().unwrap_or(99);
+        match (hour, minute) {
+            (0..=23, 0) if hour <= 12 => format!("{} o'clock", cardinal(hour)),
+            (0..=23, 0) => format!("{} hundred", cardinal(hour)),
+            (0..=23, 1..=9) => format!("{} oh {}", cardinal(hour), cardinal(minute)),
+            (0..=23, 10..=59) => format!("{} {}", cardinal(hour), cardinal(minute)),
+            _ => caps[0].to_string(),
+        }
+    });
+    let text = CURRENCY.replace_all(&text, expand_currency);
+    let text = PERCENT.replace_all(&text, |caps: &Captures| {
+        format!(
+            "{} percent",
+            decimal(&caps[1], caps.get(2).map(|m| m.as_str()))
+        )
+    });
+    let text = ORDINAL.replace_all(&text, |caps: &Captures| match caps[1].parse() {
+        Ok(n) if caps[1].len() <= MAX_CARDINAL_DIGITS => ordinal(n),
+        _ => caps[0].to_string(),
+    });
+    let text = FRACTION.replace_all(&text, |caps: &Captures| {
+        let whole = caps.get(0).unwrap();
+        let before = text[..whole.start()].chars().next_back();
+        let after = text[whole.end()..].chars().next();
+        // Part of a longer path or date like 5/1/2024
+        if before == Some('/') || after == Some('/') {
+            return caps[0].to_string();
+        }
+        match (caps[1].parse(), caps[2].parse()) {
+            (Ok(numerator), Ok(denominator)) if denominator > 1 => fraction(numerator, denominator),
+            _ => caps[0].to_string(),
+        }
+    });
+    let text = NUMBER.replace_all(&text, |caps: &Captures| expand_number(&text, caps));
+    text.into_owned()
+}
+
+fn expand_abbreviation(text: &str, caps: &Captures) -> String {
+    let whole = caps.get(0).unwrap();
+    let rest = &text[whole.end()..];
+    let next_word = rest.trim_start().chars().next();
+    let followed_by_space = rest.starts_with(char::is_whitespace);
+    let before_name = followed_by_space && next_word.is_some_and(char::is_uppercase);
+    let before_number = followed_by_space && next_word.is_some_and(|c| c.is_ascii_digit());
+    // The period may also have ended the sentence
+    let ends_sentence = rest.trim().is_empty() || before_name;
+
+    let (word, is_title) = match whole.as_str() {
+        "Dr." if before_name => ("Doctor", true),
+        "Dr." => ("Drive", false),
+        "St." if before_name => ("Saint", true),
+        "St." => ("Street", false),
+        "Mr." => ("Mister", true),
+        "Mrs." => ("Missus", true),
+        "Ms." => ("Miz", true),
+        "Prof." => ("Professor", true),
+        "Mt." => ("Mount", true),
+        "No." if before_number => ("number", true),
+        "No." => return whole.as_str().to_string(),
+        "Jr." => ("Junior", false),
+        "Sr." => ("Senior", false),
+        "vs." => ("versus", true),
+        "etc." => ("et cetera", false),
+        "approx." => ("approximately", true),
+        "e.g." => ("for example", true),
+        "i.e." => ("that is", true),
+        _ => return whole.as_str().to_string(),
+    };
+    if !is_title && ends_sentence {
+        format!("{}.", word)
+    } else {
+        word.to_string()
+    }
+}
+
+fn expand_currency(caps: &Captures) -> String {
+    let (unit, units, cent, cents) = match &caps[1] {
+        "€" => ("euro", "euros", "cent", "cents"),
+        "£" => ("pound", "pounds", "penny", "pence"),
+        _ => ("dollar", "dollars", "cent", "cents"),
+    };
+    let whole_digits = caps[2].replace(',', "");
+    let fraction_digits = caps.get(3).map(|m| m.as_str());
+
+    // "$1.5 million" is "one point five million dollars"
+    if let Some(scale) = caps.get(4) {
+        return format!(
+            "{} {} {}",
+            decimal(&whole_digits, fraction_digits),
+            scale.as_str(),
+            units
+        );
+    }
+
+    let Ok(whole) = whole_digits.parse::<u64>() else {
+        return caps[0].to_string();
+    };
+    let minor = match fraction_digits {
+        None => 0,
+        Some(digits) if digits.len() == 1 => digits.parse::<u64>().unwrap_or(0) * 10,
+        Some(digits) if digits.len() == 2 => digits.parse().unwrap_or(0),
+        // Not a price, e.g. a rate of $0.0125
+        Some(digits) => {
+            return format!("{} {}", decimal(&whole_digits, Some(digits)), units);
+        }
+    };
+    let major_words = format!(
+        "{} {}",
+        cardinal(whole),
+        if whole == 1 { unit } else { units }
+    );
+    let minor_words = format!(
+        "{} {}",
+        cardinal(minor),
+        if minor == 1 { cent } else { cents }
+    );
+    match (whole, minor) {
+        (_, 0) => major_words,
+        (0, _) => minor_words,
+        _ => format!("{} and {}", major_words, minor_words),
+    }
+}
+
+fn expand_number(text: &str, caps: &Captures) -> String {
+    let whole = caps.get(0).unwrap();
+    let before = text[..whole.start()].chars().next_back();
+    let after = text[whole.end()..].chars().next();
+    let signed = whole.as_str().starts_with('-');
+    // Part of a word or code, like "mp3" or "R2D2"
+    if (!signed && before.is_some_and(char::is_alphanumeric))
+        || after.is_some_and(char::is_alphanumeric)
+    {
+        return whole.as_str().to_string();
+    }
+
+    let mut number = whole.as_str();
+    let mut prefix = "";
+    if let Some(unsigned) = number.strip_prefix('-') {
+        number = unsigned;
+        // A minus sign only at the start of a word; otherwise a hyphen, as in "10-20"
+        prefix = if before.is_none_or(|c| c.is_whitespace() || c == '(') {
+            "minus "
+        } else {
+            "-"
+        };
+    }
+    let (integer, fraction) = match number.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (number, None),
+    };
+
+    // Four digits on their own are most often a year
+    if fraction.is_none()
+        && prefix.is_empty()
+        && integer.len() == 4
+        && let Ok(year) = integer.parse::<u64>()
+        && ((1100..=1999).contains(&year) || (2010..=2099).contains(&year))
+    {
+        return year_words(year);
+    }
+    format!("{}{}", prefix, decimal(&integer.replace(',', ""), fraction))
+}
+
+/// An integer and optional fractional part as words, e.g. "three point one four"
+fn decimal(integer: &str, fraction: Option<&str>) -> String {
+    let integer = integer.replace(',', "");
+    let mut words = match integer.parse::<u64>() {
+        // Leading zeros, as in "007", are read digit by digit
+        Ok(n)
+            if integer.len() <= MAX_CARDINAL_DIGITS
+                && !(integer.len() > 1 && integer.starts_with('0')) =>
+        {
+            cardinal(n)
+        }
+        _ => digits(&integer),
+    };
+    if let Some(fraction) = fraction.filter(|f| !f.is_empty()) {
+        words.push_str(" point ");
+        words.push_str(&digits(fraction));
+    }
+    words
+}
+
+/// Each digit as a word, e.g. "one two three"
+fn digits(digits: &str) -> String {
+    digits
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .map(|d| ONES[d as usize])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `n` in words, e.g. "one thousand two hundred thirty-four"
+pub fn cardinal(n: u64) -> String {
+    if n < 20 {
+        return ONES[n as usize].to_string();
+    }
+    if n < 100 {
+        let tens = TENS[(n / 10) as usize];
+        return match n % 10 {
+            0 => tens.to_string(),
+            ones => format!("{}-{}", tens, ONES[ones as usize]),
+        };
+    }
+    if n < 1000 {
+        let hundreds = format!("{} hundred", ONES[(n / 100) as usize]);
+        return match n % 100 {
+            0 => hundreds,
+            rest => format!("{} {}", hundreds, cardinal(rest)),
+        };
+    }
+    let (scale, name) = SCALES
+        .iter()
+        .find(|(scale, _)| n >= *scale)
+        .expect("n is at least 1000");
+    let head = format!("{} {}", cardinal(n / scale), name);
+    match n % scale {
+        0 => head,
+        rest => format!("{} {}", head, cardinal(rest)),
+    }
+}
+
+/// `n` as an ordinal, e.g. "twenty-third"
+pub fn ordinal(n: u64) -> String {
+    let words = cardinal(n);
+    let split = words.rfind([' ', '-']).map_or(0, |i| i + 1);
+    let (head, last) = words.split_at(split);
+    let last = match last {
+        "one" => "first".to_string(),
+        "two" => "second".to_string(),
+        "three" => "third".to_string(),
+        "five" => "fifth".to_string(),
+        "eight" => "eighth".to_string(),
+        "nine" => "ninth".to_string(),
+        "twelve" => "twelfth".to_string(),
+        word if word.ends_with('y') => format!("{}ieth", &word[..word.len() - 1]),
+        word => format!("{}th", word),
+    };
+    format!("{}{}", head, last)
+}
+
+/// A year as it is usually said, e.g. "nineteen eighty-four" or "two thousand five"
+fn year_words(year: u64) -> String {
+    if !(1100..=2099).contains(&year) || (2000..2010).contains(&year) {
+        return cardinal(year);
+    }
+    let (century, rest) = (year / 100, year % 100);
+    match rest {
+        0 => format!("{} hundred", cardinal(century)),
+        1..=9 => format!("{} oh {}", cardinal(century), cardinal(rest)),
+        _ => format!("{} {}", cardinal(century), cardinal(rest)),
+    }
+}
+
+fn fraction(numerator: u64, denominator: u64) -> String {
+    let singular = match denominator {
+        2 => "half".to_string(),
+        4 => "quarter".to_string(),
+        _ => ordinal(denominator),
+    };
+    if numerator == 1 {
+        return format!("one {}", singular);
+    }
+    let plural = match denominator {
+        2 => "halves".to_string(),
+        _ => format!("{}s", singular),
+    };
+    format!("{} {}", cardinal(numerator), plural)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_lang() {
+        assert_eq!(normalize_lang("Dr. No", "en-gb"), "Doctor No");
+        assert_eq!(normalize_lang("Cuesta $5.", "es"), "Cuesta $5.");
+    }
+
+    #[test]
+    fn test_cardinal_and_ordinal() {
+        assert_eq!(cardinal(0), "zero");
+        assert_eq!(cardinal(42), "forty-two");
+        assert_eq!(cardinal(1_234), "one thousand two hundred thirty-four");
+        assert_eq!(cardinal(3_000_005), "three million five");
+        assert_eq!(ordinal(1), "first");
+        assert_eq!(ordinal(12), "twelfth");
+        assert_eq!(ordinal(20), "twentieth");
+        assert_eq!(ordinal(23), "twenty-third");
+        assert_eq!(ordinal(101), "one hundred first");
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            normalize("1,234 people"),
+            "one thousand two hundred thirty-four people"
+        );
+        assert_eq!(normalize("pi is 3.14"), "pi is three point one four");
+        assert_eq!(normalize("It was -5 outside"), "It was minus five outside");
+        assert_eq!(normalize("pages 10-20"), "pages ten-twenty");
+        assert_eq!(
+            normalize("In 1984 and 2005, 2024."),
+            "In nineteen eighty-four and two thousand five, twenty twenty-four."
+        );
+        assert_eq!(normalize("agent 007"), "agent zero zero seven");
+        assert_eq!(normalize("an mp3 of R2D2"), "an mp3 of R2D2");
+        assert_eq!(normalize("1,2,3"), "one,two,three");
+    }
+
+    #[test]
+    fn test_currency_and_percent() {
+        assert_eq!(normalize("$5.99"), "five dollars and ninety-nine cents");
+        assert_eq!(normalize("$1"), "one dollar");
+        assert_eq!(normalize("$0.50"), "fifty cents");
+        assert_eq!(normalize("£2.01"), "two pounds and one penny");
+        assert_eq!(normalize("€1,000"), "one thousand euros");
+        assert_eq!(normalize("$1.5 million"), "one point five million dollars");
+        assert_eq!(normalize("up 12.5%"), "up twelve point five percent");
+    }
+
+    #[test]
+    fn test_dates_times_fractions() {
+        assert_eq!(normalize("2024-05-01"), "May first, twenty twenty-four");
+        assert_eq!(
+            normalize("at 3:05 or 9:30"),
+            "at three oh five or nine thirty"
+        );
+        assert_eq!(normalize("at 3:00"), "at three o'clock");
+        assert_eq!(
+            normalize("3/4 cup and 1/2 tsp"),
+            "three quarters cup and one half tsp"
+        );
+        assert_eq!(normalize("2/3"), "two thirds");
+        assert_eq!(normalize("the 21st century"), "the twenty-first century");
+    }
+
+    #[test]
+    fn test_abbreviations() {
+        assert_eq!(
+            normalize("Dr. Smith lives on Elm Dr."),
+            "Doctor Smith lives on Elm Drive."
+        );
+        assert_eq!(normalize("Mr. and Mrs. Jones"), "Mister and Missus Jones");
+        assert_eq!(
+            normalize("St. Louis, Main St. and No. 5"),
+            "Saint Louis, Main Street and number five"
+        );
+        assert_eq!(normalize("Say no. Then go."), "Say no. Then go.");
+        assert_eq!(
+            normalize("apples, pears, etc. And more"),
+            "apples, pears, et cetera. And more"
+        );
+        assert_eq!(normalize("fruit, e.g. apples"), "fruit, for example apples");
+    }
+}
@@ -0,0 +1,201 @@
+//! OpenAPI description of the HTTP API, served at `/openapi.json`, with an optional
+//! Swagger UI at `/docs` (`SWAGGER_UI=true`).
+//!
+//! Errors are plain-text bodies with a status code; each path lists the codes it returns.
+
+use axum::response::Json;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Text-to-speech",
+        description = "Batch and live speech synthesis with Kokoro. Authenticated routes take a Keycloak access token as `Authorization: Bearer <token>`, or a static API key as `X-Api-Key`."
+    ),
+    paths(
+        crate::handlers::generate_speech,
+        crate::handlers::generate_speech_json,
+        crate::handlers::generate_epub,
+        crate::handlers::generate_from_url,
+        crate::stream::stream_speech,
+        crate::handlers::check_status,
+        crate::handlers::job_input,
+        crate::handlers::list_artifacts,
+        crate::handlers::download,
+        crate::signed_url::create_signed_url,
+        crate::handlers::pin_job,
+        crate::handlers::unpin_job,
+        crate::handlers::list_jobs,
+        crate::voices::list_voices,
+        crate::custom_voices::list_custom_voices,
+        crate::custom_voices::upload_custom_voice,
+        crate::custom_voices::delete_custom_voice,
+        crate::usage::get_usage,
+        crate::usage::get_character_usage,
+        crate::lexicon::list_entries,
+        crate::lexicon::create_entry,
+        crate::lexicon::update_entry,
+        crate::lexicon::delete_entry,
+        crate::health::healthz,
+        crate::health::readyz,
+    ),
+    components(schemas(GenerateForm, GenerateEpubForm, crate::upload_limit::UploadTooLarge)),
+    modifiers(&Security),
+    security(("bearer" = []), ("api_key" = []))
+)]
+pub struct ApiDoc;
+
+/// Registers the `bearer` and `api_key` schemes that the paths accept
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+        );
+    }
+}
+
+/// Multipart form fields of `POST /generate`. Only used to describe the form; the handler
+/// reads the fields one at a time.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct GenerateForm {
+    /// The text to synthesize, as a UTF-8 file or a PDF
+    #[schema(format = Binary, value_type = String)]
+    text_file: Vec<u8>,
+    /// Defaults to `af_heart`; see `GET /voices`
+    voice: Option<String>,
+    /// Language to phonemize the text as, e.g. `es` or `fr-fr`; defaults to the voice's
+    lang: Option<String>,
+    /// Speaking rate, `0.25` to `4.0`, defaults to `1.0`
+    speed: Option<String>,
+    /// Pitch shift in semitones, `-12` to `12`
+    pitch: Option<String>,
+    /// Target loudness in LUFS, `-70` to `-5`, e.g. `-16`; `0` for none. Defaults to
+    /// `TTS_LOUDNESS_LUFS`
+    loudness: Option<String>,
+    /// `mp3` (default), `opus`, `ogg`, `flac`, `wav` or `m4b`
+    format: Option<String>,
+    /// For lossy formats, `32k` to `320k`
+    bitrate: Option<String>,
+    /// Output sample rate in Hz, defaults to 24000
+    sample_rate: Option<String>,
+    /// For `m4b`: lines starting with this begin a chapter, instead of headings
+    chapter_delimiter: Option<String>,
+    /// Track title tag, defaults to the uploaded file's name
+    title: Option<String>,
+    /// Album tag
+    tag: Option<String>,
+    /// Track number tag, e.g. `3` or `3/12`
+    track: Option<String>,
+    /// Delete the job this many days after it was last downloaded (1 to 3650)
+    ttl_days: Option<String>,
+    /// Comma-separated labels to find the job by with `GET /jobs?tag=`; not written to the file
+    tags: Option<String>,
+    /// Comma-separated formats to produce as well, e.g. `opus,vtt`; `vtt` is a WebVTT
+    /// transcript. Listed by `GET /jobs/{id}/artifacts`
+    renditions: Option<String>,
+}
+
+/// Multipart form fields of `POST /generate/epub`; the other fields are as for `/generate`
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct GenerateEpubForm {
+    /// The book, as an EPUB file
+    #[schema(format = Binary, value_type = String)]
+    epub_file: Vec<u8>,
+    /// Defaults to `af_heart`; see `GET /voices`
+    voice: Option<String>,
+    /// Language to phonemize the text as, e.g. `es` or `fr-fr`; defaults to the voice's
+    lang: Option<String>,
+    /// Speaking rate, `0.25` to `4.0`, defaults to `1.0`
+    speed: Option<String>,
+    /// Pitch shift in semitones, `-12` to `12`
+    pitch: Option<String>,
+    /// Target loudness in LUFS, `-70` to `-5`, e.g. `-16`; `0` for none. Defaults to
+    /// `TTS_LOUDNESS_LUFS`
+    loudness: Option<String>,
+    /// `m4b` (default), or any other `/generate` format, without chapter marks
+    format: Option<String>,
+    /// For lossy formats, `32k` to `320k`
+    bitrate: Option<String>,
+    /// Output sample rate in Hz, defaults to 24000
+    sample_rate: Option<String>,
+    /// Title tag, defaults to the book's title
+    title: Option<String>,
+    /// Album tag, defaults to the book's title
+    tag: Option<String>,
+    /// Track number tag, e.g. `3` or `3/12`
+    track: Option<String>,
+    /// Delete the job this many days after it was last downloaded (1 to 3650)
+    ttl_days: Option<String>,
+    /// Comma-separated labels to find the job by with `GET /jobs?tag=`; not written to the file
+    tags: Option<String>,
+    /// Comma-separated formats to produce as well, e.g. `opus,vtt`; `vtt` is a WebVTT
+    /// transcript. Listed by `GET /jobs/{id}/artifacts`
+    renditions: Option<String>,
+}
+
+/// `GET /openapi.json`
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Swagger UI at `/docs`, reading `/openapi.json`
+pub fn swagger_ui() -> SwaggerUi {
+    SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::TestApp;
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn test_openapi_json() {
+        let app = TestApp::spawn_without_workers().await;
+
+        let resp = app
+            .client
+            .get(app.url("/openapi.json"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let spec: serde_json::Value = resp.json().await.unwrap();
+        for path in [
+            "/generate",
+            "/generate-json",
+            "/status/{id}",
+            "/jobs",
+            "/voices",
+        ] {
+            assert!(spec["paths"][path].is_object(), "{} is missing", path);
+        }
+        let form =
+            &spec["paths"]["/generate"]["post"]["requestBody"]["content"]["multipart/form-data"];
+        assert_eq!(form["schema"]["$ref"], "#/components/schemas/GenerateForm");
+        let fields = &spec["components"]["schemas"]["GenerateForm"]["properties"];
+        assert!(fields["text_file"].is_object());
+        assert!(fields["voice"].is_object());
+
+        let resp = app.client.get(app.url("/docs/")).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        app.teardown().await;
+    }
+}
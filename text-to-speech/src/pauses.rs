@@ -0,0 +1,119 @@
+//! Explicit pauses in the input text.
+//!
+//! `[pause 800ms]` or `[pause 1.5s]` (`[pause]` alone is half a second) inserts exactly that
+//! much silence, and a blank line between paragraphs inserts a shorter one, instead of
+//! leaving the spacing to sentence punctuation. Adjacent pauses are merged; an explicit
+//! pause replaces the paragraph pause around it rather than adding to it.
+
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// Silence between paragraphs
+pub const PARAGRAPH_PAUSE_MS: u32 = 600;
+/// Silence for `[pause]` without a duration
+const DEFAULT_PAUSE_MS: u32 = 500;
+/// Longest pause; longer markers are shortened to this
+const MAX_PAUSE_MS: u32 = 30_000;
+
+/// A pause marker or a paragraph break. Only markers have a `[`.
+static PAUSE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\[\s*pause(?:\s+(\d+(?:\.\d+)?)\s*(ms|s))?\s*\]|\n[ \t\r]*\n\s*").unwrap()
+});
+
+#[derive(Debug, PartialEq)]
+pub enum Piece {
+    Text(String),
+    /// Silence, in milliseconds
+    Pause(u32),
+}
+
+/// Split `text` into the text to synthesize and the pauses between it. Text pieces are
+/// never blank, and no two pauses are adjacent.
+pub fn split_pauses(text: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    // The pause being built up from adjacent markers and breaks
+    let mut explicit_ms: Option<u32> = None;
+    let mut paragraph = false;
+    let mut position = 0;
+
+    let flush = |pieces: &mut Vec<Piece>, explicit_ms: &mut Option<u32>, paragraph: &mut bool| {
+        let ms = explicit_ms
+            .take()
+            .or(paragraph.then_some(PARAGRAPH_PAUSE_MS));
+        *paragraph = false;
+        if let Some(ms) = ms.filter(|ms| *ms > 0) {
+            pieces.push(Piece::Pause(ms.min(MAX_PAUSE_MS)));
+        }
+    };
+
+    for caps in PAUSE.captures_iter(text) {
+        let whole = caps.get(0).unwrap();
+        let between = &text[position..whole.start()];
+        if !between.trim().is_empty() {
+            flush(&mut pieces, &mut explicit_ms, &mut paragraph);
+            pieces.push(Piece::Text(between.to_string()));
+        }
+        position = whole.end();
+
+        if !whole.as_str().starts_with('[') {
+            paragraph = true;
+            continue;
+        }
+        let ms = match (caps.get(1), caps.get(2)) {
+            (Some(amount), Some(unit)) => {
+                let amount: f64 = amount.as_str().parse().unwrap_or(0.0);
+                let scale = if unit.as_str().eq_ignore_ascii_case("s") {
+                    1000.0
+                } else {
+                    1.0
+                };
+                (amount * scale).round().min(MAX_PAUSE_MS as f64) as u32
+            }
+            _ => DEFAULT_PAUSE_MS,
+        };
+        explicit_ms = Some(explicit_ms.unwrap_or(0).saturating_add(ms));
+    }
+
+    let rest = &text[position..];
+    if !rest.trim().is_empty() {
+        flush(&mut pieces, &mut explicit_ms, &mut paragraph);
+        pieces.push(Piece::Text(rest.to_string()));
+    } else {
+        flush(&mut pieces, &mut explicit_ms, &mut paragraph);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_pauses() {
+        let text = "Ready? [pause 800ms] Go!\n\nSecond paragraph. [PAUSE 1.5s]\n\n[pause] Third.\n";
+        assert_eq!(
+            split_pauses(text),
+            [
+                Piece::Text("Ready? ".to_string()),
+                Piece::Pause(800),
+                Piece::Text(" Go!".to_string()),
+                Piece::Pause(PARAGRAPH_PAUSE_MS),
+                Piece::Text("Second paragraph. ".to_string()),
+                Piece::Pause(2000),
+                Piece::Text(" Third.\n".to_string()),
+            ]
+        );
+        assert_eq!(
+            split_pauses("No pauses\nhere."),
+            [Piece::Text("No pauses\nhere.".to_string())]
+        );
+        assert_eq!(
+            split_pauses("End. [pause 99s]"),
+            [Piece::Text("End. ".to_string()), Piece::Pause(MAX_PAUSE_MS)]
+        );
+        assert_eq!(
+            split_pauses("[pause 0ms] [pause nope]"),
+            [Piece::Text(" [pause nope]".to_string())]
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Text extraction from PDF uploads to `POST /generate`.
+//!
+//! PDFs are recognized by their content type or `%PDF-` header. The text of each page is
+//! reflowed: lines within a paragraph are joined, words hyphenated across a line break
+//! are rejoined, and lines holding only a page number are dropped. Pages without text
+//! (usually scanned images, which would need OCR) are reported back to the client.
+
+/// Whether an upload is a PDF, by its declared content type or its contents
+pub fn is_pdf(bytes: &[u8], content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|ct| ct.trim().eq_ignore_ascii_case("application/pdf"))
+        || bytes.starts_with(b"%PDF-")
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExtractedPdf {
+    pub text: String,
+    /// 1-based numbers of pages that produced no text
+    pub empty_pages: Vec<usize>,
+}
+
+/// Extract and reflow the text of a PDF, page by page
+pub fn extract(bytes: &[u8]) -> Result<ExtractedPdf, String> {
+    let pages = pdf_extract::extract_text_from_mem_by_pages(bytes)
+        .map_err(|e| format!("Failed to read PDF: {}", e))?;
+
+    let mut paragraphs = Vec::new();
+    let mut empty_pages = Vec::new();
+    for (index, page) in pages.iter().enumerate() {
+        let page_paragraphs = reflow(page);
+        if page_paragraphs.is_empty() {
+            empty_pages.push(index + 1);
+        }
+        paragraphs.extend(page_paragraphs);
+    }
+    if paragraphs.is_empty() {
+        return Err("No text found in the PDF; scanned documents need OCR first".to_string());
+    }
+    Ok(ExtractedPdf {
+        text: paragraphs.join("\n\n"),
+        empty_pages,
+    })
+}
+
+/// Join a page's lines into paragraphs, which are separated by blank lines
+fn reflow(page: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut current = String::new();
+    for line in page.lines().map(str::trim) {
+        // Page numbers
+        if !line.is_empty() && line.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        if line.is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if current.is_empty() {
+            current = line;
+        } else if let Some(stem) = hyphenated_stem(&current, &line) {
+            current.truncate(stem);
+            current.push_str(&line);
+        } else {
+            current.push(' ');
+            current.push_str(&line);
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs
+}
+
+/// If `current` ends with a word broken by a hyphen that `next` continues, e.g.
+/// "synthe-" + "sized", the length of `current` without the hyphen
+fn hyphenated_stem(current: &str, next: &str) -> Option<usize> {
+    let stem = current.strip_suffix('-')?;
+    let broken_word = stem.chars().next_back().is_some_and(char::is_alphabetic);
+    let continues = next.chars().next().is_some_and(char::is_lowercase);
+    (broken_word && continues).then_some(stem.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    /// A PDF with a page per entry, each showing its lines of text in Helvetica
+    fn sample_pdf(pages: &[&[&str]]) -> Vec<u8> {
+        let font_id = 3 + pages.len() * 2;
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                (0..pages.len())
+                    .map(|i| format!("{} 0 R", 3 + i * 2))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                pages.len()
+            ),
+        ];
+        for (i, lines) in pages.iter().enumerate() {
+            let mut content = String::new();
+            for (n, line) in lines.iter().enumerate() {
+                content.push_str(&format!(
+                    "BT /F1 12 Tf 72 {} Td ({}) Tj ET\n",
+                    720 - n * 14,
+                    line
+                ));
+            }
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Contents {} 0 R /Resources << /Font << /F1 {} 0 R >> >> >>",
+                4 + i * 2,
+                font_id
+            ));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{}endstream",
+                content.len(),
+                content
+            ));
+        }
+        objects.push(
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+                .to_string(),
+        );
+
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+        }
+        let xref_offset = pdf.len();
+        pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for offset in offsets {
+            pdf.extend(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        pdf.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref_offset
+            )
+            .as_bytes(),
+        );
+        pdf
+    }
+
+    #[test]
+    fn test_is_pdf() {
+        assert!(is_pdf(b"%PDF-1.7\n...", None));
+        assert!(is_pdf(b"", Some("application/pdf")));
+        assert!(!is_pdf(b"Hello.", Some("text/plain")));
+    }
+
+    #[test]
+    fn test_reflow() {
+        let page = "The quick brown fox\njumps over the   lazy\ndog. It was synthe-\nsized speech.\n\nA well-\nKnown fact.\n\n  12  \n";
+        assert_eq!(
+            reflow(page),
+            [
+                "The quick brown fox jumps over the lazy dog. It was synthesized speech.",
+                "A well- Known fact.",
+            ]
+        );
+        assert!(reflow("\n 3 \n\n").is_empty());
+    }
+
+    #[test]
+    fn test_extract() {
+        let pdf = sample_pdf(&[&["Hello from page one."], &[], &["Page three."]]);
+        let extracted = extract(&pdf).unwrap();
+        assert_eq!(extracted.empty_pages, [2]);
+        assert!(
+            extracted.text.contains("Hello from page one."),
+            "{:?}",
+            extracted.text
+        );
+        assert!(
+            extracted.text.contains("Page three."),
+            "{:?}",
+            extracted.text
+        );
+
+        assert!(
+            extract(&sample_pdf(&[&[]]))
+                .unwrap_err()
+                .contains("No text found")
+        );
+        assert!(extract(b"%PDF-1.4 garbage").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_pdf() {
+        let app = TestApp::spawn_without_workers().await;
+        let upload = |bytes: Vec<u8>| {
+            let form = reqwest::multipart::Form::new().part(
+                "text_file",
+                reqwest::multipart::Part::bytes(bytes)
+                    .file_name("paper.pdf")
+                    .mime_str("application/pdf")
+                    .unwrap(),
+            );
+            app.client
+                .post(app.url("/generate"))
+                .bearer_auth(app.token("alice"))
+                .multipart(form)
+                .send()
+        };
+
+        let pdf = sample_pdf(&[&["Hello from", "page one."], &[], &[]]);
+        let resp = upload(pdf).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(
+            body["warnings"],
+            serde_json::json!(["No text found on PDF pages 2, 3; scanned pages need OCR"])
+        );
+        let input_text: String = sqlx::query_scalar("SELECT input_text FROM jobs WHERE id = $1")
+            .bind(uuid::Uuid::parse_str(body["id"].as_str().unwrap()).unwrap())
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        assert!(input_text.contains("Hello from"), "{:?}", input_text);
+        assert!(!input_text.starts_with("%PDF"));
+
+        let resp = upload(sample_pdf(&[&[]])).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        app.teardown().await;
+    }
+}
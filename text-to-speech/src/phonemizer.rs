@@ -1,6 +1,269 @@
-//! Phonemizer module - converts text to IPA phonemes using espeak-ng.
+//! Phonemizer module - converts text to IPA phonemes using espeak-ng, or an
+//! ONNX G2P model for languages that have one configured. espeak-ng runs in-process
+//! when the service is built with `--features espeak-ng`, see [`EspeakBackend`].
+//!
+//! Phonemes of recently seen text are kept in an LRU cache keyed by text and language,
+//! so repeated phrases — book headers, UI prompts, text sent again to `/ws/live` — skip
+//! the backend entirely.
 
+use crate::g2p::G2pModel;
+use crate::lexicon::{Lexicon, Segment};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Texts whose phonemes are cached unless `PHONEME_CACHE_SIZE` says otherwise
+const DEFAULT_CACHE_SIZE: usize = 2048;
+
+/// Parse `PHONEME_CACHE_SIZE`: how many texts' phonemes to keep, `0` for none
+pub fn parse_cache_size(value: Option<&str>) -> Result<usize, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .parse()
+            .map_err(|_| format!("Invalid PHONEME_CACHE_SIZE '{}'", v)),
+        None => Ok(DEFAULT_CACHE_SIZE),
+    }
+}
+
+/// Phonemes by text and language, least recently used evicted first
+struct PhonemeCache {
+    capacity: usize,
+    /// Phonemes and when they were last used, by (text, lang)
+    entries: Mutex<HashMap<(String, String), (String, u64)>>,
+    /// Incremented on every use, to order entries by recency
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PhonemeCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, text: &str, lang: &str) -> Option<String> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut entries = self.entries.lock().ok()?;
+        let found =
+            entries
+                .get_mut(&(text.to_string(), lang.to_string()))
+                .map(|(phonemes, last_used)| {
+                    *last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+                    phonemes.clone()
+                });
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    fn insert(&self, text: &str, lang: &str, phonemes: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        if entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        entries.insert(
+            (text.to_string(), lang.to_string()),
+            (phonemes.to_string(), now),
+        );
+    }
+
+    fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheStats {
+            capacity: self.capacity,
+            entries: self.entries.lock().map_or(0, |entries| entries.len()),
+            hits,
+            misses,
+            hit_rate: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+        }
+    }
+}
+
+/// How well the phoneme cache is doing since startup
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of lookups that were hits; `None` before the first
+    pub hit_rate: Option<f64>,
+}
+
+/// How espeak-ng is run (`ESPEAK_BACKEND`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EspeakBackend {
+    /// In-process, through the C library; needs a build with `--features espeak-ng`
+    #[default]
+    Library,
+    /// The `espeak-ng` binary, run for each piece of text
+    Subprocess,
+}
+
+impl EspeakBackend {
+    /// Parse `ESPEAK_BACKEND`: `library` (the default) or `subprocess`
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("library") => Ok(Self::Library),
+            Some("subprocess") => Ok(Self::Subprocess),
+            Some(other) => Err(format!(
+                "Invalid ESPEAK_BACKEND '{}', expected library or subprocess",
+                other
+            )),
+        }
+    }
+
+    /// This backend if it can be used, or else the binary
+    fn select(self) -> Self {
+        if self == Self::Subprocess {
+            return self;
+        }
+        #[cfg(feature = "espeak-ng")]
+        let loaded = crate::espeak::init();
+        #[cfg(not(feature = "espeak-ng"))]
+        let loaded: Result<(), String> = Err("not built with --features espeak-ng".to_string());
+        match loaded {
+            Ok(()) => self,
+            Err(e) => {
+                tracing::warn!(error = %e, "espeak-ng library unavailable, falling back to the espeak-ng binary");
+                Self::Subprocess
+            }
+        }
+    }
+}
+
+/// Text-to-phoneme front end with a per-language choice of backend
+pub struct Phonemizer {
+    g2p_models: HashMap<String, G2pModel>,
+    espeak: EspeakBackend,
+    cache: PhonemeCache,
+}
+
+impl Phonemizer {
+    /// Load G2P models from a spec like `en=/app/g2p/en,de=/app/g2p/de`, where each
+    /// directory holds `model.onnx` and `vocab.json`. Languages without a model (or
+    /// whose model fails to load) use espeak-ng, run by `espeak`. The phonemes of up to
+    /// `cache_size` texts are cached.
+    pub fn load(g2p_spec: &str, espeak: EspeakBackend, cache_size: usize) -> Self {
+        let mut g2p_models = HashMap::new();
+
+        for entry in g2p_spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((lang, dir)) = entry.split_once('=') else {
+                tracing::warn!(entry = %entry, "Ignoring malformed G2P model entry (expected lang=dir)");
+                continue;
+            };
+            match G2pModel::load(dir) {
+                Ok(model) => {
+                    tracing::info!(lang = %lang, dir = %dir, "Loaded G2P model");
+                    g2p_models.insert(lang.to_string(), model);
+                }
+                Err(e) => {
+                    tracing::warn!(lang = %lang, dir = %dir, error = %e, "Failed to load G2P model, using espeak-ng");
+                }
+            }
+        }
+
+        let espeak = espeak.select();
+        tracing::info!(backend = ?espeak, "Selected espeak-ng backend");
+        Self {
+            g2p_models,
+            espeak,
+            cache: PhonemeCache::new(cache_size),
+        }
+    }
+
+    /// Convert text to IPA phonemes with the backend configured for `lang`, or for its
+    /// base language (`en` for `en-gb`). Falls back to espeak-ng if the G2P model fails.
+    pub fn phonemize(&self, text: &str, lang: &str) -> Result<String, String> {
+        if text.trim().is_empty() {
+            return Ok(String::new());
+        }
+        if let Some(phonemes) = self.cache.get(text, lang) {
+            return Ok(phonemes);
+        }
+        let phonemes = self.phonemize_uncached(text, lang)?;
+        self.cache.insert(text, lang, &phonemes);
+        Ok(phonemes)
+    }
+
+    /// Hits and misses of the phoneme cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    fn phonemize_uncached(&self, text: &str, lang: &str) -> Result<String, String> {
+        let model = self.g2p_models.get(lang).or_else(|| {
+            lang.split_once('-')
+                .and_then(|(base, _)| self.g2p_models.get(base))
+        });
+        if let Some(model) = model {
+            match model.phonemize(text) {
+                Ok(phonemes) => return Ok(clean_phonemes(&phonemes)),
+                Err(e) => {
+                    tracing::warn!(lang = %lang, error = %e, "G2P model failed, falling back to espeak-ng");
+                }
+            }
+        }
+
+        match self.espeak {
+            #[cfg(feature = "espeak-ng")]
+            EspeakBackend::Library => {
+                crate::espeak::phonemize(text, lang).map(|phonemes| clean_phonemes(&phonemes))
+            }
+            _ => phonemize(text, lang),
+        }
+    }
+
+    /// Like `phonemize`, but words in the user's lexicon take its pronunciation
+    pub fn phonemize_with_lexicon(
+        &self,
+        text: &str,
+        lang: &str,
+        lexicon: &Lexicon,
+    ) -> Result<String, String> {
+        if lexicon.is_empty() {
+            return self.phonemize(text, lang);
+        }
+        let mut parts = Vec::new();
+        for segment in lexicon.segments(text) {
+            let phonemes = match segment {
+                Segment::Text(text) => self.phonemize(&text, lang)?,
+                Segment::Phonemes(phonemes) => phonemes,
+            };
+            if !phonemes.is_empty() {
+                parts.push(phonemes);
+            }
+        }
+        Ok(parts.join(" "))
+    }
+}
 
 /// Split text into sentences for incremental synthesis
 pub fn split_sentences(text: &str) -> Vec<String> {
@@ -29,7 +292,7 @@ pub fn split_sentences(text: &str) -> Vec<String> {
     sentences
 }
 
-/// Convert text to IPA phonemes using espeak-ng
+/// Convert text to IPA phonemes by running the espeak-ng binary
 ///
 /// Returns phonemes in IPA notation suitable for Kokoro model
 pub fn phonemize(text: &str, lang: &str) -> Result<String, String> {
@@ -113,6 +376,72 @@ pub fn estimate_word_timings(
     timings
 }
 
+/// Word timings from when each character of `phonemes` is spoken, as start and end
+/// samples. The words of the phonemes are paired with the words of `text` in order, so
+/// `None` if there are more or fewer of them, e.g. where a symbol was read out as two words.
+pub fn aligned_word_timings(
+    text: &str,
+    phonemes: &str,
+    spans: &[(usize, usize)],
+    sample_rate: u32,
+) -> Option<Vec<(String, u32, u32)>> {
+    let spoken = phoneme_words(phonemes);
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if phonemes.chars().count() != spans.len() || words.len() != spoken.len() {
+        return None;
+    }
+    let ms = |samples: usize| (samples as u64 * 1000 / sample_rate as u64) as u32;
+    Some(
+        words
+            .into_iter()
+            .zip(spoken)
+            .map(|(word, (first, last))| (word.to_string(), ms(spans[first].0), ms(spans[last].1)))
+            .collect(),
+    )
+}
+
+/// The phonemes of each word of `text`, without punctuation, or `None` if the words of
+/// `phonemes` don't pair up with them
+pub fn word_phonemes(text: &str, phonemes: &str) -> Option<Vec<String>> {
+    let spoken = phoneme_words(phonemes);
+    if spoken.len() != text.split_whitespace().count() {
+        return None;
+    }
+    let chars: Vec<char> = phonemes.chars().collect();
+    Some(
+        spoken
+            .into_iter()
+            .map(|(first, last)| {
+                chars[first..=last]
+                    .iter()
+                    .filter(|&&c| !is_punctuation(c))
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
+/// The words of `phonemes`, separated by spaces, as the indexes of the first and last of
+/// their characters that aren't punctuation
+fn phoneme_words(phonemes: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut current: Option<(usize, usize)> = None;
+    for (i, c) in phonemes.chars().enumerate() {
+        if c.is_whitespace() {
+            words.extend(current.take());
+        } else if !is_punctuation(c) {
+            current = Some((current.map_or(i, |(first, _)| first), i));
+        }
+    }
+    words.extend(current);
+    words
+}
+
+/// Punctuation is a pause after a word rather than part of it
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || matches!(c, '—' | '…' | '“' | '”' | '«' | '»' | '¡' | '¿')
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -141,6 +470,67 @@ mod tests {
         assert!(sentences.is_empty());
     }
 
+    #[test]
+    fn test_espeak_backend_parse() {
+        assert_eq!(EspeakBackend::parse(None), Ok(EspeakBackend::Library));
+        assert_eq!(
+            EspeakBackend::parse(Some(" Subprocess ")),
+            Ok(EspeakBackend::Subprocess)
+        );
+        assert!(EspeakBackend::parse(Some("ffi")).is_err());
+        assert_eq!(
+            EspeakBackend::Subprocess.select(),
+            EspeakBackend::Subprocess
+        );
+    }
+
+    #[cfg(feature = "espeak-ng")]
+    #[test]
+    fn test_library_matches_binary() {
+        // Only where espeak-ng is installed
+        let text = "Hello, world. It's 5 o'clock!";
+        let Ok(expected) = phonemize(text, "en-us") else {
+            return;
+        };
+        let phonemizer = Phonemizer::load("", EspeakBackend::Library, 0);
+        assert_eq!(phonemizer.phonemize(text, "en-us"), Ok(expected));
+    }
+
+    #[test]
+    fn test_phoneme_cache() {
+        assert_eq!(parse_cache_size(None), Ok(DEFAULT_CACHE_SIZE));
+        assert_eq!(parse_cache_size(Some("0")), Ok(0));
+        assert!(parse_cache_size(Some("-1")).is_err());
+
+        let cache = PhonemeCache::new(2);
+        assert_eq!(cache.stats().hit_rate, None);
+        cache.insert("Chapter one.", "en-us", "tʃˈæptɚ wˈʌn");
+        cache.insert("Chapter one.", "fr-fr", "ʃapitʁ ɔn");
+        assert_eq!(
+            cache.get("Chapter one.", "en-us").as_deref(),
+            Some("tʃˈæptɚ wˈʌn")
+        );
+        // The French entry is now the least recently used
+        cache.insert("Chapter two.", "en-us", "tʃˈæptɚ tˈuː");
+        assert_eq!(cache.get("Chapter one.", "fr-fr"), None);
+        assert!(cache.get("Chapter one.", "en-us").is_some());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                capacity: 2,
+                entries: 2,
+                hits: 2,
+                misses: 1,
+                hit_rate: Some(2.0 / 3.0),
+            }
+        );
+
+        let disabled = PhonemeCache::new(0);
+        disabled.insert("Chapter one.", "en-us", "tʃˈæptɚ wˈʌn");
+        assert_eq!(disabled.get("Chapter one.", "en-us"), None);
+        assert_eq!(disabled.stats().misses, 0);
+    }
+
     #[test]
     fn test_estimate_word_timings() {
         let text = "Hello world";
@@ -155,4 +545,34 @@ mod tests {
         // Rough timing check - "Hello" is 5 chars, "world" is 5 chars, so ~50% each
         assert!(timings[1].1 > 400 && timings[1].1 < 600);
     }
+
+    #[test]
+    fn test_aligned_word_timings() {
+        // One phoneme character per 10 ms, after 100 ms of silence; the comma is a pause
+        let phonemes = "hɛlˈoʊ, wɜːld";
+        let spans: Vec<(usize, usize)> = (0..phonemes.chars().count())
+            .map(|i| (2400 + i * 240, 2400 + (i + 1) * 240))
+            .collect();
+        let timings = aligned_word_timings("Hello, world", phonemes, &spans, 24000).unwrap();
+        assert_eq!(
+            timings,
+            vec![
+                ("Hello,".to_string(), 100, 160),
+                ("world".to_string(), 180, 230),
+            ]
+        );
+
+        // Words that don't pair up are left to the estimate
+        assert!(aligned_word_timings("Hello there world", phonemes, &spans, 24000).is_none());
+        assert!(aligned_word_timings("Hello, world", phonemes, &spans[1..], 24000).is_none());
+    }
+
+    #[test]
+    fn test_word_phonemes() {
+        assert_eq!(
+            word_phonemes("Hello, world!", "hɛlˈoʊ, wɜːld!"),
+            Some(vec!["hɛlˈoʊ".to_string(), "wɜːld".to_string()])
+        );
+        assert_eq!(word_phonemes("$5", "faɪv dˈɑːlɚz"), None);
+    }
 }
@@ -0,0 +1,97 @@
+//! Speaking rate and pitch of synthesized speech.
+//!
+//! The rate is passed to the model; pitch is shifted afterwards while encoding batch jobs,
+//! by resampling with ffmpeg and stretching the audio back to its original length.
+
+use std::ops::RangeInclusive;
+
+/// Speaking rates accepted, as multiples of the voice's normal rate
+pub const SPEED_RANGE: RangeInclusive<f32> = 0.25..=4.0;
+/// Pitch shifts accepted, in semitones
+const PITCH_RANGE: RangeInclusive<f32> = -12.0..=12.0;
+
+/// Check that a speaking rate is within [`SPEED_RANGE`]
+pub fn check_speed(speed: f32) -> Result<f32, String> {
+    if SPEED_RANGE.contains(&speed) {
+        Ok(speed)
+    } else {
+        Err(format!(
+            "Speed must be between {} and {}",
+            SPEED_RANGE.start(),
+            SPEED_RANGE.end()
+        ))
+    }
+}
+
+/// Parse a speaking rate such as "1.25"
+pub fn parse_speed(value: &str) -> Result<f32, String> {
+    let speed = value
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| format!("Invalid speed '{}'", value))?;
+    check_speed(speed)
+}
+
+/// Parse a pitch shift in semitones, e.g. "-2" or "1.5". No shift is `None`.
+pub fn parse_pitch(value: &str) -> Result<Option<f32>, String> {
+    let pitch = value
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| format!("Invalid pitch '{}'", value))?;
+    if !PITCH_RANGE.contains(&pitch) {
+        return Err(format!(
+            "Pitch must be between {} and {} semitones",
+            PITCH_RANGE.start(),
+            PITCH_RANGE.end()
+        ));
+    }
+    Ok((pitch != 0.0).then_some(pitch))
+}
+
+/// ffmpeg audio filter shifting audio at `sample_rate` by `semitones` without changing its
+/// length: playing it at a rate scaled by the pitch ratio raises the pitch and shortens it,
+/// and `atempo` stretches it back
+pub fn pitch_filter(semitones: f32, sample_rate: u32) -> String {
+    let ratio = 2f64.powf(semitones as f64 / 12.0);
+    format!(
+        "asetrate={},aresample={},atempo={:.6}",
+        (sample_rate as f64 * ratio).round() as u32,
+        sample_rate,
+        1.0 / ratio
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!(parse_speed(" 1.5 "), Ok(1.5));
+        assert_eq!(parse_speed("0.25"), Ok(0.25));
+        assert!(parse_speed("1000").is_err());
+        assert!(parse_speed("0").is_err());
+        assert!(parse_speed("NaN").is_err());
+        assert!(parse_speed("fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_pitch() {
+        assert_eq!(parse_pitch("-2"), Ok(Some(-2.0)));
+        assert_eq!(parse_pitch("0"), Ok(None));
+        assert!(parse_pitch("13").is_err());
+        assert!(parse_pitch("high").is_err());
+    }
+
+    #[test]
+    fn test_pitch_filter() {
+        assert_eq!(
+            pitch_filter(12.0, 24000),
+            "asetrate=48000,aresample=24000,atempo=0.500000"
+        );
+        assert_eq!(
+            pitch_filter(-12.0, 24000),
+            "asetrate=12000,aresample=24000,atempo=2.000000"
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! Bounded processing of batch jobs.
+//!
+//! New jobs are recorded as `queued`. A fixed pool of `TTS_MAX_CONCURRENT_JOBS`
+//! workers claims them from the database oldest first and synthesizes one at a time
+//! each, so a burst of large uploads waits its turn instead of running all at once
+//! and exhausting memory. Claiming uses `FOR UPDATE SKIP LOCKED`, so replicas sharing
+//! the database never pick the same job.
+//!
+//! A job that fails for a transient reason, such as running out of memory or disk space,
+//! is queued again with exponential backoff until it has been retried
+//! `TTS_JOB_MAX_RETRIES` times; only then, or on a permanent failure, is it marked as
+//! an error.
+
+use crate::handlers::{self, JobError};
+use crate::state::AppState;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Workers also check for queued jobs this often, e.g. ones queued by another replica
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+const DEFAULT_MAX_CONCURRENT_JOBS: usize = 2;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_SECS: u64 = 30;
+/// Backoff stops doubling here
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Wakes idle workers when a job is queued
+#[derive(Default)]
+pub struct JobQueue {
+    notify: Notify,
+}
+
+impl JobQueue {
+    /// Let a worker know a job was queued
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Parse `TTS_MAX_CONCURRENT_JOBS`: number of jobs processed at once (default 2)
+pub fn parse_max_concurrent(value: Option<&str>) -> Result<usize, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid TTS_MAX_CONCURRENT_JOBS '{}'", v)),
+        None => Ok(DEFAULT_MAX_CONCURRENT_JOBS),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, recorded on each job when it is created
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each one after it
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Parse `TTS_JOB_MAX_RETRIES` (default 3) and `TTS_JOB_RETRY_BASE_SECS` (default 30)
+    pub fn parse(max_retries: Option<&str>, base_secs: Option<&str>) -> Result<Self, String> {
+        let max_retries = match max_retries.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(v) => v
+                .parse::<u32>()
+                .ok()
+                .filter(|&n| n <= i32::MAX as u32)
+                .ok_or_else(|| format!("Invalid TTS_JOB_MAX_RETRIES '{}'", v))?,
+            None => DEFAULT_MAX_RETRIES,
+        };
+        let base_secs = match base_secs.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(v) => v
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid TTS_JOB_RETRY_BASE_SECS '{}'", v))?,
+            None => DEFAULT_RETRY_BASE_SECS,
+        };
+        Ok(Self {
+            max_retries,
+            base_delay: Duration::from_secs(base_secs),
+        })
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_delay: Duration::from_secs(DEFAULT_RETRY_BASE_SECS),
+        }
+    }
+}
+
+/// Start `count` workers processing queued jobs
+pub fn spawn_workers(state: AppState, count: usize) {
+    tracing::info!(workers = count, "Starting job workers");
+    for worker in 0..count {
+        let state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                match claim_next_job(&state.pool).await {
+                    Ok(Some(id)) => {
+                        tracing::info!(worker, job_id = %id, "Claimed queued job");
+                        process_claimed(&state, id).await;
+                    }
+                    Ok(None) => {
+                        tokio::select! {
+                            _ = state.job_queue.notify.notified() => {}
+                            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!(worker, error = %e, "Failed to claim queued job");
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Mark the oldest queued job that is due as processing and return its id
+async fn claim_next_job(pool: &Pool<Postgres>) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        UPDATE jobs SET status = 'processing', chunks_done = 0, chunks_total = NULL
+        WHERE id = (
+            SELECT id FROM jobs
+            WHERE status = 'queued' AND (next_attempt_at IS NULL OR next_attempt_at <= NOW())
+            ORDER BY created_at, id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id
+        "#,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+async fn process_claimed(state: &AppState, id: Uuid) {
+    match handlers::load_job_spec(state, id).await {
+        Ok(spec) => {
+            let span = spec.span();
+            handlers::run_job(state, spec).instrument(span).await
+        }
+        Err(e) => {
+            tracing::error!(job_id = %id, error = %e, "Cannot process queued job");
+            let _ =
+                sqlx::query("UPDATE jobs SET status = 'error', error_message = $1 WHERE id = $2")
+                    .bind(&e)
+                    .bind(id)
+                    .execute(&state.pool)
+                    .await;
+        }
+    }
+}
+
+/// Queue a failed job again after its backoff if the failure is retryable and it has
+/// retries left, otherwise mark it as an error
+pub async fn retry_or_fail(
+    pool: &Pool<Postgres>,
+    policy: &RetryPolicy,
+    id: Uuid,
+    error: &JobError,
+) -> Result<(), sqlx::Error> {
+    if let JobError::Retryable(message) = error {
+        let retry: Option<i32> = sqlx::query_scalar(
+            r#"
+            UPDATE jobs SET
+                status = 'queued',
+                error_message = $2,
+                chunks_done = 0,
+                chunks_total = NULL,
+                next_attempt_at = NOW() + make_interval(secs => LEAST($3 * power(2, retry_count), $4)),
+                retry_count = retry_count + 1
+            WHERE id = $1 AND retry_count < max_retries
+            RETURNING retry_count
+            "#,
+        )
+        .bind(id)
+        .bind(message)
+        .bind(policy.base_delay.as_secs_f64())
+        .bind(MAX_RETRY_DELAY.as_secs_f64())
+        .fetch_optional(pool)
+        .await?;
+        if let Some(retry) = retry {
+            tracing::warn!(job_id = %id, retry, "Job failed transiently, queued for retry");
+            return Ok(());
+        }
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE jobs SET
+            status = 'error',
+            error_message = CASE WHEN retry_count > 0 THEN $2 || ' (gave up after ' || retry_count || ' retries)' ELSE $2 END
+        WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .bind(error.message())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// 1-based position of a queued job: the number of queued jobs up to and including it
+pub async fn queue_position(pool: &Pool<Postgres>, id: Uuid) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT COUNT(*) FROM jobs q, jobs j
+        WHERE j.id = $1 AND q.status = 'queued' AND (q.created_at, q.id) <= (j.created_at, j.id)
+        "#,
+    )
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+    use sqlx::Row;
+
+    #[test]
+    fn test_parse_max_concurrent() {
+        assert_eq!(parse_max_concurrent(None), Ok(DEFAULT_MAX_CONCURRENT_JOBS));
+        assert_eq!(parse_max_concurrent(Some(" 4 ")), Ok(4));
+        assert!(parse_max_concurrent(Some("0")).is_err());
+        assert!(parse_max_concurrent(Some("many")).is_err());
+    }
+
+    #[test]
+    fn test_retry_policy_parse() {
+        assert_eq!(RetryPolicy::parse(None, None), Ok(RetryPolicy::default()));
+        assert_eq!(
+            RetryPolicy::parse(Some("0"), Some(" 5 ")),
+            Ok(RetryPolicy {
+                max_retries: 0,
+                base_delay: Duration::from_secs(5),
+            })
+        );
+        assert!(RetryPolicy::parse(Some("-1"), None).is_err());
+        assert!(RetryPolicy::parse(None, Some("soon")).is_err());
+    }
+
+    async fn insert_queued_job(app: &TestApp, age_secs: f64) -> Uuid {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO jobs (id, status, username, input_text, created_at) VALUES ($1, 'queued', 'alice', 'Hello.', NOW() - make_interval(secs => $2))",
+        )
+        .bind(id)
+        .bind(age_secs)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn test_claim_order_and_queue_position() {
+        // Workers would race this test for the queued jobs
+        let app = TestApp::spawn_without_workers().await;
+
+        let newest = insert_queued_job(&app, 10.0).await;
+        let oldest = insert_queued_job(&app, 30.0).await;
+        let middle = insert_queued_job(&app, 20.0).await;
+
+        assert_eq!(queue_position(&app.pool, oldest).await.unwrap(), 1);
+        assert_eq!(queue_position(&app.pool, middle).await.unwrap(), 2);
+        assert_eq!(queue_position(&app.pool, newest).await.unwrap(), 3);
+
+        assert_eq!(claim_next_job(&app.pool).await.unwrap(), Some(oldest));
+        assert_eq!(queue_position(&app.pool, newest).await.unwrap(), 2);
+        assert_eq!(claim_next_job(&app.pool).await.unwrap(), Some(middle));
+        assert_eq!(claim_next_job(&app.pool).await.unwrap(), Some(newest));
+        assert_eq!(claim_next_job(&app.pool).await.unwrap(), None);
+
+        app.teardown().await;
+    }
+
+    async fn job_row(app: &TestApp, id: Uuid) -> (String, Option<String>, i32) {
+        let row = sqlx::query("SELECT status, error_message, retry_count FROM jobs WHERE id = $1")
+            .bind(id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        (
+            row.get("status"),
+            row.get("error_message"),
+            row.get("retry_count"),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_retry_or_fail() {
+        let app = TestApp::spawn_without_workers().await;
+        let policy = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_secs(60),
+        };
+        let oom = JobError::Retryable("kokoro-tts failed (exit code None)".to_string());
+
+        let set_max_retries = |id: Uuid| {
+            sqlx::query("UPDATE jobs SET max_retries = 2 WHERE id = $1")
+                .bind(id)
+                .execute(&app.pool)
+        };
+        let id = insert_queued_job(&app, 10.0).await;
+        set_max_retries(id).await.unwrap();
+        assert_eq!(claim_next_job(&app.pool).await.unwrap(), Some(id));
+
+        retry_or_fail(&app.pool, &policy, id, &oom).await.unwrap();
+        assert_eq!(
+            job_row(&app, id).await,
+            ("queued".to_string(), Some(oom.message().to_string()), 1)
+        );
+        // Not claimed again until the backoff has passed
+        assert_eq!(claim_next_job(&app.pool).await.unwrap(), None);
+        let delay: f64 = sqlx::query_scalar(
+            "SELECT EXTRACT(EPOCH FROM next_attempt_at - NOW())::float8 FROM jobs WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        assert!((50.0..=60.0).contains(&delay), "delay {}", delay);
+
+        let make_due = || {
+            sqlx::query("UPDATE jobs SET next_attempt_at = NOW() WHERE id = $1")
+                .bind(id)
+                .execute(&app.pool)
+        };
+        make_due().await.unwrap();
+        assert_eq!(claim_next_job(&app.pool).await.unwrap(), Some(id));
+        retry_or_fail(&app.pool, &policy, id, &oom).await.unwrap();
+        assert_eq!(job_row(&app, id).await.2, 2);
+
+        // Out of retries
+        make_due().await.unwrap();
+        assert_eq!(claim_next_job(&app.pool).await.unwrap(), Some(id));
+        retry_or_fail(&app.pool, &policy, id, &oom).await.unwrap();
+        assert_eq!(
+            job_row(&app, id).await,
+            (
+                "error".to_string(),
+                Some("kokoro-tts failed (exit code None) (gave up after 2 retries)".to_string()),
+                2
+            )
+        );
+
+        // Permanent failures are not retried
+        let id = insert_queued_job(&app, 5.0).await;
+        set_max_retries(id).await.unwrap();
+        retry_or_fail(
+            &app.pool,
+            &policy,
+            id,
+            &JobError::Permanent("Input text is empty".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            job_row(&app, id).await,
+            (
+                "error".to_string(),
+                Some("Input text is empty".to_string()),
+                0
+            )
+        );
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,211 @@
+//! Storage quota for batch job output.
+//!
+//! With `TTS_STORAGE_QUOTA_BYTES` set, each new job is checked against the total size of
+//! the stored outputs, as recorded with each job and rendition when it completes, so that
+//! a full volume can't take the service down. Once over the quota, new jobs are refused
+//! with `507` (`TTS_STORAGE_QUOTA_POLICY=reject`, the default), or the least recently
+//! downloaded jobs that aren't pinned are deleted to make room (`evict`).
+
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::http::StatusCode;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaPolicy {
+    Reject,
+    Evict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StorageQuota {
+    pub limit_bytes: i64,
+    pub policy: QuotaPolicy,
+}
+
+impl StorageQuota {
+    /// Parse `TTS_STORAGE_QUOTA_BYTES` and `TTS_STORAGE_QUOTA_POLICY`; no quota if the
+    /// former is unset
+    pub fn parse(bytes: Option<&str>, policy: Option<&str>) -> Result<Option<Self>, String> {
+        let policy = match policy.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("reject") => QuotaPolicy::Reject,
+            Some("evict") => QuotaPolicy::Evict,
+            Some(other) => {
+                return Err(format!(
+                    "Invalid TTS_STORAGE_QUOTA_POLICY '{}', expected reject or evict",
+                    other
+                ));
+            }
+        };
+        let Some(bytes) = bytes.map(str::trim).filter(|v| !v.is_empty()) else {
+            return Ok(None);
+        };
+        let limit_bytes = bytes
+            .parse::<i64>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid TTS_STORAGE_QUOTA_BYTES '{}'", bytes))?;
+        Ok(Some(Self {
+            limit_bytes,
+            policy,
+        }))
+    }
+}
+
+/// Total size of completed jobs' outputs and renditions, as recorded in the database
+pub async fn used_bytes(pool: &Pool<Postgres>) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT (SELECT COALESCE(SUM(output_file_size), 0) FROM jobs WHERE status = 'completed')::BIGINT \
+         + (SELECT COALESCE(SUM(file_size_bytes), 0) FROM job_artifacts)::BIGINT",
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Make room for a new job: refuse it with `507` if stored output is at or over the quota,
+/// or under the `evict` policy delete the least recently downloaded jobs until it is under
+pub async fn check_storage(state: &AppState) -> Result<(), ApiError> {
+    let Some(quota) = state.storage_quota else {
+        return Ok(());
+    };
+    let db_error = |e: sqlx::Error| {
+        tracing::error!(error = %e, "Failed to check storage quota");
+        ApiError::internal(e.to_string())
+    };
+
+    let mut used = used_bytes(&state.pool).await.map_err(db_error)?;
+    if used >= quota.limit_bytes && quota.policy == QuotaPolicy::Evict {
+        let freed = evict(
+            &state.pool,
+            &state.storage_path,
+            used - quota.limit_bytes + 1,
+        )
+        .await
+        .map_err(db_error)?;
+        used -= freed;
+    }
+    if used >= quota.limit_bytes {
+        tracing::warn!(
+            used_bytes = used,
+            limit_bytes = quota.limit_bytes,
+            "Storage quota reached"
+        );
+        return Err(ApiError::new(
+            StatusCode::INSUFFICIENT_STORAGE,
+            "storage_full",
+            format!(
+                "Storage quota of {} bytes reached ({} bytes used)",
+                quota.limit_bytes, used
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Delete unpinned completed jobs, least recently downloaded first, until their recorded sizes add
+/// up to at least `bytes`. Returns the bytes freed by those sizes.
+async fn evict(pool: &Pool<Postgres>, storage_path: &str, bytes: i64) -> Result<i64, sqlx::Error> {
+    let candidates: Vec<(Uuid, i64)> = sqlx::query_as(
+        "SELECT j.id, (COALESCE(j.output_file_size, 0) + COALESCE((SELECT SUM(a.file_size_bytes) FROM job_artifacts a WHERE a.job_id = j.id), 0))::BIGINT \
+         FROM jobs j WHERE j.status = 'completed' AND NOT j.pinned ORDER BY j.last_accessed_at, j.created_at",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut freed = 0;
+    let mut ids = Vec::new();
+    for (id, size) in candidates {
+        if freed >= bytes {
+            break;
+        }
+        freed += size;
+        ids.push(id);
+    }
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let (jobs, _) = crate::cleanup::delete_jobs(
+        pool,
+        storage_path,
+        "DELETE FROM jobs WHERE id = ANY($1) AND status = 'completed' AND NOT pinned RETURNING id, file_path",
+        ids,
+    )
+    .await?;
+    tracing::warn!(
+        jobs,
+        bytes = freed,
+        "Evicted jobs to stay under the storage quota"
+    );
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_storage_quota_parse() {
+        assert_eq!(StorageQuota::parse(None, None), Ok(None));
+        assert_eq!(
+            StorageQuota::parse(Some("1000"), Some(" Evict ")),
+            Ok(Some(StorageQuota {
+                limit_bytes: 1000,
+                policy: QuotaPolicy::Evict
+            }))
+        );
+        assert_eq!(
+            StorageQuota::parse(Some("1000"), None)
+                .unwrap()
+                .unwrap()
+                .policy,
+            QuotaPolicy::Reject
+        );
+        assert!(StorageQuota::parse(Some("0"), None).is_err());
+        assert!(StorageQuota::parse(Some("10G"), None).is_err());
+        assert!(StorageQuota::parse(Some("1000"), Some("delete")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_storage() {
+        let mut app = TestApp::spawn().await;
+
+        // Two 5-byte jobs, the first downloaded longest ago
+        let old = app.insert_completed_job("alice", b"audio").await;
+        let recent = app.insert_completed_job("bob", b"audio").await;
+        sqlx::query("UPDATE jobs SET last_accessed_at = NOW() - INTERVAL '1 day' WHERE id = $1")
+            .bind(old)
+            .execute(&app.pool)
+            .await
+            .unwrap();
+        assert_eq!(used_bytes(&app.pool).await.unwrap(), 10);
+
+        app.state.storage_quota = Some(StorageQuota {
+            limit_bytes: 10,
+            policy: QuotaPolicy::Reject,
+        });
+        let error = check_storage(&app.state).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(error.code, "storage_full");
+
+        app.state.storage_quota = Some(StorageQuota {
+            limit_bytes: 10,
+            policy: QuotaPolicy::Evict,
+        });
+        check_storage(&app.state).await.unwrap();
+        let remaining: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM jobs")
+            .fetch_all(&app.pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, vec![recent]);
+        assert!(
+            !std::path::Path::new(app.storage_path())
+                .join(format!("{}.mp3", old))
+                .exists()
+        );
+
+        app.teardown().await;
+    }
+}
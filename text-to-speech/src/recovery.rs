@@ -0,0 +1,193 @@
+//! Recovery of jobs interrupted by a restart.
+//!
+//! Jobs are processed in-process, so a pod restart mid-synthesis leaves them in
+//! `processing` with nothing working on them. At startup, jobs that have been
+//! processing longer than `STUCK_JOB_THRESHOLD_SECS` are either queued again, to be
+//! synthesized from their stored input text (`STUCK_JOB_ACTION=requeue`, the default),
+//! or marked as failed (`STUCK_JOB_ACTION=error`). Jobs still `queued` need nothing;
+//! the workers pick them up.
+
+use crate::state::AppState;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Shown for jobs that are given up on
+const INTERRUPTED_MESSAGE: &str =
+    "Processing was interrupted by a server restart; please submit the text again";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StuckJobAction {
+    Requeue,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveryConfig {
+    /// Only jobs created at least this long ago are considered stuck. Zero (the default)
+    /// recovers every processing job, which is right with a single replica.
+    pub threshold: Duration,
+    pub action: StuckJobAction,
+}
+
+impl RecoveryConfig {
+    /// Parse `STUCK_JOB_THRESHOLD_SECS` and `STUCK_JOB_ACTION`
+    pub fn parse(threshold_secs: Option<&str>, action: Option<&str>) -> Result<Self, String> {
+        let threshold_secs = match threshold_secs.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(secs) => secs
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid STUCK_JOB_THRESHOLD_SECS '{}'", secs))?,
+            None => 0,
+        };
+        let action = match action.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("requeue") => StuckJobAction::Requeue,
+            Some("error") => StuckJobAction::Error,
+            Some(other) => {
+                return Err(format!(
+                    "Invalid STUCK_JOB_ACTION '{}', expected requeue or error",
+                    other
+                ));
+            }
+        };
+        Ok(Self {
+            threshold: Duration::from_secs(threshold_secs),
+            action,
+        })
+    }
+}
+
+/// Requeue or fail stuck jobs. Call once at startup, before the workers start.
+pub async fn recover_stuck_jobs(
+    state: &AppState,
+    config: &RecoveryConfig,
+) -> Result<(), sqlx::Error> {
+    let stuck: Vec<Uuid> = sqlx::query_scalar(
+        "SELECT id FROM jobs WHERE status = 'processing' AND created_at <= NOW() - make_interval(secs => $1) ORDER BY created_at",
+    )
+    .bind(config.threshold.as_secs_f64())
+    .fetch_all(&state.pool)
+    .await?;
+
+    if stuck.is_empty() {
+        return Ok(());
+    }
+    tracing::warn!(jobs = stuck.len(), action = ?config.action, "Found jobs interrupted by a restart");
+
+    for id in stuck {
+        if config.action == StuckJobAction::Requeue {
+            // Keeps its created_at, so it goes back to the front of the queue
+            let requeued = sqlx::query(
+                "UPDATE jobs SET status = 'queued', chunks_done = 0, chunks_total = NULL WHERE id = $1 AND input_text IS NOT NULL",
+            )
+            .bind(id)
+            .execute(&state.pool)
+            .await?
+            .rows_affected()
+                > 0;
+            if requeued {
+                tracing::info!(job_id = %id, "Requeued interrupted job");
+                continue;
+            }
+            tracing::warn!(job_id = %id, "Cannot requeue interrupted job without stored input text");
+        }
+
+        tracing::info!(job_id = %id, "Marking interrupted job as failed");
+        sqlx::query("UPDATE jobs SET status = 'error', error_message = $1 WHERE id = $2")
+            .bind(INTERRUPTED_MESSAGE)
+            .bind(id)
+            .execute(&state.pool)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+    use sqlx::Row;
+
+    #[test]
+    fn test_recovery_config_parse() {
+        assert_eq!(
+            RecoveryConfig::parse(None, None),
+            Ok(RecoveryConfig {
+                threshold: Duration::ZERO,
+                action: StuckJobAction::Requeue,
+            })
+        );
+        assert_eq!(
+            RecoveryConfig::parse(Some("600"), Some("Error")),
+            Ok(RecoveryConfig {
+                threshold: Duration::from_secs(600),
+                action: StuckJobAction::Error,
+            })
+        );
+        assert!(RecoveryConfig::parse(Some("-1"), None).is_err());
+        assert!(RecoveryConfig::parse(None, Some("retry")).is_err());
+    }
+
+    async fn insert_processing_job(app: &TestApp, input_text: Option<&str>, age_secs: f64) -> Uuid {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO jobs (id, status, username, voice, speed, input_text, chunks_done, created_at) VALUES ($1, 'processing', 'alice', 'af_heart', '1.0', $2, 3, NOW() - make_interval(secs => $3))",
+        )
+        .bind(id)
+        .bind(input_text)
+        .bind(age_secs)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+        id
+    }
+
+    async fn job_row(app: &TestApp, id: Uuid) -> (String, Option<String>, i32) {
+        let row = sqlx::query("SELECT status, error_message, chunks_done FROM jobs WHERE id = $1")
+            .bind(id)
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        (
+            row.get("status"),
+            row.get("error_message"),
+            row.get("chunks_done"),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_recover_stuck_jobs() {
+        // Requeued jobs stay queued, so they can be checked
+        let app = TestApp::spawn_without_workers().await;
+
+        let requeued = insert_processing_job(&app, Some("Hello again."), 3600.0).await;
+        let without_input = insert_processing_job(&app, None, 3600.0).await;
+        let recent = insert_processing_job(&app, Some("Still running."), 10.0).await;
+
+        let config = RecoveryConfig {
+            threshold: Duration::from_secs(600),
+            action: StuckJobAction::Requeue,
+        };
+        recover_stuck_jobs(&app.state, &config).await.unwrap();
+
+        // Back in the queue, to be synthesized again from the start
+        assert_eq!(
+            job_row(&app, requeued).await,
+            ("queued".to_string(), None, 0)
+        );
+
+        let (status, message, _) = job_row(&app, without_input).await;
+        assert_eq!(status, "error");
+        assert_eq!(message.as_deref(), Some(INTERRUPTED_MESSAGE));
+
+        // Younger than the threshold: possibly still being worked on elsewhere
+        assert_eq!(job_row(&app, recent).await.0, "processing");
+
+        let config = RecoveryConfig {
+            threshold: Duration::ZERO,
+            action: StuckJobAction::Error,
+        };
+        recover_stuck_jobs(&app.state, &config).await.unwrap();
+        assert_eq!(job_row(&app, recent).await.0, "error");
+
+        app.teardown().await;
+    }
+}
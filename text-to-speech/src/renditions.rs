@@ -0,0 +1,163 @@
+//! Extra outputs of a batch job besides its main `format`.
+//!
+//! A job can ask for `renditions`, e.g. `opus,vtt`, to get the same synthesis in other
+//! formats without submitting the text again. Audio renditions are encoded from the joined
+//! audio with the format's default bitrate at the job's sample rate; `vtt` is a WebVTT
+//! transcript with a cue per sentence. Each is stored next to the main file and listed by
+//! `GET /jobs/:id/artifacts`.
+
+use crate::format::{EncodingOptions, OutputFormat};
+
+/// An output of a job besides its main format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rendition {
+    Audio(OutputFormat),
+    /// WebVTT transcript
+    Vtt,
+}
+
+impl Rendition {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim().to_ascii_lowercase();
+        if value == "vtt" {
+            return Ok(Self::Vtt);
+        }
+        OutputFormat::parse(&value).map(Self::Audio).map_err(|_| {
+            format!(
+                "Unsupported rendition '{}' (expected a format or vtt)",
+                value
+            )
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Audio(format) => format.as_str(),
+            Self::Vtt => "vtt",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Audio(format) => format.extension(),
+            Self::Vtt => "vtt",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Audio(format) => format.content_type(),
+            Self::Vtt => "text/vtt; charset=utf-8",
+        }
+    }
+}
+
+/// Parse a job's requested renditions; comma-separated values are split. Repeats and the
+/// job's own format are dropped, and audio formats must take the job's sample rate.
+pub fn parse_renditions(
+    values: &[String],
+    encoding: &EncodingOptions,
+) -> Result<Vec<Rendition>, String> {
+    let mut renditions = Vec::new();
+    for value in values.iter().flat_map(|value| value.split(',')) {
+        if value.trim().is_empty() {
+            continue;
+        }
+        let rendition = Rendition::parse(value)?;
+        if rendition == Rendition::Audio(encoding.format) || renditions.contains(&rendition) {
+            continue;
+        }
+        if let Rendition::Audio(format) = rendition {
+            rendition_encoding(format, encoding)?;
+        }
+        renditions.push(rendition);
+    }
+    Ok(renditions)
+}
+
+/// How an audio rendition of a job with `encoding` is encoded
+pub fn rendition_encoding(
+    format: OutputFormat,
+    encoding: &EncodingOptions,
+) -> Result<EncodingOptions, String> {
+    let sample_rate = encoding.sample_rate.map(|rate| rate.to_string());
+    EncodingOptions::new(format, None, sample_rate.as_deref())
+}
+
+/// WebVTT transcript of a job. Each chunk's speech, its `chunk_secs` less the pause after
+/// it, is shared between its sentences by length, as the audio has no sentence timings.
+pub fn webvtt(chunks: &[(usize, String, u32)], chunk_secs: &[f64]) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    let mut start = 0.0;
+    for ((_, chunk, pause_ms), secs) in chunks.iter().zip(chunk_secs) {
+        let speech = (secs - *pause_ms as f64 / 1000.0).max(0.0);
+        let sentences = crate::phonemizer::split_sentences(chunk);
+        let chars: usize = sentences.iter().map(|s| s.chars().count()).sum();
+        let mut cue_start = start;
+        for sentence in &sentences {
+            let cue_end =
+                cue_start + speech * sentence.chars().count() as f64 / chars.max(1) as f64;
+            vtt.push_str(&format!(
+                "\n{} --> {}\n{}\n",
+                timestamp(cue_start),
+                timestamp(cue_end),
+                sentence.replace("-->", "->")
+            ));
+            cue_start = cue_end;
+        }
+        start += secs;
+    }
+    vtt
+}
+
+/// `HH:MM:SS.mmm`
+fn timestamp(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_renditions() {
+        let encoding = EncodingOptions::new(OutputFormat::Mp3, Some("128k"), None).unwrap();
+        let renditions = parse_renditions(
+            &["opus, VTT".to_string(), "mp3,opus,".to_string()],
+            &encoding,
+        );
+        assert_eq!(
+            renditions,
+            Ok(vec![Rendition::Audio(OutputFormat::Opus), Rendition::Vtt])
+        );
+        assert!(parse_renditions(&["srt".to_string()], &encoding).is_err());
+
+        // Opus can't be encoded at 22.05 kHz
+        let encoding = EncodingOptions::new(OutputFormat::Mp3, None, Some("22050")).unwrap();
+        assert!(parse_renditions(&["opus".to_string()], &encoding).is_err());
+        assert!(parse_renditions(&["flac".to_string()], &encoding).is_ok());
+    }
+
+    #[test]
+    fn test_webvtt() {
+        let chunks = vec![
+            (0, "One two. Three four five.".to_string(), 500),
+            (0, "Seven.".to_string(), 0),
+        ];
+        let vtt = webvtt(&chunks, &[3.5, 3661.0]);
+        assert_eq!(
+            vtt,
+            "WEBVTT\n\
+             \n00:00:00.000 --> 00:00:01.000\nOne two.\n\
+             \n00:00:01.000 --> 00:00:03.000\nThree four five.\n\
+             \n00:00:03.500 --> 01:01:04.500\nSeven.\n"
+        );
+    }
+}
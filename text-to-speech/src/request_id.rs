@@ -0,0 +1,112 @@
+//! Request IDs.
+//!
+//! Every request gets an ID: the client's `X-Request-Id` if it sent a usable one, else a
+//! new UUID. It is returned in the `X-Request-Id` header of every response, errors
+//! included, logged with everything done for the request, and stored with the jobs it
+//! creates, so a job that fails later can be traced back to the request that submitted it.
+
+use axum::extract::Request;
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request ID accepted from a client
+const MAX_LEN: usize = 128;
+
+/// ID of the current request, set by [`set_request_id`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+/// IDs from clients end up in logs and the database, so only short, plain ones are kept
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Gives the request an ID and returns it in the response
+pub async fn set_request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    // Also kept for the request's errors, which report it in their body
+    let mut response = crate::error::CURRENT_REQUEST_ID
+        .scope(RequestId(id.clone()), next.run(request))
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+    use axum::http::StatusCode;
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("3f2c9a1e-req.42:a_b"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("has space"));
+        assert!(!is_valid(&"a".repeat(MAX_LEN + 1)));
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let app = TestApp::spawn_without_workers().await;
+
+        // A client's ID is kept, on errors too, and stored with the job it creates
+        let resp = app
+            .client
+            .post(app.url("/generate-json"))
+            .bearer_auth(app.token("alice"))
+            .header(REQUEST_ID_HEADER, "client-req-1")
+            .json(&serde_json::json!({ "text": "Hello." }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER], "client-req-1");
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let job_id = Uuid::parse_str(body["id"].as_str().unwrap()).unwrap();
+        let stored: Option<String> =
+            sqlx::query_scalar("SELECT request_id FROM jobs WHERE id = $1")
+                .bind(job_id)
+                .fetch_one(&app.pool)
+                .await
+                .unwrap();
+        assert_eq!(stored.as_deref(), Some("client-req-1"));
+
+        let resp = app
+            .client
+            .get(app.url("/status/not-a-uuid"))
+            .header(REQUEST_ID_HEADER, "bad id\t")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let id = resp.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(Uuid::parse_str(&id).is_ok());
+        // Errors report the ID in their body as well
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "missing_credentials");
+        assert_eq!(problem["request_id"], id);
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,187 @@
+//! Sample rate conversion of synthesized audio, in process.
+//!
+//! The model produces 24 kHz audio, which some players (DECT phones, car head units) won't
+//! take. Jobs and streams that ask for another `sample_rate` are resampled with rubato's
+//! FFT resampler before encoding, so formats encoded in process need no ffmpeg for it, and
+//! ffmpeg is handed audio already at the rate it writes.
+
+use crate::encode::PcmSink;
+use rubato::{FftFixedIn, Resampler as _};
+
+/// Input frames per resampler call
+const CHUNK_FRAMES: usize = 1024;
+
+/// Resamples interleaved audio as it arrives, without a delay at the start or a tail of
+/// padding at the end
+pub struct Resampler {
+    resampler: FftFixedIn<f32>,
+    channels: usize,
+    from_rate: u32,
+    to_rate: u32,
+    /// Interleaved input not yet resampled
+    pending: Vec<f32>,
+    /// Output frames still to drop to make up for the resampler's delay
+    delay: usize,
+    frames_in: u64,
+    frames_out: u64,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32) -> Result<Self, String> {
+        let resampler = FftFixedIn::new(
+            from_rate as usize,
+            to_rate as usize,
+            CHUNK_FRAMES,
+            1,
+            channels,
+        )
+        .map_err(|e| format!("Failed to create resampler: {}", e))?;
+        Ok(Self {
+            delay: resampler.output_delay(),
+            resampler,
+            channels,
+            from_rate,
+            to_rate,
+            pending: Vec::new(),
+            frames_in: 0,
+            frames_out: 0,
+        })
+    }
+
+    /// Resample interleaved `samples`, returning the output that is ready
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>, String> {
+        self.pending.extend_from_slice(samples);
+        self.frames_in += (samples.len() / self.channels) as u64;
+        let mut output = Vec::new();
+        loop {
+            let needed = self.resampler.input_frames_next() * self.channels;
+            if self.pending.len() < needed {
+                break;
+            }
+            let input = self.deinterleave(needed);
+            let resampled = self
+                .resampler
+                .process(&input, None)
+                .map_err(|e| format!("Resampling failed: {}", e))?;
+            self.interleave(&resampled, &mut output);
+        }
+        Ok(output)
+    }
+
+    /// The rest of the output, once all input has been processed
+    pub fn flush(&mut self) -> Result<Vec<f32>, String> {
+        let expected = (self.frames_in * self.to_rate as u64).div_ceil(self.from_rate as u64);
+        let mut output = Vec::new();
+        let input = self.deinterleave(self.pending.len());
+        let mut last = (!input[0].is_empty()).then_some(input.as_slice());
+        while self.frames_out < expected {
+            let resampled = self
+                .resampler
+                .process_partial(last.take(), None)
+                .map_err(|e| format!("Resampling failed: {}", e))?;
+            self.interleave(&resampled, &mut output);
+        }
+        let excess = (self.frames_out - expected) as usize;
+        output.truncate(output.len() - excess * self.channels);
+        self.frames_out = expected;
+        Ok(output)
+    }
+
+    /// Take the first `len` pending samples, one `Vec` per channel
+    fn deinterleave(&mut self, len: usize) -> Vec<Vec<f32>> {
+        let mut channels = vec![Vec::with_capacity(len / self.channels); self.channels];
+        for frame in self
+            .pending
+            .drain(..len)
+            .collect::<Vec<_>>()
+            .chunks_exact(self.channels)
+        {
+            for (channel, sample) in channels.iter_mut().zip(frame) {
+                channel.push(*sample);
+            }
+        }
+        channels
+    }
+
+    /// Append resampled channels to `output`, after dropping the resampler's delay
+    fn interleave(&mut self, resampled: &[Vec<f32>], output: &mut Vec<f32>) {
+        let frames = resampled.first().map_or(0, Vec::len);
+        let skip = self.delay.min(frames);
+        self.delay -= skip;
+        for frame in skip..frames {
+            output.extend(resampled.iter().map(|channel| channel[frame]));
+        }
+        self.frames_out += (frames - skip) as u64;
+    }
+}
+
+/// Passes audio on to another sink at `to_rate`, resampling it if it comes at another rate
+pub struct ResamplingSink {
+    sink: Box<dyn PcmSink>,
+    to_rate: u32,
+    resampler: Option<Resampler>,
+}
+
+impl ResamplingSink {
+    pub fn new(sink: Box<dyn PcmSink>, to_rate: u32) -> Self {
+        Self {
+            sink,
+            to_rate,
+            resampler: None,
+        }
+    }
+}
+
+impl PcmSink for ResamplingSink {
+    fn start(&mut self, channels: usize, sample_rate: u32) -> Result<(), String> {
+        if sample_rate != self.to_rate {
+            self.resampler = Some(Resampler::new(channels, sample_rate, self.to_rate)?);
+        }
+        self.sink.start(channels, self.to_rate)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        match &mut self.resampler {
+            Some(resampler) => self.sink.write(&resampler.process(samples)?),
+            None => self.sink.write(samples),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        if let Some(resampler) = &mut self.resampler {
+            self.sink.write(&resampler.flush()?)?;
+        }
+        self.sink.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resampler() {
+        // Half a second of a 440 Hz tone, fed in uneven pieces
+        let tone: Vec<f32> = (0..12000)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / 24000.0).sin() * 0.5)
+            .collect();
+        let mut resampler = Resampler::new(1, 24000, 48000).unwrap();
+        let mut output = Vec::new();
+        for piece in tone.chunks(777) {
+            output.extend(resampler.process(piece).unwrap());
+        }
+        output.extend(resampler.flush().unwrap());
+        assert_eq!(output.len(), 24000);
+
+        // Every other output sample lines up with an input sample, once past the edges
+        for i in 1000..11000 {
+            assert!((output[i * 2] - tone[i]).abs() < 0.01, "sample {}", i);
+        }
+
+        let mut stereo = Resampler::new(2, 24000, 16000).unwrap();
+        let mut output = stereo.process(&[0.25; 2400]).unwrap();
+        output.extend(stereo.flush().unwrap());
+        assert_eq!(output.len(), 1600);
+        assert!((output[800] - 0.25).abs() < 0.01);
+    }
+}
@@ -0,0 +1,251 @@
+//! Signed, expiring download links.
+//!
+//! `POST /jobs/:id/signed-url` returns `/download/:id?expires=...&sig=...`, which can be
+//! fetched without an `Authorization` header until `expires` (Unix seconds), e.g. by
+//! podcast apps and smart speakers. `sig` is an HMAC-SHA256 of the job ID and expiry
+//! keyed with `DOWNLOAD_URL_SECRET`; without a secret, links can't be minted.
+
+use crate::auth::{self, AuthenticatedUser};
+use crate::state::AppState;
+use axum::{
+    extract::{Extension, Path, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{Json, Response},
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+const DEFAULT_EXPIRES_IN_SECS: u64 = 3600;
+/// Longest lifetime a link can be minted with (7 days)
+const MAX_EXPIRES_IN_SECS: u64 = 7 * 24 * 3600;
+
+/// Signs and checks download links with `DOWNLOAD_URL_SECRET`
+pub struct UrlSigner {
+    secret: Vec<u8>,
+}
+
+impl UrlSigner {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, id: Uuid, expires: i64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(format!("{}:{}", id, expires).as_bytes());
+        mac
+    }
+
+    /// Hex signature for downloading job `id` until `expires`
+    pub fn sign(&self, id: Uuid, expires: i64) -> String {
+        hex::encode(self.mac(id, expires).finalize().into_bytes())
+    }
+
+    /// Whether `sig` is valid for job `id` and hasn't expired by `now`
+    pub fn verify(&self, id: Uuid, expires: i64, sig: &str, now: i64) -> bool {
+        let Ok(sig) = hex::decode(sig) else {
+            return false;
+        };
+        expires > now && self.mac(id, expires).verify_slice(&sig).is_ok()
+    }
+}
+
+/// Query parameters of a signed download link
+#[derive(Debug, Default, Deserialize)]
+pub struct SignedLink {
+    pub expires: Option<i64>,
+    pub sig: Option<String>,
+}
+
+impl SignedLink {
+    /// Whether this link grants access to job `id`
+    pub fn grants(&self, state: &AppState, id: Uuid) -> bool {
+        match (&state.url_signer, self.expires, &self.sig) {
+            (Some(signer), Some(expires), Some(sig)) => {
+                signer.verify(id, expires, sig, Utc::now().timestamp())
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Lets requests carrying a signature through to the handler, which checks it; everything
+/// else goes through `auth_middleware`
+pub async fn auth_unless_signed(
+    state: State<AppState>,
+    Query(link): Query<SignedLink>,
+    request: Request,
+    next: Next,
+) -> Result<Response, crate::error::ApiError> {
+    if link.sig.is_some() {
+        return Ok(next.run(request).await);
+    }
+    auth::auth_middleware(state, request, next).await
+}
+
+#[derive(Deserialize)]
+pub struct SignedUrlParams {
+    /// Seconds until the link expires (default 3600, at most 7 days)
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SignedUrlResponse {
+    /// Relative to the service, like `download_url` in the job status
+    url: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// `POST /jobs/:id/signed-url`: mint a link to download a completed job without auth
+#[utoipa::path(
+    post,
+    path = "/jobs/{id}/signed-url",
+    tag = "jobs",
+    params(
+        ("id" = Uuid, Path, description = "Job ID"),
+        ("expires_in" = Option<u64>, Query, description = "Seconds until the link expires (default 3600, at most 604800)"),
+    ),
+    responses(
+        (status = 200, body = SignedUrlResponse),
+        (status = 400, description = "Invalid job ID or expiry", body = String, content_type = "text/plain"),
+        (status = 404, description = "No such job for this user", body = String, content_type = "text/plain"),
+        (status = 409, description = "The job hasn't completed", body = String, content_type = "text/plain"),
+        (status = 503, description = "`DOWNLOAD_URL_SECRET` isn't set", body = String, content_type = "text/plain"),
+    )
+)]
+pub async fn create_signed_url(
+    Extension(user): Extension<AuthenticatedUser>,
+    Path(id_str): Path<String>,
+    Query(params): Query<SignedUrlParams>,
+    State(state): State<AppState>,
+) -> Result<Json<SignedUrlResponse>, (StatusCode, String)> {
+    let signer = state.url_signer.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Signed download URLs are not configured".to_string(),
+    ))?;
+    let id = Uuid::parse_str(&id_str)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid UUID".to_string()))?;
+    let expires_in = params.expires_in.unwrap_or(DEFAULT_EXPIRES_IN_SECS);
+    if expires_in == 0 || expires_in > MAX_EXPIRES_IN_SECS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "expires_in must be between 1 and {} seconds",
+                MAX_EXPIRES_IN_SECS
+            ),
+        ));
+    }
+
+    let status: Option<String> =
+        sqlx::query_scalar("SELECT status FROM jobs WHERE id = $1 AND username = $2")
+            .bind(id)
+            .bind(&user.username)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|e| {
+                tracing::error!(job_id = %id, error = %e, "Database error while fetching job");
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            })?;
+    match status.as_deref() {
+        Some("completed") => {}
+        Some(_) => return Err((StatusCode::CONFLICT, "Job is not completed".to_string())),
+        None => return Err((StatusCode::NOT_FOUND, "Job not found".to_string())),
+    }
+
+    let expires_at = Utc::now() + chrono::Duration::seconds(expires_in as i64);
+    let expires = expires_at.timestamp();
+    tracing::info!(job_id = %id, user = %user.username, expires, "Minted signed download URL");
+    Ok(Json(SignedUrlResponse {
+        url: format!(
+            "/download/{}?expires={}&sig={}",
+            id,
+            expires,
+            signer.sign(id, expires)
+        ),
+        expires_at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = UrlSigner::new("secret");
+        let id = Uuid::new_v4();
+        let sig = signer.sign(id, 1000);
+        assert_eq!(sig.len(), 64);
+
+        assert!(signer.verify(id, 1000, &sig, 999));
+        assert!(!signer.verify(id, 1000, &sig, 1000), "expired");
+        assert!(!signer.verify(id, 2000, &sig, 999), "expiry changed");
+        assert!(!signer.verify(Uuid::new_v4(), 1000, &sig, 999), "other job");
+        assert!(
+            !UrlSigner::new("other").verify(id, 1000, &sig, 999),
+            "other secret"
+        );
+        assert!(!signer.verify(id, 1000, "zz", 999));
+        assert!(!signer.verify(id, 1000, &sig[1..], 999));
+    }
+
+    #[tokio::test]
+    async fn test_signed_download() {
+        let app = TestApp::spawn().await;
+        let id = app.insert_completed_job("alice", b"audio bytes").await;
+
+        let mint = |username: &str, query: &str| {
+            app.client
+                .post(app.url(&format!("/jobs/{}/signed-url{}", id, query)))
+                .bearer_auth(app.token(username))
+                .send()
+        };
+        assert_eq!(
+            mint("bob", "").await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            mint("alice", "?expires_in=999999999")
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let resp = mint("alice", "?expires_in=60").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        let url = body["url"].as_str().unwrap();
+        assert!(url.starts_with(&format!("/download/{}?expires=", id)));
+
+        // No Authorization header needed
+        let resp = app.client.get(app.url(url)).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.bytes().await.unwrap().as_ref(), b"audio bytes");
+
+        // Tampering with the expiry breaks the signature
+        let (_, sig) = url.split_once("&sig=").unwrap();
+        let forged = format!("/download/{}?expires=9999999999&sig={}", id, sig);
+        let resp = app.client.get(app.url(&forged)).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Without a signature, the usual auth applies
+        let resp = app
+            .client
+            .get(app.url(&format!("/download/{}", id)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        app.teardown().await;
+    }
+}
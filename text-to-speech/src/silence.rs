@@ -0,0 +1,101 @@
+//! Trimming the silence the model leaves around each sentence.
+//!
+//! Kokoro pads every sentence with leading and trailing silence, so joined sentences and
+//! chunks end up with long, uneven gaps between them. With `TTS_TRIM_SILENCE_DB` set, each
+//! segment is cut down to its audible part plus a short margin before it is joined.
+//! Explicit pauses are added afterwards and aren't affected.
+
+/// Quietest threshold accepted, in dBFS; below this even 16-bit dither counts as sound
+const MIN_THRESHOLD_DB: f32 = -90.0;
+/// Loudest threshold accepted, in dBFS; above this quiet speech would be cut
+const MAX_THRESHOLD_DB: f32 = -20.0;
+/// Silence left on each side of a segment, so soft onsets and decays aren't clipped and
+/// segments don't run into each other
+const KEEP_MS: u32 = 80;
+
+/// Cut silence below a threshold from both ends of a segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceTrim {
+    /// Peak sample amplitude, 0 to 1, below which audio counts as silence
+    threshold: f32,
+}
+
+/// Parse `TTS_TRIM_SILENCE_DB`, e.g. "-50" (default: unset, no trimming)
+pub fn parse_trim_silence_db(value: Option<&str>) -> Result<Option<SilenceTrim>, String> {
+    let Some(v) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    let db = v
+        .parse::<f32>()
+        .ok()
+        .filter(|db| (MIN_THRESHOLD_DB..=MAX_THRESHOLD_DB).contains(db))
+        .ok_or_else(|| {
+            format!(
+                "Invalid TTS_TRIM_SILENCE_DB '{}', expected {} to {}",
+                v, MIN_THRESHOLD_DB, MAX_THRESHOLD_DB
+            )
+        })?;
+    Ok(Some(SilenceTrim {
+        threshold: 10f32.powf(db / 20.0),
+    }))
+}
+
+impl SilenceTrim {
+    /// The frames of interleaved `samples` to keep: from shortly before the first audible
+    /// frame to shortly after the last. A segment that is silent throughout is kept whole.
+    pub fn bounds(&self, samples: &[f32], channels: usize, sample_rate: u32) -> (usize, usize) {
+        let frames = samples.len() / channels;
+        let audible = |frame: &usize| {
+            samples[frame * channels..(frame + 1) * channels]
+                .iter()
+                .any(|sample| sample.abs() >= self.threshold)
+        };
+        let Some(first) = (0..frames).find(audible) else {
+            return (0, frames);
+        };
+        let last = (0..frames).rev().find(audible).unwrap_or(first);
+        let keep = (sample_rate as u64 * KEEP_MS as u64 / 1000) as usize;
+        (first.saturating_sub(keep), (last + 1 + keep).min(frames))
+    }
+
+    /// Trim interleaved `samples` in place to [`Self::bounds`]
+    pub fn apply(&self, samples: &mut Vec<f32>, channels: usize, sample_rate: u32) {
+        let (start, end) = self.bounds(samples, channels, sample_rate);
+        samples.truncate(end * channels);
+        samples.drain(..start * channels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trim_silence_db() {
+        assert_eq!(parse_trim_silence_db(None), Ok(None));
+        let trim = parse_trim_silence_db(Some(" -40 ")).unwrap().unwrap();
+        assert!((trim.threshold - 0.01).abs() < 1e-6);
+        assert!(parse_trim_silence_db(Some("-10")).is_err());
+        assert!(parse_trim_silence_db(Some("quiet")).is_err());
+    }
+
+    #[test]
+    fn test_trim() {
+        let trim = SilenceTrim { threshold: 0.01 };
+        // At 100 Hz, 8 frames are kept on each side
+        let mut samples = vec![0.0; 20];
+        samples.extend([0.5, 0.005, -0.5]);
+        samples.extend([0.001; 30]);
+        assert_eq!(trim.bounds(&samples, 1, 100), (12, 31));
+        trim.apply(&mut samples, 1, 100);
+        assert_eq!(samples.len(), 19);
+        assert_eq!(samples[8], 0.5);
+
+        // Stereo frames count as audible if either channel is
+        let stereo = [0.0, 0.0, 0.0, 0.2, 0.0, 0.0];
+        assert_eq!(trim.bounds(&stereo, 2, 1), (1, 2));
+
+        let silent = vec![0.0; 10];
+        assert_eq!(trim.bounds(&silent, 1, 100), (0, 10));
+    }
+}
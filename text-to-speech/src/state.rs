@@ -1,19 +1,109 @@
-use crate::inference::KokoroModel;
+use crate::auth::ApiKeys;
+use crate::cleanup::CleanupConfig;
+use crate::inference::{KokoroModel, LoadProgress};
+use crate::phonemizer::Phonemizer;
+use crate::queue::{JobQueue, RetryPolicy};
+use crate::signed_url::UrlSigner;
+use crate::storage::StorageLayout;
+use crate::usage::{CharacterQuotas, UsageCaps};
 use jsonwebtoken::DecodingKey;
 use sqlx::{Pool, Postgres};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: Pool<Postgres>,
     pub storage_path: String,
+    /// Where each job's MP3 goes under `storage_path`
+    pub storage_layout: StorageLayout,
+    /// Root for per-job scratch directories holding intermediate files
+    pub scratch_path: String,
     pub jwks_cache: Arc<RwLock<JwksCache>>,
     pub keycloak_url: String,
     pub keycloak_realm: String,
     pub keycloak_audience: String,
-    pub kokoro_model: Option<Arc<KokoroModel>>,
+    /// Static API keys from `API_KEYS`, accepted in `X-Api-Key` instead of a token
+    pub api_keys: Arc<ApiKeys>,
+    /// Signs download links; `None` without `DOWNLOAD_URL_SECRET`
+    pub url_signer: Option<Arc<UrlSigner>>,
+    pub kokoro_model: Arc<RwLock<ModelState>>,
+    pub phonemizer: Arc<Phonemizer>,
+    /// Wakes the workers that process queued jobs
+    pub job_queue: Arc<JobQueue>,
+    /// How often and how soon jobs that fail transiently are retried
+    pub retry_policy: RetryPolicy,
+    /// Jobs still processing, so their audio can be followed over `/jobs/:id/stream`
+    pub active_jobs: Arc<RwLock<HashMap<Uuid, ActiveJob>>>,
+    /// Monthly limits on synthesized audio per user
+    pub usage_caps: Arc<UsageCaps>,
+    /// Daily limits on submitted characters per user
+    pub character_quotas: Arc<CharacterQuotas>,
+    /// Retention and orphan sweeping settings, also used by `POST /admin/cleanup`
+    pub cleanup: CleanupConfig,
+    /// Limit on stored job output (`TTS_STORAGE_QUOTA_BYTES`, `TTS_STORAGE_QUOTA_POLICY`)
+    pub storage_quota: Option<crate::quota::StorageQuota>,
+    /// Users allowed to call the `/admin` endpoints (`ADMIN_USERS`)
+    pub admin_users: Arc<HashSet<String>>,
+    /// Origins browsers may call the API from (`CORS_ALLOWED_ORIGINS`); `None` for none
+    pub cors_origins: Option<crate::cors::AllowedOrigins>,
+    /// Serve Swagger UI at `/docs` (`SWAGGER_UI`)
+    pub swagger_ui: bool,
+    /// Let `POST /generate/url` fetch from private and loopback addresses (`URL_FETCH_ALLOW_PRIVATE`)
+    pub url_fetch_allow_private: bool,
+    /// Longest text a job accepts, in characters (`TTS_MAX_INPUT_CHARS`)
+    pub max_input_chars: usize,
+    /// Largest request body accepted, in bytes (`TTS_MAX_UPLOAD_BYTES`)
+    pub max_upload_bytes: usize,
+    /// Overlap between consecutive chunks of a batch job, or sentences of streamed audio,
+    /// in milliseconds (`TTS_CROSSFADE_MS`)
+    pub crossfade_ms: u32,
+    /// Silence between sentences, in milliseconds (`TTS_SENTENCE_GAP_MS`)
+    pub sentence_gap_ms: u32,
+    /// Silence trimmed from the ends of each sentence or chunk before joining (`TTS_TRIM_SILENCE_DB`)
+    pub silence_trim: Option<crate::silence::SilenceTrim>,
+    /// What synthesizes batch jobs (`TTS_BATCH_ENGINE`)
+    pub batch_engine: crate::engine::EngineKind,
+    /// What synthesizes `/ws/live` and `/stream` audio (`TTS_LIVE_ENGINE`)
+    pub live_engine: crate::engine::EngineKind,
+    /// The remote speech API (`TTS_HTTP_ENGINE_URL`), if configured
+    pub http_engine: Option<Arc<crate::engine::HttpEngine>>,
+    /// Loudness target in LUFS of jobs that don't set one (`TTS_LOUDNESS_LUFS`)
+    pub default_loudness: Option<f32>,
+    /// WebSocket pings and idle timeout (`WS_PING_INTERVAL_SECS`, `WS_IDLE_TIMEOUT_SECS`)
+    pub ws_keepalive: crate::ws_handler::Keepalive,
+    /// Where the model is loaded from, also the defaults of `POST /admin/model/reload`
+    pub model_files: Arc<crate::model_loader::ModelFiles>,
+    /// Models kept loaded after a reload for requests pinned to their version
+    pub model_versions: Arc<crate::model_loader::ModelVersions>,
+    /// `/ws/live` sessions that lost their connection, to be resumed on reconnecting
+    pub suspended_sessions: Arc<crate::ws_handler::SuspendedSessions>,
+    /// Accept every request as `test_user` without checking a token (`TTS_TEST_MODE`)
+    pub auth_disabled: bool,
+    /// Write silent audio instead of running kokoro-tts for batch jobs (`TTS_TEST_MODE`)
+    pub mock_synthesis: bool,
+}
+
+/// Chunks a job may publish before a slow `/jobs/:id/stream` client misses some
+pub const JOB_STREAM_CAPACITY: usize = 16;
+
+/// A processing job, publishing each chunk of audio as it is synthesized
+pub struct ActiveJob {
+    pub username: String,
+    pub chunks: tokio::sync::broadcast::Sender<JobChunk>,
+}
+
+/// A chunk of a job's audio, as the batch pipeline synthesized it
+#[derive(Debug, Clone)]
+pub struct JobChunk {
+    pub index: u32,
+    pub text: String,
+    /// Mono at the model's sample rate
+    pub audio: Arc<[f32]>,
+    /// Silence after the chunk, in milliseconds
+    pub pause_ms: u32,
 }
 
 #[derive(Default)]
@@ -21,3 +111,34 @@ pub struct JwksCache {
     pub keys: HashMap<String, DecodingKey>,
     pub last_fetched: Option<std::time::Instant>,
 }
+
+/// Load state of the Kokoro ONNX model, which is loaded in the background at startup
+pub enum ModelState {
+    Loading {
+        started_at: std::time::Instant,
+        progress: Option<LoadProgress>,
+    },
+    Ready(Arc<KokoroModel>),
+    Failed(String),
+}
+
+impl ModelState {
+    pub fn loading() -> Self {
+        Self::Loading {
+            started_at: std::time::Instant::now(),
+            progress: None,
+        }
+    }
+
+    /// The loaded model, if loading has finished successfully
+    pub fn model(&self) -> Option<Arc<KokoroModel>> {
+        match self {
+            Self::Ready(model) => Some(Arc::clone(model)),
+            _ => None,
+        }
+    }
+
+    pub fn is_loading(&self) -> bool {
+        matches!(self, Self::Loading { .. })
+    }
+}
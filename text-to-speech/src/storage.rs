@@ -0,0 +1,150 @@
+//! Layout of generated audio files under the storage root.
+//!
+//! The path of each job's MP3 comes from a template set with `STORAGE_LAYOUT`,
+//! e.g. `{user}/{yyyy}/{mm}/{job_id}.mp3`, so large deployments can spread files
+//! across directories instead of one flat directory. Supported placeholders:
+//! `{user}`, `{yyyy}`, `{mm}`, `{dd}` and `{job_id}` (required, keeps paths unique).
+//! The file extension always comes from the job's output format, replacing any
+//! extension written in the template.
+
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Flat layout used when `STORAGE_LAYOUT` is unset
+pub const DEFAULT_LAYOUT: &str = "{job_id}.mp3";
+
+const PLACEHOLDERS: &[&str] = &["user", "yyyy", "mm", "dd", "job_id"];
+
+#[derive(Debug, Clone)]
+pub struct StorageLayout {
+    template: String,
+}
+
+impl StorageLayout {
+    /// Validate a layout template
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in storage layout '{}'", template))?;
+            let name = &rest[open + 1..open + close];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "Unknown placeholder '{{{}}}' in storage layout (expected one of {})",
+                    name,
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+            rest = &rest[open + close + 1..];
+        }
+
+        if !template.contains("{job_id}") {
+            return Err("Storage layout must contain {job_id}".to_string());
+        }
+        if template.starts_with('/') || template.split('/').any(|part| part == "..") {
+            return Err("Storage layout must be a relative path without '..'".to_string());
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+        })
+    }
+
+    /// Output path for a job, under `root`, with the given file extension
+    pub fn path_for(
+        &self,
+        root: &str,
+        username: &str,
+        job_id: Uuid,
+        created_at: DateTime<Utc>,
+        extension: &str,
+    ) -> PathBuf {
+        let relative = self
+            .template
+            .replace("{user}", &sanitize_segment(username))
+            .replace("{yyyy}", &created_at.format("%Y").to_string())
+            .replace("{mm}", &created_at.format("%m").to_string())
+            .replace("{dd}", &created_at.format("%d").to_string())
+            .replace("{job_id}", &job_id.to_string());
+        Path::new(root).join(relative).with_extension(extension)
+    }
+}
+
+/// Make a username safe to use as a single path segment
+fn sanitize_segment(value: &str) -> String {
+    let sanitized: String = value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match sanitized.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => sanitized,
+    }
+}
+
+/// Remove directories left empty by deleting `file`, stopping at `root`
+pub async fn remove_empty_parents(file: &Path, root: &Path) {
+    let mut dir = file.parent();
+    while let Some(d) = dir {
+        if d == root || !d.starts_with(root) {
+            break;
+        }
+        // Fails (and stops) if the directory still has files in it
+        if tokio::fs::remove_dir(d).await.is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_path_for() {
+        let layout = StorageLayout::parse("{user}/{yyyy}/{mm}/{job_id}.mp3").unwrap();
+        let job_id = Uuid::nil();
+        let created_at = Utc.with_ymd_and_hms(2025, 3, 7, 12, 0, 0).unwrap();
+        assert_eq!(
+            layout.path_for("/app/storage", "alice", job_id, created_at, "mp3"),
+            PathBuf::from(format!("/app/storage/alice/2025/03/{}.mp3", job_id))
+        );
+
+        // The output format decides the extension
+        let flat = StorageLayout::parse("{job_id}").unwrap();
+        assert_eq!(
+            flat.path_for("/app/storage", "alice", job_id, created_at, "flac"),
+            PathBuf::from(format!("/app/storage/{}.flac", job_id))
+        );
+        assert_eq!(
+            layout.path_for("/app/storage", "alice", job_id, created_at, "opus"),
+            PathBuf::from(format!("/app/storage/alice/2025/03/{}.opus", job_id))
+        );
+
+        // Usernames can't escape their directory
+        let path = layout.path_for("/app/storage", "../../etc", job_id, created_at, "mp3");
+        assert!(path.starts_with("/app/storage/.._.._etc"));
+        let path = layout.path_for("/app/storage", "..", job_id, created_at, "mp3");
+        assert!(path.starts_with("/app/storage/_/2025"));
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_layouts() {
+        assert!(StorageLayout::parse(DEFAULT_LAYOUT).is_ok());
+        assert!(StorageLayout::parse("{user}/out.mp3").is_err());
+        assert!(StorageLayout::parse("{year}/{job_id}.mp3").is_err());
+        assert!(StorageLayout::parse("{user/{job_id}.mp3").is_err());
+        assert!(StorageLayout::parse("/abs/{job_id}.mp3").is_err());
+        assert!(StorageLayout::parse("../{job_id}.mp3").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! `POST /stream`: synthesize text and stream the encoded audio back as the response.
+//!
+//! Sentences are synthesized one at a time with the in-process model, as for `/ws/live`,
+//! and piped through ffmpeg, so the first audio arrives while later sentences are still
+//! being synthesized. Sentences are joined with the gap or crossfade of batch jobs.
+//! Nothing is stored; long texts are better queued as a job.
+
+use crate::auth::AuthenticatedUser;
+use crate::format::{EncodingOptions, OutputFormat};
+use crate::inference::{Quality, SAMPLE_RATE};
+use crate::state::AppState;
+use axum::{
+    Extension,
+    body::Body,
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Json, Response},
+};
+use serde::Deserialize;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tracing::Instrument;
+use utoipa::ToSchema;
+
+/// Body of `POST /stream`
+#[derive(Deserialize, ToSchema)]
+pub struct StreamRequest {
+    text: String,
+    /// Defaults to `af_heart`; see `GET /voices`
+    voice: Option<String>,
+    /// Language to phonemize the text as, e.g. `es` or `fr-fr`. Defaults to the voice's;
+    /// `auto` phonemizes sentences detected as another language as that one.
+    lang: Option<String>,
+    /// Speaking rate, `0.25` to `4.0`, defaults to `1.0`
+    speed: Option<f32>,
+    /// `fast` for the quantized model, if the server has one; defaults to `KOKORO_QUALITY`
+    quality: Option<Quality>,
+    /// Synthesize with this model version rather than the current one, see
+    /// `GET /admin/model`
+    model_version: Option<String>,
+    /// `mp3` (default), `opus`, `ogg`, `flac` or `wav`
+    format: Option<String>,
+    /// For lossy formats, `32k` to `320k`
+    bitrate: Option<String>,
+    /// Output sample rate in Hz, defaults to 24000
+    sample_rate: Option<u32>,
+}
+
+/// Synthesize text and stream the encoded audio as it is produced
+#[utoipa::path(
+    post,
+    path = "/stream",
+    tag = "live",
+    request_body = StreamRequest,
+    responses(
+        (status = 200, description = "Audio in the requested format, sent as it is synthesized", content_type = "audio/mpeg"),
+        (status = 400, description = "An invalid option, or `m4b`, which can't be streamed", body = String, content_type = "text/plain"),
+        (status = 413, description = "Text longer than `TTS_MAX_INPUT_CHARS` characters", body = String, content_type = "text/plain"),
+        (status = 422, description = "The text is empty", body = String, content_type = "text/plain"),
+        (status = 429, description = "Over the monthly audio limit or daily character quota", body = String, content_type = "text/plain"),
+        (status = 503, description = "The model is still loading", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn stream_speech(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+    Json(body): Json<StreamRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    tracing::info!(user = %user.username, text_len = body.text.len(), "Received stream request");
+
+    if body.text.trim().is_empty() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Input text is empty".to_string(),
+        ));
+    }
+    let chars = body.text.chars().count();
+    if chars > state.max_input_chars {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Input text is {} characters, over the limit of {} characters",
+                chars, state.max_input_chars
+            ),
+        ));
+    }
+
+    let voice = body.voice.unwrap_or_else(|| "af_heart".to_string());
+    let speed = crate::prosody::check_speed(body.speed.unwrap_or(1.0))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let custom_voice = crate::custom_voices::CustomVoice::load(&state.pool, &user.username, &voice)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to load custom voice");
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+    // With `auto`, each sentence is phonemized in the language it's written in
+    let detect_lang = crate::voices::is_auto(body.lang.as_deref());
+    // A custom voice speaks its own language unless the request says otherwise
+    let lang = crate::voices::resolve_lang(
+        body.lang
+            .as_deref()
+            .filter(|_| !detect_lang)
+            .or(custom_voice.as_ref().map(|custom| custom.lang.as_str())),
+        &voice,
+    )
+    .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let format = match body.format.as_deref() {
+        Some(format) => OutputFormat::parse(format).map_err(|e| (StatusCode::BAD_REQUEST, e))?,
+        None => OutputFormat::default(),
+    };
+    let encoding = EncodingOptions::new(
+        format,
+        body.bitrate.as_deref(),
+        body.sample_rate.map(|rate| rate.to_string()).as_deref(),
+    )
+    .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let args = ffmpeg_args(&encoding).ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("{} can't be streamed; queue a job instead", format.as_str()),
+        )
+    })?;
+
+    let engine = crate::engine::live_engine(&state, body.model_version.as_deref(), custom_voice)
+        .await
+        .map_err(|e| (e.status, e.detail))?;
+    if !engine.has_voice(&voice) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Unknown voice '{}'. See GET /voices for available voices.",
+                voice
+            ),
+        ));
+    }
+
+    crate::usage::check_cap(&state, &user.username)
+        .await
+        .map_err(|e| (e.status, e.detail))?;
+    crate::usage::charge_characters(&state, &user.username, chars as i64)
+        .await
+        .map_err(|e| (e.status, e.detail))?;
+    let lexicon = crate::lexicon::Lexicon::load(&state.pool, &user.username)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to load lexicon");
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+
+    // ffmpeg is fed audio at the output rate
+    let mut resampler = encoding
+        .sample_rate
+        .filter(|&rate| rate != SAMPLE_RATE)
+        .map(|rate| crate::resample::Resampler::new(1, SAMPLE_RATE, rate))
+        .transpose()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    let mut ffmpeg = tokio::process::Command::new("ffmpeg")
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to start ffmpeg");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to start encoder: {}", e),
+            )
+        })?;
+    let mut stdin = ffmpeg.stdin.take().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to open encoder input".to_string(),
+    ))?;
+    let stdout = ffmpeg.stdout.take().ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Failed to open encoder output".to_string(),
+    ))?;
+
+    // Synthesis feeds the encoder while the response body reads from it. If the client
+    // goes away, ffmpeg can't write its output and exits, and writing to it fails here.
+    let username = user.username.clone();
+    tokio::spawn(async move {
+        let lexicon = Arc::new(lexicon);
+        let mut joiner = crate::join::SentenceJoiner::new(
+            SAMPLE_RATE,
+            state.crossfade_ms,
+            state.sentence_gap_ms,
+        );
+        let mut synthesized_samples = 0;
+        let sentences = crate::ws_handler::live_sentences(&body.text, lang);
+        let sentences_total = sentences.len();
+        for (index, (sentence, pause_ms)) in sentences.into_iter().enumerate() {
+            let lang = if detect_lang {
+                crate::voices::sentence_lang(&sentence, lang)
+            } else {
+                lang
+            };
+            let audio = match crate::ws_handler::synthesize_sentence(
+                &state, &engine, &sentence, &voice, lang, speed, body.quality, &lexicon,
+            )
+            .await
+            {
+                Ok((_, synthesis)) => synthesis.audio,
+                Err(e) => {
+                    // The status has been sent, so the audio just ends early
+                    tracing::error!(user = %username, error = %e, "Streaming synthesis failed");
+                    break;
+                }
+            };
+            synthesized_samples += audio.len();
+            let (mut audio, _) = joiner.push(audio, pause_ms, index + 1 == sentences_total);
+            if let Some(resampler) = &mut resampler {
+                audio = match resampler.process(&audio) {
+                    Ok(audio) => audio,
+                    Err(e) => {
+                        tracing::error!(user = %username, error = %e, "Resampling failed");
+                        break;
+                    }
+                };
+            }
+            if let Err(e) = stdin.write_all(&pcm_bytes(&audio)).await {
+                tracing::info!(user = %username, error = %e, "Stream closed before synthesis finished");
+                break;
+            }
+        }
+        if let Some(Ok(rest)) = resampler.as_mut().map(|resampler| resampler.flush()) {
+            let _ = stdin.write_all(&pcm_bytes(&rest)).await;
+        }
+        drop(stdin);
+        if let Err(e) = ffmpeg.wait().await {
+            tracing::warn!(error = %e, "Failed to wait for ffmpeg");
+        }
+        crate::usage::record(
+            &state.pool,
+            &username,
+            synthesized_samples as f64 / SAMPLE_RATE as f64,
+        )
+        .await;
+    }
+    // Keeps the request's fields on the synthesis logs
+    .instrument(tracing::Span::current()));
+
+    Ok((
+        [(header::CONTENT_TYPE, format.content_type())],
+        Body::from_stream(tokio_util::io::ReaderStream::new(stdout)),
+    )
+        .into_response())
+}
+
+/// Samples as f32le bytes, the input format of [`ffmpeg_args`]
+fn pcm_bytes(samples: &[f32]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect()
+}
+
+/// ffmpeg arguments to encode mono f32le PCM on stdin, already at the output sample rate,
+/// to `encoding` on stdout; `None` for formats that can't be written to a pipe
+fn ffmpeg_args(encoding: &EncodingOptions) -> Option<Vec<String>> {
+    let muxer = encoding.format.stream_muxer()?;
+    let mut args: Vec<String> = [
+        "-f",
+        "f32le",
+        "-ar",
+        &encoding.sample_rate.unwrap_or(SAMPLE_RATE).to_string(),
+        "-ac",
+        "1",
+        "-i",
+        "pipe:0",
+    ]
+    .map(String::from)
+    .to_vec();
+    args.extend(encoding.ffmpeg_args());
+    args.extend(["-f", muxer, "pipe:1"].map(String::from));
+    Some(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ffmpeg_args() {
+        let opus = EncodingOptions::new(OutputFormat::Opus, Some("48k"), None).unwrap();
+        assert_eq!(
+            ffmpeg_args(&opus).unwrap().join(" "),
+            "-f f32le -ar 24000 -ac 1 -i pipe:0 -c:a libopus -b:a 48k -f ogg pipe:1"
+        );
+        let m4b = EncodingOptions::new(OutputFormat::M4b, None, None).unwrap();
+        assert_eq!(ffmpeg_args(&m4b), None);
+    }
+}
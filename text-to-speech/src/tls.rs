@@ -0,0 +1,300 @@
+//! Optional HTTPS.
+//!
+//! With `TLS_CERT_PATH` and `TLS_KEY_PATH` set, the API is served over HTTPS directly, for
+//! deployments without an ingress controller, e.g. a NodePort reached by devices on the LAN.
+//! The files are checked for changes every 30 seconds and reloaded, so a renewed
+//! certificate is picked up without a restart; if the new files can't be loaded, the
+//! previous certificate stays in use.
+
+use axum::Router;
+use axum::extract::ConnectInfo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use rustls::ServerConfig;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceBuilder;
+
+/// How often the certificate and key files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    /// Parse `TLS_CERT_PATH` and `TLS_KEY_PATH`; plain HTTP if neither is set
+    pub fn parse(cert_path: Option<&str>, key_path: Option<&str>) -> Result<Option<Self>, String> {
+        let cert_path = cert_path.map(str::trim).filter(|v| !v.is_empty());
+        let key_path = key_path.map(str::trim).filter(|v| !v.is_empty());
+        match (cert_path, key_path) {
+            (None, None) => Ok(None),
+            (Some(cert_path), Some(key_path)) => Ok(Some(Self {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            })),
+            _ => Err("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string()),
+        }
+    }
+
+    fn load(&self) -> Result<CertifiedKey, String> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read {}: {}", self.cert_path.display(), e))?;
+        if certs.is_empty() {
+            return Err(format!("No certificate in {}", self.cert_path.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| format!("Failed to read {}: {}", self.key_path.display(), e))?;
+        let key = rustls::crypto::ring::sign::any_supported_type(&key)
+            .map_err(|e| format!("Unusable key in {}: {}", self.key_path.display(), e))?;
+        let certified = CertifiedKey::new(certs, key);
+        certified.keys_match().map_err(|e| {
+            format!(
+                "{} and {} don't match: {}",
+                self.cert_path.display(),
+                self.key_path.display(),
+                e
+            )
+        })?;
+        Ok(certified)
+    }
+
+    /// Last modification of either file, to notice when they are replaced
+    fn modified(&self) -> Option<SystemTime> {
+        let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(&self.cert_path).max(modified(&self.key_path))
+    }
+}
+
+/// Hands out the current certificate, which [`watch`] replaces when the files change
+#[derive(Debug)]
+struct ReloadingCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Load the certificate, failing if it can't be, and keep it up to date with the files
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let cert = Arc::new(ReloadingCert {
+        current: RwLock::new(Arc::new(config.load()?)),
+    });
+    let acceptor = tls_acceptor(cert.clone())?;
+    tokio::spawn(watch(config.clone(), cert));
+    Ok(acceptor)
+}
+
+fn tls_acceptor(cert: Arc<ReloadingCert>) -> Result<TlsAcceptor, String> {
+    let mut server =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_cert_resolver(cert);
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+async fn watch(config: TlsConfig, cert: Arc<ReloadingCert>) {
+    let mut loaded = config.modified();
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if reload_if_changed(&config, &cert, &mut loaded) {
+            tracing::info!(cert_path = %config.cert_path.display(), "Reloaded TLS certificate");
+        }
+    }
+}
+
+/// Reload the certificate if the files changed since `loaded`, returning whether it was
+fn reload_if_changed(
+    config: &TlsConfig,
+    cert: &ReloadingCert,
+    loaded: &mut Option<SystemTime>,
+) -> bool {
+    let modified = config.modified();
+    if modified == *loaded {
+        return false;
+    }
+    // Only tried once per change; a half-written file is usually complete by the next
+    // write, which changes the time again
+    *loaded = modified;
+    match config.load() {
+        Ok(key) => {
+            *cert.current.write().unwrap() = Arc::new(key);
+            true
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to reload TLS certificate, keeping the previous one");
+            false
+        }
+    }
+}
+
+/// Serve `app` over TLS on `listener`, with the peer address available as
+/// `ConnectInfo<SocketAddr>` as with plain HTTP
+pub async fn serve(listener: TcpListener, acceptor: TlsAcceptor, app: Router) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to accept connection");
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::debug!(%peer, error = %e, "TLS handshake failed");
+                    return;
+                }
+            };
+            let service = ServiceBuilder::new()
+                .map_request(
+                    move |mut request: axum::http::Request<hyper::body::Incoming>| {
+                        request.extensions_mut().insert(ConnectInfo(peer));
+                        request
+                    },
+                )
+                .service(app);
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(
+                    TokioIo::new(stream),
+                    TowerToHyperService::new(service),
+                )
+                .await
+            {
+                tracing::debug!(%peer, error = %e, "Connection ended with error");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use rustls::pki_types::ServerName;
+    use std::net::SocketAddr;
+
+    /// Self-signed certificate and key for `localhost`, as PEM
+    fn self_signed() -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        (cert.cert.pem(), cert.signing_key.serialize_pem())
+    }
+
+    fn write(dir: &tempfile::TempDir, (cert, key): &(String, String)) -> TlsConfig {
+        let config = TlsConfig {
+            cert_path: dir.path().join("tls.crt"),
+            key_path: dir.path().join("tls.key"),
+        };
+        std::fs::write(&config.cert_path, cert).unwrap();
+        std::fs::write(&config.key_path, key).unwrap();
+        config
+    }
+
+    /// The certificate the server at `addr` presents
+    async fn served_cert(addr: SocketAddr, trusted: &[&str]) -> CertificateDer<'static> {
+        let mut roots = rustls::RootCertStore::empty();
+        for pem in trusted {
+            roots
+                .add(CertificateDer::from_pem_slice(pem.as_bytes()).unwrap())
+                .unwrap();
+        }
+        let client = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let tls = tokio_rustls::TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        tls.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[test]
+    fn test_tls_config_parse() {
+        assert_eq!(TlsConfig::parse(None, Some(" ")), Ok(None));
+        assert!(
+            TlsConfig::parse(Some("/tls/tls.crt"), Some("/tls/tls.key"))
+                .unwrap()
+                .is_some()
+        );
+        assert!(TlsConfig::parse(Some("/tls/tls.crt"), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = self_signed();
+        let config = write(&dir, &first);
+        let cert = Arc::new(ReloadingCert {
+            current: RwLock::new(Arc::new(config.load().unwrap())),
+        });
+        let acceptor = tls_acceptor(cert.clone()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.ip().to_string() }),
+        );
+        tokio::spawn(serve(listener, acceptor, app));
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(first.0.as_bytes()).unwrap())
+            .resolve("localhost", addr)
+            .build()
+            .unwrap();
+        let resp = client
+            .get(format!("https://localhost:{}/", addr.port()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.text().await.unwrap(), "127.0.0.1");
+
+        // A replaced certificate is served once reloaded; a broken one is ignored
+        let mut loaded = config.modified();
+        assert!(!reload_if_changed(&config, &cert, &mut loaded));
+        std::fs::write(&config.key_path, "not a key").unwrap();
+        loaded = None;
+        assert!(!reload_if_changed(&config, &cert, &mut loaded));
+        let second = self_signed();
+        write(&dir, &second);
+        loaded = None;
+        assert_eq!(
+            served_cert(addr, &[&first.0, &second.0]).await,
+            CertificateDer::from_pem_slice(first.0.as_bytes()).unwrap()
+        );
+        assert!(reload_if_changed(&config, &cert, &mut loaded));
+        assert_eq!(
+            served_cert(addr, &[&first.0, &second.0]).await,
+            CertificateDer::from_pem_slice(second.0.as_bytes()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Limit on the size of request bodies (`TTS_MAX_UPLOAD_BYTES`).
+//!
+//! The limit replaces axum's default of 2 MB for the `Json` and `Multipart` extractors. Bodies
+//! over it are rejected with a JSON `413` before they reach a handler, rather than failing
+//! part way through reading a multipart upload.
+
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+
+/// Largest request body accepted by default: 50 MiB, enough for most EPUBs with images
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 50 * 1024 * 1024;
+
+/// Parse `TTS_MAX_UPLOAD_BYTES`
+pub fn parse_max_upload_bytes(value: Option<&str>) -> Result<usize, String> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        Some(v) => v
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid TTS_MAX_UPLOAD_BYTES '{}'", v)),
+        None => Ok(DEFAULT_MAX_UPLOAD_BYTES),
+    }
+}
+
+/// Response body for a request over the limit
+#[derive(Serialize, utoipa::ToSchema)]
+pub struct UploadTooLarge {
+    pub error: String,
+    pub max_upload_bytes: usize,
+}
+
+/// Reads the request body up to `max_upload_bytes`, responding with `413` if there is more.
+/// Runs after `auth_middleware`, so only authenticated uploads are read.
+pub async fn limit_body(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let limit = state.max_upload_bytes;
+    let (parts, body) = request.into_parts();
+    let bytes = match axum::body::to_bytes(body, limit).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            let e = e.into_inner();
+            if e.is::<http_body_util::LengthLimitError>() {
+                tracing::warn!(limit, path = %parts.uri.path(), "Request body too large");
+                let body = UploadTooLarge {
+                    error: format!("Request body is larger than the limit of {} bytes", limit),
+                    max_upload_bytes: limit,
+                };
+                return (StatusCode::PAYLOAD_TOO_LARGE, Json(body)).into_response();
+            }
+            tracing::warn!(error = %e, "Failed to read request body");
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Failed to read request body: {}", e),
+            )
+                .into_response();
+        }
+    };
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_parse_max_upload_bytes() {
+        assert_eq!(parse_max_upload_bytes(None), Ok(DEFAULT_MAX_UPLOAD_BYTES));
+        assert_eq!(parse_max_upload_bytes(Some("1048576")), Ok(1_048_576));
+        assert!(parse_max_upload_bytes(Some("0")).is_err());
+        assert!(parse_max_upload_bytes(Some("1MB")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_limit() {
+        let app = TestApp::spawn_without_workers().await;
+        let upload = |size: usize| {
+            let form = reqwest::multipart::Form::new().part(
+                "text_file",
+                reqwest::multipart::Part::bytes(vec![b'a'; size]).file_name("big.txt"),
+            );
+            app.client
+                .post(app.url("/generate"))
+                .bearer_auth(app.token("alice"))
+                .multipart(form)
+                .send()
+        };
+
+        let resp = upload(TestApp::MAX_UPLOAD_BYTES + 1).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["max_upload_bytes"], TestApp::MAX_UPLOAD_BYTES);
+
+        // Under the upload limit, the text length limit applies
+        let resp = upload(TestApp::MAX_UPLOAD_BYTES / 2).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "input_too_long");
+
+        let resp = upload(TestApp::MAX_INPUT_CHARS).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,449 @@
+//! Per-user accounting of synthesized audio, with optional monthly caps, and of
+//! submitted characters, with optional daily quotas.
+//!
+//! Every batch job and live synthesis adds the duration of the audio it produced
+//! (counted from samples) to the user's total for the current UTC month. When a cap
+//! is configured, new jobs and live synthesis requests are refused with `429` once the
+//! month's total reaches it. A request that starts under the cap is allowed to finish,
+//! since its length isn't known until it has been synthesized.
+//!
+//! Characters are known up front, so they are counted when a request is accepted, and a
+//! request that would take the user over their daily quota is refused outright.
+
+use crate::auth::AuthenticatedUser;
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::Serialize;
+use sqlx::{Executor, PgConnection, Pool, Postgres, Row};
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+/// Monthly caps on synthesized audio, in seconds. `None` means unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageCaps {
+    default_secs: Option<f64>,
+    overrides: HashMap<String, Option<f64>>,
+}
+
+impl UsageCaps {
+    /// Parse `MONTHLY_AUDIO_MINUTES` (default cap) and `MONTHLY_AUDIO_MINUTES_OVERRIDES`
+    /// (`user=minutes` pairs, comma separated; `unlimited` lifts the cap for a user)
+    pub fn parse(default_minutes: Option<&str>, overrides: &str) -> Result<Self, String> {
+        let default_secs = default_minutes
+            .filter(|v| !v.trim().is_empty())
+            .map(parse_minutes)
+            .transpose()?
+            .flatten();
+
+        Ok(Self {
+            default_secs,
+            overrides: parse_overrides(overrides, "minutes", parse_minutes)?,
+        })
+    }
+
+    pub fn cap_secs(&self, username: &str) -> Option<f64> {
+        self.overrides
+            .get(username)
+            .copied()
+            .unwrap_or(self.default_secs)
+    }
+}
+
+/// Daily quotas on submitted characters. `None` means unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CharacterQuotas {
+    default: Option<i64>,
+    overrides: HashMap<String, Option<i64>>,
+}
+
+impl CharacterQuotas {
+    /// Parse `DAILY_CHARACTER_QUOTA` (default quota) and `DAILY_CHARACTER_QUOTA_OVERRIDES`
+    /// (`user=characters` pairs, comma separated; `unlimited` lifts the quota for a user)
+    pub fn parse(default: Option<&str>, overrides: &str) -> Result<Self, String> {
+        let default = default
+            .filter(|v| !v.trim().is_empty())
+            .map(parse_characters)
+            .transpose()?
+            .flatten();
+        Ok(Self {
+            default,
+            overrides: parse_overrides(overrides, "characters", parse_characters)?,
+        })
+    }
+
+    pub fn quota(&self, username: &str) -> Option<i64> {
+        self.overrides
+            .get(username)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Parse comma-separated `user=limit` pairs
+fn parse_overrides<T>(
+    overrides: &str,
+    unit: &str,
+    parse: fn(&str) -> Result<Option<T>, String>,
+) -> Result<HashMap<String, Option<T>>, String> {
+    let mut parsed = HashMap::new();
+    for entry in overrides
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        let (user, limit) = entry.split_once('=').ok_or_else(|| {
+            format!(
+                "Invalid usage cap override '{}', expected user={}",
+                entry, unit
+            )
+        })?;
+        parsed.insert(user.trim().to_string(), parse(limit)?);
+    }
+    Ok(parsed)
+}
+
+fn parse_characters(value: &str) -> Result<Option<i64>, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+    match value.parse::<i64>() {
+        Ok(characters) if characters >= 0 => Ok(Some(characters)),
+        _ => Err(format!("Invalid daily character quota '{}'", value)),
+    }
+}
+
+fn parse_minutes(value: &str) -> Result<Option<f64>, String> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("unlimited") {
+        return Ok(None);
+    }
+    match value.parse::<f64>() {
+        Ok(minutes) if minutes.is_finite() && minutes >= 0.0 => Ok(Some(minutes * 60.0)),
+        _ => Err(format!("Invalid monthly audio minutes '{}'", value)),
+    }
+}
+
+/// Add synthesized audio to a user's total for the current month
+pub async fn record(pool: &Pool<Postgres>, username: &str, audio_secs: f64) {
+    if audio_secs <= 0.0 {
+        return;
+    }
+    let result = sqlx::query(
+        r#"
+        INSERT INTO audio_usage (username, month, audio_seconds)
+        VALUES ($1, date_trunc('month', NOW() AT TIME ZONE 'UTC')::date, $2)
+        ON CONFLICT (username, month)
+        DO UPDATE SET audio_seconds = audio_usage.audio_seconds + EXCLUDED.audio_seconds
+        "#,
+    )
+    .bind(username)
+    .bind(audio_secs)
+    .execute(pool)
+    .await;
+
+    if let Err(e) = result {
+        tracing::error!(user = %username, audio_secs, error = %e, "Failed to record audio usage");
+    }
+}
+
+/// Seconds of audio synthesized by a user this month
+async fn used_secs(pool: &Pool<Postgres>, username: &str) -> Result<f64, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT audio_seconds FROM audio_usage WHERE username = $1 AND month = date_trunc('month', NOW() AT TIME ZONE 'UTC')::date",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.get("audio_seconds")).unwrap_or(0.0))
+}
+
+/// Refuse new synthesis once the user has used up this month's cap
+pub async fn check_cap(state: &AppState, username: &str) -> Result<(), ApiError> {
+    let Some(cap_secs) = state.usage_caps.cap_secs(username) else {
+        return Ok(());
+    };
+    let used = used_secs(&state.pool, username).await.map_err(|e| {
+        tracing::error!(user = %username, error = %e, "Failed to read audio usage");
+        ApiError::internal(e.to_string())
+    })?;
+
+    if used >= cap_secs {
+        tracing::warn!(user = %username, used_secs = used, cap_secs, "Monthly audio cap reached");
+        return Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "audio_cap_exceeded",
+            format!(
+                "Monthly audio limit of {:.0} minutes reached ({:.1} minutes used)",
+                cap_secs / 60.0,
+                used / 60.0
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Characters a user has submitted today
+async fn used_characters<'e>(
+    db: impl Executor<'e, Database = Postgres>,
+    username: &str,
+) -> Result<i64, sqlx::Error> {
+    let used: Option<i64> = sqlx::query_scalar(
+        "SELECT characters FROM character_usage WHERE username = $1 AND day = (NOW() AT TIME ZONE 'UTC')::date",
+    )
+    .bind(username)
+    .fetch_optional(db)
+    .await?;
+    Ok(used.unwrap_or(0))
+}
+
+/// Count `characters` against the user's quota for today, or refuse the request with `429`
+/// if they would take the user over it
+pub async fn charge_characters(
+    state: &AppState,
+    username: &str,
+    characters: i64,
+) -> Result<(), ApiError> {
+    let mut conn = state.pool.acquire().await.map_err(|e| {
+        tracing::error!(user = %username, error = %e, "Failed to record character usage");
+        ApiError::internal(e.to_string())
+    })?;
+    charge_characters_in(state, &mut conn, username, characters).await
+}
+
+/// [`charge_characters`] on `conn`, so that within a transaction the charge only stands if
+/// the transaction commits
+pub async fn charge_characters_in(
+    state: &AppState,
+    conn: &mut PgConnection,
+    username: &str,
+    characters: i64,
+) -> Result<(), ApiError> {
+    let quota = state.character_quotas.quota(username);
+    let db_error = |e: sqlx::Error| {
+        tracing::error!(user = %username, error = %e, "Failed to record character usage");
+        ApiError::internal(e.to_string())
+    };
+
+    // The quota is checked in the same statement that adds to the total, so concurrent
+    // requests can't both slip under it
+    let charged = quota.is_none_or(|quota| characters <= quota)
+        && sqlx::query(
+            r#"
+            INSERT INTO character_usage (username, day, characters)
+            VALUES ($1, (NOW() AT TIME ZONE 'UTC')::date, $2)
+            ON CONFLICT (username, day)
+            DO UPDATE SET characters = character_usage.characters + EXCLUDED.characters
+            WHERE $3::BIGINT IS NULL OR character_usage.characters + EXCLUDED.characters <= $3
+            "#,
+        )
+        .bind(username)
+        .bind(characters)
+        .bind(quota)
+        .execute(&mut *conn)
+        .await
+        .map_err(db_error)?
+        .rows_affected()
+            > 0;
+    if charged {
+        return Ok(());
+    }
+
+    let quota = quota.unwrap_or_default();
+    let used = used_characters(&mut *conn, username)
+        .await
+        .map_err(db_error)?;
+    tracing::warn!(user = %username, characters, used, quota, "Daily character quota exceeded");
+    Err(ApiError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "character_quota_exceeded",
+        format!(
+            "Daily character quota of {} exceeded: this request has {} characters, {} remaining today",
+            quota,
+            characters,
+            (quota - used).max(0)
+        ),
+    ))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageResponse {
+    /// Current accounting month, `YYYY-MM` (UTC)
+    month: String,
+    audio_seconds: f64,
+    /// `null` when the user has no cap
+    cap_seconds: Option<f64>,
+    remaining_seconds: Option<f64>,
+}
+
+/// `GET /me/usage`
+#[utoipa::path(
+    get,
+    path = "/me/usage",
+    tag = "usage",
+    responses((status = 200, body = UsageResponse))
+)]
+pub async fn get_usage(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+) -> Result<Json<UsageResponse>, (StatusCode, String)> {
+    let used = used_secs(&state.pool, &user.username).await.map_err(|e| {
+        tracing::error!(user = %user.username, error = %e, "Failed to read audio usage");
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    let cap = state.usage_caps.cap_secs(&user.username);
+
+    Ok(Json(UsageResponse {
+        month: chrono::Utc::now().format("%Y-%m").to_string(),
+        audio_seconds: used,
+        cap_seconds: cap,
+        remaining_seconds: cap.map(|cap| (cap - used).max(0.0)),
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CharacterUsageResponse {
+    /// Current accounting day, `YYYY-MM-DD` (UTC)
+    day: String,
+    characters: i64,
+    /// `null` when the user has no quota
+    quota: Option<i64>,
+    remaining: Option<i64>,
+}
+
+/// `GET /usage`
+#[utoipa::path(
+    get,
+    path = "/usage",
+    tag = "usage",
+    responses((status = 200, body = CharacterUsageResponse))
+)]
+pub async fn get_character_usage(
+    Extension(user): Extension<AuthenticatedUser>,
+    State(state): State<AppState>,
+) -> Result<Json<CharacterUsageResponse>, (StatusCode, String)> {
+    let used = used_characters(&state.pool, &user.username)
+        .await
+        .map_err(|e| {
+            tracing::error!(user = %user.username, error = %e, "Failed to read character usage");
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+    let quota = state.character_quotas.quota(&user.username);
+
+    Ok(Json(CharacterUsageResponse {
+        day: chrono::Utc::now().format("%Y-%m-%d").to_string(),
+        characters: used,
+        quota,
+        remaining: quota.map(|quota| (quota - used).max(0)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestApp;
+
+    #[test]
+    fn test_usage_caps() {
+        let caps = UsageCaps::parse(Some("600"), "alice=30, bob=unlimited").unwrap();
+        assert_eq!(caps.cap_secs("carol"), Some(36000.0));
+        assert_eq!(caps.cap_secs("alice"), Some(1800.0));
+        assert_eq!(caps.cap_secs("bob"), None);
+
+        let uncapped = UsageCaps::parse(None, "alice=30").unwrap();
+        assert_eq!(uncapped.cap_secs("carol"), None);
+        assert_eq!(uncapped.cap_secs("alice"), Some(1800.0));
+
+        assert!(UsageCaps::parse(Some("lots"), "").is_err());
+        assert!(UsageCaps::parse(None, "alice").is_err());
+        assert!(UsageCaps::parse(None, "alice=-5").is_err());
+    }
+
+    #[test]
+    fn test_character_quotas() {
+        let quotas = CharacterQuotas::parse(Some("50000"), "alice=100, bob=unlimited").unwrap();
+        assert_eq!(quotas.quota("carol"), Some(50000));
+        assert_eq!(quotas.quota("alice"), Some(100));
+        assert_eq!(quotas.quota("bob"), None);
+
+        assert_eq!(
+            CharacterQuotas::parse(None, "").unwrap().quota("carol"),
+            None
+        );
+        assert!(CharacterQuotas::parse(Some("1.5"), "").is_err());
+        assert!(CharacterQuotas::parse(None, "alice=-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_daily_character_quota() {
+        let app = TestApp::spawn_without_workers().await;
+        let generate = |text: &'static str| {
+            app.client
+                .post(app.url("/generate-json"))
+                .bearer_auth(app.token(TestApp::LIMITED))
+                .json(&serde_json::json!({ "text": text }))
+                .send()
+        };
+        let usage = || async {
+            let resp = app
+                .client
+                .get(app.url("/usage"))
+                .bearer_auth(app.token(TestApp::LIMITED))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            resp.json::<serde_json::Value>().await.unwrap()
+        };
+
+        assert_eq!(
+            generate("Twelve chars").await.unwrap().status(),
+            StatusCode::OK
+        );
+        let body = usage().await;
+        assert_eq!(body["characters"], 12);
+        assert_eq!(body["quota"], TestApp::LIMITED_CHARACTERS);
+        assert_eq!(body["remaining"], 8);
+
+        // A charge only stands if its transaction commits, as when creating a job fails
+        let mut tx = app.state.pool.begin().await.unwrap();
+        charge_characters_in(&app.state, &mut tx, TestApp::LIMITED, 5)
+            .await
+            .unwrap();
+        tx.rollback().await.unwrap();
+        assert_eq!(usage().await["remaining"], 8);
+
+        // Would go over the quota, so nothing is charged
+        let resp = generate("Ten chars!").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "character_quota_exceeded");
+        assert_eq!(
+            problem["detail"],
+            "Daily character quota of 20 exceeded: this request has 10 characters, 8 remaining today"
+        );
+        assert_eq!(usage().await["characters"], 12);
+
+        assert_eq!(generate("Eight ch").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(usage().await["remaining"], 0);
+
+        // Users without a quota are only counted
+        let resp = app
+            .client
+            .get(app.url("/usage"))
+            .bearer_auth(app.token("alice"))
+            .send()
+            .await
+            .unwrap();
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["characters"], 0);
+        assert_eq!(body["quota"], serde_json::Value::Null);
+
+        app.teardown().await;
+    }
+}
@@ -0,0 +1,261 @@
+//! Voice discovery.
+//!
+//! Kokoro voice names encode their language and gender in a two-letter prefix:
+//! `af_heart` is an American English female voice, `bm_george` a British English
+//! male one. The language prefix also picks the espeak-ng language text is phonemized
+//! with, unless a request sets `lang`.
+
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde::Serialize;
+use std::sync::OnceLock;
+use utoipa::ToSchema;
+
+/// A voice available for synthesis
+#[derive(Debug, Serialize, PartialEq, ToSchema)]
+pub struct VoiceInfo {
+    pub name: String,
+    /// Language prefix letter from the voice name, e.g. `a`
+    pub language_code: String,
+    /// Human-readable language, if the prefix is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<&'static str>,
+    /// Default `lang` for the voice, if the prefix is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gender: Option<&'static str>,
+}
+
+impl VoiceInfo {
+    /// Infer language and gender from a Kokoro voice name
+    pub fn from_name(name: &str) -> Self {
+        let mut prefix = name.split('_').next().unwrap_or("").chars();
+        let lang = prefix.next();
+        let gender = prefix.next();
+
+        Self {
+            name: name.to_string(),
+            language_code: lang.map(String::from).unwrap_or_default(),
+            language: lang.and_then(language).map(|(name, _)| name),
+            lang: lang.and_then(language).map(|(_, code)| code),
+            gender: match gender {
+                Some('f') => Some("female"),
+                Some('m') => Some("male"),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Voice prefix letter, language name and espeak-ng language code
+const LANGUAGES: &[(char, &str, &str)] = &[
+    ('a', "American English", "en-us"),
+    ('b', "British English", "en-gb"),
+    ('e', "Spanish", "es"),
+    ('f', "French", "fr-fr"),
+    ('h', "Hindi", "hi"),
+    ('i', "Italian", "it"),
+    ('j', "Japanese", "ja"),
+    ('p', "Brazilian Portuguese", "pt-br"),
+    ('z', "Mandarin Chinese", "cmn"),
+];
+
+/// Shorter names accepted for `lang`
+const LANG_ALIASES: &[(&str, &str)] = &[
+    ("en", "en-us"),
+    ("fr", "fr-fr"),
+    ("pt", "pt-br"),
+    ("zh", "cmn"),
+];
+
+/// Name and espeak-ng code of the language with this voice prefix letter
+fn language(prefix: char) -> Option<(&'static str, &'static str)> {
+    LANGUAGES
+        .iter()
+        .find(|(letter, ..)| *letter == prefix)
+        .map(|(_, name, code)| (*name, *code))
+}
+
+/// The `lang` asking for each sentence to be phonemized in the language it's detected as,
+/// and otherwise in the voice's own
+pub const AUTO_LANG: &str = "auto";
+
+/// Whether `lang` asks for each sentence's language to be detected
+pub fn is_auto(lang: Option<&str>) -> bool {
+    lang.is_some_and(|lang| lang.trim().eq_ignore_ascii_case(AUTO_LANG))
+}
+
+/// The language to phonemize with: `lang` if given, otherwise the voice's own language,
+/// which `auto` falls back to too. Voices with an unknown prefix default to American English.
+pub fn resolve_lang(lang: Option<&str>, voice: &str) -> Result<&'static str, String> {
+    let Some(lang) = lang
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !is_auto(Some(l)))
+    else {
+        return Ok(default_lang(voice));
+    };
+    let lang = lang.to_ascii_lowercase().replace('_', "-");
+    LANGUAGES
+        .iter()
+        .map(|(_, _, code)| *code)
+        .find(|code| *code == lang)
+        .or_else(|| {
+            LANG_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == lang)
+                .map(|(_, code)| *code)
+        })
+        .ok_or_else(|| {
+            format!(
+                "Unsupported lang '{}'. Use one of: {}, or {} to detect it",
+                lang,
+                LANGUAGES
+                    .iter()
+                    .map(|(_, _, code)| *code)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                AUTO_LANG
+            )
+        })
+}
+
+/// The language a voice speaks, from its prefix
+pub fn default_lang(voice: &str) -> &'static str {
+    voice
+        .chars()
+        .next()
+        .and_then(language)
+        .map_or("en-us", |(_, code)| code)
+}
+
+/// Languages a sentence can be detected as, with their espeak-ng code. Sentences in
+/// English are left in the requested variety.
+const DETECTABLE: &[(whatlang::Lang, &str)] = &[
+    (whatlang::Lang::Eng, "en-us"),
+    (whatlang::Lang::Spa, "es"),
+    (whatlang::Lang::Fra, "fr-fr"),
+    (whatlang::Lang::Hin, "hi"),
+    (whatlang::Lang::Ita, "it"),
+    (whatlang::Lang::Jpn, "ja"),
+    (whatlang::Lang::Por, "pt-br"),
+    (whatlang::Lang::Cmn, "cmn"),
+];
+
+/// Least confidence to switch a sentence's language on. Whatlang's own "reliable" mark
+/// needs long sentences; short English ones rarely get near this.
+const MIN_DETECT_CONFIDENCE: f64 = 0.5;
+
+/// The language to phonemize one sentence of `lang` text with: the one it's written in
+/// if that's detected with confidence, otherwise `lang`. Keeps e.g. a French quotation in an
+/// English article from being read with English rules.
+pub fn sentence_lang<'a>(sentence: &str, lang: &'a str) -> &'a str {
+    static DETECTOR: OnceLock<whatlang::Detector> = OnceLock::new();
+    let detector = DETECTOR.get_or_init(|| {
+        whatlang::Detector::with_allowlist(DETECTABLE.iter().map(|(l, _)| *l).collect())
+    });
+    let detected = detector
+        .detect(sentence)
+        .filter(|info| info.confidence() >= MIN_DETECT_CONFIDENCE)
+        .and_then(|info| DETECTABLE.iter().find(|(l, _)| *l == info.lang()))
+        .map(|(_, code)| *code);
+    // Detection can't tell varieties apart, so en-gb isn't switched to en-us
+    fn family(code: &str) -> &str {
+        code.split_once('-').map_or(code, |(family, _)| family)
+    }
+    match detected {
+        Some(code) if family(code) != family(lang) => code,
+        _ => lang,
+    }
+}
+
+/// List the voices loaded from the voices file
+#[utoipa::path(
+    get,
+    path = "/voices",
+    tag = "voices",
+    responses(
+        (status = 200, body = Vec<VoiceInfo>),
+        (status = 503, description = "The model is loading or failed to load", body = crate::error::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn list_voices(State(state): State<AppState>) -> Result<Json<Vec<VoiceInfo>>, ApiError> {
+    let model = state.kokoro_model.read().await.model().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "model_not_loaded",
+            "TTS model not loaded",
+        )
+    })?;
+
+    let voices = model
+        .voice_names()
+        .iter()
+        .map(|name| VoiceInfo::from_name(name))
+        .collect();
+    Ok(Json(voices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_voice_info_from_name() {
+        assert_eq!(
+            VoiceInfo::from_name("af_heart"),
+            VoiceInfo {
+                name: "af_heart".to_string(),
+                language_code: "a".to_string(),
+                language: Some("American English"),
+                lang: Some("en-us"),
+                gender: Some("female"),
+            }
+        );
+
+        let voice = VoiceInfo::from_name("bm_george");
+        assert_eq!(voice.language, Some("British English"));
+        assert_eq!(voice.gender, Some("male"));
+
+        let voice = VoiceInfo::from_name("custom");
+        assert_eq!(voice.language, None);
+        assert_eq!(voice.gender, None);
+    }
+
+    #[test]
+    fn test_resolve_lang() {
+        assert_eq!(resolve_lang(None, "af_heart"), Ok("en-us"));
+        assert_eq!(resolve_lang(None, "bm_george"), Ok("en-gb"));
+        assert_eq!(resolve_lang(None, "ef_dora"), Ok("es"));
+        assert_eq!(resolve_lang(Some(" "), "ff_siwis"), Ok("fr-fr"));
+        assert_eq!(resolve_lang(None, "custom"), Ok("en-us"));
+        assert_eq!(resolve_lang(Some("es"), "af_heart"), Ok("es"));
+        assert_eq!(resolve_lang(Some("FR"), "af_heart"), Ok("fr-fr"));
+        assert_eq!(resolve_lang(Some("pt_BR"), "af_heart"), Ok("pt-br"));
+        assert!(
+            resolve_lang(Some("klingon"), "af_heart")
+                .unwrap_err()
+                .contains("en-us")
+        );
+        assert_eq!(resolve_lang(Some("Auto"), "ef_dora"), Ok("es"));
+        assert!(is_auto(Some(" auto ")));
+        assert!(!is_auto(None));
+        assert!(!is_auto(Some("en-us")));
+    }
+
+    #[test]
+    fn test_sentence_lang() {
+        let french = "Je pense, donc je suis, et la vie est belle quand il fait beau.";
+        let english = "The weather was lovely and we spent the whole afternoon in the park.";
+        assert_eq!(sentence_lang(french, "en-us"), "fr-fr");
+        assert_eq!(sentence_lang(english, "fr-fr"), "en-us");
+        assert_eq!(sentence_lang(english, "en-gb"), "en-gb");
+        assert_eq!(sentence_lang(english, "en-us"), "en-us");
+        assert_eq!(sentence_lang("Hola, ¿cómo estás?", "en-us"), "es");
+        // Too little to go on
+        assert_eq!(sentence_lang("Oui.", "en-us"), "en-us");
+        assert_eq!(sentence_lang("He said hello.", "en-us"), "en-us");
+        assert_eq!(sentence_lang("", "es"), "es");
+    }
+}
@@ -1,23 +1,89 @@
 //! WebSocket handler for real-time TTS streaming.
 //!
 //! This module provides a WebSocket endpoint for live TTS synthesis with
-//! audio streaming and word timing information.
+//! audio streaming and word timing information, and one for following the
+//! audio of an async job while it is still processing.
+//!
+//! Clients are pinged every `WS_PING_INTERVAL_SECS` and dropped if they don't answer a
+//! ping before the next is due, and a live session with nothing streaming is closed after
+//! `WS_IDLE_TIMEOUT_SECS` without a message, so abandoned browser tabs don't hold on to
+//! the connection forever.
 
 use crate::auth::validate_token_public;
-use crate::inference::SAMPLE_RATE;
-use crate::phonemizer::{estimate_word_timings, phonemize, split_sentences};
+use crate::framing::Framing;
+use crate::inference::{Quality, SAMPLE_RATE, Synthesis};
+use crate::live_audio::{Codec, FrameEncoder};
+use crate::phonemizer::{
+    aligned_word_timings, estimate_word_timings, split_sentences, word_phonemes,
+};
 use crate::state::AppState;
 
 use axum::{
     extract::{
-        State,
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code},
     },
     response::Response,
 };
 use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroU32;
 use std::sync::Arc;
-use tokio::sync::watch;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+/// How often WebSocket clients are pinged, and how long a live session may sit idle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keepalive {
+    pub ping_interval: Duration,
+    /// `None` to keep idle sessions open
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+impl Keepalive {
+    /// Parse `WS_PING_INTERVAL_SECS` (default 30) and `WS_IDLE_TIMEOUT_SECS` (default 600,
+    /// 0 to never close idle sessions)
+    pub fn parse(
+        ping_interval_secs: Option<&str>,
+        idle_timeout_secs: Option<&str>,
+    ) -> Result<Self, String> {
+        let secs = |value: Option<&str>, name: &str, min: u64| match value
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+        {
+            Some(v) => v
+                .parse::<u64>()
+                .ok()
+                .filter(|&n| n >= min)
+                .map(Some)
+                .ok_or_else(|| format!("Invalid {} '{}'", name, v)),
+            None => Ok(None),
+        };
+        let defaults = Self::default();
+        let ping_interval = secs(ping_interval_secs, "WS_PING_INTERVAL_SECS", 1)?
+            .map_or(defaults.ping_interval, Duration::from_secs);
+        let idle_timeout = match secs(idle_timeout_secs, "WS_IDLE_TIMEOUT_SECS", 0)? {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => defaults.idle_timeout,
+        };
+        Ok(Self {
+            ping_interval,
+            idle_timeout,
+        })
+    }
+}
 
 /// Client to server message types
 #[derive(Debug, Deserialize)]
@@ -25,18 +91,67 @@ use tokio::sync::watch;
 enum ClientMessage {
     Auth {
         token: String,
+        /// `session_token` of an earlier `/ws/live` connection to pick up where it left off
+        #[serde(default)]
+        resume: Option<String>,
     },
     Synthesize {
         text: String,
-        voice: String,
-        speed: f32,
+        /// Voice, speed and language default to the session's, see [`VoiceSettings`]
+        #[serde(default)]
+        voice: Option<String>,
+        #[serde(default)]
+        speed: Option<f32>,
+        #[serde(default)]
+        lang: Option<String>,
+        /// `fast` for the quantized model, if the server has one
+        #[serde(default)]
+        quality: Option<Quality>,
+        /// Pins the session to a model version, to keep it through a model reload
+        #[serde(default)]
+        model_version: Option<String>,
+        /// How the audio frames are encoded, see `live_audio`
+        #[serde(default)]
+        codec: Codec,
+        /// Which header they start with, see `framing`
+        #[serde(default)]
+        framing: Framing,
+        /// Echoed in the responses to this message
+        #[serde(default)]
+        request_id: Option<String>,
+        /// Turns on flow control for the session, see [`FlowControl`]
+        #[serde(default)]
+        window: Option<NonZeroU32>,
     },
     SynthesizeAppend {
         text: String,
-        voice: String,
-        speed: f32,
+        #[serde(default)]
+        voice: Option<String>,
+        #[serde(default)]
+        speed: Option<f32>,
+        #[serde(default)]
+        lang: Option<String>,
+        #[serde(default)]
+        quality: Option<Quality>,
+        #[serde(default)]
+        model_version: Option<String>,
+        #[serde(default)]
+        codec: Codec,
+        #[serde(default)]
+        framing: Framing,
+        #[serde(default)]
+        request_id: Option<String>,
+        #[serde(default)]
+        window: Option<NonZeroU32>,
     },
     Stop,
+    /// Hold the audio where it is, mid-sentence if need be, until `Resume`
+    Pause,
+    Resume,
+    /// The client is done with this many more audio frames
+    Ack {
+        frames: u32,
+    },
 }
 
 /// Server to client message types
@@ -45,22 +160,237 @@ enum ClientMessage {
 enum ServerMessage {
     AuthOk {
         username: String,
+        /// For `/ws/live`, to resume the session after reconnecting
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_token: Option<String>,
+        /// Whether the session asked to be resumed was
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        resumed: bool,
     },
     AuthError {
         message: String,
     },
     WordTiming {
         sentence_index: u32,
+        /// The sentence as synthesized, with numbers and abbreviations spelled out
+        text: String,
+        /// What the sentence was phonemized as, lexicon entries applied
+        phonemes: String,
         words: Vec<WordInfo>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
     },
     SentenceDone {
         sentence_index: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    Done {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
     },
-    Done,
     Error {
         message: String,
+        /// Of the `synthesize` message that failed, if it had one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    /// A finished job's output follows on `/jobs/:id/stream`, in binary messages
+    Output {
+        format: String,
+        content_type: String,
+        size: u64,
     },
     Stopped,
+    Paused,
+    Resumed,
+}
+
+/// The voice, speed, language and quality a live session synthesizes with. Each `synthesize`
+/// message only needs to name what changes, so a dialogue reader can switch voices at each
+/// speaker turn and keep the rest.
+#[derive(Debug, Clone, PartialEq)]
+struct VoiceSettings {
+    voice: String,
+    speed: f32,
+    /// `None` for the voice's own language, or `auto` for the one each sentence is detected as
+    lang: Option<String>,
+    /// `None` for the server's default
+    quality: Option<Quality>,
+    /// `None` for the current model
+    model_version: Option<String>,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        Self {
+            voice: "af_heart".to_string(),
+            speed: 1.0,
+            lang: None,
+            quality: None,
+            model_version: None,
+        }
+    }
+}
+
+impl VoiceSettings {
+    /// Apply the settings a message sets, if they are valid. A new voice goes back to its
+    /// own language unless the message sets one too.
+    fn update(
+        &mut self,
+        voice: Option<String>,
+        speed: Option<f32>,
+        lang: Option<String>,
+    ) -> Result<(), String> {
+        let mut updated = self.clone();
+        if let Some(voice) = voice {
+            if voice != updated.voice {
+                updated.lang = None;
+            }
+            updated.voice = voice;
+        }
+        if let Some(speed) = speed {
+            crate::prosody::check_speed(speed)?;
+            updated.speed = speed;
+        }
+        if lang.is_some() {
+            updated.lang = lang;
+        }
+        crate::voices::resolve_lang(updated.lang.as_deref(), &updated.voice)?;
+        *self = updated;
+        Ok(())
+    }
+}
+
+/// Credit-based flow control. Once a `synthesize` message sets a `window`, at most that
+/// many audio frames are sent ahead of the client's `ack`s, with no delay between them, so
+/// a slow client isn't flooded and a fast one isn't held back. Without one, frames are
+/// paced by a fixed delay as they always have been.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct FlowControl {
+    window: Option<NonZeroU32>,
+    /// Frames sent since flow control was turned on and not acked yet
+    unacked: u32,
+}
+
+impl FlowControl {
+    /// Apply the window a message sets, if any; it stays in effect for later messages
+    fn set_window(&mut self, window: Option<NonZeroU32>) {
+        if window.is_some() {
+            self.window = window;
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.window.is_none_or(|window| self.unacked < window.get())
+    }
+
+    fn sent(&mut self) {
+        if self.window.is_some() {
+            self.unacked += 1;
+        }
+    }
+
+    fn ack(&mut self, frames: u32) {
+        self.unacked = self.unacked.saturating_sub(frames);
+    }
+}
+
+/// What a live session keeps between messages, and across reconnects
+#[derive(Debug, Default)]
+struct Session {
+    /// Index of the next sentence; sentence indexes run on across append messages
+    sentence_counter: u32,
+    settings: VoiceSettings,
+    /// Buffer for incomplete text (text that hasn't ended with sentence-ending punctuation)
+    pending_text: String,
+    /// Flow control window, once a message has set one
+    window: Option<NonZeroU32>,
+    /// The batch being streamed, or left unfinished when the connection was lost
+    batch: Option<Batch>,
+}
+
+/// The sentences of a `synthesize` message that are yet to be sent in full
+#[derive(Debug)]
+struct Batch {
+    /// Each with the silence after it, in milliseconds
+    sentences: VecDeque<(String, u32)>,
+    /// Index of the first of `sentences`
+    next_index: u32,
+    lang: &'static str,
+    /// Whether sentences detected as another language are phonemized as that one
+    detect_lang: bool,
+    codec: Codec,
+    framing: Framing,
+    request_id: Option<String>,
+    /// Characters not yet counted against the daily quota, which they are once there is
+    /// an engine to speak them
+    uncharged_characters: i64,
+}
+
+impl Batch {
+    /// Move on once the first sentence is sent, or has nothing to send
+    fn advance(&mut self) {
+        self.sentences.pop_front();
+        self.next_index += 1;
+    }
+}
+
+/// How long a live session can be resumed for after its connection is lost
+const RESUME_WINDOW: Duration = Duration::from_secs(300);
+
+/// Sessions kept at most, beyond which the oldest are dropped
+const MAX_SUSPENDED_SESSIONS: usize = 1000;
+
+/// Live sessions whose connection went away, kept for [`RESUME_WINDOW`] in case their
+/// client reconnects. Held in memory, so a session only resumes on the same replica.
+#[derive(Default)]
+pub struct SuspendedSessions {
+    sessions: std::sync::Mutex<HashMap<String, Suspended>>,
+}
+
+struct Suspended {
+    username: String,
+    session: Session,
+    expires_at: Instant,
+}
+
+impl SuspendedSessions {
+    fn suspend(&self, token: String, username: &str, session: Session) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, suspended| suspended.expires_at > now);
+        while sessions.len() >= MAX_SUSPENDED_SESSIONS {
+            let Some(oldest) = sessions
+                .iter()
+                .min_by_key(|(_, suspended)| suspended.expires_at)
+                .map(|(token, _)| token.clone())
+            else {
+                break;
+            };
+            sessions.remove(&oldest);
+        }
+        sessions.insert(
+            token,
+            Suspended {
+                username: username.to_string(),
+                session,
+                expires_at: now + RESUME_WINDOW,
+            },
+        );
+    }
+
+    /// The session `token` names, if it is `username`'s and hasn't expired. It can only
+    /// be resumed once; the connection resuming it suspends it again when it ends.
+    fn resume(&self, token: &str, username: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let suspended = sessions.get(token)?;
+        if suspended.username != username {
+            return None;
+        }
+        let suspended = sessions.remove(token)?;
+        (suspended.expires_at > Instant::now()).then_some(suspended.session)
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -68,122 +398,233 @@ struct WordInfo {
     word: String,
     start_ms: u32,
     end_ms: u32,
+    /// Unless the sentence's phonemes don't split into its words
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phonemes: Option<String>,
+}
+
+/// Credentials in the query string, `?token=...&resume=...`, for browser clients that
+/// can't easily send an `auth` message before anything else
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthQuery {
+    token: Option<String>,
+    resume: Option<String>,
 }
 
 /// WebSocket upgrade handler
-pub async fn ws_live_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
-    ws.on_upgrade(move |socket| handle_connection(socket, state))
+pub async fn ws_live_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(auth): Query<AuthQuery>,
+) -> Response {
+    ws.on_upgrade(move |socket| {
+        handle_connection(socket, state, auth).instrument(tracing::Span::current())
+    })
 }
 
-async fn handle_connection(mut socket: WebSocket, state: AppState) {
+async fn handle_connection(mut socket: WebSocket, state: AppState, auth: AuthQuery) {
     tracing::info!("New WebSocket connection for live TTS");
 
-    // First message must be auth
-    let username = match wait_for_auth(&mut socket, &state).await {
-        Ok(username) => username,
+    // First message must be auth, unless the token came in the query string
+    let (username, resume) = match wait_for_auth(&mut socket, &state, auth).await {
+        Ok(auth) => auth,
         Err(e) => {
             let _ = send_message(&mut socket, &ServerMessage::AuthError { message: e }).await;
             return;
         }
     };
 
-    tracing::info!(username = %username, "WebSocket authenticated");
+    // A reconnecting client carries on with its session, if it hasn't expired
+    let resumed = resume.and_then(|token| {
+        let session = state.suspended_sessions.resume(&token, &username)?;
+        Some((token, session))
+    });
+    let (session_token, mut session, resumed) = match resumed {
+        Some((token, session)) => (token, session, true),
+        None => (uuid::Uuid::new_v4().to_string(), Session::default(), false),
+    };
+
+    tracing::info!(user = %username, resumed, "WebSocket authenticated");
     if send_message(
         &mut socket,
         &ServerMessage::AuthOk {
             username: username.clone(),
+            session_token: Some(session_token.clone()),
+            resumed,
         },
     )
     .await
     .is_err()
     {
+        state
+            .suspended_sessions
+            .suspend(session_token, &username, session);
         return;
     }
 
-    // Create stop signal channel
-    let (stop_tx, _stop_rx) = watch::channel(false);
+    // Messages that arrived while audio was streaming, handled once it is done, and the
+    // session's flow control
+    let mut controls = Controls::new(state.ws_keepalive);
+    controls.flow.set_window(session.window);
 
-    // Sentence counter persists across append messages
-    let mut sentence_counter: u32 = 0;
-    // Buffer for incomplete text (text that hasn't ended with sentence-ending punctuation)
-    let mut pending_text = String::new();
+    // Finish the batch the connection was lost in the middle of
+    if let Some(batch) = &session.batch {
+        let request_id = batch.request_id.clone();
+        let result =
+            continue_batch(&mut socket, &state, &username, &mut session, &mut controls).await;
+        report_result(&mut socket, &mut session, result, request_id).await;
+    }
 
     // Main message loop
     loop {
-        let msg = match socket.recv().await {
-            Some(Ok(msg)) => msg,
-            Some(Err(e)) => {
-                tracing::error!("WebSocket receive error: {}", e);
-                break;
-            }
-            None => {
-                tracing::info!("WebSocket closed");
-                break;
-            }
+        let msg = match controls.deferred.pop_front() {
+            Some(text) => Message::Text(text),
+            None => match controls.recv(&mut socket, true).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => {
+                    tracing::info!("WebSocket closed");
+                    break;
+                }
+                Err(e) => {
+                    tracing::info!(reason = %e, "Closing WebSocket");
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::AWAY,
+                            reason: e.into(),
+                        })))
+                        .await;
+                    break;
+                }
+            },
         };
 
         match msg {
             Message::Text(text) => {
                 match serde_json::from_str::<ClientMessage>(&text) {
-                    Ok(ClientMessage::Synthesize { text, voice, speed }) => {
+                    Ok(ClientMessage::Synthesize {
+                        text,
+                        voice,
+                        speed,
+                        lang,
+                        quality,
+                        model_version,
+                        codec,
+                        framing,
+                        request_id,
+                        window,
+                    }) => {
                         // Full synthesize: reset state and process entire text
-                        sentence_counter = 0;
-                        pending_text.clear();
-                        let stop_rx = stop_tx.subscribe();
-                        if let Err(e) =
-                            handle_synthesize(&mut socket, &state, &text, &voice, speed, stop_rx, &mut sentence_counter)
-                                .await
-                        {
-                            let _ = send_message(&mut socket, &ServerMessage::Error { message: e })
-                                .await;
+                        session.sentence_counter = 0;
+                        session.pending_text.clear();
+                        if let Err(e) = session.settings.update(voice, speed, lang) {
+                            let _ = send_message(
+                                &mut socket,
+                                &ServerMessage::Error {
+                                    message: e,
+                                    request_id,
+                                },
+                            )
+                            .await;
+                            continue;
+                        }
+                        if quality.is_some() {
+                            session.settings.quality = quality;
                         }
+                        if model_version.is_some() {
+                            session.settings.model_version = model_version;
+                        }
+                        controls.flow.set_window(window);
+                        let result = handle_synthesize(
+                            &mut socket,
+                            &state,
+                            &username,
+                            &text,
+                            &mut session,
+                            codec,
+                            framing,
+                            request_id.clone(),
+                            &mut controls,
+                        )
+                        .await;
+                        report_result(&mut socket, &mut session, result, request_id).await;
                     }
-                    Ok(ClientMessage::SynthesizeAppend { text, voice, speed }) => {
+                    Ok(ClientMessage::SynthesizeAppend {
+                        text,
+                        voice,
+                        speed,
+                        lang,
+                        quality,
+                        model_version,
+                        codec,
+                        framing,
+                        request_id,
+                        window,
+                    }) => {
+                        if let Err(e) = session.settings.update(voice, speed, lang) {
+                            let _ = send_message(
+                                &mut socket,
+                                &ServerMessage::Error {
+                                    message: e,
+                                    request_id,
+                                },
+                            )
+                            .await;
+                            continue;
+                        }
+                        if quality.is_some() {
+                            session.settings.quality = quality;
+                        }
+                        if model_version.is_some() {
+                            session.settings.model_version = model_version;
+                        }
+                        controls.flow.set_window(window);
                         // Append new text to pending buffer
-                        pending_text.push_str(&text);
+                        session.pending_text.push_str(&text);
 
-                        let to_speak = pending_text.trim().to_string();
+                        let to_speak = session.pending_text.trim().to_string();
                         if to_speak.is_empty() {
                             continue;
                         }
 
                         // The frontend debounces (waits for typing pause) before sending,
                         // so we synthesize everything we have. Clear the buffer.
-                        pending_text.clear();
+                        session.pending_text.clear();
 
-                        let stop_rx = stop_tx.subscribe();
-                        if let Err(e) = handle_synthesize(
+                        let result = handle_synthesize(
                             &mut socket,
                             &state,
+                            &username,
                             &to_speak,
-                            &voice,
-                            speed,
-                            stop_rx,
-                            &mut sentence_counter,
+                            &mut session,
+                            codec,
+                            framing,
+                            request_id.clone(),
+                            &mut controls,
                         )
-                        .await
-                        {
-                            let _ = send_message(
-                                &mut socket,
-                                &ServerMessage::Error { message: e },
-                            )
-                            .await;
-                        }
+                        .await;
+                        report_result(&mut socket, &mut session, result, request_id).await;
+                    }
+                    Ok(ClientMessage::Ack { frames }) => {
+                        // For audio sent before it finished
+                        controls.flow.ack(frames);
                     }
                     Ok(ClientMessage::Stop) => {
-                        let _ = stop_tx.send(true);
-                        pending_text.clear();
+                        // Streaming audio is stopped by `Controls`; this only drops pending text
+                        session.pending_text.clear();
                         let _ = send_message(&mut socket, &ServerMessage::Stopped).await;
                     }
-                    Ok(ClientMessage::Auth { .. }) => {
-                        // Already authenticated, ignore
+                    Ok(
+                        ClientMessage::Auth { .. } | ClientMessage::Pause | ClientMessage::Resume,
+                    ) => {
+                        // Already authenticated, or nothing streaming to pause; ignore
                     }
                     Err(e) => {
-                        tracing::warn!("Invalid message: {}", e);
+                        tracing::warn!(error = %e, "Invalid message");
                         let _ = send_message(
                             &mut socket,
                             &ServerMessage::Error {
                                 message: format!("Invalid message: {}", e),
+                                request_id: None,
                             },
                         )
                         .await;
@@ -200,9 +641,274 @@ async fn handle_connection(mut socket: WebSocket, state: AppState) {
             _ => {}
         }
     }
+
+    session.window = controls.flow.window;
+    state
+        .suspended_sessions
+        .suspend(session_token, &username, session);
+}
+
+/// Tell the client a batch failed. The batch is kept for a resumed session only if the
+/// connection was lost before it finished, not if it failed in a way the client was told.
+async fn report_result(
+    socket: &mut WebSocket,
+    session: &mut Session,
+    result: Result<(), String>,
+    request_id: Option<String>,
+) {
+    let Err(e) = result else {
+        return;
+    };
+    if send_message(
+        socket,
+        &ServerMessage::Error {
+            message: e,
+            request_id,
+        },
+    )
+    .await
+    .is_ok()
+    {
+        session.batch = None;
+    }
+}
+
+/// WebSocket upgrade handler for following an in-progress job
+pub async fn job_stream_handler(
+    ws: WebSocketUpgrade,
+    Path(job_id): Path<String>,
+    State(state): State<AppState>,
+    Query(auth): Query<AuthQuery>,
+) -> Response {
+    ws.on_upgrade(move |socket| {
+        handle_job_stream(socket, state, job_id, auth).instrument(tracing::Span::current())
+    })
+}
+
+/// Stream a job's audio in the same message format as `/ws/live`: each chunk the batch
+/// pipeline synthesizes from when the client joins, or the stored output of a job that
+/// has already finished.
+async fn handle_job_stream(
+    mut socket: WebSocket,
+    state: AppState,
+    job_id: String,
+    auth: AuthQuery,
+) {
+    let username = match wait_for_auth(&mut socket, &state, auth).await {
+        Ok((username, _)) => username,
+        Err(e) => {
+            let _ = send_message(&mut socket, &ServerMessage::AuthError { message: e }).await;
+            return;
+        }
+    };
+
+    if send_message(
+        &mut socket,
+        &ServerMessage::AuthOk {
+            username: username.clone(),
+            session_token: None,
+            resumed: false,
+        },
+    )
+    .await
+    .is_err()
+    {
+        return;
+    }
+
+    let result = match uuid::Uuid::parse_str(&job_id) {
+        Ok(job_id) => {
+            let chunks = state
+                .active_jobs
+                .read()
+                .await
+                .get(&job_id)
+                .filter(|job| job.username == username)
+                .map(|job| job.chunks.subscribe());
+            match chunks {
+                Some(chunks) => {
+                    tracing::info!(job_id = %job_id, user = %username, "Streaming job audio");
+                    stream_job_chunks(&mut socket, &state, job_id, chunks).await
+                }
+                None => send_job_output(&mut socket, &state, job_id, &username).await,
+            }
+        }
+        Err(_) => Err("Job not found".to_string()),
+    };
+    if let Err(e) = result {
+        tracing::warn!(job_id = %job_id, error = %e, "Job stream ended with error");
+        let _ = send_message(
+            &mut socket,
+            &ServerMessage::Error {
+                message: e,
+                request_id: None,
+            },
+        )
+        .await;
+    }
+
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+/// Relay each chunk a processing job publishes until it finishes. The chunks are the
+/// batch pipeline's own audio, before it is joined and encoded, with the pause after each.
+async fn stream_job_chunks(
+    socket: &mut WebSocket,
+    state: &AppState,
+    job_id: uuid::Uuid,
+    mut chunks: tokio::sync::broadcast::Receiver<crate::state::JobChunk>,
+) -> Result<(), String> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    // Nothing but pause, resume and stop is expected here
+    let mut controls = Controls::new(state.ws_keepalive);
+    let mut encoder = FrameEncoder::new(Codec::default(), Framing::default(), SAMPLE_RATE)?;
+    let mut last_index = 0;
+    loop {
+        let chunk = match controls.during(socket, chunks.recv()).await? {
+            None => return Ok(()),
+            Some(Ok(chunk)) => chunk,
+            Some(Err(RecvError::Lagged(missed))) => {
+                return Err(format!("Fell {} chunks behind the job", missed));
+            }
+            Some(Err(RecvError::Closed)) => break,
+        };
+        last_index = chunk.index;
+
+        let words = estimate_word_timings(&chunk.text, "", chunk.audio.len(), SAMPLE_RATE)
+            .into_iter()
+            .map(|(word, start_ms, end_ms)| WordInfo {
+                word,
+                start_ms,
+                end_ms,
+                phonemes: None,
+            })
+            .collect();
+        send_message(
+            socket,
+            &ServerMessage::WordTiming {
+                sentence_index: chunk.index,
+                text: chunk.text.clone(),
+                phonemes: String::new(),
+                words,
+                request_id: None,
+            },
+        )
+        .await?;
+
+        let silence = vec![0.0; (SAMPLE_RATE as u64 * chunk.pause_ms as u64 / 1000) as usize];
+        let chunk_samples = (SAMPLE_RATE as usize / 10).max(1);
+        for samples in chunk
+            .audio
+            .chunks(chunk_samples)
+            .chain(silence.chunks(chunk_samples))
+        {
+            if let Flow::Stop = controls.pace(socket).await? {
+                return Ok(());
+            }
+            for frame in encoder.encode(chunk.index, samples)? {
+                if let Flow::Stop = controls.send_audio(socket, frame).await? {
+                    return Ok(());
+                }
+            }
+        }
+        send_message(
+            socket,
+            &ServerMessage::SentenceDone {
+                sentence_index: chunk.index,
+                request_id: None,
+            },
+        )
+        .await?;
+    }
+
+    // The job is no longer processing, having finished or failed
+    let row = sqlx::query("SELECT status, error_message FROM jobs WHERE id = $1")
+        .bind(job_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| {
+            tracing::error!(job_id = %job_id, error = %e, "Database error while fetching job");
+            "Failed to look up the job".to_string()
+        })?;
+    match row {
+        Some(row) if row.get::<String, _>("status") == "completed" => {}
+        Some(row) => {
+            let error: Option<String> = row.get("error_message");
+            return Err(error.unwrap_or_else(|| "Job stopped before finishing".to_string()));
+        }
+        None => return Err("Job not found".to_string()),
+    }
+    for frame in encoder.finish(last_index)? {
+        if let Flow::Stop = controls.send_audio(socket, frame).await? {
+            return Ok(());
+        }
+    }
+    send_message(socket, &ServerMessage::Done { request_id: None }).await
+}
+
+/// Bytes of a finished job's output per binary message
+const OUTPUT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Send the stored output of a job that has finished, as an `output` message followed by
+/// the file in binary messages and then `done`
+async fn send_job_output(
+    socket: &mut WebSocket,
+    state: &AppState,
+    job_id: uuid::Uuid,
+    username: &str,
+) -> Result<(), String> {
+    let row = sqlx::query(
+        "SELECT status, file_path, format, content_type FROM jobs WHERE id = $1 AND username = $2",
+    )
+    .bind(job_id)
+    .bind(username)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(job_id = %job_id, error = %e, "Database error while fetching job");
+        "Failed to look up the job".to_string()
+    })?
+    .ok_or("Job not found")?;
+    let path = match (
+        row.get::<String, _>("status").as_str(),
+        row.get::<Option<String>, _>("file_path"),
+    ) {
+        ("completed", Some(path)) => path,
+        _ => return Err("Job is not processing or completed".to_string()),
+    };
+    let output = tokio::fs::read(&path).await.map_err(|e| {
+        tracing::error!(job_id = %job_id, path = %path, error = %e, "Failed to read job output");
+        "Failed to read the job's output".to_string()
+    })?;
+
+    tracing::info!(job_id = %job_id, user = %username, "Sending finished job output");
+    send_message(
+        socket,
+        &ServerMessage::Output {
+            format: row.get("format"),
+            content_type: row.get("content_type"),
+            size: output.len() as u64,
+        },
+    )
+    .await?;
+    for part in output.chunks(OUTPUT_CHUNK_BYTES) {
+        send_audio(socket, part.to_vec()).await?;
+    }
+    send_message(socket, &ServerMessage::Done { request_id: None }).await
 }
 
-async fn wait_for_auth(socket: &mut WebSocket, state: &AppState) -> Result<String, String> {
+/// The authenticated user, and the session token they asked to resume, from the query
+/// string or else the first message
+async fn wait_for_auth(
+    socket: &mut WebSocket,
+    state: &AppState,
+    query: AuthQuery,
+) -> Result<(String, Option<String>), String> {
+    if let Some(token) = query.token {
+        return Ok((authenticate(state, &token).await?, query.resume));
+    }
+
     // Wait for auth message with timeout
     let timeout = tokio::time::Duration::from_secs(10);
 
@@ -221,85 +927,220 @@ async fn wait_for_auth(socket: &mut WebSocket, state: &AppState) -> Result<Strin
         serde_json::from_str(&text).map_err(|e| format!("Invalid auth message: {}", e))?;
 
     match client_msg {
-        ClientMessage::Auth { token } => {
-            // Strip "Bearer " prefix if present
-            let token = token.strip_prefix("Bearer ").unwrap_or(&token);
-            validate_token_public(state, token).await
-        }
+        ClientMessage::Auth { token, resume } => Ok((authenticate(state, &token).await?, resume)),
         _ => Err("First message must be auth".to_string()),
     }
 }
 
+async fn authenticate(state: &AppState, token: &str) -> Result<String, String> {
+    // Strip "Bearer " prefix if present
+    let token = token.strip_prefix("Bearer ").unwrap_or(token);
+    let username = validate_token_public(state, token).await?;
+    crate::logging::record_user(&username);
+    Ok(username)
+}
+
+/// Synthesize and stream `text` as the session's next batch
+#[allow(clippy::too_many_arguments)]
 async fn handle_synthesize(
     socket: &mut WebSocket,
     state: &AppState,
+    username: &str,
     text: &str,
-    voice: &str,
-    speed: f32,
-    stop_rx: watch::Receiver<bool>,
-    sentence_counter: &mut u32,
+    session: &mut Session,
+    codec: Codec,
+    framing: Framing,
+    request_id: Option<String>,
+    controls: &mut Controls,
 ) -> Result<(), String> {
-    let model = state.kokoro_model.as_ref().ok_or("TTS model not loaded")?;
+    let settings = &session.settings;
+    let custom_voice =
+        crate::custom_voices::CustomVoice::load(&state.pool, username, &settings.voice)
+            .await
+            .map_err(|e| format!("Failed to load custom voice: {}", e))?;
+    // With `auto`, each sentence is phonemized in the language it's written in
+    let detect_lang = crate::voices::is_auto(settings.lang.as_deref());
+    // A custom voice speaks its own language unless the session sets one
+    let lang = crate::voices::resolve_lang(
+        settings
+            .lang
+            .as_deref()
+            .filter(|_| !detect_lang)
+            .or(custom_voice.as_ref().map(|custom| custom.lang.as_str())),
+        &settings.voice,
+    )?;
+    crate::prosody::check_speed(settings.speed)?;
+    crate::usage::check_cap(state, username)
+        .await
+        .map_err(|e| e.detail)?;
 
-    // Split text into sentences
-    let sentences = split_sentences(text);
+    let sentences = live_sentences(text, lang);
+    let first_index = session.sentence_counter;
+    session.sentence_counter += sentences.len() as u32;
+    session.batch = Some(Batch {
+        sentences: sentences.into(),
+        next_index: first_index,
+        lang,
+        detect_lang,
+        codec,
+        framing,
+        request_id,
+        uncharged_characters: text.chars().count() as i64,
+    });
+    continue_batch(socket, state, username, session, controls).await
+}
 
-    for sentence in sentences.iter() {
-        let sentence_idx = *sentence_counter;
-        *sentence_counter += 1;
+/// Stream what is left of the session's batch, counting the audio produced against the
+/// user's monthly usage. The batch is done with once it is all sent or the client stops it.
+async fn continue_batch(
+    socket: &mut WebSocket,
+    state: &AppState,
+    username: &str,
+    session: &mut Session,
+    controls: &mut Controls,
+) -> Result<(), String> {
+    let Some(batch) = &mut session.batch else {
+        return Ok(());
+    };
+    let lexicon = crate::lexicon::Lexicon::load(&state.pool, username)
+        .await
+        .map_err(|e| format!("Failed to load lexicon: {}", e))?;
+    let custom_voice =
+        crate::custom_voices::CustomVoice::load(&state.pool, username, &session.settings.voice)
+            .await
+            .map_err(|e| format!("Failed to load custom voice: {}", e))?;
 
-        // Check for stop signal
-        if *stop_rx.borrow() {
-            return Ok(());
-        }
+    let mut synthesized_samples = 0;
+    let result = stream_sentences(
+        socket,
+        state,
+        username,
+        &session.settings,
+        custom_voice,
+        batch,
+        Arc::new(lexicon),
+        controls,
+        &mut synthesized_samples,
+    )
+    .await;
+    crate::usage::record(
+        &state.pool,
+        username,
+        synthesized_samples as f64 / SAMPLE_RATE as f64,
+    )
+    .await;
+    if result.is_ok() {
+        session.batch = None;
+    }
+    result
+}
 
-        // Phonemize the sentence (blocking operation)
-        let phonemes = {
-            let sentence = sentence.clone();
-            tokio::task::spawn_blocking(move || phonemize(&sentence, "en"))
-                .await
-                .map_err(|e| format!("Phonemize task failed: {}", e))?
-                .map_err(|e| format!("Phonemization failed: {}", e))?
+#[allow(clippy::too_many_arguments)]
+async fn stream_sentences(
+    socket: &mut WebSocket,
+    state: &AppState,
+    username: &str,
+    settings: &VoiceSettings,
+    custom_voice: Option<crate::custom_voices::CustomVoice>,
+    batch: &mut Batch,
+    lexicon: Arc<crate::lexicon::Lexicon>,
+    controls: &mut Controls,
+    synthesized_samples: &mut usize,
+) -> Result<(), String> {
+    let engine = crate::engine::live_engine(state, settings.model_version.as_deref(), custom_voice)
+        .await
+        .map_err(|e| e.detail)?;
+    if batch.uncharged_characters > 0 {
+        crate::usage::charge_characters(state, username, batch.uncharged_characters)
+            .await
+            .map_err(|e| e.detail)?;
+        batch.uncharged_characters = 0;
+    }
+
+    let mut encoder = FrameEncoder::new(batch.codec, batch.framing, SAMPLE_RATE)?;
+    controls.paused = false;
+    let request_id = batch.request_id.clone();
+    let request_id = request_id.as_deref();
+    let last_index = (batch.next_index + batch.sentences.len() as u32).saturating_sub(1);
+    let mut joiner =
+        crate::join::SentenceJoiner::new(SAMPLE_RATE, state.crossfade_ms, state.sentence_gap_ms);
+    while let Some((sentence, pause_ms)) = batch.sentences.front().cloned() {
+        let sentence_idx = batch.next_index;
+        let is_last = batch.sentences.len() == 1;
+        let lang = if batch.detect_lang {
+            crate::voices::sentence_lang(&sentence, batch.lang)
+        } else {
+            batch.lang
         };
 
-        if phonemes.is_empty() {
+        // The client can stop the audio while the sentence is synthesized, which abandons
+        // the inference rather than waiting for it
+        let synthesis = synthesize_sentence(
+            state,
+            &engine,
+            &sentence,
+            &settings.voice,
+            lang,
+            settings.speed,
+            settings.quality,
+            &lexicon,
+        );
+        let Some(result) = controls.during(socket, synthesis).await? else {
+            return Ok(());
+        };
+        let (
+            phonemes,
+            Synthesis {
+                audio,
+                phoneme_spans,
+            },
+        ) = result?;
+        if audio.is_empty() && !is_last {
+            batch.advance();
             continue;
         }
+        *synthesized_samples += audio.len();
+        let sentence_samples = audio.len();
 
-        // Run synthesis in blocking task
-        let model_clone = Arc::clone(model);
-        let phonemes_clone = phonemes.clone();
-        let voice_clone = voice.to_string();
-
-        let audio = tokio::task::spawn_blocking(move || {
-            model_clone.synthesize(&phonemes_clone, &voice_clone, speed)
-        })
-        .await
-        .map_err(|e| format!("Synthesis task failed: {}", e))?
-        .map_err(|e| format!("Synthesis failed: {}", e))?;
-
+        // Pauses and gaps are streamed as silence at the end of the sentence before them.
+        // The audio may start with the end of the previous sentence, crossfaded into this
+        // one, and its own end may be held back for the next.
+        let (audio, start) = joiner.push(audio, pause_ms, is_last);
         if audio.is_empty() {
+            batch.advance();
             continue;
         }
 
-        // Estimate word timings
-        let word_timings = estimate_word_timings(sentence, &phonemes, audio.len(), SAMPLE_RATE);
-
-        // Send word timing info
-        let words: Vec<WordInfo> = word_timings
-            .into_iter()
-            .map(|(word, start_ms, end_ms)| WordInfo {
-                word,
-                start_ms,
-                end_ms,
-            })
-            .collect();
+        // Word timings from the model's timing of each phoneme where it gives one, or else
+        // estimated, from where the sentence starts
+        let start_ms = (start as u64 * 1000 / SAMPLE_RATE as u64) as u32;
+        let words: Vec<WordInfo> = if sentence_samples == 0 {
+            Vec::new()
+        } else {
+            let mut pronunciations = word_phonemes(&sentence, &phonemes).into_iter().flatten();
+            phoneme_spans
+                .and_then(|spans| aligned_word_timings(&sentence, &phonemes, &spans, SAMPLE_RATE))
+                .unwrap_or_else(|| {
+                    estimate_word_timings(&sentence, &phonemes, sentence_samples, SAMPLE_RATE)
+                })
+                .into_iter()
+                .map(|(word, word_start_ms, word_end_ms)| WordInfo {
+                    word,
+                    start_ms: start_ms + word_start_ms,
+                    end_ms: start_ms + word_end_ms,
+                    phonemes: pronunciations.next(),
+                })
+                .collect()
+        };
 
         send_message(
             socket,
             &ServerMessage::WordTiming {
                 sentence_index: sentence_idx,
+                text: sentence.clone(),
+                phonemes,
                 words,
+                request_id: request_id.map(str::to_string),
             },
         )
         .await?;
@@ -308,25 +1149,17 @@ async fn handle_synthesize(
         let chunk_samples = (SAMPLE_RATE as usize / 10).max(1); // 100ms = 2400 samples at 24kHz
 
         for chunk in audio.chunks(chunk_samples) {
-            // Check for stop signal
-            if *stop_rx.borrow() {
+            // The client can pause or stop the audio between chunks
+            if let Flow::Stop = controls.pace(socket).await? {
                 return Ok(());
             }
 
-            // Binary format: [4 bytes sentence_index u32 LE][PCM f32le samples]
-            let mut data = Vec::with_capacity(4 + chunk.len() * 4);
-            data.extend_from_slice(&(sentence_idx as u32).to_le_bytes());
-            for sample in chunk {
-                data.extend_from_slice(&sample.to_le_bytes());
+            // Binary format: [header, see `framing`][audio in the client's codec]
+            for frame in encoder.encode(sentence_idx, chunk)? {
+                if let Flow::Stop = controls.send_audio(socket, frame).await? {
+                    return Ok(());
+                }
             }
-
-            socket
-                .send(Message::Binary(data.into()))
-                .await
-                .map_err(|e| format!("Failed to send audio: {}", e))?;
-
-            // Small delay to avoid overwhelming the client
-            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
         }
 
         // Sentence complete
@@ -334,23 +1167,509 @@ async fn handle_synthesize(
             socket,
             &ServerMessage::SentenceDone {
                 sentence_index: sentence_idx,
+                request_id: request_id.map(str::to_string),
             },
         )
         .await?;
+        batch.advance();
     }
 
     // All done for this batch
-    send_message(socket, &ServerMessage::Done).await?;
+    for frame in encoder.finish(last_index)? {
+        if let Flow::Stop = controls.send_audio(socket, frame).await? {
+            return Ok(());
+        }
+    }
+    send_message(
+        socket,
+        &ServerMessage::Done {
+            request_id: request_id.map(str::to_string),
+        },
+    )
+    .await?;
 
     Ok(())
 }
 
+/// Messages held while audio streams, beyond which more are refused
+const MAX_QUEUED_MESSAGES: usize = 32;
+
+/// Whether to go on streaming after a message from the client
+enum Flow {
+    Continue,
+    Stop,
+}
+
+/// The client's messages while audio streams: `pause` holds the audio until `resume`,
+/// however long that takes, `stop` ends it along with anything queued and `ack` makes
+/// room for more frames. Other messages are queued in `deferred`, to be handled in order
+/// once the audio is done.
+#[derive(Debug)]
+struct Controls {
+    deferred: VecDeque<String>,
+    /// Only while a batch of audio streams
+    paused: bool,
+    flow: FlowControl,
+    keepalive: Keepalive,
+    pinged_at: Instant,
+    /// Whether the last ping is yet to be answered
+    awaiting_pong: bool,
+}
+
+/// What ended a wait for the client in [`Controls::recv`]
+enum Wake {
+    Received(Option<Result<Message, axum::Error>>),
+    Ping,
+    Idle,
+}
+
+impl Controls {
+    fn new(keepalive: Keepalive) -> Self {
+        Self {
+            deferred: VecDeque::new(),
+            paused: false,
+            flow: FlowControl::default(),
+            keepalive,
+            pinged_at: Instant::now(),
+            awaiting_pong: false,
+        }
+    }
+
+    /// The client's next message, `None` once it has closed the connection. It is pinged
+    /// meanwhile, and an error returned if it misses a pong or, when `idle`, sends nothing
+    /// for the idle timeout.
+    async fn recv(
+        &mut self,
+        socket: &mut WebSocket,
+        idle: bool,
+    ) -> Result<Option<Message>, String> {
+        let idle_at = self
+            .keepalive
+            .idle_timeout
+            .filter(|_| idle)
+            .map(|timeout| Instant::now() + timeout);
+        loop {
+            let ping_at = self.pinged_at + self.keepalive.ping_interval;
+            let wake = tokio::select! {
+                msg = socket.recv() => Wake::Received(msg),
+                _ = tokio::time::sleep_until(ping_at) => Wake::Ping,
+                _ = tokio::time::sleep_until(idle_at.unwrap_or(ping_at)), if idle_at.is_some() => {
+                    Wake::Idle
+                }
+            };
+            match wake {
+                Wake::Received(Some(Ok(Message::Pong(_)))) => self.awaiting_pong = false,
+                Wake::Received(Some(Ok(msg))) => return Ok(Some(msg)),
+                Wake::Received(Some(Err(e))) => return Err(format!("Receive error: {}", e)),
+                Wake::Received(None) => return Ok(None),
+                Wake::Ping if self.awaiting_pong => {
+                    return Err("No answer to ping".to_string());
+                }
+                Wake::Ping => {
+                    self.pinged_at = Instant::now();
+                    self.awaiting_pong = true;
+                    socket
+                        .send(Message::Ping(Vec::new()))
+                        .await
+                        .map_err(|e| format!("Failed to send ping: {}", e))?;
+                }
+                Wake::Idle => return Err("Idle timeout".to_string()),
+            }
+        }
+    }
+
+    /// Wait before an audio chunk, for as long as the audio is paused. Without flow
+    /// control, also wait 10 ms, a small delay to avoid overwhelming the client.
+    async fn pace(&mut self, socket: &mut WebSocket) -> Result<Flow, String> {
+        let delay = match self.flow.window {
+            Some(_) => tokio::time::Duration::ZERO,
+            None => tokio::time::Duration::from_millis(10),
+        };
+        self.wait(socket, delay).await
+    }
+
+    /// Send an audio frame once the client has room for it
+    async fn send_audio(&mut self, socket: &mut WebSocket, frame: Vec<u8>) -> Result<Flow, String> {
+        if let Flow::Stop = self.wait(socket, tokio::time::Duration::ZERO).await? {
+            return Ok(Flow::Stop);
+        }
+        send_audio(socket, frame).await?;
+        self.flow.sent();
+        Ok(Flow::Continue)
+    }
+
+    /// Handle messages for at least `delay`, and until the audio isn't paused and there
+    /// is room for a frame
+    async fn wait(
+        &mut self,
+        socket: &mut WebSocket,
+        delay: tokio::time::Duration,
+    ) -> Result<Flow, String> {
+        let mut delay = std::pin::pin!(tokio::time::sleep(delay));
+        loop {
+            let ready = !self.paused && self.flow.has_room();
+            let msg = tokio::select! {
+                biased;
+                msg = self.recv(socket, false) => msg?,
+                _ = &mut delay, if ready => return Ok(Flow::Continue),
+            };
+            if let Flow::Stop = self.handle(socket, msg).await? {
+                return Ok(Flow::Stop);
+            }
+        }
+    }
+
+    /// Run `work` while handling messages, or give it up, dropping it, if the client
+    /// stops the audio first
+    async fn during<T>(
+        &mut self,
+        socket: &mut WebSocket,
+        work: impl Future<Output = T>,
+    ) -> Result<Option<T>, String> {
+        let mut work = std::pin::pin!(work);
+        loop {
+            // A waiting stop wins over work that is done too
+            let msg = tokio::select! {
+                biased;
+                msg = self.recv(socket, false) => msg?,
+                output = &mut work => return Ok(Some(output)),
+            };
+            if let Flow::Stop = self.handle(socket, msg).await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    async fn handle(
+        &mut self,
+        socket: &mut WebSocket,
+        msg: Option<Message>,
+    ) -> Result<Flow, String> {
+        let text = match msg {
+            Some(Message::Text(text)) => text,
+            Some(Message::Close(_)) | None => return Err("Connection closed".to_string()),
+            Some(_) => return Ok(Flow::Continue),
+        };
+        match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(ClientMessage::Pause) if !self.paused => {
+                self.paused = true;
+                send_message(socket, &ServerMessage::Paused).await?;
+            }
+            Ok(ClientMessage::Resume) if self.paused => {
+                self.paused = false;
+                send_message(socket, &ServerMessage::Resumed).await?;
+            }
+            Ok(ClientMessage::Pause | ClientMessage::Resume) => {}
+            Ok(ClientMessage::Ack { frames }) => self.flow.ack(frames),
+            Ok(ClientMessage::Stop) => {
+                self.deferred.clear();
+                send_message(socket, &ServerMessage::Stopped).await?;
+                return Ok(Flow::Stop);
+            }
+            _ if self.deferred.len() >= MAX_QUEUED_MESSAGES => {
+                send_message(
+                    socket,
+                    &ServerMessage::Error {
+                        message: "Too many queued messages".to_string(),
+                        request_id: None,
+                    },
+                )
+                .await?;
+            }
+            _ => self.deferred.push_back(text),
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Each sentence of `text` with the silence after it, in milliseconds. Numbers and
+/// abbreviations are spelled out first, so "Dr." or "$5.99" don't end a sentence.
+pub fn live_sentences(text: &str, lang: &str) -> Vec<(String, u32)> {
+    let mut sentences: Vec<(String, u32)> = Vec::new();
+    for piece in crate::pauses::split_pauses(text) {
+        match piece {
+            crate::pauses::Piece::Text(text) => sentences.extend(
+                split_sentences(&crate::normalize::normalize_lang(&text, lang))
+                    .into_iter()
+                    .map(|sentence| (sentence, 0)),
+            ),
+            crate::pauses::Piece::Pause(ms) => {
+                if let Some(last) = sentences.last_mut() {
+                    last.1 += ms;
+                }
+            }
+        }
+    }
+    sentences
+}
+
+/// Phonemize and synthesize one sentence with `engine` on blocking threads, returning its
+/// phonemes and audio. Both are empty for a sentence with nothing to say.
+#[allow(clippy::too_many_arguments)]
+pub async fn synthesize_sentence(
+    state: &AppState,
+    engine: &Arc<dyn crate::engine::SynthesisEngine>,
+    sentence: &str,
+    voice: &str,
+    lang: &'static str,
+    speed: f32,
+    quality: Option<Quality>,
+    lexicon: &Arc<crate::lexicon::Lexicon>,
+) -> Result<(String, Synthesis), String> {
+    let phonemes = {
+        let sentence = sentence.to_string();
+        let phonemizer = Arc::clone(&state.phonemizer);
+        let lexicon = Arc::clone(lexicon);
+        tokio::task::spawn_blocking(move || {
+            phonemizer.phonemize_with_lexicon(&sentence, lang, &lexicon)
+        })
+        .await
+        .map_err(|e| format!("Phonemize task failed: {}", e))?
+        .map_err(|e| format!("Phonemization failed: {}", e))?
+    };
+    if phonemes.is_empty() {
+        return Ok((
+            phonemes,
+            Synthesis {
+                audio: Vec::new(),
+                phoneme_spans: None,
+            },
+        ));
+    }
+
+    let engine = Arc::clone(engine);
+    let phonemes_clone = phonemes.clone();
+    // Engines that only take text get just the respellings of the lexicon
+    let text = lexicon.respell(sentence);
+    let voice = voice.to_string();
+    let cancel = crate::inference::Cancel::new()?;
+    // If this future is dropped, e.g. when the client stops the audio, the inference is
+    // aborted instead of holding the model until it finishes
+    let _abort = CancelOnDrop(cancel.clone());
+    let synthesis = tokio::task::spawn_blocking(move || {
+        engine.synthesize(&crate::engine::Utterance {
+            text: &text,
+            phonemes: &phonemes_clone,
+            voice: &voice,
+            lang,
+            speed,
+            quality,
+            cancel: Some(&cancel),
+        })
+    })
+    .await
+    .map_err(|e| format!("Synthesis task failed: {}", e))?
+    .map_err(|e| e.message().to_string())?;
+    Ok((phonemes, synthesis))
+}
+
+struct CancelOnDrop(crate::inference::Cancel);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+async fn send_audio(socket: &mut WebSocket, frame: Vec<u8>) -> Result<(), String> {
+    socket
+        .send(Message::Binary(frame))
+        .await
+        .map_err(|e| format!("Failed to send audio: {}", e))
+}
+
 async fn send_message(socket: &mut WebSocket, msg: &ServerMessage) -> Result<(), String> {
     let json =
         serde_json::to_string(msg).map_err(|e| format!("Failed to serialize message: {}", e))?;
 
     socket
-        .send(Message::Text(json.into()))
+        .send(Message::Text(json))
         .await
         .map_err(|e| format!("Failed to send message: {}", e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_voice_settings() {
+        let mut settings = VoiceSettings::default();
+        settings
+            .update(Some("bm_george".to_string()), None, None)
+            .unwrap();
+        settings
+            .update(None, Some(1.2), Some("en-us".to_string()))
+            .unwrap();
+        assert_eq!(settings.voice, "bm_george");
+        assert_eq!(settings.speed, 1.2);
+        assert_eq!(settings.lang.as_deref(), Some("en-us"));
+
+        // Switching voice keeps the speed but not the other voice's language
+        settings
+            .update(Some("ff_siwis".to_string()), None, None)
+            .unwrap();
+        assert_eq!(settings.speed, 1.2);
+        assert_eq!(settings.lang, None);
+
+        // Invalid settings change nothing
+        let before = settings.clone();
+        assert!(
+            settings
+                .update(Some("af_heart".to_string()), Some(10.0), None)
+                .is_err()
+        );
+        assert!(settings.update(None, None, Some("xx".to_string())).is_err());
+        assert_eq!(settings, before);
+    }
+
+    #[test]
+    fn test_keepalive_parse() {
+        assert_eq!(Keepalive::parse(None, Some(" ")), Ok(Keepalive::default()));
+        assert_eq!(
+            Keepalive::parse(Some("10"), Some("0")),
+            Ok(Keepalive {
+                ping_interval: Duration::from_secs(10),
+                idle_timeout: None,
+            })
+        );
+        assert!(Keepalive::parse(Some("0"), None).is_err());
+        assert!(Keepalive::parse(None, Some("-1")).is_err());
+    }
+
+    #[test]
+    fn test_flow_control() {
+        // Frames aren't counted until a window is set
+        let mut flow = FlowControl::default();
+        flow.sent();
+        assert!(flow.has_room());
+        assert_eq!(flow.unacked, 0);
+
+        let msg: ClientMessage =
+            serde_json::from_str(r#"{"type": "synthesize", "text": "Hi.", "window": 2}"#).unwrap();
+        let ClientMessage::Synthesize { window, .. } = msg else {
+            panic!("expected synthesize");
+        };
+        flow.set_window(window);
+        flow.sent();
+        flow.sent();
+        assert!(!flow.has_room());
+        flow.ack(1);
+        assert!(flow.has_room());
+
+        // Later messages without a window keep it; acks can't go below nothing
+        flow.set_window(None);
+        assert_eq!(flow.window, NonZeroU32::new(2));
+        flow.ack(5);
+        assert_eq!(flow.unacked, 0);
+        assert!(
+            serde_json::from_str::<ClientMessage>(
+                r#"{"type": "synthesize", "text": "Hi.", "window": 0}"#
+            )
+            .is_err()
+        );
+        assert!(matches!(
+            serde_json::from_str(r#"{"type": "ack", "frames": 3}"#),
+            Ok(ClientMessage::Ack { frames: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_suspended_sessions() {
+        let sessions = SuspendedSessions::default();
+        let mut batch = Batch {
+            sentences: VecDeque::from([("One.".to_string(), 0), ("Two.".to_string(), 0)]),
+            next_index: 4,
+            lang: "en-us",
+            detect_lang: true,
+            codec: Codec::Opus,
+            framing: Framing::V1,
+            request_id: Some("r1".to_string()),
+            uncharged_characters: 0,
+        };
+        batch.advance();
+        sessions.suspend(
+            "token".to_string(),
+            "alice",
+            Session {
+                sentence_counter: 6,
+                batch: Some(batch),
+                ..Session::default()
+            },
+        );
+
+        // Only its own user can resume a session, and only once
+        assert!(sessions.resume("token", "bob").is_none());
+        assert!(sessions.resume("other", "alice").is_none());
+        let session = sessions.resume("token", "alice").unwrap();
+        assert_eq!(session.sentence_counter, 6);
+        let batch = session.batch.unwrap();
+        assert_eq!(batch.next_index, 5);
+        assert_eq!(batch.sentences, VecDeque::from([("Two.".to_string(), 0)]));
+        assert!(sessions.resume("token", "alice").is_none());
+
+        let json = serde_json::to_value(ServerMessage::AuthOk {
+            username: "alice".to_string(),
+            session_token: Some("token".to_string()),
+            resumed: true,
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "type": "auth_ok", "username": "alice", "session_token": "token", "resumed": true })
+        );
+    }
+
+    #[test]
+    fn test_auth_query() {
+        let query = |uri: &str| {
+            Query::<AuthQuery>::try_from_uri(&uri.parse().unwrap())
+                .unwrap()
+                .0
+        };
+        let auth = query("/ws/live?token=Bearer%20abc.def&resume=s1");
+        assert_eq!(auth.token.as_deref(), Some("Bearer abc.def"));
+        assert_eq!(auth.resume.as_deref(), Some("s1"));
+        // Without one, the first message authenticates as before
+        assert!(query("/ws/live").token.is_none());
+    }
+
+    #[test]
+    fn test_request_id_echo() {
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"type": "synthesize", "text": "Hi.", "voice": "af_heart", "speed": 1.0, "request_id": "r1"}"#,
+        )
+        .unwrap();
+        let ClientMessage::Synthesize { request_id, .. } = message else {
+            panic!("not a synthesize message: {:?}", message);
+        };
+        assert_eq!(request_id.as_deref(), Some("r1"));
+
+        let json = |message: ServerMessage| serde_json::to_value(message).unwrap();
+        assert_eq!(
+            json(ServerMessage::SentenceDone {
+                sentence_index: 2,
+                request_id,
+            }),
+            serde_json::json!({ "type": "sentence_done", "sentence_index": 2, "request_id": "r1" })
+        );
+        // Without one, responses are as they were
+        assert_eq!(
+            json(ServerMessage::Done { request_id: None }),
+            serde_json::json!({ "type": "done" })
+        );
+    }
+
+    #[test]
+    fn test_output_message() {
+        let message = ServerMessage::Output {
+            format: "mp3".to_string(),
+            content_type: "audio/mpeg".to_string(),
+            size: 1024,
+        };
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            serde_json::json!({ "type": "output", "format": "mp3", "content_type": "audio/mpeg", "size": 1024 })
+        );
+    }
+}
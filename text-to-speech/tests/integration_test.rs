@@ -277,6 +277,12 @@ fn run_all_tests() {
     // Test 8: Speed boundary values
     test_generate_speed_boundaries(&client, &base_url);
 
+    // Test 9: Output formats
+    test_generate_output_formats(&client, &base_url);
+
+    // Test 10: JSON generate endpoint
+    test_generate_json(&client, &base_url);
+
     println!("\n========== All Tests Passed! ==========\n");
 }
 
@@ -317,28 +323,59 @@ fn test_happy_path_generate_and_complete(client: &Client, base_url: &str) {
             .send()
             .expect("Failed to get status");
 
-        let content_type = resp
-            .headers()
-            .get("content-type")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("");
+        let body: serde_json::Value = resp.json().expect("Status should be JSON");
+        if body["status"] == "error" {
+            panic!("Job failed with error: {}", body["message"]);
+        }
 
-        if content_type.contains("audio/mpeg") {
+        if body["status"] == "completed" {
             println!("Job Completed! Audio is ready.");
+            let download_url = body["download_url"]
+                .as_str()
+                .expect("Completed status should include download_url");
+
+            let resp = client
+                .get(format!("{}{}", base_url, download_url))
+                .send()
+                .expect("Failed to download audio");
+            let content_type = resp
+                .headers()
+                .get("content-type")
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("");
+            assert!(
+                content_type.contains("audio/mpeg"),
+                "Download should be MP3"
+            );
+            assert_eq!(
+                resp.headers()
+                    .get("accept-ranges")
+                    .and_then(|h| h.to_str().ok()),
+                Some("bytes")
+            );
 
             // Verify we got actual content
             let bytes = resp.bytes().expect("Failed to read audio bytes");
             assert!(!bytes.is_empty(), "Audio response should not be empty");
 
+            // Range requests return just the requested bytes
+            let resp = client
+                .get(format!("{}{}", base_url, download_url))
+                .header("Range", "bytes=0-9")
+                .send()
+                .expect("Failed to download audio range");
+            assert_eq!(
+                resp.status().as_u16(),
+                206,
+                "Range request should be partial"
+            );
+            let partial = resp.bytes().expect("Failed to read range bytes");
+            assert_eq!(&partial[..], &bytes[..10]);
+
             completed = true;
             break;
         }
 
-        let body: serde_json::Value = resp.json().unwrap_or(serde_json::json!({}));
-        if body["status"] == "error" {
-            panic!("Job failed with error: {}", body["message"]);
-        }
-
         println!("Status: Processing...");
         thread::sleep(Duration::from_secs(2));
     }
@@ -635,6 +672,159 @@ fn test_generate_speed_boundaries(client: &Client, base_url: &str) {
     println!("✓ Speed boundary values test passed!");
 }
 
+//=============================================================================
+// TEST 9: Output Formats
+//=============================================================================
+fn test_generate_output_formats(client: &Client, base_url: &str) {
+    println!("\n--- Test: Output Formats ---");
+
+    // Unknown formats are rejected up front
+    let form = multipart::Form::new()
+        .file("text_file", "tests/resources/test_tts_input.txt")
+        .expect("Failed to create part")
+        .text("format", "aac");
+    let resp = client
+        .post(format!("{}/generate", base_url))
+        .multipart(form)
+        .send()
+        .expect("Failed to send request");
+    assert_eq!(
+        resp.status().as_u16(),
+        400,
+        "Unknown format should be rejected"
+    );
+
+    // Bitrates only apply to lossy formats
+    let form = multipart::Form::new()
+        .file("text_file", "tests/resources/test_tts_input.txt")
+        .expect("Failed to create part")
+        .text("format", "flac")
+        .text("bitrate", "128k");
+    let resp = client
+        .post(format!("{}/generate", base_url))
+        .multipart(form)
+        .send()
+        .expect("Failed to send request");
+    assert_eq!(
+        resp.status().as_u16(),
+        400,
+        "Bitrate for FLAC should be rejected"
+    );
+
+    for (format, content_type) in [
+        ("flac", "audio/flac"),
+        ("opus", "audio/ogg"),
+        ("wav", "audio/wav"),
+    ] {
+        println!("Testing format: {}", format);
+
+        let form = multipart::Form::new()
+            .file("text_file", "tests/resources/test_tts_input.txt")
+            .expect("Failed to create part")
+            .text("format", format)
+            .text("sample_rate", "16000");
+        let resp = client
+            .post(format!("{}/generate", base_url))
+            .multipart(form)
+            .send()
+            .expect("Failed to send request");
+        assert!(
+            resp.status().is_success(),
+            "Generate should succeed for format: {}",
+            format
+        );
+        let json: serde_json::Value = resp.json().expect("Failed to parse JSON");
+        let job_id = json["id"].as_str().expect("No id in response").to_string();
+
+        let start_time = Instant::now();
+        let status = loop {
+            assert!(
+                start_time.elapsed() < Duration::from_secs(60),
+                "Timed out waiting for {} job",
+                format
+            );
+            let body: serde_json::Value = client
+                .get(format!("{}/status/{}", base_url, job_id))
+                .send()
+                .expect("Failed to get status")
+                .json()
+                .expect("Status should be JSON");
+            if body["status"] != "queued" && body["status"] != "processing" {
+                break body;
+            }
+            thread::sleep(Duration::from_secs(2));
+        };
+        assert_eq!(status["status"], "completed", "Job failed: {}", status);
+        assert_eq!(status["format"], format);
+
+        let resp = client
+            .get(format!(
+                "{}{}",
+                base_url,
+                status["download_url"].as_str().unwrap()
+            ))
+            .send()
+            .expect("Failed to download audio");
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("")
+                .to_string()
+        };
+        assert!(header("content-type").starts_with(content_type));
+        assert!(header("content-disposition").contains(&format!("{}.{}", job_id, format)));
+    }
+
+    println!("✓ Output formats test passed!");
+}
+
+//=============================================================================
+// TEST 10: JSON Generate Endpoint
+//=============================================================================
+fn test_generate_json(client: &Client, base_url: &str) {
+    println!("\n--- Test: JSON Generate Endpoint ---");
+
+    let resp = client
+        .post(format!("{}/generate-json", base_url))
+        .json(&serde_json::json!({ "text": "Hello from a script.", "voice": "af_heart", "speed": 1.2 }))
+        .send()
+        .expect("Failed to send request");
+    assert!(resp.status().is_success(), "JSON generate should succeed");
+    let json: serde_json::Value = resp.json().expect("Failed to parse JSON");
+    assert!(json["id"].is_string(), "Should return job ID");
+
+    // Validation is shared with the multipart endpoint
+    let resp = client
+        .post(format!("{}/generate-json", base_url))
+        .json(&serde_json::json!({ "text": "   " }))
+        .send()
+        .expect("Failed to send request");
+    assert_eq!(resp.status().as_u16(), 422, "Should reject empty text");
+    let problem: serde_json::Value = resp.json().expect("Failed to parse problem");
+    assert_eq!(problem["code"], "empty_input");
+
+    for (body, reason) in [
+        (
+            serde_json::json!({ "text": "Hi", "format": "aac" }),
+            "unknown format",
+        ),
+        (
+            serde_json::json!({ "text": "Hi", "format": "wav", "bitrate": "128k" }),
+            "bitrate for WAV",
+        ),
+    ] {
+        let resp = client
+            .post(format!("{}/generate-json", base_url))
+            .json(&body)
+            .send()
+            .expect("Failed to send request");
+        assert_eq!(resp.status().as_u16(), 400, "Should reject {}", reason);
+    }
+
+    println!("✓ JSON generate test passed!");
+}
+
 fn get_container_ip(container_name: &str) -> Option<String> {
     let output = Command::new("docker")
         .args(&[